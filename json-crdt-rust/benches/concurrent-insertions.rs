@@ -0,0 +1,69 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use json_crdt_rust::{Doc, ObjRef, ReadableDoc, WritableDoc};
+
+fn text_of(doc: &Doc) -> ObjRef {
+    *doc.get(ObjRef::Root, "text")
+        .unwrap()
+        .unwrap()
+        .as_object()
+        .unwrap()
+}
+
+// A long run typed by one replica, split in many blocks by concurrent insertions of another
+// one. Every block inserted at the start by the other replicas comes after the whole run,
+// so ordering them has to find the end of a deeply branched subtree.
+fn build_replicas(chars: u32, prepends: u32, replicas: u32) -> (Vec<u8>, Vec<Doc>) {
+    let mut writer = Doc::new("0".to_string());
+    let mut txn = writer.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    for _ in 0..chars {
+        txn.append_text(text, "a").unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut splitter = Doc::new("1".to_string());
+    splitter.merge(&writer).unwrap();
+    let splitter_text = text_of(&splitter);
+    let mut txn = splitter.transaction();
+    for position in (1..chars).step_by(10).rev() {
+        txn.insert_text(splitter_text, position, "b").unwrap();
+    }
+    txn.commit().unwrap();
+    writer.merge(&splitter).unwrap();
+
+    let mut others = Vec::new();
+    for replica in 2..replicas + 2 {
+        let mut doc = Doc::new(replica.to_string());
+        doc.merge(&writer).unwrap();
+        let text = text_of(&doc);
+        for _ in 0..prepends {
+            let mut txn = doc.transaction();
+            txn.insert_text(text, 0, "c").unwrap();
+            txn.commit().unwrap();
+        }
+        others.push(doc);
+    }
+
+    (writer.serialize().unwrap(), others)
+}
+
+fn merge_all(mut doc: Doc, others: &[Doc]) {
+    for other in others {
+        doc.merge(other).unwrap();
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let (buffer, others) = build_replicas(10_000, 250, 4);
+
+    c.bench_function("concurrent-insertions", |b| {
+        b.iter_batched(
+            || Doc::load("0".to_string(), buffer.clone().into()).unwrap(),
+            |doc| merge_all(black_box(doc), &others),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use json_crdt_rust::{Doc, ObjRef, WritableDoc};
+
+const CHUNK: &str = "0123456789";
+
+// Prepending keeps every chunk in its own block, so a single deletion of the whole text
+// has to go through thousands of them
+fn build_fragmented_text(chars: u32) -> Vec<u8> {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+
+    for _ in 0..chars / CHUNK.len() as u32 {
+        txn.insert_text(text, 0, CHUNK).unwrap();
+    }
+    txn.commit().unwrap();
+
+    doc.serialize().unwrap()
+}
+
+fn delete_all(mut doc: Doc, chars: u32) {
+    let mut txn = doc.transaction();
+    let text = txn.get_or_create_text(ObjRef::Root, "text").unwrap();
+    txn.delete_text(text, 0, chars).unwrap();
+    txn.commit().unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let chars = 100_000;
+    let buffer = build_fragmented_text(chars);
+
+    c.bench_function("large-delete", |b| {
+        b.iter_batched(
+            || Doc::load("2".to_string(), buffer.clone().into()).unwrap(),
+            |doc| delete_all(black_box(doc), chars),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
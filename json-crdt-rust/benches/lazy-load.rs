@@ -0,0 +1,72 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use json_crdt_rust::{Doc, ObjRef, ReadableDoc, WritableDoc};
+use serde_json::Value;
+
+// Lazy documents should answer the first read in constant time, regardless of the history
+fn build_trace_doc() -> Vec<u8> {
+    let trace_file_content = include_str!("automerge-trace/trace.json");
+    let trace: Value = serde_json::from_str(trace_file_content).unwrap();
+
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+
+    for edit in trace.as_array().unwrap() {
+        let action = edit.as_array().unwrap();
+        let index = action[0].as_u64().unwrap() as u32;
+        if action[1] == 0 {
+            txn.insert_text(text, index, action[2].as_str().unwrap())
+                .unwrap();
+        } else {
+            txn.delete_text(text, index, action[1].as_u64().unwrap() as u32)
+                .unwrap();
+        }
+    }
+    txn.commit().unwrap();
+
+    doc.serialize().unwrap()
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let buffer = build_trace_doc();
+
+    let mut group = c.benchmark_group("lazy-load");
+
+    group.bench_function("lazy-get", |b| {
+        b.iter(|| {
+            let doc = Doc::lazy("2".to_string(), black_box(buffer.clone()).into()).unwrap();
+            doc.get(ObjRef::Root, "text").unwrap().is_some()
+        })
+    });
+
+    group.bench_function("lazy-get-text", |b| {
+        b.iter(|| {
+            let doc = Doc::lazy("2".to_string(), black_box(buffer.clone()).into()).unwrap();
+            let text = *doc
+                .get(ObjRef::Root, "text")
+                .unwrap()
+                .unwrap()
+                .as_object()
+                .unwrap();
+            doc.get_text(text).unwrap().unwrap().len()
+        })
+    });
+
+    group.bench_function("load-get-text", |b| {
+        b.iter(|| {
+            let doc = Doc::load("2".to_string(), black_box(buffer.clone()).into()).unwrap();
+            let text = *doc
+                .get(ObjRef::Root, "text")
+                .unwrap()
+                .unwrap()
+                .as_object()
+                .unwrap();
+            doc.get_text(text).unwrap().unwrap().len()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use json_crdt_rust::{Doc, ObjRef, WritableDoc};
+
+// Run with `--features parallel` to compare against the parallel load path
+fn build_doc(objects: u32, edits: u32) -> Vec<u8> {
+    let mut doc = Doc::new("1".to_string());
+
+    for object in 0..objects {
+        let mut txn = doc.transaction();
+        let text = txn
+            .create_text(ObjRef::Root, format!("text_{}", object))
+            .unwrap();
+
+        for i in 0..edits {
+            txn.insert_text(text, i / 2, "a").unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    doc.serialize().unwrap()
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let buffer = build_doc(64, 5000);
+
+    c.bench_function("multi-object-load", |b| {
+        b.iter(|| Doc::load("2".to_string(), black_box(buffer.clone()).into()).unwrap())
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
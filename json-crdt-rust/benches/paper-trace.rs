@@ -0,0 +1,119 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use json_crdt_rust::{Doc, DocOptions, DocVersion, ObjRef, TextFanout, WritableDoc};
+use serde_json::Value;
+
+enum Edit {
+    Insert(u32, String),
+    Delete(u32, u32),
+}
+
+// Editing trace of a LaTeX paper, typed by a single author, see `automerge-trace/README.md`
+fn load_edits() -> Vec<Edit> {
+    let trace: Value = serde_json::from_str(include_str!("automerge-trace/trace.json")).unwrap();
+
+    trace
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|edit| {
+            let action = edit.as_array().unwrap();
+            let position = action[0].as_u64().unwrap() as u32;
+            if action[1] == 0 {
+                Edit::Insert(position, action[2].as_str().unwrap().to_owned())
+            } else {
+                Edit::Delete(position, action[1].as_u64().unwrap() as u32)
+            }
+        })
+        .collect()
+}
+
+fn execute_trace(edits: &[Edit]) -> Doc {
+    execute_trace_with_fanout(edits, TextFanout::default())
+}
+
+fn execute_trace_with_fanout(edits: &[Edit], fanout: TextFanout) -> Doc {
+    let mut doc = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            text_fanout: fanout,
+            ..Default::default()
+        },
+    );
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+
+    for edit in edits {
+        match edit {
+            Edit::Insert(position, content) => txn.insert_text(text, *position, content),
+            Edit::Delete(position, count) => txn.delete_text(text, *position, *count),
+        }
+        .unwrap();
+    }
+    txn.commit().unwrap();
+
+    doc
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let edits = load_edits();
+
+    let mut group = c.benchmark_group("paper-trace");
+    group.sample_size(10);
+    group.bench_function("local-edits", |b| {
+        b.iter(|| execute_trace(black_box(&edits)))
+    });
+
+    let doc = execute_trace(&edits);
+    group.bench_function("merge", |b| {
+        b.iter(|| {
+            let mut replica = Doc::new("2".to_string());
+            replica.merge(black_box(&doc)).unwrap();
+            replica
+        })
+    });
+
+    // Same as `merge`, but the operations are received as an encoded update
+    let update = doc.encode_new_operations_since(&DocVersion::new()).unwrap();
+    group.bench_function("apply-encoded", |b| {
+        b.iter(|| {
+            let mut replica = Doc::new("2".to_string());
+            replica
+                .apply_encoded_operations(black_box(update.clone()).into())
+                .unwrap();
+            replica
+        })
+    });
+    group.finish();
+
+    // Wider nodes use less memory on large texts, but each edit updates a larger node
+    let mut group = c.benchmark_group("paper-trace-fanout");
+    group.sample_size(10);
+    for fanout in [TextFanout::Narrow, TextFanout::Medium, TextFanout::Wide] {
+        group.bench_function(format!("local-edits-{:?}", fanout), |b| {
+            b.iter(|| execute_trace_with_fanout(black_box(&edits), fanout))
+        });
+
+        let update = execute_trace_with_fanout(&edits, fanout)
+            .encode_new_operations_since(&DocVersion::new())
+            .unwrap();
+        group.bench_function(format!("apply-encoded-{:?}", fanout), |b| {
+            b.iter(|| {
+                let mut replica = Doc::new_with_options(
+                    "2".to_string(),
+                    DocOptions {
+                        text_fanout: fanout,
+                        ..Default::default()
+                    },
+                );
+                replica
+                    .apply_encoded_operations(black_box(update.clone()).into())
+                    .unwrap();
+                replica
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+use json_crdt_rust::{NetworkConditions, ObjRef, ReadableDoc, Simulation, WritableDoc};
+
+fn simulate(name: &str, conditions: NetworkConditions) {
+    let mut simulation = Simulation::new(5, conditions, 42);
+
+    let mut txn = simulation.doc_mut(0).transaction();
+    txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+    simulation.sync_until_converged(100).unwrap();
+
+    let text = *simulation
+        .doc(0)
+        .get(ObjRef::Root, "text")
+        .unwrap()
+        .unwrap()
+        .as_object()
+        .unwrap();
+
+    for _ in 0..100 {
+        for peer in 0..simulation.peers() {
+            let mut txn = simulation.doc_mut(peer).transaction();
+            txn.append_text(text, "a").unwrap();
+            txn.commit().unwrap();
+
+            simulation.broadcast(peer).unwrap();
+            simulation.advance(10).unwrap();
+        }
+    }
+
+    let rounds = simulation.sync_until_converged(100).unwrap();
+    let expected = simulation.doc(0).get_text(text).unwrap();
+    for peer in 1..simulation.peers() {
+        assert_eq!(simulation.doc(peer).get_text(text).unwrap(), expected);
+    }
+
+    let stats = simulation.stats();
+    println!("{}:", name);
+    println!(
+        "  converged after {:?} rounds, at {}ms",
+        rounds,
+        simulation.now()
+    );
+    println!(
+        "  messages: {} sent, {} delivered, {} dropped, {} duplicated",
+        stats.messages_sent,
+        stats.messages_delivered,
+        stats.messages_dropped,
+        stats.messages_duplicated
+    );
+    println!(
+        "  updates: {} bytes, {} operations applied, {} redundant",
+        stats.update_bytes, stats.operations_applied, stats.redundant_updates
+    );
+}
+
+fn main() {
+    simulate("reliable network", NetworkConditions::default());
+    simulate(
+        "lossy network",
+        NetworkConditions {
+            drop_rate: 0.2,
+            duplicate_rate: 0.1,
+            ..NetworkConditions::default()
+        },
+    );
+    simulate(
+        "high latency",
+        NetworkConditions {
+            min_latency: 200,
+            max_latency: 1000,
+            ..NetworkConditions::default()
+        },
+    );
+}
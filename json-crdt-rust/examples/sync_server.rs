@@ -0,0 +1,16 @@
+use json_crdt_rust::{serve, Doc, SharedDoc};
+use tokio::net::TcpListener;
+
+// Relays the operations of the clients connected to ws://<address>, e.g.
+// `cargo run --example sync_server --features net -- 127.0.0.1:9001`
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9001".to_string());
+    let listener = TcpListener::bind(&address).await.unwrap();
+    println!("listening on ws://{}", address);
+
+    let doc = SharedDoc::new(Doc::new("server".to_string()));
+    serve(listener, doc).await.unwrap();
+}
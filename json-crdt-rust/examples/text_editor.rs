@@ -0,0 +1,457 @@
+// A tiny terminal text editor built on top of the text CRDT.
+//
+// Usage:
+//   cargo run --example text_editor -- <file>
+//   cargo run --example text_editor -- <file> --listen /tmp/editor.sock
+//   cargo run --example text_editor -- <other-file> --connect /tmp/editor.sock
+//
+// Keys: arrows move the cursor, Ctrl-T swaps the characters around it, Ctrl-S saves,
+// Ctrl-Q quits.
+// The document is also saved periodically: the operations added since the last save are
+// appended to <file>.log, and every few saves the whole document is rewritten to <file>.
+// When two instances are connected through a unix socket, the operations of every change
+// are sent to the other one.
+
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor::MoveTo,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::Print,
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use json_crdt_rust::{Doc, DocError, DocVersion, ObjRef, ReadableDoc, WritableDoc};
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Incremental saves appended to the log before the whole document is saved again
+const MAX_INCREMENTS: usize = 20;
+
+struct Editor {
+    doc: Doc,
+    text: ObjRef,
+    path: String,
+    // Incremental saves in the log since the last full one, None until the document
+    // is saved in full to the path
+    increments: Option<usize>,
+    // Byte offset of the cursor inside the text
+    cursor: usize,
+    last_save: Instant,
+    peer: Option<UnixStream>,
+    // Version of the last operations sent to the peer
+    synced: DocVersion,
+    status: String,
+}
+
+impl Editor {
+    fn new(doc: Doc, path: String, increments: Option<usize>, peer: Option<UnixStream>) -> Self {
+        let text = text_ref(&doc);
+        let synced = doc.version().unwrap();
+
+        Self {
+            doc,
+            text,
+            path,
+            increments,
+            cursor: 0,
+            last_save: Instant::now(),
+            peer,
+            synced,
+            status: "Ctrl-S: save | Ctrl-Q: quit".to_string(),
+        }
+    }
+
+    fn content(&self) -> String {
+        self.doc
+            .get_text(self.text)
+            .unwrap()
+            .expect("missing text object")
+    }
+
+    fn insert(&mut self, value: &str) {
+        let mut txn = self.doc.transaction();
+        txn.insert_text(self.text, self.cursor as u32, value)
+            .unwrap();
+        txn.commit().unwrap();
+
+        self.cursor += value.len();
+        self.on_local_change();
+    }
+
+    fn delete_before_cursor(&mut self) {
+        let content = self.content();
+        if let Some((index, char)) = content[..self.cursor].char_indices().next_back() {
+            let mut txn = self.doc.transaction();
+            txn.delete_text(self.text, index as u32, char.len_utf8() as u32)
+                .unwrap();
+            txn.commit().unwrap();
+
+            self.cursor = index;
+            self.on_local_change();
+        }
+    }
+
+    fn delete_after_cursor(&mut self) {
+        let content = self.content();
+        if let Some(char) = content[self.cursor..].chars().next() {
+            let mut txn = self.doc.transaction();
+            txn.delete_text(self.text, self.cursor as u32, char.len_utf8() as u32)
+                .unwrap();
+            txn.commit().unwrap();
+
+            self.on_local_change();
+        }
+    }
+
+    // Replaces the two characters around the cursor with a single splice
+    fn transpose(&mut self) {
+        let content = self.content();
+        let before = content[..self.cursor].char_indices().next_back();
+        let after = content[self.cursor..].chars().next();
+        if let (Some((index, before)), Some(after)) = (before, after) {
+            let swapped = format!("{}{}", after, before);
+            let len = swapped.len();
+            let mut txn = self.doc.transaction();
+            txn.splice_text(self.text, index as u32, len as u32, swapped)
+                .unwrap();
+            txn.commit().unwrap();
+
+            self.cursor = index + len;
+            self.on_local_change();
+        }
+    }
+
+    fn move_left(&mut self) {
+        let content = self.content();
+        if let Some((index, _)) = content[..self.cursor].char_indices().next_back() {
+            self.cursor = index;
+        }
+    }
+
+    fn move_right(&mut self) {
+        let content = self.content();
+        if let Some(char) = content[self.cursor..].chars().next() {
+            self.cursor += char.len_utf8();
+        }
+    }
+
+    fn move_vertically(&mut self, up: bool) {
+        let content = self.content();
+        let line_start = content[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+        let column = content[line_start..self.cursor].chars().count();
+
+        let target_line_start = if up {
+            if line_start == 0 {
+                return;
+            }
+            content[..line_start - 1].rfind('\n').map_or(0, |i| i + 1)
+        } else {
+            match content[self.cursor..].find('\n') {
+                Some(offset) => self.cursor + offset + 1,
+                None => return,
+            }
+        };
+
+        let target_line = content[target_line_start..]
+            .split('\n')
+            .next()
+            .unwrap_or_default();
+        let offset: usize = target_line
+            .chars()
+            .take(column)
+            .map(|char| char.len_utf8())
+            .sum();
+        self.cursor = target_line_start + offset;
+    }
+
+    fn on_local_change(&mut self) {
+        if let Some(peer) = self.peer.as_mut() {
+            // Only the operations the peer hasn't received yet
+            let buffer = self.doc.encode_new_operations_since(&self.synced).unwrap();
+            if write_frame(peer, &buffer).is_err() {
+                self.peer = None;
+                self.status = "Peer disconnected".to_string();
+            }
+            self.synced = self.doc.version().unwrap();
+        }
+    }
+
+    fn on_remote_change(&mut self, buffer: Vec<u8>) {
+        let before = self.doc.version().unwrap();
+        if let Err(error) = self.doc.apply_encoded_operations(buffer.into()) {
+            self.status = format!("Unable to apply the changes of the peer: {}", error);
+        }
+        let after = self.doc.version().unwrap();
+
+        // Local changes are sent right away, so the peer has everything up to now
+        self.synced = after.clone();
+
+        // The cursor follows the text around it, when the peer inserts or deletes before it
+        self.cursor = self
+            .doc
+            .map_position(self.text, self.cursor as u32, &before, &after)
+            .unwrap() as usize;
+    }
+
+    fn save(&mut self) {
+        let result = match self.increments {
+            Some(increments) if increments < MAX_INCREMENTS => self.save_increment(),
+            _ => self.save_full(),
+        };
+
+        self.last_save = Instant::now();
+        self.status = match result {
+            Ok(status) => status,
+            Err(error) => format!("Unable to save: {}", error),
+        };
+    }
+
+    // Appends the operations added since the last save to the log
+    fn save_increment(&mut self) -> Result<String, Box<dyn Error>> {
+        let buffer = match self.doc.save_incremental() {
+            Ok(buffer) => buffer,
+            Err(DocError::FullSaveRequired) => return self.save_full(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let path = log_path(&self.path);
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut log| write_frame(&mut log, &buffer));
+        if let Err(error) = written {
+            // The operations count as saved already, so the next save rewrites everything
+            self.increments = None;
+            return Err(error.into());
+        }
+
+        self.increments = self.increments.map(|increments| increments + 1);
+        Ok(format!("Appended {} bytes to {}", buffer.len(), path))
+    }
+
+    // Rewrites the whole document, the log is not needed anymore
+    fn save_full(&mut self) -> Result<String, Box<dyn Error>> {
+        let buffer = self.doc.save()?;
+        std::fs::write(&self.path, &buffer)?;
+        std::fs::write(log_path(&self.path), [])?;
+
+        self.increments = Some(0);
+        Ok(format!("Saved {} bytes to {}", buffer.len(), self.path))
+    }
+
+    fn render(&self, stdout: &mut impl Write) -> std::io::Result<()> {
+        let content = self.content();
+        let (_, rows) = terminal::size()?;
+
+        queue!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+        for line in content.split('\n').take(rows.saturating_sub(1) as usize) {
+            queue!(stdout, Print(line), Print("\r\n"))?;
+        }
+        queue!(
+            stdout,
+            MoveTo(0, rows.saturating_sub(1)),
+            Print(&self.status)
+        )?;
+
+        let before_cursor = &content[..self.cursor];
+        let row = before_cursor.matches('\n').count();
+        let column = before_cursor
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count();
+        queue!(stdout, MoveTo(column as u16, row as u16))?;
+
+        stdout.flush()
+    }
+
+    // Returns false when the editor should exit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            match key.code {
+                KeyCode::Char('q') => return false,
+                KeyCode::Char('s') => self.save(),
+                KeyCode::Char('t') => self.transpose(),
+                _ => {}
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char(char) => self.insert(char.encode_utf8(&mut [0; 4])),
+            KeyCode::Enter => self.insert("\n"),
+            KeyCode::Tab => self.insert("    "),
+            KeyCode::Backspace => self.delete_before_cursor(),
+            KeyCode::Delete => self.delete_after_cursor(),
+            KeyCode::Left => self.move_left(),
+            KeyCode::Right => self.move_right(),
+            KeyCode::Up => self.move_vertically(true),
+            KeyCode::Down => self.move_vertically(false),
+            KeyCode::Home => {
+                let content = self.content();
+                self.cursor = content[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+            }
+            KeyCode::End => {
+                let content = self.content();
+                self.cursor = content[self.cursor..]
+                    .find('\n')
+                    .map_or(content.len(), |offset| self.cursor + offset);
+            }
+            _ => {}
+        }
+
+        true
+    }
+}
+
+fn text_ref(doc: &Doc) -> ObjRef {
+    *doc.get(ObjRef::Root, "text")
+        .unwrap()
+        .expect("document does not contain a text")
+        .as_object()
+        .expect("expected text object")
+}
+
+fn log_path(path: &str) -> String {
+    format!("{}.log", path)
+}
+
+// Both the messages to the peer and the increments in the log are prefixed by their length
+fn write_frame(writer: &mut impl Write, buffer: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(buffer.len() as u32).to_le_bytes())?;
+    writer.write_all(buffer)
+}
+
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+
+    let mut buffer = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn spawn_reader(mut stream: UnixStream) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        while let Ok(frame) = read_frame(&mut stream) {
+            if sender.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+fn client_id() -> String {
+    format!("editor-{}", std::process::id())
+}
+
+// Returns the document and the number of increments loaded from the log
+fn load_or_create(path: &str) -> (Doc, Option<usize>) {
+    match std::fs::read(path) {
+        Ok(buffer) => {
+            let mut doc = Doc::load(client_id(), buffer.into()).unwrap();
+
+            // An increment cut short by a crash is ignored, its operations were never saved
+            let log = std::fs::read(log_path(path)).unwrap_or_default();
+            let mut reader = log.as_slice();
+            let mut increments = 0;
+            while let Ok(buffer) = read_frame(&mut reader) {
+                doc.load_incremental(buffer.into()).unwrap();
+                increments += 1;
+            }
+
+            (doc, Some(increments))
+        }
+        Err(_) => {
+            let mut doc = Doc::new(client_id());
+            let mut txn = doc.transaction();
+            txn.create_text(ObjRef::Root, "text").unwrap();
+            txn.commit().unwrap();
+            (doc, None)
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = args.first().expect("usage: text_editor <file>").clone();
+
+    let (doc, increments, peer) = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("--listen"), Some(socket)) => {
+            let _ = std::fs::remove_file(socket);
+            let listener = UnixListener::bind(socket)?;
+            println!("Waiting for a peer on {}...", socket);
+            let (mut stream, _) = listener.accept()?;
+
+            // The listener owns the initial state, so both peers share the same text object
+            let (doc, increments) = load_or_create(&path);
+            write_frame(&mut stream, &doc.serialize().unwrap())?;
+            (doc, increments, Some(stream))
+        }
+        (Some("--connect"), Some(socket)) => {
+            let mut stream = UnixStream::connect(socket)?;
+            let initial_state = read_frame(&mut stream)?;
+            let doc = Doc::load(client_id(), initial_state.into()).unwrap();
+            // The file doesn't contain this document yet, the first save is a full one
+            (doc, None, Some(stream))
+        }
+        _ => {
+            let (doc, increments) = load_or_create(&path);
+            (doc, increments, None)
+        }
+    };
+
+    let remote_changes = match &peer {
+        Some(stream) => Some(spawn_reader(stream.try_clone()?)),
+        None => None,
+    };
+    let mut editor = Editor::new(doc, path, increments, peer);
+
+    let mut stdout = std::io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, EnterAlternateScreen)?;
+
+    loop {
+        editor.render(&mut stdout)?;
+
+        if event::poll(Duration::from_millis(100))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !editor.handle_key(key) {
+                    break;
+                }
+            }
+        }
+
+        if let Some(remote_changes) = &remote_changes {
+            while let Ok(buffer) = remote_changes.try_recv() {
+                editor.on_remote_change(buffer);
+            }
+        }
+
+        if editor.doc.is_dirty() && editor.last_save.elapsed() > AUTOSAVE_INTERVAL {
+            editor.save();
+        }
+    }
+
+    if editor.doc.is_dirty() {
+        editor.save();
+    }
+
+    execute!(stdout, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()
+}
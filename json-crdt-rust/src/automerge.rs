@@ -0,0 +1,480 @@
+// Migration from and to Automerge, through the JSON representation of its changes (the
+// one of `Automerge.decodeChange`). Only the contents are migrated, not the history:
+// imported changes are replayed with the Automerge semantics and the resulting state is
+// written in a single transaction, while exports describe the current state as a single
+// change. Lists, tables and counters have no equivalent here and are rejected.
+
+use std::cmp::Ordering;
+
+use rustc_hash::FxHashMap;
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+use thiserror::Error;
+
+use crate::{
+    transaction::Transaction,
+    view::{View, ViewError},
+    DocError, ObjRef, ObjectValue, ScalarValue, Selector, TransactionError, Value, ValueKind,
+};
+
+const ROOT: &str = "_root";
+const HEAD: &str = "_head";
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum AutomergeError {
+    #[error("invalid JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    #[error("invalid change: {0}")]
+    InvalidChange(String),
+
+    #[error("unsupported operation: {0}")]
+    UnsupportedOperation(String),
+
+    #[error("unknown object: {0}")]
+    UnknownObject(String),
+
+    #[error("unknown element: {0}")]
+    UnknownElement(String),
+
+    #[error("unable to write the imported state: {0}")]
+    TransactionError(#[from] TransactionError),
+}
+
+// Automerge operation ids are made of a Lamport counter and the actor, which breaks ties
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct OpId {
+    counter: u64,
+    actor: String,
+}
+
+impl OpId {
+    fn parse(id: &str) -> Result<Self, AutomergeError> {
+        let (counter, actor) = id
+            .split_once('@')
+            .ok_or_else(|| AutomergeError::InvalidChange(format!("invalid id: {}", id)))?;
+        let counter = counter
+            .parse()
+            .map_err(|_| AutomergeError::InvalidChange(format!("invalid id: {}", id)))?;
+
+        Ok(Self {
+            counter,
+            actor: actor.to_string(),
+        })
+    }
+
+    fn to_key(&self) -> String {
+        format!("{}@{}", self.counter, self.actor)
+    }
+}
+
+impl Ord for OpId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.counter, &self.actor).cmp(&(other.counter, &other.actor))
+    }
+}
+
+impl PartialOrd for OpId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ImportedValue {
+    Scalar(ScalarValue),
+    // Key of the object in `Importer::objects`
+    Object(String),
+}
+
+#[derive(Debug)]
+struct TextElement {
+    id: OpId,
+    value: String,
+    deleted: bool,
+}
+
+#[derive(Debug)]
+enum ImportedObject {
+    // Concurrent values of each key, the one with the highest id is the current one
+    Map(FxHashMap<String, Vec<(OpId, ImportedValue)>>),
+    // Elements in document order, deleted ones included
+    Text(Vec<TextElement>),
+}
+
+// Replays changes with the Automerge semantics, keyed by the ids of the objects
+#[derive(Debug)]
+struct Importer {
+    objects: FxHashMap<String, ImportedObject>,
+}
+
+impl Importer {
+    fn new() -> Self {
+        let mut objects = FxHashMap::default();
+        objects.insert(ROOT.to_string(), ImportedObject::Map(FxHashMap::default()));
+        Self { objects }
+    }
+
+    fn apply_change(&mut self, change: &JsonValue) -> Result<(), AutomergeError> {
+        let actor = change["actor"]
+            .as_str()
+            .ok_or_else(|| AutomergeError::InvalidChange("missing actor".to_string()))?;
+        let mut counter = change["startOp"]
+            .as_u64()
+            .ok_or_else(|| AutomergeError::InvalidChange("missing startOp".to_string()))?;
+        let operations = change["ops"]
+            .as_array()
+            .ok_or_else(|| AutomergeError::InvalidChange("missing ops".to_string()))?;
+
+        for operation in operations {
+            let id = OpId {
+                counter,
+                actor: actor.to_string(),
+            };
+            counter += self.apply_operation(id, operation)?;
+        }
+        Ok(())
+    }
+
+    // Returns the number of ids used by the operation
+    fn apply_operation(&mut self, id: OpId, operation: &JsonValue) -> Result<u64, AutomergeError> {
+        let action = operation["action"]
+            .as_str()
+            .ok_or_else(|| AutomergeError::InvalidChange("missing action".to_string()))?;
+        let object = operation["obj"]
+            .as_str()
+            .ok_or_else(|| AutomergeError::InvalidChange("missing obj".to_string()))?;
+        let pred = operation["pred"]
+            .as_array()
+            .map(|pred| {
+                pred.iter()
+                    .map(|id| {
+                        id.as_str()
+                            .ok_or_else(|| {
+                                AutomergeError::InvalidChange(format!("invalid pred: {}", id))
+                            })
+                            .and_then(OpId::parse)
+                    })
+                    .collect::<Result<Vec<OpId>, AutomergeError>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        // Objects created by the operation
+        let created = match action {
+            "set" | "del" => None,
+            "makeMap" => Some(ImportedObject::Map(FxHashMap::default())),
+            "makeText" => Some(ImportedObject::Text(Vec::new())),
+            _ => return Err(AutomergeError::UnsupportedOperation(action.to_string())),
+        };
+
+        match self.objects.get_mut(object) {
+            Some(ImportedObject::Map(map)) => {
+                let key = operation["key"].as_str().ok_or_else(|| {
+                    AutomergeError::InvalidChange("missing key of a map operation".to_string())
+                })?;
+                let values = map.entry(key.to_string()).or_default();
+                values.retain(|(value_id, _)| !pred.contains(value_id));
+
+                match (action, created) {
+                    ("del", _) => {}
+                    (_, Some(created)) => {
+                        values.push((id.clone(), ImportedValue::Object(id.to_key())));
+                        self.objects.insert(id.to_key(), created);
+                    }
+                    _ => values.push((id, ImportedValue::Scalar(parse_scalar(operation)?))),
+                }
+                Ok(1)
+            }
+            Some(ImportedObject::Text(elements)) => {
+                if created.is_some() {
+                    return Err(AutomergeError::UnsupportedOperation(format!(
+                        "{} inside a text",
+                        action
+                    )));
+                }
+                let element = operation["elemId"].as_str().ok_or_else(|| {
+                    AutomergeError::InvalidChange("missing elemId of a text operation".to_string())
+                })?;
+
+                match (action, operation["insert"].as_bool().unwrap_or(false)) {
+                    ("del", _) => {
+                        find_element(elements, element)?.deleted = true;
+                        Ok(1)
+                    }
+                    ("set", true) => insert_elements(elements, id, element, operation),
+                    _ => {
+                        find_element(elements, element)?.value = parse_char(operation)?;
+                        Ok(1)
+                    }
+                }
+            }
+            None => Err(AutomergeError::UnknownObject(object.to_string())),
+        }
+    }
+
+    // Writes the current state of the object into the given map of the document
+    fn write_map(
+        &self,
+        txn: &mut Transaction,
+        key: &str,
+        target: ObjRef,
+    ) -> Result<(), AutomergeError> {
+        let Some(ImportedObject::Map(map)) = self.objects.get(key) else {
+            return Err(AutomergeError::UnknownObject(key.to_string()));
+        };
+
+        for (selector, values) in map {
+            let Some((_, value)) = values.iter().max_by(|(a, _), (b, _)| a.cmp(b)) else {
+                continue;
+            };
+
+            match value {
+                ImportedValue::Scalar(scalar) => {
+                    txn.set_scalar(target, selector.as_str(), scalar.clone())?;
+                }
+                ImportedValue::Object(child) => match self.objects.get(child) {
+                    Some(ImportedObject::Map(_)) => {
+                        let map = txn.create_map(target, selector.as_str())?;
+                        self.write_map(txn, child, map)?;
+                    }
+                    Some(ImportedObject::Text(elements)) => {
+                        let text = txn.create_text(target, selector.as_str())?;
+                        let value: String = elements
+                            .iter()
+                            .filter(|element| !element.deleted)
+                            .map(|element| element.value.as_str())
+                            .collect();
+                        if !value.is_empty() {
+                            txn.insert_text(text, 0, value)?;
+                        }
+                    }
+                    None => return Err(AutomergeError::UnknownObject(child.clone())),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn find_element<'a>(
+    elements: &'a mut [TextElement],
+    id: &str,
+) -> Result<&'a mut TextElement, AutomergeError> {
+    let id = OpId::parse(id)?;
+    elements
+        .iter_mut()
+        .find(|element| element.id == id)
+        .ok_or_else(|| AutomergeError::UnknownElement(id.to_key()))
+}
+
+// Insertions with several `values` get consecutive ids, each one after the previous.
+// Concurrent insertions after the same element are ordered by decreasing id, so the
+// elements with a higher id that follow the reference are skipped.
+fn insert_elements(
+    elements: &mut Vec<TextElement>,
+    id: OpId,
+    reference: &str,
+    operation: &JsonValue,
+) -> Result<u64, AutomergeError> {
+    let values = match operation["values"].as_array() {
+        Some(values) => values
+            .iter()
+            .map(|value| {
+                value.as_str().map(str::to_string).ok_or_else(|| {
+                    AutomergeError::UnsupportedOperation(format!("text element {}", value))
+                })
+            })
+            .collect::<Result<Vec<String>, AutomergeError>>()?,
+        None => vec![parse_char(operation)?],
+    };
+
+    let mut index = match reference {
+        HEAD => 0,
+        reference => {
+            let reference = OpId::parse(reference)?;
+            elements
+                .iter()
+                .position(|element| element.id == reference)
+                .ok_or_else(|| AutomergeError::UnknownElement(reference.to_key()))?
+                + 1
+        }
+    };
+    while index < elements.len() && elements[index].id > id {
+        index += 1;
+    }
+
+    let count = values.len() as u64;
+    for (offset, value) in values.into_iter().enumerate() {
+        elements.insert(
+            index + offset,
+            TextElement {
+                id: OpId {
+                    counter: id.counter + offset as u64,
+                    actor: id.actor.clone(),
+                },
+                value,
+                deleted: false,
+            },
+        );
+    }
+    Ok(count)
+}
+
+fn parse_char(operation: &JsonValue) -> Result<String, AutomergeError> {
+    operation["value"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| {
+            AutomergeError::UnsupportedOperation(format!("text element {}", operation["value"]))
+        })
+}
+
+fn parse_scalar(operation: &JsonValue) -> Result<ScalarValue, AutomergeError> {
+    let value = &operation["value"];
+    let invalid = || AutomergeError::InvalidChange(format!("invalid value: {}", value));
+
+    match (operation["datatype"].as_str(), value) {
+        (Some("counter"), _) => Err(AutomergeError::UnsupportedOperation("counter".to_string())),
+        (Some("timestamp"), value) => {
+            Ok(ScalarValue::Timestamp(value.as_i64().ok_or_else(invalid)?))
+        }
+        (Some("float64"), value) => Ok(ScalarValue::Double(value.as_f64().ok_or_else(invalid)?)),
+        (Some("int" | "uint"), value) => value
+            .as_i64()
+            .and_then(|value| i32::try_from(value).ok())
+            .map(ScalarValue::Int)
+            .ok_or_else(invalid),
+        (_, JsonValue::Null) => Ok(ScalarValue::Null),
+        (_, JsonValue::Bool(value)) => Ok(ScalarValue::Bool(*value)),
+        (_, JsonValue::String(value)) => Ok(ScalarValue::String(value.clone())),
+        // Without a datatype, integers that fit are ints and the others doubles
+        (_, JsonValue::Number(number)) => Ok(number
+            .as_i64()
+            .and_then(|value| i32::try_from(value).ok())
+            .map(ScalarValue::Int)
+            .unwrap_or_else(|| ScalarValue::Double(number.as_f64().unwrap_or_default()))),
+        _ => Err(invalid()),
+    }
+}
+
+// Changes must be in causal order, e.g. the order of `Automerge.getAllChanges`, as the
+// dependencies are hashes of the binary changes and can't be checked
+pub(crate) fn import_changes(txn: &mut Transaction, buffer: &[u8]) -> Result<(), AutomergeError> {
+    let changes: JsonValue = serde_json::from_slice(buffer)?;
+    let changes = changes
+        .as_array()
+        .ok_or_else(|| AutomergeError::InvalidChange("expected an array of changes".to_string()))?;
+
+    let mut importer = Importer::new();
+    for change in changes {
+        importer.apply_change(change)?;
+    }
+    importer.write_map(txn, ROOT, ObjRef::Root)
+}
+
+// The actor must be made of hex digits. Index selectors become string keys, as Automerge
+// maps only have those. Dependencies are left empty, as the change doesn't build on others.
+pub(crate) fn export_changes(view: &View, actor: &str) -> Result<Vec<u8>, DocError> {
+    let mut operations = Vec::new();
+    export_map(view, &ObjRef::Root, ROOT, actor, &mut operations)?;
+
+    let change = json!({
+        "actor": actor,
+        "seq": 1,
+        "startOp": 1,
+        "time": 0,
+        "message": "",
+        "deps": [],
+        "ops": operations,
+    });
+    Ok(serde_json::to_vec(&json!([change])).expect("JSON values are serializable"))
+}
+
+fn export_map(
+    view: &View,
+    object: &ObjRef,
+    object_id: &str,
+    actor: &str,
+    operations: &mut Vec<JsonValue>,
+) -> Result<(), DocError> {
+    let map = match view.get_object(object)? {
+        Some(ObjectValue::Map(map)) => map,
+        Some(value) => return Err(ViewError::incompatible(object, ValueKind::Map, value).into()),
+        None => return Err(ViewError::ObjectNotFound(*object).into()),
+    };
+    let mut entries: Vec<(&Selector, &Value)> = map.iter(view.now()).collect();
+    entries.sort_by_key(|(selector, _)| *selector);
+
+    for (selector, value) in entries {
+        let key = match selector {
+            Selector::Key(key) => key.clone(),
+            Selector::Index(index) => index.to_string(),
+        };
+        let id = format!("{}@{}", operations.len() + 1, actor);
+        let mut operation = JsonMap::new();
+        operation.insert("obj".to_string(), json!(object_id));
+        operation.insert("key".to_string(), json!(key));
+        operation.insert("insert".to_string(), json!(false));
+        operation.insert("pred".to_string(), json!([]));
+
+        match value {
+            Value::Scalar(scalar) => {
+                operation.insert("action".to_string(), json!("set"));
+                let (value, datatype) = export_scalar(scalar);
+                operation.insert("value".to_string(), value);
+                if let Some(datatype) = datatype {
+                    operation.insert("datatype".to_string(), json!(datatype));
+                }
+                operations.push(JsonValue::Object(operation));
+            }
+            Value::Object(child) => match view.get_object(child)? {
+                Some(ObjectValue::Text(text)) => {
+                    operation.insert("action".to_string(), json!("makeText"));
+                    operations.push(JsonValue::Object(operation));
+
+                    // One element per char, each inserted after the previous one
+                    let mut previous = HEAD.to_string();
+                    for char in text.to_string().chars() {
+                        let element = format!("{}@{}", operations.len() + 1, actor);
+                        operations.push(json!({
+                            "action": "set",
+                            "obj": id,
+                            "elemId": previous,
+                            "insert": true,
+                            "value": char.to_string(),
+                            "pred": [],
+                        }));
+                        previous = element;
+                    }
+                }
+                Some(ObjectValue::Map(_)) => {
+                    operation.insert("action".to_string(), json!("makeMap"));
+                    operations.push(JsonValue::Object(operation));
+                    export_map(view, child, &id, actor, operations)?;
+                }
+                None => {}
+            },
+        }
+    }
+    Ok(())
+}
+
+fn export_scalar(scalar: &ScalarValue) -> (JsonValue, Option<&'static str>) {
+    match scalar {
+        ScalarValue::String(value) => (json!(value), None),
+        ScalarValue::Int(value) => (json!(value), Some("int")),
+        ScalarValue::Double(value) => (json!(value), Some("float64")),
+        ScalarValue::Bool(value) => (json!(value), None),
+        ScalarValue::Timestamp(value) => (json!(value), Some("timestamp")),
+        ScalarValue::Null => (JsonValue::Null, None),
+    }
+}
+
+// Automerge actors are hex strings, e.g. of the bytes of a UUID
+pub(crate) fn actor_of(client_id: &str) -> String {
+    client_id
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -1,10 +1,16 @@
+use std::cmp::Ordering;
+
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use bytes_varint::{VarIntSupport, VarIntSupportMut};
 use rustc_hash::{FxHashMap, FxHashSet};
 use thiserror::Error;
 
-use crate::{serde::Serializable, ClientId, GlobalClient, GlobalClientId};
+use crate::{serde::Serializable, ClientId, GlobalClient, GlobalClientId, OperationId};
 
+// Local IDs are positions in `clients`, which are always sorted by creation time and global
+// ID. Since this order is the same on every replica, comparing the local IDs of two clients
+// gives the same result everywhere, even if each replica assigned different IDs to them.
+// Tie-breaks between concurrent operations rely on it, and so does remapping in place.
 #[derive(Clone)]
 pub struct ClientRegistry {
     clients: Vec<GlobalClient>,
@@ -43,17 +49,21 @@ impl ClientRegistry {
     ) -> Result<(Self, Option<ClientRemappings>), ClientRegistryError> {
         let loaded_clients = Self::deserialize_clients(buffer)?;
         let mut registry = Self::new(global_client_id, timestamp);
-        let remappings = registry.register_clients(&loaded_clients);
+        registry.register_clients(&loaded_clients);
+
+        // The loaded operations use the positions of the loaded clients as IDs
+        let remappings = registry.remappings_from(&loaded_clients);
         Ok((registry, remappings))
     }
 
-    fn deserialize_clients(buffer: Bytes) -> Result<Vec<GlobalClient>, ClientRegistryError> {
+    pub fn deserialize_clients(buffer: Bytes) -> Result<Vec<GlobalClient>, ClientRegistryError> {
         let mut buffer = Bytes::from(buffer);
         let clients_len = buffer.get_u32_varint().map_err(|_| {
             ClientRegistryError::SerializationError("error reading clients_len".to_string())
         })?;
 
         let mut clients = Vec::new();
+        let mut seen = FxHashSet::default();
         for _ in 0..clients_len {
             let created_at = buffer.get_u64_varint().map_err(|_| {
                 ClientRegistryError::SerializationError("error reading created_at".to_string())
@@ -63,17 +73,37 @@ impl ClientRegistry {
                 ClientRegistryError::SerializationError("error reading global_id_len".to_string())
             })?;
 
+            if buffer.remaining() < global_id_len as usize {
+                return Err(ClientRegistryError::SerializationError(
+                    "global_id is truncated".to_string(),
+                ));
+            }
+
             let global_id_bytes = buffer.copy_to_bytes(global_id_len as usize);
             let global_id = String::from_utf8(global_id_bytes.to_vec()).map_err(|_| {
                 ClientRegistryError::SerializationError("error reading global_id".to_string())
             })?;
 
+            if !seen.insert(global_id.clone()) {
+                return Err(ClientRegistryError::SerializationError(format!(
+                    "duplicate client {}",
+                    global_id
+                )));
+            }
+
             clients.push(GlobalClient {
                 created_at,
                 global_id,
             });
         }
 
+        if buffer.has_remaining() {
+            return Err(ClientRegistryError::SerializationError(format!(
+                "{} unexpected bytes after the clients",
+                buffer.remaining()
+            )));
+        }
+
         Ok(clients)
     }
 
@@ -82,7 +112,7 @@ impl ClientRegistry {
     }
 
     pub fn register_clients(&mut self, clients: &[GlobalClient]) -> Option<ClientRemappings> {
-        if !self.has_unknown_clients(clients) {
+        if !self.has_new_clients(clients) {
             return None;
         }
 
@@ -101,27 +131,49 @@ impl ClientRegistry {
         remappings
     }
 
-    fn has_unknown_clients(&self, clients: &[GlobalClient]) -> bool {
+    // Maps the local IDs of another registry, holding the given clients, to the IDs of this
+    // one. All the clients must have been registered already.
+    // Returns None if the IDs are the same in both registries.
+    pub fn remappings_from(&self, clients: &[GlobalClient]) -> Option<ClientRemappings> {
+        let remappings: ClientRemappings = clients
+            .iter()
+            .enumerate()
+            .map(|(other_id, client)| {
+                let local_id = self.global_to_local_cache[&client.global_id];
+                (other_id as ClientId, local_id)
+            })
+            .collect();
+
+        if remappings
+            .iter()
+            .all(|(other_id, local_id)| other_id == local_id)
+        {
+            return None;
+        }
+
+        Some(remappings)
+    }
+
+    // Known clients with an earlier creation time count as new, as they change the order
+    fn has_new_clients(&self, clients: &[GlobalClient]) -> bool {
         for client in clients {
-            if !self.global_to_local_cache.contains_key(&client.global_id) {
-                return true;
+            match self.global_to_local_cache.get(&client.global_id) {
+                Some(local_id)
+                    if self.clients[*local_id as usize].created_at <= client.created_at => {}
+                _ => return true,
             }
         }
 
         false
     }
 
+    // If a client was registered with different creation times, the earliest one is kept,
+    // so that all replicas eventually agree on the order
     fn merge_clients(clients_a: &[GlobalClient], clients_b: &[GlobalClient]) -> Vec<GlobalClient> {
         let mut all_clients = Vec::new();
         all_clients.extend(clients_a);
         all_clients.extend(clients_b);
-        all_clients.sort_by(|a, b| {
-            if a.created_at == b.created_at {
-                return a.global_id.cmp(&b.global_id);
-            } else {
-                return a.created_at.cmp(&b.created_at);
-            }
-        });
+        all_clients.sort_by(|a, b| client_order(a, b));
 
         let mut visited_clients = FxHashSet::default();
         let mut new_clients = Vec::new();
@@ -160,12 +212,9 @@ impl ClientRegistry {
             new_clients_global_to_local.insert(&new_client.global_id, new_client_local_id);
         }
 
+        // Unchanged ids are included too, as remapped structures expect to find all of them
         for (local_id, local_client) in self.clients.iter().enumerate() {
             let new_client_local_id = new_clients_global_to_local[&local_client.global_id];
-            if local_id == new_client_local_id {
-                continue;
-            }
-
             remappings.insert(local_id as ClientId, new_client_local_id as ClientId);
         }
 
@@ -173,6 +222,11 @@ impl ClientRegistry {
     }
 
     fn rebuild_caches(&mut self) {
+        debug_assert!(self
+            .clients
+            .windows(2)
+            .all(|pair| client_order(&pair[0], &pair[1]) == Ordering::Less));
+
         self.local_to_global_cache.clear();
         self.global_to_local_cache.clear();
 
@@ -184,9 +238,71 @@ impl ClientRegistry {
         }
     }
 
+    pub fn get_global_id(&self, local_id: ClientId) -> Option<&GlobalClientId> {
+        self.local_to_global_cache.get(&local_id)
+    }
+
+    // Local IDs depend on the order clients were seen, so IDs are sorted by their global
+    // client to be listed in the same order by every peer
+    pub fn sort_by_global_id(&self, ids: &mut [OperationId]) {
+        ids.sort_by_key(|id| (self.get_global_id(id.client_id), id.sequence));
+    }
+
+    pub fn get_local_id(&self, global_id: &GlobalClientId) -> Option<ClientId> {
+        self.global_to_local_cache.get(global_id).copied()
+    }
+
     pub fn get_current_id(&self) -> ClientId {
         self.current_local
     }
+
+    // Checks that the clients are sorted and that the caches match them
+    pub fn check_integrity(&self) -> Vec<RegistryIntegrityError> {
+        let mut errors = Vec::new();
+
+        if !self
+            .clients
+            .windows(2)
+            .all(|pair| client_order(&pair[0], &pair[1]) == Ordering::Less)
+        {
+            errors.push(RegistryIntegrityError::UnsortedClients);
+        }
+
+        let caches_len = self.clients.len();
+        for (local_id, client) in self.clients.iter().enumerate() {
+            let local_id = local_id as ClientId;
+            if self.get_global_id(local_id) != Some(&client.global_id)
+                || self.get_local_id(&client.global_id) != Some(local_id)
+            {
+                errors.push(RegistryIntegrityError::StaleCache(local_id));
+            }
+        }
+        if self.local_to_global_cache.len() != caches_len
+            || self.global_to_local_cache.len() != caches_len
+        {
+            errors.push(RegistryIntegrityError::UnexpectedCacheEntries);
+        }
+
+        if self.get_global_id(self.current_local) != Some(&self.current_global) {
+            errors.push(RegistryIntegrityError::CurrentClientMismatch);
+        }
+
+        errors
+    }
+}
+
+// Whether the relative order of the remapped clients stays the same. It only changes when
+// the same client was registered with different creation times.
+pub fn preserves_order(remappings: &ClientRemappings) -> bool {
+    let mut remappings: Vec<(&PreviousClientId, &NewClientId)> = remappings.iter().collect();
+    remappings.sort();
+    remappings.windows(2).all(|pair| pair[0].1 < pair[1].1)
+}
+
+fn client_order(a: &GlobalClient, b: &GlobalClient) -> Ordering {
+    a.created_at
+        .cmp(&b.created_at)
+        .then_with(|| a.global_id.cmp(&b.global_id))
 }
 
 impl Serializable for ClientRegistry {
@@ -222,11 +338,28 @@ impl Serializable for ClientRegistry {
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ClientRegistryError {
     #[error("serialization error: {0}")]
     SerializationError(String),
 }
 
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum RegistryIntegrityError {
+    #[error("clients are not sorted by creation time and global id")]
+    UnsortedClients,
+
+    #[error("cached ids of client {0} don't match its position")]
+    StaleCache(ClientId),
+
+    #[error("caches contain clients that are not registered")]
+    UnexpectedCacheEntries,
+
+    #[error("local id of the current client doesn't match its global id")]
+    CurrentClientMismatch,
+}
+
 pub type PreviousClientId = ClientId;
 pub type NewClientId = ClientId;
 pub type ClientRemappings = FxHashMap<PreviousClientId, NewClientId>;
@@ -234,3 +367,77 @@ pub type ClientRemappings = FxHashMap<PreviousClientId, NewClientId>;
 pub trait ClientRemappable {
     fn remap_client_ids(&mut self, mappings: &ClientRemappings);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(global_id: &str, created_at: u64) -> GlobalClient {
+        GlobalClient {
+            created_at,
+            global_id: global_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_integrity_detects_stale_caches() {
+        let mut registry = ClientRegistry::new("b".to_string(), 2);
+        registry.register_clients(&[client("a", 1)]);
+        assert_eq!(registry.check_integrity(), vec![]);
+
+        registry.global_to_local_cache.insert("a".to_string(), 1);
+        registry.local_to_global_cache.insert(2, "c".to_string());
+        assert_eq!(
+            registry.check_integrity(),
+            vec![
+                RegistryIntegrityError::StaleCache(0),
+                RegistryIntegrityError::UnexpectedCacheEntries,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clients_are_sorted_by_creation_time() {
+        let mut registry = ClientRegistry::new("b".to_string(), 2);
+        let remappings = registry
+            .register_clients(&[client("c", 3), client("a", 2)])
+            .unwrap();
+
+        let global_ids: Vec<&str> = registry
+            .get_clients()
+            .iter()
+            .map(|client| client.global_id.as_str())
+            .collect();
+        assert_eq!(global_ids, ["a", "b", "c"]);
+        assert_eq!(registry.get_current_id(), 1);
+        assert_eq!(remappings.get(&0), Some(&1));
+        assert!(preserves_order(&remappings));
+    }
+
+    #[test]
+    fn test_remappings_from_other_registry() {
+        let mut registry = ClientRegistry::new("b".to_string(), 2);
+        let other_clients = [client("a", 1), client("c", 3)];
+        registry.register_clients(&other_clients);
+
+        let remappings = registry.remappings_from(&other_clients).unwrap();
+        assert_eq!(remappings.get(&0), Some(&0));
+        assert_eq!(remappings.get(&1), Some(&2));
+
+        let same_clients = registry.get_clients().to_vec();
+        assert!(registry.remappings_from(&same_clients).is_none());
+    }
+
+    #[test]
+    fn test_earliest_creation_time_is_kept() {
+        let mut registry = ClientRegistry::new("b".to_string(), 5);
+        registry.register_clients(&[client("a", 4)]);
+
+        // The same client, created earlier on another replica, now comes first
+        let remappings = registry.register_clients(&[client("b", 3)]).unwrap();
+        assert_eq!(registry.get_clients()[0], client("b", 3));
+        assert!(!preserves_order(&remappings));
+
+        assert!(registry.register_clients(&[client("b", 4)]).is_none());
+    }
+}
@@ -1,14 +1,46 @@
-use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
 
-use crate::{ClientId, MapBlockId, Selector, SequenceIndex, Timestamp, Value};
+use rustc_hash::{FxHashMap, FxHashSet};
+use thiserror::Error;
 
-use super::{set::BlockSet, shared::MapBlock};
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    ClientId, Conflict, ConflictPolicy, MapBlockId, OperationId, ScalarValue, Selector,
+    SequenceIndex, Timestamp, Value,
+};
+
+use super::{
+    set::BlockSet,
+    shared::{MapBlock, MoveOrigin},
+};
+
+#[cfg(feature = "debug-tools")]
+use crate::debug::{DebugGraph, FormatId};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MapCRDT {
     client: ClientId,
     next_available_sequence: SequenceIndex,
     fields: FxHashMap<Selector, BlockSet>,
+    // Blocks that have been moved to another key, along with the blocks replacing them
+    redirects: FxHashMap<MapBlockId, Vec<Redirect>>,
+    // Placements of objects that have been moved to another location
+    detached: FxHashSet<OperationId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Redirect {
+    from: Selector,
+    to: Selector,
+    target: MapBlockId,
+}
+
+pub struct KeyRevision<'a> {
+    pub id: MapBlockId,
+    pub value: &'a Value,
+    pub timestamp: Timestamp,
+    pub overwritten: bool,
+    pub deleted: bool,
 }
 
 pub struct SetParams {
@@ -17,6 +49,8 @@ pub struct SetParams {
     pub parents: Vec<MapBlockId>,
     pub value: Value,
     pub timestamp: Timestamp,
+    pub placement: Option<OperationId>,
+    pub expires_at: Option<Timestamp>,
 }
 
 pub struct DeleteParams {
@@ -24,12 +58,23 @@ pub struct DeleteParams {
     pub parents: Vec<MapBlockId>,
 }
 
+pub struct MoveParams {
+    pub from: Selector,
+    pub to: Selector,
+    pub id: MapBlockId,
+    pub parents: Vec<MapBlockId>,
+    pub sources: Vec<MapBlockId>,
+    pub timestamp: Timestamp,
+}
+
 impl MapCRDT {
     pub fn new(client: ClientId) -> Self {
         Self {
             client,
             next_available_sequence: 0,
             fields: FxHashMap::default(),
+            redirects: FxHashMap::default(),
+            detached: FxHashSet::default(),
         }
     }
 
@@ -43,17 +88,48 @@ impl MapCRDT {
         }
     }
 
-    pub fn get(&self, key: &Selector) -> Option<&Value> {
+    // Values written with a TTL are hidden once `now` reaches their expiration, as if
+    // they were deleted
+    pub fn get(&self, key: &Selector, now: Timestamp) -> Option<&Value> {
         let field = self.fields.get(key)?;
-        let latest_block = field.get_latest()?;
+        let latest_block = field.get_latest(&self.detached, now)?;
         Some(&latest_block.value)
     }
 
+    pub fn get_with_policy(
+        &self,
+        key: &Selector,
+        policy: &ConflictPolicy,
+        now: Timestamp,
+    ) -> Option<&Value> {
+        let ConflictPolicy::Custom(resolver) = policy else {
+            return self.get(key, now);
+        };
+
+        let conflicts = self.get_visible_conflicts(key, now);
+        if conflicts.len() < 2 {
+            return self.get(key, now);
+        }
+
+        match resolver(key, &conflicts).and_then(|index| conflicts.get(index)) {
+            Some(conflict) => Some(conflict.value),
+            None => self.get(key, now),
+        }
+    }
+
+    // Time at which the visible value of the key expires, if it was written with a TTL
+    pub fn expires_at(&self, key: &Selector, now: Timestamp) -> Option<Timestamp> {
+        let field = self.fields.get(key)?;
+        field.get_latest(&self.detached, now)?.expires_at
+    }
+
     pub fn get_latest_ids(&self, key: &Selector) -> Vec<MapBlockId> {
         if let Some(field) = self.fields.get(key) {
             if let Some(latest_blocks) = field.get_latest_with_conflicts() {
+                // Moved blocks now live under another key, so new writes must not follow them
                 return latest_blocks
                     .iter()
+                    .filter(|block| !block.moved)
                     .map(|block| block.id.clone())
                     .collect::<Vec<MapBlockId>>();
             }
@@ -62,37 +138,289 @@ impl MapCRDT {
         Vec::new()
     }
 
-    pub fn set(&mut self, action: SetParams) {
-        let field = self
-            .fields
-            .entry(action.selector)
-            .or_insert_with(BlockSet::new);
+    pub fn get_conflicts(&self, key: &Selector, now: Timestamp) -> Vec<Conflict<'_>> {
+        let Some(field) = self.fields.get(key) else {
+            return Vec::new();
+        };
+
+        field
+            .iter_conflicts()
+            .map(|(block, supersedes)| Conflict {
+                id: block.id.clone(),
+                value: &block.value,
+                timestamp: block.timestamp,
+                deleted: block.is_deleted()
+                    || block.moved
+                    || block.is_detached(&self.detached)
+                    || block.is_expired(now),
+                supersedes,
+            })
+            .collect()
+    }
+
+    // Every value written to the key, in causal order
+    pub fn key_history(&self, key: &Selector, now: Timestamp) -> Vec<KeyRevision<'_>> {
+        let Some(field) = self.fields.get(key) else {
+            return Vec::new();
+        };
+
+        field
+            .iter_history()
+            .map(|(block, overwritten)| KeyRevision {
+                id: block.id.clone(),
+                value: &block.value,
+                timestamp: block.timestamp,
+                overwritten,
+                deleted: block.is_deleted()
+                    || block.moved
+                    || block.is_detached(&self.detached)
+                    || block.is_expired(now),
+            })
+            .collect()
+    }
+
+    fn get_visible_conflicts(&self, key: &Selector, now: Timestamp) -> Vec<Conflict<'_>> {
+        let mut conflicts = self.get_conflicts(key, now);
+        conflicts.retain(|conflict| !conflict.deleted);
+        conflicts
+    }
+
+    // Number of concurrent writes of a visible key that no later write has resolved yet,
+    // or 0 if the value is not conflicting
+    pub fn conflict_count(&self, key: &Selector, now: Timestamp) -> usize {
+        let Some(field) = self.fields.get(key) else {
+            return 0;
+        };
+        if field.get_latest(&self.detached, now).is_none() {
+            return 0;
+        }
 
+        match field.get_latest_with_conflicts() {
+            Some(latest_blocks) if latest_blocks.len() > 1 => latest_blocks.len(),
+            _ => 0,
+        }
+    }
+
+    // Whether the blocks were all written to the key, including the ones moved away from it
+    pub fn contains_all(&self, key: &Selector, ids: &[MapBlockId]) -> bool {
+        let field = self.fields.get(key);
+        ids.iter().all(|id| {
+            field.is_some_and(|field| field.contains(id)) || self.redirects.contains_key(id)
+        })
+    }
+
+    pub fn set(&mut self, action: SetParams) -> Result<(), MapError> {
         let block = MapBlock {
             id: action.id,
             parents: action.parents,
             value: action.value,
             timestamp: action.timestamp,
             deleted: false,
+            moved: false,
+            origin: None,
+            vacant: false,
+            placement: action.placement,
+            expires_at: action.expires_at,
         };
 
-        field.insert(block);
+        self.insert_block(action.selector, block)
     }
 
-    pub fn delete(&mut self, action: DeleteParams) {
+    pub fn delete(&mut self, action: DeleteParams) -> Result<(), MapError> {
+        let parents = self.resolve_parents(&action.selector, &action.parents);
         let field = self
             .fields
-            .entry(action.selector)
+            .entry(action.selector.clone())
             .or_insert_with(BlockSet::new);
 
-        field.delete(&action.parents);
+        let deleted = field.delete(&parents)?;
+
+        // Deleting a moved value also deletes it from its new key, unless it was replaced
+        for block in deleted {
+            for (to, target) in self.redirect_targets(&action.selector, &block) {
+                self.update_moved_value(to, target)?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn to_map(&self) -> FxHashMap<Selector, &Value> {
+    pub fn move_value(&mut self, action: MoveParams) -> Result<(), MapError> {
+        let sources = self.resolve_parents(&action.from, &action.sources);
+        let field = self
+            .fields
+            .entry(action.from.clone())
+            .or_insert_with(BlockSet::new);
+
+        // Concurrent writes that superseded the sources are moved as well, and so are the
+        // ones received later, see `insert_block`
+        let moved = field.descendants(&sources);
+        field.set_moved(&moved)?;
+
+        for block in moved {
+            self.redirects.entry(block).or_default().push(Redirect {
+                from: action.from.clone(),
+                to: action.to.clone(),
+                target: action.id.clone(),
+            });
+        }
+
+        let block = MapBlock {
+            id: action.id,
+            parents: action.parents,
+            deleted: false,
+            value: Value::Scalar(ScalarValue::Null),
+            timestamp: action.timestamp,
+            moved: false,
+            origin: Some(MoveOrigin {
+                from: action.from,
+                sources,
+            }),
+            vacant: true,
+            placement: None,
+            expires_at: None,
+        };
+
+        self.insert_block(action.to, block)
+    }
+
+    pub fn set_detached(&mut self, placement: OperationId, detached: bool) {
+        if detached {
+            self.detached.insert(placement);
+        } else {
+            self.detached.remove(&placement);
+        }
+    }
+
+    fn insert_block(&mut self, selector: Selector, mut block: MapBlock) -> Result<(), MapError> {
+        block.parents = self.resolve_parents(&selector, &block.parents);
+
+        // A write superseding a moved block is moved along with it
+        let mut targets: Vec<(Selector, MapBlockId)> = Vec::new();
+        for parent in &block.parents {
+            for target in self.redirect_targets(&selector, parent) {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        block.moved = !targets.is_empty();
+
+        // When the map is rebuilt from the log, local ids must not be reused
+        if block.id.client_id == self.client {
+            self.next_available_sequence = self.next_available_sequence.max(block.id.sequence + 1);
+        }
+
+        let id = block.id.clone();
+        let is_move = block.origin.is_some();
+        self.fields
+            .entry(selector.clone())
+            .or_insert_with(BlockSet::new)
+            .insert(block)?;
+        if is_move {
+            self.update_moved_value(selector.clone(), id.clone())?;
+        }
+
+        for (to, target) in targets {
+            self.redirects
+                .entry(id.clone())
+                .or_default()
+                .push(Redirect {
+                    from: selector.clone(),
+                    to: to.clone(),
+                    target: target.clone(),
+                });
+            self.update_moved_value(to, target)?;
+        }
+
+        Ok(())
+    }
+
+    // A move holds the latest value among the blocks it moved, which is updated when they
+    // are replaced or deleted, so that it doesn't depend on the order in which the writes
+    // were received. The moves that took the block further are then updated as well.
+    fn update_moved_value(&mut self, selector: Selector, id: MapBlockId) -> Result<(), MapError> {
+        let mut to_update = vec![(selector, id)];
+        while let Some((selector, id)) = to_update.pop() {
+            let field = self.field(&selector, &id)?;
+            let Some(origin) = &field.get(&id)?.origin else {
+                continue;
+            };
+
+            let from = self.field(&origin.from, &id)?;
+            let moved = from.descendants(&origin.sources);
+            let latest = from
+                .get_latest_among(&moved)?
+                .map(|block| (block.value.clone(), block.placement, block.expires_at));
+
+            let block = self
+                .fields
+                .get_mut(&selector)
+                .expect("the field was found above")
+                .get_mut(&id)?;
+            // Moved values keep their expiration
+            block.vacant = latest.is_none();
+            (block.value, block.placement, block.expires_at) =
+                latest.unwrap_or((Value::Scalar(ScalarValue::Null), None, None));
+
+            to_update.extend(self.redirect_targets(&selector, &id));
+        }
+
+        Ok(())
+    }
+
+    fn field(&self, selector: &Selector, id: &MapBlockId) -> Result<&BlockSet, MapError> {
+        self.fields
+            .get(selector)
+            .ok_or_else(|| MapError::UnknownBlock(id.clone()))
+    }
+
+    fn redirect_targets(&self, from: &Selector, block: &MapBlockId) -> Vec<(Selector, MapBlockId)> {
+        self.redirects
+            .get(block)
+            .into_iter()
+            .flatten()
+            .filter(|redirect| redirect.from == *from)
+            .map(|redirect| (redirect.to.clone(), redirect.target.clone()))
+            .collect()
+    }
+
+    // Parents might refer to blocks that have been moved to the given key from another
+    // one, in which case they are replaced by the blocks that took their place.
+    fn resolve_parents(&self, selector: &Selector, parents: &[MapBlockId]) -> Vec<MapBlockId> {
+        let mut resolved = Vec::new();
+        let mut visited = FxHashSet::default();
+
+        let mut to_resolve: VecDeque<MapBlockId> = parents.iter().cloned().collect();
+        while let Some(parent) = to_resolve.pop_front() {
+            if !visited.insert(parent.clone()) {
+                continue;
+            }
+
+            let is_present = self
+                .fields
+                .get(selector)
+                .is_some_and(|field| field.contains(&parent));
+            if is_present {
+                if !resolved.contains(&parent) {
+                    resolved.push(parent);
+                }
+            } else if let Some(redirects) = self.redirects.get(&parent) {
+                to_resolve.extend(redirects.iter().map(|redirect| redirect.target.clone()));
+            } else {
+                // Keep unknown parents, so that inconsistencies are not silently ignored
+                resolved.push(parent);
+            }
+        }
+
+        resolved
+    }
+
+    pub fn to_map(&self, now: Timestamp) -> FxHashMap<Selector, &Value> {
         let mut map = FxHashMap::default();
 
         for (selector, field) in &self.fields {
-            let latest_block = field.get_latest();
+            let latest_block = field.get_latest(&self.detached, now);
 
             if let Some(latest_block) = latest_block {
                 map.insert(selector.clone(), &latest_block.value);
@@ -102,9 +430,116 @@ impl MapCRDT {
         map
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Selector, &Value)> {
-        self.fields.iter().filter_map(|(selector, field)| {
-            field.get_latest().map(|block| (selector, &block.value))
+    // Number of values written to the keys of the map (overwritten and deleted ones included),
+    // along with the number of deleted ones
+    pub fn value_counts(&self) -> (usize, usize) {
+        self.fields
+            .values()
+            .fold((0, 0), |(values, deleted), field| {
+                (
+                    values + field.block_count(),
+                    deleted + field.deleted_count(),
+                )
+            })
+    }
+
+    // One node per block, linked to the blocks it replaced, grouped by key
+    #[cfg(feature = "debug-tools")]
+    pub fn render_debug_graph(&self, graph: &mut DebugGraph, prefix: &str, format_id: FormatId) {
+        let mut fields: Vec<(String, &BlockSet)> = self
+            .fields
+            .iter()
+            .map(|(selector, field)| match selector {
+                Selector::Key(key) => (format!("{:?}", key), field),
+                Selector::Index(index) => (format!("[{}]", index), field),
+            })
+            .collect();
+        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let node_id = |id: &MapBlockId| format!("{}_{}_{}", prefix, id.client_id, id.sequence);
+        for (key, field) in fields {
+            for block in field.iter() {
+                let value = match &block.value {
+                    Value::Scalar(value) => format!("{:?}", value),
+                    Value::Object(object) => format_id(object),
+                };
+                let mut label = format!("{}: {} = {}", format_id(&block.id), key, value);
+                if block.is_deleted() {
+                    label.push_str(" (deleted)");
+                }
+                if block.moved {
+                    label.push_str(" (moved)");
+                }
+                graph.node(node_id(&block.id), label);
+
+                for parent in &block.parents {
+                    graph.edge(node_id(parent), node_id(&block.id));
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self, now: Timestamp) -> impl Iterator<Item = (&Selector, &Value)> {
+        self.fields.iter().filter_map(move |(selector, field)| {
+            field
+                .get_latest(&self.detached, now)
+                .map(|block| (selector, &block.value))
         })
     }
+
+    // Drops the values that expired before `now`, keeping the blocks themselves, as later
+    // writes might still refer to them. Returns the number of cleared values.
+    pub fn clear_expired_values(&mut self, now: Timestamp) -> usize {
+        self.fields
+            .values_mut()
+            .map(|field| field.clear_expired_values(now))
+            .sum()
+    }
+
+    pub fn has_expired_values(&self, now: Timestamp) -> bool {
+        self.fields
+            .values()
+            .any(|field| field.has_expired_values(now))
+    }
+
+    // Whether any value was written with a TTL, in which case the contents depend on the time
+    pub fn has_expiring_values(&self) -> bool {
+        self.fields.values().any(BlockSet::has_expiring_values)
+    }
+}
+
+impl ClientRemappable for MapCRDT {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.client = *mappings.get(&self.client).expect("client ID not found");
+
+        for field in self.fields.values_mut() {
+            field.remap_client_ids(mappings);
+        }
+
+        self.redirects = std::mem::take(&mut self.redirects)
+            .into_iter()
+            .map(|(mut block, mut redirects)| {
+                block.remap_client_ids(mappings);
+                for redirect in redirects.iter_mut() {
+                    redirect.target.remap_client_ids(mappings);
+                }
+                (block, redirects)
+            })
+            .collect();
+
+        self.detached = std::mem::take(&mut self.detached)
+            .into_iter()
+            .map(|mut placement| {
+                placement.remap_client_ids(mappings);
+                placement
+            })
+            .collect();
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum MapError {
+    #[error("block {0:?} not found")]
+    UnknownBlock(MapBlockId),
 }
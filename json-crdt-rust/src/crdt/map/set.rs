@@ -1,8 +1,13 @@
-use rustc_hash::FxHashMap;
+use std::cmp::Ordering;
 
-use crate::MapBlockId;
+use rustc_hash::{FxHashMap, FxHashSet};
 
-use super::shared::MapBlock;
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    MapBlockId, OperationId, ScalarValue, Timestamp, Value,
+};
+
+use super::{map::MapError, shared::MapBlock};
 
 type BlockIndex = usize;
 
@@ -22,71 +27,252 @@ impl BlockSet {
         }
     }
 
-    pub fn insert(&mut self, block: MapBlock) {
-        let index = self.blocks.len();
-        self.blocks.push(block);
+    pub fn insert(&mut self, block: MapBlock) -> Result<(), MapError> {
+        let parent_indexes = block
+            .parents
+            .iter()
+            .map(|parent| self.index_of(parent))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let block = &self.blocks[index];
+        let index = self.blocks.len();
         self.id_to_index.insert(block.id.clone(), index);
+        self.blocks.push(block);
 
         // Initialize children
         self.block_children.entry(index).or_insert_with(Vec::new);
 
-        for parent in &block.parents {
-            let parent_index = self.id_to_index[&parent];
+        for parent_index in parent_indexes {
             self.block_children
                 .entry(parent_index)
                 .or_insert_with(Vec::new)
                 .push(index);
         }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &MapBlockId) -> Result<&MapBlock, MapError> {
+        Ok(&self.blocks[self.index_of(id)?])
+    }
+
+    pub fn get_mut(&mut self, id: &MapBlockId) -> Result<&mut MapBlock, MapError> {
+        let index = self.index_of(id)?;
+        Ok(&mut self.blocks[index])
+    }
+
+    fn index_of(&self, id: &MapBlockId) -> Result<BlockIndex, MapError> {
+        self.id_to_index
+            .get(id)
+            .copied()
+            .ok_or_else(|| MapError::UnknownBlock(id.clone()))
+    }
+
+    #[cfg(feature = "debug-tools")]
+    pub fn iter(&self) -> impl Iterator<Item = &MapBlock> {
+        self.blocks.iter()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn deleted_count(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| block.is_deleted())
+            .count()
+    }
+
+    pub fn contains(&self, id: &MapBlockId) -> bool {
+        self.id_to_index.contains_key(id)
+    }
+
+    // Returns the blocks that were not already deleted
+    pub fn delete(&mut self, blocks: &[MapBlockId]) -> Result<Vec<MapBlockId>, MapError> {
+        let mut deleted = Vec::new();
+
+        for block in blocks {
+            let block = self.get_mut(block)?;
+            if !block.deleted {
+                block.deleted = true;
+                deleted.push(block.id.clone());
+            }
+        }
+
+        Ok(deleted)
     }
 
-    pub fn delete(&mut self, blocks: &[MapBlockId]) {
+    pub fn set_moved(&mut self, blocks: &[MapBlockId]) -> Result<(), MapError> {
         for block in blocks {
-            let block_index = self.id_to_index[block];
-            self.blocks[block_index].deleted = true;
+            self.get_mut(block)?.moved = true;
         }
+        Ok(())
     }
 
-    pub fn get_latest_with_conflicts(&self) -> Option<Vec<&MapBlock>> {
-        let block_indexes_without_children: Vec<BlockIndex> = self
-            .block_children
+    // The given blocks, along with all the blocks that superseded them
+    pub fn descendants(&self, blocks: &[MapBlockId]) -> Vec<MapBlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut to_visit: Vec<BlockIndex> = blocks
             .iter()
-            .filter(|(_, children)| children.is_empty())
-            .map(|(index, _)| *index)
+            .filter_map(|block| self.id_to_index.get(block).copied())
             .collect();
+        let mut descendants = Vec::new();
+
+        while let Some(index) = to_visit.pop() {
+            if visited[index] {
+                continue;
+            }
+            visited[index] = true;
+            descendants.push(self.blocks[index].id.clone());
+            to_visit.extend(&self.block_children[&index]);
+        }
+
+        descendants
+    }
+
+    // The winning block among the heads included in the given blocks
+    pub fn get_latest_among(&self, blocks: &[MapBlockId]) -> Result<Option<&MapBlock>, MapError> {
+        let mut latest: Option<&MapBlock> = None;
+        for block in blocks {
+            let index = self.index_of(block)?;
+            let block = &self.blocks[index];
+            if !self.block_children[&index].is_empty() || block.is_deleted() {
+                continue;
+            }
+            if latest.is_none_or(|latest| canonical_order(block, latest).is_gt()) {
+                latest = Some(block);
+            }
+        }
+        Ok(latest)
+    }
 
-        let blocks_without_children: Vec<&MapBlock> = block_indexes_without_children
+    pub fn get_latest_with_conflicts(&self) -> Option<Vec<&MapBlock>> {
+        // Blocks with children have been superseded by a later write, so only
+        // the "heads" of the history can be part of a conflict.
+        let mut blocks_without_children: Vec<&MapBlock> = self
+            .block_children
             .iter()
-            .map(|index| &self.blocks[*index])
+            .filter(|(_, children)| children.is_empty())
+            .map(|(index, _)| &self.blocks[*index])
             .collect();
 
         if blocks_without_children.is_empty() {
             None
         } else {
+            // Sorting by the canonical order keeps the result stable, regardless
+            // of the order in which the blocks were received
+            blocks_without_children.sort_by(|a, b| canonical_order(a, b));
             Some(blocks_without_children)
         }
     }
 
-    pub fn get_latest(&self) -> Option<&MapBlock> {
-        let mut latest = self.get_latest_with_conflicts()?;
-
-        latest.sort_by(|a, b| {
-            if a.id.client_id == b.id.client_id {
-                a.id.sequence.cmp(&b.id.sequence)
-            } else if a.timestamp == b.timestamp {
-                a.id.client_id.cmp(&b.id.client_id)
-            } else {
-                a.timestamp.cmp(&b.timestamp)
-            }
-        });
+    pub fn get_latest(
+        &self,
+        detached: &FxHashSet<OperationId>,
+        now: Timestamp,
+    ) -> Option<&MapBlock> {
+        let latest = self.get_latest_with_conflicts()?;
 
         for block in latest.iter().rev() {
-            if !block.deleted {
+            if !block.is_deleted()
+                && !block.moved
+                && !block.is_detached(detached)
+                && !block.is_expired(now)
+            {
                 return Some(block);
             }
         }
 
         None
     }
+
+    pub fn clear_expired_values(&mut self, now: Timestamp) -> usize {
+        let mut cleared = 0;
+        for block in self.blocks.iter_mut() {
+            if holds_expired_value(block, now) {
+                block.value = Value::Scalar(ScalarValue::Null);
+                cleared += 1;
+            }
+        }
+        cleared
+    }
+
+    pub fn has_expired_values(&self, now: Timestamp) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| holds_expired_value(block, now))
+    }
+
+    pub fn has_expiring_values(&self) -> bool {
+        self.blocks.iter().any(|block| block.expires_at.is_some())
+    }
+
+    // Every block in causal order, along with whether a later write superseded it.
+    // Concurrent blocks follow the canonical order, so every replica lists them the same way.
+    pub fn iter_history(&self) -> impl Iterator<Item = (&MapBlock, bool)> {
+        let mut pending_parents: Vec<usize> = self
+            .blocks
+            .iter()
+            .map(|block| block.parents.len())
+            .collect();
+        let mut ready: Vec<BlockIndex> = (0..self.blocks.len())
+            .filter(|index| pending_parents[*index] == 0)
+            .collect();
+        let mut history = Vec::with_capacity(self.blocks.len());
+
+        while !ready.is_empty() {
+            let (position, _) = ready
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| canonical_order(&self.blocks[**a], &self.blocks[**b]))
+                .expect("ready blocks are not empty");
+            let index = ready.swap_remove(position);
+
+            let children = &self.block_children[&index];
+            history.push((&self.blocks[index], !children.is_empty()));
+            for child in children {
+                pending_parents[*child] -= 1;
+                if pending_parents[*child] == 0 {
+                    ready.push(*child);
+                }
+            }
+        }
+
+        history.into_iter()
+    }
+
+    pub fn iter_conflicts(&self) -> impl Iterator<Item = (&MapBlock, &[MapBlockId])> {
+        self.get_latest_with_conflicts()
+            .into_iter()
+            .flatten()
+            .map(|block| (block, block.parents.as_slice()))
+    }
+}
+
+// Children are tracked by index, so only the ids need to be rewritten
+impl ClientRemappable for BlockSet {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.id_to_index.clear();
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            block.remap_client_ids(mappings);
+            self.id_to_index.insert(block.id.clone(), index);
+        }
+    }
+}
+
+// Expired values are replaced by null once cleared
+fn holds_expired_value(block: &MapBlock, now: Timestamp) -> bool {
+    block.is_expired(now) && block.value != Value::Scalar(ScalarValue::Null)
+}
+
+// Total order used to pick a winner among concurrent blocks, the last one wins.
+// Client IDs are ordered the same way on every replica, see `ClientRegistry`.
+fn canonical_order(a: &MapBlock, b: &MapBlock) -> Ordering {
+    if a.id.client_id == b.id.client_id {
+        a.id.sequence.cmp(&b.id.sequence)
+    } else if a.timestamp == b.timestamp {
+        a.id.client_id.cmp(&b.id.client_id)
+    } else {
+        a.timestamp.cmp(&b.timestamp)
+    }
 }
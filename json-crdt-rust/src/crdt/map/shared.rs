@@ -1,4 +1,9 @@
-use crate::{MapBlockId, Timestamp, Value};
+use rustc_hash::FxHashSet;
+
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    MapBlockId, OperationId, Selector, Timestamp, Value,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MapBlock {
@@ -7,4 +12,54 @@ pub struct MapBlock {
     pub value: Value,
     pub timestamp: Timestamp,
     pub deleted: bool,
+    // The block has been moved to another key
+    pub moved: bool,
+    // For blocks created by a move, where their value comes from. The value is updated when
+    // the moved blocks are replaced or deleted, see `MapCRDT::update_moved_value`.
+    pub origin: Option<MoveOrigin>,
+    // The moved blocks were all deleted
+    pub vacant: bool,
+    // For blocks holding an object, the operation that placed the object here
+    pub placement: Option<OperationId>,
+    pub expires_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveOrigin {
+    pub from: Selector,
+    pub sources: Vec<MapBlockId>,
+}
+
+impl MapBlock {
+    // The object held by the block has been placed somewhere else
+    pub fn is_detached(&self, detached: &FxHashSet<OperationId>) -> bool {
+        self.placement
+            .is_some_and(|placement| detached.contains(&placement))
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted || self.vacant
+    }
+}
+
+impl ClientRemappable for MapBlock {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.id.remap_client_ids(mappings);
+        for parent in self.parents.iter_mut() {
+            parent.remap_client_ids(mappings);
+        }
+        self.value.remap_client_ids(mappings);
+        if let Some(placement) = self.placement.as_mut() {
+            placement.remap_client_ids(mappings);
+        }
+        if let Some(origin) = self.origin.as_mut() {
+            for source in origin.sources.iter_mut() {
+                source.remap_client_ids(mappings);
+            }
+        }
+    }
 }
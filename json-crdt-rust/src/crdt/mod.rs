@@ -1,3 +1,3 @@
 pub(crate) mod map;
-mod shared;
+pub(crate) mod shared;
 pub(crate) mod text;
@@ -0,0 +1,200 @@
+use std::ops::Index;
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    ClientId, SequenceBlockId, SequenceIndex,
+};
+
+use super::tree::NodeIndex;
+
+const NO_BLOCK: NodeIndex = NodeIndex::MAX;
+const PAGE_LEN: usize = 256;
+
+type Page = Box<[NodeIndex; PAGE_LEN]>;
+
+// Leaf that contains each block, by the id of its first item. Sequences of a client are
+// allocated with few gaps, so they are stored in pages indexed by sequence instead of
+// hashing each id, which is also smaller for the long runs typed by a single client.
+// Only the pages with blocks are allocated, as remote ids can start at any sequence.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct BlockLocations {
+    clients: FxHashMap<ClientId, FxHashMap<SequenceIndex, Page>>,
+}
+
+fn page_of(sequence: SequenceIndex) -> (SequenceIndex, usize) {
+    (
+        sequence / PAGE_LEN as SequenceIndex,
+        sequence as usize % PAGE_LEN,
+    )
+}
+
+impl BlockLocations {
+    pub fn get(&self, id: &SequenceBlockId) -> Option<&NodeIndex> {
+        let (page, offset) = page_of(id.sequence);
+        self.clients
+            .get(&id.client_id)?
+            .get(&page)
+            .map(|nodes| &nodes[offset])
+            .filter(|node_index| **node_index != NO_BLOCK)
+    }
+
+    // Closest block of the same client starting at the id or at most `max_distance` items
+    // before it, scanning the pages of the client backwards
+    pub fn find_at_or_before(
+        &self,
+        id: &SequenceBlockId,
+        max_distance: u32,
+    ) -> Option<(SequenceBlockId, NodeIndex)> {
+        let pages = self.clients.get(&id.client_id)?;
+        let lowest_sequence = id.sequence.saturating_sub(max_distance);
+        let (lowest_page, lowest_offset) = page_of(lowest_sequence);
+        let (highest_page, highest_offset) = page_of(id.sequence);
+
+        (lowest_page..=highest_page).rev().find_map(|page| {
+            let nodes = pages.get(&page)?;
+            let start = if page == lowest_page {
+                lowest_offset
+            } else {
+                0
+            };
+            let end = if page == highest_page {
+                highest_offset
+            } else {
+                PAGE_LEN - 1
+            };
+            let offset = start
+                + nodes[start..=end]
+                    .iter()
+                    .rposition(|node_index| *node_index != NO_BLOCK)?;
+            let sequence = page * PAGE_LEN as SequenceIndex + offset as SequenceIndex;
+            Some((SequenceBlockId::new(id.client_id, sequence), nodes[offset]))
+        })
+    }
+
+    pub fn contains_key(&self, id: &SequenceBlockId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn insert(&mut self, id: SequenceBlockId, node_index: NodeIndex) -> Option<NodeIndex> {
+        let (page, offset) = page_of(id.sequence);
+        let nodes = self
+            .clients
+            .entry(id.client_id)
+            .or_default()
+            .entry(page)
+            .or_insert_with(|| Box::new([NO_BLOCK; PAGE_LEN]));
+
+        let previous = std::mem::replace(&mut nodes[offset], node_index);
+        (previous != NO_BLOCK).then_some(previous)
+    }
+
+    pub fn remove(&mut self, id: &SequenceBlockId) -> Option<NodeIndex> {
+        let (page, offset) = page_of(id.sequence);
+        let slot = &mut self.clients.get_mut(&id.client_id)?.get_mut(&page)?[offset];
+        let previous = std::mem::replace(slot, NO_BLOCK);
+        (previous != NO_BLOCK).then_some(previous)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SequenceBlockId, NodeIndex)> + '_ {
+        self.clients.iter().flat_map(|(client_id, pages)| {
+            pages.iter().flat_map(move |(page, nodes)| {
+                nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, node_index)| **node_index != NO_BLOCK)
+                    .map(move |(offset, node_index)| {
+                        let sequence = page * PAGE_LEN as SequenceIndex + offset as SequenceIndex;
+                        (SequenceBlockId::new(*client_id, sequence), *node_index)
+                    })
+            })
+        })
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+}
+
+impl Index<&SequenceBlockId> for BlockLocations {
+    type Output = NodeIndex;
+
+    fn index(&self, id: &SequenceBlockId) -> &NodeIndex {
+        self.get(id).expect("block should have a location")
+    }
+}
+
+impl ClientRemappable for BlockLocations {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.clients = std::mem::take(&mut self.clients)
+            .into_iter()
+            .map(|(client_id, pages)| {
+                let new_client_id = mappings.get(&client_id).expect("client ID not found");
+                (*new_client_id, pages)
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_remove_locations() {
+        let mut locations = BlockLocations::default();
+        assert_eq!(locations.get(&SequenceBlockId::new(0, 3)), None);
+
+        assert_eq!(locations.insert(SequenceBlockId::new(0, 3), 7), None);
+        assert_eq!(locations.insert(SequenceBlockId::new(1, 0), 2), None);
+        assert_eq!(locations.insert(SequenceBlockId::new(0, 3), 8), Some(7));
+        assert_eq!(locations.len(), 2);
+
+        assert_eq!(locations.get(&SequenceBlockId::new(0, 3)), Some(&8));
+        assert_eq!(locations[&SequenceBlockId::new(1, 0)], 2);
+        assert!(!locations.contains_key(&SequenceBlockId::new(0, 2)));
+        assert!(!locations.contains_key(&SequenceBlockId::new(0, 100)));
+
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, 5), 2),
+            Some((SequenceBlockId::new(0, 3), 8))
+        );
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, 100), 50),
+            None
+        );
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, 5), 1),
+            None
+        );
+
+        assert_eq!(locations.remove(&SequenceBlockId::new(0, 3)), Some(8));
+        assert_eq!(locations.remove(&SequenceBlockId::new(0, 3)), None);
+        assert_eq!(locations.len(), 1);
+    }
+
+    #[test]
+    fn test_locations_of_distant_sequences() {
+        let mut locations = BlockLocations::default();
+        assert_eq!(locations.insert(SequenceBlockId::new(0, u32::MAX), 1), None);
+        assert_eq!(locations.insert(SequenceBlockId::new(0, 250), 2), None);
+        assert_eq!(locations.clients[&0].len(), 2);
+
+        assert_eq!(locations[&SequenceBlockId::new(0, u32::MAX)], 1);
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, 700), 500),
+            Some((SequenceBlockId::new(0, 250), 2))
+        );
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, 700), 400),
+            None
+        );
+        assert_eq!(
+            locations.find_at_or_before(&SequenceBlockId::new(0, u32::MAX), u32::MAX),
+            Some((SequenceBlockId::new(0, u32::MAX), 1))
+        );
+        assert_eq!(locations.len(), 2);
+    }
+}
@@ -1 +1,2 @@
+pub(crate) mod locations;
 pub(crate) mod tree;
@@ -1,24 +1,47 @@
-use std::collections::VecDeque;
-
 use enum_as_inner::EnumAsInner;
 use heapless::Vec as StackVec;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
+use thiserror::Error;
+
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    SequenceBlockId,
+};
+
+use super::locations::BlockLocations;
 
-use crate::SequenceBlockId;
+#[cfg(feature = "debug-tools")]
+use crate::debug::DebugGraph;
 
 #[derive(Clone, PartialEq)]
 pub struct SequenceTree<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize> {
     blocks: Vec<SequenceBlock<Items>>,
+    // Shared by the items of every block, see `Sizable::Storage`
+    storage: Items::Storage,
 
     nodes: Vec<Node<BRANCH_SIZE, LEAF_SIZE>>,
     root: NodeIndex,
     start: NodeIndex,
     end: NodeIndex,
+    // Nodes left unused after merging leaves, reused by the next splits
+    free_nodes: Vec<NodeIndex>,
 
-    // TODO: optimize to id_to_node: FxHashMap<ClientId, Vec<NodeIndex>>,
+    // Blocks inserted after each item, keyed by the id of the item. Continuations (the next
+    // item of the same client) are not included, as they always come after the other children.
     block_children: FxHashMap<SequenceBlockId, Vec<SequenceBlockId>>,
     root_blocks: Vec<SequenceBlockId>,
-    sequence_id_to_node: FxHashMap<SequenceBlockId, NodeIndex>,
+    // Blocks that are in `block_children` or `root_blocks`, so that continuations can be told
+    // apart from a later insertion of the same client somewhere else
+    non_continuations: FxHashSet<SequenceBlockId>,
+    sequence_id_to_node: BlockLocations,
+    // Results of `find_latest_descendent` by the item they were searched from, and the
+    // other way around. They are updated when an insertion extends the subtrees.
+    latest_descendents: FxHashMap<SequenceBlockId, SequenceBlockId>,
+    latest_descendent_sources: FxHashMap<SequenceBlockId, Vec<SequenceBlockId>>,
+    // Upper bound on the length of the blocks, used to limit the search of containing blocks
+    max_block_len: u32,
+    // Insertions are not merged into blocks that would grow past this length
+    block_len_limit: u32,
 }
 
 impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
@@ -29,17 +52,29 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
 
         Self {
             blocks: Vec::new(),
+            storage: Items::Storage::default(),
             nodes,
             root: 0,
             start: 0,
             end: 0,
+            free_nodes: Vec::new(),
             block_children: FxHashMap::default(),
             root_blocks: Vec::new(),
-            sequence_id_to_node: FxHashMap::default(),
+            non_continuations: FxHashSet::default(),
+            sequence_id_to_node: BlockLocations::default(),
+            latest_descendents: FxHashMap::default(),
+            latest_descendent_sources: FxHashMap::default(),
+            max_block_len: 0,
+            block_len_limit: u32::MAX,
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Items> {
+    // Only affects the following insertions, existing blocks are left as they are
+    pub fn set_block_len_limit(&mut self, limit: u32) {
+        self.block_len_limit = limit;
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Items> {
         // println!(
         //     "sizeof SequenceBlockId {}",
         //     std::mem::size_of::<SequenceBlockId>()
@@ -54,9 +89,271 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         // println!("block_children {}", self.block_children.len());
         // println!("sequence_id_to_node {}", self.sequence_id_to_node.len());
 
+        SequenceTreeIterator::new(self).map(|block| &block.items)
+    }
+
+    pub fn storage(&self) -> &Items::Storage {
+        &self.storage
+    }
+
+    // Items created for this tree can refer to the storage, so it can only be extended or
+    // rebuilt along with them, see `for_each_items_mut`
+    pub fn storage_mut(&mut self) -> &mut Items::Storage {
+        &mut self.storage
+    }
+
+    // Calls `action` on the items of every block (deleted ones included), in sequence order
+    pub fn for_each_items_mut(&mut self, mut action: impl FnMut(&mut Items)) {
+        let mut current_leaf = Some(self.start);
+        while let Some(node_index) = current_leaf {
+            let leaf_node = self.nodes[node_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+            for block_index in leaf_node.items.iter() {
+                action(&mut self.blocks[*block_index].items);
+            }
+            current_leaf = leaf_node.next_block;
+        }
+    }
+
+    // Calls `action` on the items of every deleted block, in no particular order
+    pub fn for_each_deleted(&mut self, mut action: impl FnMut(&mut Items)) {
+        for block in self.blocks.iter_mut().filter(|block| block.deleted) {
+            action(&mut block.items);
+        }
+    }
+
+    // Every block (deleted ones included), in sequence order
+    pub fn iter_all_blocks(&self) -> impl Iterator<Item = &SequenceBlock<Items>> {
+        let leaf = |node_index: NodeIndex| {
+            self.nodes[node_index as usize]
+                .as_leaf()
+                .expect("not a leaf")
+        };
+        std::iter::successors(Some(self.start), move |node_index| {
+            leaf(*node_index).next_block
+        })
+        .flat_map(move |node_index| {
+            leaf(node_index)
+                .items
+                .iter()
+                .map(|block_index| &self.blocks[*block_index])
+        })
+    }
+
+    // Visible blocks, in sequence order
+    pub fn iter_blocks(&self) -> impl DoubleEndedIterator<Item = &SequenceBlock<Items>> {
         SequenceTreeIterator::new(self)
     }
 
+    // Visible blocks starting from the one that contains the given position, along with the
+    // offset of the position in that block. The branches are used to skip the previous blocks.
+    pub fn iter_blocks_from(
+        &self,
+        position: u32,
+    ) -> (u32, SequenceTreeIterator<'_, Items, BRANCH_SIZE, LEAF_SIZE>) {
+        let mut current_node_index = self.root;
+        let mut current_position = 0;
+
+        while let Node::Branch(branch_node) = &self.nodes[current_node_index as usize] {
+            let branch = branch_node.items.iter().find(|branch| {
+                if current_position + branch.total_size > position {
+                    true
+                } else {
+                    current_position += branch.total_size;
+                    false
+                }
+            });
+
+            match branch {
+                Some(branch) => current_node_index = branch.node,
+                None => return (0, SequenceTreeIterator::at_end(self)),
+            }
+        }
+
+        let mut current_leaf = Some(current_node_index);
+        while let Some(node_index) = current_leaf {
+            let leaf_node = self.nodes[node_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+
+            for (index, block_index) in leaf_node.items.iter().enumerate() {
+                let block = &self.blocks[*block_index];
+                if block.deleted {
+                    continue;
+                }
+
+                let block_size = block.items.len() as u32;
+                if current_position + block_size > position {
+                    let iterator = SequenceTreeIterator::starting_at(self, node_index, index);
+                    return (position - current_position, iterator);
+                }
+
+                current_position += block_size;
+            }
+
+            current_leaf = leaf_node.next_block;
+        }
+
+        (0, SequenceTreeIterator::at_end(self))
+    }
+
+    // Number of blocks, deleted ones included
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Number of deleted blocks, along with the number of items they hold
+    pub fn tombstones(&self) -> (usize, usize) {
+        self.blocks
+            .iter()
+            .filter(|block| block.deleted)
+            .fold((0, 0), |(count, len), block| {
+                (count + 1, len + block.items.len())
+            })
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len() - self.free_nodes.len()
+    }
+
+    // Number of visible (non deleted) items in the sequence
+    pub fn len(&self) -> u32 {
+        match &self.nodes[self.root as usize] {
+            Node::Branch(branch_node) => branch_node
+                .items
+                .iter()
+                .map(|branch| branch.total_size)
+                .sum(),
+            Node::Leaf(leaf_node) => leaf_node
+                .items
+                .iter()
+                .map(|block_index| &self.blocks[*block_index])
+                .filter(|block| !block.deleted)
+                .map(|block| block.items.len() as u32)
+                .sum(),
+        }
+    }
+
+    // Number of visible (non deleted) chars in the sequence
+    pub fn len_chars(&self) -> u32 {
+        match &self.nodes[self.root as usize] {
+            Node::Branch(branch_node) => branch_node
+                .items
+                .iter()
+                .map(|branch| branch.total_chars)
+                .sum(),
+            Node::Leaf(_) => self.get_total_chars_for_node(self.root),
+        }
+    }
+
+    // Converts a char index into a position, which is at most the length of the sequence
+    pub fn char_to_position(&self, char_index: u32) -> Option<u32> {
+        let mut current_node_index = self.root;
+        let mut current_chars = 0;
+        let mut current_position = 0;
+
+        while let Node::Branch(branch_node) = &self.nodes[current_node_index as usize] {
+            let branch = branch_node.items.iter().find(|branch| {
+                if current_chars + branch.total_chars > char_index {
+                    true
+                } else {
+                    current_chars += branch.total_chars;
+                    current_position += branch.total_size;
+                    false
+                }
+            });
+
+            match branch {
+                Some(branch) => current_node_index = branch.node,
+                None if current_chars == char_index => return Some(current_position),
+                None => return None,
+            }
+        }
+
+        let mut current_leaf = Some(current_node_index);
+        while let Some(node_index) = current_leaf {
+            let leaf_node = self.nodes[node_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+
+            for block_index in leaf_node.items.iter() {
+                let block = &self.blocks[*block_index];
+                if block.deleted {
+                    continue;
+                }
+
+                let block_chars = block.items.char_len() as u32;
+                if current_chars + block_chars > char_index {
+                    let offset = block
+                        .items
+                        .char_offset(&self.storage, (char_index - current_chars) as usize);
+                    return Some(current_position + offset as u32);
+                }
+
+                current_chars += block_chars;
+                current_position += block.items.len() as u32;
+            }
+
+            current_leaf = leaf_node.next_block;
+        }
+
+        if current_chars == char_index {
+            Some(current_position)
+        } else {
+            None
+        }
+    }
+
+    // Whether the position falls between two items, and not inside a multi-byte one
+    pub fn is_boundary(&self, position: u32) -> bool {
+        let mut current_node_index = self.root;
+        let mut current_position = 0;
+
+        while let Node::Branch(branch_node) = &self.nodes[current_node_index as usize] {
+            let branch = branch_node.items.iter().find(|branch| {
+                if current_position + branch.total_size > position {
+                    true
+                } else {
+                    current_position += branch.total_size;
+                    false
+                }
+            });
+
+            match branch {
+                Some(branch) => current_node_index = branch.node,
+                None => return true,
+            }
+        }
+
+        let mut current_leaf = Some(current_node_index);
+        while let Some(node_index) = current_leaf {
+            let leaf_node = self.nodes[node_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+
+            for block_index in leaf_node.items.iter() {
+                let block = &self.blocks[*block_index];
+                if block.deleted {
+                    continue;
+                }
+
+                let block_size = block.items.len() as u32;
+                if current_position + block_size > position {
+                    return block
+                        .items
+                        .is_boundary(&self.storage, (position - current_position) as usize);
+                }
+
+                current_position += block_size;
+            }
+
+            current_leaf = leaf_node.next_block;
+        }
+
+        true
+    }
+
     pub fn find_id_starting_at_position(&self, position: u32) -> Option<SequenceBlockId> {
         let mut current_node_index: Option<NodeIndex> = Some(self.root);
         let mut current_position = 0;
@@ -170,9 +467,148 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         })
     }
 
+    // Checks that a block can be inserted without corrupting the tree. Operations coming from
+    // other replicas are not trusted, so they should be checked before calling `insert`
+    pub fn check_insert(
+        &self,
+        id: &SequenceBlockId,
+        left: Option<&SequenceBlockId>,
+        len: usize,
+    ) -> Result<(), SequenceError> {
+        if len == 0 {
+            return Err(SequenceError::EmptyBlock(id.clone()));
+        }
+        let last_sequence = u32::try_from(len - 1)
+            .ok()
+            .and_then(|offset| id.sequence.checked_add(offset))
+            .ok_or_else(|| SequenceError::OutOfRange(id.clone()))?;
+        let last = SequenceBlockId {
+            client_id: id.client_id,
+            sequence: last_sequence,
+        };
+        if self.find_containing_block(id).is_some() || self.find_containing_block(&last).is_some() {
+            return Err(SequenceError::DuplicateBlock(id.clone()));
+        }
+
+        if let Some(left) = left {
+            self.check_position(left, 1)?;
+        }
+
+        Ok(())
+    }
+
+    // Same as `check_insert`, but for the deletion of the given range
+    pub fn check_delete(
+        &self,
+        from: &SequenceBlockId,
+        to: &SequenceBlockId,
+    ) -> Result<(), SequenceError> {
+        let (from_node, from_block, from_offset) = self.check_position(from, 0)?;
+        let (to_node, to_block, to_offset) = self.check_position(to, 1)?;
+
+        let is_ordered = if from_block.id == to_block.id {
+            from_offset <= to_offset
+        } else {
+            self.block_location(from_node, &from_block.id)
+                < self.block_location(to_node, &to_block.id)
+        };
+        if !is_ordered {
+            return Err(SequenceError::InvalidRange(from.clone(), to.clone()));
+        }
+
+        Ok(())
+    }
+
+    // Whether the item with the given id is in the tree, deleted or not
+    pub fn contains(&self, id: &SequenceBlockId) -> bool {
+        self.find_containing_block(id).is_some()
+    }
+
+    // Highest sequence of the blocks inserted after the item, or at the start for `None`,
+    // without its continuation
+    pub fn max_child_sequence(&self, left: Option<&SequenceBlockId>) -> Option<u32> {
+        match left {
+            Some(left) => self.block_children.get(left)?,
+            None => &self.root_blocks,
+        }
+        .iter()
+        .map(|id| id.sequence)
+        .max()
+    }
+
+    // Checks that the position exists and that the containing block can be split `shift`
+    // items after it (0 before the position, 1 after it)
+    fn check_position(
+        &self,
+        position: &SequenceBlockId,
+        shift: u32,
+    ) -> Result<(NodeIndex, &SequenceBlock<Items>, u32), SequenceError> {
+        let (node_index, block, offset) = self
+            .find_containing_block(position)
+            .ok_or_else(|| SequenceError::BlockNotFound(position.clone()))?;
+
+        let split_offset = (offset + shift) as usize;
+        if split_offset > 0
+            && split_offset < block.items.len()
+            && !block.items.is_boundary(&self.storage, split_offset)
+        {
+            return Err(SequenceError::NotABoundary(position.clone()));
+        }
+
+        Ok((node_index, block, offset))
+    }
+
+    fn find_containing_block(
+        &self,
+        position: &SequenceBlockId,
+    ) -> Option<(NodeIndex, &SequenceBlock<Items>, u32)> {
+        // Blocks of the same client never overlap, so only the closest one can contain it
+        let (id, node_index) = self
+            .sequence_id_to_node
+            .find_at_or_before(position, self.max_block_len)?;
+        let block = self.find_block(&node_index, &id);
+        let offset = position.sequence - id.sequence;
+        ((offset as usize) < block.items.len()).then_some((node_index, block, offset))
+    }
+
+    // Position of the block in the tree, as the path from the root followed by its index
+    // inside the leaf. Comparing two locations gives the order of the blocks
+    fn block_location(&self, node_index: NodeIndex, block_id: &SequenceBlockId) -> Vec<usize> {
+        let leaf = self.nodes[node_index as usize]
+            .as_leaf()
+            .expect("not a leaf");
+        let mut location = vec![leaf
+            .items
+            .iter()
+            .position(|item| &self.blocks[*item].id == block_id)
+            .expect("block should exist in its leaf")];
+
+        let mut current = node_index;
+        while let Some(parent) = self.nodes[current as usize].parent() {
+            let branch = self.nodes[parent as usize]
+                .as_branch()
+                .expect("not a branch");
+            location.push(
+                branch
+                    .items
+                    .iter()
+                    .position(|item| item.node == current)
+                    .expect("node should exist in its parent"),
+            );
+            current = parent;
+        }
+
+        location.reverse();
+        location
+    }
+
     pub fn insert(&mut self, block: SequenceBlock<Items>) {
         let block_id = block.id.clone();
         let virtual_left_block_id = block.left.clone();
+        let last_item_id = SequenceBlockId {
+            client_id: block_id.client_id,
+            sequence: block_id.sequence + block.items.len() as u32 - 1,
+        };
 
         let left_block_id = if let Some(left) = &virtual_left_block_id {
             Some(self.get_or_split_block_ending_at(left))
@@ -180,100 +616,572 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             None
         };
 
+        let is_continuation = virtual_left_block_id
+            .as_ref()
+            .is_some_and(|left| Self::is_continuation_of(&block_id, left));
+        if !is_continuation {
+            match &virtual_left_block_id {
+                Some(left) => self
+                    .block_children
+                    .entry(left.clone())
+                    .or_default()
+                    .push(block_id.clone()),
+                None => self.root_blocks.push(block_id.clone()),
+            }
+            self.non_continuations.insert(block_id.clone());
+        }
+
         // When possible, we should try to merge the new items with an existing block.
         // This allows us to reduce the overhead per block that we generate
         let should_merge = if let (Some(virtual_left), Some(real_left)) =
             (&virtual_left_block_id, &left_block_id)
         {
-            self.is_block_mergeable(virtual_left, real_left, &block_id)
+            is_continuation
+                && !self.block_children.contains_key(virtual_left)
+                && self.is_block_mergeable(real_left, &block.items)
         } else {
             false
         };
 
-        if should_merge {
+        let preceding_item_id = if should_merge {
             let left_block_id = left_block_id.expect("left block should exist");
-            self.merge_block(block, left_block_id)
+            self.merge_block(block, left_block_id);
+            virtual_left_block_id.clone()
         } else {
-            self.insert_block(block, left_block_id)
+            self.insert_block(block, left_block_id, is_continuation)
+        };
+
+        if let (Some(left), Some(preceding)) = (&virtual_left_block_id, &preceding_item_id) {
+            self.extend_latest_descendents(left, preceding, last_item_id);
+        }
+    }
+
+    // A block placed right after the latest descendent of some items becomes their latest
+    // descendent, as long as it's inserted after one of the items of their subtree. Those
+    // subtrees contain both the preceding item and the left item, which can only be true for
+    // the items that come before the left one (the subtrees are either nested or disjoint).
+    fn extend_latest_descendents(
+        &mut self,
+        left: &SequenceBlockId,
+        preceding: &SequenceBlockId,
+        last_item_id: SequenceBlockId,
+    ) {
+        let Some(sources) = self.latest_descendent_sources.remove(preceding) else {
+            return;
+        };
+
+        let left_location = self.item_location(left);
+        let (extended, unchanged): (Vec<_>, Vec<_>) = sources
+            .into_iter()
+            .partition(|source| left == preceding || self.item_location(source) <= left_location);
+
+        for source in extended.iter() {
+            self.latest_descendents
+                .insert(source.clone(), last_item_id.clone());
+        }
+        if !extended.is_empty() {
+            self.latest_descendent_sources
+                .entry(last_item_id)
+                .or_default()
+                .extend(extended);
+        }
+        if !unchanged.is_empty() {
+            self.latest_descendent_sources
+                .insert(preceding.clone(), unchanged);
         }
     }
 
+    // Like `block_location`, for any item, so that the order of two items can be compared
+    fn item_location(&self, id: &SequenceBlockId) -> (Vec<usize>, u32) {
+        let (node_index, block, offset) = self
+            .find_containing_block(id)
+            .expect("item should be in the tree");
+        (self.block_location(node_index, &block.id), offset)
+    }
+
     pub fn delete(&mut self, from: &SequenceBlockId, to: &SequenceBlockId) {
+        self.delete_with(from, to, |_| {})
+    }
+
+    // Deletes several ranges, all of them are checked first so that either every range is
+    // deleted or none is
+    pub fn delete_ranges_with(
+        &mut self,
+        ranges: &[(&SequenceBlockId, &SequenceBlockId)],
+        mut on_delete: impl FnMut(&mut Items),
+    ) -> Result<(), SequenceError> {
+        for (from, to) in ranges {
+            self.check_delete(from, to)?;
+        }
+
+        for (from, to) in ranges {
+            self.delete_with(from, to, &mut on_delete);
+        }
+        Ok(())
+    }
+
+    // Deletes several ranges of items, each made of the consecutive sequences of a single
+    // client. Unlike `delete_ranges_with`, only the items of those sequences are deleted: the
+    // ones inserted between them, even concurrently, are kept.
+    pub fn delete_items_with(
+        &mut self,
+        ranges: &[(&SequenceBlockId, &SequenceBlockId)],
+        mut on_delete: impl FnMut(&mut Items),
+    ) -> Result<(), SequenceError> {
+        for (from, to) in ranges {
+            if from.client_id != to.client_id {
+                return Err(SequenceError::MixedClients((*from).clone(), (*to).clone()));
+            }
+            if from.sequence > to.sequence {
+                return Err(SequenceError::InvalidRange((*from).clone(), (*to).clone()));
+            }
+            self.check_delete(from, to)?;
+        }
+
+        for (from, to) in ranges {
+            // Blocks are split at both ends, so they are either entirely in the range or out
+            let sequences = from.sequence..=to.sequence;
+            self.delete_matching(
+                from,
+                to,
+                |id| id.client_id == from.client_id && sequences.contains(&id.sequence),
+                &mut on_delete,
+            );
+        }
+        Ok(())
+    }
+
+    // Deletes the given range, calling `on_delete` on the items of every block that wasn't
+    // already deleted
+    pub fn delete_with(
+        &mut self,
+        from: &SequenceBlockId,
+        to: &SequenceBlockId,
+        on_delete: impl FnMut(&mut Items),
+    ) {
+        self.delete_matching(from, to, |_| true, on_delete)
+    }
+
+    // Same as `delete_with`, skipping the blocks whose id doesn't match
+    fn delete_matching(
+        &mut self,
+        from: &SequenceBlockId,
+        to: &SequenceBlockId,
+        matches: impl Fn(&SequenceBlockId) -> bool,
+        mut on_delete: impl FnMut(&mut Items),
+    ) {
         let start_block_id = self.get_or_split_block_starting_at(from);
         let end_block_id = self.get_or_split_block_ending_at(to);
 
         let mut current_node_index = self.sequence_id_to_node[&start_block_id];
 
-        let mut size_reductions_per_node: FxHashMap<NodeIndex, u32> = FxHashMap::default();
+        // Leaves are visited in order, so the reductions of siblings end up next to each other
+        let mut reductions: Vec<(NodeIndex, u32, u32)> = Vec::new();
+        let mut visited_leaves: Vec<NodeIndex> = Vec::new();
 
         let mut inside = false;
-        'outer: loop {
+        loop {
             let current_node = &self.nodes[current_node_index as usize]
                 .as_leaf()
                 .expect("not a leaf");
 
+            visited_leaves.push(current_node_index);
+
+            let mut size_reduction = 0;
+            let mut chars_reduction = 0;
+            let mut reached_end = false;
             for item_index in &current_node.items {
                 let block = &mut self.blocks[*item_index];
                 if block.id == start_block_id {
                     inside = true;
                 }
 
-                if inside {
+                // Concurrent deletions can target blocks that are already deleted, which are
+                // left untouched so that deleting a range twice is the same as deleting it once
+                if inside && !block.deleted && matches(&block.id) {
+                    size_reduction += block.items.len() as u32;
+                    chars_reduction += block.items.char_len() as u32;
                     block.deleted = true;
 
-                    size_reductions_per_node
-                        .entry(current_node_index)
-                        .and_modify(|e| {
-                            *e += block.items.len() as u32;
-                        })
-                        .or_insert(block.items.len() as u32);
+                    on_delete(&mut block.items);
                 }
 
                 if block.id == end_block_id {
-                    inside = false;
-                    break 'outer;
+                    reached_end = true;
+                    break;
+                }
+            }
+
+            if size_reduction > 0 {
+                reductions.push((current_node_index, size_reduction, chars_reduction));
+            }
+
+            if reached_end {
+                break;
+            }
+
+            current_node_index = current_node.next_block.expect("next block should exist");
+        }
+
+        // Update the parent metrics to reflect the deletion
+        self.subtract_size_metrics_batched(reductions);
+
+        self.compact_leaves(&visited_leaves);
+    }
+
+    // Merges the tombstones of the given leaves, then merges the leaves that are left less
+    // than half full with their siblings
+    fn compact_leaves(&mut self, leaves: &[NodeIndex]) {
+        for leaf_index in leaves {
+            // Merged into a previous leaf
+            if self.free_nodes.contains(leaf_index) {
+                continue;
+            }
+
+            // Once the blocks of the leaf are merged, only the first one can continue the
+            // last block of the previous leaf
+            self.merge_deleted_blocks(*leaf_index);
+            if !self.merge_first_deleted_block(*leaf_index) {
+                continue;
+            }
+
+            let mut leaf_index = *leaf_index;
+            while let Some(merged_leaf) = self.merge_underflowing_leaf(leaf_index) {
+                // Tombstones at the boundary of the two leaves are now next to each other
+                self.merge_deleted_blocks(merged_leaf);
+                leaf_index = merged_leaf;
+            }
+        }
+    }
+
+    // Collapses adjacent deleted blocks that continue each other into a single block, as
+    // `merge_block` does for insertions. Merged blocks never exceed `max_block_len`, so the
+    // search of containing blocks stays bounded.
+    fn merge_deleted_blocks(&mut self, leaf_index: NodeIndex) {
+        let mut position = 1;
+        loop {
+            let leaf_node = self.nodes[leaf_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+            if position >= leaf_node.items.len() {
+                break;
+            }
+
+            if self.is_tombstone_mergeable(leaf_node.items[position - 1], leaf_node.items[position])
+            {
+                self.merge_into_previous_block(leaf_index, position, leaf_index, position - 1);
+            } else {
+                position += 1;
+            }
+        }
+    }
+
+    // Same as `merge_deleted_blocks`, for the first block of the leaf and the last one of the
+    // previous leaf. The leaf is removed if it's left empty, in which case `false` is returned
+    fn merge_first_deleted_block(&mut self, leaf_index: NodeIndex) -> bool {
+        let leaf_node = self.nodes[leaf_index as usize]
+            .as_leaf()
+            .expect("not a leaf");
+        let (Some(previous_leaf), Some(first_block)) =
+            (leaf_node.previous_block, leaf_node.items.first())
+        else {
+            return true;
+        };
+
+        let previous_node = self.nodes[previous_leaf as usize]
+            .as_leaf()
+            .expect("not a leaf");
+        let last_position = previous_node.items.len() - 1;
+        if !self.is_tombstone_mergeable(previous_node.items[last_position], *first_block) {
+            return true;
+        }
+
+        self.merge_into_previous_block(leaf_index, 0, previous_leaf, last_position);
+
+        let leaf_node = self.nodes[leaf_index as usize]
+            .as_leaf()
+            .expect("not a leaf");
+        if leaf_node.items.is_empty() {
+            self.remove_empty_leaf(leaf_index);
+            return false;
+        }
+        true
+    }
+
+    // Removes the block at the given position, appending its items to the one at
+    // `left_position` of `left_leaf`, which must be the block right before it
+    fn merge_into_previous_block(
+        &mut self,
+        leaf_index: NodeIndex,
+        position: usize,
+        left_leaf: NodeIndex,
+        left_position: usize,
+    ) {
+        let right_index = self.nodes[leaf_index as usize]
+            .as_leaf_mut()
+            .expect("not a leaf")
+            .items
+            .remove(position);
+        let right_block = self.remove_block(right_index);
+
+        // Removing the block can move the left one to a different index
+        let left_index = self.nodes[left_leaf as usize]
+            .as_leaf()
+            .expect("not a leaf")
+            .items[left_position];
+        self.blocks[left_index].items.push(right_block.items);
+
+        // Deleted blocks don't contribute to the sizes, only the number of items changes
+        self.update_ancestor_items(leaf_index, |item| item.item_count -= 1);
+    }
+
+    fn is_tombstone_mergeable(
+        &self,
+        left_index: SequenceBlockIndex,
+        right_index: SequenceBlockIndex,
+    ) -> bool {
+        let left_block = &self.blocks[left_index];
+        let right_block = &self.blocks[right_index];
+        if !left_block.deleted || !right_block.deleted {
+            return false;
+        }
+
+        let left_len = left_block.items.len() as u32;
+        let last_left_id = SequenceBlockId {
+            client_id: left_block.id.client_id,
+            sequence: left_block.id.sequence + left_len - 1,
+        };
+
+        Self::is_continuation_of(&right_block.id, &last_left_id)
+            && !self.non_continuations.contains(&right_block.id)
+            && !self.block_children.contains_key(&last_left_id)
+            && left_len + right_block.items.len() as u32 <= self.max_block_len
+            && left_block.items.can_push(&right_block.items)
+    }
+
+    // Removes a block that is no longer referenced by its leaf. The last block takes its
+    // index, so the leaf that contains it is updated accordingly.
+    fn remove_block(&mut self, block_index: SequenceBlockIndex) -> SequenceBlock<Items> {
+        let block = self.blocks.swap_remove(block_index);
+        self.sequence_id_to_node.remove(&block.id);
+
+        if let Some(moved_block) = self.blocks.get(block_index) {
+            let previous_index = self.blocks.len();
+            let node_index = self.sequence_id_to_node[&moved_block.id];
+            let leaf_node = self.nodes[node_index as usize]
+                .as_leaf_mut()
+                .expect("not a leaf");
+            for item in leaf_node.items.iter_mut() {
+                if *item == previous_index {
+                    *item = block_index;
+                    break;
+                }
+            }
+        }
+
+        block
+    }
+
+    // Merges a leaf that is less than half full with the next one (or the previous one, for
+    // the last leaf) when their blocks fit in a single leaf. Returns the leaf that contains
+    // the blocks of both.
+    fn merge_underflowing_leaf(&mut self, leaf_index: NodeIndex) -> Option<NodeIndex> {
+        let leaf_node = self.nodes[leaf_index as usize]
+            .as_leaf()
+            .expect("not a leaf");
+        if leaf_node.items.len() >= LEAF_SIZE / 2 {
+            return None;
+        }
+
+        let (left, right) = match (leaf_node.previous_block, leaf_node.next_block) {
+            (_, Some(next)) => (leaf_index, next),
+            (Some(previous), None) => (previous, leaf_index),
+            (None, None) => return None,
+        };
+
+        let left_len = self.nodes[left as usize]
+            .as_leaf()
+            .expect("not a leaf")
+            .items
+            .len();
+        let right_len = self.nodes[right as usize]
+            .as_leaf()
+            .expect("not a leaf")
+            .items
+            .len();
+        if left_len + right_len > LEAF_SIZE {
+            return None;
+        }
+
+        self.merge_leaves(left, right);
+        Some(left)
+    }
+
+    // Moves the blocks of `right` at the end of `left`, the leaf before it, and removes it
+    fn merge_leaves(&mut self, left: NodeIndex, right: NodeIndex) {
+        let moved_size = self.get_total_size_for_node(right);
+        let moved_chars = self.get_total_chars_for_node(right);
+        let right_items = std::mem::take(
+            &mut self.nodes[right as usize]
+                .as_leaf_mut()
+                .expect("not a leaf")
+                .items,
+        );
+        let moved_count = right_items.len() as u32;
+
+        for block_index in right_items.iter() {
+            let block = &self.blocks[*block_index];
+            self.sequence_id_to_node.insert(block.id.clone(), left);
+        }
+
+        let left_node = self.nodes[left as usize].as_leaf_mut().expect("not a leaf");
+        for block_index in right_items.iter() {
+            left_node
+                .items
+                .push(*block_index)
+                .expect("insertion failed");
+        }
+
+        // The leaves can have different parents, so both paths are updated
+        self.update_ancestor_items(right, |item| {
+            item.total_size -= moved_size;
+            item.total_chars -= moved_chars;
+            item.item_count -= moved_count;
+        });
+        self.update_ancestor_items(left, |item| {
+            item.total_size += moved_size;
+            item.total_chars += moved_chars;
+            item.item_count += moved_count;
+        });
+
+        self.remove_empty_leaf(right);
+    }
+
+    // Calls `update` on the branch items that refer to the node and to each of its ancestors
+    fn update_ancestor_items(
+        &mut self,
+        node_index: NodeIndex,
+        mut update: impl FnMut(&mut BranchItem),
+    ) {
+        let mut target_node = node_index;
+        while let Some(parent) = self.nodes[target_node as usize].parent() {
+            let parent_node = self.nodes[parent as usize]
+                .as_branch_mut()
+                .expect("not a branch");
+            for item in parent_node.items.iter_mut() {
+                if item.node == target_node {
+                    update(item);
+                    break;
                 }
             }
+            target_node = parent;
+        }
+    }
 
-            current_node_index = current_node.next_block.expect("next block should exist");
+    // Unlinks a leaf without blocks, along with the branches that are left without children.
+    // It can't be the only leaf, as leaves are only emptied by moving blocks to another one.
+    fn remove_empty_leaf(&mut self, leaf_index: NodeIndex) {
+        let leaf_node = self.free_node(leaf_index).into_leaf().expect("not a leaf");
+
+        match leaf_node.previous_block {
+            Some(previous) => {
+                self.nodes[previous as usize]
+                    .as_leaf_mut()
+                    .expect("not a leaf")
+                    .next_block = leaf_node.next_block
+            }
+            None => self.start = leaf_node.next_block.expect("next leaf should exist"),
+        }
+        match leaf_node.next_block {
+            Some(next) => {
+                self.nodes[next as usize]
+                    .as_leaf_mut()
+                    .expect("not a leaf")
+                    .previous_block = leaf_node.previous_block
+            }
+            None => {
+                self.end = leaf_node
+                    .previous_block
+                    .expect("previous leaf should exist")
+            }
         }
 
-        debug_assert!(
-            size_reductions_per_node.len() > 0,
-            "at least one node should have been modified"
-        );
+        // The removed nodes have no items, so the metrics of the ancestors don't change
+        let mut child = leaf_index;
+        let mut current_parent = leaf_node.parent;
+        while let Some(parent) = current_parent {
+            let parent_node = self.nodes[parent as usize]
+                .as_branch_mut()
+                .expect("not a branch");
+            let child_position = parent_node
+                .items
+                .iter()
+                .position(|item| item.node == child)
+                .expect("node should exist in its parent");
+            parent_node.items.remove(child_position);
 
-        // Update the parent metrics to reflect the deletion
-        for (leaf_node_index, size_reduction) in size_reductions_per_node.iter() {
-            self.subtract_size_metrics_recursively(*leaf_node_index, *size_reduction);
+            if !parent_node.items.is_empty() {
+                break;
+            }
+            current_parent = parent_node.parent;
+            self.free_node(parent);
+            child = parent;
         }
+
+        self.collapse_root();
     }
 
-    fn is_block_mergeable(
-        &self,
-        virtual_left: &SequenceBlockId,
-        real_left: &SequenceBlockId,
-        current: &SequenceBlockId,
-    ) -> bool {
-        if virtual_left.client_id != current.client_id {
-            return false;
+    // Replaces a root that has a single child with the child, until the root has siblings
+    // to choose from or is a leaf
+    fn collapse_root(&mut self) {
+        while let Node::Branch(root_node) = &self.nodes[self.root as usize] {
+            if root_node.items.len() != 1 {
+                break;
+            }
+
+            let child = root_node.items[0].node;
+            self.free_node(self.root);
+            self.root = child;
+            match &mut self.nodes[child as usize] {
+                Node::Branch(branch_node) => branch_node.parent = None,
+                Node::Leaf(leaf_node) => leaf_node.parent = None,
+            }
         }
+    }
 
-        if virtual_left.sequence + 1 != current.sequence {
-            return false;
+    // Index for a new node, reusing the ones freed by merges. The caller must store the node
+    fn allocate_node(&mut self) -> NodeIndex {
+        match self.free_nodes.pop() {
+            Some(node_index) => node_index,
+            None => {
+                self.nodes.push(Node::new_root());
+                (self.nodes.len() - 1) as NodeIndex
+            }
         }
+    }
+
+    // Replaces the node with an empty placeholder until it's reused, returning it
+    fn free_node(&mut self, node_index: NodeIndex) -> Node<BRANCH_SIZE, LEAF_SIZE> {
+        self.free_nodes.push(node_index);
+        std::mem::replace(&mut self.nodes[node_index as usize], Node::new_root())
+    }
 
+    fn is_block_mergeable(&self, real_left: &SequenceBlockId, items: &Items) -> bool {
         let containing_node = self
             .sequence_id_to_node
             .get(real_left)
             .cloned()
             .expect("node should exist");
         let left_block = self.find_block(&containing_node, real_left);
-        if left_block.deleted {
-            return false;
-        }
+        !left_block.deleted
+            && left_block.items.len() + items.len() <= self.block_len_limit as usize
+            && left_block.items.can_push(items)
+    }
 
-        true
+    // A block that continues the text right after the previous item of the same client, as
+    // if they were inserted together
+    fn is_continuation_of(block: &SequenceBlockId, left: &SequenceBlockId) -> bool {
+        block.client_id == left.client_id && left.sequence + 1 == block.sequence
     }
 
     fn merge_block(&mut self, block: SequenceBlock<Items>, left_block_id: SequenceBlockId) {
@@ -286,8 +1194,11 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         assert!(!left_block.deleted, "left block should not be deleted");
 
         let new_items_count = block.items.len();
+        let new_items_chars = block.items.char_len();
 
         left_block.items.push(block.items);
+        let merged_len = left_block.items.len() as u32;
+        self.max_block_len = self.max_block_len.max(merged_len);
 
         // Update parent metrics recursively
         let leaf_node = &self.nodes[left_node_index as usize]
@@ -302,6 +1213,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             for item in parent_node.items.iter_mut() {
                 if item.node == target_node {
                     item.total_size += new_items_count as u32;
+                    item.total_chars += new_items_chars as u32;
                     break;
                 }
             }
@@ -310,71 +1222,50 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         }
     }
 
+    // Blocks are ordered as a depth-first visit of the tree formed by the items they were
+    // inserted after. Children are visited in deterministic order, followed by the
+    // continuation of the item, so every replica ends up with the same sequence.
+    // Returns the item that precedes the new block, if any
     fn insert_block(
         &mut self,
         block: SequenceBlock<Items>,
         left_block_id: Option<SequenceBlockId>,
-    ) {
+        is_continuation: bool,
+    ) -> Option<SequenceBlockId> {
         let block_id = block.id.clone();
-        if let Some(left) = &left_block_id {
-            self.block_children
-                .entry(left.clone())
-                .or_insert_with(Vec::new)
-                .push(block.id.clone());
-        } else {
-            self.root_blocks.push(block.id.clone());
-        }
+        let virtual_left_block_id = block.left.clone();
 
+        self.max_block_len = self.max_block_len.max(block.items.len() as u32);
         let block_index: SequenceBlockIndex = self.blocks.len();
         self.blocks.push(block);
 
-        let actual_left_id = match left_block_id {
-            // 1. If root and there are no other roots, add it as a first element on the left
-            None if self.root_blocks.len() == 1 => None,
-
-            // 2. If root and there are other roots, determine the order between the roots, and
-            //    place it at the right of all the descendents of the previous element
-            None if self.root_blocks.len() > 1 => {
-                let sorted_roots = self.deterministic_id_sort(&self.root_blocks);
-                let current_element_index = sorted_roots
-                    .iter()
-                    .position(|id| id == &block_id)
-                    .expect("current element should exist");
-
-                if current_element_index == 0 {
-                    None
-                } else {
-                    let previous_root = &sorted_roots[current_element_index - 1];
-                    let latest_descendent = self.find_latest_descendent(&previous_root);
-                    Some(latest_descendent)
-                }
-            }
-            Some(left) => {
-                let parent_children = &self.block_children[&left];
-                assert!(parent_children.len() > 0, "parent should have children");
+        let siblings = match &virtual_left_block_id {
+            Some(left) => self.block_children.get(left).map(Vec::as_slice),
+            None => Some(self.root_blocks.as_slice()),
+        }
+        .unwrap_or_default();
 
-                if parent_children.len() == 1 {
-                    // 3. If has "left", check if this parent has other children. If no, place it at the right of it
-                    Some(left)
-                } else {
-                    // 4. If has "left" and parent has other children, determine the order between the children
-                    //    and add it at the right of all the descendents of the previous
-                    let sorted_parent_children = self.deterministic_id_sort(parent_children);
-                    let current_element_index = sorted_parent_children
-                        .iter()
-                        .position(|id| id == &block_id)
-                        .expect("current element should exist");
+        // The previous sibling, whose descendents must all come before the new block
+        let previous_sibling = if is_continuation {
+            self.deterministic_id_sort(siblings).last().cloned()
+        } else {
+            let sorted_siblings = self.deterministic_id_sort(siblings);
+            let current_element_index = sorted_siblings
+                .iter()
+                .position(|id| id == &block_id)
+                .expect("current element should exist");
+            current_element_index
+                .checked_sub(1)
+                .map(|index| sorted_siblings[index].clone())
+        };
 
-                    if current_element_index == 0 {
-                        Some(left)
-                    } else {
-                        let previous_child = &sorted_parent_children[current_element_index - 1];
-                        let latest_descendent = self.find_latest_descendent(&previous_child);
-                        Some(latest_descendent)
-                    }
-                }
+        let (actual_left_id, preceding_item_id) = match previous_sibling {
+            Some(sibling) => {
+                let latest_descendent = self.find_latest_descendent(&sibling);
+                let actual_left_id = self.get_or_split_block_ending_at(&latest_descendent);
+                (Some(actual_left_id), Some(latest_descendent))
             }
-            _ => unreachable!(),
+            None => (left_block_id, virtual_left_block_id),
         };
 
         let target_node_index: NodeIndex = if let Some(actual_left_id) = &actual_left_id {
@@ -389,6 +1280,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         };
 
         self.insert_block_in_node(block_index, actual_left_id, target_node_index);
+        preceding_item_id
     }
 
     fn get_or_split_block_starting_at(&mut self, position: &SequenceBlockId) -> SequenceBlockId {
@@ -398,20 +1290,14 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             return position.clone();
         } else {
             // Not in cache, find the earliest block scrolling left and split at the appropriate position
-            for sequence_id in (0..position.sequence).rev() {
-                let id = SequenceBlockId {
-                    client_id: position.client_id.clone(),
-                    sequence: sequence_id,
-                };
-
-                let node_index = self.sequence_id_to_node.get(&id).cloned();
+            let found = self
+                .sequence_id_to_node
+                .find_at_or_before(position, self.max_block_len);
 
-                if let Some(node_index) = node_index {
-                    let block = self.find_block(&node_index, &id);
-                    let offset = position.sequence - block.id.sequence;
-                    self.split_block(&node_index, &id, offset);
-                    return position.clone();
-                }
+            if let Some((id, node_index)) = found {
+                let offset = position.sequence - id.sequence;
+                self.split_block(&node_index, &id, offset);
+                return position.clone();
             }
         }
 
@@ -434,25 +1320,20 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             }
         } else {
             // Not in cache, find the earliest block scrolling left and split at the appropriate position
-            for sequence_id in (0..position.sequence).rev() {
-                let id = SequenceBlockId {
-                    client_id: position.client_id.clone(),
-                    sequence: sequence_id,
-                };
-
-                let node_index = self.sequence_id_to_node.get(&id).cloned();
+            let found = self
+                .sequence_id_to_node
+                .find_at_or_before(position, self.max_block_len);
 
-                if let Some(node_index) = node_index {
-                    let block = self.find_block(&node_index, &id);
-                    let offset = position.sequence - block.id.sequence;
+            if let Some((id, node_index)) = found {
+                let block = self.find_block(&node_index, &id);
+                let offset = position.sequence - block.id.sequence;
 
-                    if offset == block.items.len() as u32 - 1 {
-                        // No need to split, as we are referring to the last element in the block
-                        return id;
-                    } else {
-                        self.split_block(&node_index, &id, offset + 1);
-                        return id;
-                    }
+                if offset == block.items.len() as u32 - 1 {
+                    // No need to split, as we are referring to the last element in the block
+                    return id;
+                } else {
+                    self.split_block(&node_index, &id, offset + 1);
+                    return id;
                 }
             }
         }
@@ -511,7 +1392,12 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         panic!("unable to find the block index")
     }
 
-    fn subtract_size_metrics_recursively(&mut self, leaf_node_index: NodeIndex, reduction: u32) {
+    fn subtract_size_metrics_recursively(
+        &mut self,
+        leaf_node_index: NodeIndex,
+        reduction: u32,
+        chars_reduction: u32,
+    ) {
         let leaf_node = &self.nodes[leaf_node_index as usize]
             .as_leaf()
             .expect("not a leaf");
@@ -524,6 +1410,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             for item in parent_node.items.iter_mut() {
                 if item.node == target_node {
                     item.total_size -= reduction;
+                    item.total_chars -= chars_reduction;
                     break;
                 }
             }
@@ -532,13 +1419,56 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         }
     }
 
+    // Like `subtract_size_metrics_recursively`, but for many nodes at once. The tree is
+    // walked one level at a time, merging the reductions of siblings, so that every ancestor
+    // is updated once instead of once per node below it.
+    fn subtract_size_metrics_batched(&mut self, mut reductions: Vec<(NodeIndex, u32, u32)>) {
+        while !reductions.is_empty() {
+            let mut parent_reductions: Vec<(NodeIndex, u32, u32)> = Vec::new();
+
+            for (node_index, reduction, chars_reduction) in reductions {
+                let Some(parent) = self.nodes[node_index as usize].parent() else {
+                    continue;
+                };
+
+                let parent_node = &mut self.nodes[parent as usize]
+                    .as_branch_mut()
+                    .expect("not a branch");
+                for item in parent_node.items.iter_mut() {
+                    if item.node == node_index {
+                        item.total_size -= reduction;
+                        item.total_chars -= chars_reduction;
+                        break;
+                    }
+                }
+
+                match parent_reductions.last_mut() {
+                    Some((last_parent, last_reduction, last_chars_reduction))
+                        if *last_parent == parent =>
+                    {
+                        *last_reduction += reduction;
+                        *last_chars_reduction += chars_reduction;
+                    }
+                    _ => parent_reductions.push((parent, reduction, chars_reduction)),
+                }
+            }
+
+            reductions = parent_reductions;
+        }
+    }
+
     fn split_block(&mut self, containing_node: &NodeIndex, block: &SequenceBlockId, offset: u32) {
         let block_index = self.find_block_index(containing_node, block);
 
-        let (right_block_index, right_content_size) = {
+        let (right_block_index, right_content_size, right_content_chars) = {
             let left_block = &mut self.blocks[block_index];
-            let right_content = left_block.items.split(offset as usize);
-            let right_content_size = right_content.len() as u32;
+            let right_content = left_block.items.split(&self.storage, offset as usize);
+            // Deleted blocks don't contribute to the metrics
+            let (right_content_size, right_content_chars) = if left_block.deleted {
+                (0, 0)
+            } else {
+                (right_content.len() as u32, right_content.char_len() as u32)
+            };
             let right_block = SequenceBlock::<Items> {
                 id: SequenceBlockId {
                     client_id: left_block.id.client_id.clone(),
@@ -560,41 +1490,84 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
 
             let right_block_index = self.blocks.len();
             self.blocks.push(right_block);
-            (right_block_index, right_content_size)
+            (right_block_index, right_content_size, right_content_chars)
         };
 
-        self.subtract_size_metrics_recursively(*containing_node, right_content_size);
+        self.subtract_size_metrics_recursively(
+            *containing_node,
+            right_content_size,
+            right_content_chars,
+        );
         self.insert_block_in_node(right_block_index, Some(block.clone()), *containing_node);
     }
 
-    fn find_latest_descendent(&self, parent: &SequenceBlockId) -> SequenceBlockId {
-        let mut to_visit = VecDeque::new();
-        to_visit.push_back(parent);
+    // Last item of the subtree of the given item, which is the last item of its continuations
+    // or the latest descendent of its last child. Results are cached, see
+    // `extend_latest_descendents`.
+    fn find_latest_descendent(&mut self, parent: &SequenceBlockId) -> SequenceBlockId {
+        if let Some(latest_descendent) = self.latest_descendents.get(parent) {
+            return latest_descendent.clone();
+        }
+
+        let latest_descendent = self.search_latest_descendent(parent);
+        self.latest_descendents
+            .insert(parent.clone(), latest_descendent.clone());
+        self.latest_descendent_sources
+            .entry(latest_descendent.clone())
+            .or_default()
+            .push(parent.clone());
+        latest_descendent
+    }
+
+    fn search_latest_descendent(&self, parent: &SequenceBlockId) -> SequenceBlockId {
+        let mut current = parent.clone();
+
+        loop {
+            // The rest of the walk is the same as the one of a previous search
+            if let Some(latest_descendent) = self.latest_descendents.get(&current) {
+                return latest_descendent.clone();
+            }
+
+            // Items of the same block are continuations of each other, so it can be skipped
+            let (_, block, offset) = self
+                .find_containing_block(&current)
+                .expect("descendent should be in the tree");
+            current.sequence += block.items.len() as u32 - 1 - offset;
+
+            let continuation = SequenceBlockId {
+                client_id: current.client_id,
+                sequence: current.sequence + 1,
+            };
+            if self.sequence_id_to_node.contains_key(&continuation)
+                && !self.non_continuations.contains(&continuation)
+            {
+                current = continuation;
+                continue;
+            }
 
-        while let Some(current) = to_visit.pop_back() {
-            match self.block_children.get(current) {
-                None => return current.clone(),
-                Some(children) if children.len() == 0 => return current.clone(),
-                Some(children) => {
-                    to_visit.extend(children);
+            match self.block_children.get(&current) {
+                Some(children) if !children.is_empty() => {
+                    current = self
+                        .deterministic_id_sort(children)
+                        .pop()
+                        .expect("children should not be empty");
                 }
+                _ => return current,
             }
         }
-
-        panic!("unable to find the latest decendants of {:?}", parent);
     }
 
     fn deterministic_id_sort(&self, ids: &[SequenceBlockId]) -> Vec<SequenceBlockId> {
         let mut ids = Vec::from(ids);
 
-        // TODO: make sure that the client ID sorting order is globally deterministic
+        // A more recent item has precedence: blocks get a higher sequence than the ones
+        // already inserted at the same place, see `TextCRDT::next_id_after`. Concurrent
+        // insertions are then ordered by client, as local client IDs are ordered the same way
+        // on every replica, see `ClientRegistry`.
         ids.sort_by(|a, b| {
-            if a.client_id == b.client_id {
-                // A more recent item has precedence
-                b.sequence.cmp(&a.sequence)
-            } else {
-                a.client_id.cmp(&b.client_id)
-            }
+            b.sequence
+                .cmp(&a.sequence)
+                .then_with(|| a.client_id.cmp(&b.client_id))
         });
 
         ids
@@ -663,7 +1636,11 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
 
         // Update the parent metrics
         let block = &self.blocks[block_index];
-        let block_size = block.items.len() as u32;
+        let (block_size, block_chars) = if block.deleted {
+            (0, 0)
+        } else {
+            (block.items.len() as u32, block.items.char_len() as u32)
+        };
 
         let leaf_node = &self.nodes[insertion_leaf as usize]
             .as_leaf()
@@ -677,6 +1654,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             for item in parent_node.items.iter_mut() {
                 if item.node == target_node {
                     item.total_size += block_size;
+                    item.total_chars += block_chars;
                     item.item_count += 1;
                     break;
                 }
@@ -693,7 +1671,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
     fn split_leaf(&mut self, node_index: NodeIndex) -> NodeIndex {
         let (left_node_id, right_node_id, parent_id) = {
             let left_node_id = node_index;
-            let right_node_id = self.nodes.len() as NodeIndex;
+            let right_node_id = self.allocate_node();
 
             let left_node = &mut self.nodes[left_node_id as usize];
             let left_node = left_node.as_leaf_mut().expect("not a leaf");
@@ -717,13 +1695,20 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             right_node.items.reverse();
             left_node.next_block = Some(right_node.id);
 
+            if let Some(next) = right_node.next_block {
+                self.nodes[next as usize]
+                    .as_leaf_mut()
+                    .expect("not a leaf")
+                    .previous_block = Some(right_node.id);
+            }
+
             for index in right_node.items.iter() {
                 let block = &self.blocks[*index];
                 self.sequence_id_to_node
                     .insert(block.id.clone(), right_node.id);
             }
 
-            self.nodes.push(Node::Leaf(right_node));
+            self.nodes[right_node_id as usize] = Node::Leaf(right_node);
 
             (left_node_id, right_node_id, left_node_parent)
         };
@@ -773,6 +1758,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
                         BranchItem {
                             node: right_item,
                             total_size: 0,
+                            total_chars: 0,
                             item_count: 0,
                         },
                     )
@@ -791,13 +1777,14 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             let branch_node = &self.nodes[*branch as usize];
             let branch_node = branch_node.as_branch().expect("not a branch");
 
-            let new_items_metrics: Vec<(u32, u32)> = branch_node
+            let new_items_metrics: Vec<(u32, u32, u32)> = branch_node
                 .items
                 .iter()
                 .map(|item| {
                     let items_count = self.get_items_count_for_node(item.node);
                     let total_size = self.get_total_size_for_node(item.node);
-                    (items_count, total_size)
+                    let total_chars = self.get_total_chars_for_node(item.node);
+                    (items_count, total_size, total_chars)
                 })
                 .collect();
 
@@ -807,6 +1794,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             for (index, item) in branch_node.items.iter_mut().enumerate() {
                 item.item_count = new_items_metrics[index].0;
                 item.total_size = new_items_metrics[index].1;
+                item.total_chars = new_items_metrics[index].2;
             }
         }
     }
@@ -814,7 +1802,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
     fn split_branch(&mut self, node_index: NodeIndex) -> NodeIndex {
         let (left_node_id, right_node_id, parent_id) = {
             let left_node_id = node_index;
-            let right_node_id = self.nodes.len() as NodeIndex;
+            let right_node_id = self.allocate_node();
 
             let left_node = &mut self.nodes[left_node_id as usize];
             let left_node = left_node.as_branch_mut().expect("not a branch");
@@ -840,7 +1828,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
                 node.set_parent(right_node_id);
             }
 
-            self.nodes.push(Node::Branch(right_node));
+            self.nodes[right_node_id as usize] = Node::Branch(right_node);
 
             (left_node_id, right_node_id, left_node_parent)
         };
@@ -856,22 +1844,24 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
 
     fn create_upper_root(&mut self, left: NodeIndex, right: NodeIndex) {
         let new_root = BranchNode::<BRANCH_SIZE> {
-            id: self.nodes.len() as NodeIndex,
+            id: self.allocate_node(),
             parent: None,
             items: StackVec::new(),
         };
         let new_root_id = new_root.id.clone();
-        self.nodes.push(Node::Branch(new_root));
+        self.nodes[new_root_id as usize] = Node::Branch(new_root);
         self.root = new_root_id.clone();
 
         let left_node: &mut Node<BRANCH_SIZE, LEAF_SIZE> = &mut self.nodes[left as usize];
         left_node.set_parent(new_root_id.clone());
         let left_total_size = self.get_total_size_for_node(left);
+        let left_total_chars = self.get_total_chars_for_node(left);
         let left_items_count = self.get_items_count_for_node(left);
 
         let right_node: &mut Node<BRANCH_SIZE, LEAF_SIZE> = &mut self.nodes[right as usize];
         right_node.set_parent(new_root_id.clone());
         let right_total_size = self.get_total_size_for_node(right);
+        let right_total_chars = self.get_total_chars_for_node(right);
         let right_items_count = self.get_items_count_for_node(right);
 
         let root_node = &mut self.nodes[self.root as usize]
@@ -882,6 +1872,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             .push(BranchItem {
                 node: left,
                 total_size: left_total_size,
+                total_chars: left_total_chars,
                 item_count: left_items_count,
             })
             .expect("insertion failed");
@@ -890,6 +1881,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
             .push(BranchItem {
                 node: right,
                 total_size: right_total_size,
+                total_chars: right_total_chars,
                 item_count: right_items_count,
             })
             .expect("insertion failed");
@@ -914,6 +1906,27 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         }
     }
 
+    fn get_total_chars_for_node(&self, node_index: NodeIndex) -> u32 {
+        let node = &self.nodes[node_index as usize];
+        match node {
+            Node::Branch(branch_node) => {
+                branch_node.items.iter().map(|item| item.total_chars).sum()
+            }
+            Node::Leaf(leaf_node) => leaf_node
+                .items
+                .iter()
+                .map(|item| {
+                    let block = &self.blocks[*item];
+                    if block.deleted {
+                        0
+                    } else {
+                        block.items.char_len() as u32
+                    }
+                })
+                .sum(),
+        }
+    }
+
     fn get_items_count_for_node(&self, node_index: NodeIndex) -> u32 {
         let node = &self.nodes[node_index as usize];
         match node {
@@ -922,83 +1935,131 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         }
     }
 
-    // TODO: remove/improve
-    // pub fn validate(&self) {
-    //     self.validate_branch_metrics();
-    // }
-
-    // fn validate_branch_metrics(&self) {
-    //     for node in self.nodes.iter() {
-    //         match node {
-    //             Node::Branch(branch) => {
-    //                 for item in branch.items.iter() {
-    //                     let actual_total_size = self.get_total_size_for_node(item.node);
-    //                     let actual_item_count = self.get_items_count_for_node(item.node);
-    //                     assert_eq!(actual_total_size, item.total_size);
-    //                     assert_eq!(actual_item_count, item.item_count);
-    //                 }
-    //             }
-    //             _ => {}
-    //         }
-    //     }
-    // }
-
-    // TODO: hide under test flag
-    pub fn render_mermaid_tree(&self) -> String {
-        let mut commands: Vec<String> = Vec::new();
-        commands.push("flowchart TB".to_string());
+    // Checks the metrics of the branches, the links between the nodes and the locations of the
+    // blocks, reporting every inconsistency instead of panicking on the first one
+    pub fn check_integrity(&self) -> Vec<TreeIntegrityError> {
+        let mut errors = Vec::new();
+        let mut leaves = Vec::new();
+        let mut block_leaves: FxHashMap<SequenceBlockId, NodeIndex> = FxHashMap::default();
+
+        let mut to_visit = vec![(self.root, None)];
+        while let Some((node_index, parent)) = to_visit.pop() {
+            let node = match self.nodes.get(node_index as usize) {
+                Some(node) if !self.free_nodes.contains(&node_index) => node,
+                _ => {
+                    errors.push(TreeIntegrityError::MissingNode(node_index));
+                    continue;
+                }
+            };
 
-        for node in self.nodes.iter() {
             match node {
                 Node::Branch(branch) => {
-                    let mut subcommands = Vec::new();
-
-                    for (index, item) in branch.items.iter().enumerate() {
-                        // let block = &self.blocks[item.node];
-                        subcommands.push(format!(
-                            "branchitem{}{}[Size: {:?}, Count: {:?}]",
-                            branch.id, index, item.total_size, item.item_count
-                        ));
-                        subcommands.push(format!(
-                            "branchitem{}{} --> {}",
-                            branch.id, index, item.node,
-                        ));
+                    if branch.parent != parent {
+                        errors.push(TreeIntegrityError::WrongParent(node_index));
                     }
 
-                    commands.push(format!("subgraph {}", branch.id));
-                    commands.push("direction TB".to_string());
-                    commands.extend(subcommands);
-                    commands.push("end".to_string());
+                    // Pushed in reverse, so that the leaves are visited in order
+                    for item in branch.items.iter().rev() {
+                        if item.node as usize >= self.nodes.len() {
+                            errors.push(TreeIntegrityError::MissingNode(item.node));
+                            continue;
+                        }
 
-                    if let Some(parent) = branch.parent {
-                        commands.push(format!("{} -- parent --> {}", branch.id, parent,));
+                        if self.get_total_size_for_node(item.node) != item.total_size
+                            || self.get_total_chars_for_node(item.node) != item.total_chars
+                            || self.get_items_count_for_node(item.node) != item.item_count
+                        {
+                            errors.push(TreeIntegrityError::StaleMetrics(item.node));
+                        }
+                        to_visit.push((item.node, Some(node_index)));
                     }
                 }
                 Node::Leaf(leaf) => {
-                    let mut subcommands = Vec::new();
+                    if leaf.parent != parent {
+                        errors.push(TreeIntegrityError::WrongParent(node_index));
+                    }
 
-                    for (index, item) in leaf.items.iter().enumerate() {
-                        let block = &self.blocks[*item];
-                        subcommands
-                            .push(format!("leafitem{}{}[{:?}]", leaf.id, index, block.items));
+                    for block_index in leaf.items.iter() {
+                        let block = &self.blocks[*block_index];
+                        if self.sequence_id_to_node.get(&block.id) != Some(&node_index) {
+                            errors.push(TreeIntegrityError::WrongLocation(block.id.clone()));
+                        }
+                        block_leaves.insert(block.id.clone(), node_index);
                     }
+                    leaves.push(node_index);
+                }
+            }
+        }
+
+        for (index, node_index) in leaves.iter().enumerate() {
+            let leaf = self.nodes[*node_index as usize]
+                .as_leaf()
+                .expect("not a leaf");
+            let previous = index.checked_sub(1).map(|index| leaves[index]);
+            let next = leaves.get(index + 1).copied();
+            if leaf.previous_block != previous || leaf.next_block != next {
+                errors.push(TreeIntegrityError::BrokenLeafLinks(*node_index));
+            }
+        }
+        if leaves.first() != Some(&self.start) || leaves.last() != Some(&self.end) {
+            errors.push(TreeIntegrityError::WrongBounds);
+        }
+
+        // Locations of blocks that are no longer in the tree
+        let mut stale_locations: Vec<SequenceBlockId> = self
+            .sequence_id_to_node
+            .iter()
+            .filter(|(id, _)| !block_leaves.contains_key(id))
+            .map(|(id, _)| id)
+            .collect();
+        stale_locations.sort_by_key(|id| (id.client_id, id.sequence));
+        errors.extend(
+            stale_locations
+                .into_iter()
+                .map(TreeIntegrityError::WrongLocation),
+        );
+
+        errors
+    }
 
-                    commands.push(format!("subgraph {}", leaf.id));
-                    commands.push("direction TB".to_string());
-                    commands.extend(subcommands);
-                    commands.push("end".to_string());
+    // One node per tree node, listing the blocks of the leaves in order
+    #[cfg(feature = "debug-tools")]
+    pub fn render_debug_graph(
+        &self,
+        graph: &mut DebugGraph,
+        prefix: &str,
+        describe: impl Fn(&SequenceBlock<Items>, &Items::Storage) -> String,
+    ) {
+        let node_id = |node: NodeIndex| format!("{}_n{}", prefix, node);
+        let mut to_visit = vec![self.root];
+        while let Some(node_index) = to_visit.pop() {
+            match &self.nodes[node_index as usize] {
+                Node::Branch(branch) => {
+                    let sizes: Vec<String> = branch
+                        .items
+                        .iter()
+                        .map(|item| format!("{}:{}", item.total_size, item.item_count))
+                        .collect();
+                    graph.node(node_id(node_index), format!("B [{}]", sizes.join(", ")));
 
-                    if let Some(parent) = leaf.parent {
-                        commands.push(format!("{} -- parent --> {}", leaf.id, parent,));
+                    for item in branch.items.iter() {
+                        graph.edge(node_id(node_index), node_id(item.node));
+                        to_visit.push(item.node);
                     }
                 }
+                Node::Leaf(leaf) => {
+                    let blocks: Vec<String> = leaf
+                        .items
+                        .iter()
+                        .map(|block| describe(&self.blocks[*block], &self.storage))
+                        .collect();
+                    graph.node(node_id(node_index), format!("L [{}]", blocks.join(", ")));
+                }
             }
         }
-
-        commands.join("\n")
     }
 
-    // #[cfg(test)]
+    #[cfg(test)]
     pub fn render_debug_tree(&self) -> String {
         let mut buffer = String::new();
 
@@ -1007,7 +2068,7 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
         buffer
     }
 
-    // #[cfg(test)]
+    #[cfg(test)]
     fn generate_debug_tree_recursively(&self, node_index: NodeIndex, buffer: &mut String) {
         let node = &self.nodes[node_index as usize];
         match node {
@@ -1044,27 +2105,60 @@ impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
 }
 
 pub trait Sizable {
+    // Data owned by the tree and shared by all the items, e.g. an arena with their contents,
+    // so that each block doesn't need its own allocation
+    type Storage: Default + Clone + PartialEq + std::fmt::Debug;
+
     fn len(&self) -> usize;
+
+    // Number of chars, which can be lower than `len` for multi-byte items
+    fn char_len(&self) -> usize;
+
+    // Offset of the given char, or `len` when past the end
+    fn char_offset(&self, storage: &Self::Storage, char_index: usize) -> usize;
+
+    fn is_boundary(&self, storage: &Self::Storage, offset: usize) -> bool;
 }
 
-pub trait Splittable {
-    fn split(&mut self, offset: usize) -> Self;
+pub trait Splittable: Sizable {
+    fn split(&mut self, storage: &Self::Storage, offset: usize) -> Self;
 }
 
 pub trait Mergeable {
     fn push(&mut self, items: Self);
+
+    // Whether `push` can be called with the given items
+    fn can_push(&self, _items: &Self) -> bool {
+        true
+    }
 }
 
 pub trait SequenceItems: Sizable + Splittable + Mergeable + std::fmt::Debug {}
 
 impl Sizable for String {
+    type Storage = ();
+
     fn len(&self) -> usize {
         self.len()
     }
+
+    fn char_len(&self) -> usize {
+        self.chars().count()
+    }
+
+    fn char_offset(&self, _storage: &(), char_index: usize) -> usize {
+        self.char_indices()
+            .nth(char_index)
+            .map_or(self.len(), |(offset, _)| offset)
+    }
+
+    fn is_boundary(&self, _storage: &(), offset: usize) -> bool {
+        self.is_char_boundary(offset)
+    }
 }
 
 impl Splittable for String {
-    fn split(&mut self, offset: usize) -> Self {
+    fn split(&mut self, _storage: &(), offset: usize) -> Self {
         let right_part = self.split_off(offset);
         return right_part;
     }
@@ -1078,6 +2172,112 @@ impl Mergeable for String {
 
 impl SequenceItems for String {}
 
+// Nodes refer to blocks by index, so only the ids need to be rewritten
+impl<Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize> ClientRemappable
+    for SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>
+{
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        for block in self.blocks.iter_mut() {
+            block.id.remap_client_ids(mappings);
+            if let Some(left) = block.left.as_mut() {
+                left.remap_client_ids(mappings);
+            }
+        }
+
+        self.block_children = std::mem::take(&mut self.block_children)
+            .into_iter()
+            .map(|(mut parent, mut children)| {
+                parent.remap_client_ids(mappings);
+                for child in children.iter_mut() {
+                    child.remap_client_ids(mappings);
+                }
+                (parent, children)
+            })
+            .collect();
+
+        for root_block in self.root_blocks.iter_mut() {
+            root_block.remap_client_ids(mappings);
+        }
+
+        self.non_continuations = std::mem::take(&mut self.non_continuations)
+            .into_iter()
+            .map(|mut id| {
+                id.remap_client_ids(mappings);
+                id
+            })
+            .collect();
+
+        self.sequence_id_to_node.remap_client_ids(mappings);
+
+        self.latest_descendents = std::mem::take(&mut self.latest_descendents)
+            .into_iter()
+            .map(|(mut source, mut latest_descendent)| {
+                source.remap_client_ids(mappings);
+                latest_descendent.remap_client_ids(mappings);
+                (source, latest_descendent)
+            })
+            .collect();
+
+        self.latest_descendent_sources = std::mem::take(&mut self.latest_descendent_sources)
+            .into_iter()
+            .map(|(mut latest_descendent, mut sources)| {
+                latest_descendent.remap_client_ids(mappings);
+                for source in sources.iter_mut() {
+                    source.remap_client_ids(mappings);
+                }
+                (latest_descendent, sources)
+            })
+            .collect();
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum SequenceError {
+    #[error("block {0:?} not found")]
+    BlockNotFound(SequenceBlockId),
+
+    #[error("block {0:?} already exists")]
+    DuplicateBlock(SequenceBlockId),
+
+    #[error("block {0:?} is empty")]
+    EmptyBlock(SequenceBlockId),
+
+    #[error("block {0:?} exceeds the range of sequences")]
+    OutOfRange(SequenceBlockId),
+
+    #[error("block {0:?} can't be split at the given position")]
+    NotABoundary(SequenceBlockId),
+
+    #[error("range from {0:?} to {1:?} is not ordered")]
+    InvalidRange(SequenceBlockId, SequenceBlockId),
+
+    #[error("range from {0:?} to {1:?} includes the items of several clients")]
+    MixedClients(SequenceBlockId, SequenceBlockId),
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum TreeIntegrityError {
+    #[error("node {0} is referenced by a branch but doesn't exist")]
+    MissingNode(u32),
+
+    #[error("node {0} doesn't point to the branch that contains it")]
+    WrongParent(u32),
+
+    #[error("sizes of node {0} stored in its parent branch are out of date")]
+    StaleMetrics(u32),
+
+    #[error("leaf {0} is not linked to the leaves next to it")]
+    BrokenLeafLinks(u32),
+
+    #[error("first or last leaf of the tree is out of date")]
+    WrongBounds,
+
+    #[error("location of block {0:?} doesn't point to the leaf that contains it")]
+    WrongLocation(SequenceBlockId),
+}
+
 // TODO: convert to u32?
 type SequenceBlockIndex = usize;
 
@@ -1100,7 +2300,7 @@ impl<Items: SequenceItems> SequenceBlock<Items> {
     }
 }
 
-type NodeIndex = u32;
+pub(crate) type NodeIndex = u32;
 
 #[derive(Debug, Clone, EnumAsInner, PartialEq)]
 enum Node<const BRANCH_SIZE: usize, const LEAF_SIZE: usize> {
@@ -1126,6 +2326,13 @@ impl<const BRANCH_SIZE: usize, const LEAF_SIZE: usize> Node<BRANCH_SIZE, LEAF_SI
         }
     }
 
+    pub fn parent(&self) -> Option<NodeIndex> {
+        match self {
+            Self::Branch(branch_node) => branch_node.parent,
+            Self::Leaf(leaf_node) => leaf_node.parent,
+        }
+    }
+
     pub fn set_parent(&mut self, parent: NodeIndex) {
         match self {
             Self::Branch(branch_node) => branch_node.parent = Some(parent),
@@ -1145,6 +2352,8 @@ struct BranchNode<const BRANCH_SIZE: usize> {
 struct BranchItem {
     node: NodeIndex,
     total_size: u32,
+    // Same as `total_size`, but counting chars instead of bytes
+    total_chars: u32,
     item_count: u32,
 }
 
@@ -1255,29 +2464,65 @@ pub struct SequenceTreeIterator<
     tree: &'a SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>,
     current_node: NodeIndex,
     current_index: usize,
+    // Position right after the last block that is yet to be returned from the back
+    back_node: NodeIndex,
+    back_index: usize,
 }
 
 impl<'a, Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
     SequenceTreeIterator<'a, Items, BRANCH_SIZE, LEAF_SIZE>
 {
     pub fn new(tree: &'a SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>) -> Self {
-        let current_node = tree.start;
+        Self::starting_at(tree, tree.start, 0)
+    }
+
+    fn at_end(tree: &'a SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>) -> Self {
+        let (end_node, end_index) = Self::end_of(tree);
+        Self::starting_at(tree, end_node, end_index)
+    }
+
+    fn starting_at(
+        tree: &'a SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>,
+        current_node: NodeIndex,
+        current_index: usize,
+    ) -> Self {
+        let (back_node, back_index) = Self::end_of(tree);
 
         Self {
             tree,
             current_node,
-            current_index: 0,
+            current_index,
+            back_node,
+            back_index,
         }
     }
+
+    fn end_of(tree: &SequenceTree<Items, BRANCH_SIZE, LEAF_SIZE>) -> (NodeIndex, usize) {
+        let end_index = tree.nodes[tree.end as usize]
+            .as_leaf()
+            .expect("not a leaf")
+            .items
+            .len();
+        (tree.end, end_index)
+    }
+
+    // The two ends have met, so every block has been returned
+    fn is_exhausted(&self) -> bool {
+        self.current_node == self.back_node && self.current_index >= self.back_index
+    }
 }
 
 impl<'a, Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize> Iterator
     for SequenceTreeIterator<'a, Items, BRANCH_SIZE, LEAF_SIZE>
 {
-    type Item = &'a Items;
+    type Item = &'a SequenceBlock<Items>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
+            if self.is_exhausted() {
+                return None;
+            }
+
             let current_node = &self.tree.nodes[self.current_node as usize];
             let current_leaf = current_node.as_leaf().expect("not a leaf");
 
@@ -1303,8 +2548,45 @@ impl<'a, Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize>
                     continue;
                 }
 
-                return Some(&block.items);
+                return Some(block);
+            }
+        }
+    }
+}
+
+// Walks the leaves backwards through the `previous_block` links
+impl<'a, Items: SequenceItems, const BRANCH_SIZE: usize, const LEAF_SIZE: usize> DoubleEndedIterator
+    for SequenceTreeIterator<'a, Items, BRANCH_SIZE, LEAF_SIZE>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.is_exhausted() {
+                return None;
+            }
+
+            let back_leaf = self.tree.nodes[self.back_node as usize]
+                .as_leaf()
+                .expect("not a leaf");
+
+            if self.back_index == 0 {
+                let previous_node = back_leaf.previous_block?;
+                self.back_node = previous_node;
+                self.back_index = self.tree.nodes[previous_node as usize]
+                    .as_leaf()
+                    .expect("not a leaf")
+                    .items
+                    .len();
+                continue;
+            }
+
+            self.back_index -= 1;
+            let block = &self.tree.blocks[back_leaf.items[self.back_index]];
+
+            if block.deleted {
+                continue;
             }
+
+            return Some(block);
         }
     }
 }
@@ -1316,14 +2598,48 @@ mod tests {
     type TestSequenceTree = SequenceTree<String, 2, 2>;
     type TestSequenceBlock = SequenceBlock<String>;
 
-    fn render_as_string(tree: &TestSequenceTree) -> String {
+    fn render_as_string<const BRANCH_SIZE: usize, const LEAF_SIZE: usize>(
+        tree: &SequenceTree<String, BRANCH_SIZE, LEAF_SIZE>,
+    ) -> String {
         let mut result = String::new();
 
-        for item in tree.iter() {
-            result.push_str(&item);
+        for item in tree.iter() {
+            result.push_str(&item);
+        }
+
+        result
+    }
+
+    #[test]
+    fn test_check_integrity_detects_stale_metrics_and_locations() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+        for (index, item) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            tree.insert(TestSequenceBlock::new(
+                SequenceBlockId::new(index as u32, 0),
+                item.to_string(),
+                None,
+            ));
         }
+        assert_eq!(tree.check_integrity(), vec![]);
 
-        result
+        let Node::Branch(root) = &mut tree.nodes[tree.root as usize] else {
+            panic!("expected a branch");
+        };
+        root.items[0].total_size += 1;
+        let stale_node = root.items[0].node;
+        let stale_block = SequenceBlockId::new(4, 0);
+        tree.sequence_id_to_node.remove(&stale_block);
+        tree.sequence_id_to_node
+            .insert(SequenceBlockId::new(5, 0), tree.start);
+
+        assert_eq!(
+            tree.check_integrity(),
+            vec![
+                TreeIntegrityError::StaleMetrics(stale_node),
+                TreeIntegrityError::WrongLocation(stale_block),
+                TreeIntegrityError::WrongLocation(SequenceBlockId::new(5, 0)),
+            ]
+        );
     }
 
     #[test]
@@ -1478,6 +2794,101 @@ mod tests {
         assert_eq!(&tree.render_debug_tree(), r#"L(~"Hello","World")"#);
     }
 
+    #[test]
+    fn test_check_rejects_invalid_references() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "Hello".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(1, 0),
+            "World".to_string(),
+            Some(SequenceBlockId::new(0, 4)),
+        ));
+
+        assert_eq!(
+            tree.check_insert(&SequenceBlockId::new(0, 3), None, 2),
+            Err(SequenceError::DuplicateBlock(SequenceBlockId::new(0, 3)))
+        );
+        assert_eq!(
+            tree.check_insert(
+                &SequenceBlockId::new(0, 5),
+                Some(&SequenceBlockId::new(0, 5)),
+                1
+            ),
+            Err(SequenceError::BlockNotFound(SequenceBlockId::new(0, 5)))
+        );
+        assert_eq!(
+            tree.check_insert(&SequenceBlockId::new(0, 5), None, 0),
+            Err(SequenceError::EmptyBlock(SequenceBlockId::new(0, 5)))
+        );
+        assert_eq!(
+            tree.check_delete(&SequenceBlockId::new(1, 1), &SequenceBlockId::new(0, 2)),
+            Err(SequenceError::InvalidRange(
+                SequenceBlockId::new(1, 1),
+                SequenceBlockId::new(0, 2)
+            ))
+        );
+        assert_eq!(
+            tree.check_delete(&SequenceBlockId::new(0, 2), &SequenceBlockId::new(1, 1)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_insertions_converge_in_any_order() {
+        let block = |id: (u32, u32), value: &str, left: Option<(u32, u32)>| {
+            TestSequenceBlock::new(
+                SequenceBlockId::new(id.0, id.1),
+                value.to_string(),
+                left.map(|(client, sequence)| SequenceBlockId::new(client, sequence)),
+            )
+        };
+        let blocks = [
+            block((0, 0), "ab", None),
+            // Inserted in the middle of the first block
+            block((1, 0), "X", Some((0, 0))),
+            // Continuations of the first two blocks
+            block((0, 2), "c", Some((0, 1))),
+            block((1, 1), "Z", Some((1, 0))),
+            block((2, 0), "Y", Some((0, 1))),
+        ];
+        let dependencies = [None, Some(0), Some(0), Some(1), Some(0)];
+
+        // Every order in which the blocks could be received
+        fn orders(
+            dependencies: &[Option<usize>],
+            order: &mut Vec<usize>,
+            all: &mut Vec<Vec<usize>>,
+        ) {
+            if order.len() == dependencies.len() {
+                all.push(order.clone());
+                return;
+            }
+            for (index, dependency) in dependencies.iter().enumerate() {
+                let is_ready = dependency.is_none_or(|dependency| order.contains(&dependency));
+                if !order.contains(&index) && is_ready {
+                    order.push(index);
+                    orders(dependencies, order, all);
+                    order.pop();
+                }
+            }
+        }
+        let mut all = Vec::new();
+        orders(&dependencies, &mut Vec::new(), &mut all);
+        assert_eq!(all.len(), 12);
+
+        for order in all {
+            let mut tree: TestSequenceTree = SequenceTree::new();
+            for index in &order {
+                tree.insert(blocks[*index].clone());
+            }
+            assert_eq!(render_as_string(&tree), "aXZbYc", "order: {:?}", order);
+        }
+    }
+
     #[test]
     fn test_delete_multiple_words_boundaries() {
         let mut tree: TestSequenceTree = SequenceTree::new();
@@ -1585,6 +2996,530 @@ mod tests {
         assert_eq!(render_as_string(&tree), "HeldEnding");
     }
 
+    #[test]
+    fn test_len_counts_visible_items() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+        assert_eq!(tree.len(), 0);
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "Hello".to_string(),
+            None,
+        ));
+        assert_eq!(tree.len(), 5);
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 5),
+            "World".to_string(),
+            Some(SequenceBlockId::new(0, 4)),
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 10),
+            "Another".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 17),
+            "Test".to_string(),
+            Some(SequenceBlockId::new(0, 9)),
+        ));
+        assert_eq!(tree.len(), 21);
+
+        tree.delete(&SequenceBlockId::new(0, 1), &SequenceBlockId::new(0, 3));
+        assert_eq!(render_as_string(&tree), "AnotherHoWorldTest");
+        assert_eq!(tree.len(), 18);
+    }
+
+    #[test]
+    fn test_delete_across_many_leaves_keeps_branch_metrics() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        // Blocks of different clients are never merged, so every one gets its own slot
+        let mut left = None;
+        for client_id in 0..40 {
+            tree.insert(TestSequenceBlock::new(
+                SequenceBlockId::new(client_id, 0),
+                "ab".to_string(),
+                left,
+            ));
+            left = Some(SequenceBlockId::new(client_id, 1));
+        }
+
+        tree.delete(&SequenceBlockId::new(3, 1), &SequenceBlockId::new(30, 0));
+        tree.delete(&SequenceBlockId::new(20, 0), &SequenceBlockId::new(35, 1));
+
+        let expected = "ab".repeat(3) + "a" + &"ab".repeat(4);
+        assert_eq!(render_as_string(&tree), expected);
+        assert_eq!(tree.len(), expected.len() as u32);
+        assert_eq!(tree.len_chars(), expected.len() as u32);
+
+        for node in &tree.nodes {
+            if let Node::Branch(branch_node) = node {
+                for item in &branch_node.items {
+                    assert_eq!(item.total_size, tree.get_total_size_for_node(item.node));
+                    assert_eq!(item.total_chars, tree.get_total_chars_for_node(item.node));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_deleting_overlapping_ranges_is_idempotent() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        let mut left = None;
+        for client_id in 0..40 {
+            tree.insert(TestSequenceBlock::new(
+                SequenceBlockId::new(client_id, 0),
+                "ab".to_string(),
+                left,
+            ));
+            left = Some(SequenceBlockId::new(client_id, 1));
+        }
+
+        // The same range twice, then one that overlaps it on both sides
+        let mut deleted_blocks = 0;
+        for (from, to) in [((3, 1), (30, 0)), ((3, 1), (30, 0)), ((2, 0), (32, 1))] {
+            tree.delete_with(
+                &SequenceBlockId::new(from.0, from.1),
+                &SequenceBlockId::new(to.0, to.1),
+                |_| deleted_blocks += 1,
+            );
+        }
+
+        let expected = "ab".repeat(2) + &"ab".repeat(7);
+        assert_eq!(render_as_string(&tree), expected);
+        assert_eq!(tree.len(), expected.len() as u32);
+        assert_eq!(tree.len_chars(), expected.len() as u32);
+        assert_eq!(tree.check_integrity(), vec![]);
+
+        // Blocks are split at the boundaries of the first range: 3..=30 in the first deletion,
+        // 2, 3 (left part), 30 (right part), 31 and 32 in the last one
+        assert_eq!(deleted_blocks, 28 + 5);
+    }
+
+    #[test]
+    fn test_deleting_items_keeps_the_ones_inserted_between() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "Hello".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(1, 0),
+            "XY".to_string(),
+            Some(SequenceBlockId::new(0, 1)),
+        ));
+        assert_eq!(render_as_string(&tree), "HeXYllo");
+
+        let (from, to) = (SequenceBlockId::new(0, 1), SequenceBlockId::new(1, 0));
+        assert_eq!(
+            tree.delete_items_with(&[(&from, &to)], |_| {}),
+            Err(SequenceError::MixedClients(from, to))
+        );
+
+        let (from, to) = (SequenceBlockId::new(0, 1), SequenceBlockId::new(0, 3));
+        tree.delete_items_with(&[(&from, &to)], |_| {}).unwrap();
+        assert_eq!(render_as_string(&tree), "HXYo");
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.check_integrity(), vec![]);
+    }
+
+    #[test]
+    fn test_iter_blocks_from_skips_previous_blocks() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        let mut left = None;
+        for client_id in 0..20 {
+            tree.insert(TestSequenceBlock::new(
+                SequenceBlockId::new(client_id, 0),
+                format!("{:03}", client_id),
+                left,
+            ));
+            left = Some(SequenceBlockId::new(client_id, 2));
+        }
+        tree.delete(&SequenceBlockId::new(5, 0), &SequenceBlockId::new(9, 2));
+
+        let expected = render_as_string(&tree);
+        for position in 0..tree.len() {
+            let (offset, blocks) = tree.iter_blocks_from(position);
+            let rest: String = blocks.map(|block| block.items.as_str()).collect();
+            assert_eq!(&rest[offset as usize..], &expected[position as usize..]);
+            assert!(offset < 3);
+        }
+
+        let (_, mut blocks) = tree.iter_blocks_from(tree.len());
+        assert!(blocks.next().is_none());
+    }
+
+    #[test]
+    fn test_iter_from_both_ends() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        let mut left = None;
+        for client_id in 0..10 {
+            tree.insert(TestSequenceBlock::new(
+                SequenceBlockId::new(client_id, 0),
+                client_id.to_string(),
+                left,
+            ));
+            left = Some(SequenceBlockId::new(client_id, 0));
+        }
+        tree.delete(&SequenceBlockId::new(0, 0), &SequenceBlockId::new(0, 0));
+        tree.delete(&SequenceBlockId::new(9, 0), &SequenceBlockId::new(9, 0));
+        tree.delete(&SequenceBlockId::new(4, 0), &SequenceBlockId::new(4, 0));
+
+        let reversed: String = tree.iter().rev().map(String::as_str).collect();
+        assert_eq!(reversed, "8765321");
+
+        // The two ends meet without returning a block twice
+        let mut blocks = tree.iter_blocks();
+        let mut result = Vec::new();
+        while let Some(block) = blocks.next() {
+            result.push(block.items.as_str());
+            if let Some(block) = blocks.next_back() {
+                result.push(block.items.as_str());
+            }
+        }
+        assert_eq!(result, vec!["1", "8", "2", "7", "3", "6", "5"]);
+
+        let (offset, blocks) = tree.iter_blocks_from(3);
+        assert_eq!(offset, 0);
+        let tail: Vec<&str> = blocks.rev().map(|block| block.items.as_str()).collect();
+        assert_eq!(tail, vec!["8", "7", "6", "5"]);
+
+        let empty: TestSequenceTree = SequenceTree::new();
+        assert!(empty.iter().next_back().is_none());
+    }
+
+    #[test]
+    fn test_cached_latest_descendents_match_a_full_search() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+        let mut items: Vec<SequenceBlockId> = Vec::new();
+        let mut next_sequences = [0; 4];
+
+        // Deterministic pseudo-random insertions, to get deeply nested concurrent blocks
+        let mut seed: u64 = 42;
+        let mut random = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+
+        for _ in 0..300 {
+            let client_id = random(4);
+            let len = 1 + random(3) as u32;
+            let left = match random(items.len() + 1) {
+                0 => None,
+                index => Some(items[index - 1].clone()),
+            };
+            let id = SequenceBlockId::new(client_id as u32, next_sequences[client_id]);
+            next_sequences[client_id] += len;
+
+            tree.insert(TestSequenceBlock::new(
+                id.clone(),
+                "x".repeat(len as usize),
+                left,
+            ));
+            items.extend(
+                (0..len).map(|offset| SequenceBlockId::new(id.client_id, id.sequence + offset)),
+            );
+
+            let mut uncached = tree.clone();
+            uncached.latest_descendents.clear();
+            for item in items.iter().step_by(7) {
+                assert_eq!(
+                    tree.find_latest_descendent(item),
+                    uncached.search_latest_descendent(item)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_deleting_twice_keeps_metrics() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "Hello".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 5),
+            "World".to_string(),
+            None,
+        ));
+
+        tree.delete(&SequenceBlockId::new(0, 1), &SequenceBlockId::new(0, 3));
+        tree.delete(&SequenceBlockId::new(0, 0), &SequenceBlockId::new(0, 4));
+        assert_eq!(render_as_string(&tree), "World");
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.len_chars(), 5);
+
+        // Inserting inside a deleted block splits it
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 10),
+            "Again".to_string(),
+            Some(SequenceBlockId::new(0, 2)),
+        ));
+        assert_eq!(render_as_string(&tree), "WorldAgain");
+        assert_eq!(tree.len(), 10);
+        assert_eq!(tree.len_chars(), 10);
+    }
+
+    #[test]
+    fn test_char_metrics_with_multi_byte_items() {
+        let mut tree: TestSequenceTree = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "héllo".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 6),
+            "wörld".to_string(),
+            Some(SequenceBlockId::new(0, 5)),
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 12),
+            "日本".to_string(),
+            None,
+        ));
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 18),
+            "✓".to_string(),
+            Some(SequenceBlockId::new(0, 17)),
+        ));
+        assert_eq!(render_as_string(&tree), "日本✓héllowörld");
+        assert_eq!(tree.len(), 21);
+        assert_eq!(tree.len_chars(), 13);
+
+        assert_eq!(tree.char_to_position(0), Some(0));
+        assert_eq!(tree.char_to_position(2), Some(6));
+        assert_eq!(tree.char_to_position(4), Some(10));
+        assert_eq!(tree.char_to_position(12), Some(20));
+        assert_eq!(tree.char_to_position(13), Some(21));
+        assert_eq!(tree.char_to_position(14), None);
+
+        assert!(tree.is_boundary(0));
+        assert!(!tree.is_boundary(1));
+        assert!(tree.is_boundary(6));
+        assert!(!tree.is_boundary(11));
+        assert!(tree.is_boundary(21));
+
+        // Delete the "é"
+        tree.delete(&SequenceBlockId::new(0, 1), &SequenceBlockId::new(0, 2));
+        assert_eq!(render_as_string(&tree), "日本✓hllowörld");
+        assert_eq!(tree.len_chars(), 12);
+        assert_eq!(tree.char_to_position(4), Some(10));
+        assert_eq!(tree.char_to_position(12), Some(19));
+    }
+
+    // Checks the metrics of the reachable branches, the links between the leaves and that
+    // every block is referenced by exactly one leaf
+    fn assert_consistent<const BRANCH_SIZE: usize, const LEAF_SIZE: usize>(
+        tree: &SequenceTree<String, BRANCH_SIZE, LEAF_SIZE>,
+    ) {
+        let mut leaves = Vec::new();
+        let mut pending = vec![tree.root];
+        while let Some(node_index) = pending.pop() {
+            assert!(!tree.free_nodes.contains(&node_index));
+            match &tree.nodes[node_index as usize] {
+                Node::Branch(branch_node) => {
+                    for item in branch_node.items.iter().rev() {
+                        assert_eq!(tree.nodes[item.node as usize].parent(), Some(node_index));
+                        assert_eq!(item.total_size, tree.get_total_size_for_node(item.node));
+                        assert_eq!(item.total_chars, tree.get_total_chars_for_node(item.node));
+                        assert_eq!(item.item_count, tree.get_items_count_for_node(item.node));
+                        pending.push(item.node);
+                    }
+                }
+                Node::Leaf(_) => leaves.push(node_index),
+            }
+        }
+
+        let mut linked_leaves = vec![tree.start];
+        while let Some(next) = tree.nodes[*linked_leaves.last().unwrap() as usize]
+            .as_leaf()
+            .unwrap()
+            .next_block
+        {
+            let previous = tree.nodes[next as usize].as_leaf().unwrap().previous_block;
+            assert_eq!(previous, linked_leaves.last().cloned());
+            linked_leaves.push(next);
+        }
+        assert_eq!(linked_leaves, leaves);
+        assert_eq!(tree.end, *leaves.last().unwrap());
+
+        let mut block_count = 0;
+        for leaf_index in leaves {
+            for block_index in tree.nodes[leaf_index as usize]
+                .as_leaf()
+                .unwrap()
+                .items
+                .iter()
+            {
+                let block = &tree.blocks[*block_index];
+                assert_eq!(tree.sequence_id_to_node[&block.id], leaf_index);
+                block_count += 1;
+            }
+        }
+        assert_eq!(block_count, tree.blocks.len());
+        assert_eq!(tree.sequence_id_to_node.len(), tree.blocks.len());
+    }
+
+    #[test]
+    fn test_delete_merges_adjacent_tombstones() {
+        let mut tree: SequenceTree<String, 8, 8> = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "HelloWorld".to_string(),
+            None,
+        ));
+
+        tree.delete(&SequenceBlockId::new(0, 1), &SequenceBlockId::new(0, 2));
+        tree.delete(&SequenceBlockId::new(0, 5), &SequenceBlockId::new(0, 6));
+        assert_eq!(
+            &tree.render_debug_tree(),
+            r#"L("H",~"el","lo",~"Wo","rld")"#
+        );
+
+        tree.delete(&SequenceBlockId::new(0, 3), &SequenceBlockId::new(0, 4));
+        assert_eq!(render_as_string(&tree), "Hrld");
+        assert_eq!(&tree.render_debug_tree(), r#"L("H",~"elloWo","rld")"#);
+        assert_consistent(&tree);
+
+        // Insertions inside the merged tombstone split it again
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 10),
+            "!".to_string(),
+            Some(SequenceBlockId::new(0, 4)),
+        ));
+        assert_eq!(render_as_string(&tree), "H!rld");
+        assert_eq!(
+            &tree.render_debug_tree(),
+            r#"L("H",~"ello","!",~"Wo","rld")"#
+        );
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn test_delete_keeps_unrelated_tombstones_apart() {
+        let mut tree: SequenceTree<String, 4, 4> = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "Hello".to_string(),
+            None,
+        ));
+        // Inserted by another client between "He" and "llo"
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(1, 0),
+            "y".to_string(),
+            Some(SequenceBlockId::new(0, 1)),
+        ));
+        tree.delete(&SequenceBlockId::new(1, 0), &SequenceBlockId::new(1, 0));
+        tree.delete(&SequenceBlockId::new(0, 0), &SequenceBlockId::new(0, 4));
+
+        // "He" has a child, so the continuation can't be merged into it
+        assert_eq!(render_as_string(&tree), "");
+        assert_eq!(&tree.render_debug_tree(), r#"L(~"He",~"y",~"llo")"#);
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn test_delete_merges_underflowing_leaves() {
+        let mut tree: SequenceTree<String, 4, 4> = SequenceTree::new();
+
+        tree.insert(TestSequenceBlock::new(
+            SequenceBlockId::new(0, 0),
+            "abcdefghijklmnopqrstuvwxyz".to_string(),
+            None,
+        ));
+        for sequence in (0..26).step_by(2) {
+            let id = SequenceBlockId::new(0, sequence);
+            tree.delete(&id, &id);
+        }
+        assert_eq!(render_as_string(&tree), "bdfhjlnprtvxz");
+        assert!(tree.nodes[tree.root as usize].is_branch());
+        assert_consistent(&tree);
+
+        tree.delete(&SequenceBlockId::new(0, 1), &SequenceBlockId::new(0, 23));
+        assert_eq!(render_as_string(&tree), "z");
+        assert_eq!(tree.len(), 1);
+        assert_eq!(
+            &tree.render_debug_tree(),
+            r#"L(~"abcdefghijklmnopqrstuvwxy","z")"#
+        );
+        assert!(!tree.free_nodes.is_empty());
+        assert_consistent(&tree);
+
+        // Freed nodes are reused by the next splits
+        let mut left = SequenceBlockId::new(0, 25);
+        for sequence in 26..46 {
+            let id = SequenceBlockId::new(1, sequence);
+            tree.insert(TestSequenceBlock::new(
+                id.clone(),
+                "0".to_string(),
+                Some(left),
+            ));
+            left = id;
+        }
+        assert_eq!(render_as_string(&tree), "z".to_string() + &"0".repeat(20));
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn test_automerge_trace_keeps_tombstones_compact() {
+        let trace: serde_json::Value =
+            serde_json::from_str(include_str!("../../../benches/automerge-trace/trace.json"))
+                .unwrap();
+        let expected: serde_json::Value =
+            serde_json::from_str(include_str!("../../../benches/automerge-trace/final.json"))
+                .unwrap();
+
+        let mut tree: SequenceTree<String, 32, 32> = SequenceTree::new();
+        let mut next_sequence = 0;
+        for edit in trace.as_array().unwrap() {
+            let edit = edit.as_array().unwrap();
+            let char_index = edit[0].as_u64().unwrap() as u32;
+            let position = tree.char_to_position(char_index).unwrap();
+
+            if edit[1] == 0 {
+                let content = edit[2].as_str().unwrap().to_string();
+                let id = SequenceBlockId::new(0, next_sequence);
+                next_sequence += content.len() as u32;
+                let left = tree.find_id_ending_at_position(position);
+                tree.insert(TestSequenceBlock::new(id, content, left));
+            } else {
+                let end_char = char_index + edit[1].as_u64().unwrap() as u32;
+                let end = tree.char_to_position(end_char).unwrap();
+                let from = tree.find_id_starting_at_position(position).unwrap();
+                let to = tree.find_id_ending_at_position(end).unwrap();
+                tree.delete(&from, &to);
+            }
+        }
+
+        assert_eq!(render_as_string(&tree), expected.as_str().unwrap());
+        assert_consistent(&tree);
+
+        // Tombstones that continue each other are merged, so that no more than one sits
+        // between two visible blocks
+        for node in tree.nodes.iter() {
+            if let Node::Leaf(leaf_node) = node {
+                for pair in leaf_node.items.windows(2) {
+                    assert!(!tree.is_tombstone_mergeable(pair[0], pair[1]));
+                }
+            }
+        }
+    }
+
     // #[test]
     // fn test_get_item_starting_at_position() {
     //     let mut tree: SequenceTree<TestItem, 2, 2> = SequenceTree::new();
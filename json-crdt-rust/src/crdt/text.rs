@@ -1,32 +1,411 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    ops::Range,
+};
 
-use crate::{ClientId, DeleteTextAction, InsertTextAction, SequenceBlockId, SequenceIndex};
+use rustc_hash::FxHashMap;
 
-use super::shared::tree::{SequenceBlock, SequenceTree};
+use crate::{
+    client_registry::{ClientRemappable, ClientRemappings},
+    AnchorBias, ClientId, CreateAnnotationAction, DeleteTextAction, DeleteTextItemsAction,
+    DeleteTextRangesAction, DeletedTextRange, InsertTextAction, OperationId, SequenceBlockId,
+    SequenceIndex, TextFanout,
+};
 
-// TODO: fine-tune them
-const BRANCH_SIZE: usize = 32;
-const LEAF_SIZE: usize = 32;
+use super::shared::tree::{
+    Mergeable, SequenceBlock, SequenceError, SequenceItems, SequenceTree, Sizable, Splittable,
+    TreeIntegrityError,
+};
+
+#[cfg(feature = "debug-tools")]
+use crate::debug::{DebugGraph, FormatId};
+
+// Bytes of dropped contents tolerated in the arena before rebuilding it
+const MIN_ARENA_SLACK: usize = 4096;
+
+// Larger insertions are stored as several blocks with contiguous ids, so that editing inside
+// a pasted text doesn't keep splitting (and copying the metrics of) a huge block
+pub const DEFAULT_MAX_BLOCK_LEN: u32 = 4096;
 
 #[derive(Clone, PartialEq)]
 pub struct TextCRDT {
     client: ClientId,
     next_available_sequence: SequenceIndex,
+    // Whether deleted blocks should only keep their length
+    drop_tombstones: bool,
+    max_block_len: u32,
+
+    tree: TextTree,
+    annotations: Vec<TextAnnotation>,
+}
+
+// Anchors of an annotation, keyed by the operation that created it
+#[derive(Clone, PartialEq)]
+struct TextAnnotation {
+    id: OperationId,
+    start: Option<SequenceBlockId>,
+    end: Option<SequenceBlockId>,
+    bias: AnchorBias,
+}
+
+type TextBlock = SequenceBlock<TextItems>;
+
+// Contents of a text block, as a range of the arena shared by the whole text (the tree
+// storage), so that blocks don't need their own allocation. Deleted blocks are still needed
+// to order concurrent insertions, but only their ids and lengths matter, so their contents
+// can be dropped.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TextItems {
+    Text { start: u32, len: u32, chars: u32 },
+    Tombstone(usize),
+}
+
+impl TextItems {
+    // Appends the value to the arena, returning the items that refer to it
+    fn append(arena: &mut String, value: &str) -> Self {
+        let start = arena.len() as u32;
+        arena.push_str(value);
+        Self::Text {
+            start,
+            len: value.len() as u32,
+            chars: value.chars().count() as u32,
+        }
+    }
+
+    pub fn as_str<'a>(&self, arena: &'a str) -> &'a str {
+        match self {
+            Self::Text { start, len, .. } => &arena[*start as usize..(*start + *len) as usize],
+            Self::Tombstone(_) => "",
+        }
+    }
+
+    fn drop_contents(&mut self) {
+        if let Self::Text { len, .. } = self {
+            *self = Self::Tombstone(*len as usize);
+        }
+    }
+}
+
+impl Sizable for TextItems {
+    type Storage = String;
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Text { len, .. } => *len as usize,
+            Self::Tombstone(len) => *len,
+        }
+    }
+
+    // Tombstones are never visible, so they don't need to know their chars
+    fn char_len(&self) -> usize {
+        match self {
+            Self::Text { chars, .. } => *chars as usize,
+            Self::Tombstone(_) => 0,
+        }
+    }
+
+    fn char_offset(&self, arena: &String, char_index: usize) -> usize {
+        match self {
+            Self::Text { .. } => {
+                let text = self.as_str(arena);
+                text.char_indices()
+                    .nth(char_index)
+                    .map(|(offset, _)| offset)
+                    .unwrap_or(text.len())
+            }
+            Self::Tombstone(len) => *len,
+        }
+    }
+
+    fn is_boundary(&self, arena: &String, offset: usize) -> bool {
+        match self {
+            Self::Text { .. } => self.as_str(arena).is_char_boundary(offset),
+            Self::Tombstone(_) => true,
+        }
+    }
+}
+
+impl Splittable for TextItems {
+    fn split(&mut self, arena: &String, offset: usize) -> Self {
+        match self {
+            Self::Text { start, len, chars } => {
+                let left_start = *start as usize;
+                let left_chars = arena[left_start..left_start + offset].chars().count() as u32;
+                let right = Self::Text {
+                    start: *start + offset as u32,
+                    len: *len - offset as u32,
+                    chars: *chars - left_chars,
+                };
+                *len = offset as u32;
+                *chars = left_chars;
+                right
+            }
+            Self::Tombstone(len) => {
+                let right_len = *len - offset;
+                *len = offset;
+                Self::Tombstone(right_len)
+            }
+        }
+    }
+}
+
+impl Mergeable for TextItems {
+    fn push(&mut self, items: Self) {
+        match (self, items) {
+            (
+                Self::Text { len, chars, .. },
+                Self::Text {
+                    len: items_len,
+                    chars: items_chars,
+                    ..
+                },
+            ) => {
+                *len += items_len;
+                *chars += items_chars;
+            }
+            (this, items) => *this = Self::Tombstone(this.len() + items.len()),
+        }
+    }
+
+    // Texts can only be merged when their ranges are adjacent in the arena
+    fn can_push(&self, items: &Self) -> bool {
+        match (self, items) {
+            (
+                Self::Text { start, len, .. },
+                Self::Text {
+                    start: next_start, ..
+                },
+            ) => start + len == *next_start,
+            _ => true,
+        }
+    }
+}
+
+impl SequenceItems for TextItems {}
+
+// The node sizes are const generics of the tree, so each fanout is a different type
+#[derive(Clone, PartialEq)]
+enum TextTree {
+    Narrow(SequenceTree<TextItems, 16, 16>),
+    Medium(SequenceTree<TextItems, 32, 32>),
+    Wide(SequenceTree<TextItems, 64, 64>),
+}
 
-    tree: SequenceTree<String, BRANCH_SIZE, LEAF_SIZE>,
+// Runs the body with `$tree` bound to the tree, whatever its fanout
+macro_rules! with_tree {
+    ($text_tree:expr, $tree:ident => $body:expr) => {
+        match $text_tree {
+            TextTree::Narrow($tree) => $body,
+            TextTree::Medium($tree) => $body,
+            TextTree::Wide($tree) => $body,
+        }
+    };
 }
 
-type StringBlock = SequenceBlock<String>;
+impl TextTree {
+    fn new(fanout: TextFanout) -> Self {
+        match fanout {
+            TextFanout::Narrow => Self::Narrow(SequenceTree::new()),
+            TextFanout::Medium => Self::Medium(SequenceTree::new()),
+            TextFanout::Wide => Self::Wide(SequenceTree::new()),
+        }
+    }
+
+    fn fanout(&self) -> TextFanout {
+        match self {
+            Self::Narrow(_) => TextFanout::Narrow,
+            Self::Medium(_) => TextFanout::Medium,
+            Self::Wide(_) => TextFanout::Wide,
+        }
+    }
+
+    fn check_insert(
+        &self,
+        id: &SequenceBlockId,
+        left: Option<&SequenceBlockId>,
+        len: usize,
+    ) -> Result<(), SequenceError> {
+        with_tree!(self, tree => tree.check_insert(id, left, len))
+    }
+
+    fn insert(&mut self, block: TextBlock) {
+        with_tree!(self, tree => tree.insert(block))
+    }
+
+    fn contains(&self, id: &SequenceBlockId) -> bool {
+        with_tree!(self, tree => tree.contains(id))
+    }
+
+    fn check_delete(
+        &self,
+        from: &SequenceBlockId,
+        to: &SequenceBlockId,
+    ) -> Result<(), SequenceError> {
+        with_tree!(self, tree => tree.check_delete(from, to))
+    }
+
+    fn delete_with(
+        &mut self,
+        from: &SequenceBlockId,
+        to: &SequenceBlockId,
+        on_delete: impl FnMut(&mut TextItems),
+    ) {
+        with_tree!(self, tree => tree.delete_with(from, to, on_delete))
+    }
+
+    fn delete(&mut self, from: &SequenceBlockId, to: &SequenceBlockId) {
+        with_tree!(self, tree => tree.delete(from, to))
+    }
+
+    fn delete_ranges_with(
+        &mut self,
+        ranges: &[(&SequenceBlockId, &SequenceBlockId)],
+        on_delete: impl FnMut(&mut TextItems),
+    ) -> Result<(), SequenceError> {
+        with_tree!(self, tree => tree.delete_ranges_with(ranges, on_delete))
+    }
+
+    fn delete_items_with(
+        &mut self,
+        ranges: &[(&SequenceBlockId, &SequenceBlockId)],
+        on_delete: impl FnMut(&mut TextItems),
+    ) -> Result<(), SequenceError> {
+        with_tree!(self, tree => tree.delete_items_with(ranges, on_delete))
+    }
+
+    fn storage(&self) -> &String {
+        with_tree!(self, tree => tree.storage())
+    }
+
+    fn storage_mut(&mut self) -> &mut String {
+        with_tree!(self, tree => tree.storage_mut())
+    }
+
+    fn set_block_len_limit(&mut self, limit: u32) {
+        with_tree!(self, tree => tree.set_block_len_limit(limit))
+    }
+
+    fn for_each_deleted(&mut self, action: impl FnMut(&mut TextItems)) {
+        with_tree!(self, tree => tree.for_each_deleted(action))
+    }
+
+    fn for_each_items_mut(&mut self, action: impl FnMut(&mut TextItems)) {
+        with_tree!(self, tree => tree.for_each_items_mut(action))
+    }
+
+    fn len(&self) -> u32 {
+        with_tree!(self, tree => tree.len())
+    }
+
+    fn len_chars(&self) -> u32 {
+        with_tree!(self, tree => tree.len_chars())
+    }
+
+    fn block_count(&self) -> usize {
+        with_tree!(self, tree => tree.block_count())
+    }
+
+    fn tombstones(&self) -> (usize, usize) {
+        with_tree!(self, tree => tree.tombstones())
+    }
+
+    fn node_count(&self) -> usize {
+        with_tree!(self, tree => tree.node_count())
+    }
+
+    fn check_integrity(&self) -> Vec<TreeIntegrityError> {
+        with_tree!(self, tree => tree.check_integrity())
+    }
+
+    #[cfg(feature = "debug-tools")]
+    fn render_debug_graph(
+        &self,
+        graph: &mut DebugGraph,
+        prefix: &str,
+        describe: impl Fn(&TextBlock, &String) -> String,
+    ) {
+        with_tree!(self, tree => tree.render_debug_graph(graph, prefix, describe))
+    }
+
+    fn char_to_position(&self, char_index: u32) -> Option<u32> {
+        with_tree!(self, tree => tree.char_to_position(char_index))
+    }
+
+    fn is_boundary(&self, position: u32) -> bool {
+        with_tree!(self, tree => tree.is_boundary(position))
+    }
+
+    fn find_id_starting_at_position(&self, position: u32) -> Option<SequenceBlockId> {
+        with_tree!(self, tree => tree.find_id_starting_at_position(position))
+    }
+
+    fn find_id_ending_at_position(&self, position: u32) -> Option<SequenceBlockId> {
+        with_tree!(self, tree => tree.find_id_ending_at_position(position))
+    }
+
+    fn last_block(&self) -> Option<SequenceBlockId> {
+        with_tree!(self, tree => tree.last_block())
+    }
+
+    fn max_child_sequence(&self, left: Option<&SequenceBlockId>) -> Option<SequenceIndex> {
+        with_tree!(self, tree => tree.max_child_sequence(left))
+    }
+
+    fn iter_blocks_from(&self, position: u32) -> (u32, Box<dyn Iterator<Item = &TextBlock> + '_>) {
+        with_tree!(self, tree => {
+            let (offset, blocks) = tree.iter_blocks_from(position);
+            (offset, Box::new(blocks))
+        })
+    }
+
+    fn iter_blocks(&self) -> Box<dyn DoubleEndedIterator<Item = &TextBlock> + '_> {
+        with_tree!(self, tree => Box::new(tree.iter_blocks()))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &TextItems> + '_> {
+        with_tree!(self, tree => Box::new(tree.iter()))
+    }
+
+    fn iter_all_blocks(&self) -> Box<dyn Iterator<Item = &TextBlock> + '_> {
+        with_tree!(self, tree => Box::new(tree.iter_all_blocks()))
+    }
+}
+
+impl ClientRemappable for TextTree {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        with_tree!(self, tree => tree.remap_client_ids(mappings))
+    }
+}
 
 impl TextCRDT {
     pub fn new(client: ClientId) -> Self {
+        Self::new_with_fanout(client, TextFanout::default())
+    }
+
+    pub fn new_with_fanout(client: ClientId, fanout: TextFanout) -> Self {
+        let mut tree = TextTree::new(fanout);
+        tree.set_block_len_limit(DEFAULT_MAX_BLOCK_LEN);
+
         Self {
             client,
             next_available_sequence: 0,
-            tree: SequenceTree::new(),
+            drop_tombstones: false,
+            max_block_len: DEFAULT_MAX_BLOCK_LEN,
+            tree,
+            annotations: Vec::new(),
         }
     }
 
+    // Text made of the given value, as if it had been inserted at once
+    pub fn from_str(client: ClientId, id: SequenceBlockId, value: &str) -> Self {
+        let mut text = Self::new(client);
+        text.insert_blocks(&id, None, value);
+        if id.client_id == client {
+            text.next_available_sequence = id.sequence + value.len() as SequenceIndex;
+        }
+        text
+    }
+
     pub fn next_id(&mut self, length: u32) -> SequenceBlockId {
         let new_sequence = self.next_available_sequence;
         self.next_available_sequence = new_sequence + length;
@@ -36,14 +415,294 @@ impl TextCRDT {
         }
     }
 
-    pub fn insert(&mut self, action: &InsertTextAction) {
-        // TODO: possible optimization, keep only one string copy (the one in the action)
-        let block = StringBlock::new(action.id.clone(), action.value.clone(), action.left.clone());
-        self.tree.insert(block);
+    // Id of a block inserted after `left` (at the start for `None`), which must come before
+    // the blocks already inserted there. Those are ordered by sequence first, so the sequence
+    // skips past theirs, and past the continuation of `left`, which would come after them.
+    pub fn next_id_after(
+        &mut self,
+        left: Option<&SequenceBlockId>,
+        length: u32,
+    ) -> SequenceBlockId {
+        if let Some(max_sequence) = self.tree.max_child_sequence(left) {
+            let mut sequence = self.next_available_sequence.max(max_sequence + 1);
+            if left
+                .is_some_and(|left| left.client_id == self.client && left.sequence + 1 == sequence)
+            {
+                sequence += 1;
+            }
+            self.next_available_sequence = sequence;
+        }
+        self.next_id(length)
+    }
+
+    // Deleted characters are kept as tombstones, so text anchored to a character deleted
+    // concurrently is placed right after it, as if it was still there
+    pub fn insert(&mut self, action: &InsertTextAction) -> Result<(), SequenceError> {
+        self.tree
+            .check_insert(&action.id, action.left.as_ref(), action.value.len())?;
+
+        self.insert_blocks(&action.id, action.left.as_ref(), &action.value);
+
+        // When the text is rebuilt from the log, local ids must not be reused
+        if action.id.client_id == self.client {
+            self.next_available_sequence = self
+                .next_available_sequence
+                .max(action.id.sequence + action.value.len() as SequenceIndex);
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self, action: &DeleteTextAction) -> Result<(), SequenceError> {
+        self.tree.check_delete(&action.left, &action.right)?;
+
+        if self.drop_tombstones {
+            self.tree
+                .delete_with(&action.left, &action.right, TextItems::drop_contents);
+            self.compact_arena_if_sparse();
+        } else {
+            self.tree.delete(&action.left, &action.right);
+        }
+        Ok(())
+    }
+
+    pub fn delete_ranges(&mut self, action: &DeleteTextRangesAction) -> Result<(), SequenceError> {
+        let ranges: Vec<(&SequenceBlockId, &SequenceBlockId)> = action
+            .ranges
+            .iter()
+            .map(|range| (&range.left, &range.right))
+            .collect();
+
+        if self.drop_tombstones {
+            self.tree
+                .delete_ranges_with(&ranges, TextItems::drop_contents)?;
+            self.compact_arena_if_sparse();
+            Ok(())
+        } else {
+            self.tree.delete_ranges_with(&ranges, |_| {})
+        }
+    }
+
+    pub fn delete_items(&mut self, action: &DeleteTextItemsAction) -> Result<(), SequenceError> {
+        let ranges: Vec<(&SequenceBlockId, &SequenceBlockId)> = action
+            .ranges
+            .iter()
+            .map(|range| (&range.left, &range.right))
+            .collect();
+
+        if self.drop_tombstones {
+            self.tree
+                .delete_items_with(&ranges, TextItems::drop_contents)?;
+            self.compact_arena_if_sparse();
+            Ok(())
+        } else {
+            self.tree.delete_items_with(&ranges, |_| {})
+        }
+    }
+
+    // Anchors are never removed from the tree, deleted characters are kept as tombstones
+    pub fn add_annotation(
+        &mut self,
+        id: OperationId,
+        action: &CreateAnnotationAction,
+    ) -> Result<(), SequenceError> {
+        for anchor in action.start.iter().chain(action.end.iter()) {
+            if !self.tree.contains(anchor) {
+                return Err(SequenceError::BlockNotFound(anchor.clone()));
+            }
+        }
+
+        self.annotations.push(TextAnnotation {
+            id,
+            start: action.start.clone(),
+            end: action.end.clone(),
+            bias: action.bias,
+        });
+        Ok(())
+    }
+
+    // Ranges currently covered by the annotations, along with the id of their operation,
+    // sorted by position. Annotations whose text was deleted become empty.
+    pub fn annotations(&self) -> Vec<(OperationId, Range<u32>, AnchorBias)> {
+        if self.annotations.is_empty() {
+            return Vec::new();
+        }
+
+        // Blocks of each client, sorted by sequence, along with the visible bytes before them
+        let mut blocks: FxHashMap<ClientId, Vec<(SequenceIndex, u32, u32, bool)>> =
+            FxHashMap::default();
+        let mut visible = 0;
+        for block in self.tree.iter_all_blocks() {
+            let len = block.items.len() as u32;
+            blocks.entry(block.id.client_id).or_default().push((
+                block.id.sequence,
+                len,
+                visible,
+                block.deleted,
+            ));
+            if !block.deleted {
+                visible += len;
+            }
+        }
+        for client_blocks in blocks.values_mut() {
+            client_blocks.sort_by_key(|(sequence, ..)| *sequence);
+        }
+
+        // Visible bytes before the character, and whether it's visible itself
+        let locate = |id: &SequenceBlockId| -> Option<(u32, bool)> {
+            let client_blocks = blocks.get(&id.client_id)?;
+            let index = client_blocks
+                .partition_point(|(sequence, ..)| *sequence <= id.sequence)
+                .checked_sub(1)?;
+            let (sequence, len, before, deleted) = client_blocks[index];
+            let offset = id.sequence - sequence;
+            (offset < len).then(|| match deleted {
+                true => (before, false),
+                false => (before + offset, true),
+            })
+        };
+        let before =
+            |id: &Option<SequenceBlockId>| id.as_ref().and_then(locate).map(|(before, _)| before);
+        let through = |id: &Option<SequenceBlockId>| {
+            id.as_ref()
+                .and_then(locate)
+                .map(|(before, visible)| before + visible as u32)
+        };
+
+        let mut annotations: Vec<_> = self
+            .annotations
+            .iter()
+            .map(|annotation| {
+                let (start, end) = match annotation.bias {
+                    AnchorBias::Contract => (before(&annotation.start), through(&annotation.end)),
+                    AnchorBias::Expand => (through(&annotation.start), before(&annotation.end)),
+                };
+                let start = start.unwrap_or(0);
+                let end = end.unwrap_or(visible).max(start);
+                (annotation.id, start..end, annotation.bias)
+            })
+            .collect();
+        annotations
+            .sort_by_key(|(id, range, _)| (range.start, range.end, id.client_id, id.sequence));
+        annotations
+    }
+
+    // Each block continues the previous one, so the tree orders them as a single insertion
+    fn insert_blocks(&mut self, id: &SequenceBlockId, left: Option<&SequenceBlockId>, value: &str) {
+        let mut left = left.cloned();
+        let mut start = 0;
+        while start < value.len() {
+            let end = chunk_end(value, start, self.max_block_len as usize);
+            let chunk_id = SequenceBlockId {
+                client_id: id.client_id,
+                sequence: id.sequence + start as SequenceIndex,
+            };
+
+            let items = TextItems::append(self.tree.storage_mut(), &value[start..end]);
+            self.tree
+                .insert(TextBlock::new(chunk_id.clone(), items, left.take()));
+            left = Some(SequenceBlockId {
+                client_id: id.client_id,
+                sequence: id.sequence + end as SequenceIndex - 1,
+            });
+            start = end;
+        }
+    }
+
+    // Only affects the following insertions, existing blocks are left as they are
+    pub fn set_max_block_len(&mut self, len: u32) {
+        self.max_block_len = len.max(1);
+        self.tree.set_block_len_limit(self.max_block_len);
+    }
+
+    // When enabled, the contents of deleted blocks are dropped (including the existing ones),
+    // keeping only what's needed to order concurrent insertions
+    pub fn set_drop_tombstones(&mut self, enabled: bool) {
+        if enabled && !self.drop_tombstones {
+            self.tree.for_each_deleted(TextItems::drop_contents);
+            self.compact_arena();
+        }
+        self.drop_tombstones = enabled;
+    }
+
+    // Once tombstones are dropped, their contents are still in the arena until it's rebuilt
+    fn compact_arena_if_sparse(&mut self) {
+        if self.tree.storage().len() > 2 * self.tree.len() as usize + MIN_ARENA_SLACK {
+            self.compact_arena();
+        }
+    }
+
+    // Rebuilds the arena with only the contents that are still referenced, in sequence order
+    fn compact_arena(&mut self) {
+        let old_arena = std::mem::take(self.tree.storage_mut());
+        let mut arena = String::with_capacity(self.tree.len() as usize);
+        self.tree.for_each_items_mut(|items| {
+            if let TextItems::Text { .. } = items {
+                *items = TextItems::append(&mut arena, items.as_str(&old_arena));
+            }
+        });
+        *self.tree.storage_mut() = arena;
+    }
+
+    // Whether the character was inserted, even if it was deleted afterwards
+    pub fn contains(&self, id: &SequenceBlockId) -> bool {
+        self.tree.contains(id)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.tree.len()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.tree.block_count()
+    }
+
+    // Number of deleted blocks, along with their length in bytes (which are only kept in
+    // memory when tombstones are not dropped)
+    pub fn tombstones(&self) -> (usize, usize) {
+        self.tree.tombstones()
     }
 
-    pub fn delete(&mut self, action: &DeleteTextAction) {
-        self.tree.delete(&action.left, &action.right);
+    pub fn node_count(&self) -> usize {
+        self.tree.node_count()
+    }
+
+    pub fn fanout(&self) -> TextFanout {
+        self.tree.fanout()
+    }
+
+    pub fn check_integrity(&self) -> Vec<TreeIntegrityError> {
+        self.tree.check_integrity()
+    }
+
+    #[cfg(feature = "debug-tools")]
+    pub fn render_debug_graph(&self, graph: &mut DebugGraph, prefix: &str, format_id: FormatId) {
+        self.tree
+            .render_debug_graph(graph, prefix, |block, arena| match &block.items {
+                TextItems::Text { .. } if block.deleted => {
+                    format!(
+                        "{} ~\"{}\"",
+                        format_id(&block.id),
+                        block.items.as_str(arena)
+                    )
+                }
+                TextItems::Text { .. } => {
+                    format!("{} \"{}\"", format_id(&block.id), block.items.as_str(arena))
+                }
+                TextItems::Tombstone(len) => format!("{} ~{}", format_id(&block.id), len),
+            });
+    }
+
+    pub fn len_chars(&self) -> u32 {
+        self.tree.len_chars()
+    }
+
+    // Converts an index expressed in chars (unicode scalar values) into a byte index
+    pub fn char_to_index(&self, char_index: u32) -> Option<u32> {
+        self.tree.char_to_position(char_index)
+    }
+
+    pub fn is_char_boundary(&self, index: u32) -> bool {
+        self.tree.is_boundary(index)
     }
 
     pub fn find_block_starting_at(&self, position: u32) -> Option<SequenceBlockId> {
@@ -58,19 +717,275 @@ impl TextCRDT {
         self.tree.last_block()
     }
 
-    pub fn to_string(&self) -> String {
-        let mut result = String::new();
+    // Visible parts of the text between the given positions, without walking the blocks
+    // that come before. The positions must be char boundaries, with `start <= end <= len`.
+    pub fn chunks_in_range(&self, start: u32, end: u32) -> impl Iterator<Item = &str> {
+        let (offset, blocks) = self.tree.iter_blocks_from(start);
+        let mut position = start - offset;
+        let arena = self.tree.storage();
+
+        blocks
+            .map(|block| block.items.as_str(arena))
+            .map_while(move |chunk| {
+                if position >= end {
+                    return None;
+                }
+
+                let chunk_start = position;
+                position += chunk.len() as u32;
+                let from = start.saturating_sub(chunk_start) as usize;
+                let to = (end.min(position) - chunk_start) as usize;
+                Some(&chunk[from..to])
+            })
+    }
+
+    // Visible characters between the given positions, as ranges of consecutive sequences of
+    // the same client, see `DeleteTextItemsAction`. The positions must be char boundaries,
+    // with `start <= end <= len`.
+    pub fn item_ranges(&self, start: u32, end: u32) -> Vec<DeletedTextRange> {
+        let (offset, blocks) = self.tree.iter_blocks_from(start);
+        let mut position = start - offset;
+
+        let mut ranges: Vec<DeletedTextRange> = Vec::new();
+        for block in blocks {
+            if position >= end {
+                break;
+            }
 
-        for sub_str in self.tree.iter() {
-            result.push_str(&sub_str);
+            let block_len = block.items.len() as u32;
+            let first = start.saturating_sub(position);
+            let last = end.min(position + block_len) - position - 1;
+            position += block_len;
+
+            let left = SequenceBlockId::new(block.id.client_id, block.id.sequence + first);
+            let right = SequenceBlockId::new(block.id.client_id, block.id.sequence + last);
+            match ranges.last_mut() {
+                Some(range)
+                    if range.right.client_id == left.client_id
+                        && range.right.sequence + 1 == left.sequence =>
+                {
+                    range.right = right
+                }
+                _ => ranges.push(DeletedTextRange { left, right }),
+            }
         }
+        ranges
+    }
 
-        result
+    // Visible parts of the text, along with the id of their first character
+    pub fn iter_blocks(&self) -> impl DoubleEndedIterator<Item = (&SequenceBlockId, &str)> {
+        let arena = self.tree.storage();
+        self.tree
+            .iter_blocks()
+            .map(move |block| (&block.id, block.items.as_str(arena)))
+    }
+
+    // Every block in sequence order, deleted ones included, as the id of its first byte
+    // along with its length. Unlike the positions, this order never changes.
+    pub fn iter_all_blocks(&self) -> impl Iterator<Item = (&SequenceBlockId, u32)> {
+        self.tree
+            .iter_all_blocks()
+            .map(|block| (&block.id, block.items.len() as u32))
+    }
+}
+
+// End of the chunk of at most `max_len` bytes that starts at `start`, on a char boundary.
+// Chars longer than `max_len` get a chunk of their own.
+fn chunk_end(value: &str, start: usize, max_len: usize) -> usize {
+    let mut end = (start + max_len).min(value.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end > start {
+        return end;
+    }
+
+    end = start + 1;
+    while !value.is_char_boundary(end) {
+        end += 1;
+    }
+    end
+}
+
+impl ClientRemappable for TextCRDT {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.client = *mappings.get(&self.client).expect("client ID not found");
+        self.tree.remap_client_ids(mappings);
+        for annotation in &mut self.annotations {
+            annotation.id.remap_client_ids(mappings);
+            if let Some(start) = annotation.start.as_mut() {
+                start.remap_client_ids(mappings);
+            }
+            if let Some(end) = annotation.end.as_mut() {
+                end.remap_client_ids(mappings);
+            }
+        }
+    }
+}
+
+// Writes the text block by block, without building an intermediate string
+impl Display for TextCRDT {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arena = self.tree.storage();
+        for items in self.tree.iter() {
+            f.write_str(items.as_str(arena))?;
+        }
+
+        Ok(())
     }
 }
 
 impl Debug for TextCRDT {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.to_string())
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str_continues_local_sequences() {
+        let id = SequenceBlockId {
+            client_id: 0,
+            sequence: 0,
+        };
+        let mut text = TextCRDT::from_str(0, id.clone(), "hello");
+        assert_eq!(text.to_string(), "hello");
+        assert_eq!(text.next_id(1).sequence, 5);
+
+        let action = InsertTextAction {
+            object: crate::ObjRef::Root,
+            id: text.next_id(6),
+            value: " world".into(),
+            left: Some(SequenceBlockId {
+                client_id: 0,
+                sequence: 4,
+            }),
+        };
+        text.insert(&action).unwrap();
+        assert_eq!(text.to_string(), "hello world");
+    }
+
+    #[test]
+    fn test_next_id_after_comes_before_existing_insertions() {
+        let id = |client_id, sequence| SequenceBlockId {
+            client_id,
+            sequence,
+        };
+        let mut text = TextCRDT::new(1);
+        let insert = |text: &mut TextCRDT, id, left, value: &str| {
+            let action = InsertTextAction {
+                object: crate::ObjRef::Root,
+                id,
+                value: value.into(),
+                left,
+            };
+            text.insert(&action).unwrap();
+        };
+
+        let a = text.next_id_after(None, 1);
+        insert(&mut text, a.clone(), None, "a");
+        insert(&mut text, id(0, 0), Some(a.clone()), "X");
+        assert_eq!(text.to_string(), "aX");
+
+        // The next sequence would continue "a", which comes after "X"
+        let y = text.next_id_after(Some(&a), 1);
+        assert_eq!(y, id(1, 2));
+        insert(&mut text, y, Some(a), "Y");
+        assert_eq!(text.to_string(), "aYX");
+
+        insert(&mut text, id(0, 1), None, "Z");
+        assert_eq!(text.to_string(), "ZaYX");
+        let w = text.next_id_after(None, 1);
+        assert_eq!(w, id(1, 3));
+        insert(&mut text, w, None, "W");
+        assert_eq!(text.to_string(), "WZaYX");
+    }
+
+    #[test]
+    fn test_large_insertions_are_chunked() {
+        let id = |client_id, sequence| SequenceBlockId {
+            client_id,
+            sequence,
+        };
+        let value = "é".repeat(5) + "abc";
+
+        let mut chunked = TextCRDT::new(0);
+        chunked.set_max_block_len(3);
+        let mut single = TextCRDT::new(0);
+        single.set_max_block_len(u32::MAX);
+
+        let insertion = InsertTextAction {
+            object: crate::ObjRef::Root,
+            id: id(0, 0),
+            value: value.as_str().into(),
+            left: None,
+        };
+        // Another client inserting after the last byte of the second chunk
+        let concurrent = InsertTextAction {
+            object: crate::ObjRef::Root,
+            id: id(1, 0),
+            value: "x".into(),
+            left: Some(id(0, 3)),
+        };
+        for text in [&mut chunked, &mut single] {
+            text.insert(&insertion).unwrap();
+            text.insert(&concurrent).unwrap();
+        }
+
+        // Chunks never split a char, even if it makes them shorter than the limit
+        let blocks: Vec<&str> = chunked.iter_blocks().map(|(_, text)| text).collect();
+        assert_eq!(blocks, vec!["é", "é", "x", "é", "é", "éa", "bc"]);
+        assert_eq!(chunked.to_string(), single.to_string());
+        assert!(chunked.check_integrity().is_empty());
+
+        // Typing at the end doesn't grow the last block past the limit
+        let typed = InsertTextAction {
+            object: crate::ObjRef::Root,
+            id: chunked.next_id(2),
+            value: "de".into(),
+            left: Some(id(0, 12)),
+        };
+        chunked.insert(&typed).unwrap();
+        assert_eq!(chunked.iter_blocks().last().unwrap().1, "de");
+        assert_eq!(chunked.to_string(), "ééxéééabcde");
+    }
+
+    #[test]
+    fn test_arena_is_shared_and_compacted() {
+        let id = |sequence| SequenceBlockId {
+            client_id: 0,
+            sequence,
+        };
+        let mut text = TextCRDT::from_str(0, id(0), "hello");
+
+        // Typing at the end extends the same range of the arena, so the blocks are merged
+        let action = InsertTextAction {
+            object: crate::ObjRef::Root,
+            id: text.next_id(7),
+            value: " wörld".into(),
+            left: Some(id(4)),
+        };
+        text.insert(&action).unwrap();
+        assert_eq!(text.iter_blocks().count(), 1);
+        assert_eq!(text.tree.storage(), "hello wörld");
+        assert_eq!(text.len_chars(), 11);
+
+        text.delete(&DeleteTextAction {
+            object: crate::ObjRef::Root,
+            left: id(0),
+            right: id(5),
+        })
+        .unwrap();
+        assert_eq!(text.to_string(), "wörld");
+        assert_eq!(text.tree.storage(), "hello wörld");
+
+        // Dropping the tombstones leaves only the visible text in the arena
+        text.set_drop_tombstones(true);
+        assert_eq!(text.to_string(), "wörld");
+        assert_eq!(text.tree.storage(), "wörld");
+        assert_eq!(text.char_to_index(2), Some(3));
     }
 }
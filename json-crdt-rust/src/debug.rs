@@ -0,0 +1,145 @@
+// Renders the internal structures of a document as graphs, so that they can be attached
+// to bug reports, see `Doc::debug_dump`
+
+use crate::FormattableId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    // Mermaid flowchart, which GitHub renders inside ```mermaid blocks
+    Mermaid,
+    // Graphviz, e.g. `dot -Tsvg dump.dot -o dump.svg`
+    Dot,
+}
+
+// Displays the ids of the document with the global ids of their clients
+pub type FormatId<'a> = &'a dyn Fn(&dyn FormattableId) -> String;
+
+// Node ids are only made of ASCII letters, digits and underscores, as they are not quoted
+#[derive(Debug, Default)]
+pub struct DebugGraph {
+    nodes: Vec<(String, String)>,
+    edges: Vec<(String, String)>,
+    clusters: Vec<(String, DebugGraph)>,
+}
+
+impl DebugGraph {
+    pub fn node(&mut self, id: String, label: String) {
+        self.nodes.push((id, label));
+    }
+
+    pub fn edge(&mut self, from: String, to: String) {
+        self.edges.push((from, to));
+    }
+
+    // Groups the nodes added to the returned graph in a box with the given title
+    pub fn cluster(&mut self, title: String) -> &mut DebugGraph {
+        self.clusters.push((title, DebugGraph::default()));
+        &mut self.clusters.last_mut().expect("cluster was just added").1
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        let mut buffer = String::new();
+        let mut next_cluster = 0;
+        match format {
+            GraphFormat::Mermaid => {
+                buffer.push_str("flowchart TB\n");
+                self.render_mermaid(&mut buffer, 1, &mut next_cluster);
+            }
+            GraphFormat::Dot => {
+                buffer.push_str("digraph {\n    node [shape=box];\n");
+                self.render_dot(&mut buffer, 1, &mut next_cluster);
+                buffer.push_str("}\n");
+            }
+        }
+        buffer
+    }
+
+    fn render_mermaid(&self, buffer: &mut String, depth: usize, next_cluster: &mut usize) {
+        let indent = "    ".repeat(depth);
+        for (title, cluster) in &self.clusters {
+            buffer.push_str(&format!(
+                "{}subgraph cluster_{}[\"{}\"]\n",
+                indent,
+                next_cluster,
+                escape_mermaid(title)
+            ));
+            *next_cluster += 1;
+            cluster.render_mermaid(buffer, depth + 1, next_cluster);
+            buffer.push_str(&format!("{}end\n", indent));
+        }
+        for (id, label) in &self.nodes {
+            buffer.push_str(&format!(
+                "{}{}[\"{}\"]\n",
+                indent,
+                id,
+                escape_mermaid(label)
+            ));
+        }
+        for (from, to) in &self.edges {
+            buffer.push_str(&format!("{}{} --> {}\n", indent, from, to));
+        }
+    }
+
+    fn render_dot(&self, buffer: &mut String, depth: usize, next_cluster: &mut usize) {
+        let indent = "    ".repeat(depth);
+        for (title, cluster) in &self.clusters {
+            buffer.push_str(&format!("{}subgraph cluster_{} {{\n", indent, next_cluster));
+            buffer.push_str(&format!("{}    label=\"{}\";\n", indent, escape_dot(title)));
+            *next_cluster += 1;
+            cluster.render_dot(buffer, depth + 1, next_cluster);
+            buffer.push_str(&format!("{}}}\n", indent));
+        }
+        for (id, label) in &self.nodes {
+            buffer.push_str(&format!(
+                "{}{} [label=\"{}\"];\n",
+                indent,
+                id,
+                escape_dot(label)
+            ));
+        }
+        for (from, to) in &self.edges {
+            buffer.push_str(&format!("{}{} -> {};\n", indent, from, to));
+        }
+    }
+}
+
+fn escape_mermaid(label: &str) -> String {
+    label.replace('"', "#quot;").replace('\n', "\\n")
+}
+
+fn escape_dot(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> DebugGraph {
+        let mut graph = DebugGraph::default();
+        let cluster = graph.cluster("map \"root\"".to_string());
+        cluster.node("a".to_string(), "first".to_string());
+        cluster.node("b".to_string(), "say \"hi\"".to_string());
+        cluster.edge("a".to_string(), "b".to_string());
+        graph
+    }
+
+    #[test]
+    fn test_render_mermaid() {
+        assert_eq!(
+            graph().render(GraphFormat::Mermaid),
+            "flowchart TB\n    subgraph cluster_0[\"map #quot;root#quot;\"]\n        a[\"first\"]\n        b[\"say #quot;hi#quot;\"]\n        a --> b\n    end\n"
+        );
+    }
+
+    #[test]
+    fn test_render_dot() {
+        assert_eq!(
+            graph().render(GraphFormat::Dot),
+            "digraph {\n    node [shape=box];\n    subgraph cluster_0 {\n        label=\"map \\\"root\\\"\";\n        a [label=\"first\"];\n        b [label=\"say \\\"hi\\\"\"];\n        a -> b;\n    }\n}\n"
+        );
+    }
+}
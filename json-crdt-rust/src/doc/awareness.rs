@@ -0,0 +1,414 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use bytes_varint::{VarIntSupport, VarIntSupportMut};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    serde::{deserialize_value, read_string, read_u8, serialize_value, SerializationError},
+    GlobalClientId, ScalarValue, Timestamp, Value,
+};
+
+use super::{
+    clock::{Clock, SystemClock},
+    shared::{ChangeOrigin, Subscriber},
+};
+
+// Peers that haven't updated their state for this long are considered offline
+pub const DEFAULT_AWARENESS_TIMEOUT: Timestamp = 30_000;
+
+// Fields of the state of a peer, e.g. its name and the position of its cursor
+pub type AwarenessState = BTreeMap<String, ScalarValue>;
+
+// Ephemeral state of the peers editing a document, such as cursors, selections and user
+// names. It's exchanged like the operations, but never enters the document history.
+// Each peer only writes its own state, versioned by a counter, so the latest one wins.
+// Handles can be cloned and shared between threads, like `SharedDoc`.
+#[derive(Clone)]
+pub struct Awareness {
+    inner: Arc<AwarenessInner>,
+}
+
+struct AwarenessInner {
+    client_id: GlobalClientId,
+    clock: Arc<dyn Clock>,
+    timeout: Timestamp,
+    peers: Mutex<FxHashMap<GlobalClientId, PeerState>>,
+    subscribers: Mutex<Vec<Subscriber<AwarenessChange>>>,
+}
+
+#[derive(Debug, Clone)]
+struct PeerState {
+    counter: u32,
+    // `None` once the peer has left, kept to ignore older states received afterwards
+    state: Option<AwarenessState>,
+    // Local time of the last update, used to expire the peers that went silent
+    updated_at: Timestamp,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AwarenessChange {
+    pub origin: Option<ChangeOrigin>,
+    pub added: Vec<GlobalClientId>,
+    pub updated: Vec<GlobalClientId>,
+    pub removed: Vec<GlobalClientId>,
+    // Peers that sent the same state again to stay online, see `Awareness::needs_renewal`
+    pub renewed: Vec<GlobalClientId>,
+}
+
+impl AwarenessChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.updated.is_empty()
+            && self.removed.is_empty()
+            && self.renewed.is_empty()
+    }
+
+    // Peers whose state should be sent to the others
+    pub fn clients(&self) -> Vec<GlobalClientId> {
+        let mut clients = self.added.clone();
+        clients.extend(self.updated.iter().cloned());
+        clients.extend(self.removed.iter().cloned());
+        clients.extend(self.renewed.iter().cloned());
+        clients
+    }
+}
+
+impl Awareness {
+    pub fn new(client_id: GlobalClientId) -> Self {
+        Self::new_with_clock(client_id, Arc::new(SystemClock), DEFAULT_AWARENESS_TIMEOUT)
+    }
+
+    pub fn new_with_clock(
+        client_id: GlobalClientId,
+        clock: Arc<dyn Clock>,
+        timeout: Timestamp,
+    ) -> Self {
+        Self {
+            inner: Arc::new(AwarenessInner {
+                client_id,
+                clock,
+                timeout,
+                peers: Mutex::new(FxHashMap::default()),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn client_id(&self) -> &GlobalClientId {
+        &self.inner.client_id
+    }
+
+    // `None` announces that the local peer is leaving
+    pub fn set_local_state(&self, state: Option<AwarenessState>) {
+        let client_id = self.inner.client_id.clone();
+        let change = {
+            let mut peers = self.peers();
+            let previous = peers.get(&client_id);
+            let was_present = previous.is_some_and(|peer| peer.state.is_some());
+            let counter = previous.map_or(0, |peer| peer.counter + 1);
+
+            let mut change = AwarenessChange {
+                origin: Some(ChangeOrigin::Local),
+                ..Default::default()
+            };
+            match (was_present, state.is_some()) {
+                (false, true) => change.added.push(client_id.clone()),
+                (true, true) if previous.and_then(|peer| peer.state.as_ref()) == state.as_ref() => {
+                    change.renewed.push(client_id.clone())
+                }
+                (true, true) => change.updated.push(client_id.clone()),
+                (true, false) => change.removed.push(client_id.clone()),
+                (false, false) => {}
+            }
+
+            peers.insert(
+                client_id,
+                PeerState {
+                    counter,
+                    state,
+                    updated_at: self.inner.clock.now(),
+                },
+            );
+            change
+        };
+
+        self.notify(change);
+    }
+
+    // Sets a single field of the local state, starting from an empty one if needed
+    pub fn set_local_field<T: Into<ScalarValue>>(&self, key: &str, value: T) {
+        let mut state = self.local_state().unwrap_or_default();
+        state.insert(key.to_string(), value.into());
+        self.set_local_state(Some(state));
+    }
+
+    pub fn local_state(&self) -> Option<AwarenessState> {
+        self.state_of(&self.inner.client_id)
+    }
+
+    pub fn state_of(&self, client_id: &GlobalClientId) -> Option<AwarenessState> {
+        self.peers()
+            .get(client_id)
+            .and_then(|peer| peer.state.clone())
+    }
+
+    // States of the peers that are online, the local one included
+    pub fn states(&self) -> BTreeMap<GlobalClientId, AwarenessState> {
+        self.peers()
+            .iter()
+            .filter_map(|(client_id, peer)| Some((client_id.clone(), peer.state.clone()?)))
+            .collect()
+    }
+
+    // Encodes the state of the given peers, or of all of them (offline ones included, so that
+    // their departure is propagated)
+    pub fn encode_update(&self, clients: Option<&[GlobalClientId]>) -> Vec<u8> {
+        let peers = self.peers();
+        let entries: Vec<(&GlobalClientId, &PeerState)> = match clients {
+            Some(clients) => clients
+                .iter()
+                .filter_map(|client_id| peers.get_key_value(client_id))
+                .collect(),
+            None => peers.iter().collect(),
+        };
+
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_varint(entries.len() as u32);
+        for (client_id, peer) in entries {
+            buffer.put_u32_varint(client_id.len() as u32);
+            buffer.put_slice(client_id.as_bytes());
+            buffer.put_u32_varint(peer.counter);
+            match &peer.state {
+                Some(state) => {
+                    buffer.put_u8(1);
+                    buffer.put_u32_varint(state.len() as u32);
+                    for (key, value) in state {
+                        buffer.put_u32_varint(key.len() as u32);
+                        buffer.put_slice(key.as_bytes());
+                        serialize_value(&Value::Scalar(value.clone()), &mut buffer);
+                    }
+                }
+                None => buffer.put_u8(0),
+            }
+        }
+        buffer.to_vec()
+    }
+
+    // Applies the states received from another peer, ignoring the ones older than what's
+    // known already. The update is decoded entirely before being applied.
+    pub fn apply_update(&self, mut buffer: Bytes) -> Result<AwarenessChange, SerializationError> {
+        let entries = decode_entries(&mut buffer)?;
+        let now = self.inner.clock.now();
+
+        let change = {
+            let mut peers = self.peers();
+            let mut change = AwarenessChange {
+                origin: Some(ChangeOrigin::Remote),
+                ..Default::default()
+            };
+
+            for (client_id, counter, state) in entries {
+                // Only the local peer can change its own state
+                if client_id == self.inner.client_id {
+                    continue;
+                }
+
+                let previous = peers.get(&client_id);
+                let was_present = previous.is_some_and(|peer| peer.state.is_some());
+                let is_newer = match previous {
+                    Some(peer) => {
+                        counter > peer.counter || (counter == peer.counter && state.is_none())
+                    }
+                    None => true,
+                };
+                if !is_newer {
+                    continue;
+                }
+
+                match (was_present, state.is_some()) {
+                    (false, true) => change.added.push(client_id.clone()),
+                    (true, true)
+                        if previous.and_then(|peer| peer.state.as_ref()) == state.as_ref() =>
+                    {
+                        change.renewed.push(client_id.clone())
+                    }
+                    (true, true) => change.updated.push(client_id.clone()),
+                    (true, false) => change.removed.push(client_id.clone()),
+                    (false, false) => {}
+                }
+
+                peers.insert(
+                    client_id,
+                    PeerState {
+                        counter,
+                        state,
+                        updated_at: now,
+                    },
+                );
+            }
+            change
+        };
+
+        if !change.is_empty() {
+            self.notify(change.clone());
+        }
+        Ok(change)
+    }
+
+    // Removes the remote peers that haven't been updated within the timeout. The local state
+    // has to be sent again before it expires on the other peers, see `needs_renewal`.
+    pub fn remove_expired(&self) -> Vec<GlobalClientId> {
+        let now = self.inner.clock.now();
+        let removed: Vec<GlobalClientId> = {
+            let mut peers = self.peers();
+            let expired: Vec<GlobalClientId> = peers
+                .iter()
+                .filter(|(client_id, peer)| {
+                    **client_id != self.inner.client_id
+                        && now.saturating_sub(peer.updated_at) >= self.inner.timeout
+                })
+                .map(|(client_id, _)| client_id.clone())
+                .collect();
+
+            for client_id in &expired {
+                peers.remove(client_id);
+            }
+            expired
+        };
+
+        if !removed.is_empty() {
+            self.notify(AwarenessChange {
+                origin: None,
+                removed: removed.clone(),
+                ..Default::default()
+            });
+        }
+        removed
+    }
+
+    // True if the local state is halfway through its timeout, in which case it should be
+    // renewed with `renew_local_state` and sent again
+    pub fn needs_renewal(&self) -> bool {
+        let now = self.inner.clock.now();
+        self.peers().get(&self.inner.client_id).is_some_and(|peer| {
+            peer.state.is_some() && now.saturating_sub(peer.updated_at) >= self.inner.timeout / 2
+        })
+    }
+
+    pub fn renew_local_state(&self) {
+        if let Some(state) = self.local_state() {
+            self.set_local_state(Some(state));
+        }
+    }
+
+    // Each call returns a new receiver, which is dropped from the subscribers once it goes
+    // out of scope. Expired peers are notified without an origin.
+    pub fn subscribe(&self) -> Receiver<AwarenessChange> {
+        let (sender, receiver) = channel();
+        self.subscribe_with(Box::new(move |change| sender.send(change.clone()).is_ok()));
+        receiver
+    }
+
+    pub(crate) fn subscribe_with(&self, subscriber: Subscriber<AwarenessChange>) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(subscriber);
+    }
+
+    fn peers(&self) -> std::sync::MutexGuard<'_, FxHashMap<GlobalClientId, PeerState>> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn notify(&self, change: AwarenessChange) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|subscriber| subscriber(&change));
+    }
+}
+
+type AwarenessEntry = (GlobalClientId, u32, Option<AwarenessState>);
+
+fn decode_entries(buffer: &mut Bytes) -> Result<Vec<AwarenessEntry>, SerializationError> {
+    let malformed =
+        |name: &str| SerializationError::Malformed(format!("unable to read awareness {}", name));
+
+    let count = buffer.get_u32_varint().map_err(|_| malformed("count"))?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let len = buffer.get_u32_varint().map_err(|_| malformed("client"))?;
+        let client_id = read_string(buffer, len, "awareness client")?;
+        let counter = buffer.get_u32_varint().map_err(|_| malformed("counter"))?;
+
+        let state = match read_u8(buffer, "awareness state flag")? {
+            0 => None,
+            _ => {
+                let fields = buffer.get_u32_varint().map_err(|_| malformed("fields"))?;
+                let mut state = AwarenessState::new();
+                for _ in 0..fields {
+                    let len = buffer.get_u32_varint().map_err(|_| malformed("key"))?;
+                    let key = read_string(buffer, len, "awareness key")?;
+                    match deserialize_value(buffer)? {
+                        Value::Scalar(value) => state.insert(key, value),
+                        Value::Object(_) => return Err(malformed("value")),
+                    };
+                }
+                Some(state)
+            }
+        };
+        entries.push((client_id, counter, state));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_updates_round_trip() {
+        let awareness = Awareness::new("alice".to_string());
+        awareness.set_local_field("name", "Alice");
+        awareness.set_local_field("cursor", 42);
+
+        let mut buffer = Bytes::from(awareness.encode_update(None));
+        let entries = decode_entries(&mut buffer).unwrap();
+        assert_eq!(
+            entries,
+            vec![("alice".to_string(), 1, awareness.local_state())]
+        );
+
+        assert!(decode_entries(&mut Bytes::from_static(&[1, 5, b'a'])).is_err());
+    }
+
+    #[test]
+    fn test_silent_peers_expire() {
+        let clock = Arc::new(ManualClock::new(0));
+        let alice = Awareness::new_with_clock("alice".to_string(), clock.clone(), 1000);
+        let bob = Awareness::new_with_clock("bob".to_string(), clock.clone(), 1000);
+        bob.set_local_field("name", "Bob");
+        alice.apply_update(bob.encode_update(None).into()).unwrap();
+
+        clock.advance(500);
+        assert!(bob.needs_renewal());
+        assert!(alice.remove_expired().is_empty());
+
+        clock.advance(500);
+        assert_eq!(alice.remove_expired(), vec!["bob".to_string()]);
+        assert!(alice.states().is_empty());
+    }
+}
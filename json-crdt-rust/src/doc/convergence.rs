@@ -0,0 +1,236 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use crate::{ChangeSummary, DataMap, DataMapValue, Doc, Path};
+
+use super::{doc::DocError, ReadableDoc, WritableDoc};
+
+// Beyond this number of documents, a sample of the merge orders is checked instead of all
+// of their permutations
+const EXHAUSTIVE_DOCS: usize = 5;
+const SAMPLED_ORDERS: usize = 120;
+
+const CHECKER_CLIENT: &str = "convergence-checker";
+
+// Result of `Doc::verify_convergence`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConvergenceReport {
+    // Merge orders that were compared, including the one that diverged
+    pub orders_checked: usize,
+    pub divergence: Option<Divergence>,
+}
+
+impl ConvergenceReport {
+    pub fn is_converged(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+// Two merge orders that produced different contents. Orders are indexes of the documents
+// passed to `Doc::verify_convergence`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub expected_order: Vec<usize>,
+    pub actual_order: Vec<usize>,
+    pub expected_hash: u64,
+    pub actual_hash: u64,
+    // Values and texts whose contents differ
+    pub paths: Vec<Path>,
+    // Operations that changed the values at those paths. Their `operation` IDs are local to
+    // the merged document, so they should be identified by `author` and sequence.
+    pub operations: Vec<ChangeSummary>,
+}
+
+pub(crate) fn verify_convergence(docs: &[Doc]) -> Result<ConvergenceReport, DocError> {
+    let mut report = ConvergenceReport::default();
+    let mut orders = merge_orders(docs.len()).into_iter();
+    let Some(expected_order) = orders.next() else {
+        return Ok(report);
+    };
+
+    let expected = merge_in_order(docs, &expected_order)?;
+    let expected_entries = content_entries(&expected.as_map()?);
+    let expected_hash = hash_entries(&expected_entries);
+    report.orders_checked = 1;
+
+    for actual_order in orders {
+        let actual = merge_in_order(docs, &actual_order)?;
+        let actual_entries = content_entries(&actual.as_map()?);
+        let actual_hash = hash_entries(&actual_entries);
+        report.orders_checked += 1;
+
+        if actual_hash == expected_hash && actual_entries == expected_entries {
+            continue;
+        }
+
+        let paths = divergent_paths(&expected_entries, &actual_entries);
+        let operations = expected
+            .history()?
+            .filter(|change| {
+                change.path.as_ref().is_some_and(|path| {
+                    paths
+                        .iter()
+                        .any(|divergent| path.selectors().starts_with(divergent.selectors()))
+                })
+            })
+            .collect();
+
+        report.divergence = Some(Divergence {
+            expected_order,
+            actual_order,
+            expected_hash,
+            actual_hash,
+            paths,
+            operations,
+        });
+        break;
+    }
+
+    Ok(report)
+}
+
+fn merge_in_order(docs: &[Doc], order: &[usize]) -> Result<Doc, DocError> {
+    let mut merged = Doc::new_with_timestamp(CHECKER_CLIENT.to_string(), 0);
+    for &index in order {
+        merged.merge(&docs[index])?;
+    }
+    Ok(merged)
+}
+
+// Every permutation for a few documents, otherwise the identity, its reverse and a
+// deterministic sample of shuffles, so that reports can be reproduced
+fn merge_orders(count: usize) -> Vec<Vec<usize>> {
+    let identity: Vec<usize> = (0..count).collect();
+    if count <= EXHAUSTIVE_DOCS {
+        let mut orders = Vec::new();
+        permutations(&mut identity.clone(), 0, &mut orders);
+        return orders;
+    }
+
+    let mut orders = vec![identity.clone(), identity.iter().rev().copied().collect()];
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    while orders.len() < SAMPLED_ORDERS {
+        let mut order = identity.clone();
+        for i in (1..count).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            order.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+        orders.push(order);
+    }
+    orders
+}
+
+fn permutations(order: &mut Vec<usize>, start: usize, orders: &mut Vec<Vec<usize>>) {
+    if start + 1 >= order.len() {
+        orders.push(order.clone());
+        return;
+    }
+
+    for i in start..order.len() {
+        order.swap(start, i);
+        permutations(order, start + 1, orders);
+        order.swap(start, i);
+    }
+}
+
+// Flattens the contents into (path, value) entries sorted by path, which don't depend on
+// the local IDs of the objects
+fn content_entries(map: &DataMap) -> Vec<(Path, String)> {
+    let mut entries = Vec::new();
+    collect_entries(map, &Path::new(), &mut entries);
+    entries
+}
+
+fn collect_entries(map: &DataMap, path: &Path, entries: &mut Vec<(Path, String)>) {
+    for (selector, value) in map {
+        let path = path.join((*selector).clone());
+        match value {
+            DataMapValue::Map(child) => {
+                entries.push((path.clone(), "map".to_string()));
+                collect_entries(child, &path, entries);
+            }
+            value => entries.push((path, format!("{:?}", value))),
+        }
+    }
+}
+
+fn hash_entries(entries: &[(Path, String)]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entries.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Paths whose entry is missing or different on either side, without the ones nested in
+// another divergent path
+fn divergent_paths(expected: &[(Path, String)], actual: &[(Path, String)]) -> Vec<Path> {
+    let mut paths: Vec<Path> = Vec::new();
+    let differs = |entry: &(Path, String), other: &[(Path, String)]| !other.contains(entry);
+    let candidates = expected
+        .iter()
+        .filter(|entry| differs(entry, actual))
+        .chain(actual.iter().filter(|entry| differs(entry, expected)));
+
+    for (path, _) in candidates {
+        let nested = paths
+            .iter()
+            .any(|parent| path.selectors().starts_with(parent.selectors()));
+        if !nested {
+            paths.retain(|child| !child.selectors().starts_with(path.selectors()));
+            paths.push(path.clone());
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_orders_are_distinct_permutations() {
+        assert_eq!(merge_orders(0), vec![Vec::<usize>::new()]);
+        assert_eq!(merge_orders(3).len(), 6);
+
+        for count in [4, 9] {
+            let mut orders = merge_orders(count);
+            assert_eq!(orders.len(), if count == 4 { 24 } else { SAMPLED_ORDERS });
+            assert_eq!(orders[0], (0..count).collect::<Vec<_>>());
+            for order in &orders {
+                let mut sorted = order.clone();
+                sorted.sort();
+                assert_eq!(sorted, (0..count).collect::<Vec<_>>());
+            }
+            orders.sort();
+            orders.dedup();
+            assert!(orders.len() > 1);
+        }
+    }
+
+    #[test]
+    fn test_divergent_paths_skip_nested_ones() {
+        let entry = |path: &str, value: &str| (Path::parse(path).unwrap(), value.to_string());
+        let expected = vec![
+            entry("a", "map"),
+            entry("a.b", "Int(1)"),
+            entry("a.c", "Int(2)"),
+            entry("d", "Text(\"x\")"),
+            entry("e", "Bool(true)"),
+        ];
+        let actual = vec![
+            entry("a", "map"),
+            entry("a.b", "Int(3)"),
+            entry("d", "Text(\"y\")"),
+            entry("e", "Bool(true)"),
+            entry("f", "map"),
+            entry("f.g", "Null"),
+        ];
+
+        let paths = divergent_paths(&expected, &actual);
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        assert_eq!(paths, vec!["a.b", "a.c", "d", "f"]);
+    }
+}
@@ -1,13 +1,18 @@
+use std::{ops::Range, sync::Arc};
+
 use crate::{
+    automerge::{import_changes, AutomergeError},
     client_registry::{ClientRegistry, ClientRegistryError, ClientRemappable, ClientRemappings},
     crdt::text::TextCRDT,
     operation_log::{OperationLog, OperationLogError},
-    serde::{Serializable, SerializationError},
+    serde::{BufferReader, Compression, Serializable, SerializationError},
     transaction::Transaction,
-    types::GlobalClientId,
-    view::{View, ViewError},
-    InsertTextAction, ObjRef, ObjectValue, Operation, OperationAction, OperationId, ScalarValue,
-    Selector, SequenceBlockId, Timestamp, Value,
+    types::{GlobalClient, GlobalClientId},
+    view::{View, ViewCache, ViewError},
+    Annotation, CachedObjectValue, ChangeSummary, Conflict, DocStats, FormattableId, FormattedId,
+    InsertTextAction, IntegrityReport, KeyHistoryEntry, ObjRef, ObjectValue, Operation,
+    OperationAction, OperationId, Path, PathError, Row, ScalarValue, Selector, SequenceBlockId,
+    TextHistoryEntry, TextRef, Timestamp, Value,
 };
 use bytes::Bytes;
 use chrono::Utc;
@@ -15,13 +20,26 @@ use enum_as_inner::EnumAsInner;
 use thiserror::Error;
 
 use super::{
+    convergence::{self, ConvergenceReport},
     full::FullDoc,
     lazy::LazyDoc,
+    options::{DocOptions, LazyOptions, MergeOptions, UpgradeMode},
+    report::MergeReport,
+    snapshot::{DocSnapshot, SnapshotHandle},
     traits::{ReadableDoc, WritableDoc},
+    upgrade::DocUpgrade,
+    version::DocVersion,
 };
 
+#[cfg(feature = "debug-tools")]
+use crate::GraphFormat;
+
+#[cfg(feature = "threads")]
+use super::snapshot::SerializationHandle;
+
 pub struct Doc {
     pub(crate) handle: DocHandle,
+    upgrade_mode: UpgradeMode,
 }
 
 #[derive(EnumAsInner)]
@@ -35,6 +53,16 @@ pub enum DocStatus {
     Ready,
 }
 
+// Sizes found by `Doc::validate_buffer`, region lengths are in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferInfo {
+    pub view_cache_len: usize,
+    pub client_registry_len: usize,
+    pub operation_log_len: usize,
+    pub clients: usize,
+    pub operations: usize,
+}
+
 impl<'a> Doc {
     pub fn new(client_id: GlobalClientId) -> Self {
         let timestamp = Utc::now().timestamp_millis() as u64;
@@ -44,7 +72,19 @@ impl<'a> Doc {
     pub fn new_with_timestamp(client_id: GlobalClientId, timestamp: Timestamp) -> Self {
         let doc = FullDoc::new(client_id, timestamp);
         let handle = DocHandle::Full(doc);
-        Self { handle }
+        Self {
+            handle,
+            upgrade_mode: UpgradeMode::default(),
+        }
+    }
+
+    pub fn new_with_options(client_id: GlobalClientId, options: DocOptions) -> Self {
+        let mut doc = Self::new_with_timestamp(client_id, options.clock.now());
+        doc.handle
+            .as_full_mut()
+            .expect("new documents are full")
+            .set_options(options);
+        doc
     }
 
     pub fn load(client_id: GlobalClientId, buffer: Bytes) -> Result<Self, DocError> {
@@ -59,7 +99,10 @@ impl<'a> Doc {
     ) -> Result<Self, DocError> {
         let doc = FullDoc::from_buffer(client_id, timestamp, buffer)?;
         let handle = DocHandle::Full(doc);
-        Ok(Self { handle })
+        Ok(Self {
+            handle,
+            upgrade_mode: UpgradeMode::default(),
+        })
     }
 
     pub fn lazy(client_id: GlobalClientId, buffer: Bytes) -> Result<Self, DocError> {
@@ -72,9 +115,53 @@ impl<'a> Doc {
         timestamp: Timestamp,
         buffer: Bytes,
     ) -> Result<Self, DocError> {
-        let doc = LazyDoc::load(client_id, timestamp, buffer)?;
+        Self::lazy_with_options(client_id, timestamp, buffer, LazyOptions::default())
+    }
+
+    pub fn lazy_with_options(
+        client_id: GlobalClientId,
+        timestamp: Timestamp,
+        buffer: Bytes,
+        options: LazyOptions,
+    ) -> Result<Self, DocError> {
+        let doc = LazyDoc::load(client_id, timestamp, buffer, options)?;
         let handle = DocHandle::Lazy(doc);
-        Ok(Self { handle })
+        Ok(Self {
+            handle,
+            upgrade_mode: UpgradeMode::default(),
+        })
+    }
+
+    // Migrates an Automerge document, given the JSON of its changes, see `automerge.rs`.
+    // The history is not imported, the document starts with the resulting state.
+    pub fn import_automerge_changes(
+        client_id: GlobalClientId,
+        buffer: &[u8],
+    ) -> Result<Self, DocError> {
+        let mut doc = Self::new(client_id);
+        let mut txn = doc.transaction();
+        import_changes(&mut txn, buffer)?;
+        txn.commit().map_err(AutomergeError::from)?;
+        Ok(doc)
+    }
+
+    // Checks the structure of a serialized document without building it, so that corrupted
+    // or oversized buffers can be rejected early. Operations are not applied, so a valid
+    // buffer can still fail to load if the history it contains is inconsistent.
+    pub fn validate_buffer(buffer: Bytes) -> Result<BufferInfo, DocError> {
+        let reader = BufferReader::load(buffer)?;
+        let clients = ClientRegistry::deserialize_clients(reader.client_registry())?;
+        let operations =
+            OperationLog::validate_encoded(&mut reader.operation_log(), clients.len())?;
+        ViewCache::from_buffer(reader.view_cache())?;
+
+        Ok(BufferInfo {
+            view_cache_len: reader.view_cache().len(),
+            client_registry_len: reader.client_registry().len(),
+            operation_log_len: reader.operation_log().len(),
+            clients: clients.len(),
+            operations,
+        })
     }
 
     pub fn status(&self) -> DocStatus {
@@ -84,6 +171,16 @@ impl<'a> Doc {
         }
     }
 
+    // Lazy documents are upgraded on the first write, unless the mode is `NonBlocking`
+    pub fn set_upgrade_mode(&mut self, mode: UpgradeMode) {
+        self.upgrade_mode = mode;
+    }
+
+    // Upgrades a lazy document a step at a time, see `DocUpgrade`
+    pub fn begin_upgrade(&mut self) -> DocUpgrade<'_> {
+        DocUpgrade::new(self)
+    }
+
     pub fn initialize(&mut self) -> Result<bool, DocError> {
         Ok(self.initialize_step(u32::MAX)?)
     }
@@ -114,9 +211,321 @@ impl<'a> Doc {
         }
     }
 
+    // Same as `serialize`, but the encoding happens on another thread, working on a snapshot
+    // of the document as it is now. The document can keep being edited in the meantime.
+    #[cfg(feature = "threads")]
+    pub fn serialize_async(&self) -> SerializationHandle {
+        SerializationHandle::spawn(self.snapshot())
+    }
+
+    // Same as `serialize`, but compressing the regions of the buffer with LZ4.
+    // Compressed buffers are loaded transparently.
+    pub fn serialize_compressed(&self) -> Result<Vec<u8>, DocError> {
+        match &self.handle {
+            DocHandle::Lazy(doc) => Ok(doc.serialize_with_compression(Compression::Lz4)?),
+            DocHandle::Full(doc) => Ok(doc.serialize_with_compression(Compression::Lz4)?),
+        }
+    }
+
+    // Serializes the whole document, which becomes the base for the following
+    // `save_incremental` calls. Marks the document as clean.
+    pub fn save(&mut self) -> Result<Vec<u8>, DocError> {
+        match &mut self.handle {
+            DocHandle::Lazy(doc) => Ok(doc.serialize()?),
+            DocHandle::Full(doc) => doc.save(),
+        }
+    }
+
+    // Encodes only the operations added since the last save (or since the document was
+    // loaded), so that persistence layers can append them instead of rewriting the whole
+    // document. The saved buffers are restored with `load` followed by `load_incremental`
+    // for each increment, in the order they were saved.
+    // Compacting the document requires a full `save` before the next incremental one.
+    pub fn save_incremental(&mut self) -> Result<Vec<u8>, DocError> {
+        self.with_full_doc(|doc| doc.save_incremental())
+    }
+
+    // Appends the operations of a buffer produced by `save_incremental`. Unlike
+    // `apply_encoded_operations`, they are considered as saved already.
+    pub fn load_incremental(&mut self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        self.with_full_doc(|doc| doc.load_incremental(buffer))
+    }
+
+    // Whether the document changed since it was created, loaded or last marked as clean.
+    // Lazy documents are read-only, so they are never dirty.
+    pub fn is_dirty(&self) -> bool {
+        match &self.handle {
+            DocHandle::Lazy(_) => false,
+            DocHandle::Full(doc) => doc.is_dirty(),
+        }
+    }
+
+    // Usually called after the serialized document has been persisted
+    pub fn clear_dirty(&mut self) {
+        if let DocHandle::Full(doc) = &mut self.handle {
+            doc.clear_dirty();
+        }
+    }
+
+    // Shrinks the history by merging the text insertions that each client made
+    // one after the other, returning the number of removed operations.
+    // The contents of the values whose TTL has elapsed are dropped as well.
+    pub fn compact(&mut self) -> Result<usize, DocError> {
+        self.with_full_doc(|doc| Ok(doc.compact()))
+    }
+
+    // Deleted text is still kept in memory to order concurrent insertions, but once the
+    // operations have been persisted its contents are not needed anymore.
+    // When enabled, only the ids and lengths of deleted blocks are kept.
+    pub fn set_drop_tombstones(&mut self, enabled: bool) -> Result<(), DocError> {
+        self.with_full_doc(|doc| {
+            doc.set_drop_tombstones(enabled);
+            Ok(())
+        })
+    }
+
+    // Runs before each write of a transaction, which fails with `SchemaViolation` if the
+    // validator rejects it. Like options, validators are not saved with the document.
+    pub fn set_validator<F: Fn(&Path, &Value) -> bool + Send + Sync + 'static>(
+        &mut self,
+        validator: F,
+    ) -> Result<(), DocError> {
+        self.with_full_doc(|doc| {
+            doc.set_validator(Some(Arc::new(validator)));
+            Ok(())
+        })
+    }
+
+    pub fn remove_validator(&mut self) -> Result<(), DocError> {
+        self.with_full_doc(|doc| {
+            doc.set_validator(None);
+            Ok(())
+        })
+    }
+
+    // Options are not saved with the document, so they have to be set again after loading it
+    pub fn set_options(&mut self, options: DocOptions) -> Result<(), DocError> {
+        self.with_full_doc(|doc| {
+            doc.set_options(options);
+            Ok(())
+        })
+    }
+
+    // Same as `merge`, but also reports which parts of the document were affected
+    pub fn merge_with_report(&mut self, other: &Self) -> Result<MergeReport, DocError> {
+        self.with_full_doc(|doc| doc.merge_with_report(other))
+    }
+
+    pub fn merge_with_options(
+        &mut self,
+        other: &Self,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
+        self.with_full_doc(|doc| doc.merge_with_options(other, options))
+    }
+
+    // Latest operation of each client included in the document
+    pub fn version(&self) -> Result<DocVersion, DocError> {
+        Ok(self.full_doc()?.version())
+    }
+
+    // Encodes the operations that a document at `version` is missing, so that they can be
+    // sent to it and applied with `apply_encoded_operations`.
+    // An empty version encodes the whole history.
+    pub fn encode_new_operations_since(&self, version: &DocVersion) -> Result<Vec<u8>, DocError> {
+        self.full_doc()?.encode_new_operations_since(version)
+    }
+
+    // Applies operations encoded by another document, without having to load it.
+    // Operations that are already part of the document are ignored.
+    pub fn apply_encoded_operations(&mut self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        self.with_full_doc(|doc| doc.apply_encoded_operations(buffer))
+    }
+
+    // Merges the operations of a serialized document, or of a buffer produced by
+    // `encode_new_operations_since`, without loading the remote document first
+    pub fn merge_bytes(&mut self, buffer: &[u8]) -> Result<MergeReport, DocError> {
+        let buffer = Bytes::copy_from_slice(buffer);
+        self.with_full_doc(|doc| doc.merge_bytes(buffer))
+    }
+
+    pub fn apply_encoded_operations_with_options(
+        &mut self,
+        buffer: Bytes,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
+        self.with_full_doc(|doc| doc.apply_encoded_operations_with_options(buffer, options))
+    }
+
+    // Cheap compared to `serialize`, see `DocSnapshot`
+    pub fn snapshot(&self) -> DocSnapshot {
+        let handle = match &self.handle {
+            DocHandle::Lazy(doc) => SnapshotHandle::Lazy(Box::new(doc.clone())),
+            DocHandle::Full(doc) => SnapshotHandle::Full(Box::new(doc.snapshot())),
+        };
+
+        DocSnapshot {
+            handle: Arc::new(handle),
+        }
+    }
+
+    // Copy of the object that can be kept while the document changes. Lazy documents decode
+    // the objects one at a time, and keep them up to `LazyOptions::memory_budget`.
+    pub fn get_object<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Arc<CachedObjectValue>>, DocError> {
+        let object: ObjRef = object.into();
+        match &self.handle {
+            DocHandle::Lazy(doc) => Ok(doc.get_object(&object)),
+            DocHandle::Full(doc) => doc.get_object(object),
+        }
+    }
+
+    pub fn conflicts<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<Conflict<'_>>, DocError> {
+        self.full_doc()?.get_conflicts(object, selector)
+    }
+
+    // Every value written to the key, in causal order, including the overwritten and deleted
+    // ones, e.g. to show its previous values
+    pub fn key_history<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<KeyHistoryEntry<'_>>, DocError> {
+        self.full_doc()?.key_history(object, selector)
+    }
+
+    // Every concurrent value of the key if its policy is `ConflictPolicy::MultiValue`, i.e. the
+    // values of the `conflicts` that are not deleted. Otherwise the value returned by `get`.
+    pub fn get_all<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<&Value>, DocError> {
+        self.full_doc()?.get_all(object, selector)
+    }
+
+    // Blame view of a text: each visible range along with the operation that inserted it
+    pub fn text_history<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Vec<TextHistoryEntry>, DocError> {
+        self.full_doc()?.text_history(object)
+    }
+
+    // Annotations of the text, with the range they cover now, see `Transaction::add_annotation`
+    pub fn annotations<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Vec<Annotation>, DocError> {
+        self.full_doc()?.annotations(object)
+    }
+
+    // Rows of a table in the order they were inserted, see `Transaction::insert_row`
+    pub fn rows<TRef: Into<ObjRef>>(&self, table: TRef) -> Result<Vec<Row<'_>>, DocError> {
+        self.full_doc()?.rows(table)
+    }
+
+    // Index of a text position at the `to` version, given its index at the `from` one
+    pub fn map_position<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+        index: u32,
+        from: &DocVersion,
+        to: &DocVersion,
+    ) -> Result<u32, DocError> {
+        self.full_doc()?.map_position(object, index, from, to)
+    }
+
+    // The current state as the JSON of a single Automerge change, see `automerge.rs`
+    pub fn export_automerge_changes(&self) -> Result<Vec<u8>, DocError> {
+        self.full_doc()?.export_automerge_changes()
+    }
+
+    // Summary of every operation, in a causal order, see `ChangeSummary`
+    pub fn history(&self) -> Result<impl Iterator<Item = ChangeSummary> + '_, DocError> {
+        Ok(self.full_doc()?.history())
+    }
+
+    // Clients that contributed to the document, sorted by creation time.
+    // The position of a client is its local `ClientId`, which can change when merging
+    // documents, while the global ID is stable. Operation IDs obtained before a merge
+    // should be mapped to global clients before merging to keep them meaningful.
+    pub fn clients(&self) -> Result<&[GlobalClient], DocError> {
+        Ok(self.full_doc()?.clients())
+    }
+
+    pub fn global_client_of(&self, id: &OperationId) -> Result<Option<&GlobalClientId>, DocError> {
+        Ok(self.full_doc()?.global_client_of(id))
+    }
+
+    // Frontier of the operation log, made of the operations that no other operation depends on,
+    // sorted by the global ID of their client and their sequence
+    pub fn heads(&self) -> Result<Vec<OperationId>, DocError> {
+        Ok(self.full_doc()?.heads())
+    }
+
+    pub fn stats(&self) -> Result<DocStats, DocError> {
+        self.full_doc()?.stats()
+    }
+
+    // Verifies the internal invariants of the document, e.g. after loading a buffer from an
+    // untrusted source, without panicking on the broken ones
+    pub fn check_integrity(&self) -> Result<IntegrityReport, DocError> {
+        Ok(self.full_doc()?.check_integrity())
+    }
+
+    // Merges the documents into fresh replicas in every order, or in a sample of the orders
+    // for more than a few documents, and compares their contents. Meant for the tests of
+    // applications built on top of the documents, see `ConvergenceReport`.
+    pub fn verify_convergence(docs: &[Doc]) -> Result<ConvergenceReport, DocError> {
+        convergence::verify_convergence(docs)
+    }
+
+    // Renders the internal structures of the document, to be attached to bug reports
+    #[cfg(feature = "debug-tools")]
+    pub fn debug_dump(&self, format: GraphFormat) -> Result<String, DocError> {
+        Ok(self.full_doc()?.debug_dump(format))
+    }
+
+    // Operations received before their parent are kept aside until it arrives. These are the
+    // parents they are waiting for, which should be requested from the peers that sent them.
+    pub fn missing_dependencies(&self) -> Result<Vec<OperationId>, DocError> {
+        Ok(self.full_doc()?.missing_dependencies())
+    }
+
+    pub fn has_operation(&self, id: &OperationId) -> Result<bool, DocError> {
+        Ok(self.full_doc()?.has_operation(id))
+    }
+
+    pub fn get_operation(&self, id: &OperationId) -> Result<Option<&Operation>, DocError> {
+        Ok(self.full_doc()?.get_operation(id))
+    }
+
+    fn full_doc(&self) -> Result<&FullDoc, DocError> {
+        self.handle.as_full().ok_or(DocError::DocumentNotReady)
+    }
+
     fn with_full_doc<T: 'a>(
         &'a mut self,
         action: impl FnOnce(&'a mut FullDoc) -> Result<T, DocError>,
+    ) -> Result<T, DocError> {
+        if self.handle.is_lazy() && self.upgrade_mode == UpgradeMode::NonBlocking {
+            return Err(DocError::DocumentNotReady);
+        }
+
+        self.with_upgraded_doc(action)
+    }
+
+    // Upgrades lazy documents regardless of the mode, for the callers that can't fail
+    fn with_upgraded_doc<T: 'a>(
+        &'a mut self,
+        action: impl FnOnce(&'a mut FullDoc) -> Result<T, DocError>,
     ) -> Result<T, DocError> {
         if self.handle.is_full() {
             let doc = self.handle.as_full_mut().expect("expected full doc");
@@ -146,10 +555,10 @@ impl ReadableDoc for Doc {
         }
     }
 
-    fn get_text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<String>, DocError> {
+    fn text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<TextRef<'_>>, DocError> {
         match &self.handle {
-            DocHandle::Lazy(doc) => doc.get_text(object),
-            DocHandle::Full(doc) => doc.get_text(object),
+            DocHandle::Lazy(doc) => doc.text(object),
+            DocHandle::Full(doc) => doc.text(object),
         }
     }
 
@@ -159,11 +568,46 @@ impl ReadableDoc for Doc {
             DocHandle::Full(doc) => doc.as_map(),
         }
     }
+
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError> {
+        match &self.handle {
+            DocHandle::Lazy(doc) => doc.keys(object),
+            DocHandle::Full(doc) => doc.keys(object),
+        }
+    }
+
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        match &self.handle {
+            DocHandle::Lazy(doc) => doc.len(object),
+            DocHandle::Full(doc) => doc.len(object),
+        }
+    }
+
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError> {
+        match &self.handle {
+            DocHandle::Lazy(doc) => doc.conflict_count(object, selector),
+            DocHandle::Full(doc) => doc.conflict_count(object, selector),
+        }
+    }
+
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_> {
+        match &self.handle {
+            DocHandle::Lazy(doc) => doc.format_id(id),
+            DocHandle::Full(doc) => doc.format_id(id),
+        }
+    }
 }
 
 impl WritableDoc for Doc {
     fn transaction(&mut self) -> Transaction {
-        self.with_full_doc(|doc| Ok(doc.transaction()))
+        self.with_upgraded_doc(|doc| Ok(doc.transaction()))
             .expect("unable to create transaction")
     }
 
@@ -173,10 +617,14 @@ impl WritableDoc for Doc {
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum DocError {
     #[error("document not ready")]
     DocumentNotReady,
 
+    #[error("unable to load the document: {0}")]
+    BuildFailed(#[source] Arc<DocError>),
+
     #[error("serialization error: {0}")]
     SerializationError(#[from] SerializationError),
 
@@ -188,4 +636,29 @@ pub enum DocError {
 
     #[error("operation log error: {0}")]
     OperationLogError(#[from] OperationLogError),
+
+    #[error("unable to write text: {0}")]
+    WriteError(#[from] std::fmt::Error),
+
+    #[error("the document was compacted since the last save, a full save is required")]
+    FullSaveRequired,
+
+    #[error("index {index} is out of range for object {object:?} of length {len}")]
+    InvalidIndex {
+        object: ObjRef,
+        index: u32,
+        len: u32,
+    },
+
+    #[error("range {range:?} is not on char boundaries of object {object:?}")]
+    InvalidRange { object: ObjRef, range: Range<usize> },
+
+    #[error("the version includes operations missing from the document")]
+    UnknownVersion,
+
+    #[error("path error: {0}")]
+    PathError(#[from] PathError),
+
+    #[error("automerge error: {0}")]
+    AutomergeError(#[from] AutomergeError),
 }
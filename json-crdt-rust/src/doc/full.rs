@@ -1,20 +1,46 @@
+use std::sync::Arc;
+
 use bytes::Bytes;
+use rustc_hash::FxHashMap;
 
 use crate::{
-    client_registry::{ClientRegistry, ClientRemappable},
-    operation_log::OperationLog,
-    serde::{serialize, BufferReader, BufferRegions, Serializable, SerializationError},
+    automerge::{actor_of, export_changes},
+    client_registry::{preserves_order, ClientRegistry, ClientRemappable, ClientRemappings},
+    crdt::{map::map::MapCRDT, text::TextCRDT},
+    operation_log::{OperationDecoder, OperationLog, OperationLogSnapshot},
+    serde::{
+        deserialize_update, is_serialized_doc, serialize, serialize_update, BufferReader,
+        BufferRegions, Compression, Serializable, SerializationError,
+    },
     transaction::Transaction,
     view::{View, ViewError},
-    Doc, DocError, GlobalClientId, ObjRef, ObjectValue, Selector, Timestamp, Value,
+    Annotation, CachedObjectValue, ChangeKind, ChangeSummary, ClientId, Conflict, Doc, DocError,
+    DocOptions, DocStats, DocVersion, FormattableId, FormattedId, GlobalClient, GlobalClientId,
+    IntegrityIssue, IntegrityReport, KeyHistoryEntry, MergeOptions, MergeReport, ObjRef,
+    ObjectValue, Operation, OperationAction, OperationId, Path, Row, ScalarValue, Selector,
+    SequenceBlockId, SequenceIndex, TextHistoryEntry, TextRef, Timestamp, TimestampAdjustment,
+    Validator, Value, ValueKind,
 };
 
 use super::traits::{ReadableDoc, WritableDoc};
 
+#[cfg(feature = "debug-tools")]
+use crate::debug::{DebugGraph, GraphFormat};
+
+#[cfg(any(debug_assertions, feature = "validation"))]
+use crate::operation_log::{is_compacted_version, OperationLogError, ValidationError};
+
 pub struct FullDoc {
     operation_log: OperationLog,
     view: View,
     client_registry: ClientRegistry,
+    // Log version at the time the document was last marked as clean
+    clean_version: u64,
+    clients_changed: bool,
+    // Version persisted by the last save, `None` after a compaction, as the merged
+    // operations can't be appended to the ones that were saved already
+    saved_version: Option<DocVersion>,
+    validator: Option<Validator>,
 }
 
 impl FullDoc {
@@ -25,6 +51,10 @@ impl FullDoc {
             operation_log: OperationLog::new(client_registry.get_current_id()),
             view: View::new(client_registry.get_current_id()),
             client_registry,
+            clean_version: 0,
+            clients_changed: false,
+            saved_version: Some(DocVersion::new()),
+            validator: None,
         }
     }
 
@@ -34,12 +64,649 @@ impl FullDoc {
         buffer: Bytes,
     ) -> Result<Self, DocError> {
         let mut reader = BufferReader::load(buffer)?;
-        let mut builder = FullDocBuilder::new(client_id, timestamp, reader);
+        let builder = FullDocBuilder::new(client_id, timestamp, reader);
 
+        // Loading in one go, the error of the failing step is returned as it is
+        let mut state = BuildState::Starting;
         loop {
-            if let Some(doc) = builder.build_step()? {
-                return Ok(doc);
+            match builder.next_state(state)? {
+                BuildStep::Continue(next) => state = next,
+                BuildStep::Done(doc) => return Ok(*doc),
+            }
+        }
+    }
+
+    pub fn get_conflicts<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<Conflict<'_>>, DocError> {
+        Ok(self.view.get_conflicts(object.into(), selector.into())?)
+    }
+
+    pub fn key_history<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<KeyHistoryEntry<'_>>, DocError> {
+        let revisions = self.view.get_key_history(object.into(), selector.into())?;
+
+        let mut history = Vec::with_capacity(revisions.len());
+        for revision in revisions {
+            let author = self
+                .client_registry
+                .get_global_id(revision.id.client_id)
+                .ok_or(ViewError::UnknownClient(revision.id.client_id))?;
+
+            history.push(KeyHistoryEntry {
+                id: revision.id,
+                author: author.clone(),
+                timestamp: revision.timestamp,
+                value: revision.value,
+                overwritten: revision.overwritten,
+                deleted: revision.deleted,
+            });
+        }
+
+        Ok(history)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.clients_changed || self.operation_log.version() != self.clean_version
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.clean_version = self.operation_log.version();
+        self.clients_changed = false;
+    }
+
+    pub fn compact(&mut self) -> usize {
+        let removed = self.operation_log.compact();
+
+        // Expired values can't be read anymore, so only their operations are kept
+        let now = self.view.now();
+        let cleared = self.operation_log.clear_expired_values(now);
+        self.view.clear_expired_values(now);
+
+        if removed > 0 || cleared > 0 {
+            self.saved_version = None;
+        }
+
+        removed
+    }
+
+    pub fn save(&mut self) -> Result<Vec<u8>, DocError> {
+        let buffer = self.serialize()?;
+        self.saved_version = Some(self.version());
+        self.clear_dirty();
+        Ok(buffer)
+    }
+
+    pub fn save_incremental(&mut self) -> Result<Vec<u8>, DocError> {
+        let saved_version = self
+            .saved_version
+            .as_ref()
+            .ok_or(DocError::FullSaveRequired)?;
+        let buffer = self.encode_new_operations_since(saved_version)?;
+        self.saved_version = Some(self.version());
+        self.clear_dirty();
+        Ok(buffer)
+    }
+
+    pub fn load_incremental(&mut self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        let was_dirty = self.is_dirty();
+        let previous_version = self.version();
+        let report = self.apply_encoded_operations(buffer)?;
+
+        // The loaded operations are persisted already, so they don't have to be saved again.
+        // Clients with unsaved operations are left as they are, to keep them in the next save.
+        let version = self.version();
+        if let Some(saved_version) = &mut self.saved_version {
+            for (client, sequence) in version.iter() {
+                if saved_version.get(client) == previous_version.get(client) {
+                    saved_version.set(client.clone(), sequence);
+                }
+            }
+        }
+        if !was_dirty {
+            self.clear_dirty();
+        }
+
+        Ok(report)
+    }
+
+    pub fn set_drop_tombstones(&mut self, enabled: bool) {
+        self.view.set_drop_tombstones(enabled);
+    }
+
+    pub fn set_validator(&mut self, validator: Option<Validator>) {
+        self.validator = validator;
+    }
+
+    pub fn set_options(&mut self, options: DocOptions) {
+        self.view.set_clock(options.clock.clone());
+        self.operation_log
+            .set_timestamp_source(options.clock, options.timestamp_source);
+        self.operation_log.set_max_orphans(options.max_orphans);
+        self.view.set_max_text_block_len(options.max_text_block_len);
+        self.view.set_text_fanout(options.text_fanout);
+        self.view
+            .set_conflict_policies(options.conflict_policy, options.key_conflict_policies);
+    }
+
+    pub fn get_object(&self, object: ObjRef) -> Result<Option<Arc<CachedObjectValue>>, DocError> {
+        let now = self.view.now();
+        let object = self.view.get_object(object)?;
+        Ok(object.map(|object| Arc::new(CachedObjectValue::from_object(object, now))))
+    }
+
+    pub fn get_all<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Vec<&Value>, DocError> {
+        Ok(self.view.get_all(object.into(), selector.into())?)
+    }
+
+    pub fn text_history<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Vec<TextHistoryEntry>, DocError> {
+        let object: ObjRef = object.into();
+
+        let text = match self.view.get_object(object)? {
+            Some(ObjectValue::Text(text)) => text,
+            Some(value) => {
+                return Err(ViewError::incompatible(&object, ValueKind::Text, value).into())
             }
+            None => return Ok(Vec::new()),
+        };
+
+        // Insertions of each client, sorted by the sequence of their first character
+        let mut insertions: FxHashMap<ClientId, Vec<(SequenceIndex, &Operation)>> =
+            FxHashMap::default();
+        for operation in self.operation_log.iter() {
+            if let OperationAction::InsertText(action) = &operation.action {
+                if action.object == object {
+                    insertions
+                        .entry(action.id.client_id)
+                        .or_default()
+                        .push((action.id.sequence, operation));
+                }
+            }
+        }
+        for client_insertions in insertions.values_mut() {
+            client_insertions.sort_by_key(|(sequence, _)| *sequence);
+        }
+
+        let mut history: Vec<TextHistoryEntry> = Vec::new();
+        let mut index = 0;
+        for (id, value) in text.iter_blocks() {
+            let operation = insertions
+                .get(&id.client_id)
+                .and_then(|client_insertions| {
+                    let position =
+                        client_insertions.partition_point(|(sequence, _)| *sequence <= id.sequence);
+                    position
+                        .checked_sub(1)
+                        .map(|position| client_insertions[position].1)
+                })
+                .ok_or_else(|| ViewError::UnknownTextBlock {
+                    object,
+                    block: id.clone(),
+                })?;
+
+            let start = index;
+            index += value.len() as u32;
+
+            // Blocks are split by later edits, merge them back when possible
+            if let Some(last) = history.last_mut() {
+                if last.operation == operation.id && last.range.end == start {
+                    last.range.end = index;
+                    last.value.push_str(value);
+                    continue;
+                }
+            }
+
+            let author = self
+                .global_client_of(&operation.id)
+                .ok_or(ViewError::UnknownClient(operation.id.client_id))?;
+
+            history.push(TextHistoryEntry {
+                range: start..index,
+                value: value.to_string(),
+                operation: operation.id,
+                author: author.clone(),
+                timestamp: operation.timestamp,
+            });
+        }
+
+        Ok(history)
+    }
+
+    pub fn annotations<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Vec<Annotation>, DocError> {
+        let object: ObjRef = object.into();
+
+        match self.view.get_object(object)? {
+            Some(ObjectValue::Text(text)) => Ok(text
+                .annotations()
+                .into_iter()
+                .map(|(id, range, bias)| Annotation {
+                    payload: ObjRef::Object(id),
+                    range,
+                    bias,
+                })
+                .collect()),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Text, value).into()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // Rows of the table in the order they were inserted (the same on every replica), see
+    // `Transaction::insert_row`. Keys that don't hold a map are skipped.
+    pub fn rows<TRef: Into<ObjRef>>(&self, table: TRef) -> Result<Vec<Row<'_>>, DocError> {
+        let table: ObjRef = table.into();
+        let Some(keys) = self.keys(table)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut rows = Vec::new();
+        for key in keys {
+            let Some(Value::Object(object)) = self.get(table, key)? else {
+                continue;
+            };
+            let Some(ObjectValue::Map(row)) = self.view.get_object(object)? else {
+                continue;
+            };
+
+            let now = self.view.now();
+            let mut values = FxHashMap::default();
+            for (column, _) in row.iter(now) {
+                if let Some(value) = self.get(*object, column)? {
+                    values.insert(column.clone(), value);
+                }
+            }
+
+            let order = match object {
+                ObjRef::Object(id) => (
+                    self.get_operation(id)
+                        .map_or(0, |operation| operation.timestamp),
+                    self.global_client_of(id).cloned(),
+                    id.sequence,
+                ),
+                ObjRef::Root => (0, None, 0),
+            };
+            let id = match key {
+                Selector::Key(key) => key.clone(),
+                Selector::Index(index) => index.to_string(),
+            };
+            rows.push((order, Row::new(id, *object, values)));
+        }
+
+        rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    }
+
+    // Translates the index of a text at the `from` version into the index of the same
+    // position at the `to` version. Positions are anchored to the character that follows
+    // them, so deleted characters map to where they used to be, and the end stays the end.
+    // Insertions merged by a compaction count as a whole, as if they were made at once.
+    pub fn map_position<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+        index: u32,
+        from: &DocVersion,
+        to: &DocVersion,
+    ) -> Result<u32, DocError> {
+        let object: ObjRef = object.into();
+
+        let text = match self.view.get_object(object)? {
+            Some(ObjectValue::Text(text)) => text,
+            Some(value) => {
+                return Err(ViewError::incompatible(&object, ValueKind::Text, value).into())
+            }
+            None => return Err(ViewError::ObjectNotFound(object).into()),
+        };
+
+        let version = self.version();
+        if !version.includes(from) || !version.includes(to) {
+            return Err(DocError::UnknownVersion);
+        }
+
+        // Every character, deleted ones included, is placed in the order of the blocks.
+        // The blocks of each client are sorted by sequence to look up their position.
+        let mut blocks: FxHashMap<ClientId, Vec<(SequenceIndex, u32, usize)>> =
+            FxHashMap::default();
+        let mut len = 0;
+        for (id, block_len) in text.iter_all_blocks() {
+            blocks
+                .entry(id.client_id)
+                .or_default()
+                .push((id.sequence, block_len, len));
+            len += block_len as usize;
+        }
+        for client_blocks in blocks.values_mut() {
+            client_blocks.sort_by_key(|(sequence, _, _)| *sequence);
+        }
+        let position_of = |id: &SequenceBlockId| -> Result<usize, DocError> {
+            blocks
+                .get(&id.client_id)
+                .and_then(|client_blocks| {
+                    let index = client_blocks
+                        .partition_point(|(sequence, _, _)| *sequence <= id.sequence)
+                        .checked_sub(1)?;
+                    let (sequence, block_len, position) = client_blocks[index];
+                    (id.sequence < sequence + block_len)
+                        .then(|| position + (id.sequence - sequence) as usize)
+                })
+                .ok_or_else(|| {
+                    ViewError::UnknownTextBlock {
+                        object,
+                        block: id.clone(),
+                    }
+                    .into()
+                })
+        };
+
+        let mut visible_from = vec![false; len];
+        let mut visible_to = vec![false; len];
+        let mut deletions: Vec<(&Operation, Vec<usize>)> = Vec::new();
+        for operation in self.operation_log.iter() {
+            let (included_in_from, included_in_to) = match self.global_client_of(&operation.id) {
+                Some(global_id) => (
+                    operation.id.sequence <= from.get(global_id),
+                    operation.id.sequence <= to.get(global_id),
+                ),
+                None => continue,
+            };
+
+            match &operation.action {
+                OperationAction::InsertText(action) if action.object == object => {
+                    let start = position_of(&action.id)?;
+                    let end = (start + action.value.len()).min(len);
+                    visible_from[start..end].fill(included_in_from);
+                    visible_to[start..end].fill(included_in_to);
+                }
+                OperationAction::DeleteText(action) if action.object == object => {
+                    let range = position_of(&action.left)?..=position_of(&action.right)?;
+                    deletions.push((operation, range.collect()));
+                }
+                OperationAction::DeleteTextRanges(action) if action.object == object => {
+                    for range in &action.ranges {
+                        let range = position_of(&range.left)?..=position_of(&range.right)?;
+                        deletions.push((operation, range.collect()));
+                    }
+                }
+                // Only the characters of the ranges are deleted, not the ones between them
+                OperationAction::DeleteTextItems(action) if action.object == object => {
+                    let mut positions = Vec::new();
+                    for range in &action.ranges {
+                        for sequence in range.left.sequence..=range.right.sequence {
+                            let id = SequenceBlockId::new(range.left.client_id, sequence);
+                            positions.push(position_of(&id)?);
+                        }
+                    }
+                    deletions.push((operation, positions));
+                }
+                _ => {}
+            }
+        }
+        for (operation, positions) in deletions {
+            let Some(global_id) = self.global_client_of(&operation.id) else {
+                continue;
+            };
+            let included_in_from = operation.id.sequence <= from.get(global_id);
+            let included_in_to = operation.id.sequence <= to.get(global_id);
+            for position in positions {
+                visible_from[position] &= !included_in_from;
+                visible_to[position] &= !included_in_to;
+            }
+        }
+
+        let position = visible_from
+            .iter()
+            .enumerate()
+            .filter(|(_, visible)| **visible)
+            .map(|(position, _)| position)
+            .chain(std::iter::once(len))
+            .nth(index as usize)
+            .ok_or_else(|| DocError::InvalidIndex {
+                object,
+                index,
+                len: visible_from.iter().filter(|visible| **visible).count() as u32,
+            })?;
+
+        Ok(visible_to[..position]
+            .iter()
+            .filter(|visible| **visible)
+            .count() as u32)
+    }
+
+    pub fn export_automerge_changes(&self) -> Result<Vec<u8>, DocError> {
+        let client_id = self.client_registry.get_current_id();
+        let global_id = self
+            .client_registry
+            .get_global_id(client_id)
+            .expect("the current client is registered");
+        export_changes(&self.view, &actor_of(global_id))
+    }
+
+    // Operations in the order of the log, which is a causal order
+    pub fn history(&self) -> impl Iterator<Item = ChangeSummary> + '_ {
+        self.operation_log
+            .iter()
+            .map(|operation| self.summarize(operation))
+    }
+
+    fn summarize(&self, operation: &Operation) -> ChangeSummary {
+        let (kind, preview) = match &operation.action {
+            OperationAction::SetMapValue(action) => {
+                (ChangeKind::SetValue, preview_value(&action.value))
+            }
+            OperationAction::SetMapValues(action) => {
+                let values: Vec<String> = action
+                    .entries
+                    .iter()
+                    .map(|entry| match &action.entries[..] {
+                        [_] => preview_value(&entry.value),
+                        _ => format!(
+                            "{}: {}",
+                            Path::from_iter([entry.selector.clone()]),
+                            preview_value(&entry.value)
+                        ),
+                    })
+                    .collect();
+                (ChangeKind::SetValue, values.join(", "))
+            }
+            OperationAction::DeleteMapValue(_) => (ChangeKind::DeleteValue, String::new()),
+            OperationAction::MoveMapValue(action) => {
+                let from = Path::from_iter([action.from.clone()]);
+                (ChangeKind::MoveValue, format!("from {}", from))
+            }
+            OperationAction::MoveObject(_) => (ChangeKind::MoveValue, String::new()),
+            OperationAction::CreateMap(_) => (ChangeKind::CreateMap, String::new()),
+            OperationAction::CreateText(_) => (ChangeKind::CreateText, String::new()),
+            OperationAction::InsertText(action) => (
+                ChangeKind::InsertText,
+                format!("{:?}", action.value.as_str()),
+            ),
+            OperationAction::DeleteText(_)
+            | OperationAction::DeleteTextRanges(_)
+            | OperationAction::DeleteTextItems(_) => (ChangeKind::DeleteText, String::new()),
+            OperationAction::CreateAnnotation(_) => (ChangeKind::AddAnnotation, String::new()),
+        };
+
+        let path = self.view.path_of(operation.action.object()).map(|path| {
+            match operation.action.selector() {
+                Some(selector) => path.join(selector.clone()),
+                None => path,
+            }
+        });
+
+        ChangeSummary {
+            operation: operation.id,
+            author: self
+                .global_client_of(&operation.id)
+                .expect("clients of logged operations are registered")
+                .clone(),
+            timestamp: operation.timestamp,
+            path,
+            kind,
+            preview: truncate_preview(preview),
+        }
+    }
+
+    // Operations and objects are shared with the snapshot, objects are only copied when the
+    // document modifies them afterwards
+    pub fn snapshot(&self) -> FullDocSnapshot {
+        FullDocSnapshot {
+            client_registry: self.client_registry.clone(),
+            operation_log: self.operation_log.snapshot(),
+            view: self.view.clone(),
+        }
+    }
+
+    pub fn clients(&self) -> &[GlobalClient] {
+        self.client_registry.get_clients()
+    }
+
+    pub fn global_client_of(&self, id: &OperationId) -> Option<&GlobalClientId> {
+        self.client_registry.get_global_id(id.client_id)
+    }
+
+    pub fn heads(&self) -> Vec<OperationId> {
+        let mut heads: Vec<OperationId> = self.operation_log.heads().collect();
+        self.client_registry.sort_by_global_id(&mut heads);
+        heads
+    }
+
+    // Serializes each region to measure it, so it's as expensive as `serialize`
+    pub fn stats(&self) -> Result<DocStats, DocError> {
+        let mut stats = DocStats {
+            operations: self.operation_log.len(),
+            operations_by_action: self.operation_log.operations_by_action(),
+            orphans: self.operation_log.orphans_len(),
+            client_registry_size: self.client_registry.serialize()?.len(),
+            operation_log_size: self.operation_log.serialize()?.len(),
+            view_cache_size: self.view.serialize()?.len(),
+            ..Default::default()
+        };
+
+        for object in self.view.objects.values() {
+            match object.as_ref() {
+                ObjectValue::Map(map) => {
+                    let (values, deleted) = map.value_counts();
+                    stats.maps += 1;
+                    stats.map_values += values;
+                    stats.deleted_map_values += deleted;
+                }
+                ObjectValue::Text(text) => {
+                    let (tombstones, tombstone_len) = text.tombstones();
+                    stats.texts += 1;
+                    stats.text_blocks += text.block_count();
+                    stats.text_tombstones += tombstones;
+                    stats.tombstone_len += tombstone_len;
+                    stats.tree_nodes += text.node_count();
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    // Renders the operation log, the blocks of each map and the tree of each text as a graph
+    #[cfg(feature = "debug-tools")]
+    pub fn debug_dump(&self, format: GraphFormat) -> String {
+        let format_id = |id: &dyn FormattableId| {
+            let id = id.client_and_sequence();
+            let global_client =
+                id.and_then(|(client, _)| self.client_registry.get_global_id(client));
+            FormattedId::new(id, global_client).to_string()
+        };
+
+        let mut graph = DebugGraph::default();
+        self.operation_log
+            .render_debug_graph(graph.cluster("operations".to_string()), &format_id);
+
+        let mut objects: Vec<(&ObjRef, &ObjectValue)> = self
+            .view
+            .objects
+            .iter()
+            .map(|(object, value)| (object, value.as_ref()))
+            .collect();
+        objects.sort_by_key(|(object, _)| object.client_and_sequence());
+        for (index, (object, value)) in objects.into_iter().enumerate() {
+            let prefix = format!("obj{}", index);
+            match value {
+                ObjectValue::Map(map) => {
+                    let cluster = graph.cluster(format!("map {}", format_id(object)));
+                    map.render_debug_graph(cluster, &prefix, &format_id);
+                }
+                ObjectValue::Text(text) => {
+                    let cluster = graph.cluster(format!("text {}", format_id(object)));
+                    text.render_debug_graph(cluster, &prefix, &format_id);
+                }
+            }
+        }
+
+        graph.render(format)
+    }
+
+    pub fn check_integrity(&self) -> IntegrityReport {
+        let mut issues: Vec<IntegrityIssue> = self
+            .operation_log
+            .check_integrity()
+            .into_iter()
+            .map(IntegrityIssue::from)
+            .collect();
+        issues.extend(
+            self.client_registry
+                .check_integrity()
+                .into_iter()
+                .map(IntegrityIssue::from),
+        );
+
+        let mut texts: Vec<(&ObjRef, &TextCRDT)> = self
+            .view
+            .objects
+            .iter()
+            .filter_map(|(object, value)| Some((object, value.as_text()?)))
+            .collect();
+        texts.sort_by_key(|(object, _)| object.client_and_sequence());
+        for (object, text) in texts {
+            issues.extend(
+                text.check_integrity()
+                    .into_iter()
+                    .map(|error| IntegrityIssue::Text {
+                        object: *object,
+                        error,
+                    }),
+            );
+        }
+
+        IntegrityReport { issues }
+    }
+
+    pub fn missing_dependencies(&self) -> Vec<OperationId> {
+        self.operation_log.missing_dependencies()
+    }
+
+    pub fn has_operation(&self, id: &OperationId) -> bool {
+        self.operation_log.has_operation(id)
+    }
+
+    pub fn get_operation(&self, id: &OperationId) -> Option<&Operation> {
+        self.operation_log.get_operation(id)
+    }
+
+    fn find_map(&self, object: ObjRef) -> Result<Option<&MapCRDT>, DocError> {
+        match self.view.get_object(object)? {
+            Some(ObjectValue::Map(map)) => Ok(Some(map)),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Map, value).into()),
+            None => Ok(None),
         }
     }
 
@@ -50,14 +717,44 @@ impl FullDoc {
         view: View,
         client_registry: ClientRegistry,
     ) -> Self {
-        Self {
+        let mut doc = Self {
+            clean_version: operation_log.version(),
+            clients_changed: false,
+            saved_version: None,
+            validator: None,
             operation_log,
             view,
             client_registry,
-        }
+        };
+        // Loaded documents are persisted already
+        doc.saved_version = Some(doc.version());
+        doc
     }
 }
 
+// Longer previews are cut, as they are meant to be listed
+const MAX_PREVIEW_CHARS: usize = 80;
+
+fn preview_value(value: &Value) -> String {
+    match value {
+        Value::Scalar(ScalarValue::String(value)) => format!("{:?}", value),
+        Value::Scalar(ScalarValue::Int(value)) => value.to_string(),
+        Value::Scalar(ScalarValue::Double(value)) => value.to_string(),
+        Value::Scalar(ScalarValue::Bool(value)) => value.to_string(),
+        Value::Scalar(ScalarValue::Timestamp(value)) => value.to_string(),
+        Value::Scalar(ScalarValue::Null) => "null".to_string(),
+        Value::Object(_) => "object".to_string(),
+    }
+}
+
+fn truncate_preview(mut preview: String) -> String {
+    if let Some((index, _)) = preview.char_indices().nth(MAX_PREVIEW_CHARS) {
+        preview.truncate(index);
+        preview.push('…');
+    }
+    preview
+}
+
 impl ReadableDoc for FullDoc {
     fn get<TRef: Into<crate::ObjRef>, TSelector: Into<crate::Selector>>(
         &self,
@@ -70,17 +767,15 @@ impl ReadableDoc for FullDoc {
         Ok(self.view.get(object, selector)?)
     }
 
-    fn get_text<TRef: Into<crate::ObjRef>>(
+    fn text<TRef: Into<crate::ObjRef>>(
         &self,
         object: TRef,
-    ) -> Result<Option<String>, DocError> {
+    ) -> Result<Option<TextRef<'_>>, DocError> {
         let object: ObjRef = object.into();
 
         match self.view.get_object(object)? {
-            Some(ObjectValue::Text(value)) => Ok(Some(value.to_string())),
-            Some(_) => Err(DocError::ViewError(ViewError::IncompatibleTypes(
-                "expected text".to_string(),
-            ))),
+            Some(ObjectValue::Text(value)) => Ok(Some(TextRef::from_crdt(value))),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Text, value).into()),
             None => Ok(None),
         }
     }
@@ -88,6 +783,37 @@ impl ReadableDoc for FullDoc {
     fn as_map<'a>(&'a self) -> Result<crate::DataMap<'a>, DocError> {
         Ok(self.view.as_map())
     }
+
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError> {
+        Ok(self
+            .find_map(object.into())?
+            .map(|map| Box::new(map.iter(self.view.now()).map(|(selector, _)| selector)) as Box<_>))
+    }
+
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        Ok(self
+            .find_map(object.into())?
+            .map(|map| map.iter(self.view.now()).count()))
+    }
+
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError> {
+        Ok(self
+            .view
+            .get_conflict_count(object.into(), selector.into())?)
+    }
+
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_> {
+        let id = id.client_and_sequence();
+        let global_client = id.and_then(|(client, _)| self.client_registry.get_global_id(client));
+        FormattedId::new(id, global_client)
+    }
 }
 
 impl WritableDoc for FullDoc {
@@ -97,61 +823,404 @@ impl WritableDoc for FullDoc {
             &mut self.view,
             &mut self.client_registry,
         )
+        .with_validator(self.validator.as_ref())
     }
 
     fn merge(&mut self, other: &Doc) -> Result<(), DocError> {
+        self.merge_with_report(other)?;
+        Ok(())
+    }
+}
+
+impl FullDoc {
+    pub fn merge_with_report(&mut self, other: &Doc) -> Result<MergeReport, DocError> {
+        self.merge_with_options(other, &MergeOptions::default())
+    }
+
+    pub fn merge_with_options(
+        &mut self,
+        other: &Doc,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
         let other_doc = other
             .handle
             .as_full()
             .ok_or_else(|| DocError::DocumentNotReady)?;
 
-        let other_docs_clients = other_doc.client_registry.get_clients();
-        let remappings = self.client_registry.register_clients(other_docs_clients);
+        self.register_clients(other_doc.client_registry.get_clients())?;
 
-        if let Some(remappings) = remappings {
-            self.operation_log.remap_client_ids(&remappings);
-            self.view
-                .repopulate(&self.operation_log, &self.client_registry)?;
+        let other_remappings = self
+            .client_registry
+            .remappings_from(other_doc.client_registry.get_clients());
+
+        self.ingest_operations(
+            other_doc.operation_log.iter_sorted().cloned(),
+            other_remappings.as_ref(),
+            options,
+        )
+    }
+
+    pub fn version(&self) -> DocVersion {
+        let mut version = DocVersion::new();
+        for (client_id, sequence) in self.operation_log.client_sequences() {
+            if let Some(global_id) = self.client_registry.get_global_id(*client_id) {
+                version.set(global_id.clone(), *sequence);
+            }
+        }
+
+        version
+    }
+
+    pub fn encode_new_operations_since(&self, version: &DocVersion) -> Result<Vec<u8>, DocError> {
+        let operations = self.operation_log.iter().filter(|operation| {
+            match self.client_registry.get_global_id(operation.id.client_id) {
+                Some(global_id) => operation.id.sequence > version.get(global_id),
+                None => true,
+            }
+        });
+
+        Ok(serialize_update(
+            self.client_registry.serialize()?,
+            OperationLog::encode(operations)?,
+        )?)
+    }
+
+    pub fn apply_encoded_operations(&mut self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        self.apply_encoded_operations_with_options(buffer, &MergeOptions::default())
+    }
+
+    pub fn apply_encoded_operations_with_options(
+        &mut self,
+        buffer: Bytes,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
+        let (client_registry, operations) = deserialize_update(buffer)?;
+        self.apply_operation_regions(client_registry, operations, options)
+    }
+
+    // Accepts both serialized documents and the buffers of `encode_new_operations_since`.
+    // Only the clients and the operations of a document are read, its view is skipped.
+    pub fn merge_bytes(&mut self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        if !is_serialized_doc(&buffer) {
+            return self.apply_encoded_operations(buffer);
+        }
+
+        let reader = BufferReader::load(buffer)?;
+        self.apply_operation_regions(
+            reader.client_registry(),
+            reader.operation_log(),
+            &MergeOptions::default(),
+        )
+    }
+
+    fn apply_operation_regions(
+        &mut self,
+        client_registry: Bytes,
+        mut operations: Bytes,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
+        let clients = ClientRegistry::deserialize_clients(client_registry)?;
+        let operations = OperationLog::decode(&mut operations, clients.len())?;
+        // Reject malformed operations before any of them is applied
+        for operation in &operations {
+            OperationLog::check_operation(operation)?;
         }
 
-        // TODO: make this actually efficient (from here and forward)
-        let mut other_client_registry = other_doc.client_registry.clone();
-        let other_remappings =
-            other_client_registry.register_clients(self.client_registry.get_clients());
+        self.register_clients(&clients)?;
 
-        for operation in other_doc.operation_log.iter_sorted() {
-            let mut operation = operation.clone();
+        // The operations use the local IDs of the sender, which are the positions of its clients
+        let remappings = self.client_registry.remappings_from(&clients);
 
-            if let Some(remappings) = &other_remappings {
+        self.ingest_operations(operations.into_iter(), remappings.as_ref(), options)
+    }
+
+    fn ingest_operations(
+        &mut self,
+        operations: impl Iterator<Item = Operation>,
+        remappings: Option<&ClientRemappings>,
+        options: &MergeOptions,
+    ) -> Result<MergeReport, DocError> {
+        let log_len = self.operation_log.len();
+        let mut report = MergeReport::default();
+        let mut applied_operations = Vec::new();
+        let ingested = self.ingest_all(
+            operations,
+            remappings,
+            options,
+            &mut report,
+            &mut applied_operations,
+        );
+        // Only the new operations, as the rest of the log was checked when it was ingested
+        #[cfg(any(debug_assertions, feature = "validation"))]
+        let ingested = ingested.and_then(|()| {
+            self.operation_log
+                .validate_from(log_len)
+                .map_err(|error| OperationLogError::from(error).into())
+        });
+
+        // The operations ingested before a failure stay in the log (an orphan over the limit
+        // or a rejected duplicate doesn't invalidate them), so the view must include them too
+        self.update_view(log_len)?;
+        ingested?;
+
+        self.fill_report(&mut report, applied_operations);
+        Ok(report)
+    }
+
+    fn ingest_all(
+        &mut self,
+        operations: impl Iterator<Item = Operation>,
+        remappings: Option<&ClientRemappings>,
+        options: &MergeOptions,
+        report: &mut MergeReport,
+        applied_operations: &mut Vec<OperationId>,
+    ) -> Result<(), DocError> {
+        for mut operation in operations {
+            if let Some(remappings) = remappings {
                 operation.remap_client_ids(remappings);
             }
+            applied_operations.extend(self.ingest_operation(operation, options, report)?);
+        }
+
+        Ok(())
+    }
 
-            self.operation_log.apply_operation(operation)?;
+    // Operations are always appended to the log, and the view executes them in log order,
+    // so executing the ones added after `log_len` on top of the current view is equivalent
+    // to replaying the whole log
+    fn update_view(&mut self, log_len: usize) -> Result<(), DocError> {
+        for operation in self.operation_log.iter().skip(log_len) {
+            self.view
+                .apply_operation(operation, &self.client_registry)?;
         }
 
-        self.view
-            .repopulate(&self.operation_log, &self.client_registry)?;
+        Ok(())
+    }
+
+    fn register_clients(&mut self, clients: &[GlobalClient]) -> Result<(), DocError> {
+        let previous_clients = self.client_registry.get_clients().to_vec();
+        let remappings = self.client_registry.register_clients(clients);
+        if self.client_registry.get_clients() != previous_clients {
+            self.clients_changed = true;
+        }
+
+        if let Some(remappings) = remappings {
+            self.operation_log.remap_client_ids(&remappings);
+
+            // Tie-breaks between concurrent operations depend on the order of the clients
+            if preserves_order(&remappings) {
+                self.view.remap_client_ids(&remappings);
+            } else {
+                self.view
+                    .repopulate(&self.operation_log, &self.client_registry)?;
+            }
+        }
 
         Ok(())
     }
+
+    // Adds a remote operation to the log, returning the IDs of the applied operations
+    fn ingest_operation(
+        &mut self,
+        mut operation: Operation,
+        options: &MergeOptions,
+        report: &mut MergeReport,
+    ) -> Result<Vec<OperationId>, DocError> {
+        // The same ID must always identify the same operation (or its compacted version).
+        // Timestamps are not compared, as they might have been adjusted by a `TimestampPolicy`.
+        #[cfg(any(debug_assertions, feature = "validation"))]
+        if let Some(existing) = self.operation_log.get_operation(&operation.id) {
+            let operation = Operation {
+                timestamp: existing.timestamp,
+                ..operation.clone()
+            };
+
+            if !is_compacted_version(existing, &operation)
+                && !is_compacted_version(&operation, existing)
+            {
+                let error = ValidationError::DuplicateOperationId(operation.id);
+                return Err(OperationLogError::from(error).into());
+            }
+        }
+
+        if !self.operation_log.is_processed(&operation.id) {
+            if let Some(original) = options.timestamp_policy.apply(&mut operation) {
+                report.timestamp_adjustments.push(TimestampAdjustment {
+                    operation: operation.id,
+                    original,
+                    effective: operation.timestamp,
+                });
+            }
+        }
+
+        let applied = self.operation_log.apply_operation(operation)?;
+        Ok(applied.iter().map(|operation| operation.id).collect())
+    }
+
+    fn fill_report(&self, report: &mut MergeReport, applied_operations: Vec<OperationId>) {
+        report.applied_operations = applied_operations.len();
+        for id in applied_operations {
+            let operation = self
+                .operation_log
+                .get_operation(&id)
+                .expect("applied operation should be in the log");
+            if let Some(key) = self.view.top_level_key(operation) {
+                *report.by_key.entry(key).or_default() += 1;
+            }
+        }
+    }
 }
 
-impl Serializable for FullDoc {
+// Everything needed to serialize a document, detached from the document itself
+pub struct FullDocSnapshot {
+    client_registry: ClientRegistry,
+    operation_log: OperationLogSnapshot,
+    view: View,
+}
+
+impl FullDocSnapshot {
+    pub fn serialize_with_compression(
+        &self,
+        compression: Compression,
+    ) -> Result<Vec<u8>, SerializationError> {
+        let regions = BufferRegions {
+            client_registry: self.client_registry.serialize()?,
+            operation_log: self.operation_log.serialize()?,
+            view_cache: self.view.serialize()?,
+        };
+
+        serialize(regions, compression)
+    }
+
+    fn find_map(&self, object: ObjRef) -> Result<Option<&MapCRDT>, DocError> {
+        match self.view.get_object(object)? {
+            Some(ObjectValue::Map(map)) => Ok(Some(map)),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Map, value).into()),
+            None => Ok(None),
+        }
+    }
+}
+
+// Same as the implementation of `FullDoc`, reading the objects shared with it
+impl ReadableDoc for FullDocSnapshot {
+    fn get<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Option<&Value>, DocError> {
+        Ok(self.view.get(object.into(), selector.into())?)
+    }
+
+    fn text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<TextRef<'_>>, DocError> {
+        let object: ObjRef = object.into();
+
+        match self.view.get_object(object)? {
+            Some(ObjectValue::Text(value)) => Ok(Some(TextRef::from_crdt(value))),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Text, value).into()),
+            None => Ok(None),
+        }
+    }
+
+    fn as_map<'a>(&'a self) -> Result<crate::DataMap<'a>, DocError> {
+        Ok(self.view.as_map())
+    }
+
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError> {
+        Ok(self
+            .find_map(object.into())?
+            .map(|map| Box::new(map.iter(self.view.now()).map(|(selector, _)| selector)) as Box<_>))
+    }
+
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        Ok(self
+            .find_map(object.into())?
+            .map(|map| map.iter(self.view.now()).count()))
+    }
+
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError> {
+        Ok(self
+            .view
+            .get_conflict_count(object.into(), selector.into())?)
+    }
+
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_> {
+        let id = id.client_and_sequence();
+        let global_client = id.and_then(|(client, _)| self.client_registry.get_global_id(client));
+        FormattedId::new(id, global_client)
+    }
+}
+
+impl Serializable for FullDocSnapshot {
     fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
-        let serialized = serialize(BufferRegions {
+        self.serialize_with_compression(Compression::None)
+    }
+}
+
+impl FullDoc {
+    pub fn serialize_with_compression(
+        &self,
+        compression: Compression,
+    ) -> Result<Vec<u8>, SerializationError> {
+        let regions = BufferRegions {
             client_registry: self.client_registry.serialize()?,
             operation_log: self.operation_log.serialize()?,
             view_cache: self.view.serialize()?,
-        })?;
+        };
 
-        Ok(serialized)
+        serialize(regions, compression)
     }
 }
 
+impl Serializable for FullDoc {
+    fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
+        self.serialize_with_compression(Compression::None)
+    }
+}
+
+// Number of operations that each step of `FullDocBuilder` decodes, loads into the log or
+// replays on the view, so that a single step doesn't block the caller for too long
+const BUILD_STEP_OPERATIONS: usize = 4096;
+
 pub struct FullDocBuilder {
     client_id: GlobalClientId,
     timestamp: Timestamp,
     reader: BufferReader,
+    state: BuildState,
+}
+
+enum BuildState {
+    Starting,
+    Loading(Box<LoadingState>),
+    Replaying(Box<ReplayingState>),
+    // A step failed, it and the following ones report its error
+    Failed(Arc<DocError>),
+    Finished,
+}
+
+struct LoadingState {
+    client_registry: ClientRegistry,
+    remappings: Option<ClientRemappings>,
+    decoder: OperationDecoder,
+    operation_log: OperationLog,
+}
+
+enum BuildStep {
+    Continue(BuildState),
+    Done(Box<FullDoc>),
+}
+
+struct ReplayingState {
+    client_registry: ClientRegistry,
+    operation_log: OperationLog,
+    view: View,
+    next_operation: usize,
 }
 
 impl FullDocBuilder {
@@ -160,39 +1229,121 @@ impl FullDocBuilder {
             client_id,
             timestamp,
             reader,
+            state: BuildState::Starting,
         }
     }
 
+    // A builder for the same buffer, starting from the first step
+    pub fn restarted(&self) -> Self {
+        Self::new(self.client_id.clone(), self.timestamp, self.reader.clone())
+    }
+
+    // Each call performs one step: reading the clients, decoding the columns of the operations
+    // one at a time, then decoding and loading the operations into the log and replaying them
+    // on the view in batches. Returns the document after the last one.
     pub fn build_step(&mut self) -> Result<Option<FullDoc>, DocError> {
-        // TODO: This method is intended to be refactored in the future to be incremental.
-        //       model as a state machine and make each step divisible
+        if let BuildState::Failed(error) = &self.state {
+            return Err(DocError::BuildFailed(error.clone()));
+        }
 
-        let (client_registry, remappings) = ClientRegistry::from_buffer(
-            self.client_id.clone(),
-            self.timestamp,
-            self.reader.client_registry(),
-        )?;
+        let state = std::mem::replace(&mut self.state, BuildState::Finished);
+        match self.next_state(state) {
+            Ok(BuildStep::Continue(state)) => {
+                self.state = state;
+                Ok(None)
+            }
+            Ok(BuildStep::Done(doc)) => Ok(Some(*doc)),
+            Err(error) => {
+                let error = Arc::new(error);
+                self.state = BuildState::Failed(error.clone());
+                Err(DocError::BuildFailed(error))
+            }
+        }
+    }
 
-        let operation_log = OperationLog::from_buffer(
-            client_registry.get_current_id(),
-            remappings,
-            &mut self.reader.operation_log(),
-        )?;
+    fn next_state(&self, state: BuildState) -> Result<BuildStep, DocError> {
+        let state = match state {
+            BuildState::Starting => {
+                let (client_registry, remappings) = ClientRegistry::from_buffer(
+                    self.client_id.clone(),
+                    self.timestamp,
+                    self.reader.client_registry(),
+                )?;
+                // Operations refer to the clients by their position in the buffer, before
+                // the local one is registered
+                let clients = ClientRegistry::deserialize_clients(self.reader.client_registry())?;
+                let decoder = OperationLog::decoder(self.reader.operation_log(), clients.len())?;
 
-        let mut view = View::new(client_registry.get_current_id());
-        view.repopulate(&operation_log, &client_registry)?;
+                BuildState::Loading(Box::new(LoadingState {
+                    operation_log: OperationLog::new(client_registry.get_current_id()),
+                    client_registry,
+                    remappings,
+                    decoder,
+                }))
+            }
+            BuildState::Loading(mut loading) => {
+                if loading.decoder.decode_column()? {
+                    return Ok(BuildStep::Continue(BuildState::Loading(loading)));
+                }
 
-        let doc = FullDoc::from_components(
-            self.client_id.clone(),
-            self.timestamp,
-            operation_log,
-            view,
-            client_registry,
-        );
+                let operations = loading.decoder.decode_operations(BUILD_STEP_OPERATIONS)?;
+                for mut operation in operations {
+                    if let Some(remappings) = &loading.remappings {
+                        operation.remap_client_ids(remappings);
+                    }
+                    loading.operation_log.apply_operation(operation)?;
+                }
 
-        Ok(Some(doc))
+                if loading.decoder.remaining_operations() > 0 {
+                    BuildState::Loading(loading)
+                } else {
+                    let LoadingState {
+                        client_registry,
+                        operation_log,
+                        ..
+                    } = *loading;
+                    let mut view = View::new(client_registry.get_current_id());
+                    view.reset(&client_registry);
+                    BuildState::Replaying(Box::new(ReplayingState {
+                        client_registry,
+                        operation_log,
+                        view,
+                        next_operation: 0,
+                    }))
+                }
+            }
+            BuildState::Replaying(mut replaying) => {
+                let ReplayingState {
+                    client_registry,
+                    operation_log,
+                    view,
+                    next_operation,
+                } = &mut *replaying;
+                let end = (*next_operation + BUILD_STEP_OPERATIONS).min(operation_log.len());
+                view.replay_log(operation_log, *next_operation..end, client_registry)?;
+                *next_operation = end;
+
+                if end < operation_log.len() {
+                    BuildState::Replaying(replaying)
+                } else {
+                    let ReplayingState {
+                        client_registry,
+                        operation_log,
+                        view,
+                        ..
+                    } = *replaying;
+                    return Ok(BuildStep::Done(Box::new(FullDoc::from_components(
+                        self.client_id.clone(),
+                        self.timestamp,
+                        operation_log,
+                        view,
+                        client_registry,
+                    ))));
+                }
+            }
+            BuildState::Failed(_) | BuildState::Finished => return Err(DocError::DocumentNotReady),
+        };
+
+        Ok(BuildStep::Continue(state))
     }
 }
-
-// TODO: partial full doc object that takes the buffer (reference?) and load
-// the view and oplog incrementally
@@ -0,0 +1,34 @@
+use thiserror::Error;
+
+use crate::{
+    client_registry::RegistryIntegrityError, crdt::shared::tree::TreeIntegrityError,
+    operation_log::ValidationError, ObjRef,
+};
+
+// Invariants found broken by `Doc::check_integrity`. A document with issues should be
+// discarded and loaded again from a trusted serialized copy.
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum IntegrityIssue {
+    #[error("operation log: {0}")]
+    OperationLog(#[from] ValidationError),
+
+    #[error("client registry: {0}")]
+    ClientRegistry(#[from] RegistryIntegrityError),
+
+    #[error("text {object:?}: {error}")]
+    Text {
+        object: ObjRef,
+        error: TreeIntegrityError,
+    },
+}
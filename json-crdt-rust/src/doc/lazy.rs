@@ -1,41 +1,197 @@
+use std::{
+    collections::BTreeMap,
+    mem::size_of,
+    sync::{Arc, Mutex, PoisonError},
+};
+
 use bytes::Bytes;
+use rustc_hash::FxHashMap;
 
 use crate::{
-    serde::{BufferReader, Serializable, SerializationError},
+    client_registry::ClientRegistry,
+    serde::{recompress, BufferReader, Compression, Serializable, SerializationError},
     view::{ViewCache, ViewError},
-    CachedObjectValue, DocError, GlobalClientId, ObjRef, Selector, Timestamp, Value,
+    CachedObjectValue, Clock, DocError, FormattableId, FormattedId, GlobalClient, GlobalClientId,
+    ObjRef, ScalarValue, Selector, SystemClock, TextRef, Timestamp, Value, ValueKind,
 };
 
 use super::{
     full::{FullDoc, FullDocBuilder},
+    options::LazyOptions,
     traits::ReadableDoc,
 };
 
+// Cheap to clone, as the view and the decoded objects are shared, see `Doc::snapshot`
 pub struct LazyDoc {
-    view: ViewCache,
+    view: Arc<ViewCache>,
+    objects: Arc<Mutex<ObjectCache>>,
+    clients: Vec<GlobalClient>,
     buffer: Bytes,
     builder: FullDocBuilder,
 }
 
+// Objects returned by `LazyDoc::get_object`, dropped in least recently read order once their
+// estimated size is over the budget. The handles that are still held keep their object alive.
+struct ObjectCache {
+    budget: usize,
+    size: usize,
+    reads: u64,
+    entries: FxHashMap<ObjRef, CacheEntry>,
+    // Objects by the read that last returned them
+    recency: BTreeMap<u64, ObjRef>,
+}
+
+struct CacheEntry {
+    object: Arc<CachedObjectValue>,
+    size: usize,
+    last_read: u64,
+}
+
+impl ObjectCache {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            size: 0,
+            reads: 0,
+            entries: FxHashMap::default(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, obj_ref: &ObjRef) -> Option<Arc<CachedObjectValue>> {
+        let entry = self.entries.get_mut(obj_ref)?;
+        self.reads += 1;
+        self.recency.remove(&entry.last_read);
+        self.recency.insert(self.reads, *obj_ref);
+        entry.last_read = self.reads;
+        Some(entry.object.clone())
+    }
+
+    fn insert(&mut self, obj_ref: ObjRef, object: Arc<CachedObjectValue>) {
+        self.reads += 1;
+        let size = estimated_size(&object);
+        self.size += size;
+        self.recency.insert(self.reads, obj_ref);
+        let entry = CacheEntry {
+            object,
+            size,
+            last_read: self.reads,
+        };
+        if let Some(replaced) = self.entries.insert(obj_ref, entry) {
+            self.size -= replaced.size;
+            self.recency.remove(&replaced.last_read);
+        }
+
+        while self.size > self.budget {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+// Rough size of the memory used by a decoded object
+fn estimated_size(object: &CachedObjectValue) -> usize {
+    match object {
+        CachedObjectValue::Map(map) => map
+            .iter()
+            .map(|(selector, value)| {
+                let key_len = match selector {
+                    Selector::Key(key) => key.len(),
+                    Selector::Index(_) => 0,
+                };
+                let value_len = match value {
+                    Value::Scalar(ScalarValue::String(string)) => string.len(),
+                    _ => 0,
+                };
+                size_of::<(Selector, Value)>() + key_len + value_len
+            })
+            .sum(),
+        CachedObjectValue::Text(text) => text.len() + text.chunks().count() * size_of::<usize>(),
+    }
+}
+
+// Snapshots of a lazy document start building from scratch, copying the progress of the
+// builder would be as expensive as the steps themselves
+impl Clone for LazyDoc {
+    fn clone(&self) -> Self {
+        Self {
+            view: self.view.clone(),
+            objects: self.objects.clone(),
+            clients: self.clients.clone(),
+            buffer: self.buffer.clone(),
+            builder: self.builder.restarted(),
+        }
+    }
+}
+
 impl LazyDoc {
     pub fn load(
         client_id: GlobalClientId,
         timestamp: Timestamp,
         buffer: Bytes,
+        options: LazyOptions,
     ) -> Result<Self, DocError> {
         let reader = BufferReader::load(buffer.clone())?;
-        let view = ViewCache::from_buffer(reader.view_cache())?;
+        let view = Arc::new(ViewCache::from_buffer(reader.view_cache())?);
+        let clients = ClientRegistry::deserialize_clients(reader.client_registry())?;
 
         Ok(Self {
             view,
+            objects: Arc::new(Mutex::new(ObjectCache::new(options.memory_budget))),
+            clients,
             buffer,
             builder: FullDocBuilder::new(client_id, timestamp, reader),
         })
     }
 
+    pub fn serialize_with_compression(
+        &self,
+        compression: Compression,
+    ) -> Result<Vec<u8>, SerializationError> {
+        recompress(self.buffer.clone(), compression)
+    }
+
     pub fn prepare_full_doc_step(&mut self) -> Result<Option<FullDoc>, DocError> {
         self.builder.build_step()
     }
+
+    // Decodes the object, or returns the one decoded by a previous call if it's still in
+    // the cache, see `LazyOptions::memory_budget`
+    pub fn get_object(&self, object: &ObjRef) -> Option<Arc<CachedObjectValue>> {
+        // Their contents depend on the time
+        if self.view.has_expiring_values(object) {
+            return self.view.load_object(object, self.now());
+        }
+
+        let mut objects = self.objects.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = objects.get(object) {
+            return Some(cached);
+        }
+
+        let loaded = self.view.load_object(object, self.now())?;
+        objects.insert(*object, loaded.clone());
+        Some(loaded)
+    }
+}
+
+impl LazyDoc {
+    // Lazy documents are loaded without options, so values written with a TTL are read
+    // against the system clock
+    fn now(&self) -> Timestamp {
+        SystemClock.now()
+    }
+
+    fn find_map(&self, object: ObjRef) -> Result<Option<&FxHashMap<Selector, Value>>, DocError> {
+        match self.view.get_object(object)? {
+            Some(CachedObjectValue::Map(map)) => Ok(Some(map)),
+            Some(value) => Err(ViewError::incompatible(&object, ValueKind::Map, value).into()),
+            None => Ok(None),
+        }
+    }
 }
 
 impl ReadableDoc for LazyDoc {
@@ -47,23 +203,59 @@ impl ReadableDoc for LazyDoc {
         let object_ref: ObjRef = object_ref.into();
         let selector: Selector = selector.into();
 
-        Ok(self.view.get(object_ref, selector)?)
+        Ok(self.view.get(object_ref, selector, self.now())?)
     }
 
-    fn get_text<TRef: Into<ObjRef>>(&self, object_ref: TRef) -> Result<Option<String>, DocError> {
+    fn text<TRef: Into<ObjRef>>(&self, object_ref: TRef) -> Result<Option<TextRef<'_>>, DocError> {
         let object_ref: ObjRef = object_ref.into();
 
         match self.view.get_object(object_ref)? {
-            Some(CachedObjectValue::Text(value)) => Ok(Some(value.to_string())),
-            Some(_) => Err(DocError::ViewError(ViewError::IncompatibleTypes(
-                "expected text".to_string(),
-            ))),
+            Some(CachedObjectValue::Text(value)) => Ok(Some(TextRef::from_cached(value))),
+            Some(value) => Err(ViewError::incompatible(&object_ref, ValueKind::Text, value).into()),
             None => Ok(None),
         }
     }
 
     fn as_map<'a>(&'a self) -> Result<crate::DataMap<'a>, DocError> {
-        Ok(self.view.as_map())
+        Ok(self.view.as_map(self.now()))
+    }
+
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError> {
+        let object: ObjRef = object.into();
+        let now = self.now();
+        Ok(self.find_map(object)?.map(|map| {
+            Box::new(
+                map.keys()
+                    .filter(move |selector| !self.view.is_expired(&object, selector, now)),
+            ) as Box<_>
+        }))
+    }
+
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        Ok(self.keys(object)?.map(|keys| keys.count()))
+    }
+
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError> {
+        let count = self
+            .view
+            .get_conflict_count(object.into(), selector.into())?;
+        Ok(count as usize)
+    }
+
+    // The ids of the buffer are the positions of the clients in its registry
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_> {
+        let id = id.client_and_sequence();
+        let global_client = id
+            .and_then(|(client, _)| self.clients.get(client as usize))
+            .map(|client| &client.global_id);
+        FormattedId::new(id, global_client)
     }
 }
 
@@ -73,3 +265,47 @@ impl Serializable for LazyDoc {
         Ok(self.buffer.to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: usize) -> Arc<CachedObjectValue> {
+        let map = (0..entries)
+            .map(|index| (Selector::Index(index), Value::Scalar(ScalarValue::Int(1))))
+            .collect();
+        Arc::new(CachedObjectValue::Map(map))
+    }
+
+    fn object(sequence: u32) -> ObjRef {
+        ObjRef::Object(crate::ObjId {
+            client_id: 0,
+            sequence,
+        })
+    }
+
+    #[test]
+    fn test_object_cache_drops_the_least_recently_read_objects() {
+        let entry_size = size_of::<(Selector, Value)>();
+        let mut cache = ObjectCache::new(entry_size * 25);
+        for sequence in 1..=3 {
+            cache.insert(object(sequence), map(10));
+        }
+        assert_eq!(cache.size, entry_size * 20);
+        assert!(cache.get(&object(1)).is_none());
+
+        // Reading an object keeps it over the ones read before
+        assert!(cache.get(&object(2)).is_some());
+        cache.insert(object(4), map(6));
+        assert!(cache.get(&object(3)).is_none());
+        assert!(cache.get(&object(2)).is_some());
+        assert!(cache.get(&object(4)).is_some());
+
+        // Objects larger than the budget are not kept
+        let handle = map(30);
+        cache.insert(object(5), handle.clone());
+        assert_eq!(cache.size, 0);
+        assert!(cache.entries.is_empty() && cache.recency.is_empty());
+        assert_eq!(handle.as_map().unwrap().len(), 30);
+    }
+}
@@ -1,7 +1,29 @@
+mod awareness;
+mod clock;
+mod convergence;
 mod doc;
 mod full;
+mod integrity;
 mod lazy;
+mod options;
+mod report;
+mod shared;
+mod snapshot;
+mod stats;
 mod traits;
+mod upgrade;
+mod version;
 
+pub use awareness::*;
+pub use clock::*;
+pub use convergence::*;
 pub use doc::*;
+pub use integrity::*;
+pub use options::*;
+pub use report::*;
+pub use shared::*;
+pub use snapshot::*;
+pub use stats::*;
 pub use traits::*;
+pub use upgrade::*;
+pub use version::*;
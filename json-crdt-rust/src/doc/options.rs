@@ -0,0 +1,160 @@
+use std::{fmt::Debug, sync::Arc};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    crdt::text::DEFAULT_MAX_BLOCK_LEN, Conflict, Operation, Path, Selector, Timestamp, Value,
+};
+
+use super::clock::{Clock, SystemClock};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeOptions {
+    pub timestamp_policy: TimestampPolicy,
+}
+
+// Timestamps decide which concurrent write wins, but incoming ones come from the clocks
+// of other peers. Hosts that relay operations can bound them when they are received.
+// Adjusted operations are stored with the effective timestamp, so replicas only agree on
+// the outcome if they receive those operations through the same host.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    #[default]
+    Trust,
+    // Timestamps later than the given one (usually the receive time) are lowered to it
+    Clamp(Timestamp),
+    // Every incoming operation gets the given timestamp
+    Override(Timestamp),
+}
+
+impl TimestampPolicy {
+    // Returns the original timestamp if the operation was adjusted
+    pub(crate) fn apply(&self, operation: &mut Operation) -> Option<Timestamp> {
+        let effective = match *self {
+            TimestampPolicy::Trust => return None,
+            TimestampPolicy::Clamp(max) => operation.timestamp.min(max),
+            TimestampPolicy::Override(timestamp) => timestamp,
+        };
+
+        if effective == operation.timestamp {
+            return None;
+        }
+
+        Some(std::mem::replace(&mut operation.timestamp, effective))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DocOptions {
+    pub clock: Arc<dyn Clock>,
+    pub timestamp_source: TimestampSource,
+    pub conflict_policy: ConflictPolicy,
+    // Overrides the policy for the keys with the given name, in any map
+    pub key_conflict_policies: FxHashMap<Selector, ConflictPolicy>,
+    // Maximum number of received operations kept while waiting for their parent, see
+    // `Doc::missing_dependencies`. Unlimited by default.
+    pub max_orphans: Option<usize>,
+    // Insertions into texts longer than this many bytes are stored as several blocks, so
+    // that editing inside them stays cheap. Blocks that already exist are not split.
+    pub max_text_block_len: u32,
+    // Only applies to the texts created (or rebuilt when loading) afterwards
+    pub text_fanout: TextFanout,
+}
+
+impl Default for DocOptions {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            timestamp_source: TimestampSource::default(),
+            conflict_policy: ConflictPolicy::default(),
+            key_conflict_policies: FxHashMap::default(),
+            max_orphans: None,
+            max_text_block_len: DEFAULT_MAX_BLOCK_LEN,
+            text_fanout: TextFanout::default(),
+        }
+    }
+}
+
+// Number of children of the nodes in the tree of a text. Wider nodes mean fewer of them, so
+// less memory for very large texts, at the cost of more work to update a node on each edit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TextFanout {
+    Narrow,
+    #[default]
+    Medium,
+    Wide,
+}
+
+// What happens when a lazy document is asked for something that only the full document
+// has, e.g. a `Doc::merge` or the `Doc::version`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeMode {
+    // The document is fully built before the write, blocking the caller until it's done
+    #[default]
+    Blocking,
+    // The write fails with `DocError::DocumentNotReady`, the document has to be upgraded
+    // with `Doc::begin_upgrade` first. Transactions can't fail, so they still block.
+    NonBlocking,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyOptions {
+    // Estimated size in bytes of the objects kept decoded for `Doc::get_object`, the least
+    // recently read ones are dropped first. The objects read through `ReadableDoc` are not
+    // part of it, as the references to them must stay valid.
+    pub memory_budget: usize,
+}
+
+impl Default for LazyOptions {
+    fn default() -> Self {
+        Self {
+            memory_budget: 16 * 1024 * 1024,
+        }
+    }
+}
+
+// Where the timestamps of local operations come from. They decide which concurrent write
+// wins, so replicas should agree on the source: logical timestamps are small numbers that
+// always lose against wall clock ones.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampSource {
+    // Milliseconds since the unix epoch, as reported by the device
+    #[default]
+    WallClock,
+    // One more than the highest timestamp seen, local or merged, so a write always wins
+    // against the ones its author had already seen, regardless of clock skew
+    Lamport,
+    // The wall clock time, unless a timestamp at least as high has been seen already.
+    // Timestamps stay close to the real time, but never go backwards.
+    HybridLogical,
+}
+
+// Given the concurrent values of a key, in the order of `Doc::conflicts`, returns the index of
+// the one to read, or `None` to fall back to the last write
+pub type ConflictResolver = Arc<dyn Fn(&Selector, &[Conflict]) -> Option<usize> + Send + Sync>;
+
+// Given the path of a map key and the value about to be written to it, returns whether the
+// write is allowed, see `Doc::set_validator`
+pub type Validator = Arc<dyn Fn(&Path, &Value) -> bool + Send + Sync>;
+
+// Decides which value is read when a map key has been written concurrently.
+// Policies are only applied at read time and are not saved with the document, so replicas
+// with different policies still converge to the same state.
+#[derive(Clone, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    LastWriteWins,
+    // Reads return the last write, while `Doc::get_all` returns every concurrent value
+    MultiValue,
+    Custom(ConflictResolver),
+}
+
+impl Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LastWriteWins => write!(f, "LastWriteWins"),
+            Self::MultiValue => write!(f, "MultiValue"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
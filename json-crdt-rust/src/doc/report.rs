@@ -0,0 +1,28 @@
+use rustc_hash::FxHashMap;
+
+use crate::{OperationId, Selector, Timestamp};
+
+// Summary of the operations applied by a merge
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeReport {
+    // Operations that were not already part of the document
+    pub applied_operations: usize,
+    // Applied operations, grouped by the root key they currently fall under.
+    // Operations on objects that are no longer reachable from the root are not grouped.
+    pub by_key: FxHashMap<Selector, usize>,
+    // Incoming operations whose timestamp was changed by the `TimestampPolicy`
+    pub timestamp_adjustments: Vec<TimestampAdjustment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimestampAdjustment {
+    pub operation: OperationId,
+    pub original: Timestamp,
+    pub effective: Timestamp,
+}
+
+impl MergeReport {
+    pub fn operations_for<T: Into<Selector>>(&self, key: T) -> usize {
+        self.by_key.get(&key.into()).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::{
+    mpsc::{channel, Receiver},
+    Arc, Mutex, PoisonError, RwLock,
+};
+
+use bytes::Bytes;
+
+use crate::{transaction::Transaction, TransactionError};
+
+use super::{
+    doc::{Doc, DocError},
+    report::MergeReport,
+    traits::WritableDoc,
+    version::DocVersion,
+};
+
+// Handle to a document that can be cloned and shared between threads, for example between
+// a network task applying remote changes and a UI task editing the document.
+// The document is only borrowed for the duration of a callback, so callers don't hold the lock
+// across await points. Subscribers are notified after the lock is released.
+#[derive(Clone)]
+pub struct SharedDoc {
+    inner: Arc<SharedDocInner>,
+}
+
+struct SharedDocInner {
+    doc: RwLock<Doc>,
+    subscribers: Mutex<Vec<Subscriber<DocChange>>>,
+}
+
+// Called with every change, and dropped once it returns false
+pub(crate) type Subscriber<T> = Box<dyn Fn(&T) -> bool + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOrigin {
+    Local,
+    Remote,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocChange {
+    pub origin: ChangeOrigin,
+    // Version of the document right after the change
+    pub version: DocVersion,
+}
+
+impl SharedDoc {
+    pub fn new(doc: Doc) -> Self {
+        Self {
+            inner: Arc::new(SharedDocInner {
+                doc: RwLock::new(doc),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn read<T>(&self, callback: impl FnOnce(&Doc) -> T) -> T {
+        // Changes are applied operation by operation, so a panicking callback can't leave
+        // the document in an inconsistent state
+        let doc = self
+            .inner
+            .doc
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        callback(&doc)
+    }
+
+    // Runs the callback in a transaction, which is committed afterwards.
+    // Transactions can't be rolled back, so the writes made before the callback fails are
+    // kept and committed with the others. Subscribers are notified whenever the document
+    // changed, even if the callback or the commit failed.
+    pub fn write<T>(
+        &self,
+        callback: impl FnOnce(&mut Transaction) -> Result<T, TransactionError>,
+    ) -> Result<T, TransactionError> {
+        let (result, change) = {
+            let mut doc = self
+                .inner
+                .doc
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let previous_version = doc.version().ok();
+
+            let mut txn = doc.transaction();
+            let result = callback(&mut txn);
+            let result = txn.commit().and(result);
+
+            let version = doc.version().ok();
+            let change = version
+                .filter(|version| previous_version.as_ref() != Some(version))
+                .map(|version| DocChange {
+                    origin: ChangeOrigin::Local,
+                    version,
+                });
+            (result, change)
+        };
+
+        if let Some(change) = change {
+            self.notify(change);
+        }
+        result
+    }
+
+    pub fn merge(&self, other: &Doc) -> Result<MergeReport, DocError> {
+        self.apply_remote(|doc| doc.merge_with_report(other))
+    }
+
+    pub fn apply_encoded_operations(&self, buffer: Bytes) -> Result<MergeReport, DocError> {
+        self.apply_remote(|doc| doc.apply_encoded_operations(buffer))
+    }
+
+    // Each call returns a new receiver, which is dropped from the subscribers once it
+    // goes out of scope
+    pub fn subscribe(&self) -> Receiver<DocChange> {
+        let (sender, receiver) = channel();
+        self.subscribe_with(Box::new(move |change| sender.send(change.clone()).is_ok()));
+        receiver
+    }
+
+    pub(crate) fn subscribe_with(&self, subscriber: Subscriber<DocChange>) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(subscriber);
+    }
+
+    #[cfg(all(test, feature = "net"))]
+    pub(crate) fn subscribers_len(&self) -> usize {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    // Merges stop at the first operation that can't be applied, keeping the previous ones,
+    // so subscribers are notified whenever the document changed, like in `write`
+    fn apply_remote(
+        &self,
+        action: impl FnOnce(&mut Doc) -> Result<MergeReport, DocError>,
+    ) -> Result<MergeReport, DocError> {
+        let (result, change) = {
+            let mut doc = self
+                .inner
+                .doc
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let previous_version = doc.version().ok();
+
+            let result = action(&mut doc);
+
+            let version = doc.version().ok();
+            let change = version
+                .filter(|version| previous_version.as_ref() != Some(version))
+                .map(|version| DocChange {
+                    origin: ChangeOrigin::Remote,
+                    version,
+                });
+            (result, change)
+        };
+
+        if let Some(change) = change {
+            self.notify(change);
+        }
+        result
+    }
+
+    fn notify(&self, change: DocChange) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|subscriber| subscriber(&change));
+    }
+}
+
+impl From<Doc> for SharedDoc {
+    fn from(doc: Doc) -> Self {
+        Self::new(doc)
+    }
+}
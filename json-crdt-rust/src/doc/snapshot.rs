@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use crate::{
+    serde::{Compression, Serializable},
+    DataMap, FormattableId, FormattedId, ObjRef, Selector, TextRef, Value,
+};
+
+use super::{doc::DocError, full::FullDocSnapshot, lazy::LazyDoc, traits::ReadableDoc};
+
+// Frozen, read-only copy of a document. It shares the objects of the document, which are
+// only copied when the document modifies them, so it can be read or serialized (even on
+// another thread) while the original document keeps being edited. Clones are cheap.
+#[derive(Clone)]
+pub struct DocSnapshot {
+    pub(crate) handle: Arc<SnapshotHandle>,
+}
+
+pub(crate) enum SnapshotHandle {
+    Lazy(Box<LazyDoc>),
+    Full(Box<FullDocSnapshot>),
+}
+
+impl DocSnapshot {
+    pub fn serialize(&self) -> Result<Vec<u8>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => Ok(doc.serialize()?),
+            SnapshotHandle::Full(snapshot) => Ok(snapshot.serialize()?),
+        }
+    }
+
+    // Same as `serialize`, but compressing the regions of the buffer with LZ4
+    pub fn serialize_compressed(&self) -> Result<Vec<u8>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => Ok(doc.serialize_with_compression(Compression::Lz4)?),
+            SnapshotHandle::Full(snapshot) => {
+                Ok(snapshot.serialize_with_compression(Compression::Lz4)?)
+            }
+        }
+    }
+}
+
+// Serialization of a snapshot running on its own thread, see `Doc::serialize_async`
+#[cfg(feature = "threads")]
+pub struct SerializationHandle {
+    thread: std::thread::JoinHandle<Result<Vec<u8>, DocError>>,
+}
+
+#[cfg(feature = "threads")]
+impl SerializationHandle {
+    pub(crate) fn spawn(snapshot: DocSnapshot) -> Self {
+        Self {
+            thread: std::thread::spawn(move || snapshot.serialize()),
+        }
+    }
+
+    // Lets callers poll for the result instead of blocking on `join`
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    // Blocks until the serialization is done, propagating the panics of the thread
+    pub fn join(self) -> Result<Vec<u8>, DocError> {
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl ReadableDoc for DocSnapshot {
+    fn get<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Option<&Value>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.get(object, selector),
+            SnapshotHandle::Full(doc) => doc.get(object, selector),
+        }
+    }
+
+    fn text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<TextRef<'_>>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.text(object),
+            SnapshotHandle::Full(doc) => doc.text(object),
+        }
+    }
+
+    fn as_map<'a>(&'a self) -> Result<DataMap<'a>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.as_map(),
+            SnapshotHandle::Full(doc) => doc.as_map(),
+        }
+    }
+
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.keys(object),
+            SnapshotHandle::Full(doc) => doc.keys(object),
+        }
+    }
+
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.len(object),
+            SnapshotHandle::Full(doc) => doc.len(object),
+        }
+    }
+
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.conflict_count(object, selector),
+            SnapshotHandle::Full(doc) => doc.conflict_count(object, selector),
+        }
+    }
+
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_> {
+        match self.handle.as_ref() {
+            SnapshotHandle::Lazy(doc) => doc.format_id(id),
+            SnapshotHandle::Full(doc) => doc.format_id(id),
+        }
+    }
+}
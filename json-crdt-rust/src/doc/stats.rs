@@ -0,0 +1,34 @@
+use std::collections::BTreeMap;
+
+// Size of a document and of its history, to monitor it and decide when to compact it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DocStats {
+    pub operations: usize,
+    // Operations by the name of their action, e.g. "InsertText"
+    pub operations_by_action: BTreeMap<&'static str, usize>,
+    // Operations waiting for a missing parent, see `Doc::missing_dependencies`
+    pub orphans: usize,
+
+    pub maps: usize,
+    pub texts: usize,
+    // Values written to map keys, including the overwritten and deleted ones
+    pub map_values: usize,
+    pub deleted_map_values: usize,
+    pub text_blocks: usize,
+    // Deleted text blocks, which are kept to order concurrent insertions
+    pub text_tombstones: usize,
+    // Bytes of deleted text. They are still in memory unless tombstones are dropped.
+    pub tombstone_len: usize,
+    pub tree_nodes: usize,
+
+    // Size in bytes of each region of the serialized document, before compression
+    pub client_registry_size: usize,
+    pub operation_log_size: usize,
+    pub view_cache_size: usize,
+}
+
+impl DocStats {
+    pub fn serialized_size(&self) -> usize {
+        self.client_registry_size + self.operation_log_size + self.view_cache_size
+    }
+}
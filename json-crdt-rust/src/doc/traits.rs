@@ -1,15 +1,188 @@
-use crate::{transaction::Transaction, DataMap, Doc, ObjRef, Selector, Value};
+use std::fmt::Write;
+
+use crate::{
+    transaction::Transaction, view::ViewError, DataMap, Doc, FormattableId, FormattedId, ObjRef,
+    Path, PathError, Selector, TextRef, Value, ValueKind, ValueRef,
+};
 
 use super::doc::DocError;
 
+// Entries of a map along with their values, see `ReadableDoc::iter_sorted`
+pub type MapEntries<'a> = Box<dyn Iterator<Item = (&'a Selector, &'a Value)> + 'a>;
+
 pub trait ReadableDoc {
     fn get<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
         &self,
         object: TRef,
         selector: TSelector,
     ) -> Result<Option<&Value>, DocError>;
-    fn get_text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<String>, DocError>;
+    fn get_ref<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Option<ValueRef<'_>>, DocError> {
+        Ok(self.get(object, selector)?.map(ValueRef::from))
+    }
+    // Follows the path from the root, returning `None` if any of its keys is missing
+    fn get_path(&self, path: &Path) -> Result<Option<&Value>, DocError> {
+        let (last, parents) = path.split_last().ok_or(PathError::Empty)?;
+
+        let mut object = ObjRef::Root;
+        for selector in parents {
+            object = match self.get(object, selector)? {
+                Some(Value::Object(child)) => *child,
+                Some(value) => {
+                    return Err(ViewError::IncompatibleValue {
+                        object,
+                        selector: selector.clone(),
+                        expected: ValueKind::Object,
+                        actual: value.into(),
+                    }
+                    .into())
+                }
+                None => return Ok(None),
+            };
+        }
+
+        self.get(object, last)
+    }
+    // The map stored at the key, or `None` if the key is missing. Fails if the key holds a
+    // scalar or a text, like `Transaction::get_text` does.
+    fn get_map<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Option<ObjRef>, DocError> {
+        let Some(child) = get_object_ref(self, object, selector)? else {
+            return Ok(None);
+        };
+        // Fails with `IncompatibleTypes` if the object is a text
+        self.keys(child)?;
+        Ok(Some(child))
+    }
+    fn get_text_ref<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<Option<ObjRef>, DocError> {
+        let Some(child) = get_object_ref(self, object, selector)? else {
+            return Ok(None);
+        };
+        // Fails with `IncompatibleTypes` if the object is a map
+        self.text(child)?;
+        Ok(Some(child))
+    }
+    fn text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<TextRef<'_>>, DocError>;
+    fn get_text<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<String>, DocError> {
+        Ok(self.text(object)?.map(|text| text.to_string()))
+    }
+    // Reads `len` bytes of the text starting from `start`, without walking the rest of it.
+    // The range is clamped to the length of the text, but it must fall on char boundaries.
+    fn get_text_range<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+        start: usize,
+        len: usize,
+    ) -> Result<Option<String>, DocError> {
+        let object: ObjRef = object.into();
+        let Some(text) = self.text(object)? else {
+            return Ok(None);
+        };
+
+        let end = start.saturating_add(len).min(text.len());
+        let start = start.min(end);
+        if !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            return Err(DocError::InvalidRange {
+                object,
+                range: start..end,
+            });
+        }
+
+        Ok(Some(text.chunks_in_range(start..end).collect()))
+    }
+    fn text_chunks<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &str> + '_>>, DocError> {
+        Ok(self.text(object)?.map(|text| text.chunks()))
+    }
+    // Streams the text into the writer, returning false if the text doesn't exist
+    fn write_text_to<TRef: Into<ObjRef>, W: Write>(
+        &self,
+        object: TRef,
+        writer: &mut W,
+    ) -> Result<bool, DocError> {
+        match self.text(object)? {
+            Some(text) => {
+                write!(writer, "{}", text)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
     fn as_map<'a>(&'a self) -> Result<DataMap<'a>, DocError>;
+    // Keys of a map that currently have a value, in no particular order, see `iter_sorted`.
+    // Returns `None` if the map doesn't exist.
+    fn keys<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<Box<dyn Iterator<Item = &Selector> + '_>>, DocError>;
+    // Entries of a map sorted by selector, so that every replica lists them in the same order.
+    // Returns `None` if the map doesn't exist.
+    fn iter_sorted<TRef: Into<ObjRef>>(
+        &self,
+        object: TRef,
+    ) -> Result<Option<MapEntries<'_>>, DocError> {
+        let object: ObjRef = object.into();
+        let Some(keys) = self.keys(object)? else {
+            return Ok(None);
+        };
+
+        let mut entries = Vec::new();
+        for key in keys {
+            if let Some(value) = self.get(object, key)? {
+                entries.push((key, value));
+            }
+        }
+        entries.sort_by_key(|(key, _)| *key);
+
+        Ok(Some(Box::new(entries.into_iter())))
+    }
+    // Number of keys of a map that currently have a value
+    fn len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError>;
+    // Length in bytes, without building the text
+    fn text_len<TRef: Into<ObjRef>>(&self, object: TRef) -> Result<Option<usize>, DocError> {
+        Ok(self.text(object)?.map(|text| text.len()))
+    }
+    // Number of concurrent writes of the key that haven't been resolved by a later write yet,
+    // or 0 if its value is not conflicting. Unlike `Doc::conflicts`, lazy documents support it.
+    fn conflict_count<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &self,
+        object: TRef,
+        selector: TSelector,
+    ) -> Result<usize, DocError>;
+    // Displays the id with the global id of its client, e.g. "alice@42"
+    fn format_id<Id: FormattableId>(&self, id: &Id) -> FormattedId<'_>;
+}
+
+fn get_object_ref<D: ReadableDoc + ?Sized, TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+    doc: &D,
+    object: TRef,
+    selector: TSelector,
+) -> Result<Option<ObjRef>, DocError> {
+    let object: ObjRef = object.into();
+    let selector: Selector = selector.into();
+    match doc.get(object, selector.clone())? {
+        Some(Value::Object(child)) => Ok(Some(*child)),
+        Some(value) => Err(ViewError::IncompatibleValue {
+            object,
+            selector,
+            expected: ValueKind::Object,
+            actual: value.into(),
+        }
+        .into()),
+        None => Ok(None),
+    }
 }
 
 pub trait WritableDoc {
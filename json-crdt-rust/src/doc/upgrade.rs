@@ -0,0 +1,40 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::doc::{Doc, DocError};
+
+// Builds the full document of a lazy one, a step at a time, so that the caller can yield
+// between steps. It can be driven manually with `step`, or awaited: the future performs one
+// step per poll and wakes itself up, so other tasks of the executor run in between.
+pub struct DocUpgrade<'a> {
+    doc: &'a mut Doc,
+}
+
+impl<'a> DocUpgrade<'a> {
+    pub(crate) fn new(doc: &'a mut Doc) -> Self {
+        Self { doc }
+    }
+
+    // Returns true once the document is fully built
+    pub fn step(&mut self) -> Result<bool, DocError> {
+        self.doc.initialize_step(1)
+    }
+}
+
+impl Future for DocUpgrade<'_> {
+    type Output = Result<(), DocError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.step() {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes_varint::{VarIntSupport, VarIntSupportMut};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    serde::{read_string, SerializationError},
+    GlobalClientId, SequenceIndex,
+};
+
+// Latest sequence of each client that is part of a document.
+// Global IDs are used, as local ones are only meaningful inside a single document.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DocVersion {
+    sequences: FxHashMap<GlobalClientId, SequenceIndex>,
+}
+
+impl DocVersion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Sequences start at 1, so 0 means that no operation of the client is included
+    pub fn get(&self, client: &GlobalClientId) -> SequenceIndex {
+        self.sequences.get(client).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, client: GlobalClientId, sequence: SequenceIndex) {
+        self.sequences.insert(client, sequence);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&GlobalClientId, SequenceIndex)> {
+        self.sequences
+            .iter()
+            .map(|(client, sequence)| (client, *sequence))
+    }
+
+    // True if every operation included in `other` is also included in this version
+    pub fn includes(&self, other: &DocVersion) -> bool {
+        other
+            .iter()
+            .all(|(client, sequence)| self.get(client) >= sequence)
+    }
+
+    // Keeps the latest sequence of each client, so that the result includes both versions
+    pub fn merge(&mut self, other: &DocVersion) {
+        for (client, sequence) in other.iter() {
+            if sequence > self.get(client) {
+                self.set(client.clone(), sequence);
+            }
+        }
+    }
+
+    // Compact binary form, to be exchanged with peers, e.g. before `encode_new_operations_since`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_varint(self.sequences.len() as u32);
+        for (client, sequence) in self.iter() {
+            buffer.put_u32_varint(client.len() as u32);
+            buffer.put_slice(client.as_bytes());
+            buffer.put_u32_varint(sequence);
+        }
+        buffer.to_vec()
+    }
+
+    pub fn decode(mut buffer: Bytes) -> Result<Self, SerializationError> {
+        let malformed = |_| SerializationError::Malformed("unable to read version".to_string());
+
+        let mut version = Self::new();
+        let count = buffer.get_u32_varint().map_err(malformed)?;
+        for _ in 0..count {
+            let len = buffer.get_u32_varint().map_err(malformed)?;
+            let client = read_string(&mut buffer, len, "version client")?;
+            let sequence = buffer.get_u32_varint().map_err(malformed)?;
+            version.set(client, sequence);
+        }
+
+        if buffer.has_remaining() {
+            return Err(SerializationError::Malformed(
+                "unexpected bytes after the version".to_string(),
+            ));
+        }
+        Ok(version)
+    }
+}
@@ -1,11 +1,34 @@
+mod automerge;
 mod client_registry;
 mod crdt;
+#[cfg(feature = "debug-tools")]
+mod debug;
 mod doc;
+#[cfg(feature = "net")]
+mod net;
 mod operation_log;
+mod path;
 mod serde;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod simulation;
+mod storage;
 mod transaction;
 mod types;
 mod view;
 
+pub use automerge::AutomergeError;
+pub use crdt::map::map::MapError;
+pub use crdt::shared::tree::SequenceError;
+#[cfg(feature = "debug-tools")]
+pub use debug::GraphFormat;
 pub use doc::*;
+#[cfg(feature = "net")]
+pub use net::{serve, serve_with_awareness, NetError, SyncClient};
+pub use path::*;
+pub use serde::{Compression, SerializationError, FORMAT_VERSION};
+pub use simulation::*;
+pub use storage::*;
+pub use transaction::{CommitResult, TransactionError};
 pub use types::*;
+pub use view::ViewError;
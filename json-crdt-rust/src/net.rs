@@ -0,0 +1,436 @@
+// Keeps shared documents in sync over WebSockets. Every connection runs the same protocol on
+// both ends: each peer starts by sending its version, and answers the version of the other
+// peer with the operations it's missing. Afterwards, each change of the document is sent as
+// the operations that the other peer hasn't seen yet, along with the version of the sender.
+//
+// The server (see `serve`) keeps its own replica, so clients connecting later are backfilled
+// from it, and relays the operations of each client to the other ones.
+//
+// When an `Awareness` is given, the states of the peers are exchanged the same way, starting
+// with every known state and then sending the ones that change.
+
+use std::{
+    future::pending,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    accept_async, connect_async,
+    tungstenite::{self, Message},
+    WebSocketStream,
+};
+
+use crate::{
+    doc::Subscriber,
+    serde::{read_bytes, read_u8, SerializationError},
+    Awareness, DocError, DocVersion, SharedDoc,
+};
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// How often the local awareness state is renewed and the silent peers are expired
+const AWARENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum NetError {
+    #[error("websocket error: {0}")]
+    WebSocketError(#[from] tungstenite::Error),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("document error: {0}")]
+    DocError(#[from] DocError),
+
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] SerializationError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SyncMessage {
+    // Sent once, when the connection is established
+    Version(DocVersion),
+    // Operations of the sender, along with its version once they are included
+    Update {
+        version: DocVersion,
+        operations: Bytes,
+    },
+    // Encoded with `Awareness::encode_update`
+    Awareness(Bytes),
+}
+
+const VERSION_MESSAGE: u8 = 0;
+const UPDATE_MESSAGE: u8 = 1;
+const AWARENESS_MESSAGE: u8 = 2;
+
+impl SyncMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        match self {
+            Self::Version(version) => {
+                buffer.put_u8(VERSION_MESSAGE);
+                buffer.put_slice(&version.encode());
+            }
+            Self::Update {
+                version,
+                operations,
+            } => {
+                let version = version.encode();
+                buffer.put_u8(UPDATE_MESSAGE);
+                buffer.put_u32(version.len() as u32);
+                buffer.put_slice(&version);
+                buffer.put_slice(operations);
+            }
+            Self::Awareness(update) => {
+                buffer.put_u8(AWARENESS_MESSAGE);
+                buffer.put_slice(update);
+            }
+        }
+        buffer.to_vec()
+    }
+
+    fn decode(mut buffer: Bytes) -> Result<Self, SerializationError> {
+        match read_u8(&mut buffer, "message type")? {
+            VERSION_MESSAGE => Ok(Self::Version(DocVersion::decode(buffer)?)),
+            UPDATE_MESSAGE => {
+                if buffer.remaining() < 4 {
+                    return Err(SerializationError::Malformed(
+                        "unable to read version length".to_string(),
+                    ));
+                }
+                let len = buffer.get_u32();
+                let version = DocVersion::decode(read_bytes(&mut buffer, len, "version")?)?;
+                Ok(Self::Update {
+                    version,
+                    operations: buffer,
+                })
+            }
+            AWARENESS_MESSAGE => Ok(Self::Awareness(buffer)),
+            message_type => Err(SerializationError::Malformed(format!(
+                "unknown message type {}",
+                message_type
+            ))),
+        }
+    }
+}
+
+// Syncs the document with a server, reconnecting (and backfilling the operations exchanged
+// in the meantime) whenever the connection drops. Syncing stops when the client is dropped.
+pub struct SyncClient {
+    task: JoinHandle<()>,
+    connected: Arc<AtomicBool>,
+}
+
+impl SyncClient {
+    // Must be called from a tokio runtime. The first connection is established before
+    // returning, so that an invalid url or an unreachable server is reported.
+    pub async fn connect(url: &str, doc: SharedDoc) -> Result<Self, NetError> {
+        Self::start(url, doc, None).await
+    }
+
+    pub async fn connect_with_awareness(
+        url: &str,
+        doc: SharedDoc,
+        awareness: Awareness,
+    ) -> Result<Self, NetError> {
+        Self::start(url, doc, Some(awareness)).await
+    }
+
+    async fn start(
+        url: &str,
+        doc: SharedDoc,
+        awareness: Option<Awareness>,
+    ) -> Result<Self, NetError> {
+        let (stream, _) = connect_async(url).await?;
+        let connected = Arc::new(AtomicBool::new(true));
+
+        let url = url.to_string();
+        let task = tokio::spawn({
+            let connected = connected.clone();
+            async move {
+                let mut stream = Some(stream);
+                let mut delay = MIN_RECONNECT_DELAY;
+                loop {
+                    if let Some(stream) = stream.take() {
+                        connected.store(true, Ordering::Relaxed);
+                        delay = MIN_RECONNECT_DELAY;
+                        // The connection is dropped on errors, the server backfills us
+                        // on the next one anyway
+                        let _ = sync(stream, &doc, awareness.as_ref()).await;
+                        connected.store(false, Ordering::Relaxed);
+                    }
+
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    stream = connect_async(url.as_str())
+                        .await
+                        .ok()
+                        .map(|(stream, _)| stream);
+                }
+            }
+        });
+
+        Ok(Self { task, connected })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SyncClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Accepts connections until the listener fails, syncing each client with the document
+pub async fn serve(listener: TcpListener, doc: SharedDoc) -> Result<(), NetError> {
+    serve_connections(listener, doc, None).await
+}
+
+// Also relays the awareness states of the clients, which are kept in the given awareness
+pub async fn serve_with_awareness(
+    listener: TcpListener,
+    doc: SharedDoc,
+    awareness: Awareness,
+) -> Result<(), NetError> {
+    serve_connections(listener, doc, Some(awareness)).await
+}
+
+async fn serve_connections(
+    listener: TcpListener,
+    doc: SharedDoc,
+    awareness: Option<Awareness>,
+) -> Result<(), NetError> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let doc = doc.clone();
+        let awareness = awareness.clone();
+        tokio::spawn(async move {
+            if let Ok(stream) = accept_async(stream).await {
+                let _ = sync(stream, &doc, awareness.as_ref()).await;
+            }
+        });
+    }
+}
+
+// Runs the protocol until the connection is closed
+async fn sync<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: WebSocketStream<S>,
+    doc: &SharedDoc,
+    awareness: Option<&Awareness>,
+) -> Result<(), NetError> {
+    let mut changes = forward(|subscriber| doc.subscribe_with(subscriber));
+    let mut awareness_changes =
+        awareness.map(|awareness| forward(|subscriber| awareness.subscribe_with(subscriber)));
+    let mut awareness_check = tokio::time::interval(AWARENESS_CHECK_INTERVAL);
+    // Operations known by the other peer, `None` until it sends its version
+    let mut peer_version: Option<DocVersion> = None;
+
+    let version = doc.read(|doc| doc.version())?;
+    send(&mut stream, SyncMessage::Version(version)).await?;
+    if let Some(awareness) = awareness {
+        let update = awareness.encode_update(None);
+        send(&mut stream, SyncMessage::Awareness(update.into())).await?;
+    }
+
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let buffer = match message {
+                    Some(Ok(Message::Binary(buffer))) => buffer,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(error)) => return Err(error.into()),
+                };
+
+                match SyncMessage::decode(buffer.into())? {
+                    SyncMessage::Version(version) => {
+                        peer_version = Some(version);
+                    }
+                    SyncMessage::Update { version, operations } => {
+                        doc.apply_encoded_operations(operations)?;
+                        // The sender had every operation it sent, so now we do too
+                        if let Some(peer_version) = peer_version.as_mut() {
+                            peer_version.merge(&version);
+                        }
+                    }
+                    SyncMessage::Awareness(update) => {
+                        if let Some(awareness) = awareness {
+                            awareness.apply_update(update)?;
+                        }
+                    }
+                }
+            }
+            change = changes.recv() => {
+                if change.is_none() {
+                    return Ok(());
+                }
+            }
+            change = async {
+                match awareness_changes.as_mut() {
+                    Some(changes) => changes.recv().await,
+                    None => pending().await,
+                }
+            } => {
+                let (Some(change), Some(awareness)) = (change, awareness) else {
+                    return Ok(());
+                };
+                // Expired peers are removed by each peer on its own
+                if change.origin.is_some() {
+                    let update = awareness.encode_update(Some(&change.clients()));
+                    send(&mut stream, SyncMessage::Awareness(update.into())).await?;
+                }
+            }
+            _ = awareness_check.tick() => {
+                if let Some(awareness) = awareness {
+                    if awareness.needs_renewal() {
+                        awareness.renew_local_state();
+                    }
+                    awareness.remove_expired();
+                }
+            }
+        }
+
+        if let Some(peer_version) = peer_version.as_mut() {
+            if let Some(update) = update_since(doc, peer_version)? {
+                send(&mut stream, update).await?;
+            }
+        }
+    }
+}
+
+// Operations that the other peer is missing, if any. The peer version is updated as if they
+// were received already.
+fn update_since(
+    doc: &SharedDoc,
+    peer_version: &mut DocVersion,
+) -> Result<Option<SyncMessage>, DocError> {
+    doc.read(|doc| {
+        let version = doc.version()?;
+        if peer_version.includes(&version) {
+            return Ok(None);
+        }
+
+        let operations = doc.encode_new_operations_since(peer_version)?;
+        peer_version.merge(&version);
+        Ok(Some(SyncMessage::Update {
+            version,
+            operations: operations.into(),
+        }))
+    })
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut WebSocketStream<S>,
+    message: SyncMessage,
+) -> Result<(), NetError> {
+    stream.send(Message::Binary(message.encode())).await?;
+    Ok(())
+}
+
+// Changes are sent to an async channel as they are notified, without waiting on the
+// blocking receivers of `subscribe`. The subscriber is dropped on the first change after
+// the receiver is.
+fn forward<T: Clone + Send + 'static>(
+    subscribe: impl FnOnce(Subscriber<T>),
+) -> UnboundedReceiver<T> {
+    let (sender, receiver) = unbounded_channel();
+    subscribe(Box::new(move |change: &T| {
+        sender.send(change.clone()).is_ok()
+    }));
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Doc, ObjRef, ReadableDoc, ScalarValue, Value};
+
+    #[test]
+    fn test_messages_round_trip() {
+        let mut version = DocVersion::new();
+        version.set("alice".to_string(), 3);
+        version.set("bob".to_string(), 12);
+
+        let messages = [
+            SyncMessage::Version(version.clone()),
+            SyncMessage::Update {
+                version,
+                operations: Bytes::from_static(b"operations"),
+            },
+            SyncMessage::Awareness(Bytes::from_static(b"states")),
+        ];
+        for message in messages {
+            assert_eq!(
+                SyncMessage::decode(message.encode().into()).unwrap(),
+                message
+            );
+        }
+
+        assert!(SyncMessage::decode(Bytes::from_static(&[7])).is_err());
+        assert!(SyncMessage::decode(Bytes::from_static(&[UPDATE_MESSAGE, 0, 0, 0, 9])).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connections_leave_no_subscribers_or_tasks_behind() {
+        let alive_tasks = || {
+            tokio::runtime::Handle::current()
+                .metrics()
+                .num_alive_tasks()
+        };
+        async fn wait_until(condition: impl Fn() -> bool) {
+            for _ in 0..500 {
+                if condition() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("condition was not met");
+        }
+
+        let server = SharedDoc::new(Doc::new("server".to_string()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, server.clone()));
+
+        let client = SharedDoc::new(Doc::new("client".to_string()));
+        for i in 0..5 {
+            let sync_client = SyncClient::connect(&url, client.clone()).await.unwrap();
+            client
+                .write(|txn| txn.set_scalar(ObjRef::Root, "key", i))
+                .unwrap();
+            let value = Value::Scalar(ScalarValue::Int(i));
+            wait_until(|| server.read(|doc| doc.get(ObjRef::Root, "key").unwrap() == Some(&value)))
+                .await;
+            // The subscriber of the previous connection was dropped with the change
+            assert_eq!(client.subscribers_len(), 1);
+
+            // Only the task of the server is left, once it notices that the connection is closed
+            drop(sync_client);
+            wait_until(|| alive_tasks() == 1).await;
+        }
+
+        // The subscribers of the last connection are dropped with the next change
+        for doc in [&server, &client] {
+            doc.write(|txn| txn.set_scalar(ObjRef::Root, "last", 1))
+                .unwrap();
+            assert_eq!(doc.subscribers_len(), 0);
+        }
+    }
+}
@@ -1,27 +1,45 @@
-use std::{cmp::Ordering, collections::VecDeque};
+use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
 
 use bytes::Bytes;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use thiserror::Error;
 
 use crate::{
     client_registry::{ClientRemappable, ClientRemappings},
-    operation_log::serde::deserialize_operations,
+    operation_log::serde::{deserialize_operations, validate_operations, OperationDecoder},
     serde::{Serializable, SerializationError},
-    ClientId, Operation, OperationAction, OperationId, SequenceIndex, Timestamp,
+    types::ROOT_SEQUENCE,
+    ClientId, Clock, MapBlockId, ObjRef, Operation, OperationAction, OperationId, ScalarValue,
+    Selector, SequenceBlockId, SequenceIndex, SetMapValueAction, SystemClock, Timestamp,
+    TimestampSource, Value,
 };
 
 use super::{serde::serialize_operations, shared::OperationIndex};
 
+#[cfg(feature = "debug-tools")]
+use crate::debug::{DebugGraph, FormatId};
+
 #[derive(Clone)]
 pub struct OperationLog {
     local_client: ClientId,
-    operations: Vec<Operation>,
+    // Shared with snapshots, and only copied when modified (see `remap_client_ids`)
+    operations: Vec<Arc<Operation>>,
     client_sequences: FxHashMap<ClientId, SequenceIndex>,
     id_to_index: FxHashMap<OperationId, OperationIndex>,
     roots: Vec<OperationIndex>,
     last: Option<OperationIndex>,
-    orphans: FxHashMap<OperationId, Operation>,
+    // Operations that are not the parent of any other one
+    heads: FxHashSet<OperationId>,
+    // Operations received before their parent, by the id of the missing parent
+    orphans: FxHashMap<OperationId, Vec<Operation>>,
+    orphan_ids: FxHashSet<OperationId>,
+    max_orphans: Option<usize>,
+    // Incremented every time an operation is stored, orphans included
+    version: u64,
+    clock: Arc<dyn Clock>,
+    timestamp_source: TimestampSource,
+    // Highest timestamp seen so far, orphans included, which logical clocks advance from
+    max_timestamp: Timestamp,
 }
 
 impl OperationLog {
@@ -33,17 +51,63 @@ impl OperationLog {
             id_to_index: FxHashMap::default(),
             roots: Vec::new(),
             last: None,
+            heads: FxHashSet::default(),
             orphans: FxHashMap::default(),
+            orphan_ids: FxHashSet::default(),
+            max_orphans: None,
+            version: 0,
+            clock: Arc::new(SystemClock),
+            timestamp_source: TimestampSource::default(),
+            max_timestamp: 0,
         }
     }
 
     pub fn from_buffer(
         local_client: ClientId,
         remappings: Option<ClientRemappings>,
+        clients_len: usize,
         buffer: &mut Bytes,
     ) -> Result<Self, OperationLogError> {
-        let mut operations = deserialize_operations(buffer)?;
+        let operations = Self::decode(buffer, clients_len)?;
+        Self::from_operations(local_client, remappings, operations)
+    }
+
+    // Client IDs are positions in the list of `clients_len` clients serialized along with
+    // the operations
+    pub fn decode(
+        buffer: &mut Bytes,
+        clients_len: usize,
+    ) -> Result<Vec<Operation>, OperationLogError> {
+        Ok(deserialize_operations(buffer, clients_len)?)
+    }
 
+    // Like `decode`, but in steps, see `OperationDecoder`
+    pub fn decoder(
+        buffer: Bytes,
+        clients_len: usize,
+    ) -> Result<OperationDecoder, OperationLogError> {
+        Ok(OperationDecoder::new(buffer, clients_len)?)
+    }
+
+    // Checks the encoded operations without decoding them, returning how many there are
+    pub fn validate_encoded(
+        buffer: &mut Bytes,
+        clients_len: usize,
+    ) -> Result<usize, OperationLogError> {
+        Ok(validate_operations(buffer, clients_len)?)
+    }
+
+    pub fn encode<'a>(
+        operations: impl Iterator<Item = &'a Operation>,
+    ) -> Result<Vec<u8>, SerializationError> {
+        serialize_operations(operations)
+    }
+
+    pub fn from_operations(
+        local_client: ClientId,
+        remappings: Option<ClientRemappings>,
+        mut operations: Vec<Operation>,
+    ) -> Result<Self, OperationLogError> {
         if let Some(remappings) = remappings {
             for operation in operations.iter_mut() {
                 operation.remap_client_ids(&remappings);
@@ -63,6 +127,74 @@ impl OperationLog {
         Ok(operation_log)
     }
 
+    // Operations whose parent is missing are kept until it arrives, up to `max_orphans` of
+    // them. Past the limit, new ones are rejected with `TooManyOrphans`.
+    pub fn set_max_orphans(&mut self, max_orphans: Option<usize>) {
+        self.max_orphans = max_orphans;
+    }
+
+    pub fn orphans_len(&self) -> usize {
+        self.orphan_ids.len()
+    }
+
+    // Number of applied operations of each kind of action, see `OperationAction::name`
+    pub fn operations_by_action(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for operation in self.iter() {
+            *counts.entry(operation.action.name()).or_default() += 1;
+        }
+        counts
+    }
+
+    // Parents that the orphans are waiting for, sorted by id. Parents that are orphans
+    // themselves are not included, as only their own missing parent is needed.
+    pub fn missing_dependencies(&self) -> Vec<OperationId> {
+        let mut missing: Vec<OperationId> = self
+            .orphans
+            .keys()
+            .filter(|parent| !self.orphan_ids.contains(parent))
+            .copied()
+            .collect();
+        missing.sort_by_key(|id| (id.client_id, id.sequence));
+        missing
+    }
+
+    // One node per operation, linked to its parent. Orphans link to the missing parent.
+    #[cfg(feature = "debug-tools")]
+    pub fn render_debug_graph(&self, graph: &mut DebugGraph, format_id: FormatId) {
+        let node_id = |id: &OperationId| format!("op_{}_{}", id.client_id, id.sequence);
+
+        let mut orphans: Vec<&Operation> = self.orphans.values().flatten().collect();
+        orphans.sort_by_key(|operation| (operation.id.client_id, operation.id.sequence));
+        let operations = self.iter().map(|operation| (operation, false));
+        let orphans = orphans.into_iter().map(|operation| (operation, true));
+
+        for (operation, orphan) in operations.chain(orphans) {
+            let mut label = format!("{} {}", format_id(&operation.id), operation.action.name());
+            if orphan {
+                label.push_str(" (orphan)");
+            }
+            graph.node(node_id(&operation.id), label);
+
+            if let Some(parent) = &operation.parent {
+                graph.edge(node_id(parent), node_id(&operation.id));
+            }
+        }
+    }
+
+    pub fn set_timestamp_source(&mut self, clock: Arc<dyn Clock>, source: TimestampSource) {
+        self.clock = clock;
+        self.timestamp_source = source;
+    }
+
+    pub fn next_timestamp(&self) -> Timestamp {
+        match self.timestamp_source {
+            TimestampSource::WallClock => self.clock.now(),
+            TimestampSource::Lamport => self.max_timestamp + 1,
+            TimestampSource::HybridLogical => self.clock.now().max(self.max_timestamp + 1),
+        }
+    }
+
     pub fn apply_local_action(
         &mut self,
         action: OperationAction,
@@ -85,53 +217,434 @@ impl OperationLog {
     pub fn apply_operation(&mut self, op: Operation) -> Result<Vec<&Operation>, OperationLogError> {
         let mut applied_operations = Vec::new();
 
-        let mut operation_id = op.id.clone();
+        let mut to_resolve = vec![op.id];
 
         if let Some(applied_operation) = self.insert_operation(op)? {
             applied_operations.push(applied_operation);
         }
 
-        // Process any orphans
-        loop {
-            let orphan = match self.orphans.remove(&operation_id) {
-                Some(orphan) => orphan,
-                None => break,
+        // Process any orphans, including the ones that were waiting for a resolved orphan
+        while let Some(operation_id) = to_resolve.pop() {
+            let orphans = match self.orphans.remove(&operation_id) {
+                Some(orphans) => orphans,
+                None => continue,
             };
 
-            operation_id = orphan.id;
+            for orphan in orphans {
+                self.orphan_ids.remove(&orphan.id);
+                to_resolve.push(orphan.id);
 
-            if let Some(applied_operation) = self.insert_operation(orphan)? {
-                applied_operations.push(applied_operation);
+                if let Some(applied_operation) = self.insert_operation(orphan)? {
+                    applied_operations.push(applied_operation);
+                }
             }
         }
 
         Ok(applied_operations
             .iter()
-            .map(|index| &self.operations[*index])
+            .map(|index| self.operations[*index].as_ref())
             .collect())
     }
 
+    // Operations that are not the parent of any other one, in no particular order, see
+    // `ClientRegistry::sort_by_global_id`
+    pub fn heads(&self) -> impl Iterator<Item = OperationId> + '_ {
+        self.heads.iter().copied()
+    }
+
+    // Orphans are not considered, as they haven't been applied yet
+    pub fn has_operation(&self, id: &OperationId) -> bool {
+        self.id_to_index.contains_key(id)
+    }
+
+    // Like `has_operation`, but also true for operations compacted into a later one
+    pub fn is_processed(&self, id: &OperationId) -> bool {
+        self.id_to_index.contains_key(id) || self.is_compacted(id)
+    }
+
+    pub fn get_operation(&self, id: &OperationId) -> Option<&Operation> {
+        self.id_to_index
+            .get(id)
+            .map(|index| self.operations[*index].as_ref())
+    }
+
+    // Checks the properties that every operation must have on its own, regardless of the
+    // content of the log. Operations received from other peers can't be trusted to have them.
+    pub fn check_operation(op: &Operation) -> Result<(), OperationLogError> {
+        let reason = if op.id.sequence == ROOT_SEQUENCE {
+            InvalidOperationReason::ReservedSequence
+        } else {
+            match op.parent {
+                Some(parent)
+                    if parent.client_id == op.id.client_id && parent.sequence >= op.id.sequence =>
+                {
+                    InvalidOperationReason::ParentNotBefore(parent)
+                }
+                _ => match &op.action {
+                    // Inserted blocks take sequences of the client that creates them, and
+                    // leave room for the id that would continue them
+                    OperationAction::InsertText(action)
+                        if action.id.client_id != op.id.client_id
+                            || SequenceIndex::try_from(action.value.len())
+                                .ok()
+                                .and_then(|len| action.id.sequence.checked_add(len))
+                                .is_none() =>
+                    {
+                        InvalidOperationReason::InvalidBlock(action.id.clone())
+                    }
+                    _ => return Ok(()),
+                },
+            }
+        };
+
+        Err(OperationLogError::InvalidOperation {
+            operation: op.id,
+            reason,
+        })
+    }
+
+    pub fn snapshot(&self) -> OperationLogSnapshot {
+        let orphans = self
+            .orphans
+            .values()
+            .flatten()
+            .map(|orphan| Arc::new(orphan.clone()));
+
+        OperationLogSnapshot {
+            operations: self.operations.iter().cloned().chain(orphans).collect(),
+        }
+    }
+
+    // Checks the invariants of the log, which could be broken by an inconsistent
+    // client remapping or by corrupted remote operations
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        match self.check_integrity().into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // Same as `validate`, limited to the operations from the `start` index onwards, e.g. the
+    // ones appended by a merge, so that checking them doesn't cost the whole history
+    pub fn validate_from(&self, start: usize) -> Result<(), ValidationError> {
+        for (index, operation) in self.iter_from(start).enumerate() {
+            let index = start + index;
+            if self.id_to_index.get(&operation.id) != Some(&index) {
+                return Err(ValidationError::DuplicateOperationId(operation.id));
+            }
+
+            if let Some(parent) = operation.parent {
+                match self.id_to_index.get(&parent) {
+                    Some(parent_index) if *parent_index < index => {}
+                    _ => {
+                        return Err(ValidationError::BrokenParentLink {
+                            operation: operation.id,
+                            parent,
+                        })
+                    }
+                }
+            }
+
+            let client_id = operation.id.client_id;
+            if self
+                .client_sequences
+                .get(&client_id)
+                .is_none_or(|sequence| *sequence < operation.id.sequence)
+            {
+                return Err(ValidationError::InconsistentClientSequence(client_id));
+            }
+
+            if let Some(orphans) = self.orphans.get(&operation.id) {
+                return Err(ValidationError::StaleOrphan(orphans[0].id));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Same checks as `validate`, but reports every broken invariant instead of the first one
+    pub fn check_integrity(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        if self.id_to_index.len() != self.operations.len() {
+            let mut seen = FxHashSet::default();
+            for operation in self.iter() {
+                if !seen.insert(operation.id) {
+                    errors.push(ValidationError::DuplicateOperationId(operation.id));
+                }
+            }
+        }
+
+        let mut max_sequences: FxHashMap<ClientId, SequenceIndex> = FxHashMap::default();
+        for (index, operation) in self.iter().enumerate() {
+            if self.id_to_index.get(&operation.id) != Some(&index) {
+                let error = ValidationError::DuplicateOperationId(operation.id);
+                if !errors.contains(&error) {
+                    errors.push(error);
+                }
+            }
+
+            if let Some(parent) = operation.parent {
+                match self.id_to_index.get(&parent) {
+                    Some(parent_index) if *parent_index < index => {}
+                    _ => errors.push(ValidationError::BrokenParentLink {
+                        operation: operation.id,
+                        parent,
+                    }),
+                }
+            }
+
+            let max_sequence = max_sequences.entry(operation.id.client_id).or_default();
+            *max_sequence = (*max_sequence).max(operation.id.sequence);
+        }
+
+        let mut clients: Vec<(ClientId, SequenceIndex)> = max_sequences.into_iter().collect();
+        clients.sort_unstable();
+        for (client_id, max_sequence) in clients {
+            if self.client_sequences.get(&client_id) != Some(&max_sequence) {
+                errors.push(ValidationError::InconsistentClientSequence(client_id));
+            }
+        }
+
+        for (parent, orphans) in &self.orphans {
+            for orphan in orphans {
+                if orphan.parent != Some(*parent) || self.id_to_index.contains_key(parent) {
+                    errors.push(ValidationError::StaleOrphan(orphan.id));
+                }
+            }
+        }
+
+        errors
+    }
+
+    // Latest sequence of each client, including the compacted ones
+    pub fn client_sequences(&self) -> &FxHashMap<ClientId, SequenceIndex> {
+        &self.client_sequences
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &Operation> {
-        self.operations.iter()
+        self.operations.iter().map(Arc::as_ref)
+    }
+
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = &Operation> {
+        self.operations[start.min(self.operations.len())..]
+            .iter()
+            .map(Arc::as_ref)
     }
 
     pub fn iter_sorted(&self) -> impl Iterator<Item = &Operation> {
         SortedOperationIterator::new(&self.roots, &self.operations, &self.id_to_index)
     }
 
+    // Merges runs of InsertText operations that a client typed one after the other,
+    // returning the number of operations that were removed.
+    // The merged operation keeps the id of the last one of the run, and the previous ids
+    // are still recognized, see `is_compacted`.
+    pub fn compact(&mut self) -> usize {
+        let mut children_count: FxHashMap<OperationId, usize> = FxHashMap::default();
+        let parents = self.iter().filter_map(|operation| operation.parent);
+        let orphan_parents = self
+            .orphans
+            .values()
+            .flatten()
+            .filter_map(|orphan| orphan.parent);
+        for parent in parents.chain(orphan_parents) {
+            *children_count.entry(parent).or_default() += 1;
+        }
+
+        let mut operations: Vec<Option<Operation>> = Vec::with_capacity(self.operations.len());
+        let mut new_indexes: FxHashMap<OperationId, usize> = FxHashMap::default();
+        let mut removed = 0;
+
+        for operation in self.iter() {
+            let mut operation = operation.clone();
+
+            let previous_index = operation
+                .parent
+                .filter(|parent| children_count.get(parent) == Some(&1))
+                .and_then(|parent| new_indexes.get(&parent).copied());
+            if let Some(previous_index) = previous_index {
+                let previous = operations[previous_index]
+                    .as_ref()
+                    .expect("parent should not be merged yet");
+
+                if let Some(action) = merge_insert_actions(previous, &operation) {
+                    let previous = operations[previous_index]
+                        .take()
+                        .expect("parent should not be merged yet");
+                    operation = Operation {
+                        id: operation.id,
+                        parent: previous.parent,
+                        action,
+                        timestamp: previous.timestamp,
+                    };
+                    removed += 1;
+                }
+            }
+
+            new_indexes.insert(operation.id, operations.len());
+            operations.push(Some(operation));
+        }
+
+        if removed == 0 {
+            return 0;
+        }
+
+        let orphans = std::mem::take(&mut self.orphans);
+        let orphan_ids = std::mem::take(&mut self.orphan_ids);
+        let max_orphans = self.max_orphans;
+        let version = self.version;
+        let clock = self.clock.clone();
+        let timestamp_source = self.timestamp_source;
+        // The merged operations keep the timestamp of the first one of their run
+        let max_timestamp = self.max_timestamp;
+
+        *self = Self::load(
+            self.local_client,
+            operations.into_iter().flatten().collect(),
+        )
+        .expect("compacted operations should be valid");
+        self.orphans = orphans;
+        self.orphan_ids = orphan_ids;
+        self.max_orphans = max_orphans;
+        self.version = version + 1;
+        self.clock = clock;
+        self.timestamp_source = timestamp_source;
+        self.max_timestamp = max_timestamp;
+
+        removed
+    }
+
+    // Compacts the local operations stored from the given index, as done when a transaction
+    // is committed, returning the number of operations that were removed
+    pub fn compact_local_operations(&mut self, start: OperationIndex) -> usize {
+        if self.operations.len() <= start + 1 {
+            return 0;
+        }
+
+        let operations: Vec<Operation> = self
+            .operations
+            .drain(start..)
+            .map(Arc::unwrap_or_clone)
+            .collect();
+        debug_assert!(
+            operations
+                .iter()
+                .all(|operation| operation.id.client_id == self.local_client),
+            "only local operations can be compacted"
+        );
+
+        for operation in &operations {
+            self.id_to_index.remove(&operation.id);
+            self.heads.remove(&operation.id);
+        }
+        self.roots.retain(|index| *index < start);
+
+        // Local operations are always appended after the last one
+        let mut parent = operations[0].parent;
+        self.last = parent.map(|parent| self.id_to_index[&parent]);
+
+        let first_sequence = operations[0].id.sequence;
+        if first_sequence > 1 {
+            self.client_sequences
+                .insert(self.local_client, first_sequence - 1);
+        } else {
+            self.client_sequences.remove(&self.local_client);
+        }
+
+        let operations_len = operations.len();
+        let compacted = compact_operations(operations);
+        let removed = operations_len - compacted.len();
+
+        for mut operation in compacted {
+            operation.parent = parent;
+            parent = Some(operation.id);
+            self.insert_operation(operation)
+                .expect("compacted operation should be inserted");
+        }
+
+        removed
+    }
+
+    // Replaces the values that expired before `now` with null. The operations themselves are
+    // kept, as later ones might still refer to them. Returns the number of cleared values.
+    pub fn clear_expired_values(&mut self, now: Timestamp) -> usize {
+        let mut cleared = 0;
+        for operation in self.operations.iter_mut() {
+            let OperationAction::SetMapValue(action) = &operation.action else {
+                continue;
+            };
+            let expired = action
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now);
+            if !expired || action.value == Value::Scalar(ScalarValue::Null) {
+                continue;
+            }
+
+            if let OperationAction::SetMapValue(action) = &mut Arc::make_mut(operation).action {
+                action.value = Value::Scalar(ScalarValue::Null);
+                cleared += 1;
+            }
+        }
+
+        if cleared > 0 {
+            self.version += 1;
+        }
+
+        cleared
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
     fn insert_operation(
         &mut self,
-        op: Operation,
+        mut op: Operation,
     ) -> Result<Option<OperationIndex>, OperationLogError> {
-        // Already processed
-        if self.id_to_index.contains_key(&op.id) {
+        Self::check_operation(&op)?;
+
+        // Already processed, possibly merged into a later operation by a compaction.
+        // Other operations received after a later one of their client are rejected below.
+        if self.id_to_index.contains_key(&op.id) || self.is_merged_into_later(&op) {
             return Ok(None);
         }
 
+        // The parent could have been merged into a later operation
+        if let Some(parent) = op.parent {
+            if self.is_compacted(&parent) {
+                op.parent = self.find_compacted_into(&parent);
+            }
+        }
+        self.skip_seen_text(&mut op);
+
+        self.version += 1;
+        self.max_timestamp = self.max_timestamp.max(op.timestamp);
+
         // Orphan entry, we don't have the necessary dependencies yet
-        if self.is_orphan(&op) {
-            let op_parent = op.parent.expect("orphan should have a parent");
-            self.orphans.insert(op_parent, op);
+        if let Some(parent) = self.missing_parent(&op) {
+            if self.orphan_ids.contains(&op.id) {
+                return Ok(None);
+            }
+            if let Some(limit) = self.max_orphans {
+                if self.orphan_ids.len() >= limit {
+                    return Err(OperationLogError::TooManyOrphans {
+                        operation: op.id,
+                        limit,
+                    });
+                }
+            }
+
+            self.orphan_ids.insert(op.id);
+            self.orphans.entry(parent).or_default().push(op);
             return Ok(None);
         }
 
@@ -145,33 +658,120 @@ impl OperationLog {
         // Update client sequences
         if let Some(sequence) = self.client_sequences.get(&op.id.client_id) {
             if op.id.sequence <= *sequence {
-                panic!("sequence is not monotonically increasing");
+                return Err(OperationLogError::InvalidOperation {
+                    operation: op.id,
+                    reason: InvalidOperationReason::NonIncreasingSequence(*sequence),
+                });
             }
         }
 
         self.client_sequences
             .insert(op.id.client_id, op.id.sequence);
 
+        // Parents are always inserted before their children, see `missing_parent`
+        if let Some(parent) = &op.parent {
+            self.heads.remove(parent);
+        }
+        self.heads.insert(op.id);
+
         // TODO: is the operation concurrent? If yes, we need to re-sort the entries
         if self.is_concurrent(&op) {
-            self.operations.push(op);
+            self.operations.push(Arc::new(op));
             self.recalculate_last();
         } else {
-            self.operations.push(op);
+            self.operations.push(Arc::new(op));
             self.last = Some(index);
         }
 
         Ok(Some(index))
     }
 
-    fn is_orphan(&self, op: &Operation) -> bool {
-        if let Some(parent) = op.parent.as_ref() {
-            if !self.id_to_index.contains_key(parent) {
-                return true;
-            }
+    // Operations of a client are received in order, so a missing operation with a lower
+    // sequence than the latest one is assumed to be compacted. Only the ones received
+    // again can be checked, see `is_merged_into_later`.
+    fn is_compacted(&self, id: &OperationId) -> bool {
+        match self.client_sequences.get(&id.client_id) {
+            Some(sequence) => id.sequence <= *sequence && !self.id_to_index.contains_key(id),
+            None => false,
+        }
+    }
+
+    // A peer might send the result of a compaction that merged insertions we already have.
+    // Only the text inserted by the ones we didn't receive is kept, after the latest one.
+    fn skip_seen_text(&self, op: &mut Operation) {
+        let OperationAction::InsertText(action) = &mut op.action else {
+            return;
+        };
+        let Some(&sequence) = self.client_sequences.get(&op.id.client_id) else {
+            return;
+        };
+        let latest = OperationId {
+            client_id: op.id.client_id,
+            sequence,
+        };
+        let Some(existing) = self.get_operation(&latest) else {
+            return;
+        };
+        let OperationAction::InsertText(existing_action) = &existing.action else {
+            return;
+        };
+
+        let start = action.id.sequence;
+        let seen_end = existing_action.id.sequence + existing_action.value.len() as SequenceIndex;
+        let is_merged = existing_action.object == action.object
+            && existing_action.id.client_id == action.id.client_id
+            && existing_action.id.sequence >= start
+            && seen_end < start + action.value.len() as SequenceIndex;
+        if !is_merged {
+            return;
         }
+        let Some(unseen) = action.value.get((seen_end - start) as usize..) else {
+            return;
+        };
 
-        false
+        action.value = unseen.into();
+        action.left = Some(SequenceBlockId {
+            client_id: action.id.client_id,
+            sequence: seen_end - 1,
+        });
+        action.id.sequence = seen_end;
+        op.parent = Some(latest);
+    }
+
+    // Whether the text inserted by the operation is part of the one it was compacted into
+    fn is_merged_into_later(&self, op: &Operation) -> bool {
+        let OperationAction::InsertText(action) = &op.action else {
+            return false;
+        };
+        let Some(merged) = self
+            .find_compacted_into(&op.id)
+            .and_then(|id| self.get_operation(&id))
+        else {
+            return false;
+        };
+        let OperationAction::InsertText(merged_action) = &merged.action else {
+            return false;
+        };
+
+        let offset = action.id.sequence.wrapping_sub(merged_action.id.sequence) as usize;
+        merged_action.object == action.object
+            && merged_action.id.client_id == action.id.client_id
+            && action.id.sequence >= merged_action.id.sequence
+            && merged_action.value.get(offset..offset + action.value.len())
+                == Some(action.value.as_str())
+    }
+
+    // The first operation of the same client after the compacted one, which contains it
+    fn find_compacted_into(&self, id: &OperationId) -> Option<OperationId> {
+        self.iter()
+            .map(|operation| operation.id)
+            .filter(|other| other.client_id == id.client_id && other.sequence > id.sequence)
+            .min_by_key(|other| other.sequence)
+    }
+
+    fn missing_parent(&self, op: &Operation) -> Option<OperationId> {
+        op.parent
+            .filter(|parent| !self.id_to_index.contains_key(parent))
     }
 
     fn is_concurrent(&self, op: &Operation) -> bool {
@@ -195,7 +795,7 @@ impl OperationLog {
         });
     }
 
-    fn next_id(&self) -> OperationId {
+    pub(crate) fn next_id(&self) -> OperationId {
         let sequence = self.client_sequences.get(&self.local_client).unwrap_or(&0) + 1;
 
         OperationId {
@@ -205,41 +805,206 @@ impl OperationLog {
     }
 }
 
+// Compacts a linear sequence of operations: insertions continuing each other are merged,
+// and map values that are overwritten (or deleted) right away are dropped
+fn compact_operations(operations: Vec<Operation>) -> Vec<Operation> {
+    let mut compacted: Vec<Operation> = Vec::with_capacity(operations.len());
+
+    for mut operation in operations {
+        if let Some(previous) = compacted.last_mut() {
+            if let Some(action) = merge_insert_actions(previous, &operation) {
+                previous.id = operation.id;
+                previous.action = action;
+                continue;
+            }
+        }
+
+        let overwritten = match &operation.action {
+            OperationAction::SetMapValue(action) => find_overwritten_value(
+                &compacted,
+                &action.object,
+                &action.selector,
+                &action.parents,
+            ),
+            OperationAction::DeleteMapValue(action) => find_overwritten_value(
+                &compacted,
+                &action.object,
+                &action.selector,
+                &action.parents,
+            ),
+            _ => None,
+        };
+
+        if let Some(index) = overwritten {
+            let OperationAction::SetMapValue(overwritten) = compacted.remove(index).action else {
+                unreachable!("only set values can be overwritten");
+            };
+
+            match &mut operation.action {
+                OperationAction::SetMapValue(action) => action.parents = overwritten.parents,
+                OperationAction::DeleteMapValue(action) => {
+                    // Nothing left to delete
+                    if overwritten.parents.is_empty() {
+                        continue;
+                    }
+                    action.parents = overwritten.parents;
+                }
+                _ => unreachable!("only set and delete can overwrite values"),
+            }
+        }
+
+        compacted.push(operation);
+    }
+
+    compacted
+}
+
+// Index of the value set by the given operations, if it's the only one being overwritten
+fn find_overwritten_value(
+    operations: &[Operation],
+    object: &ObjRef,
+    selector: &Selector,
+    parents: &[MapBlockId],
+) -> Option<usize> {
+    let [parent] = parents else {
+        return None;
+    };
+
+    operations
+        .iter()
+        .rposition(|operation| match &operation.action {
+            OperationAction::SetMapValue(action) => {
+                action.id == *parent && action.object == *object && action.selector == *selector
+            }
+            _ => false,
+        })
+}
+
+// Merges two insertions, if the second one continues the text of the first one
+fn merge_insert_actions(first: &Operation, second: &Operation) -> Option<OperationAction> {
+    let (OperationAction::InsertText(first_action), OperationAction::InsertText(second_action)) =
+        (&first.action, &second.action)
+    else {
+        return None;
+    };
+
+    let first_len = first_action.value.len() as SequenceIndex;
+    let last_id = SequenceBlockId {
+        client_id: first_action.id.client_id,
+        sequence: first_action.id.sequence + first_len - 1,
+    };
+    let is_continuation = first.id.client_id == second.id.client_id
+        && first_action.object == second_action.object
+        && first_len > 0
+        && second_action.id.client_id == first_action.id.client_id
+        && second_action.id.sequence == first_action.id.sequence + first_len
+        && second_action.left == Some(last_id);
+    if !is_continuation {
+        return None;
+    }
+
+    let mut action = first_action.clone();
+    action.value = [first_action.value.as_str(), second_action.value.as_str()]
+        .concat()
+        .into();
+    Some(OperationAction::InsertText(action))
+}
+
+// Whether `existing` is either `operation` or the result of compacting it with the
+// operations that preceded it
+pub fn is_compacted_version(existing: &Operation, operation: &Operation) -> bool {
+    if existing == operation {
+        return true;
+    }
+
+    match (&existing.action, &operation.action) {
+        // Expired values are cleared by `clear_expired_values`
+        (OperationAction::SetMapValue(existing_action), OperationAction::SetMapValue(action)) => {
+            let cleared = SetMapValueAction {
+                value: Value::Scalar(ScalarValue::Null),
+                ..action.clone()
+            };
+            existing.id == operation.id
+                && existing.parent == operation.parent
+                && existing_action.expires_at.is_some()
+                && *existing_action == cleared
+        }
+        (OperationAction::InsertText(existing_action), OperationAction::InsertText(action)) => {
+            let offset = action.id.sequence.wrapping_sub(existing_action.id.sequence) as usize;
+            existing.id == operation.id
+                && existing_action.object == action.object
+                && existing_action.id.client_id == action.id.client_id
+                && action.id.sequence > existing_action.id.sequence
+                && existing_action.value.get(offset..) == Some(action.value.as_str())
+        }
+        _ => false,
+    }
+}
+
 impl Serializable for OperationLog {
     fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
-        let all_operations = self.operations.iter().chain(self.orphans.values());
+        let all_operations = self.iter().chain(self.orphans.values().flatten());
         let serialized = serialize_operations(all_operations)?;
         Ok(serialized)
     }
 }
 
+// Frozen copy of the log, sharing the operations with it
+pub struct OperationLogSnapshot {
+    operations: Vec<Arc<Operation>>,
+}
+
+impl Serializable for OperationLogSnapshot {
+    fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
+        let serialized = serialize_operations(self.operations.iter().map(Arc::as_ref))?;
+        Ok(serialized)
+    }
+}
+
 pub struct SortedOperationIterator<'a> {
-    operations: &'a [Operation],
-    children: FxHashMap<OperationIndex, Vec<OperationIndex>>,
-    to_visit: VecDeque<OperationIndex>,
+    operations: &'a [Arc<Operation>],
+    // Children of the operation at index `i` are `children[children_start[i]..children_start[i + 1]]`,
+    // which avoids allocating a list for every parent of a long chain
+    children_start: Vec<usize>,
+    children: Vec<OperationIndex>,
+    to_visit: Vec<OperationIndex>,
 }
 
 impl<'a> SortedOperationIterator<'a> {
     pub fn new(
         roots: &'a [OperationIndex],
-        operations: &'a [Operation],
+        operations: &'a [Arc<Operation>],
         id_to_index: &'a FxHashMap<OperationId, OperationIndex>,
     ) -> Self {
-        let mut to_visit: VecDeque<OperationIndex> = VecDeque::new();
-        let mut roots = Vec::from(roots);
-        roots.sort_by(|a, b| Self::compare_operations(*a, *b, operations));
-        to_visit.extend(roots);
+        let mut to_visit = Vec::from(roots);
+        to_visit.sort_by(|a, b| Self::compare_operations(*a, *b, operations));
 
-        let mut children: FxHashMap<OperationIndex, Vec<OperationIndex>> = FxHashMap::default();
-        for (index, operation) in operations.iter().enumerate() {
-            if let Some(parent) = operation.parent {
-                let parent_index = id_to_index[&parent];
-                children.entry(parent_index).or_default().push(index);
+        let parents: Vec<Option<OperationIndex>> = operations
+            .iter()
+            .map(|operation| operation.parent.map(|parent| id_to_index[&parent]))
+            .collect();
+
+        let mut children_start = vec![0; operations.len() + 1];
+        for parent_index in parents.iter().flatten() {
+            children_start[parent_index + 1] += 1;
+        }
+        for index in 0..operations.len() {
+            children_start[index + 1] += children_start[index];
+        }
+
+        // Children are added in index order, like they were appended to the log
+        let mut next_child = children_start.clone();
+        let mut children = vec![0; children_start[operations.len()]];
+        for (index, parent_index) in parents.iter().enumerate() {
+            if let Some(parent_index) = parent_index {
+                children[next_child[*parent_index]] = index;
+                next_child[*parent_index] += 1;
             }
         }
 
         Self {
             operations,
+            children_start,
             children,
             to_visit,
         }
@@ -248,7 +1013,7 @@ impl<'a> SortedOperationIterator<'a> {
     fn compare_operations(
         a: OperationIndex,
         b: OperationIndex,
-        operations: &'a [Operation],
+        operations: &'a [Arc<Operation>],
     ) -> Ordering {
         let a_operation = &operations[a];
         let b_operation = &operations[b];
@@ -269,34 +1034,91 @@ impl<'a> Iterator for SortedOperationIterator<'a> {
     type Item = &'a Operation;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(index) = self.to_visit.pop_back() {
-            match self.children.get(&index) {
-                Some(children) if children.len() == 1 => {
-                    self.to_visit.push_back(children[0]);
-                }
-                Some(children) => {
-                    let mut children_copy = children.clone();
-                    children_copy
-                        .sort_by(|a, b| Self::compare_operations(*a, *b, &self.operations));
-
-                    for child in children_copy {
-                        self.to_visit.push_back(child);
-                    }
-                }
-                None => {}
-            }
+        let index = self.to_visit.pop()?;
 
-            return Some(&self.operations[index]);
+        let children = &self.children[self.children_start[index]..self.children_start[index + 1]];
+        let first_child = self.to_visit.len();
+        self.to_visit.extend_from_slice(children);
+        if children.len() > 1 {
+            let operations = self.operations;
+            self.to_visit[first_child..]
+                .sort_by(|a, b| Self::compare_operations(*a, *b, operations));
         }
 
-        None
+        Some(&self.operations[index])
     }
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum OperationLogError {
     #[error("serialization error: {0}")]
     SerializationError(#[from] SerializationError),
+
+    #[error("validation error: {0}")]
+    ValidationError(#[from] ValidationError),
+
+    #[error("invalid operation {operation:?}: {reason}")]
+    InvalidOperation {
+        operation: OperationId,
+        reason: InvalidOperationReason,
+    },
+
+    #[error(
+        "operation {operation:?} is missing its parent, but {limit} operations are already \
+         waiting for missing dependencies"
+    )]
+    TooManyOrphans {
+        operation: OperationId,
+        limit: usize,
+    },
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum InvalidOperationReason {
+    #[error("sequence 0 is reserved for the root object")]
+    ReservedSequence,
+
+    #[error("sequence is not greater than the latest one of the client ({0})")]
+    NonIncreasingSequence(SequenceIndex),
+
+    #[error("parent {0:?} of the same client doesn't precede it")]
+    ParentNotBefore(OperationId),
+
+    #[error("block {0:?} is not in the sequences of the client of the operation")]
+    InvalidBlock(SequenceBlockId),
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ValidationError {
+    #[error(
+        "duplicate operation {0:?}, the client registries of the merged documents are probably \
+         inconsistent: reload the documents from their serialized form before merging"
+    )]
+    DuplicateOperationId(OperationId),
+
+    #[error(
+        "operation {operation:?} depends on {parent:?}, which is missing or applied after it: \
+         merge the full history of the remote document"
+    )]
+    BrokenParentLink {
+        operation: OperationId,
+        parent: OperationId,
+    },
+
+    #[error(
+        "sequence of client {0} is out of sync with its operations: \
+         rebuild the document by loading its serialized form"
+    )]
+    InconsistentClientSequence(ClientId),
+
+    #[error(
+        "orphan operation {0:?} is attached to the wrong parent or should have been applied: \
+         rebuild the document by loading its serialized form"
+    )]
+    StaleOrphan(OperationId),
 }
 
 impl ClientRemappable for OperationLog {
@@ -307,7 +1129,7 @@ impl ClientRemappable for OperationLog {
             .clone();
 
         for operation in self.operations.iter_mut() {
-            operation.remap_client_ids(mappings);
+            Arc::make_mut(operation).remap_client_ids(mappings);
         }
 
         let mut new_client_sequences = FxHashMap::default();
@@ -334,8 +1156,16 @@ impl ClientRemappable for OperationLog {
         }
         self.id_to_index = new_id_to_index;
 
+        self.heads = std::mem::take(&mut self.heads)
+            .into_iter()
+            .map(|mut id| {
+                id.remap_client_ids(mappings);
+                id
+            })
+            .collect();
+
         let mut new_orphans = FxHashMap::default();
-        for (id, operation) in self.orphans.iter() {
+        for (id, mut operations) in std::mem::take(&mut self.orphans) {
             let new_client_id = mappings
                 .get(&id.client_id)
                 .expect("client ID not found")
@@ -344,8 +1174,462 @@ impl ClientRemappable for OperationLog {
                 client_id: new_client_id,
                 sequence: id.sequence,
             };
-            new_orphans.insert(new_id, operation.clone());
+            for operation in operations.iter_mut() {
+                operation.remap_client_ids(mappings);
+            }
+            new_orphans.insert(new_id, operations);
         }
         self.orphans = new_orphans;
+        self.orphan_ids = self
+            .orphans
+            .values()
+            .flatten()
+            .map(|operation| operation.id)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        CreateMapAction, DeleteMapValueAction, InsertTextAction, MapBlockId, ObjRef,
+        SetMapValueAction, Value,
+    };
+
+    use super::*;
+
+    fn create_map_action(key: &str) -> OperationAction {
+        OperationAction::CreateMap(CreateMapAction {
+            object: ObjRef::Root,
+            selector: key.into(),
+            id: MapBlockId {
+                client_id: 0,
+                sequence: 0,
+            },
+            parents: Vec::new(),
+        })
+    }
+
+    fn insert_text_action(sequence: SequenceIndex, value: &str) -> OperationAction {
+        OperationAction::InsertText(InsertTextAction {
+            object: ObjRef::from(OperationId {
+                client_id: 0,
+                sequence: 1,
+            }),
+            id: SequenceBlockId::new(0, sequence),
+            value: value.into(),
+            left: sequence
+                .checked_sub(1)
+                .map(|left| SequenceBlockId::new(0, left)),
+        })
+    }
+
+    fn set_map_value_action(sequence: SequenceIndex, parents: &[SequenceIndex]) -> OperationAction {
+        OperationAction::SetMapValue(SetMapValueAction {
+            object: ObjRef::Root,
+            selector: "key".into(),
+            id: MapBlockId {
+                client_id: 0,
+                sequence,
+            },
+            parents: parents
+                .iter()
+                .map(|sequence| MapBlockId {
+                    client_id: 0,
+                    sequence: *sequence,
+                })
+                .collect(),
+            value: Value::Scalar((sequence as i32).into()),
+            expires_at: None,
+        })
+    }
+
+    fn operation_log() -> OperationLog {
+        let mut log = OperationLog::new(0);
+        log.apply_local_action(create_map_action("a"), 1).unwrap();
+        log.apply_local_action(create_map_action("b"), 2).unwrap();
+        log
+    }
+
+    #[test]
+    fn test_validate_consistent_log() {
+        assert_eq!(operation_log().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_duplicate_operation_id() {
+        let mut log = operation_log();
+        let duplicate = log.operations[1].clone();
+        log.operations.push(duplicate);
+
+        assert_eq!(
+            log.validate(),
+            Err(ValidationError::DuplicateOperationId(OperationId {
+                client_id: 0,
+                sequence: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn test_validate_from_only_checks_the_later_operations() {
+        let mut log = operation_log();
+        let missing_parent = OperationId {
+            client_id: 1,
+            sequence: 1,
+        };
+        Arc::make_mut(&mut log.operations[0]).parent = Some(missing_parent);
+
+        assert_eq!(log.validate_from(1), Ok(()));
+        assert!(log.validate_from(0).is_err());
+
+        let duplicate = log.operations[1].clone();
+        log.operations.push(duplicate);
+        assert_eq!(
+            log.validate_from(2),
+            Err(ValidationError::DuplicateOperationId(OperationId {
+                client_id: 0,
+                sequence: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn test_check_integrity_reports_every_error() {
+        let mut log = operation_log();
+        let missing_parent = OperationId {
+            client_id: 1,
+            sequence: 1,
+        };
+        Arc::make_mut(&mut log.operations[1]).parent = Some(missing_parent);
+        log.client_sequences.insert(0, 1);
+
+        assert_eq!(
+            log.check_integrity(),
+            vec![
+                ValidationError::BrokenParentLink {
+                    operation: OperationId {
+                        client_id: 0,
+                        sequence: 2
+                    },
+                    parent: missing_parent
+                },
+                ValidationError::InconsistentClientSequence(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_broken_parent_link() {
+        let mut log = operation_log();
+        let missing_parent = OperationId {
+            client_id: 1,
+            sequence: 1,
+        };
+        Arc::make_mut(&mut log.operations[1]).parent = Some(missing_parent);
+
+        assert_eq!(
+            log.validate(),
+            Err(ValidationError::BrokenParentLink {
+                operation: OperationId {
+                    client_id: 0,
+                    sequence: 2
+                },
+                parent: missing_parent
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_reserved_sequence() {
+        let operation = Operation {
+            id: OperationId::new(1, 0),
+            parent: None,
+            action: create_map_action("a"),
+            timestamp: 1,
+        };
+
+        assert!(matches!(
+            OperationLog::from_operations(0, None, vec![operation]),
+            Err(OperationLogError::InvalidOperation {
+                reason: InvalidOperationReason::ReservedSequence,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_apply_operation_rejects_parent_of_same_client_after_it() {
+        let mut log = operation_log();
+        let version = log.version();
+        let operation = Operation {
+            id: OperationId::new(1, 1),
+            parent: Some(OperationId::new(1, 2)),
+            action: create_map_action("c"),
+            timestamp: 3,
+        };
+
+        let result = log.apply_operation(operation).map(|applied| applied.len());
+        assert!(matches!(
+            result,
+            Err(OperationLogError::InvalidOperation {
+                operation: OperationId {
+                    client_id: 1,
+                    sequence: 1
+                },
+                reason: InvalidOperationReason::ParentNotBefore(OperationId {
+                    client_id: 1,
+                    sequence: 2
+                }),
+            })
+        ));
+        // Rejected operations are not kept around as orphans
+        assert_eq!(log.version(), version);
+        assert_eq!(log.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_apply_operation_rejects_blocks_of_other_clients() {
+        let mut log = operation_log();
+        let version = log.version();
+        let operation = Operation {
+            id: OperationId::new(1, 1),
+            parent: None,
+            action: insert_text_action(5, "abc"),
+            timestamp: 3,
+        };
+
+        let result = log.apply_operation(operation).map(|applied| applied.len());
+        assert!(matches!(
+            result,
+            Err(OperationLogError::InvalidOperation {
+                reason: InvalidOperationReason::InvalidBlock(SequenceBlockId {
+                    client_id: 0,
+                    sequence: 5
+                }),
+                ..
+            })
+        ));
+        assert_eq!(log.version(), version);
+    }
+
+    #[test]
+    fn test_orphans_sharing_a_parent_are_all_applied() {
+        let mut log = operation_log();
+        let operation = |client_id, sequence, parent: OperationId| Operation {
+            id: OperationId::new(client_id, sequence),
+            parent: Some(parent),
+            action: create_map_action("c"),
+            timestamp: 3,
+        };
+        let missing = OperationId::new(1, 1);
+
+        // Two clients built on the same missing operation, and a third one on one of them
+        for orphan in [
+            operation(2, 1, missing),
+            operation(3, 1, missing),
+            operation(4, 1, OperationId::new(3, 1)),
+            operation(2, 1, missing),
+        ] {
+            assert!(log.apply_operation(orphan).unwrap().is_empty());
+        }
+        assert_eq!(log.missing_dependencies(), [missing]);
+        assert_eq!(log.validate(), Ok(()));
+
+        let applied = log
+            .apply_operation(operation(1, 1, OperationId::new(0, 2)))
+            .unwrap();
+        assert_eq!(applied.len(), 4);
+        assert!(log.missing_dependencies().is_empty());
+        assert_eq!(log.len(), 6);
+        assert_eq!(log.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_orphans_over_the_limit_are_rejected() {
+        let mut log = operation_log();
+        log.set_max_orphans(Some(1));
+        let orphan = |client_id| Operation {
+            id: OperationId::new(client_id, 2),
+            parent: Some(OperationId::new(client_id, 1)),
+            action: create_map_action("c"),
+            timestamp: 3,
+        };
+
+        log.apply_operation(orphan(1)).unwrap();
+        let result = log.apply_operation(orphan(2)).map(|applied| applied.len());
+        assert!(matches!(
+            result,
+            Err(OperationLogError::TooManyOrphans { limit: 1, .. })
+        ));
+        assert_eq!(log.missing_dependencies(), [OperationId::new(1, 1)]);
+    }
+
+    #[test]
+    fn test_validate_inconsistent_client_sequence() {
+        let mut log = operation_log();
+        log.client_sequences.insert(0, 1);
+
+        assert_eq!(
+            log.validate(),
+            Err(ValidationError::InconsistentClientSequence(0))
+        );
+    }
+
+    #[test]
+    fn test_compact_merges_consecutive_insertions() {
+        let mut log = operation_log();
+        log.apply_local_action(insert_text_action(0, "a"), 3)
+            .unwrap();
+        log.apply_local_action(insert_text_action(1, "bc"), 4)
+            .unwrap();
+        log.apply_local_action(insert_text_action(3, "d"), 5)
+            .unwrap();
+
+        assert_eq!(log.compact(), 2);
+        assert_eq!(log.operations.len(), 3);
+        assert_eq!(log.validate(), Ok(()));
+
+        let merged = log.iter().last().unwrap();
+        assert_eq!(merged.id, OperationId::new(0, 5));
+        assert_eq!(merged.parent, Some(OperationId::new(0, 2)));
+        assert_eq!(merged.timestamp, 3);
+        assert_eq!(merged.action, insert_text_action(0, "abcd"));
+
+        assert_eq!(log.compact(), 0);
+    }
+
+    #[test]
+    fn test_compact_keeps_branching_history() {
+        let mut log = operation_log();
+        log.apply_local_action(insert_text_action(0, "a"), 3)
+            .unwrap();
+        log.apply_local_action(insert_text_action(1, "b"), 4)
+            .unwrap();
+        log.apply_operation(Operation {
+            id: OperationId::new(1, 1),
+            parent: Some(OperationId::new(0, 3)),
+            action: create_map_action("c"),
+            timestamp: 4,
+        })
+        .unwrap();
+
+        assert_eq!(log.compact(), 0);
+        assert_eq!(log.operations.len(), 5);
+    }
+
+    #[test]
+    fn test_compacted_operations_are_still_recognized() {
+        let mut log = operation_log();
+        log.apply_local_action(insert_text_action(0, "a"), 3)
+            .unwrap();
+        let compacted = log
+            .apply_local_action(insert_text_action(1, "b"), 4)
+            .unwrap()
+            .clone();
+        log.apply_local_action(insert_text_action(2, "c"), 5)
+            .unwrap();
+        assert_eq!(log.compact(), 2);
+
+        assert!(log.apply_operation(compacted).unwrap().is_empty());
+
+        // Operations depending on a compacted one depend on its merged version instead
+        let applied = log
+            .apply_operation(Operation {
+                id: OperationId::new(1, 1),
+                parent: Some(OperationId::new(0, 4)),
+                action: create_map_action("d"),
+                timestamp: 6,
+            })
+            .unwrap();
+        assert_eq!(applied[0].parent, Some(OperationId::new(0, 5)));
+        assert_eq!(log.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_compacted_insertions_skip_the_text_already_received() {
+        let mut source = operation_log();
+        let mut log = operation_log();
+        for (sequence, text) in [(0, "a"), (1, "bc")] {
+            let operation = source
+                .apply_local_action(insert_text_action(sequence, text), 3)
+                .unwrap()
+                .clone();
+            log.apply_operation(operation).unwrap();
+        }
+        source
+            .apply_local_action(insert_text_action(3, "d"), 4)
+            .unwrap();
+        assert_eq!(source.compact(), 2);
+
+        let merged = source.iter().last().unwrap().clone();
+        let applied = log.apply_operation(merged).unwrap();
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].id, OperationId::new(0, 5));
+        assert_eq!(applied[0].parent, Some(OperationId::new(0, 4)));
+        assert_eq!(applied[0].action, insert_text_action(3, "d"));
+        assert_eq!(log.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_compact_local_operations_merges_insertions() {
+        let mut log = operation_log();
+        let start = log.len();
+        log.apply_local_action(insert_text_action(0, "a"), 3)
+            .unwrap();
+        log.apply_local_action(insert_text_action(1, "b"), 4)
+            .unwrap();
+        log.apply_local_action(insert_text_action(2, "c"), 5)
+            .unwrap();
+
+        assert_eq!(log.compact_local_operations(start), 2);
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.validate(), Ok(()));
+        assert_eq!(
+            log.heads().collect::<Vec<_>>(),
+            vec![OperationId::new(0, 5)]
+        );
+        assert_eq!(
+            log.get_operation(&OperationId::new(0, 5)).unwrap().action,
+            insert_text_action(0, "abc")
+        );
+
+        let next = log.apply_local_action(create_map_action("c"), 6).unwrap();
+        assert_eq!(next.id, OperationId::new(0, 6));
+    }
+
+    #[test]
+    fn test_compact_local_operations_drops_overwritten_values() {
+        let mut log = operation_log();
+        log.apply_local_action(set_map_value_action(10, &[]), 3)
+            .unwrap();
+
+        let start = log.len();
+        log.apply_local_action(set_map_value_action(11, &[10]), 4)
+            .unwrap();
+        log.apply_local_action(set_map_value_action(12, &[11]), 5)
+            .unwrap();
+        assert_eq!(log.compact_local_operations(start), 1);
+        assert_eq!(
+            log.iter().last().unwrap().action,
+            set_map_value_action(12, &[10])
+        );
+
+        // Values that are created and deleted in the same transaction disappear
+        let start = log.len();
+        log.apply_local_action(set_map_value_action(13, &[]), 6)
+            .unwrap();
+        let delete = OperationAction::DeleteMapValue(DeleteMapValueAction {
+            object: ObjRef::Root,
+            selector: "key".into(),
+            parents: vec![MapBlockId {
+                client_id: 0,
+                sequence: 13,
+            }],
+        });
+        log.apply_local_action(delete, 7).unwrap();
+        assert_eq!(log.compact_local_operations(start), 2);
+        assert_eq!(log.len(), start);
+        assert_eq!(log.validate(), Ok(()));
     }
 }
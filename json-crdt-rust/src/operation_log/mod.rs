@@ -2,4 +2,5 @@ mod log;
 mod serde;
 mod shared;
 
+pub use self::serde::OperationDecoder;
 pub use log::*;
@@ -1,19 +1,22 @@
 use std::{
-    cmp::Ordering,
-    ops::{Add, AddAssign},
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap},
+    hash::Hash,
 };
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use bytes_varint::{VarIntSupport, VarIntSupportMut};
 use num_integer::Integer;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
     serde::{
-        serialize_obj_ref, serialize_selector, serialize_value, ObjRefType, SelectorType,
+        deserialize_obj_ref, read_f64, read_string, read_u8, serialize_obj_ref, SelectorType,
         SerializationError,
     },
-    ClientId, ObjId, ObjRef, Operation, OperationAction, OperationId, Selector, SequenceBlockId,
-    SequenceIndex, Timestamp, Value,
+    types::ROOT_SEQUENCE,
+    AnchorBias, ClientId, MapBlockId, ObjId, ObjRef, Operation, OperationAction, OperationId,
+    ScalarValue, Selector, SequenceBlockId, SequenceIndex, TextValue, Timestamp, Value,
 };
 
 pub fn serialize_operations<'a>(
@@ -21,8 +24,7 @@ pub fn serialize_operations<'a>(
 ) -> Result<Vec<u8>, SerializationError> {
     let mut buf = BytesMut::new();
 
-    let mut sorted_operations: Vec<&Operation> = operations.collect();
-    sorted_operations.sort_by(compare_operations);
+    let sorted_operations = sort_operations_by_chunk(operations.collect());
 
     let sorted_operations_len: u32 = sorted_operations
         .len()
@@ -41,12 +43,18 @@ pub fn serialize_operations<'a>(
     Ok(buf.to_vec())
 }
 
-pub fn deserialize_operations(bytes: &mut Bytes) -> Result<Vec<Operation>, SerializationError> {
+// Operations refer to clients by their position in the client registry stored along with
+// them, so any client ID past `clients_len` makes the buffer malformed
+pub fn deserialize_operations(
+    bytes: &mut Bytes,
+    clients_len: usize,
+) -> Result<Vec<Operation>, SerializationError> {
     let operations_len: u32 = bytes.get_u32_varint().map_err(|_| {
         SerializationError::Malformed("unable to read operations length".to_string())
     })?;
 
     let mut columns = Columns::deserialize(bytes)?;
+    columns.check_client_ids(clients_len)?;
 
     let mut operations = Vec::new();
 
@@ -58,6 +66,104 @@ pub fn deserialize_operations(bytes: &mut Bytes) -> Result<Vec<Operation>, Seria
     Ok(operations)
 }
 
+// Decodes the operations in steps, so that loading a large document doesn't block for the
+// whole time: first the columns one at a time, then the operations in batches
+pub struct OperationDecoder {
+    buffer: Bytes,
+    clients_len: usize,
+    columns: Columns,
+    decoded_columns: usize,
+    remaining_operations: usize,
+}
+
+impl OperationDecoder {
+    pub fn new(mut buffer: Bytes, clients_len: usize) -> Result<Self, SerializationError> {
+        let operations_len: u32 = buffer.get_u32_varint().map_err(|_| {
+            SerializationError::Malformed("unable to read operations length".to_string())
+        })?;
+
+        Ok(Self {
+            buffer,
+            clients_len,
+            columns: Columns::default(),
+            decoded_columns: 0,
+            remaining_operations: operations_len as usize,
+        })
+    }
+
+    // Decodes the next column, returning false once all of them are decoded
+    pub fn decode_column(&mut self) -> Result<bool, SerializationError> {
+        if self.decoded_columns == Columns::STEPS {
+            return Ok(false);
+        }
+
+        self.columns
+            .deserialize_step(self.decoded_columns, &mut self.buffer)?;
+        self.decoded_columns += 1;
+        if self.decoded_columns == Columns::STEPS {
+            self.columns.check_client_ids(self.clients_len)?;
+        }
+        Ok(true)
+    }
+
+    // Parses up to `count` operations, after the columns are decoded
+    pub fn decode_operations(
+        &mut self,
+        count: usize,
+    ) -> Result<Vec<Operation>, SerializationError> {
+        debug_assert_eq!(self.decoded_columns, Columns::STEPS);
+        let count = count.min(self.remaining_operations);
+        self.remaining_operations -= count;
+        (0..count)
+            .map(|_| parse_operation_from_columns(&mut self.columns))
+            .collect()
+    }
+
+    pub fn remaining_operations(&self) -> usize {
+        self.remaining_operations
+    }
+}
+
+// Checks that the columns are consistent with each other, without parsing the operations.
+// Returns the number of operations.
+pub fn validate_operations(
+    bytes: &mut Bytes,
+    clients_len: usize,
+) -> Result<usize, SerializationError> {
+    let operations_len: u32 = bytes.get_u32_varint().map_err(|_| {
+        SerializationError::Malformed("unable to read operations length".to_string())
+    })?;
+
+    let columns = Columns::deserialize(bytes)?;
+    columns.validate(operations_len as usize)?;
+    columns.check_client_ids(clients_len)?;
+
+    if bytes.has_remaining() {
+        return Err(SerializationError::Malformed(format!(
+            "{} unexpected bytes after the columns",
+            bytes.remaining()
+        )));
+    }
+
+    Ok(operations_len as usize)
+}
+
+// Upper bound of the values decoded from the runs of a single column. Runs can describe
+// a huge number of values in a few bytes, so a corrupted count could otherwise exhaust
+// the memory. Real documents stay far below it.
+const MAX_RUN_VALUES: usize = 1 << 24;
+
+fn check_run_len(decoded: usize, count: u32) -> Result<(), SerializationError> {
+    if decoded + count as usize > MAX_RUN_VALUES {
+        return Err(SerializationError::Malformed(format!(
+            "column exceeds the maximum of {} values",
+            MAX_RUN_VALUES
+        )));
+    }
+
+    Ok(())
+}
+
 // TODO: move to the top-level serde module?
 trait SerializableType: Sized + PartialEq + std::fmt::Debug + Clone {
     fn serialize(&self, buf: &mut BytesMut);
@@ -82,7 +188,7 @@ impl SerializableType for bool {
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        let value = buf.get_u8();
+        let value = read_u8(buf, "bool")?;
         Ok(value != 0)
     }
 }
@@ -99,34 +205,54 @@ impl SerializableType for Timestamp {
     }
 }
 
+impl SerializableType for i64 {
+    fn serialize(&self, buf: &mut BytesMut) {
+        buf.put_i64_varint(*self);
+    }
+
+    fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
+        buf.get_i64_varint()
+            .map_err(|_| SerializationError::Malformed("unable to read i64".to_string()))
+    }
+}
+
 impl SerializableType for u8 {
     fn serialize(&self, buf: &mut BytesMut) {
         buf.put_u8(*self);
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        Ok(buf.get_u8())
+        read_u8(buf, "byte")
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum SerializedValueType {
-    String,
-    Int,
-    Double,
-    Bool,
-    Object,
+    String = 1,
+    Int = 2,
+    Double = 3,
+    Bool = 4,
+    Object = 5,
+    Timestamp = 6,
+    Null = 7,
 }
 
-impl From<u8> for SerializedValueType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for SerializedValueType {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => SerializedValueType::String,
-            2 => SerializedValueType::Int,
-            3 => SerializedValueType::Double,
-            4 => SerializedValueType::Bool,
-            5 => SerializedValueType::Object,
-            _ => panic!("unknown value type: {}", value),
+            1 => Ok(SerializedValueType::String),
+            2 => Ok(SerializedValueType::Int),
+            3 => Ok(SerializedValueType::Double),
+            4 => Ok(SerializedValueType::Bool),
+            5 => Ok(SerializedValueType::Object),
+            6 => Ok(SerializedValueType::Timestamp),
+            7 => Ok(SerializedValueType::Null),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown value type: {}",
+                value
+            ))),
         }
     }
 }
@@ -154,6 +280,14 @@ impl SerializableType for Value {
                     buf.put_u8(SerializedValueType::Bool as u8);
                     buf.put_u8(if *bool { 1 } else { 0 });
                 }
+                crate::ScalarValue::Timestamp(_) => {
+                    // Timestamps are stored in a dedicated (delta compressed) column,
+                    // so only the type marker is needed here
+                    buf.put_u8(SerializedValueType::Timestamp as u8);
+                }
+                crate::ScalarValue::Null => {
+                    buf.put_u8(SerializedValueType::Null as u8);
+                }
             },
             Value::Object(object) => {
                 buf.put_u8(SerializedValueType::Object as u8);
@@ -163,7 +297,38 @@ impl SerializableType for Value {
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        todo!()
+        let value_type = SerializedValueType::try_from(read_u8(buf, "value type")?)?;
+
+        match value_type {
+            SerializedValueType::String => {
+                let string_len = buf.get_u32_varint().map_err(|_| {
+                    SerializationError::Malformed("unable to read string len".to_string())
+                })?;
+                let string = read_string(buf, string_len, "string")?;
+                Ok(Value::Scalar(ScalarValue::String(string)))
+            }
+            SerializedValueType::Int => {
+                let int = buf
+                    .get_i32_varint()
+                    .map_err(|_| SerializationError::Malformed("unable to read int".to_string()))?;
+                Ok(Value::Scalar(ScalarValue::Int(int)))
+            }
+            SerializedValueType::Double => {
+                let double = read_f64(buf, "double")?;
+                Ok(Value::Scalar(ScalarValue::Double(double)))
+            }
+            SerializedValueType::Bool => {
+                let bool = read_u8(buf, "bool")?;
+                Ok(Value::Scalar(ScalarValue::Bool(bool != 0)))
+            }
+            // Placeholder, the actual value is read from the timestamp column
+            SerializedValueType::Timestamp => Ok(Value::Scalar(ScalarValue::Timestamp(0))),
+            SerializedValueType::Null => Ok(Value::Scalar(ScalarValue::Null)),
+            SerializedValueType::Object => {
+                let obj_ref = deserialize_obj_ref(buf)?;
+                Ok(Value::Object(obj_ref))
+            }
+        }
     }
 }
 
@@ -272,6 +437,7 @@ impl<Type: SerializableType> CompressionStrategy<Type> for DuplicateCompressionS
             let count = buf.get_u32_varint().map_err(|_| {
                 SerializationError::Malformed("unable to read range count".to_string())
             })?;
+            check_run_len(values.len(), count)?;
 
             for _ in 0..count {
                 values.push(value.clone());
@@ -301,7 +467,7 @@ impl SequenceCompressionStrategy {
         for (index, value) in values.iter().enumerate() {
             let is_last = index == values.len() - 1;
             let is_sequential = if let Some(previous) = prev_value {
-                value == &(previous + 1)
+                previous.checked_add(1) == Some(*value)
             } else {
                 false
             };
@@ -355,6 +521,12 @@ impl CompressionStrategy<u32> for SequenceCompressionStrategy {
             let count = buf.get_u32_varint().map_err(|_| {
                 SerializationError::Malformed("unable to read range count".to_string())
             })?;
+            check_run_len(values.len(), count)?;
+            if count > 0 && start.checked_add(count - 1).is_none() {
+                return Err(SerializationError::Malformed(
+                    "range overflows the value type".to_string(),
+                ));
+            }
 
             for offset in 0..count {
                 let actual_value = start + offset;
@@ -375,12 +547,17 @@ enum TwoWaySequenceRangeDirection {
     Decreasing,
 }
 
-impl From<u8> for TwoWaySequenceRangeDirection {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for TwoWaySequenceRangeDirection {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => TwoWaySequenceRangeDirection::Increasing,
-            1 => TwoWaySequenceRangeDirection::Decreasing,
-            _ => panic!("unknown two way sequence range direction: {}", value),
+            0 => Ok(TwoWaySequenceRangeDirection::Increasing),
+            1 => Ok(TwoWaySequenceRangeDirection::Decreasing),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown two way sequence range direction: {}",
+                value
+            ))),
         }
     }
 }
@@ -416,9 +593,9 @@ impl TwoWaySequenceCompressionStrategy {
             let is_last = index == values.len() - 1;
 
             let direction = if let Some(previous) = prev_value {
-                if value == &(previous + 1) {
+                if previous.checked_add(1) == Some(*value) {
                     Some(TwoWaySequenceRangeDirection::Increasing)
-                } else if value == &(previous - 1) {
+                } else if previous.checked_sub(1) == Some(*value) {
                     Some(TwoWaySequenceRangeDirection::Decreasing)
                 } else {
                     None
@@ -510,13 +687,28 @@ impl CompressionStrategy<u32> for TwoWaySequenceCompressionStrategy {
         let mut values = Vec::new();
 
         for _ in 0..ranges_len {
-            let direction: TwoWaySequenceRangeDirection = buf.get_u8().into();
+            let direction =
+                TwoWaySequenceRangeDirection::try_from(read_u8(buf, "range direction")?)?;
             let start = buf.get_u32_varint().map_err(|_| {
                 SerializationError::Malformed("unable to read range start".to_string())
             })?;
             let count = buf.get_u32_varint().map_err(|_| {
                 SerializationError::Malformed("unable to read range count".to_string())
             })?;
+            check_run_len(values.len(), count)?;
+            let end = match direction {
+                TwoWaySequenceRangeDirection::Increasing => {
+                    start.checked_add(count.saturating_sub(1))
+                }
+                TwoWaySequenceRangeDirection::Decreasing => {
+                    start.checked_sub(count.saturating_sub(1))
+                }
+            };
+            if end.is_none() {
+                return Err(SerializationError::Malformed(
+                    "range overflows the value type".to_string(),
+                ));
+            }
 
             for offset in 0..count {
                 let actual_value = match direction {
@@ -557,7 +749,30 @@ impl DeltaCompressionStrategy {
     }
 }
 
-impl<Type: SerializableType + Integer + Copy + Add + Default> CompressionStrategy<Type>
+// Deltas come from the buffer, so their sum can't be trusted not to overflow
+trait CheckedAddition: Sized {
+    fn checked_addition(self, other: Self) -> Option<Self>;
+}
+
+impl CheckedAddition for u32 {
+    fn checked_addition(self, other: Self) -> Option<Self> {
+        self.checked_add(other)
+    }
+}
+
+impl CheckedAddition for u64 {
+    fn checked_addition(self, other: Self) -> Option<Self> {
+        self.checked_add(other)
+    }
+}
+
+impl CheckedAddition for i64 {
+    fn checked_addition(self, other: Self) -> Option<Self> {
+        self.checked_add(other)
+    }
+}
+
+impl<Type: SerializableType + Integer + Copy + CheckedAddition + Default> CompressionStrategy<Type>
     for DeltaCompressionStrategy
 {
     fn serialize(&self, buf: &mut BytesMut, values: &[Type]) {
@@ -582,7 +797,39 @@ impl<Type: SerializableType + Integer + Copy + Add + Default> CompressionStrateg
 
         for _ in 0..deltas_len {
             let delta = Type::deserialize(buf)?;
-            let value = previous.add(delta);
+            let value = previous.checked_addition(delta).ok_or_else(|| {
+                SerializationError::Malformed("delta overflows the value type".to_string())
+            })?;
+            previous = value;
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+}
+
+// Deltas stored as runs, so columns growing by a constant step (such as the timestamps
+// of operations made at a regular pace) take a single run
+#[derive(Default)]
+struct DeltaRunsCompressionStrategy {}
+
+impl<Type: SerializableType + Integer + Copy + CheckedAddition + Default> CompressionStrategy<Type>
+    for DeltaRunsCompressionStrategy
+{
+    fn serialize(&self, buf: &mut BytesMut, values: &[Type]) {
+        let deltas = DeltaCompressionStrategy::calculate_deltas(values);
+        DuplicateCompressionStrategy {}.serialize(buf, &deltas);
+    }
+
+    fn deserialize(&self, buf: &mut Bytes) -> Result<Vec<Type>, SerializationError> {
+        let deltas: Vec<Type> = DuplicateCompressionStrategy {}.deserialize(buf)?;
+
+        let mut values = Vec::with_capacity(deltas.len());
+        let mut previous: Type = Type::default();
+        for delta in deltas {
+            let value = previous.checked_addition(delta).ok_or_else(|| {
+                SerializationError::Malformed("delta overflows the value type".to_string())
+            })?;
             previous = value;
             values.push(value);
         }
@@ -591,6 +838,195 @@ impl<Type: SerializableType + Integer + Copy + Add + Default> CompressionStrateg
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StrategyKind {
+    None,
+    Duplicate,
+    Sequence,
+    TwoWaySequence,
+    Delta,
+    DeltaRuns,
+}
+
+impl TryFrom<u8> for StrategyKind {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(StrategyKind::None),
+            1 => Ok(StrategyKind::Duplicate),
+            2 => Ok(StrategyKind::Sequence),
+            3 => Ok(StrategyKind::TwoWaySequence),
+            4 => Ok(StrategyKind::Delta),
+            5 => Ok(StrategyKind::DeltaRuns),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown compression strategy: {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl From<StrategyKind> for u8 {
+    fn from(value: StrategyKind) -> Self {
+        match value {
+            StrategyKind::None => 0,
+            StrategyKind::Duplicate => 1,
+            StrategyKind::Sequence => 2,
+            StrategyKind::TwoWaySequence => 3,
+            StrategyKind::Delta => 4,
+            StrategyKind::DeltaRuns => 5,
+        }
+    }
+}
+
+// Types that can be stored in columns whose compression strategy is selected at
+// serialization time. By default, only the strategies that work for any type are used.
+trait AdaptiveType: SerializableType + Eq + Hash {
+    fn candidates(_values: &[Self]) -> Vec<StrategyKind> {
+        vec![StrategyKind::None, StrategyKind::Duplicate]
+    }
+
+    fn serialize_with(kind: StrategyKind, buf: &mut BytesMut, values: &[Self]) {
+        match kind {
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.serialize(buf, values),
+            _ => NoneCompressionStrategy {}.serialize(buf, values),
+        }
+    }
+
+    fn deserialize_with(
+        kind: StrategyKind,
+        buf: &mut Bytes,
+    ) -> Result<Vec<Self>, SerializationError> {
+        match kind {
+            StrategyKind::None => NoneCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.deserialize(buf),
+            _ => Err(SerializationError::Malformed(format!(
+                "unsupported compression strategy: {:?}",
+                kind
+            ))),
+        }
+    }
+
+    // Encodes the column with every candidate and keeps the smallest result. Ties go to
+    // the earliest candidate, which are the cheapest to decode.
+    fn encode_smallest(values: &[Self]) -> (StrategyKind, BytesMut) {
+        Self::candidates(values)
+            .into_iter()
+            .map(|kind| {
+                let mut buf = BytesMut::new();
+                Self::serialize_with(kind, &mut buf, values);
+                (kind, buf)
+            })
+            .min_by_key(|(_, buf)| buf.len())
+            .expect("there should be at least one candidate")
+    }
+}
+
+// Deltas can only be stored for non-decreasing columns, as they are unsigned
+fn is_non_decreasing<Type: PartialOrd>(values: &[Type]) -> bool {
+    values.windows(2).all(|pair| pair[0] <= pair[1])
+}
+
+impl AdaptiveType for bool {}
+impl AdaptiveType for SerializedAction {}
+impl AdaptiveType for SelectorType {}
+impl AdaptiveType for AnchorBias {}
+
+impl AdaptiveType for u32 {
+    fn candidates(values: &[Self]) -> Vec<StrategyKind> {
+        let mut candidates = vec![
+            StrategyKind::None,
+            StrategyKind::Duplicate,
+            StrategyKind::Sequence,
+            StrategyKind::TwoWaySequence,
+        ];
+        if is_non_decreasing(values) {
+            candidates.extend([StrategyKind::Delta, StrategyKind::DeltaRuns]);
+        }
+        candidates
+    }
+
+    fn serialize_with(kind: StrategyKind, buf: &mut BytesMut, values: &[Self]) {
+        match kind {
+            StrategyKind::None => NoneCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::Sequence => SequenceCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::TwoWaySequence => {
+                TwoWaySequenceCompressionStrategy {}.serialize(buf, values)
+            }
+            StrategyKind::Delta => DeltaCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::DeltaRuns => DeltaRunsCompressionStrategy {}.serialize(buf, values),
+        }
+    }
+
+    fn deserialize_with(
+        kind: StrategyKind,
+        buf: &mut Bytes,
+    ) -> Result<Vec<Self>, SerializationError> {
+        match kind {
+            StrategyKind::None => NoneCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Sequence => SequenceCompressionStrategy {}.deserialize(buf),
+            StrategyKind::TwoWaySequence => TwoWaySequenceCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Delta => DeltaCompressionStrategy {}.deserialize(buf),
+            StrategyKind::DeltaRuns => DeltaRunsCompressionStrategy {}.deserialize(buf),
+        }
+    }
+}
+
+impl AdaptiveType for u64 {
+    fn candidates(values: &[Self]) -> Vec<StrategyKind> {
+        let mut candidates = vec![StrategyKind::None, StrategyKind::Duplicate];
+        if is_non_decreasing(values) {
+            candidates.extend([StrategyKind::Delta, StrategyKind::DeltaRuns]);
+        }
+        candidates
+    }
+
+    fn serialize_with(kind: StrategyKind, buf: &mut BytesMut, values: &[Self]) {
+        match kind {
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::Delta => DeltaCompressionStrategy {}.serialize(buf, values),
+            StrategyKind::DeltaRuns => DeltaRunsCompressionStrategy {}.serialize(buf, values),
+            _ => NoneCompressionStrategy {}.serialize(buf, values),
+        }
+    }
+
+    fn deserialize_with(
+        kind: StrategyKind,
+        buf: &mut Bytes,
+    ) -> Result<Vec<Self>, SerializationError> {
+        match kind {
+            StrategyKind::None => NoneCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Duplicate => DuplicateCompressionStrategy {}.deserialize(buf),
+            StrategyKind::Delta => DeltaCompressionStrategy {}.deserialize(buf),
+            StrategyKind::DeltaRuns => DeltaRunsCompressionStrategy {}.deserialize(buf),
+            _ => Err(SerializationError::Malformed(format!(
+                "unsupported compression strategy: {:?}",
+                kind
+            ))),
+        }
+    }
+}
+
+#[derive(Default)]
+struct AdaptiveCompressionStrategy {}
+
+impl<Type: AdaptiveType> CompressionStrategy<Type> for AdaptiveCompressionStrategy {
+    fn serialize(&self, buf: &mut BytesMut, values: &[Type]) {
+        // The selected strategy is stored as a header, before the column values
+        let (kind, encoded) = Type::encode_smallest(values);
+        buf.put_u8(kind.into());
+        buf.put_slice(&encoded);
+    }
+
+    fn deserialize(&self, buf: &mut Bytes) -> Result<Vec<Type>, SerializationError> {
+        let kind = StrategyKind::try_from(read_u8(buf, "compression strategy")?)?;
+        Type::deserialize_with(kind, buf)
+    }
+}
+
 struct Column<Type, Strategy: CompressionStrategy<Type>> {
     cursor: usize,
     values: Vec<Type>,
@@ -655,6 +1091,58 @@ impl<Strategy: CompressionStrategy<u8>> Column<u8, Strategy> {
     }
 }
 
+// Bytes of the inserted texts. It's encoded like a `Column<u8, NoneCompressionStrategy>`,
+// but values are read as slices of the deserialized buffer instead of being copied.
+#[derive(Default)]
+struct TextColumn {
+    cursor: usize,
+    written: BytesMut,
+    values: Bytes,
+}
+
+impl TextColumn {
+    fn push_str(&mut self, string: &str) {
+        self.written.put_slice(string.as_bytes());
+    }
+
+    fn read(&mut self, len: usize) -> Result<TextValue, SerializationError> {
+        if self.cursor + len > self.values.len() {
+            return Err(SerializationError::Malformed(
+                "column read out of bounds".to_string(),
+            ));
+        }
+
+        let bytes = self.values.slice(self.cursor..self.cursor + len);
+        self.cursor += len;
+        TextValue::from_bytes(bytes)
+            .map_err(|_| SerializationError::Malformed("unable to read string".to_string()))
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn serialize(&self, buf: &mut BytesMut) {
+        let values_len: u32 = self.written.len().try_into().expect("too many values");
+        buf.put_u32_varint(values_len);
+        buf.put_slice(&self.written);
+    }
+
+    fn deserialize(&mut self, buf: &mut Bytes) -> Result<(), SerializationError> {
+        let values_len: u32 = buf.get_u32_varint().map_err(|_| {
+            SerializationError::Malformed("unable to read values length".to_string())
+        })?;
+        if buf.remaining() < values_len as usize {
+            return Err(SerializationError::Malformed(
+                "unable to read text values".to_string(),
+            ));
+        }
+
+        self.values = buf.split_to(values_len as usize);
+        Ok(())
+    }
+}
+
 impl<Type, Strategy: CompressionStrategy<Type>> Default for Column<Type, Strategy> {
     fn default() -> Self {
         Self {
@@ -665,7 +1153,7 @@ impl<Type, Strategy: CompressionStrategy<Type>> Default for Column<Type, Strateg
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 enum SerializedAction {
     CreateMap,
     SetMapValue,
@@ -673,18 +1161,38 @@ enum SerializedAction {
     CreateText,
     InsertText,
     DeleteText,
+    MoveMapValue,
+    MoveObject,
+    SetMapValues,
+    DeleteTextRanges,
+    // SetMapValue with a TTL, followed by its expiration
+    SetExpiringMapValue,
+    CreateAnnotation,
+    DeleteTextItems,
 }
 
-impl From<u8> for SerializedAction {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for SerializedAction {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => SerializedAction::CreateMap,
-            2 => SerializedAction::SetMapValue,
-            3 => SerializedAction::DeleteMapValue,
-            4 => SerializedAction::CreateText,
-            5 => SerializedAction::InsertText,
-            6 => SerializedAction::DeleteText,
-            _ => panic!("unknown action type: {}", value),
+            1 => Ok(SerializedAction::CreateMap),
+            2 => Ok(SerializedAction::SetMapValue),
+            3 => Ok(SerializedAction::DeleteMapValue),
+            4 => Ok(SerializedAction::CreateText),
+            5 => Ok(SerializedAction::InsertText),
+            6 => Ok(SerializedAction::DeleteText),
+            7 => Ok(SerializedAction::MoveMapValue),
+            8 => Ok(SerializedAction::MoveObject),
+            9 => Ok(SerializedAction::SetMapValues),
+            10 => Ok(SerializedAction::DeleteTextRanges),
+            11 => Ok(SerializedAction::SetExpiringMapValue),
+            12 => Ok(SerializedAction::CreateAnnotation),
+            13 => Ok(SerializedAction::DeleteTextItems),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown action type: {}",
+                value
+            ))),
         }
     }
 }
@@ -698,6 +1206,13 @@ impl From<&SerializedAction> for u8 {
             SerializedAction::CreateText => 4,
             SerializedAction::InsertText => 5,
             SerializedAction::DeleteText => 6,
+            SerializedAction::MoveMapValue => 7,
+            SerializedAction::MoveObject => 8,
+            SerializedAction::SetMapValues => 9,
+            SerializedAction::DeleteTextRanges => 10,
+            SerializedAction::SetExpiringMapValue => 11,
+            SerializedAction::CreateAnnotation => 12,
+            SerializedAction::DeleteTextItems => 13,
         }
     }
 }
@@ -708,19 +1223,27 @@ impl SerializableType for SerializedAction {
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        let value = buf.get_u8();
-        Ok(value.into())
+        SerializedAction::try_from(read_u8(buf, "action type")?)
     }
 }
 
-impl SerializableType for ObjRefType {
+impl SerializableType for AnchorBias {
     fn serialize(&self, buf: &mut BytesMut) {
-        buf.put_u8(self.into());
+        buf.put_u8(match self {
+            AnchorBias::Contract => 0,
+            AnchorBias::Expand => 1,
+        });
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        let value = buf.get_u8();
-        Ok(value.into())
+        match read_u8(buf, "anchor bias")? {
+            0 => Ok(AnchorBias::Contract),
+            1 => Ok(AnchorBias::Expand),
+            value => Err(SerializationError::Malformed(format!(
+                "unknown anchor bias: {}",
+                value
+            ))),
+        }
     }
 }
 
@@ -730,54 +1253,65 @@ impl SerializableType for SelectorType {
     }
 
     fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        let value = buf.get_u8();
-        Ok(value.into())
+        SelectorType::try_from(read_u8(buf, "selector type")?)
     }
 }
 
 #[derive(Default)]
 struct Columns {
-    op_id_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_id_sequence: Column<SequenceIndex, SequenceCompressionStrategy>,
+    op_id_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_id_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
-    op_has_parent: Column<bool, DuplicateCompressionStrategy>,
-    op_parent_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_parent_sequence: Column<SequenceIndex, SequenceCompressionStrategy>,
+    op_has_parent: Column<bool, AdaptiveCompressionStrategy>,
+    op_parent_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_parent_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
-    op_timestamp: Column<Timestamp, DeltaCompressionStrategy>,
+    op_timestamp: Column<Timestamp, AdaptiveCompressionStrategy>,
 
-    op_action_type: Column<SerializedAction, DuplicateCompressionStrategy>,
+    op_action_type: Column<SerializedAction, AdaptiveCompressionStrategy>,
 
-    op_action_object_ref_type: Column<ObjRefType, DuplicateCompressionStrategy>,
-    op_action_object_ref_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_object_ref_sequence: Column<SequenceIndex, DuplicateCompressionStrategy>,
+    // Sequence ROOT_SEQUENCE marks a reference to the root, which has no client id
+    op_action_object_ref_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
+    op_action_object_ref_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
 
-    op_action_selector_type: Column<SelectorType, DuplicateCompressionStrategy>,
-    op_action_selector_key_len: Column<u32, DuplicateCompressionStrategy>,
+    op_action_selector_type: Column<SelectorType, AdaptiveCompressionStrategy>,
+    op_action_selector_key_len: Column<u32, AdaptiveCompressionStrategy>,
     op_action_selector_key: Column<u8, NoneCompressionStrategy>,
-    op_action_selector_indexes: Column<u32, DuplicateCompressionStrategy>,
+    op_action_selector_indexes: Column<u32, AdaptiveCompressionStrategy>,
 
-    op_action_map_block_id_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_map_block_id_sequence: Column<SequenceIndex, SequenceCompressionStrategy>,
+    op_action_map_block_id_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_action_map_block_id_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
-    op_action_map_parents_len: Column<u32, DuplicateCompressionStrategy>,
-    op_action_map_parents_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_map_parents_sequence: Column<SequenceIndex, SequenceCompressionStrategy>,
+    op_action_map_parents_len: Column<u32, AdaptiveCompressionStrategy>,
+    op_action_map_parents_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_action_map_parents_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
     op_action_map_value: Column<Value, NoneCompressionStrategy>,
+    op_action_map_value_timestamp: Column<i64, DeltaCompressionStrategy>,
+
+    op_action_sequence_block_id_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_action_sequence_block_id_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
+
+    op_action_text_value_len: Column<u32, AdaptiveCompressionStrategy>,
+    op_action_text_value: TextColumn,
 
-    op_action_sequence_block_id_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_sequence_block_id_sequence: Column<SequenceIndex, SequenceCompressionStrategy>,
+    op_action_has_left: Column<bool, AdaptiveCompressionStrategy>,
+    op_action_left_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_action_left_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
-    op_action_text_value_len: Column<u32, DuplicateCompressionStrategy>,
-    op_action_text_value: Column<u8, NoneCompressionStrategy>,
+    op_action_right_client_id: Column<ClientId, AdaptiveCompressionStrategy>,
+    op_action_right_sequence: Column<SequenceIndex, AdaptiveCompressionStrategy>,
 
-    op_action_has_left: Column<bool, DuplicateCompressionStrategy>,
-    op_action_left_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_left_sequence: Column<SequenceIndex, TwoWaySequenceCompressionStrategy>,
+    // Added in version 5, so it's missing (and empty) in older buffers
+    op_action_ranges_len: Column<u32, AdaptiveCompressionStrategy>,
 
-    op_action_right_client_id: Column<ClientId, DuplicateCompressionStrategy>,
-    op_action_right_sequence: Column<SequenceIndex, TwoWaySequenceCompressionStrategy>,
+    // Added in version 6, like the ranges above
+    op_action_expires_at: Column<Timestamp, AdaptiveCompressionStrategy>,
+
+    // Added in version 7. The start anchor of annotations is stored in the left columns,
+    // the end one in the right columns, when present.
+    op_action_has_right: Column<bool, AdaptiveCompressionStrategy>,
+    op_action_anchor_bias: Column<AnchorBias, AdaptiveCompressionStrategy>,
 }
 
 impl Columns {
@@ -789,9 +1323,8 @@ impl Columns {
         self.op_parent_sequence.serialize(buf);
         self.op_timestamp.serialize(buf);
         self.op_action_type.serialize(buf);
-        self.op_action_object_ref_type.serialize(buf);
-        self.op_action_object_ref_client_id.serialize(buf);
         self.op_action_object_ref_sequence.serialize(buf);
+        self.op_action_object_ref_client_id.serialize(buf);
         self.op_action_selector_type.serialize(buf);
         self.op_action_selector_key_len.serialize(buf);
         self.op_action_selector_key.serialize(buf);
@@ -802,6 +1335,7 @@ impl Columns {
         self.op_action_map_parents_client_id.serialize(buf);
         self.op_action_map_parents_sequence.serialize(buf);
         self.op_action_map_value.serialize(buf);
+        self.op_action_map_value_timestamp.serialize(buf);
         self.op_action_sequence_block_id_client_id.serialize(buf);
         self.op_action_sequence_block_id_sequence.serialize(buf);
         self.op_action_text_value_len.serialize(buf);
@@ -811,51 +1345,282 @@ impl Columns {
         self.op_action_left_sequence.serialize(buf);
         self.op_action_right_client_id.serialize(buf);
         self.op_action_right_sequence.serialize(buf);
+        self.op_action_ranges_len.serialize(buf);
+        self.op_action_expires_at.serialize(buf);
+        self.op_action_has_right.serialize(buf);
+        self.op_action_anchor_bias.serialize(buf);
 
         // TODO: add a check to make sure all fields have been serialized?
     }
 
     pub fn deserialize(buf: &mut Bytes) -> Result<Self, SerializationError> {
-        let mut column = Self::default();
-
-        column.op_id_client_id.deserialize(buf)?;
-        column.op_id_sequence.deserialize(buf)?;
-        column.op_has_parent.deserialize(buf)?;
-        column.op_parent_client_id.deserialize(buf)?;
-        column.op_parent_sequence.deserialize(buf)?;
-        column.op_timestamp.deserialize(buf)?;
-        column.op_action_type.deserialize(buf)?;
-        column.op_action_object_ref_type.deserialize(buf)?;
-        column.op_action_object_ref_client_id.deserialize(buf)?;
-        column.op_action_object_ref_sequence.deserialize(buf)?;
-        column.op_action_selector_type.deserialize(buf)?;
-        column.op_action_selector_key_len.deserialize(buf)?;
-        column.op_action_selector_key.deserialize(buf)?;
-        column.op_action_selector_indexes.deserialize(buf)?;
-        column.op_action_map_block_id_client_id.deserialize(buf)?;
-        column.op_action_map_block_id_sequence.deserialize(buf)?;
-        column.op_action_map_parents_len.deserialize(buf)?;
-        column.op_action_map_parents_client_id.deserialize(buf)?;
-        column.op_action_map_parents_sequence.deserialize(buf)?;
-        column.op_action_map_value.deserialize(buf)?;
-        column
-            .op_action_sequence_block_id_client_id
-            .deserialize(buf)?;
-        column
-            .op_action_sequence_block_id_sequence
-            .deserialize(buf)?;
-        column.op_action_text_value_len.deserialize(buf)?;
-        column.op_action_text_value.deserialize(buf)?;
-        column.op_action_has_left.deserialize(buf)?;
-        column.op_action_left_client_id.deserialize(buf)?;
-        column.op_action_left_sequence.deserialize(buf)?;
-        column.op_action_right_client_id.deserialize(buf)?;
-        column.op_action_right_sequence.deserialize(buf)?;
-
-        Ok(column)
+        let mut columns = Self::default();
+        for index in 0..Self::STEPS {
+            columns.deserialize_step(index, buf)?;
+        }
+
+        Ok(columns)
+    }
+
+    // Columns are decoded one (or two, for the ones that go together) at a time, in the order
+    // they are serialized, see `OperationDecoder`
+    const STEPS: usize = 32;
+
+    fn deserialize_step(
+        &mut self,
+        index: usize,
+        buf: &mut Bytes,
+    ) -> Result<(), SerializationError> {
+        match index {
+            0 => self.op_id_client_id.deserialize(buf),
+            1 => self.op_id_sequence.deserialize(buf),
+            2 => self.op_has_parent.deserialize(buf),
+            3 => self.op_parent_client_id.deserialize(buf),
+            4 => self.op_parent_sequence.deserialize(buf),
+            5 => self.op_timestamp.deserialize(buf),
+            6 => self.op_action_type.deserialize(buf),
+            7 => self.op_action_object_ref_sequence.deserialize(buf),
+            8 => self.op_action_object_ref_client_id.deserialize(buf),
+            9 => self.op_action_selector_type.deserialize(buf),
+            10 => self.op_action_selector_key_len.deserialize(buf),
+            11 => self.op_action_selector_key.deserialize(buf),
+            12 => self.op_action_selector_indexes.deserialize(buf),
+            13 => self.op_action_map_block_id_client_id.deserialize(buf),
+            14 => self.op_action_map_block_id_sequence.deserialize(buf),
+            15 => self.op_action_map_parents_len.deserialize(buf),
+            16 => self.op_action_map_parents_client_id.deserialize(buf),
+            17 => self.op_action_map_parents_sequence.deserialize(buf),
+            18 => self.op_action_map_value.deserialize(buf),
+            19 => self.op_action_map_value_timestamp.deserialize(buf),
+            20 => self.op_action_sequence_block_id_client_id.deserialize(buf),
+            21 => self.op_action_sequence_block_id_sequence.deserialize(buf),
+            22 => self.op_action_text_value_len.deserialize(buf),
+            23 => self.op_action_text_value.deserialize(buf),
+            24 => self.op_action_has_left.deserialize(buf),
+            25 => self.op_action_left_client_id.deserialize(buf),
+            26 => self.op_action_left_sequence.deserialize(buf),
+            27 => self.op_action_right_client_id.deserialize(buf),
+            28 => self.op_action_right_sequence.deserialize(buf),
+            // Missing in buffers written by older versions of the format
+            29 if buf.has_remaining() => self.op_action_ranges_len.deserialize(buf),
+            30 if buf.has_remaining() => self.op_action_expires_at.deserialize(buf),
+            31 if buf.has_remaining() => {
+                self.op_action_has_right.deserialize(buf)?;
+                self.op_action_anchor_bias.deserialize(buf)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Columns {
+    fn validate(&self, operations_len: usize) -> Result<(), SerializationError> {
+        let parents = self
+            .op_has_parent
+            .values
+            .iter()
+            .filter(|value| **value)
+            .count();
+        let object_refs = self
+            .op_action_object_ref_sequence
+            .values
+            .iter()
+            .filter(|value| **value != ROOT_SEQUENCE)
+            .count();
+        // Strings are stored in a separate column of bytes
+        let key_bytes = total_len(&self.op_action_selector_key_len.values);
+        let text_bytes = total_len(&self.op_action_text_value_len.values);
+        let ranges_actions = self
+            .op_action_type
+            .values
+            .iter()
+            .filter(|action| {
+                **action == SerializedAction::DeleteTextRanges
+                    || **action == SerializedAction::DeleteTextItems
+            })
+            .count();
+        let expiring_actions = self
+            .op_action_type
+            .values
+            .iter()
+            .filter(|action| **action == SerializedAction::SetExpiringMapValue)
+            .count();
+        let annotation_actions = self
+            .op_action_type
+            .values
+            .iter()
+            .filter(|action| **action == SerializedAction::CreateAnnotation)
+            .count();
+
+        let expected_lens = [
+            (
+                "op_id_client_id",
+                self.op_id_client_id.values.len(),
+                operations_len,
+            ),
+            (
+                "op_id_sequence",
+                self.op_id_sequence.values.len(),
+                operations_len,
+            ),
+            (
+                "op_has_parent",
+                self.op_has_parent.values.len(),
+                operations_len,
+            ),
+            (
+                "op_timestamp",
+                self.op_timestamp.values.len(),
+                operations_len,
+            ),
+            (
+                "op_action_type",
+                self.op_action_type.values.len(),
+                operations_len,
+            ),
+            (
+                "op_parent_client_id",
+                self.op_parent_client_id.values.len(),
+                parents,
+            ),
+            (
+                "op_parent_sequence",
+                self.op_parent_sequence.values.len(),
+                parents,
+            ),
+            (
+                "op_action_object_ref_client_id",
+                self.op_action_object_ref_client_id.values.len(),
+                object_refs,
+            ),
+            (
+                "op_action_selector_key",
+                self.op_action_selector_key.values.len(),
+                key_bytes,
+            ),
+            (
+                "op_action_text_value",
+                self.op_action_text_value.len(),
+                text_bytes,
+            ),
+            (
+                "op_action_map_block_id_sequence",
+                self.op_action_map_block_id_sequence.values.len(),
+                self.op_action_map_block_id_client_id.values.len(),
+            ),
+            (
+                "op_action_map_parents_sequence",
+                self.op_action_map_parents_sequence.values.len(),
+                self.op_action_map_parents_client_id.values.len(),
+            ),
+            (
+                "op_action_sequence_block_id_sequence",
+                self.op_action_sequence_block_id_sequence.values.len(),
+                self.op_action_sequence_block_id_client_id.values.len(),
+            ),
+            (
+                "op_action_left_sequence",
+                self.op_action_left_sequence.values.len(),
+                self.op_action_left_client_id.values.len(),
+            ),
+            (
+                "op_action_right_sequence",
+                self.op_action_right_sequence.values.len(),
+                self.op_action_right_client_id.values.len(),
+            ),
+            (
+                "op_action_ranges_len",
+                self.op_action_ranges_len.values.len(),
+                ranges_actions,
+            ),
+            (
+                "op_action_expires_at",
+                self.op_action_expires_at.values.len(),
+                expiring_actions,
+            ),
+            (
+                "op_action_has_right",
+                self.op_action_has_right.values.len(),
+                annotation_actions,
+            ),
+            (
+                "op_action_anchor_bias",
+                self.op_action_anchor_bias.values.len(),
+                annotation_actions,
+            ),
+        ];
+
+        for (column, len, expected_len) in expected_lens {
+            if len != expected_len {
+                return Err(SerializationError::Malformed(format!(
+                    "column {} has {} values, expected {}",
+                    column, len, expected_len
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Columns {
+    fn check_client_ids(&self, clients_len: usize) -> Result<(), SerializationError> {
+        let client_id_columns = [
+            ("op_id_client_id", &self.op_id_client_id),
+            ("op_parent_client_id", &self.op_parent_client_id),
+            (
+                "op_action_object_ref_client_id",
+                &self.op_action_object_ref_client_id,
+            ),
+            (
+                "op_action_map_block_id_client_id",
+                &self.op_action_map_block_id_client_id,
+            ),
+            (
+                "op_action_map_parents_client_id",
+                &self.op_action_map_parents_client_id,
+            ),
+            (
+                "op_action_sequence_block_id_client_id",
+                &self.op_action_sequence_block_id_client_id,
+            ),
+            ("op_action_left_client_id", &self.op_action_left_client_id),
+            ("op_action_right_client_id", &self.op_action_right_client_id),
+        ];
+
+        for (column, values) in client_id_columns {
+            if let Some(client_id) = values
+                .values
+                .iter()
+                .find(|client_id| **client_id as usize >= clients_len)
+            {
+                return Err(SerializationError::Malformed(format!(
+                    "column {} refers to unknown client {}, only {} are known",
+                    column, client_id, clients_len
+                )));
+            }
+        }
+
+        // Map values can refer to objects as well
+        for value in &self.op_action_map_value.values {
+            if let Value::Object(ObjRef::Object(id)) = value {
+                if id.client_id as usize >= clients_len {
+                    return Err(SerializationError::Malformed(format!(
+                        "column op_action_map_value refers to unknown client {}, only {} are known",
+                        id.client_id, clients_len
+                    )));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
+fn total_len(lens: &[u32]) -> usize {
+    lens.iter().map(|len| *len as usize).sum()
+}
+
 fn populate_columns_for_operation(operation: &Operation, columns: &mut Columns) {
     columns.op_id_client_id.push(operation.id.client_id);
     columns.op_id_sequence.push(operation.id.sequence);
@@ -878,6 +1643,11 @@ fn parse_operation_from_columns(columns: &mut Columns) -> Result<Operation, Seri
         client_id: *columns.op_id_client_id.read()?,
         sequence: *columns.op_id_sequence.read()?,
     };
+    if id.sequence == ROOT_SEQUENCE {
+        return Err(SerializationError::Malformed(
+            "operation sequence is reserved for the root object".to_string(),
+        ));
+    }
 
     let parent = if *columns.op_has_parent.read()? {
         Some(OperationId {
@@ -907,9 +1677,15 @@ fn populate_columns_for_action(action: &OperationAction, columns: &mut Columns)
         OperationAction::SetMapValue(action) => {
             populate_columns_for_set_map_value_action(action, columns);
         }
+        OperationAction::SetMapValues(action) => {
+            populate_columns_for_set_map_values_action(action, columns);
+        }
         OperationAction::DeleteMapValue(action) => {
             populate_columns_for_delete_map_value_action(action, columns);
         }
+        OperationAction::MoveMapValue(action) => {
+            populate_columns_for_move_map_value_action(action, columns);
+        }
         OperationAction::CreateText(action) => {
             populate_columns_for_create_text_action(action, columns);
         }
@@ -919,6 +1695,24 @@ fn populate_columns_for_action(action: &OperationAction, columns: &mut Columns)
         OperationAction::DeleteText(action) => {
             populate_columns_for_delete_text_action(action, columns);
         }
+        OperationAction::DeleteTextRanges(action) => {
+            columns
+                .op_action_type
+                .push(SerializedAction::DeleteTextRanges);
+            populate_columns_for_deleted_text_ranges(&action.object, &action.ranges, columns);
+        }
+        OperationAction::DeleteTextItems(action) => {
+            columns
+                .op_action_type
+                .push(SerializedAction::DeleteTextItems);
+            populate_columns_for_deleted_text_ranges(&action.object, &action.ranges, columns);
+        }
+        OperationAction::MoveObject(action) => {
+            populate_columns_for_move_object_action(action, columns);
+        }
+        OperationAction::CreateAnnotation(action) => {
+            populate_columns_for_create_annotation_action(action, columns);
+        }
     }
 }
 
@@ -927,41 +1721,56 @@ fn parse_action_from_columns(columns: &mut Columns) -> Result<OperationAction, S
 
     match action_type {
         SerializedAction::CreateMap => parse_create_map_action_from_columns(columns),
-        SerializedAction::SetMapValue => parse_set_map_value_action_from_columns(columns),
+        SerializedAction::SetMapValue => parse_set_map_value_action_from_columns(columns, false),
+        SerializedAction::SetExpiringMapValue => {
+            parse_set_map_value_action_from_columns(columns, true)
+        }
         SerializedAction::DeleteMapValue => parse_delete_map_value_action_from_columns(columns),
         SerializedAction::CreateText => parse_create_text_action_from_columns(columns),
         SerializedAction::InsertText => parse_insert_text_action_from_columns(columns),
         SerializedAction::DeleteText => parse_delete_text_action_from_columns(columns),
+        SerializedAction::MoveMapValue => parse_move_map_value_action_from_columns(columns),
+        SerializedAction::MoveObject => parse_move_object_action_from_columns(columns),
+        SerializedAction::SetMapValues => parse_set_map_values_action_from_columns(columns),
+        SerializedAction::DeleteTextRanges => {
+            let (object, ranges) = parse_deleted_text_ranges_from_columns(columns)?;
+            Ok(OperationAction::DeleteTextRanges(
+                crate::DeleteTextRangesAction { object, ranges },
+            ))
+        }
+        SerializedAction::DeleteTextItems => {
+            let (object, ranges) = parse_deleted_text_ranges_from_columns(columns)?;
+            Ok(OperationAction::DeleteTextItems(
+                crate::DeleteTextItemsAction { object, ranges },
+            ))
+        }
+        SerializedAction::CreateAnnotation => parse_create_annotation_action_from_columns(columns),
     }
 }
 
 fn populate_columns_for_obj_ref(obj_ref: &crate::ObjRef, columns: &mut Columns) {
     match obj_ref {
-        crate::ObjRef::Root => columns.op_action_object_ref_type.push(ObjRefType::Root),
+        crate::ObjRef::Root => columns.op_action_object_ref_sequence.push(ROOT_SEQUENCE),
         crate::ObjRef::Object(obj_ref) => {
-            columns.op_action_object_ref_type.push(ObjRefType::Object);
+            columns.op_action_object_ref_sequence.push(obj_ref.sequence);
             columns
                 .op_action_object_ref_client_id
                 .push(obj_ref.client_id);
-            columns.op_action_object_ref_sequence.push(obj_ref.sequence);
         }
     }
 }
 
 fn parse_obj_ref_from_columns(columns: &mut Columns) -> Result<ObjRef, SerializationError> {
-    let obj_ref_type = columns.op_action_object_ref_type.read()?;
-
-    match obj_ref_type {
-        ObjRefType::Root => Ok(ObjRef::Root),
-        ObjRefType::Object => {
-            let client_id = *columns.op_action_object_ref_client_id.read()?;
-            let sequence = *columns.op_action_object_ref_sequence.read()?;
-            Ok(ObjRef::Object(ObjId {
-                client_id,
-                sequence,
-            }))
-        }
+    let sequence = *columns.op_action_object_ref_sequence.read()?;
+    if sequence == ROOT_SEQUENCE {
+        return Ok(ObjRef::Root);
     }
+
+    let client_id = *columns.op_action_object_ref_client_id.read()?;
+    Ok(ObjRef::Object(ObjId {
+        client_id,
+        sequence,
+    }))
 }
 
 fn populate_columns_for_selector(selector: &Selector, columns: &mut Columns) {
@@ -1060,7 +1869,11 @@ fn populate_columns_for_set_map_value_action(
     action: &crate::SetMapValueAction,
     columns: &mut Columns,
 ) {
-    columns.op_action_type.push(SerializedAction::SetMapValue);
+    let action_type = match action.expires_at {
+        Some(_) => SerializedAction::SetExpiringMapValue,
+        None => SerializedAction::SetMapValue,
+    };
+    columns.op_action_type.push(action_type);
 
     populate_columns_for_obj_ref(&action.object, columns);
     populate_columns_for_selector(&action.selector, columns);
@@ -1073,11 +1886,16 @@ fn populate_columns_for_set_map_value_action(
         populate_columns_for_map_block_id(parent, columns);
     }
 
-    columns.op_action_map_value.push(action.value.clone());
+    populate_columns_for_map_value(&action.value, columns);
+
+    if let Some(expires_at) = action.expires_at {
+        columns.op_action_expires_at.push(expires_at);
+    }
 }
 
 fn parse_set_map_value_action_from_columns(
     columns: &mut Columns,
+    expiring: bool,
 ) -> Result<OperationAction, SerializationError> {
     let obj_ref = parse_obj_ref_from_columns(columns)?;
     let selector = parse_selector_from_columns(columns)?;
@@ -1091,7 +1909,12 @@ fn parse_set_map_value_action_from_columns(
         parents.push(parent);
     }
 
-    let value = columns.op_action_map_value.read()?.clone();
+    let value = parse_map_value_from_columns(columns)?;
+
+    let expires_at = match expiring {
+        true => Some(*columns.op_action_expires_at.read()?),
+        false => None,
+    };
 
     Ok(OperationAction::SetMapValue(crate::SetMapValueAction {
         object: obj_ref,
@@ -1099,39 +1922,119 @@ fn parse_set_map_value_action_from_columns(
         id,
         parents,
         value,
+        expires_at,
     }))
 }
 
-fn populate_columns_for_delete_map_value_action(
-    action: &crate::DeleteMapValueAction,
+fn populate_columns_for_map_value(value: &Value, columns: &mut Columns) {
+    if let Value::Scalar(ScalarValue::Timestamp(timestamp)) = value {
+        columns.op_action_map_value_timestamp.push(*timestamp);
+    }
+
+    columns.op_action_map_value.push(value.clone());
+}
+
+fn parse_map_value_from_columns(columns: &mut Columns) -> Result<Value, SerializationError> {
+    let value = match columns.op_action_map_value.read()?.clone() {
+        Value::Scalar(ScalarValue::Timestamp(_)) => Value::Scalar(ScalarValue::Timestamp(
+            *columns.op_action_map_value_timestamp.read()?,
+        )),
+        value => value,
+    };
+
+    Ok(value)
+}
+
+// Only the first block id is stored, as the entries use consecutive ones
+fn populate_columns_for_set_map_values_action(
+    action: &crate::SetMapValuesAction,
     columns: &mut Columns,
 ) {
-    columns
-        .op_action_type
-        .push(SerializedAction::DeleteMapValue);
+    columns.op_action_type.push(SerializedAction::SetMapValues);
 
     populate_columns_for_obj_ref(&action.object, columns);
-    populate_columns_for_selector(&action.selector, columns);
+    populate_columns_for_map_block_id(&action.id, columns);
 
-    let parents_len: u32 = action.parents.len().try_into().expect("too many parents");
-    columns.op_action_map_parents_len.push(parents_len);
+    let entries_len: u32 = action.entries.len().try_into().expect("too many entries");
+    columns.op_action_map_parents_len.push(entries_len);
 
-    for parent in &action.parents {
-        populate_columns_for_map_block_id(parent, columns);
+    for entry in &action.entries {
+        populate_columns_for_selector(&entry.selector, columns);
+
+        let parents_len: u32 = entry.parents.len().try_into().expect("too many parents");
+        columns.op_action_map_parents_len.push(parents_len);
+
+        for parent in &entry.parents {
+            populate_columns_for_map_block_id(parent, columns);
+        }
+
+        populate_columns_for_map_value(&entry.value, columns);
     }
 }
 
-fn parse_delete_map_value_action_from_columns(
+fn parse_set_map_values_action_from_columns(
     columns: &mut Columns,
 ) -> Result<OperationAction, SerializationError> {
     let obj_ref = parse_obj_ref_from_columns(columns)?;
-    let selector = parse_selector_from_columns(columns)?;
+    let id = parse_map_block_id_from_columns(columns)?;
 
-    let parents_len: u32 = *columns.op_action_map_parents_len.read()?;
+    let entries_len: u32 = *columns.op_action_map_parents_len.read()?;
+    let mut entries = Vec::new();
 
-    let mut parents = Vec::new();
-    for _ in 0..parents_len {
-        let parent = parse_map_block_id_from_columns(columns)?;
+    for _ in 0..entries_len {
+        let selector = parse_selector_from_columns(columns)?;
+
+        let parents_len: u32 = *columns.op_action_map_parents_len.read()?;
+        let mut parents = Vec::new();
+        for _ in 0..parents_len {
+            parents.push(parse_map_block_id_from_columns(columns)?);
+        }
+
+        let value = parse_map_value_from_columns(columns)?;
+        entries.push(crate::MapValueEntry {
+            selector,
+            parents,
+            value,
+        });
+    }
+
+    Ok(OperationAction::SetMapValues(crate::SetMapValuesAction {
+        object: obj_ref,
+        id,
+        entries,
+    }))
+}
+
+fn populate_columns_for_delete_map_value_action(
+    action: &crate::DeleteMapValueAction,
+    columns: &mut Columns,
+) {
+    columns
+        .op_action_type
+        .push(SerializedAction::DeleteMapValue);
+
+    populate_columns_for_obj_ref(&action.object, columns);
+    populate_columns_for_selector(&action.selector, columns);
+
+    let parents_len: u32 = action.parents.len().try_into().expect("too many parents");
+    columns.op_action_map_parents_len.push(parents_len);
+
+    for parent in &action.parents {
+        populate_columns_for_map_block_id(parent, columns);
+    }
+}
+
+fn parse_delete_map_value_action_from_columns(
+    columns: &mut Columns,
+) -> Result<OperationAction, SerializationError> {
+    let obj_ref = parse_obj_ref_from_columns(columns)?;
+    let selector = parse_selector_from_columns(columns)?;
+
+    let parents_len: u32 = *columns.op_action_map_parents_len.read()?;
+
+    let mut parents = Vec::new();
+    for _ in 0..parents_len {
+        let parent = parse_map_block_id_from_columns(columns)?;
         parents.push(parent);
     }
 
@@ -1144,6 +2047,100 @@ fn parse_delete_map_value_action_from_columns(
     ))
 }
 
+fn populate_columns_for_move_map_value_action(
+    action: &crate::MoveMapValueAction,
+    columns: &mut Columns,
+) {
+    columns.op_action_type.push(SerializedAction::MoveMapValue);
+
+    populate_columns_for_obj_ref(&action.object, columns);
+    populate_columns_for_selector(&action.from, columns);
+    populate_columns_for_selector(&action.to, columns);
+    populate_columns_for_map_block_id(&action.id, columns);
+
+    for blocks in [&action.parents, &action.sources] {
+        let blocks_len: u32 = blocks.len().try_into().expect("too many parents");
+        columns.op_action_map_parents_len.push(blocks_len);
+
+        for block in blocks {
+            populate_columns_for_map_block_id(block, columns);
+        }
+    }
+}
+
+fn parse_move_map_value_action_from_columns(
+    columns: &mut Columns,
+) -> Result<OperationAction, SerializationError> {
+    let obj_ref = parse_obj_ref_from_columns(columns)?;
+    let from = parse_selector_from_columns(columns)?;
+    let to = parse_selector_from_columns(columns)?;
+    let id = parse_map_block_id_from_columns(columns)?;
+
+    let mut parents = Vec::new();
+    let mut sources = Vec::new();
+    for blocks in [&mut parents, &mut sources] {
+        let blocks_len: u32 = *columns.op_action_map_parents_len.read()?;
+        for _ in 0..blocks_len {
+            blocks.push(parse_map_block_id_from_columns(columns)?);
+        }
+    }
+
+    Ok(OperationAction::MoveMapValue(crate::MoveMapValueAction {
+        object: obj_ref,
+        from,
+        to,
+        id,
+        parents,
+        sources,
+    }))
+}
+
+fn populate_columns_for_move_object_action(
+    action: &crate::MoveObjectAction,
+    columns: &mut Columns,
+) {
+    columns.op_action_type.push(SerializedAction::MoveObject);
+
+    populate_columns_for_obj_ref(&action.object, columns);
+    populate_columns_for_selector(&action.selector, columns);
+    populate_columns_for_map_block_id(&action.id, columns);
+
+    let parents_len: u32 = action.parents.len().try_into().expect("too many parents");
+    columns.op_action_map_parents_len.push(parents_len);
+
+    for parent in &action.parents {
+        populate_columns_for_map_block_id(parent, columns);
+    }
+
+    populate_columns_for_obj_ref(&action.moved_object, columns);
+}
+
+fn parse_move_object_action_from_columns(
+    columns: &mut Columns,
+) -> Result<OperationAction, SerializationError> {
+    let obj_ref = parse_obj_ref_from_columns(columns)?;
+    let selector = parse_selector_from_columns(columns)?;
+    let id = parse_map_block_id_from_columns(columns)?;
+
+    let parents_len: u32 = *columns.op_action_map_parents_len.read()?;
+    let mut parents = Vec::new();
+
+    for _ in 0..parents_len {
+        let parent = parse_map_block_id_from_columns(columns)?;
+        parents.push(parent);
+    }
+
+    let moved_object = parse_obj_ref_from_columns(columns)?;
+
+    Ok(OperationAction::MoveObject(crate::MoveObjectAction {
+        object: obj_ref,
+        selector,
+        id,
+        parents,
+        moved_object,
+    }))
+}
+
 fn populate_columns_for_create_text_action(
     action: &crate::CreateTextAction,
     columns: &mut Columns,
@@ -1214,7 +2211,6 @@ fn populate_columns_for_insert_text_action(
     populate_columns_for_obj_ref(&action.object, columns);
     populate_columns_for_sequence_block_id(&action.id, columns);
 
-    // TODO: we can probably optimize this by avoiding the string clone
     let text_len: u32 = action.value.len().try_into().expect("text too long");
     columns.op_action_text_value_len.push(text_len);
     columns.op_action_text_value.push_str(&action.value);
@@ -1241,10 +2237,7 @@ fn parse_insert_text_action_from_columns(
     let text_len_usize: usize = text_len
         .try_into()
         .map_err(|_| SerializationError::Malformed("text too long".to_string()))?;
-    let text = columns
-        .op_action_text_value
-        .read_str(text_len_usize)?
-        .to_string();
+    let text = columns.op_action_text_value.read(text_len_usize)?;
 
     let left = if *columns.op_action_has_left.read()? {
         let left_client_id = *columns.op_action_left_client_id.read()?;
@@ -1306,6 +2299,118 @@ fn parse_delete_text_action_from_columns(
     }))
 }
 
+// Ranges share the left and right columns with the DeleteText action. The encoding is the
+// same for the DeleteTextRanges and DeleteTextItems actions, only their type differs.
+fn populate_columns_for_deleted_text_ranges(
+    object: &crate::ObjRef,
+    ranges: &[crate::DeletedTextRange],
+    columns: &mut Columns,
+) {
+    populate_columns_for_obj_ref(object, columns);
+
+    let ranges_len: u32 = ranges.len().try_into().expect("too many ranges");
+    columns.op_action_ranges_len.push(ranges_len);
+
+    for range in ranges {
+        columns.op_action_left_client_id.push(range.left.client_id);
+        columns.op_action_left_sequence.push(range.left.sequence);
+        columns
+            .op_action_right_client_id
+            .push(range.right.client_id);
+        columns.op_action_right_sequence.push(range.right.sequence);
+    }
+}
+
+fn parse_deleted_text_ranges_from_columns(
+    columns: &mut Columns,
+) -> Result<(crate::ObjRef, Vec<crate::DeletedTextRange>), SerializationError> {
+    let obj_ref = parse_obj_ref_from_columns(columns)?;
+
+    let ranges_len: u32 = *columns.op_action_ranges_len.read()?;
+    let mut ranges = Vec::new();
+    for _ in 0..ranges_len {
+        let left = SequenceBlockId {
+            client_id: *columns.op_action_left_client_id.read()?,
+            sequence: *columns.op_action_left_sequence.read()?,
+        };
+        let right = SequenceBlockId {
+            client_id: *columns.op_action_right_client_id.read()?,
+            sequence: *columns.op_action_right_sequence.read()?,
+        };
+        ranges.push(crate::DeletedTextRange { left, right });
+    }
+
+    Ok((obj_ref, ranges))
+}
+
+fn populate_columns_for_create_annotation_action(
+    action: &crate::CreateAnnotationAction,
+    columns: &mut Columns,
+) {
+    columns
+        .op_action_type
+        .push(SerializedAction::CreateAnnotation);
+
+    populate_columns_for_obj_ref(&action.object, columns);
+    columns.op_action_anchor_bias.push(action.bias);
+
+    match action.start.as_ref() {
+        Some(start) => {
+            columns.op_action_has_left.push(true);
+            columns.op_action_left_client_id.push(start.client_id);
+            columns.op_action_left_sequence.push(start.sequence);
+        }
+        None => {
+            columns.op_action_has_left.push(false);
+        }
+    }
+
+    match action.end.as_ref() {
+        Some(end) => {
+            columns.op_action_has_right.push(true);
+            columns.op_action_right_client_id.push(end.client_id);
+            columns.op_action_right_sequence.push(end.sequence);
+        }
+        None => {
+            columns.op_action_has_right.push(false);
+        }
+    }
+}
+
+fn parse_create_annotation_action_from_columns(
+    columns: &mut Columns,
+) -> Result<OperationAction, SerializationError> {
+    let obj_ref = parse_obj_ref_from_columns(columns)?;
+    let bias = *columns.op_action_anchor_bias.read()?;
+
+    let start = if *columns.op_action_has_left.read()? {
+        Some(SequenceBlockId {
+            client_id: *columns.op_action_left_client_id.read()?,
+            sequence: *columns.op_action_left_sequence.read()?,
+        })
+    } else {
+        None
+    };
+
+    let end = if *columns.op_action_has_right.read()? {
+        Some(SequenceBlockId {
+            client_id: *columns.op_action_right_client_id.read()?,
+            sequence: *columns.op_action_right_sequence.read()?,
+        })
+    } else {
+        None
+    };
+
+    Ok(OperationAction::CreateAnnotation(
+        crate::CreateAnnotationAction {
+            object: obj_ref,
+            start,
+            end,
+            bias,
+        },
+    ))
+}
+
 fn compare_operations(a: &&Operation, b: &&Operation) -> Ordering {
     if a.id.client_id == b.id.client_id {
         a.id.sequence.cmp(&b.id.sequence)
@@ -1314,6 +2419,258 @@ fn compare_operations(a: &&Operation, b: &&Operation) -> Ordering {
     }
 }
 
+// Operations are grouped in chunks of consecutive operations of the same client (usually
+// created in the same transaction), and chunks are then sorted in causal order.
+// Compared to sorting by client, related operations of different clients end up next to each
+// other, so that columns like timestamps stay monotonic and compress better.
+fn sort_operations_by_chunk(mut operations: Vec<&Operation>) -> Vec<&Operation> {
+    operations.sort_by(compare_operations);
+
+    // Parents don't capture every dependency, as an operation can also refer to the objects
+    // and blocks created by concurrent operations (such as an insertion right after the text
+    // of another client). Chunks are split at the operations that need something the first
+    // operation of the chunk didn't, so the chunk can depend on what its first one needs.
+    let references = ReferenceIndex::new(&operations);
+    let creators: Vec<Vec<OperationId>> = operations
+        .iter()
+        .map(|operation| references.creators(operation))
+        .collect();
+
+    let mut chunks: Vec<&[&Operation]> = Vec::new();
+    let mut chunk_start = 0;
+    for index in 1..=operations.len() {
+        let is_chunk_end = index == operations.len()
+            || operations[index].id.client_id != operations[index - 1].id.client_id
+            || operations[index].parent != Some(operations[index - 1].id)
+            || creators[index].iter().any(|creator| {
+                // Operations are sorted by client and sequence, so the chunk is a range
+                let in_chunk = creator.client_id == operations[chunk_start].id.client_id
+                    && creator.sequence >= operations[chunk_start].id.sequence;
+                !in_chunk && !creators[chunk_start].contains(creator)
+            });
+        if is_chunk_end {
+            chunks.push(&operations[chunk_start..index]);
+            chunk_start = index;
+        }
+    }
+
+    let mut chunk_of_operation: FxHashMap<OperationId, usize> = FxHashMap::default();
+    let mut chunk_offsets = Vec::with_capacity(chunks.len());
+    let mut offset = 0;
+    for (chunk_index, chunk) in chunks.iter().enumerate() {
+        for operation in chunk.iter() {
+            chunk_of_operation.insert(operation.id, chunk_index);
+        }
+        chunk_offsets.push(offset);
+        offset += chunk.len();
+    }
+
+    // A chunk depends on the one containing its parent and on the previous chunk of the same
+    // client, as sequences must be loaded in increasing order.
+    let mut dependencies = vec![0; chunks.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); chunks.len()];
+    for (chunk_index, chunk) in chunks.iter().enumerate() {
+        let mut chunk_dependencies: Vec<usize> = Vec::new();
+
+        if chunk_index > 0 && chunks[chunk_index - 1][0].id.client_id == chunk[0].id.client_id {
+            chunk_dependencies.push(chunk_index - 1);
+        }
+
+        // Orphans might not have their parent in the log, and referenced blocks might have
+        // been created by operations that are not being serialized
+        let first_index = chunk_offsets[chunk_index];
+        let parent = chunk[0].parent;
+        for dependency in parent.iter().chain(creators[first_index].iter()) {
+            if let Some(dependency_chunk) = chunk_of_operation.get(dependency) {
+                if *dependency_chunk != chunk_index
+                    && !chunk_dependencies.contains(dependency_chunk)
+                {
+                    chunk_dependencies.push(*dependency_chunk);
+                }
+            }
+        }
+
+        dependencies[chunk_index] = chunk_dependencies.len();
+        for dependency in chunk_dependencies {
+            dependents[dependency].push(chunk_index);
+        }
+    }
+
+    // Among the chunks that can be serialized, the oldest one comes first
+    let chunk_key = |chunk_index: usize| {
+        let first = chunks[chunk_index][0];
+        let key = (first.timestamp, first.id.client_id, first.id.sequence);
+        (Reverse(key), chunk_index)
+    };
+
+    let mut ready: BinaryHeap<_> = (0..chunks.len())
+        .filter(|chunk_index| dependencies[*chunk_index] == 0)
+        .map(chunk_key)
+        .collect();
+
+    let mut sorted_operations = Vec::with_capacity(operations.len());
+    let mut is_sorted = vec![false; chunks.len()];
+    while let Some((_, chunk_index)) = ready.pop() {
+        sorted_operations.extend_from_slice(chunks[chunk_index]);
+        is_sorted[chunk_index] = true;
+
+        for dependent in &dependents[chunk_index] {
+            dependencies[*dependent] -= 1;
+            if dependencies[*dependent] == 0 {
+                ready.push(chunk_key(*dependent));
+            }
+        }
+    }
+
+    // Only reachable with inconsistent parents, keep the remaining chunks in client order
+    for (chunk_index, chunk) in chunks.iter().enumerate() {
+        if !is_sorted[chunk_index] {
+            sorted_operations.extend_from_slice(chunk);
+        }
+    }
+
+    sorted_operations
+}
+
+// Finds the operations that created the objects and blocks referenced by other operations.
+// Only the given operations are considered, the others are already known by the reader.
+struct ReferenceIndex {
+    operations: FxHashSet<OperationId>,
+    map_blocks: FxHashMap<(ObjRef, MapBlockId), OperationId>,
+    // Sequences covered by each text insertion, by object and client, indexed by their start
+    text_blocks: FxHashMap<(ObjRef, ClientId), BTreeMap<SequenceIndex, (u32, OperationId)>>,
+}
+
+impl ReferenceIndex {
+    fn new(operations: &[&Operation]) -> Self {
+        let mut map_blocks = FxHashMap::default();
+        let mut text_blocks: FxHashMap<_, BTreeMap<_, _>> = FxHashMap::default();
+
+        for operation in operations {
+            let object = *operation.action.object();
+            let mut add_map_block = |id: MapBlockId| {
+                map_blocks.insert((object, id), operation.id);
+            };
+
+            match &operation.action {
+                OperationAction::CreateMap(action) => add_map_block(action.id.clone()),
+                OperationAction::SetMapValue(action) => add_map_block(action.id.clone()),
+                OperationAction::SetMapValues(action) => {
+                    for index in 0..action.entries.len() {
+                        add_map_block(action.entry_id(index));
+                    }
+                }
+                OperationAction::MoveMapValue(action) => add_map_block(action.id.clone()),
+                OperationAction::CreateText(action) => add_map_block(action.id.clone()),
+                OperationAction::MoveObject(action) => add_map_block(action.id.clone()),
+                OperationAction::InsertText(action) => {
+                    text_blocks
+                        .entry((object, action.id.client_id))
+                        .or_default()
+                        .insert(
+                            action.id.sequence,
+                            (action.value.len() as u32, operation.id),
+                        );
+                }
+                OperationAction::DeleteMapValue(_)
+                | OperationAction::DeleteText(_)
+                | OperationAction::DeleteTextRanges(_)
+                | OperationAction::DeleteTextItems(_)
+                | OperationAction::CreateAnnotation(_) => {}
+            }
+        }
+
+        Self {
+            operations: operations.iter().map(|operation| operation.id).collect(),
+            map_blocks,
+            text_blocks,
+        }
+    }
+
+    fn creators(&self, operation: &Operation) -> Vec<OperationId> {
+        let object = operation.action.object();
+        let mut creators = Vec::new();
+        let add_object = |creators: &mut Vec<OperationId>, object: &ObjRef| {
+            if let ObjRef::Object(id) = object {
+                creators.push(*id);
+            }
+        };
+        add_object(&mut creators, object);
+
+        let map_blocks = |parents: &[MapBlockId]| -> Vec<OperationId> {
+            parents
+                .iter()
+                .filter_map(|parent| self.map_blocks.get(&(*object, parent.clone())))
+                .copied()
+                .collect()
+        };
+
+        match &operation.action {
+            OperationAction::CreateMap(action) => creators.extend(map_blocks(&action.parents)),
+            OperationAction::SetMapValue(action) => {
+                creators.extend(map_blocks(&action.parents));
+                if let Value::Object(value) = &action.value {
+                    add_object(&mut creators, value);
+                }
+            }
+            OperationAction::SetMapValues(action) => {
+                for entry in &action.entries {
+                    creators.extend(map_blocks(&entry.parents));
+                    if let Value::Object(value) = &entry.value {
+                        add_object(&mut creators, value);
+                    }
+                }
+            }
+            OperationAction::DeleteMapValue(action) => creators.extend(map_blocks(&action.parents)),
+            OperationAction::MoveMapValue(action) => {
+                creators.extend(map_blocks(&action.parents));
+                creators.extend(map_blocks(&action.sources));
+            }
+            OperationAction::CreateText(action) => creators.extend(map_blocks(&action.parents)),
+            OperationAction::MoveObject(action) => {
+                creators.extend(map_blocks(&action.parents));
+                add_object(&mut creators, &action.moved_object);
+            }
+            OperationAction::InsertText(action) => creators.extend(
+                action
+                    .left
+                    .iter()
+                    .filter_map(|left| self.text_block(object, left)),
+            ),
+            OperationAction::DeleteText(action) => {
+                creators.extend(self.text_block(object, &action.left));
+                creators.extend(self.text_block(object, &action.right));
+            }
+            OperationAction::DeleteTextRanges(action) => {
+                for range in &action.ranges {
+                    creators.extend(self.text_block(object, &range.left));
+                    creators.extend(self.text_block(object, &range.right));
+                }
+            }
+            OperationAction::DeleteTextItems(action) => {
+                for range in &action.ranges {
+                    creators.extend(self.text_block(object, &range.left));
+                    creators.extend(self.text_block(object, &range.right));
+                }
+            }
+            OperationAction::CreateAnnotation(action) => {
+                for anchor in action.start.iter().chain(action.end.iter()) {
+                    creators.extend(self.text_block(object, anchor));
+                }
+            }
+        }
+
+        creators.retain(|creator| *creator != operation.id && self.operations.contains(creator));
+        creators
+    }
+
+    fn text_block(&self, object: &ObjRef, id: &SequenceBlockId) -> Option<OperationId> {
+        let blocks = self.text_blocks.get(&(*object, id.client_id))?;
+        let (start, (len, creator)) = blocks.range(..=id.sequence).next_back()?;
+        (id.sequence < start + len).then_some(*creator)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1402,4 +2759,448 @@ mod tests {
             ]
         );
     }
+
+    fn operation(id: (u32, u32), parent: Option<(u32, u32)>, timestamp: Timestamp) -> Operation {
+        let to_id = |(client_id, sequence)| OperationId {
+            client_id,
+            sequence,
+        };
+
+        Operation {
+            id: to_id(id),
+            parent: parent.map(to_id),
+            action: OperationAction::DeleteMapValue(crate::DeleteMapValueAction {
+                object: ObjRef::Root,
+                selector: Selector::from("key"),
+                parents: Vec::new(),
+            }),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_sort_operations_by_chunk_follows_causal_order() {
+        let operations = [
+            operation((1, 1), Some((0, 1)), 2),
+            operation((0, 2), Some((1, 1)), 3),
+            operation((0, 3), Some((0, 2)), 4),
+            operation((1, 2), Some((0, 3)), 5),
+            operation((0, 1), None, 1),
+        ];
+
+        let sorted: Vec<(u32, u32)> = sort_operations_by_chunk(operations.iter().collect())
+            .iter()
+            .map(|operation| (operation.id.client_id, operation.id.sequence))
+            .collect();
+        assert_eq!(sorted, [(0, 1), (1, 1), (0, 2), (0, 3), (1, 2)]);
+    }
+
+    #[test]
+    fn test_sort_operations_by_chunk_keeps_client_sequences_increasing() {
+        // The second chunk of client 0 has an earlier timestamp and a missing parent (orphan),
+        // but it must still come after the first one
+        let operations = [
+            operation((0, 1), None, 10),
+            operation((0, 2), Some((0, 1)), 11),
+            operation((0, 3), Some((2, 7)), 1),
+            operation((1, 1), None, 5),
+        ];
+
+        let sorted: Vec<(u32, u32)> = sort_operations_by_chunk(operations.iter().collect())
+            .iter()
+            .map(|operation| (operation.id.client_id, operation.id.sequence))
+            .collect();
+        assert_eq!(sorted, [(1, 1), (0, 1), (0, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn test_sort_operations_by_chunk_follows_referenced_blocks() {
+        let insert =
+            |id: (u32, u32), block: (u32, u32), left: Option<(u32, u32)>, timestamp| Operation {
+                action: OperationAction::InsertText(crate::InsertTextAction {
+                    object: ObjRef::Root,
+                    id: SequenceBlockId::new(block.0, block.1),
+                    value: "a".into(),
+                    left: left.map(|(client, sequence)| SequenceBlockId::new(client, sequence)),
+                }),
+                ..operation(id, Some((0, 1)), timestamp)
+            };
+
+        // The second operation of client 0 is typed after the text of client 1, even if its
+        // parent is the first one and its timestamp is older
+        let operations = [
+            Operation {
+                parent: None,
+                ..insert((0, 1), (0, 0), None, 1)
+            },
+            insert((0, 2), (0, 1), Some((1, 0)), 2),
+            insert((1, 1), (1, 0), Some((0, 0)), 3),
+        ];
+
+        let sorted: Vec<(u32, u32)> = sort_operations_by_chunk(operations.iter().collect())
+            .iter()
+            .map(|operation| (operation.id.client_id, operation.id.sequence))
+            .collect();
+        assert_eq!(sorted, [(0, 1), (1, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn test_validate_operations_checks_column_lengths() {
+        let operations = [
+            operation((0, 1), None, 1),
+            operation((0, 2), Some((0, 1)), 2),
+        ];
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        let validated = validate_operations(&mut Bytes::from(serialized), 1).unwrap();
+        assert_eq!(validated, 2);
+
+        let mut columns = Columns::default();
+        for operation in &operations {
+            populate_columns_for_operation(operation, &mut columns);
+        }
+        assert!(columns.validate(2).is_ok());
+        assert!(columns.validate(3).is_err());
+
+        columns.op_parent_sequence.values.pop();
+        assert!(matches!(
+            columns.validate(2),
+            Err(SerializationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_obj_refs_encode_root_as_reserved_sequence() {
+        let mut child = operation((1, 2), None, 3);
+        if let OperationAction::DeleteMapValue(action) = &mut child.action {
+            action.object = ObjRef::Object(ObjId::new(0, 1));
+        }
+        let operations = [operation((0, 1), None, 1), child];
+
+        let mut columns = Columns::default();
+        for operation in &operations {
+            populate_columns_for_operation(operation, &mut columns);
+        }
+        // Only the reference to an object needs a client id
+        assert_eq!(columns.op_action_object_ref_sequence.values, [0, 1]);
+        assert_eq!(columns.op_action_object_ref_client_id.values, [0]);
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        let deserialized = deserialize_operations(&mut Bytes::from(serialized), 2).unwrap();
+        assert_eq!(deserialized, operations);
+
+        let reserved = serialize_operations([operation((0, 0), None, 1)].iter()).unwrap();
+        assert!(matches!(
+            deserialize_operations(&mut Bytes::from(reserved), 1),
+            Err(SerializationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_text_values_are_read_from_the_buffer() {
+        let insert = |sequence: u32, value: &str| Operation {
+            action: OperationAction::InsertText(crate::InsertTextAction {
+                object: ObjRef::Root,
+                id: SequenceBlockId::new(0, sequence),
+                value: value.into(),
+                left: None,
+            }),
+            ..operation((0, sequence), None, 1)
+        };
+        let operations = [insert(1, "hello"), insert(2, "wörld")];
+
+        let buffer = Bytes::from(serialize_operations(operations.iter()).unwrap());
+        let deserialized = deserialize_operations(&mut buffer.clone(), 1).unwrap();
+        assert_eq!(deserialized, operations);
+
+        let buffer_range = buffer.as_ptr_range();
+        for operation in &deserialized {
+            if let OperationAction::InsertText(action) = &operation.action {
+                assert!(buffer_range.contains(&action.value.as_ptr()));
+            }
+        }
+
+        let mut invalid = Columns::default();
+        populate_columns_for_operation(&operations[0], &mut invalid);
+        invalid.op_action_text_value = TextColumn::default();
+        invalid.op_action_text_value.written.put_slice(&[0xff; 5]);
+        let mut buf = BytesMut::new();
+        buf.put_u32_varint(1);
+        invalid.serialize(&mut buf);
+        assert!(matches!(
+            deserialize_operations(&mut buf.freeze(), 1),
+            Err(SerializationError::Malformed(_))
+        ));
+    }
+
+    fn set_map_value(sequence: u32, value: Value) -> Operation {
+        Operation {
+            action: OperationAction::SetMapValue(crate::SetMapValueAction {
+                object: ObjRef::Root,
+                selector: Selector::from("key"),
+                id: MapBlockId {
+                    client_id: 0,
+                    sequence,
+                },
+                parents: Vec::new(),
+                value,
+                expires_at: None,
+            }),
+            ..operation(
+                (0, sequence),
+                sequence.checked_sub(1).map(|seq| (0, seq)),
+                100,
+            )
+        }
+    }
+
+    #[test]
+    fn test_map_values_round_trip() {
+        let values = [
+            Value::Scalar(ScalarValue::String("hello".to_string())),
+            Value::Scalar(ScalarValue::String(String::new())),
+            Value::Scalar(ScalarValue::Int(-42)),
+            Value::Scalar(ScalarValue::Int(i32::MAX)),
+            Value::Scalar(ScalarValue::Double(3.5)),
+            Value::Scalar(ScalarValue::Bool(true)),
+            Value::Scalar(ScalarValue::Bool(false)),
+            Value::Scalar(ScalarValue::Timestamp(1_700_000_000_000)),
+            Value::Scalar(ScalarValue::Timestamp(-5)),
+            Value::Scalar(ScalarValue::Null),
+            Value::Object(ObjRef::Root),
+            Value::Object(ObjRef::Object(ObjId::new(1, 7))),
+        ];
+
+        let mut operations: Vec<Operation> = values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| set_map_value(index as u32 + 1, value.clone()))
+            .collect();
+        operations.push(Operation {
+            action: OperationAction::SetMapValues(crate::SetMapValuesAction {
+                object: ObjRef::Object(ObjId::new(1, 7)),
+                id: MapBlockId {
+                    client_id: 0,
+                    sequence: 20,
+                },
+                entries: values
+                    .iter()
+                    .enumerate()
+                    .map(|(index, value)| crate::MapValueEntry {
+                        selector: Selector::from(format!("key-{}", index)),
+                        parents: Vec::new(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+            }),
+            ..operation((0, 20), Some((0, 12)), 200)
+        });
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        let deserialized = deserialize_operations(&mut Bytes::from(serialized), 2).unwrap();
+        assert_eq!(deserialized, operations);
+    }
+
+    #[test]
+    fn test_expiring_map_values_round_trip() {
+        let mut operations: Vec<Operation> = (1..=4)
+            .map(|sequence| set_map_value(sequence, Value::Scalar(ScalarValue::Int(1))))
+            .collect();
+        for (operation, expires_at) in operations.iter_mut().zip([Some(500), None, Some(100)]) {
+            let OperationAction::SetMapValue(action) = &mut operation.action else {
+                unreachable!();
+            };
+            action.expires_at = expires_at;
+        }
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        assert_eq!(
+            validate_operations(&mut Bytes::from(serialized.clone()), 1).unwrap(),
+            4
+        );
+        let deserialized = deserialize_operations(&mut Bytes::from(serialized), 1).unwrap();
+        assert_eq!(deserialized, operations);
+    }
+
+    #[test]
+    fn test_annotations_round_trip() {
+        let anchor = |sequence| Some(SequenceBlockId::new(0, sequence));
+        let anchors = [
+            (anchor(3), anchor(8), AnchorBias::Contract),
+            (None, anchor(2), AnchorBias::Expand),
+            (anchor(5), None, AnchorBias::Expand),
+        ];
+        let operations: Vec<Operation> = anchors
+            .into_iter()
+            .zip(1..)
+            .map(|((start, end, bias), sequence)| Operation {
+                action: OperationAction::CreateAnnotation(crate::CreateAnnotationAction {
+                    object: ObjRef::Object(ObjId::new(0, 100)),
+                    start,
+                    end,
+                    bias,
+                }),
+                ..operation((0, sequence), Some((0, sequence - 1)), 100)
+            })
+            .collect();
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        assert_eq!(
+            validate_operations(&mut Bytes::from(serialized.clone()), 1).unwrap(),
+            3
+        );
+        let deserialized = deserialize_operations(&mut Bytes::from(serialized), 1).unwrap();
+        assert_eq!(deserialized, operations);
+    }
+
+    #[test]
+    fn test_map_values_check_object_client_ids() {
+        let operations = [set_map_value(
+            1,
+            Value::Object(ObjRef::Object(ObjId::new(1, 7))),
+        )];
+
+        let serialized = serialize_operations(operations.iter()).unwrap();
+        assert!(deserialize_operations(&mut Bytes::from(serialized.clone()), 2).is_ok());
+        assert!(matches!(
+            deserialize_operations(&mut Bytes::from(serialized), 1),
+            Err(SerializationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_strategy_selection() {
+        assert_eq!(
+            u32::encode_smallest(&[1, 2, 3, 4, 5, 6]).0,
+            StrategyKind::Sequence
+        );
+        assert_eq!(
+            u32::encode_smallest(&[7, 7, 7, 7, 9, 9]).0,
+            StrategyKind::Duplicate
+        );
+        assert_eq!(
+            u32::encode_smallest(&[1, 2, 3, 4, 5, 4, 3, 2, 1]).0,
+            StrategyKind::TwoWaySequence
+        );
+        assert_eq!(
+            u64::encode_smallest(&[1_700_000_000_000, 1_700_000_000_010, 1_700_000_000_015]).0,
+            StrategyKind::Delta
+        );
+        // Delta can't be used when timestamps are not monotonic
+        assert_eq!(
+            u64::encode_smallest(&[1_700_000_000_010, 1_700_000_000_000, 1_700_000_000_015]).0,
+            StrategyKind::None
+        );
+        assert_eq!(
+            bool::encode_smallest(&[true, false, true]).0,
+            StrategyKind::None
+        );
+        assert_eq!(
+            bool::encode_smallest(&[true; 10]).0,
+            StrategyKind::Duplicate
+        );
+    }
+
+    #[test]
+    fn test_adaptive_strategy_uses_delta_runs_for_constant_steps() {
+        let timestamps: Vec<u64> = (0..20).map(|i| 1_700_000_000_000 + i * 10).collect();
+        let (kind, encoded) = u64::encode_smallest(&timestamps);
+        assert_eq!(kind, StrategyKind::DeltaRuns);
+
+        let mut raw = BytesMut::new();
+        NoneCompressionStrategy {}.serialize(&mut raw, &timestamps);
+        assert!(encoded.len() * 10 < raw.len());
+    }
+
+    #[test]
+    fn test_adaptive_strategy_round_trip() {
+        let columns: &[&[u64]] = &[
+            &[],
+            &[5, 4, 3, 9, 1],
+            &[3, 3, 3, 3],
+            &[1_700_000_000_000, 1_700_000_000_001, 1_700_000_000_100],
+            &[10, 20, 30, 40, 50, 60],
+        ];
+
+        for values in columns {
+            let mut buf = BytesMut::new();
+            AdaptiveCompressionStrategy {}.serialize(&mut buf, values);
+            let deserialized: Vec<u64> = AdaptiveCompressionStrategy {}
+                .deserialize(&mut buf.freeze())
+                .unwrap();
+            assert_eq!(&deserialized, values);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_strategy_rejects_unknown_header() {
+        let mut buf = Bytes::from_static(&[42, 0]);
+        let result: Result<Vec<u32>, _> = AdaptiveCompressionStrategy {}.deserialize(&mut buf);
+        assert!(matches!(result, Err(SerializationError::Malformed(_))));
+    }
+
+    use crate::{ReadableDoc, WritableDoc};
+
+    // Updates whose text insertion is rewritten to use the given block id
+    fn updates_inserting_at(ids: &[SequenceBlockId]) -> Vec<Bytes> {
+        let mut doc = crate::Doc::new("sender".to_string());
+        let mut txn = doc.transaction();
+        let text = txn.create_text(ObjRef::Root, "text").unwrap();
+        txn.append_text(text, "xy").unwrap();
+        txn.commit().unwrap();
+
+        let update = doc
+            .encode_new_operations_since(&crate::DocVersion::new())
+            .unwrap();
+        let (client_registry, mut operations) =
+            crate::serde::deserialize_update(update.into()).unwrap();
+        let operations = deserialize_operations(&mut operations, 1).unwrap();
+
+        ids.iter()
+            .map(|id| {
+                let mut operations = operations.clone();
+                for operation in &mut operations {
+                    if let OperationAction::InsertText(action) = &mut operation.action {
+                        action.id = id.clone();
+                    }
+                }
+                let operations = serialize_operations(operations.iter()).unwrap();
+                crate::serde::serialize_update(client_registry.to_vec(), operations)
+                    .unwrap()
+                    .into()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_updates_with_huge_block_sequences() {
+        let updates = updates_inserting_at(&[
+            SequenceBlockId::new(0, u32::MAX - 2),
+            SequenceBlockId::new(0, u32::MAX - 1),
+        ]);
+
+        // The locations of the blocks don't grow with their sequence
+        let mut doc = crate::Doc::new("receiver".to_string());
+        doc.apply_encoded_operations(updates[0].clone()).unwrap();
+        let text = doc.get(ObjRef::Root, "text").unwrap().unwrap();
+        let text = *text.as_object().unwrap();
+        assert_eq!(doc.get_text(text).unwrap().unwrap().to_string(), "xy");
+
+        let mut txn = doc.transaction();
+        txn.append_text(text, "z").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(doc.get_text(text).unwrap().unwrap().to_string(), "xyz");
+
+        // The last item of the block would be past the range of sequences
+        let mut doc = crate::Doc::new("receiver".to_string());
+        assert!(matches!(
+            doc.apply_encoded_operations(updates[1].clone()),
+            Err(crate::DocError::OperationLogError(
+                crate::operation_log::OperationLogError::InvalidOperation {
+                    reason: crate::operation_log::InvalidOperationReason::InvalidBlock(_),
+                    ..
+                }
+            ))
+        ));
+    }
 }
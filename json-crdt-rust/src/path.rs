@@ -0,0 +1,279 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::Selector;
+
+// Sequence of selectors leading from the root to a nested value.
+// The string syntax separates keys with dots and puts indexes in brackets, e.g. `a.b[2].c`.
+// Keys that are not plain identifiers can be quoted in brackets, e.g. `a["b.c"]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Path {
+    selectors: Vec<Selector>,
+}
+
+impl Path {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(path: &str) -> Result<Self, PathError> {
+        PathParser::new(path).parse()
+    }
+
+    pub fn push<T: Into<Selector>>(&mut self, selector: T) {
+        self.selectors.push(selector.into());
+    }
+
+    // Returns a new path, so that paths can be built with chained calls
+    pub fn join<T: Into<Selector>>(&self, selector: T) -> Self {
+        let mut path = self.clone();
+        path.push(selector);
+        path
+    }
+
+    pub fn selectors(&self) -> &[Selector] {
+        &self.selectors
+    }
+
+    pub fn len(&self) -> usize {
+        self.selectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    // The selectors leading to the parent object, and the selector of the value in it
+    pub fn split_last(&self) -> Option<(&Selector, &[Selector])> {
+        self.selectors.split_last()
+    }
+}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        Self::parse(path)
+    }
+}
+
+impl<T: Into<Selector>> FromIterator<T> for Path {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self {
+            selectors: iter.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<Vec<Selector>> for Path {
+    fn from(selectors: Vec<Selector>) -> Self {
+        Self { selectors }
+    }
+}
+
+impl Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, selector) in self.selectors.iter().enumerate() {
+            match selector {
+                Selector::Index(value) => write!(f, "[{}]", value)?,
+                Selector::Key(key) if is_identifier(key) => {
+                    if index > 0 {
+                        f.write_str(".")?;
+                    }
+                    f.write_str(key)?;
+                }
+                Selector::Key(key) => {
+                    f.write_str("[\"")?;
+                    for char in key.chars() {
+                        if char == '"' || char == '\\' {
+                            f.write_str("\\")?;
+                        }
+                        write!(f, "{}", char)?;
+                    }
+                    f.write_str("\"]")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum PathError {
+    #[error("empty path")]
+    Empty,
+
+    #[error("malformed path at {position}: {message}")]
+    Malformed { position: usize, message: String },
+}
+
+fn is_identifier(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(is_identifier_char)
+}
+
+fn is_identifier_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '_' || char == '-'
+}
+
+struct PathParser<'a> {
+    path: &'a str,
+    position: usize,
+}
+
+impl<'a> PathParser<'a> {
+    fn new(path: &'a str) -> Self {
+        Self { path, position: 0 }
+    }
+
+    fn parse(mut self) -> Result<Path, PathError> {
+        let mut selectors = Vec::new();
+
+        while let Some(char) = self.peek() {
+            let selector = match char {
+                '[' => self.parse_bracket()?,
+                '.' if !selectors.is_empty() => {
+                    self.position += 1;
+                    self.parse_key()?
+                }
+                _ if selectors.is_empty() => self.parse_key()?,
+                _ => return Err(self.error("expected '.' or '['")),
+            };
+            selectors.push(selector);
+        }
+
+        if selectors.is_empty() {
+            return Err(PathError::Empty);
+        }
+
+        Ok(Path { selectors })
+    }
+
+    fn parse_key(&mut self) -> Result<Selector, PathError> {
+        let start = self.position;
+        while self.peek().is_some_and(is_identifier_char) {
+            self.advance();
+        }
+
+        if start == self.position {
+            return Err(self.error("expected a key"));
+        }
+
+        Ok(Selector::Key(self.path[start..self.position].to_string()))
+    }
+
+    fn parse_bracket(&mut self) -> Result<Selector, PathError> {
+        // Skip the opening bracket
+        self.position += 1;
+
+        let selector = match self.peek() {
+            Some('"') => self.parse_quoted_key()?,
+            Some(char) if char.is_ascii_digit() => {
+                let start = self.position;
+                while self.peek().is_some_and(|char| char.is_ascii_digit()) {
+                    self.advance();
+                }
+                let index = self.path[start..self.position]
+                    .parse()
+                    .map_err(|_| self.error("index too large"))?;
+                Selector::Index(index)
+            }
+            _ => return Err(self.error("expected an index or a quoted key")),
+        };
+
+        if self.peek() != Some(']') {
+            return Err(self.error("expected ']'"));
+        }
+        self.position += 1;
+
+        Ok(selector)
+    }
+
+    fn parse_quoted_key(&mut self) -> Result<Selector, PathError> {
+        // Skip the opening quote
+        self.position += 1;
+
+        let mut key = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(Selector::Key(key)),
+                Some('\\') => match self.advance() {
+                    Some(char @ ('"' | '\\')) => key.push(char),
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(char) => key.push(char),
+                None => return Err(self.error("unterminated key")),
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.path[self.position..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let char = self.peek()?;
+        self.position += char.len_utf8();
+        Some(char)
+    }
+
+    fn error(&self, message: &str) -> PathError {
+        PathError::Malformed {
+            position: self.position,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        let path = Path::parse("a.b[2].c").unwrap();
+        assert_eq!(
+            path.selectors(),
+            [
+                Selector::from("a"),
+                Selector::from("b"),
+                Selector::from(2),
+                Selector::from("c")
+            ]
+        );
+
+        let path = Path::parse(r#"[0]["with.dot"]["quote\"d"].snake_case-key"#).unwrap();
+        assert_eq!(
+            path.selectors(),
+            [
+                Selector::from(0),
+                Selector::from("with.dot"),
+                Selector::from("quote\"d"),
+                Selector::from("snake_case-key")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for path in ["a.b[2].c", r#"[0]["with.dot"]["back\\slash"].é"#, r#"[""]"#] {
+            assert_eq!(Path::parse(path).unwrap().to_string(), path);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_paths() {
+        assert_eq!(Path::parse(""), Err(PathError::Empty));
+        for path in [
+            ".a", "a.", "a..b", "a[", "a[]", "a[1", "a[x]", r#"a["b"#, "a b", "a[1]b",
+        ] {
+            assert!(
+                matches!(Path::parse(path), Err(PathError::Malformed { .. })),
+                "{} should be rejected",
+                path
+            );
+        }
+    }
+}
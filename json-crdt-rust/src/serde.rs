@@ -2,54 +2,216 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use bytes_varint::{VarIntError, VarIntSupport, VarIntSupportMut};
 use thiserror::Error;
 
-use crate::{ObjRef, Value};
+use crate::{types::ROOT_SEQUENCE, view::ChunkedTextMigration, ObjRef, Value};
 
 pub trait Serializable {
     fn serialize(&self) -> Result<Vec<u8>, SerializationError>;
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum SerializationError {
     #[error("malformed buffer {0}")]
     Malformed(String),
+
+    #[error("unsupported format version {found}, the latest supported one is {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+
+    #[error("{0:?} compression is not enabled")]
+    UnsupportedCompression(Compression),
 }
 
+// Serialized documents start with a magic number followed by the version of the format,
+// so that buffers written by newer versions of the library can be detected
+const MAGIC_NUMBER: &[u8; 4] = b"JCRD";
+pub const FORMAT_VERSION: u32 = 8;
+
+// Converts the regions written with `source_version` into the layout of the next version.
+// Every time the layout changes, the format version is bumped and a migration is added to
+// `MIGRATIONS`, so that older buffers can still be loaded.
+pub(crate) trait FormatMigration: Sync {
+    fn source_version(&self) -> u32;
+
+    fn migrate(&self, regions: BufferRegions) -> Result<BufferRegions, SerializationError>;
+}
+
+static MIGRATIONS: &[&dyn FormatMigration] = &[
+    &ChunkedTextMigration,
+    &CompatibleMigration { source_version: 2 },
+    &CompatibleMigration { source_version: 3 },
+    &CompatibleMigration { source_version: 4 },
+    &CompatibleMigration { source_version: 5 },
+    &CompatibleMigration { source_version: 6 },
+    &CompatibleMigration { source_version: 7 },
+];
+
 pub struct BufferRegions {
     pub view_cache: Vec<u8>,
     pub client_registry: Vec<u8>,
     pub operation_log: Vec<u8>,
 }
 
-pub fn serialize(regions: BufferRegions) -> Result<Vec<u8>, SerializationError> {
+// Compression applied to each region of a serialized document, stored in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl From<Compression> for u8 {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown compression: {}",
+                value
+            ))),
+        }
+    }
+}
+
+// Versions that only extended the format, so the regions of the previous one can be
+// read as they are. Version 3 added the compression flag to the header, version 4
+// the delta runs column strategy, version 5 the DeleteTextRanges action, version 6 the
+// expiration of map values, version 7 the annotations of texts and version 8 the
+// DeleteTextItems action.
+struct CompatibleMigration {
+    source_version: u32,
+}
+
+impl FormatMigration for CompatibleMigration {
+    fn source_version(&self) -> u32 {
+        self.source_version
+    }
+
+    fn migrate(&self, regions: BufferRegions) -> Result<BufferRegions, SerializationError> {
+        Ok(regions)
+    }
+}
+
+pub fn serialize(
+    regions: BufferRegions,
+    compression: Compression,
+) -> Result<Vec<u8>, SerializationError> {
     let mut buffer = BytesMut::new();
+    buffer.put_slice(MAGIC_NUMBER);
+    buffer.put_u32_varint(FORMAT_VERSION);
+    buffer.put_u8(compression.into());
+
+    write_region(&mut buffer, &regions.view_cache, compression, "view cache")?;
+    write_region(
+        &mut buffer,
+        &regions.client_registry,
+        compression,
+        "client registry",
+    )?;
+    write_region(
+        &mut buffer,
+        &regions.operation_log,
+        compression,
+        "operation log",
+    )?;
 
-    let view_cache_len: u32 = regions
-        .view_cache
-        .len()
-        .try_into()
-        .expect("view cache too large");
-    buffer.put_u32_varint(view_cache_len);
-    buffer.put_slice(&regions.view_cache);
+    Ok(buffer.to_vec())
+}
 
-    let client_registry_len: u32 = regions
-        .client_registry
-        .len()
-        .try_into()
-        .expect("client registry too large");
-    buffer.put_u32_varint(client_registry_len);
-    buffer.put_slice(&regions.client_registry);
+// Re-serializes a document with the given compression, without loading it
+pub fn recompress(buffer: Bytes, compression: Compression) -> Result<Vec<u8>, SerializationError> {
+    let reader = BufferReader::load(buffer)?;
+    serialize(
+        BufferRegions {
+            view_cache: reader.view_cache.to_vec(),
+            client_registry: reader.client_registry.to_vec(),
+            operation_log: reader.operation_log.to_vec(),
+        },
+        compression,
+    )
+}
+
+fn write_region(
+    buffer: &mut BytesMut,
+    region: &[u8],
+    compression: Compression,
+    name: &str,
+) -> Result<(), SerializationError> {
+    let compressed;
+    let region = match compression {
+        Compression::None => region,
+        Compression::Lz4 => {
+            compressed = compress_lz4(region)?;
+            &compressed
+        }
+    };
 
-    let operation_log_len: u32 = regions
-        .operation_log
+    let region_len: u32 = region
         .len()
         .try_into()
-        .expect("operation log too large");
-    buffer.put_u32_varint(operation_log_len);
-    buffer.put_slice(&regions.operation_log);
+        .unwrap_or_else(|_| panic!("{} too large", name));
+    buffer.put_u32_varint(region_len);
+    buffer.put_slice(region);
+    Ok(())
+}
 
+// Compressed regions start with their uncompressed length
+#[cfg(feature = "compression")]
+fn compress_lz4(region: &[u8]) -> Result<Vec<u8>, SerializationError> {
+    let mut buffer = BytesMut::new();
+    let region_len: u32 = region.len().try_into().expect("region too large");
+    buffer.put_u32_varint(region_len);
+    buffer.put_slice(&lz4_flex::block::compress(region));
     Ok(buffer.to_vec())
 }
 
+#[cfg(feature = "compression")]
+fn decompress_lz4(mut region: Bytes, name: &str) -> Result<Bytes, SerializationError> {
+    let len = region.get_u32_varint().map_err(|_| {
+        SerializationError::Malformed(format!("unable to read uncompressed {} len", name))
+    })?;
+
+    // LZ4 can't expand the data more than 255 times, so larger lengths can only come from a
+    // corrupted buffer and shouldn't be allocated
+    if len as usize > region.len().saturating_mul(255) {
+        return Err(SerializationError::Malformed(format!(
+            "uncompressed {} len is too large",
+            name
+        )));
+    }
+
+    let decompressed = lz4_flex::block::decompress(&region, len as usize)
+        .map_err(|err| SerializationError::Malformed(format!("{}: {}", name, err)))?;
+    if decompressed.len() != len as usize {
+        return Err(SerializationError::Malformed(format!(
+            "{} doesn't match its uncompressed len",
+            name
+        )));
+    }
+
+    Ok(Bytes::from(decompressed))
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_lz4(_region: &[u8]) -> Result<Vec<u8>, SerializationError> {
+    Err(SerializationError::UnsupportedCompression(Compression::Lz4))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_lz4(_region: Bytes, _name: &str) -> Result<Bytes, SerializationError> {
+    Err(SerializationError::UnsupportedCompression(Compression::Lz4))
+}
+
+#[derive(Clone)]
 pub struct BufferReader {
     view_cache: Bytes,
     client_registry: Bytes,
@@ -58,27 +220,68 @@ pub struct BufferReader {
 
 impl<'a> BufferReader {
     pub fn load(buffer: Bytes) -> Result<Self, SerializationError> {
-        let mut buffer = Bytes::from(buffer);
-        let view_cache_len = buffer.get_u32_varint().map_err(|_| {
-            SerializationError::Malformed("unable to read view_cache len".to_string())
-        })?;
-        let view_cache_bytes = buffer.copy_to_bytes(view_cache_len as usize);
-
-        let client_registry_len = buffer.get_u32_varint().map_err(|_| {
-            SerializationError::Malformed("unable to read client_registry len".to_string())
-        })?;
-        let client_registry_bytes = buffer.copy_to_bytes(client_registry_len as usize);
-
-        let operation_log_len = buffer.get_u32_varint().map_err(|_| {
-            SerializationError::Malformed("unable to read operation_log len".to_string())
-        })?;
-        let operation_log_bytes = buffer.copy_to_bytes(operation_log_len as usize);
-
-        Ok(Self {
+        Self::load_with_migrations(buffer, MIGRATIONS)
+    }
+
+    pub(crate) fn load_with_migrations(
+        mut buffer: Bytes,
+        migrations: &[&dyn FormatMigration],
+    ) -> Result<Self, SerializationError> {
+        let version = read_header(&mut buffer)?;
+        if version > FORMAT_VERSION {
+            return Err(SerializationError::UnsupportedVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+
+        // The compression flag was added in version 3
+        let compression = if version >= 3 {
+            Compression::try_from(read_u8(&mut buffer, "compression")?)?
+        } else {
+            Compression::None
+        };
+
+        let view_cache_bytes = read_compressed_region(&mut buffer, compression, "view_cache")?;
+        let client_registry_bytes =
+            read_compressed_region(&mut buffer, compression, "client_registry")?;
+        let operation_log_bytes =
+            read_compressed_region(&mut buffer, compression, "operation_log")?;
+
+        if buffer.has_remaining() {
+            return Err(SerializationError::Malformed(format!(
+                "{} unexpected bytes after the operation_log region",
+                buffer.remaining()
+            )));
+        }
+
+        let mut reader = Self {
             view_cache: view_cache_bytes,
             client_registry: client_registry_bytes,
             operation_log: operation_log_bytes,
-        })
+        };
+        for current_version in version..FORMAT_VERSION {
+            let migration = migrations
+                .iter()
+                .find(|migration| migration.source_version() == current_version)
+                .ok_or(SerializationError::UnsupportedVersion {
+                    found: version,
+                    supported: FORMAT_VERSION,
+                })?;
+
+            let regions = migration.migrate(BufferRegions {
+                view_cache: reader.view_cache.to_vec(),
+                client_registry: reader.client_registry.to_vec(),
+                operation_log: reader.operation_log.to_vec(),
+            })?;
+            reader = Self {
+                view_cache: Bytes::from(regions.view_cache),
+                client_registry: Bytes::from(regions.client_registry),
+                operation_log: Bytes::from(regions.operation_log),
+            };
+        }
+
+        Ok(reader)
     }
 
     pub fn view_cache(&'a self) -> Bytes {
@@ -94,43 +297,143 @@ impl<'a> BufferReader {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub(crate) enum ObjRefType {
-    Root,
-    Object,
+// Operations exchanged between documents, along with the clients needed to interpret them
+pub fn serialize_update(
+    client_registry: Vec<u8>,
+    operations: Vec<u8>,
+) -> Result<Vec<u8>, SerializationError> {
+    let mut buffer = BytesMut::new();
+
+    let client_registry_len: u32 = client_registry
+        .len()
+        .try_into()
+        .expect("client registry too large");
+    buffer.put_u32_varint(client_registry_len);
+    buffer.put_slice(&client_registry);
+    buffer.put_slice(&operations);
+
+    Ok(buffer.to_vec())
 }
 
-impl From<u8> for ObjRefType {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => ObjRefType::Root,
-            1 => ObjRefType::Object,
-            _ => panic!("unknown object reference type: {}", value),
-        }
+// Returns the client registry and operations regions of an update
+pub fn deserialize_update(mut buffer: Bytes) -> Result<(Bytes, Bytes), SerializationError> {
+    let client_registry = read_region(&mut buffer, "client_registry")?;
+
+    Ok((client_registry, buffer))
+}
+
+// Tells serialized documents apart from the buffers of `serialize_update`, which start with
+// the length of the client registry instead
+pub fn is_serialized_doc(buffer: &[u8]) -> bool {
+    buffer.starts_with(MAGIC_NUMBER)
+}
+
+// Returns the format version of a serialized document
+fn read_header(buffer: &mut Bytes) -> Result<u32, SerializationError> {
+    let magic_number = read_bytes(buffer, MAGIC_NUMBER.len() as u32, "magic number")?;
+    if magic_number.as_ref() != MAGIC_NUMBER {
+        return Err(SerializationError::Malformed(
+            "the buffer is not a serialized document".to_string(),
+        ));
     }
+
+    buffer
+        .get_u32_varint()
+        .map_err(|_| SerializationError::Malformed("unable to read format version".to_string()))
 }
 
-impl From<&ObjRefType> for u8 {
-    fn from(value: &ObjRefType) -> Self {
-        match value {
-            ObjRefType::Root => 0,
-            ObjRefType::Object => 1,
-        }
+// Reads a region prefixed by its length
+fn read_region(buffer: &mut Bytes, name: &str) -> Result<Bytes, SerializationError> {
+    let len = buffer
+        .get_u32_varint()
+        .map_err(|_| SerializationError::Malformed(format!("unable to read {} len", name)))?;
+
+    read_bytes(buffer, len, name)
+}
+
+fn read_compressed_region(
+    buffer: &mut Bytes,
+    compression: Compression,
+    name: &str,
+) -> Result<Bytes, SerializationError> {
+    let region = read_region(buffer, name)?;
+    match compression {
+        Compression::None => Ok(region),
+        Compression::Lz4 => decompress_lz4(region, name),
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+// The getters of `Buf` panic when the buffer is too short, which can't be ruled out for
+// buffers coming from other peers. These return an error instead.
+
+pub(crate) fn read_u8(buffer: &mut Bytes, name: &str) -> Result<u8, SerializationError> {
+    if !buffer.has_remaining() {
+        return Err(SerializationError::Malformed(format!(
+            "unable to read {}",
+            name
+        )));
+    }
+
+    Ok(buffer.get_u8())
+}
+
+pub(crate) fn read_f64(buffer: &mut Bytes, name: &str) -> Result<f64, SerializationError> {
+    if buffer.remaining() < 8 {
+        return Err(SerializationError::Malformed(format!(
+            "unable to read {}",
+            name
+        )));
+    }
+
+    Ok(buffer.get_f64())
+}
+
+// Lengths are checked against the remaining bytes before copying, so a corrupted length
+// can't trigger a huge allocation
+pub(crate) fn read_bytes(
+    buffer: &mut Bytes,
+    len: u32,
+    name: &str,
+) -> Result<Bytes, SerializationError> {
+    if buffer.remaining() < len as usize {
+        return Err(SerializationError::Malformed(format!(
+            "{} is truncated: expected {} bytes, found {}",
+            name,
+            len,
+            buffer.remaining()
+        )));
+    }
+
+    Ok(buffer.copy_to_bytes(len as usize))
+}
+
+pub(crate) fn read_string(
+    buffer: &mut Bytes,
+    len: u32,
+    name: &str,
+) -> Result<String, SerializationError> {
+    let bytes = read_bytes(buffer, len, name)?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| SerializationError::Malformed(format!("{} is not valid UTF-8", name)))
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub(crate) enum SelectorType {
     Key,
     Index,
 }
 
-impl From<u8> for SelectorType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for SelectorType {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => SelectorType::Key,
-            1 => SelectorType::Index,
-            _ => panic!("unknown selector type: {}", value),
+            0 => Ok(SelectorType::Key),
+            1 => Ok(SelectorType::Index),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown selector type: {}",
+                value
+            ))),
         }
     }
 }
@@ -145,40 +448,32 @@ impl From<&SelectorType> for u8 {
 }
 
 pub fn serialize_obj_ref(obj_ref: &crate::ObjRef, buf: &mut BytesMut) {
-    // TODO: probably we can use client_id 0 to mean "root"
     match obj_ref {
         crate::ObjRef::Root => {
-            buf.put_u8((&ObjRefType::Root).into());
+            buf.put_u32_varint(ROOT_SEQUENCE);
         }
         crate::ObjRef::Object(obj_ref) => {
-            buf.put_u8((&ObjRefType::Object).into());
-            buf.put_u32_varint(obj_ref.client_id);
             buf.put_u32_varint(obj_ref.sequence);
+            buf.put_u32_varint(obj_ref.client_id);
         }
     }
 }
 
 pub fn deserialize_obj_ref(buf: &mut Bytes) -> Result<ObjRef, SerializationError> {
-    let obj_ref_type = buf
-        .get_u8()
-        .try_into()
-        .map_err(|_| SerializationError::Malformed("unable to read obj_ref type".to_string()))?;
-
-    match obj_ref_type {
-        ObjRefType::Root => Ok(ObjRef::Root),
-        ObjRefType::Object => {
-            let client_id = buf.get_u32_varint().map_err(|_| {
-                SerializationError::Malformed("unable to read client_id".to_string())
-            })?;
-            let sequence = buf.get_u32_varint().map_err(|_| {
-                SerializationError::Malformed("unable to read sequence".to_string())
-            })?;
-            Ok(ObjRef::Object(crate::ObjId {
-                client_id,
-                sequence,
-            }))
-        }
+    let sequence = buf
+        .get_u32_varint()
+        .map_err(|_| SerializationError::Malformed("unable to read sequence".to_string()))?;
+    if sequence == ROOT_SEQUENCE {
+        return Ok(ObjRef::Root);
     }
+
+    let client_id = buf
+        .get_u32_varint()
+        .map_err(|_| SerializationError::Malformed("unable to read client_id".to_string()))?;
+    Ok(ObjRef::Object(crate::ObjId {
+        client_id,
+        sequence,
+    }))
 }
 
 pub fn serialize_selector(selector: &crate::Selector, buf: &mut BytesMut) {
@@ -198,10 +493,7 @@ pub fn serialize_selector(selector: &crate::Selector, buf: &mut BytesMut) {
 }
 
 pub fn deserialize_selector(buf: &mut Bytes) -> Result<crate::Selector, SerializationError> {
-    let selector_type = buf
-        .get_u8()
-        .try_into()
-        .map_err(|_| SerializationError::Malformed("unable to read selector type".to_string()))?;
+    let selector_type = SelectorType::try_from(read_u8(buf, "selector type")?)?;
 
     match selector_type {
         SelectorType::Index => {
@@ -214,11 +506,7 @@ pub fn deserialize_selector(buf: &mut Bytes) -> Result<crate::Selector, Serializ
             let key_len = buf
                 .get_u32_varint()
                 .map_err(|_| SerializationError::Malformed("unable to read key len".to_string()))?;
-            let key = buf.copy_to_bytes(key_len as usize);
-            Ok(crate::Selector::Key(
-                String::from_utf8(key.to_vec())
-                    .map_err(|_| SerializationError::Malformed("unable to read key".to_string()))?,
-            ))
+            Ok(crate::Selector::Key(read_string(buf, key_len, "key")?))
         }
     }
 }
@@ -229,6 +517,8 @@ enum ValueType {
     Double,
     Bool,
     Object,
+    Timestamp,
+    Null,
 }
 
 impl From<ValueType> for u8 {
@@ -239,19 +529,28 @@ impl From<ValueType> for u8 {
             ValueType::Double => 3,
             ValueType::Bool => 4,
             ValueType::Object => 5,
+            ValueType::Timestamp => 6,
+            ValueType::Null => 7,
         }
     }
 }
 
-impl From<u8> for ValueType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for ValueType {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => ValueType::String,
-            2 => ValueType::Int,
-            3 => ValueType::Double,
-            4 => ValueType::Bool,
-            5 => ValueType::Object,
-            _ => panic!("unknown value type: {}", value),
+            1 => Ok(ValueType::String),
+            2 => Ok(ValueType::Int),
+            3 => Ok(ValueType::Double),
+            4 => Ok(ValueType::Bool),
+            5 => Ok(ValueType::Object),
+            6 => Ok(ValueType::Timestamp),
+            7 => Ok(ValueType::Null),
+            _ => Err(SerializationError::Malformed(format!(
+                "unknown value type: {}",
+                value
+            ))),
         }
     }
 }
@@ -279,6 +578,13 @@ pub fn serialize_value(value: &Value, buf: &mut BytesMut) {
                 buf.put_u8(ValueType::Bool.into());
                 buf.put_u8(if *bool { 1 } else { 0 });
             }
+            crate::ScalarValue::Timestamp(timestamp) => {
+                buf.put_u8(ValueType::Timestamp.into());
+                buf.put_i64_varint(*timestamp);
+            }
+            crate::ScalarValue::Null => {
+                buf.put_u8(ValueType::Null.into());
+            }
         },
         Value::Object(object) => {
             buf.put_u8(ValueType::Object.into());
@@ -288,22 +594,15 @@ pub fn serialize_value(value: &Value, buf: &mut BytesMut) {
 }
 
 pub fn deserialize_value(buf: &mut Bytes) -> Result<Value, SerializationError> {
-    let value_type = buf
-        .get_u8()
-        .try_into()
-        .map_err(|_| SerializationError::Malformed("unable to read value type".to_string()))?;
+    let value_type = ValueType::try_from(read_u8(buf, "value type")?)?;
 
     match value_type {
         ValueType::String => {
             let string_len = buf.get_u32_varint().map_err(|_| {
                 SerializationError::Malformed("unable to read string len".to_string())
             })?;
-            let string = buf.copy_to_bytes(string_len as usize);
-            Ok(Value::Scalar(crate::ScalarValue::String(
-                String::from_utf8(string.to_vec()).map_err(|_| {
-                    SerializationError::Malformed("unable to read string".to_string())
-                })?,
-            )))
+            let string = read_string(buf, string_len, "string")?;
+            Ok(Value::Scalar(crate::ScalarValue::String(string)))
         }
         ValueType::Int => {
             let int = buf
@@ -312,16 +611,136 @@ pub fn deserialize_value(buf: &mut Bytes) -> Result<Value, SerializationError> {
             Ok(Value::Scalar(crate::ScalarValue::Int(int)))
         }
         ValueType::Double => {
-            let double = buf.get_f64();
+            let double = read_f64(buf, "double")?;
             Ok(Value::Scalar(crate::ScalarValue::Double(double)))
         }
         ValueType::Bool => {
-            let bool = buf.get_u8();
+            let bool = read_u8(buf, "bool")?;
             Ok(Value::Scalar(crate::ScalarValue::Bool(bool != 0)))
         }
+        ValueType::Timestamp => {
+            let timestamp = buf.get_i64_varint().map_err(|_| {
+                SerializationError::Malformed("unable to read timestamp".to_string())
+            })?;
+            Ok(Value::Scalar(crate::ScalarValue::Timestamp(timestamp)))
+        }
+        ValueType::Null => Ok(Value::Scalar(crate::ScalarValue::Null)),
         ValueType::Object => {
             let obj_ref = deserialize_obj_ref(buf)?;
             Ok(Value::Object(obj_ref))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct IdentityMigration(u32);
+
+    impl FormatMigration for IdentityMigration {
+        fn source_version(&self) -> u32 {
+            self.0
+        }
+
+        fn migrate(&self, regions: BufferRegions) -> Result<BufferRegions, SerializationError> {
+            Ok(regions)
+        }
+    }
+
+    fn test_regions() -> BufferRegions {
+        BufferRegions {
+            view_cache: vec![1],
+            client_registry: vec![2, 3],
+            operation_log: vec![],
+        }
+    }
+
+    // Versions before 3 have no compression flag
+    fn serialize_with_version(version: u32) -> Bytes {
+        let serialized = serialize(test_regions(), Compression::None).unwrap();
+
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(MAGIC_NUMBER);
+        buffer.put_u32_varint(version);
+        buffer.put_slice(&serialized[MAGIC_NUMBER.len() + 2..]);
+        buffer.freeze()
+    }
+
+    #[test]
+    fn test_load_rejects_future_versions() {
+        let result = BufferReader::load(serialize_with_version(FORMAT_VERSION + 1));
+
+        assert!(matches!(
+            result,
+            Err(SerializationError::UnsupportedVersion { found, supported })
+                if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_load_migrates_older_versions() {
+        let buffer = serialize_with_version(0);
+
+        assert!(matches!(
+            BufferReader::load_with_migrations(buffer.clone(), &[&IdentityMigration(1)]),
+            Err(SerializationError::UnsupportedVersion { found: 0, .. })
+        ));
+
+        let migrations: &[&dyn FormatMigration] = &[
+            &IdentityMigration(7),
+            &IdentityMigration(6),
+            &IdentityMigration(5),
+            &IdentityMigration(4),
+            &IdentityMigration(3),
+            &IdentityMigration(2),
+            &IdentityMigration(1),
+            &IdentityMigration(0),
+        ];
+        let reader = BufferReader::load_with_migrations(buffer, migrations).unwrap();
+        assert_eq!(reader.view_cache().as_ref(), &[1]);
+        assert_eq!(reader.client_registry().as_ref(), &[2, 3]);
+        assert!(reader.operation_log().is_empty());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_regions_are_decompressed_on_load() {
+        let mut regions = test_regions();
+        regions.operation_log = "repeated text ".repeat(100).into_bytes();
+
+        let buffer = serialize(regions, Compression::Lz4).unwrap();
+        assert!(buffer.len() < 200);
+
+        let reader = BufferReader::load(Bytes::from(buffer)).unwrap();
+        assert_eq!(reader.view_cache().as_ref(), &[1]);
+        assert_eq!(reader.client_registry().as_ref(), &[2, 3]);
+        assert_eq!(
+            reader.operation_log(),
+            "repeated text ".repeat(100).as_bytes()
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_load_rejects_oversized_uncompressed_lengths() {
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(MAGIC_NUMBER);
+        buffer.put_u32_varint(FORMAT_VERSION);
+        buffer.put_u8(Compression::Lz4.into());
+        buffer.put_u32_varint(6);
+        buffer.put_u32_varint(u32::MAX);
+        buffer.put_slice(&[0, 0]);
+
+        let result = BufferReader::load(buffer.freeze());
+
+        assert!(matches!(result, Err(SerializationError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_load_rejects_buffers_without_magic_number() {
+        let result = BufferReader::load(Bytes::from_static(&[1, 0, 0, 0]));
+
+        assert!(matches!(result, Err(SerializationError::Malformed(_))));
+    }
+}
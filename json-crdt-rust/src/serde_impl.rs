@@ -0,0 +1,278 @@
+// Integration with the serde framework, the binary format of the documents is in `serde.rs`
+
+use std::fmt;
+
+use ::serde::{
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    ser::SerializeMap,
+    Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    transaction::Transaction, DataMapValue, ObjRef, ScalarValue, Selector, TransactionError,
+};
+
+// Indexes are serialized as numbers, formats that only support string keys convert them
+impl Serialize for Selector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Selector::Key(key) => serializer.serialize_str(key),
+            Selector::Index(index) => serializer.serialize_u64(*index as u64),
+        }
+    }
+}
+
+// Timestamps are serialized as milliseconds since the unix epoch
+impl Serialize for ScalarValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ScalarValue::String(string) => serializer.serialize_str(string),
+            ScalarValue::Int(int) => serializer.serialize_i32(*int),
+            ScalarValue::Double(double) => serializer.serialize_f64(*double),
+            ScalarValue::Bool(bool) => serializer.serialize_bool(*bool),
+            ScalarValue::Timestamp(timestamp) => serializer.serialize_i64(*timestamp),
+            ScalarValue::Null => serializer.serialize_unit(),
+        }
+    }
+}
+
+// Makes the result of `as_map` serializable, e.g. `serde_json::to_string(&doc.as_map()?)`
+impl Serialize for DataMapValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DataMapValue::String(string) => serializer.serialize_str(string),
+            DataMapValue::Int(int) => serializer.serialize_i32(**int),
+            DataMapValue::Double(double) => serializer.serialize_f64(**double),
+            DataMapValue::Bool(bool) => serializer.serialize_bool(**bool),
+            DataMapValue::Timestamp(timestamp) => serializer.serialize_i64(**timestamp),
+            DataMapValue::Null => serializer.serialize_unit(),
+            DataMapValue::Map(map) => {
+                let mut serializer = serializer.serialize_map(Some(map.len()))?;
+                for (selector, value) in map {
+                    serializer.serialize_entry(selector, value)?;
+                }
+                serializer.end()
+            }
+            DataMapValue::Text(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    // Writes the entries of a serde map (for example a JSON object) into the given map.
+    // Nested maps are created as new objects, and sequences become maps keyed by index.
+    // Strings are imported as scalar values, as they can't be told apart from texts.
+    // Integers that don't fit an i32 are imported as doubles.
+    pub fn import<'de, D: Deserializer<'de>>(
+        &mut self,
+        object: ObjRef,
+        deserializer: D,
+    ) -> Result<(), TransactionError> {
+        let mut failure = None;
+        let result = deserializer.deserialize_map(ImportVisitor {
+            txn: self,
+            object,
+            failure: &mut failure,
+        });
+        result.map_err(|error| {
+            failure.unwrap_or_else(|| TransactionError::ImportError {
+                object,
+                message: error.to_string(),
+            })
+        })
+    }
+}
+
+// Imports the entries of a map or sequence into an existing object
+struct ImportVisitor<'t, 'a> {
+    txn: &'t mut Transaction<'a>,
+    object: ObjRef,
+    // Deserializers only keep the message of the errors, so the first one of the
+    // transaction is kept here
+    failure: &'t mut Option<TransactionError>,
+}
+
+impl<'de> Visitor<'de> for ImportVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map or a sequence")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(selector) = map.next_key_seed(SelectorSeed)? {
+            map.next_value_seed(ValueSeed {
+                txn: &mut *self.txn,
+                object: self.object,
+                selector,
+                failure: &mut *self.failure,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut index = 0;
+        loop {
+            let seed = ValueSeed {
+                txn: &mut *self.txn,
+                object: self.object,
+                selector: Selector::Index(index),
+                failure: &mut *self.failure,
+            };
+            if seq.next_element_seed(seed)?.is_none() {
+                return Ok(());
+            }
+            index += 1;
+        }
+    }
+}
+
+// Imports a single value under the given key
+struct ValueSeed<'t, 'a> {
+    txn: &'t mut Transaction<'a>,
+    object: ObjRef,
+    selector: Selector,
+    failure: &'t mut Option<TransactionError>,
+}
+
+impl ValueSeed<'_, '_> {
+    fn set<E: de::Error>(self, value: ScalarValue) -> Result<(), E> {
+        let result = self.txn.set_scalar(self.object, self.selector, value);
+        keep_failure(self.failure, result)
+    }
+
+    fn create_map<E: de::Error>(&mut self) -> Result<ObjRef, E> {
+        let result = self.txn.create_map(self.object, self.selector.clone());
+        keep_failure(self.failure, result)
+    }
+}
+
+fn keep_failure<T, E: de::Error>(
+    failure: &mut Option<TransactionError>,
+    result: Result<T, TransactionError>,
+) -> Result<T, E> {
+    result.map_err(|error| {
+        let message = E::custom(&error);
+        *failure = Some(error);
+        message
+    })
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a value")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<(), E> {
+        self.set(ScalarValue::Bool(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<(), E> {
+        match i32::try_from(value) {
+            Ok(int) => self.set(ScalarValue::Int(int)),
+            Err(_) => self.set(ScalarValue::Double(value as f64)),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<(), E> {
+        match i32::try_from(value) {
+            Ok(int) => self.set(ScalarValue::Int(int)),
+            Err(_) => self.set(ScalarValue::Double(value as f64)),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<(), E> {
+        self.set(ScalarValue::Double(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<(), E> {
+        self.set(ScalarValue::String(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<(), E> {
+        self.set(ScalarValue::String(value))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.set(ScalarValue::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        self.set(ScalarValue::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, map: A) -> Result<(), A::Error> {
+        let object = self.create_map()?;
+        ImportVisitor {
+            txn: self.txn,
+            object,
+            failure: self.failure,
+        }
+        .visit_map(map)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, seq: A) -> Result<(), A::Error> {
+        let object = self.create_map()?;
+        ImportVisitor {
+            txn: self.txn,
+            object,
+            failure: self.failure,
+        }
+        .visit_seq(seq)
+    }
+}
+
+// Keys are strings, but formats like CBOR also allow integers, which become indexes
+struct SelectorSeed;
+
+impl<'de> DeserializeSeed<'de> for SelectorSeed {
+    type Value = Selector;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Selector, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SelectorSeed {
+    type Value = Selector;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string or an unsigned integer key")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Selector, E> {
+        Ok(Selector::Key(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Selector, E> {
+        Ok(Selector::Key(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Selector, E> {
+        usize::try_from(value)
+            .map(Selector::Index)
+            .map_err(|_| E::custom("index too large"))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Selector, E> {
+        usize::try_from(value)
+            .map(Selector::Index)
+            .map_err(|_| E::custom("negative index"))
+    }
+}
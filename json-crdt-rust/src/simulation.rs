@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+
+use crate::{Doc, DocError, DocVersion};
+
+// Conditions applied to every message exchanged in a `Simulation`.
+// Times are in virtual milliseconds, rates are probabilities between 0 and 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConditions {
+    pub min_latency: u64,
+    pub max_latency: u64,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            min_latency: 10,
+            max_latency: 50,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SimulationStats {
+    pub messages_sent: usize,
+    pub messages_delivered: usize,
+    // Lost to the drop rate or to a partition
+    pub messages_dropped: usize,
+    pub messages_duplicated: usize,
+    // Size of the encoded operations put on the network, duplicates included
+    pub update_bytes: usize,
+    pub operations_applied: usize,
+    // Delivered updates whose operations were all known by the receiver already
+    pub redundant_updates: usize,
+}
+
+// Deterministic network simulator, driving multiple documents through the sync APIs.
+//
+// Each message carries the version of the sender and the operations that the receiver is
+// missing according to the last version it announced, so lost messages are recovered by
+// the next broadcast. Messages are delivered by advancing the virtual clock, and the whole
+// schedule (latencies, drops and duplicates) only depends on the seed. Operations still get
+// their timestamps from the system clock.
+pub struct Simulation {
+    peers: Vec<Peer>,
+    conditions: NetworkConditions,
+    now: u64,
+    // Messages in flight, ordered by delivery time and then by sending order
+    in_flight: BTreeMap<(u64, u64), Message>,
+    next_message: u64,
+    // Group of each peer, messages can only be delivered within the same group
+    partitions: Option<Vec<usize>>,
+    rng: SimulationRng,
+    stats: SimulationStats,
+}
+
+struct Peer {
+    doc: Doc,
+    // Latest version announced by each other peer
+    known_versions: Vec<DocVersion>,
+}
+
+#[derive(Clone)]
+struct Message {
+    from: usize,
+    to: usize,
+    version: DocVersion,
+    update: Option<Vec<u8>>,
+}
+
+impl Simulation {
+    pub fn new(peers: usize, conditions: NetworkConditions, seed: u64) -> Self {
+        let peers = (0..peers)
+            .map(|index| Peer {
+                doc: Doc::new_with_timestamp(format!("peer-{}", index), 0),
+                known_versions: vec![DocVersion::new(); peers],
+            })
+            .collect();
+
+        Self {
+            peers,
+            conditions,
+            now: 0,
+            in_flight: BTreeMap::new(),
+            next_message: 0,
+            partitions: None,
+            rng: SimulationRng(seed),
+            stats: SimulationStats::default(),
+        }
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn doc(&self, peer: usize) -> &Doc {
+        &self.peers[peer].doc
+    }
+
+    pub fn doc_mut(&mut self, peer: usize) -> &mut Doc {
+        &mut self.peers[peer].doc
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn stats(&self) -> &SimulationStats {
+        &self.stats
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.conditions = conditions;
+    }
+
+    // Splits the peers in groups that can't reach each other. Messages crossing the
+    // partition are lost, including the ones already in flight.
+    // Peers that are not part of any group are isolated.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let mut partitions: Vec<usize> = (0..self.peers.len())
+            .map(|peer| groups.len() + peer)
+            .collect();
+        for (group, peers) in groups.iter().enumerate() {
+            for peer in peers.iter() {
+                partitions[*peer] = group;
+            }
+        }
+
+        self.partitions = Some(partitions);
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions = None;
+    }
+
+    // Sends the operations that each other peer is missing, or just the version of the
+    // peer if they already know about all of them
+    pub fn broadcast(&mut self, peer: usize) -> Result<(), DocError> {
+        let version = self.peers[peer].doc.version()?;
+
+        for to in 0..self.peers.len() {
+            if to == peer {
+                continue;
+            }
+
+            let known_version = &self.peers[peer].known_versions[to];
+            let update = if known_version.includes(&version) {
+                None
+            } else {
+                Some(
+                    self.peers[peer]
+                        .doc
+                        .encode_new_operations_since(known_version)?,
+                )
+            };
+
+            self.send(Message {
+                from: peer,
+                to,
+                version: version.clone(),
+                update,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn broadcast_all(&mut self) -> Result<(), DocError> {
+        for peer in 0..self.peers.len() {
+            self.broadcast(peer)?;
+        }
+
+        Ok(())
+    }
+
+    // Moves the virtual clock forward, delivering the messages that arrive in the meantime
+    pub fn advance(&mut self, millis: u64) -> Result<(), DocError> {
+        let until = self.now + millis;
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            let (deliver_at, _) = *entry.key();
+            if deliver_at > until {
+                break;
+            }
+
+            let message = entry.remove();
+            self.now = deliver_at;
+            self.deliver(message)?;
+        }
+
+        self.now = until;
+        Ok(())
+    }
+
+    // Delivers every message in flight
+    pub fn run_until_idle(&mut self) -> Result<(), DocError> {
+        while let Some((deliver_at, _)) = self.in_flight.keys().next().copied() {
+            self.advance(deliver_at - self.now)?;
+        }
+
+        Ok(())
+    }
+
+    // True if all the peers include the same operations
+    pub fn is_converged(&self) -> Result<bool, DocError> {
+        let Some((first, others)) = self.peers.split_first() else {
+            return Ok(true);
+        };
+
+        let version = first.doc.version()?;
+        for peer in others {
+            if peer.doc.version()? != version {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Lets every peer broadcast until they converge, returning the number of rounds needed
+    // or `None` if they didn't converge within `max_rounds`
+    pub fn sync_until_converged(&mut self, max_rounds: usize) -> Result<Option<usize>, DocError> {
+        for round in 0..max_rounds {
+            if self.is_converged()? {
+                return Ok(Some(round));
+            }
+
+            self.broadcast_all()?;
+            self.run_until_idle()?;
+        }
+
+        Ok(self.is_converged()?.then_some(max_rounds))
+    }
+
+    fn send(&mut self, message: Message) {
+        self.stats.messages_sent += 1;
+
+        let copies = if self.rng.chance(self.conditions.duplicate_rate) {
+            self.stats.messages_duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            if let Some(update) = &message.update {
+                self.stats.update_bytes += update.len();
+            }
+
+            if self.rng.chance(self.conditions.drop_rate) {
+                self.stats.messages_dropped += 1;
+                continue;
+            }
+
+            let latency = self
+                .rng
+                .between(self.conditions.min_latency, self.conditions.max_latency);
+            self.in_flight
+                .insert((self.now + latency, self.next_message), message.clone());
+            self.next_message += 1;
+        }
+    }
+
+    fn deliver(&mut self, message: Message) -> Result<(), DocError> {
+        if let Some(partitions) = &self.partitions {
+            if partitions[message.from] != partitions[message.to] {
+                self.stats.messages_dropped += 1;
+                return Ok(());
+            }
+        }
+
+        self.stats.messages_delivered += 1;
+
+        let peer = &mut self.peers[message.to];
+        if let Some(update) = message.update {
+            let report = peer.doc.apply_encoded_operations(update.into())?;
+            self.stats.operations_applied += report.applied_operations;
+            if report.applied_operations == 0 {
+                self.stats.redundant_updates += 1;
+            }
+        }
+
+        // Messages can be reordered, so an older version must not replace a newer one
+        peer.known_versions[message.from].merge(&message.version);
+
+        Ok(())
+    }
+}
+
+// SplitMix64, which is good enough for scheduling and accepts any seed
+struct SimulationRng(u64);
+
+impl SimulationRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+        value ^ (value >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+
+        // Uses the top 53 bits, which is the precision of a f64
+        let value = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        value < probability
+    }
+
+    fn between(&mut self, min: u64, max: u64) -> u64 {
+        if max <= min {
+            return min;
+        }
+
+        min + self.next() % (max - min + 1)
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use super::traits::{Storage, StorageError};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const OPS_FILE: &str = "ops";
+
+// Stores the document in a directory, with the snapshot in a file and the appended operations
+// in another one, each prefixed by its length (u32, little endian).
+// Snapshots are written to a temporary file and then renamed, so a crash leaves either the old
+// or the new one. If it happens before the operations are dropped, they are loaded again on
+// top of the new snapshot, which is harmless as known operations are ignored.
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    // Creates the directory if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn ops_path(&self) -> PathBuf {
+        self.dir.join(OPS_FILE)
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT_FILE)
+    }
+}
+
+impl Storage for FileStorage {
+    fn append_ops(&mut self, ops: &[u8]) -> Result<(), StorageError> {
+        let ops_len: u32 = ops
+            .len()
+            .try_into()
+            .map_err(|_| StorageError::Corrupted("operations too large".to_string()))?;
+
+        let mut record = Vec::with_capacity(4 + ops.len());
+        record.extend_from_slice(&ops_len.to_le_bytes());
+        record.extend_from_slice(ops);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.ops_path())?;
+        file.write_all(&record)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn load_ops(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        let buffer = match fs::read(self.ops_path()) {
+            Ok(buffer) => buffer,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut ops = Vec::new();
+        let mut remaining = buffer.as_slice();
+        while !remaining.is_empty() {
+            let (len, rest) = remaining.split_first_chunk::<4>().ok_or_else(|| {
+                StorageError::Corrupted("truncated operations length".to_string())
+            })?;
+            let len = u32::from_le_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err(StorageError::Corrupted(format!(
+                    "truncated operations, expected {} bytes but found {}",
+                    len,
+                    rest.len()
+                )));
+            }
+
+            let (record, rest) = rest.split_at(len);
+            ops.push(record.to_vec());
+            remaining = rest;
+        }
+
+        Ok(ops)
+    }
+
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> Result<(), StorageError> {
+        let temporary_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(snapshot)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, self.snapshot_path())?;
+
+        match fs::remove_file(self.ops_path()) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.snapshot_path()) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
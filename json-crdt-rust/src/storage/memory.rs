@@ -0,0 +1,44 @@
+use super::traits::{Storage, StorageError};
+
+// Keeps everything in memory, mostly useful for tests.
+// Clones share nothing, so a clone can be used to reopen a document later.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    snapshot: Option<Vec<u8>>,
+    ops: Vec<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot_len(&self) -> Option<usize> {
+        self.snapshot.as_ref().map(|snapshot| snapshot.len())
+    }
+
+    pub fn ops_len(&self) -> usize {
+        self.ops.len()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append_ops(&mut self, ops: &[u8]) -> Result<(), StorageError> {
+        self.ops.push(ops.to_vec());
+        Ok(())
+    }
+
+    fn load_ops(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(self.ops.clone())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> Result<(), StorageError> {
+        self.snapshot = Some(snapshot.to_vec());
+        self.ops.clear();
+        Ok(())
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.snapshot.clone())
+    }
+}
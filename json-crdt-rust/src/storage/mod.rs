@@ -0,0 +1,9 @@
+mod file;
+mod memory;
+mod persistent;
+mod traits;
+
+pub use file::*;
+pub use memory::*;
+pub use persistent::*;
+pub use traits::*;
@@ -0,0 +1,135 @@
+use bytes::Bytes;
+use chrono::Utc;
+
+use crate::{
+    transaction::Transaction, Doc, DocError, GlobalClientId, MergeReport, Timestamp,
+    TransactionError, WritableDoc,
+};
+
+use super::traits::{Storage, StorageError};
+
+const DEFAULT_SNAPSHOT_INTERVAL: usize = 100;
+
+// Document whose changes are written to a `Storage` as soon as they are made.
+// Every change appends the new operations, and once `snapshot_interval` of them have been
+// appended the whole document is saved as a new snapshot, so loading stays fast.
+pub struct PersistentDoc<S: Storage> {
+    doc: Doc,
+    storage: S,
+    snapshot_interval: usize,
+    // Operations appended since the last snapshot
+    appended_ops: usize,
+}
+
+impl<S: Storage> PersistentDoc<S> {
+    pub fn open(client_id: GlobalClientId, storage: S) -> Result<Self, StorageError> {
+        let timestamp = Utc::now().timestamp_millis() as u64;
+        Self::open_with_timestamp(client_id, timestamp, storage)
+    }
+
+    // Loads the document from the storage, or creates a new one if it's empty
+    pub fn open_with_timestamp(
+        client_id: GlobalClientId,
+        timestamp: Timestamp,
+        storage: S,
+    ) -> Result<Self, StorageError> {
+        let mut doc = match storage.load_snapshot()? {
+            Some(snapshot) => Doc::load_with_timestamp(client_id, timestamp, snapshot.into())?,
+            None => Doc::new_with_timestamp(client_id, timestamp),
+        };
+
+        let ops = storage.load_ops()?;
+        for increment in &ops {
+            doc.load_incremental(Bytes::copy_from_slice(increment))?;
+        }
+
+        Ok(Self {
+            doc,
+            storage,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            appended_ops: ops.len(),
+        })
+    }
+
+    pub fn doc(&self) -> &Doc {
+        &self.doc
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    pub fn set_snapshot_interval(&mut self, interval: usize) {
+        self.snapshot_interval = interval;
+    }
+
+    // Runs the callback in a transaction, which is committed and persisted afterwards.
+    // Transactions can't be rolled back, so the changes made before an error are persisted
+    // as well, to keep the storage in sync with the document.
+    pub fn transact<T>(
+        &mut self,
+        callback: impl FnOnce(&mut Transaction) -> Result<T, TransactionError>,
+    ) -> Result<T, StorageError> {
+        let mut txn = self.doc.transaction();
+        let result = callback(&mut txn);
+        txn.commit()?;
+
+        self.persist()?;
+        Ok(result?)
+    }
+
+    pub fn merge(&mut self, other: &Doc) -> Result<MergeReport, StorageError> {
+        let report = self.doc.merge_with_report(other)?;
+        self.persist()?;
+        Ok(report)
+    }
+
+    pub fn apply_encoded_operations(&mut self, buffer: Bytes) -> Result<MergeReport, StorageError> {
+        let report = self.doc.apply_encoded_operations(buffer)?;
+        self.persist()?;
+        Ok(report)
+    }
+
+    pub fn compact(&mut self) -> Result<usize, StorageError> {
+        let removed = self.doc.compact()?;
+        self.persist()?;
+        Ok(removed)
+    }
+
+    // Replaces the snapshot with the current document
+    pub fn save_snapshot(&mut self) -> Result<(), StorageError> {
+        let snapshot = self.doc.save()?;
+        self.storage.save_snapshot(&snapshot)?;
+        self.appended_ops = 0;
+        Ok(())
+    }
+
+    fn persist(&mut self) -> Result<(), StorageError> {
+        if !self.doc.is_dirty() {
+            return Ok(());
+        }
+
+        if self.appended_ops >= self.snapshot_interval {
+            return self.save_snapshot();
+        }
+
+        match self.doc.save_incremental() {
+            Ok(ops) => {
+                if let Err(error) = self.storage.append_ops(&ops) {
+                    // The operations are considered as saved by now, so the next change has
+                    // to write a snapshot to include them
+                    self.appended_ops = self.snapshot_interval;
+                    return Err(error);
+                }
+                self.appended_ops += 1;
+                Ok(())
+            }
+            Err(DocError::FullSaveRequired) => self.save_snapshot(),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+use crate::{DocError, TransactionError};
+
+// Where a `PersistentDoc` keeps its data: a full snapshot of the document, followed by the
+// operations appended since then, as encoded by `Doc::save_incremental`
+pub trait Storage {
+    fn append_ops(&mut self, ops: &[u8]) -> Result<(), StorageError>;
+    // Operations appended since the last snapshot, in the order they were appended
+    fn load_ops(&self) -> Result<Vec<Vec<u8>>, StorageError>;
+    // Replaces the snapshot, and drops the operations appended so far as they are part of it
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> Result<(), StorageError>;
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, StorageError>;
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StorageError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("document error: {0}")]
+    DocError(#[from] DocError),
+
+    #[error("transaction error: {0}")]
+    TransactionError(#[from] TransactionError),
+
+    #[error("corrupted storage: {0}")]
+    Corrupted(String),
+}
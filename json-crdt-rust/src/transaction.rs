@@ -1,18 +1,27 @@
 use crate::{
     client_registry::{self, ClientRegistry},
+    crdt::text::TextCRDT,
     operation_log::{OperationLog, OperationLogError},
     view::{View, ViewError},
-    CreateMapAction, CreateTextAction, DeleteMapValueAction, DeleteTextAction, InsertTextAction,
-    ObjRef, ObjectValue, Operation, OperationAction, OperationId, ScalarValue, Selector,
-    SetMapValueAction, Value,
+    AnchorBias, CreateAnnotationAction, CreateMapAction, CreateTextAction, DeleteMapValueAction,
+    DeleteTextItemsAction, FormattedId, InsertTextAction, MapBlockId, MapValueEntry,
+    MoveMapValueAction, MoveObjectAction, ObjRef, ObjectValue, Operation, OperationAction,
+    OperationId, Path, PathError, ScalarValue, Selector, SetMapValueAction, SetMapValuesAction,
+    Timestamp, Validator, Value, ValueKind,
 };
-use chrono::Utc;
+use rustc_hash::FxHashMap;
+use serde_json::Value as JsonValue;
+use similar::{Algorithm, DiffTag};
+use std::{borrow::Cow, ops::Range, time::Duration};
 use thiserror::Error;
 
 pub struct Transaction<'a> {
     op_log: &'a mut OperationLog,
     view: &'a mut View,
     client_registry: &'a mut ClientRegistry,
+    // Index of the first operation created by the transaction
+    start: usize,
+    validator: Option<&'a Validator>,
 }
 
 impl<'a> Transaction<'a> {
@@ -22,23 +31,57 @@ impl<'a> Transaction<'a> {
         client_registry: &'a mut ClientRegistry,
     ) -> Self {
         Self {
+            start: op_log.len(),
             op_log,
             view,
             client_registry,
+            validator: None,
         }
     }
 
+    pub fn with_validator(mut self, validator: Option<&'a Validator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
     pub fn set_scalar<TRef: Into<ObjRef>, TSelector: Into<Selector>, TValue: Into<ScalarValue>>(
         &mut self,
         obj: TRef,
         sel: TSelector,
         value: TValue,
     ) -> Result<(), TransactionError> {
-        let obj: ObjRef = obj.into();
-        let sel: Selector = sel.into();
-        let value: ScalarValue = value.into();
+        self.set_expiring_scalar(obj.into(), sel.into(), value.into(), None)
+    }
 
-        let map = self.view.get_object_mut(&obj)?;
+    // The value disappears once the `ttl` has elapsed on the clock of the reading document,
+    // as if it was deleted, without any further operation. It survives merges like any other
+    // value until then, and its contents are dropped by `Doc::compact` afterwards.
+    pub fn set_scalar_with_ttl<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+    >(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+        value: TValue,
+        ttl: Duration,
+    ) -> Result<(), TransactionError> {
+        let expires_at = self
+            .view
+            .now()
+            .saturating_add(ttl.as_millis().try_into().unwrap_or(Timestamp::MAX));
+        self.set_expiring_scalar(obj.into(), sel.into(), value.into(), Some(expires_at))
+    }
+
+    fn set_expiring_scalar(
+        &mut self,
+        obj: ObjRef,
+        sel: Selector,
+        value: ScalarValue,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), TransactionError> {
+        let map = self.view.get_object_mut(obj)?;
         let (block_id, block_parents) = match map {
             Some(ObjectValue::Map(map)) => {
                 let map_id = map.next_id();
@@ -46,10 +89,11 @@ impl<'a> Transaction<'a> {
                 (map_id, parents)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected map, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -60,12 +104,194 @@ impl<'a> Transaction<'a> {
                 id: block_id,
                 parents: block_parents,
                 value: Value::Scalar(value),
+                expires_at,
+            }))
+        })?;
+
+        Ok(())
+    }
+
+    // Sets the value at the end of the path, creating the missing maps along the way
+    pub fn set_path<TValue: Into<ScalarValue>>(
+        &mut self,
+        path: &Path,
+        value: TValue,
+    ) -> Result<(), TransactionError> {
+        let (last, parents) = path.split_last().ok_or(PathError::Empty)?;
+
+        let mut object = ObjRef::Root;
+        for selector in parents {
+            object = match self.view.get(object, selector.clone())? {
+                Some(Value::Object(child)) => *child,
+                Some(value) => {
+                    return Err(TransactionError::IncompatibleValue {
+                        object,
+                        selector: selector.clone(),
+                        expected: ValueKind::Map,
+                        actual: value.into(),
+                    })
+                }
+                None => self.create_map(object, selector)?,
+            };
+        }
+
+        self.set_scalar(object, last, value)
+    }
+
+    // Sets several keys of a map with a single operation, which is smaller than
+    // calling `set_scalar` for each key. If a key is repeated, the last value wins.
+    pub fn set_many<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+        TValues: IntoIterator<Item = (TSelector, TValue)>,
+    >(
+        &mut self,
+        obj: TRef,
+        values: TValues,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+
+        let mut values_by_key: FxHashMap<Selector, ScalarValue> = FxHashMap::default();
+        let mut keys = Vec::new();
+        for (sel, value) in values {
+            let sel: Selector = sel.into();
+            if values_by_key.insert(sel.clone(), value.into()).is_none() {
+                keys.push(sel);
+            }
+        }
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let map = self.view.get_object_mut(obj)?;
+        let (block_id, entries) = match map {
+            Some(ObjectValue::Map(map)) => {
+                let block_id = map.next_id();
+                let mut entries = Vec::with_capacity(keys.len());
+                for (index, sel) in keys.into_iter().enumerate() {
+                    if index > 0 {
+                        map.next_id();
+                    }
+
+                    let value = values_by_key
+                        .remove(&sel)
+                        .expect("value should exist for key");
+                    entries.push(MapValueEntry {
+                        parents: map.get_latest_ids(&sel),
+                        selector: sel,
+                        value: Value::Scalar(value),
+                    });
+                }
+                (block_id, entries)
+            }
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
+            }
+        };
+
+        self.create_action(|_self| {
+            Ok(OperationAction::SetMapValues(SetMapValuesAction {
+                object: obj,
+                id: block_id,
+                entries,
             }))
         })?;
 
         Ok(())
     }
 
+    // Makes the map match the given JSON object with the fewest operations: keys missing from
+    // the JSON are deleted, changed scalars are set and nested objects are updated recursively.
+    // Arrays are maps keyed by index, as in `import`. Strings are stored as scalars, unless the
+    // key already holds a text, which is then updated with `update_text`.
+    pub fn update_map_from_json<TRef: Into<ObjRef>>(
+        &mut self,
+        obj: TRef,
+        json: &JsonValue,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let entries: Vec<(Selector, &JsonValue)> = match json {
+            JsonValue::Object(object) => object
+                .iter()
+                .map(|(key, value)| (Selector::Key(key.clone()), value))
+                .collect(),
+            JsonValue::Array(array) => array
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (Selector::Index(index), value))
+                .collect(),
+            _ => {
+                return Err(TransactionError::UnexpectedJson {
+                    object: obj,
+                    found: json.clone(),
+                })
+            }
+        };
+
+        let now = self.view.now();
+        let mut current: FxHashMap<Selector, Value> = match self.view.get_object(obj)? {
+            Some(ObjectValue::Map(map)) => map
+                .iter(now)
+                .map(|(selector, value)| (selector.clone(), value.clone()))
+                .collect(),
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value,
+                ))
+            }
+        };
+
+        for (selector, json_value) in entries {
+            let current_value = current.remove(&selector);
+            let current_object = match &current_value {
+                Some(Value::Object(object)) => self.view.get_object(object)?,
+                _ => None,
+            };
+
+            match (json_value, current_object) {
+                (JsonValue::Object(_) | JsonValue::Array(_), Some(ObjectValue::Map(_))) => {
+                    let child = current_value
+                        .and_then(|value| value.into_object().ok())
+                        .expect("key holds a map");
+                    self.update_map_from_json(child, json_value)?;
+                }
+                (JsonValue::Object(_) | JsonValue::Array(_), _) => {
+                    let child = self.create_map(obj, selector)?;
+                    self.update_map_from_json(child, json_value)?;
+                }
+                (JsonValue::String(string), Some(ObjectValue::Text(_))) => {
+                    let text = current_value
+                        .and_then(|value| value.into_object().ok())
+                        .expect("key holds a text");
+                    self.update_text(text, string)?;
+                }
+                (_, _) => {
+                    let scalar = json_to_scalar(json_value);
+                    if current_value.and_then(|value| value.into_scalar().ok())
+                        != Some(scalar.clone())
+                    {
+                        self.set_scalar(obj, selector, scalar)?;
+                    }
+                }
+            }
+        }
+
+        // Keys that are not in the JSON anymore
+        for selector in current.into_keys() {
+            self.delete(obj, selector)?;
+        }
+
+        Ok(())
+    }
+
     pub fn delete<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
         &mut self,
         obj: TRef,
@@ -74,17 +300,18 @@ impl<'a> Transaction<'a> {
         let obj: ObjRef = obj.into();
         let sel: Selector = sel.into();
 
-        let map = self.view.get_object_mut(&obj)?;
+        let map = self.view.get_object_mut(obj)?;
         let block_parents = match map {
             Some(ObjectValue::Map(map)) => {
                 let parents = map.get_latest_ids(&sel);
                 parents
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected map, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -99,6 +326,185 @@ impl<'a> Transaction<'a> {
         Ok(())
     }
 
+    // Writes the value of one of the conflicts returned by `Doc::conflicts` again, superseding
+    // all the others. Objects are moved back under the key, so their content is preserved.
+    pub fn resolve_conflict<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+        winner: &MapBlockId,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let sel: Selector = sel.into();
+
+        let value = self
+            .view
+            .get_conflicts(obj, sel.clone())?
+            .into_iter()
+            .find(|conflict| &conflict.id == winner && !conflict.deleted)
+            .map(|conflict| conflict.value.clone())
+            .ok_or_else(|| TransactionError::ConflictNotFound {
+                object: obj,
+                selector: sel.clone(),
+                id: winner.clone(),
+            })?;
+
+        let moved_object = match value {
+            Value::Scalar(value) => return self.set_scalar(obj, sel, value),
+            Value::Object(moved_object) => moved_object,
+        };
+
+        let (block_id, block_parents) = match self.view.get_object_mut(obj)? {
+            Some(ObjectValue::Map(map)) => (map.next_id(), map.get_latest_ids(&sel)),
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
+            }
+        };
+
+        self.create_action(|_self| {
+            Ok(OperationAction::MoveObject(MoveObjectAction {
+                object: obj,
+                selector: sel,
+                id: block_id,
+                parents: block_parents,
+                moved_object,
+            }))
+        })?;
+
+        Ok(())
+    }
+
+    pub fn rename<TRef: Into<ObjRef>, TFrom: Into<Selector>, TTo: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        from: TFrom,
+        to: TTo,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let from: Selector = from.into();
+        let to: Selector = to.into();
+
+        let now = self.view.now();
+        let map = self.view.get_object_mut(obj)?;
+        let (block_id, block_parents, sources) = match map {
+            Some(ObjectValue::Map(map)) => {
+                if map.get(&from, now).is_none() {
+                    return Err(TransactionError::KeyNotFound {
+                        object: obj,
+                        selector: from,
+                    });
+                }
+                if from == to {
+                    return Ok(());
+                }
+
+                let map_id = map.next_id();
+                let parents = map.get_latest_ids(&to);
+                let sources = map.get_latest_ids(&from);
+                (map_id, parents, sources)
+            }
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
+            }
+        };
+
+        self.create_action(|_self| {
+            Ok(OperationAction::MoveMapValue(MoveMapValueAction {
+                object: obj,
+                from,
+                to,
+                id: block_id,
+                parents: block_parents,
+                sources,
+            }))
+        })?;
+
+        Ok(())
+    }
+
+    pub fn move_object<
+        TSrcRef: Into<ObjRef>,
+        TSrcSelector: Into<Selector>,
+        TDstRef: Into<ObjRef>,
+        TDstSelector: Into<Selector>,
+    >(
+        &mut self,
+        src_obj: TSrcRef,
+        src_sel: TSrcSelector,
+        dst_obj: TDstRef,
+        dst_sel: TDstSelector,
+    ) -> Result<(), TransactionError> {
+        let src_obj: ObjRef = src_obj.into();
+        let src_sel: Selector = src_sel.into();
+        let dst_obj: ObjRef = dst_obj.into();
+        let dst_sel: Selector = dst_sel.into();
+
+        let moved_object = match self.view.get(src_obj, src_sel.clone())? {
+            Some(Value::Object(obj_ref)) => *obj_ref,
+            Some(value) => {
+                return Err(TransactionError::IncompatibleValue {
+                    object: src_obj,
+                    selector: src_sel,
+                    expected: ValueKind::Object,
+                    actual: value.into(),
+                })
+            }
+            None => {
+                return Err(TransactionError::KeyNotFound {
+                    object: src_obj,
+                    selector: src_sel,
+                })
+            }
+        };
+
+        if src_obj == dst_obj && src_sel == dst_sel {
+            return Ok(());
+        }
+
+        if self.view.is_descendant(&dst_obj, &moved_object) {
+            return Err(TransactionError::InvalidMove {
+                moved: moved_object,
+                destination: dst_obj,
+            });
+        }
+
+        let map = self.view.get_object_mut(dst_obj)?;
+        let (block_id, block_parents) = match map {
+            Some(ObjectValue::Map(map)) => {
+                let map_id = map.next_id();
+                let parents = map.get_latest_ids(&dst_sel);
+                (map_id, parents)
+            }
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &dst_obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
+            }
+        };
+
+        self.create_action(|_self| {
+            Ok(OperationAction::MoveObject(MoveObjectAction {
+                object: dst_obj,
+                selector: dst_sel,
+                id: block_id,
+                parents: block_parents,
+                moved_object,
+            }))
+        })?;
+
+        Ok(())
+    }
+
     pub fn create_map<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
         &mut self,
         obj: TRef,
@@ -107,7 +513,7 @@ impl<'a> Transaction<'a> {
         let obj: ObjRef = obj.into();
         let sel: Selector = sel.into();
 
-        let map = self.view.get_object_mut(&obj)?;
+        let map = self.view.get_object_mut(obj)?;
         let (block_id, block_parents) = match map {
             Some(ObjectValue::Map(map)) => {
                 let map_id = map.next_id();
@@ -115,10 +521,11 @@ impl<'a> Transaction<'a> {
                 (map_id, parents)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected map, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -142,7 +549,7 @@ impl<'a> Transaction<'a> {
         let obj: ObjRef = obj.into();
         let sel: Selector = sel.into();
 
-        let map = self.view.get_object_mut(&obj)?;
+        let map = self.view.get_object_mut(obj)?;
         let (block_id, block_parents) = match map {
             Some(ObjectValue::Map(map)) => {
                 let map_id = map.next_id();
@@ -150,10 +557,11 @@ impl<'a> Transaction<'a> {
                 (map_id, parents)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected map, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Map,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -169,6 +577,94 @@ impl<'a> Transaction<'a> {
         Ok(ObjRef::Object(text_id))
     }
 
+    // Tables are maps of rows, keyed by the id of the operation that inserted them, so that
+    // rows inserted concurrently never collide, see `Doc::rows`
+    pub fn create_table<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+    ) -> Result<ObjRef, TransactionError> {
+        self.create_map(obj, sel)
+    }
+
+    // Returns the id of the new row, formatted like `ReadableDoc::format_id`, e.g. "alice@42"
+    pub fn insert_row<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+        TValues: IntoIterator<Item = (TSelector, TValue)>,
+    >(
+        &mut self,
+        table: TRef,
+        values: TValues,
+    ) -> Result<String, TransactionError> {
+        let id = self.op_log.next_id();
+        let global_id = self
+            .client_registry
+            .get_global_id(id.client_id)
+            .expect("the current client is registered");
+        let row_id =
+            FormattedId::new(Some((id.client_id, id.sequence)), Some(global_id)).to_string();
+
+        let row = self.create_map(table, row_id.as_str())?;
+        self.set_many(row, values)?;
+        Ok(row_id)
+    }
+
+    // Concurrent updates of a row are merged column by column, like the keys of a map, while
+    // deleting the row wins over them
+    pub fn update_row<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+        TValues: IntoIterator<Item = (TSelector, TValue)>,
+    >(
+        &mut self,
+        table: TRef,
+        row_id: &str,
+        values: TValues,
+    ) -> Result<(), TransactionError> {
+        let row = self.get_row(table.into(), row_id)?;
+        self.set_many(row, values)
+    }
+
+    pub fn delete_row<TRef: Into<ObjRef>>(
+        &mut self,
+        table: TRef,
+        row_id: &str,
+    ) -> Result<(), TransactionError> {
+        let table: ObjRef = table.into();
+        self.get_row(table, row_id)?;
+        self.delete(table, row_id)
+    }
+
+    fn get_row(&self, table: ObjRef, row_id: &str) -> Result<ObjRef, TransactionError> {
+        let selector = Selector::from(row_id);
+        match self.view.get(table, selector.clone())? {
+            Some(Value::Object(row)) => Ok(*row),
+            _ => Err(TransactionError::KeyNotFound {
+                object: table,
+                selector,
+            }),
+        }
+    }
+
+    // Creates a text with the given content, which is inserted as a single block, so large
+    // texts are loaded without replaying their history
+    pub fn set_text<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+        initial: &str,
+    ) -> Result<ObjRef, TransactionError> {
+        let text = self.create_text(obj, sel)?;
+        if !initial.is_empty() {
+            self.append_text(text, initial)?;
+        }
+
+        Ok(text)
+    }
+
     pub fn get_text<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
         &mut self,
         obj: TRef,
@@ -177,24 +673,22 @@ impl<'a> Transaction<'a> {
         let obj: ObjRef = obj.into();
         let sel: Selector = sel.into();
 
-        let view_value = self.view.get(obj, sel)?;
+        let view_value = self.view.get(obj, sel.clone())?;
         match view_value {
             Some(Value::Object(obj_ref)) => match self.view.get_object(obj_ref)? {
-                Some(ObjectValue::Text(_)) => Ok(Some(obj_ref.clone())),
-                Some(_) => {
-                    return Err(TransactionError::IncompatibleTypes(format!(
-                        "expected text, found: {:?}",
-                        view_value
-                    )))
-                }
-                None => panic!("expected text object to be present"),
+                Some(ObjectValue::Text(_)) => Ok(Some(*obj_ref)),
+                actual_value => Err(TransactionError::unexpected_object(
+                    obj_ref,
+                    ValueKind::Text,
+                    actual_value,
+                )),
             },
-            Some(_) => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected object, found: {:?}",
-                    view_value
-                )))
-            }
+            Some(value) => Err(TransactionError::IncompatibleValue {
+                object: obj,
+                selector: sel,
+                expected: ValueKind::Text,
+                actual: value.into(),
+            }),
             None => Ok(None),
         }
     }
@@ -207,7 +701,7 @@ impl<'a> Transaction<'a> {
         let obj: ObjRef = obj.into();
         let sel: Selector = sel.into();
 
-        match self.get_text(&obj, &sel)? {
+        match self.get_text(obj, &sel)? {
             Some(obj_ref) => {
                 return Ok(obj_ref);
             }
@@ -215,6 +709,48 @@ impl<'a> Transaction<'a> {
         }
     }
 
+    pub fn get_map<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+    ) -> Result<Option<ObjRef>, TransactionError> {
+        let obj: ObjRef = obj.into();
+        let sel: Selector = sel.into();
+
+        let view_value = self.view.get(obj, sel.clone())?;
+        match view_value {
+            Some(Value::Object(obj_ref)) => match self.view.get_object(obj_ref)? {
+                Some(ObjectValue::Map(_)) => Ok(Some(*obj_ref)),
+                actual_value => Err(TransactionError::unexpected_object(
+                    obj_ref,
+                    ValueKind::Map,
+                    actual_value,
+                )),
+            },
+            Some(value) => Err(TransactionError::IncompatibleValue {
+                object: obj,
+                selector: sel,
+                expected: ValueKind::Map,
+                actual: value.into(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_or_create_map<TRef: Into<ObjRef>, TSelector: Into<Selector>>(
+        &mut self,
+        obj: TRef,
+        sel: TSelector,
+    ) -> Result<ObjRef, TransactionError> {
+        let obj: ObjRef = obj.into();
+        let sel: Selector = sel.into();
+
+        match self.get_map(obj, &sel)? {
+            Some(obj_ref) => Ok(obj_ref),
+            None => self.create_map(obj, sel),
+        }
+    }
+
     pub fn append_text<TRef: Into<ObjRef>, TValue: Into<String>>(
         &mut self,
         obj: TRef,
@@ -222,25 +758,27 @@ impl<'a> Transaction<'a> {
     ) -> Result<(), TransactionError> {
         let obj: ObjRef = obj.into();
         let value: String = value.into();
+        check_not_empty(&obj, &value)?;
 
-        let view_value = self.view.get_object_mut(&obj)?;
+        let view_value = self.view.get_object_mut(obj)?;
         let (text_block_id, left) = match view_value {
             Some(crate::ObjectValue::Text(text)) => {
-                let text_block_id = text.next_id(
+                let left = text.last_block();
+                let text_block_id = text.next_id_after(
+                    left.as_ref(),
                     value
                         .len()
                         .try_into()
                         .map_err(|_| TransactionError::TextTooLong)?,
                 );
-
-                let left = text.last_block();
                 (text_block_id, left)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected text, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -248,7 +786,7 @@ impl<'a> Transaction<'a> {
             Ok(OperationAction::InsertText(InsertTextAction {
                 object: obj,
                 id: text_block_id,
-                value,
+                value: value.into(),
                 left,
             }))
         })?;
@@ -264,25 +802,40 @@ impl<'a> Transaction<'a> {
     ) -> Result<(), TransactionError> {
         let obj: ObjRef = obj.into();
         let value: String = value.into();
+        check_not_empty(&obj, &value)?;
 
-        let view_value = self.view.get_object_mut(&obj)?;
+        let view_value = self.view.get_object_mut(obj)?;
         let (text_block_id, left) = match view_value {
             Some(crate::ObjectValue::Text(text)) => {
-                let text_block_id = text.next_id(
+                // Inserting past the end of the text would otherwise fall back to the start
+                if index > text.len() {
+                    return Err(TransactionError::InvalidIndex {
+                        object: obj,
+                        index,
+                        len: text.len(),
+                    });
+                }
+
+                if !text.is_char_boundary(index) {
+                    return Err(TransactionError::NotCharBoundary { object: obj, index });
+                }
+
+                let left = text.find_block_ending_at(index);
+                let text_block_id = text.next_id_after(
+                    left.as_ref(),
                     value
                         .len()
                         .try_into()
                         .map_err(|_| TransactionError::TextTooLong)?,
                 );
-
-                let left = text.find_block_ending_at(index);
                 (text_block_id, left)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected text, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value.as_deref(),
+                ))
             }
         };
 
@@ -290,7 +843,7 @@ impl<'a> Transaction<'a> {
             Ok(OperationAction::InsertText(InsertTextAction {
                 object: obj,
                 id: text_block_id,
-                value,
+                value: value.into(),
                 left,
             }))
         })?;
@@ -305,78 +858,577 @@ impl<'a> Transaction<'a> {
         count: u32,
     ) -> Result<(), TransactionError> {
         let obj: ObjRef = obj.into();
+        if count == 0 {
+            return Err(TransactionError::EmptyOperation { object: obj });
+        }
 
-        let view_value = self.view.get_object_mut(&obj)?;
-        let (left, right) = match view_value {
-            Some(crate::ObjectValue::Text(text)) => {
-                let left = text.find_block_starting_at(index);
-                let right = text.find_block_ending_at(index + count);
-                (left, right)
+        let ranges = match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => {
+                check_text_range(&obj, text, index, count)?;
+                text.item_ranges(index, index + count)
             }
             actual_value => {
-                return Err(TransactionError::IncompatibleTypes(format!(
-                    "expected text, found: {:?}",
-                    actual_value
-                )))
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value,
+                ))
+            }
+        };
+
+        // The characters are deleted by id, so the ones inserted concurrently in the middle
+        // of the range are kept, see `DeleteTextItemsAction`
+        self.create_action(|_self| {
+            Ok(OperationAction::DeleteTextItems(DeleteTextItemsAction {
+                object: obj,
+                ranges,
+            }))
+        })?;
+
+        Ok(())
+    }
+
+    // Deletes several byte ranges with a single operation, which is much smaller than
+    // calling `delete_text` for each of them. Ranges refer to the text before the deletion,
+    // and must not be empty or overlap each other.
+    pub fn delete_text_ranges<TRef: Into<ObjRef>, TRanges: IntoIterator<Item = Range<u32>>>(
+        &mut self,
+        obj: TRef,
+        ranges: TRanges,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let mut ranges: Vec<Range<u32>> = ranges.into_iter().collect();
+        if ranges.is_empty() {
+            return Err(TransactionError::EmptyOperation { object: obj });
+        }
+        ranges.sort_by_key(|range| range.start);
+
+        let text = match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => text,
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value,
+                ))
             }
         };
 
-        let left = left.ok_or_else(|| TransactionError::InvalidIndex("left".to_string()))?;
-        let right = right.ok_or_else(|| TransactionError::InvalidIndex("right".to_string()))?;
+        let mut deleted_ranges = Vec::with_capacity(ranges.len());
+        let mut previous_end = 0;
+        for range in ranges {
+            if range.is_empty() {
+                return Err(TransactionError::EmptyOperation { object: obj });
+            }
+            if range.start < previous_end {
+                return Err(TransactionError::InvalidRange { object: obj, range });
+            }
+            check_text_range(&obj, text, range.start, range.end - range.start)?;
+
+            deleted_ranges.extend(text.item_ranges(range.start, range.end));
+            previous_end = range.end;
+        }
 
         self.create_action(|_self| {
-            Ok(OperationAction::DeleteText(DeleteTextAction {
+            Ok(OperationAction::DeleteTextItems(DeleteTextItemsAction {
                 object: obj,
-                left,
-                right,
+                ranges: deleted_ranges,
             }))
         })?;
 
         Ok(())
     }
 
-    pub fn commit(self) -> Result<(), TransactionError> {
-        // TODO: here rollback all the previous actions and pack them into a single operation if possible
-        // let compacted_actions = Self::compact_actions(self.actions_buffer);
-        // let operation = self
-        //     .op_log
-        //     .register_actions(&self.client_registry, compacted_actions)?;
-        // self.view
-        //     .apply_local_operation(operation, self.object_registry)?;
+    // Anchors an annotation to the byte range `start..end` of the text, so that it follows
+    // the range through concurrent edits, see `Doc::annotations`. The payload is written to a
+    // new map, which is returned so that any other value can be added to it.
+    pub fn add_annotation<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+        TValues: IntoIterator<Item = (TSelector, TValue)>,
+    >(
+        &mut self,
+        obj: TRef,
+        start: u32,
+        end: u32,
+        payload: TValues,
+    ) -> Result<ObjRef, TransactionError> {
+        self.add_annotation_with_bias(obj, start, end, AnchorBias::default(), payload)
+    }
+
+    // Only expanding annotations can be empty, e.g. to mark a position
+    pub fn add_annotation_with_bias<
+        TRef: Into<ObjRef>,
+        TSelector: Into<Selector>,
+        TValue: Into<ScalarValue>,
+        TValues: IntoIterator<Item = (TSelector, TValue)>,
+    >(
+        &mut self,
+        obj: TRef,
+        start: u32,
+        end: u32,
+        bias: AnchorBias,
+        payload: TValues,
+    ) -> Result<ObjRef, TransactionError> {
+        let obj: ObjRef = obj.into();
+        if end < start {
+            return Err(TransactionError::InvalidRange {
+                object: obj,
+                range: start..end,
+            });
+        }
+
+        let (start_anchor, end_anchor) = match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => {
+                check_text_range(&obj, text, start, end - start)?;
+                match bias {
+                    AnchorBias::Contract if start == end => {
+                        return Err(TransactionError::EmptyOperation { object: obj })
+                    }
+                    AnchorBias::Contract => (
+                        text.find_block_starting_at(start),
+                        text.find_block_ending_at(end),
+                    ),
+                    AnchorBias::Expand => (
+                        text.find_block_ending_at(start),
+                        text.find_block_starting_at(end),
+                    ),
+                }
+            }
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value,
+                ))
+            }
+        };
+
+        let annotation_id = self.create_action(|_self| {
+            Ok(OperationAction::CreateAnnotation(CreateAnnotationAction {
+                object: obj,
+                start: start_anchor,
+                end: end_anchor,
+                bias,
+            }))
+        })?;
+
+        let payload_ref = ObjRef::Object(annotation_id);
+        self.set_many(payload_ref, payload)?;
+        Ok(payload_ref)
+    }
+
+    // Turns the content of the text into `value` with the fewest insertions and deletions,
+    // found by diffing the chars of the two versions (Myers), so that editors that only
+    // expose their full content don't replace the whole text, and concurrent edits to the
+    // unchanged parts are preserved. Deletions are grouped in a single operation.
+    pub fn update_text<TRef: Into<ObjRef>, TValue: AsRef<str>>(
+        &mut self,
+        obj: TRef,
+        value: TValue,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let value = value.as_ref();
+        u32::try_from(value.len()).map_err(|_| TransactionError::TextTooLong)?;
+
+        let current = match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => text.to_string(),
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value,
+                ))
+            }
+        };
+
+        // Byte offset of each char, followed by the length of the text
+        let char_offsets = |text: &str| -> Vec<u32> {
+            text.char_indices()
+                .map(|(offset, _)| offset as u32)
+                .chain([text.len() as u32])
+                .collect()
+        };
+        let current_offsets = char_offsets(&current);
+        let value_offsets = char_offsets(value);
+
+        let current_chars: Vec<char> = current.chars().collect();
+        let value_chars: Vec<char> = value.chars().collect();
+        let diff = similar::capture_diff_slices(Algorithm::Myers, &current_chars, &value_chars);
+
+        // Deletions refer to the current text, insertions to the text after the deletions,
+        // which matches `value` up to each of them when they are applied in order
+        let mut deletions = Vec::new();
+        let mut insertions = Vec::new();
+        for operation in diff {
+            let (tag, current_range, value_range) = operation.as_tag_tuple();
+            if matches!(tag, DiffTag::Delete | DiffTag::Replace) {
+                deletions
+                    .push(current_offsets[current_range.start]..current_offsets[current_range.end]);
+            }
+            if matches!(tag, DiffTag::Insert | DiffTag::Replace) {
+                insertions.push(value_offsets[value_range.start]..value_offsets[value_range.end]);
+            }
+        }
+
+        if !deletions.is_empty() {
+            self.delete_text_ranges(obj, deletions)?;
+        }
+        for range in insertions {
+            self.insert_text(
+                obj,
+                range.start,
+                &value[range.start as usize..range.end as usize],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces `delete_count` bytes starting at `index` with the given value.
+    // The inserted text is anchored to the left edge of the deleted range.
+    pub fn splice_text<TRef: Into<ObjRef>, TValue: Into<String>>(
+        &mut self,
+        obj: TRef,
+        index: u32,
+        delete_count: u32,
+        value: TValue,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let value: String = value.into();
+
+        // Validate everything before producing any operation
+        let value_len: u32 = value
+            .len()
+            .try_into()
+            .map_err(|_| TransactionError::TextTooLong)?;
+        let left = match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => {
+                check_text_range(&obj, text, index, delete_count)?;
+                text.find_block_ending_at(index)
+            }
+            actual_value => {
+                return Err(TransactionError::unexpected_object(
+                    &obj,
+                    ValueKind::Text,
+                    actual_value,
+                ))
+            }
+        };
+
+        if delete_count > 0 {
+            self.delete_text(obj, index, delete_count)?;
+        }
+
+        if value.is_empty() {
+            return Ok(());
+        }
+
+        let text_block_id = match self.view.get_object_mut(obj)? {
+            Some(ObjectValue::Text(text)) => text.next_id_after(left.as_ref(), value_len),
+            _ => unreachable!("text was checked above"),
+        };
+
+        self.create_action(|_self| {
+            Ok(OperationAction::InsertText(InsertTextAction {
+                object: obj,
+                id: text_block_id,
+                value: value.into(),
+                left,
+            }))
+        })?;
 
         Ok(())
     }
 
+    // Same as `insert_text`, but the index is expressed in chars instead of bytes
+    pub fn insert_text_chars<TRef: Into<ObjRef>, TValue: Into<String>>(
+        &mut self,
+        obj: TRef,
+        char_index: u32,
+        value: TValue,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        let index = self.char_to_index(&obj, char_index)?;
+        self.insert_text(obj, index, value)
+    }
+
+    // Same as `delete_text`, but the range is expressed in chars instead of bytes
+    pub fn delete_text_chars<TRef: Into<ObjRef>>(
+        &mut self,
+        obj: TRef,
+        char_index: u32,
+        char_count: u32,
+    ) -> Result<(), TransactionError> {
+        let obj: ObjRef = obj.into();
+        // An overflowing end is past the end of any text, so it's reported as out of range
+        let end_char_index = char_index.saturating_add(char_count);
+
+        let index = self.char_to_index(&obj, char_index)?;
+        let end_index = self.char_to_index(&obj, end_char_index)?;
+        self.delete_text(obj, index, end_index - index)
+    }
+
+    fn char_to_index(&self, obj: &ObjRef, char_index: u32) -> Result<u32, TransactionError> {
+        match self.view.get_object(obj)? {
+            Some(ObjectValue::Text(text)) => {
+                text.char_to_index(char_index)
+                    .ok_or_else(|| TransactionError::InvalidCharIndex {
+                        object: *obj,
+                        char_index,
+                        len_chars: text.len_chars(),
+                    })
+            }
+            actual_value => Err(TransactionError::unexpected_object(
+                obj,
+                ValueKind::Text,
+                actual_value,
+            )),
+        }
+    }
+
+    pub fn commit(self) -> Result<CommitResult, TransactionError> {
+        // The view already reflects the compacted operations, as they are equivalent
+        // TODO: rollback the operations of transactions that are not committed
+        self.op_log.compact_local_operations(self.start);
+
+        let operations: Vec<&Operation> = self.op_log.iter_from(self.start).collect();
+        let mut heads: Vec<OperationId> = self.op_log.heads().collect();
+        self.client_registry.sort_by_global_id(&mut heads);
+        Ok(CommitResult {
+            operations: operations.iter().map(|operation| operation.id).collect(),
+            heads,
+            timestamp: operations.last().map(|operation| operation.timestamp),
+        })
+    }
+
     fn create_action(
         &mut self,
         callback: impl FnOnce(&mut Self) -> Result<OperationAction, TransactionError>,
     ) -> Result<OperationId, TransactionError> {
         let action = callback(self)?;
+        self.validate(&action)?;
 
-        // TODO: this might need to be provided from outside
-        let timestamp = Utc::now().timestamp_millis() as u64;
+        let timestamp = self.op_log.next_timestamp();
         let operation = self.op_log.apply_local_action(action, timestamp)?;
-        self.view
-            .apply_local_operation(operation, &self.client_registry)?;
+        self.view.apply_operation(operation, self.client_registry)?;
 
         Ok(operation.id)
     }
+
+    // Created objects are validated as `Value::Object`, before anything is written to them.
+    // Objects that are not reachable from the root have no path, so writes to them are not
+    // validated.
+    fn validate(&self, action: &OperationAction) -> Result<(), TransactionError> {
+        let Some(validator) = self.validator else {
+            return Ok(());
+        };
+
+        let (object, writes): (&ObjRef, Vec<(&Selector, Cow<Value>)>) = match action {
+            OperationAction::SetMapValue(action) => (
+                &action.object,
+                vec![(&action.selector, Cow::Borrowed(&action.value))],
+            ),
+            OperationAction::SetMapValues(action) => (
+                &action.object,
+                action
+                    .entries
+                    .iter()
+                    .map(|entry| (&entry.selector, Cow::Borrowed(&entry.value)))
+                    .collect(),
+            ),
+            OperationAction::CreateMap(CreateMapAction {
+                object, selector, ..
+            })
+            | OperationAction::CreateText(CreateTextAction {
+                object, selector, ..
+            }) => {
+                let created = Value::Object(ObjRef::Object(self.op_log.next_id()));
+                (object, vec![(selector, Cow::Owned(created))])
+            }
+            OperationAction::MoveObject(action) => {
+                let moved = Value::Object(action.moved_object);
+                (&action.object, vec![(&action.selector, Cow::Owned(moved))])
+            }
+            OperationAction::MoveMapValue(action) => {
+                match self.view.get(action.object, action.from.clone())? {
+                    Some(value) => (&action.object, vec![(&action.to, Cow::Borrowed(value))]),
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        let Some(path) = self.view.path_of(object) else {
+            return Ok(());
+        };
+        for (selector, value) in writes {
+            let path = path.join(selector.clone());
+            if !validator(&path, &value) {
+                return Err(TransactionError::SchemaViolation { path });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Integers that don't fit an i32 become doubles, as in `import`
+fn json_to_scalar(json: &JsonValue) -> ScalarValue {
+    match json {
+        JsonValue::Bool(bool) => ScalarValue::Bool(*bool),
+        JsonValue::Number(number) => match number.as_i64().map(i32::try_from) {
+            Some(Ok(int)) => ScalarValue::Int(int),
+            _ => ScalarValue::Double(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(string) => ScalarValue::String(string.clone()),
+        JsonValue::Null | JsonValue::Array(_) | JsonValue::Object(_) => ScalarValue::Null,
+    }
+}
+
+// Empty insertions would create empty blocks, which the text CRDT doesn't support
+fn check_not_empty(object: &ObjRef, value: &str) -> Result<(), TransactionError> {
+    if value.is_empty() {
+        return Err(TransactionError::EmptyOperation { object: *object });
+    }
+
+    Ok(())
+}
+
+fn check_text_range(
+    object: &ObjRef,
+    text: &TextCRDT,
+    index: u32,
+    count: u32,
+) -> Result<(), TransactionError> {
+    if index.checked_add(count).is_none_or(|end| end > text.len()) {
+        return Err(TransactionError::InvalidIndex {
+            object: *object,
+            index: index.saturating_add(count),
+            len: text.len(),
+        });
+    }
+
+    for index in [index, index + count] {
+        if !text.is_char_boundary(index) {
+            return Err(TransactionError::NotCharBoundary {
+                object: *object,
+                index,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Operations created by a committed transaction, after compaction, in the order they were
+// applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitResult {
+    pub operations: Vec<OperationId>,
+    // Heads of the document after the commit, see `Doc::heads`
+    pub heads: Vec<OperationId>,
+    // Timestamp of the last operation, `None` if the transaction didn't change anything
+    pub timestamp: Option<Timestamp>,
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum TransactionError {
     #[error("operation log error: {0}")]
     OperationLogError(#[from] OperationLogError),
 
-    #[error("incompatible types: {0}")]
-    IncompatibleTypes(String),
+    #[error("object {0:?} not found")]
+    ObjectNotFound(ObjRef),
+
+    #[error("expected {expected}, found {actual} for object {object:?}")]
+    IncompatibleTypes {
+        object: ObjRef,
+        expected: ValueKind,
+        actual: ValueKind,
+    },
+
+    #[error("expected {expected}, found {actual} at {selector:?} of object {object:?}")]
+    IncompatibleValue {
+        object: ObjRef,
+        selector: Selector,
+        expected: ValueKind,
+        actual: ValueKind,
+    },
 
     #[error("text too long")]
     TextTooLong,
 
-    #[error("invalid index: {0}")]
-    InvalidIndex(String),
+    #[error("index {index} is out of range for object {object:?} of length {len}")]
+    InvalidIndex {
+        object: ObjRef,
+        index: u32,
+        len: u32,
+    },
+
+    #[error("char index {char_index} is out of range for object {object:?} of {len_chars} chars")]
+    InvalidCharIndex {
+        object: ObjRef,
+        char_index: u32,
+        len_chars: u32,
+    },
+
+    #[error("index {index} is not a char boundary of object {object:?}")]
+    NotCharBoundary { object: ObjRef, index: u32 },
+
+    // The range is reversed, or it overlaps another range of the same operation
+    #[error("invalid range {range:?} for object {object:?}")]
+    InvalidRange { object: ObjRef, range: Range<u32> },
+
+    #[error("key not found: {selector:?} in object {object:?}")]
+    KeyNotFound { object: ObjRef, selector: Selector },
+
+    #[error("no conflict {id:?} for key {selector:?} of object {object:?}")]
+    ConflictNotFound {
+        object: ObjRef,
+        selector: Selector,
+        id: MapBlockId,
+    },
+
+    #[error("{moved:?} can't be moved inside {destination:?}, which it contains")]
+    InvalidMove { moved: ObjRef, destination: ObjRef },
+
+    #[error("empty operation on object {object:?}")]
+    EmptyOperation { object: ObjRef },
 
     #[error("view error: {0}")]
     ViewError(#[from] ViewError),
+
+    #[error("path error: {0}")]
+    PathError(#[from] PathError),
+
+    #[error("expected a JSON object or array to write into {object:?}, found: {found}")]
+    UnexpectedJson { object: ObjRef, found: JsonValue },
+
+    // Errors of the transaction itself are returned as they are, so the message comes from
+    // the deserializer
+    #[error("import error in object {object:?}: {message}")]
+    ImportError { object: ObjRef, message: String },
+
+    #[error("schema violation at {path}")]
+    SchemaViolation { path: Path },
+}
+
+impl TransactionError {
+    // The object is expected to exist, so `None` means that it was not found
+    fn unexpected_object(
+        object: &ObjRef,
+        expected: ValueKind,
+        actual: Option<&ObjectValue>,
+    ) -> Self {
+        match actual {
+            Some(actual) => Self::IncompatibleTypes {
+                object: *object,
+                expected,
+                actual: actual.into(),
+            },
+            None => Self::ObjectNotFound(*object),
+        }
+    }
 }
@@ -1,18 +1,21 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::BTreeMap, ops::Range};
 
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
 use enum_as_inner::EnumAsInner;
 use rustc_hash::FxHashMap;
 
 use crate::{
     client_registry::{ClientRemappable, ClientRemappings},
     crdt::{map::map::MapCRDT, text::TextCRDT},
+    Path,
 };
 
 pub type GlobalClientId = String;
 pub type ClientId = u32;
 pub type Timestamp = u64;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct GlobalClient {
     pub created_at: Timestamp,
     pub global_id: GlobalClientId,
@@ -20,12 +23,26 @@ pub struct GlobalClient {
 
 pub type SequenceIndex = u32;
 
+// Operation sequences start at 1, so sequence 0 never identifies an operation.
+// Serialized object references use it to encode the root object, which isn't
+// created by any operation, without an additional type tag.
+pub(crate) const ROOT_SEQUENCE: SequenceIndex = 0;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct OperationId {
     pub client_id: ClientId,
     pub sequence: SequenceIndex,
 }
 
+impl OperationId {
+    pub fn new(client_id: ClientId, sequence: SequenceIndex) -> Self {
+        Self {
+            client_id,
+            sequence,
+        }
+    }
+}
+
 impl ClientRemappable for OperationId {
     fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
         let new_client_id = mappings.get(&self.client_id).expect("client ID not found");
@@ -35,7 +52,7 @@ impl ClientRemappable for OperationId {
 
 pub type ObjId = OperationId;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ObjRef {
     Root,
     Object(ObjId),
@@ -58,7 +75,7 @@ impl From<ObjId> for ObjRef {
 
 impl From<&ObjRef> for ObjRef {
     fn from(obj: &ObjRef) -> Self {
-        obj.clone()
+        *obj
     }
 }
 
@@ -68,6 +85,25 @@ pub enum Selector {
     Index(usize),
 }
 
+// Indexes come first, in numeric order, followed by the keys in lexicographic order, so
+// maps used as arrays are iterated like one
+impl Ord for Selector {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self, other) {
+            (Selector::Index(a), Selector::Index(b)) => a.cmp(b),
+            (Selector::Index(_), Selector::Key(_)) => std::cmp::Ordering::Less,
+            (Selector::Key(_), Selector::Index(_)) => std::cmp::Ordering::Greater,
+            (Selector::Key(a), Selector::Key(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Selector {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl From<&Selector> for Selector {
     fn from(value: &Selector) -> Self {
         value.clone()
@@ -98,6 +134,19 @@ pub enum ScalarValue {
     Int(i32),
     Double(f64),
     Bool(bool),
+    // Milliseconds since the unix epoch
+    Timestamp(i64),
+    // Unlike a deleted key, a key set to null is still present in the map
+    Null,
+}
+
+impl ScalarValue {
+    pub fn as_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Timestamp(millis) => Utc.timestamp_millis_opt(*millis).single(),
+            _ => None,
+        }
+    }
 }
 
 impl From<String> for ScalarValue {
@@ -130,18 +179,83 @@ impl From<f64> for ScalarValue {
     }
 }
 
+impl From<DateTime<Utc>> for ScalarValue {
+    fn from(value: DateTime<Utc>) -> Self {
+        Self::Timestamp(value.timestamp_millis())
+    }
+}
+
 #[derive(Debug, EnumAsInner, Clone, PartialEq)]
 pub enum ObjectValue {
     Map(MapCRDT),
     Text(TextCRDT),
 }
 
+impl ClientRemappable for ObjectValue {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        match self {
+            Self::Map(map) => map.remap_client_ids(mappings),
+            Self::Text(text) => text.remap_client_ids(mappings),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, EnumAsInner)]
 pub enum Value {
     Scalar(ScalarValue),
     Object(ObjRef),
 }
 
+// Kinds of values, reported by the errors when a value doesn't have the expected one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ValueKind {
+    Map,
+    Text,
+    // Either a map or a text, e.g. for values referring to an object of unknown type
+    Object,
+    Scalar,
+}
+
+impl std::fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Map => "map",
+            Self::Text => "text",
+            Self::Object => "object",
+            Self::Scalar => "scalar",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<&ObjectValue> for ValueKind {
+    fn from(value: &ObjectValue) -> Self {
+        match value {
+            ObjectValue::Map(_) => Self::Map,
+            ObjectValue::Text(_) => Self::Text,
+        }
+    }
+}
+
+impl From<&CachedObjectValue> for ValueKind {
+    fn from(value: &CachedObjectValue) -> Self {
+        match value {
+            CachedObjectValue::Map(_) => Self::Map,
+            CachedObjectValue::Text(_) => Self::Text,
+        }
+    }
+}
+
+impl From<&Value> for ValueKind {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Scalar(_) => Self::Scalar,
+            Value::Object(_) => Self::Object,
+        }
+    }
+}
+
 impl ClientRemappable for Value {
     fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
         match self {
@@ -151,23 +265,304 @@ impl ClientRemappable for Value {
     }
 }
 
+impl Value {
+    pub fn as_value_ref(&self) -> ValueRef<'_> {
+        ValueRef::from(self)
+    }
+}
+
+// Borrowed counterpart of Value, cheap to copy around while reading a document. Only
+// strings are borrowed, objects and the other scalars are copied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueRef<'a> {
+    String(&'a str),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    // Milliseconds since the unix epoch
+    Timestamp(i64),
+    Null,
+    Object(ObjRef),
+}
+
+impl<'a> ValueRef<'a> {
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Self::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_double(&self) -> Option<f64> {
+        match self {
+            Self::Double(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_timestamp(&self) -> Option<i64> {
+        match self {
+            Self::Timestamp(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<ObjRef> {
+        match self {
+            Self::Object(obj) => Some(*obj),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    pub fn is_object(&self) -> bool {
+        matches!(self, Self::Object(_))
+    }
+
+    pub fn to_value(&self) -> Value {
+        match *self {
+            Self::String(value) => Value::Scalar(ScalarValue::String(value.to_string())),
+            Self::Int(value) => Value::Scalar(ScalarValue::Int(value)),
+            Self::Double(value) => Value::Scalar(ScalarValue::Double(value)),
+            Self::Bool(value) => Value::Scalar(ScalarValue::Bool(value)),
+            Self::Timestamp(value) => Value::Scalar(ScalarValue::Timestamp(value)),
+            Self::Null => Value::Scalar(ScalarValue::Null),
+            Self::Object(obj) => Value::Object(obj),
+        }
+    }
+}
+
+impl<'a> From<&'a ScalarValue> for ValueRef<'a> {
+    fn from(value: &'a ScalarValue) -> Self {
+        match value {
+            ScalarValue::String(value) => Self::String(value),
+            ScalarValue::Int(value) => Self::Int(*value),
+            ScalarValue::Double(value) => Self::Double(*value),
+            ScalarValue::Bool(value) => Self::Bool(*value),
+            ScalarValue::Timestamp(value) => Self::Timestamp(*value),
+            ScalarValue::Null => Self::Null,
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Scalar(value) => Self::from(value),
+            Value::Object(obj) => Self::Object(*obj),
+        }
+    }
+}
+
+// Borrowed text of a document, that can be displayed (or written to a `fmt::Write`)
+// without copying it into a `String` first
+#[derive(Clone, Copy)]
+pub struct TextRef<'a> {
+    inner: TextRefInner<'a>,
+}
+
+#[derive(Clone, Copy)]
+enum TextRefInner<'a> {
+    Crdt(&'a TextCRDT),
+    Cached(&'a CachedText),
+}
+
+impl<'a> TextRef<'a> {
+    pub(crate) fn from_crdt(text: &'a TextCRDT) -> Self {
+        Self {
+            inner: TextRefInner::Crdt(text),
+        }
+    }
+
+    pub(crate) fn from_cached(text: &'a CachedText) -> Self {
+        Self {
+            inner: TextRefInner::Cached(text),
+        }
+    }
+
+    // Length in bytes
+    pub fn len(&self) -> usize {
+        match self.inner {
+            TextRefInner::Crdt(text) => text.len() as usize,
+            TextRefInner::Cached(text) => text.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Parts of the text in order, without joining them
+    pub fn chunks(&self) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match self.inner {
+            TextRefInner::Crdt(text) => Box::new(text.iter_blocks().map(|(_, block)| block)),
+            TextRefInner::Cached(text) => Box::new(text.chunks()),
+        }
+    }
+
+    // Same as `chunks`, starting from the end of the text. Reading the tail of a text
+    // (e.g. the last lines) doesn't walk the parts that come before.
+    pub fn chunks_rev(&self) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match self.inner {
+            TextRefInner::Crdt(text) => Box::new(text.iter_blocks().rev().map(|(_, block)| block)),
+            TextRefInner::Cached(text) => Box::new(text.chunks_rev()),
+        }
+    }
+
+    pub fn is_char_boundary(&self, index: usize) -> bool {
+        match self.inner {
+            TextRefInner::Crdt(text) => text.is_char_boundary(index as u32),
+            TextRefInner::Cached(text) => text.is_char_boundary(index),
+        }
+    }
+
+    // Same as `chunks`, but only for the given range of bytes.
+    // Panics if the range is out of bounds or not on char boundaries, like slicing a `str`.
+    pub fn chunks_in_range(&self, range: Range<usize>) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "range {:?} out of bounds",
+            range
+        );
+        assert!(
+            self.is_char_boundary(range.start) && self.is_char_boundary(range.end),
+            "range {:?} is not on char boundaries",
+            range
+        );
+
+        match self.inner {
+            _ if range.is_empty() => Box::new(std::iter::empty()),
+            TextRefInner::Crdt(text) => {
+                Box::new(text.chunks_in_range(range.start as u32, range.end as u32))
+            }
+            TextRefInner::Cached(text) => Box::new(text.chunks_in_range(range)),
+        }
+    }
+}
+
+impl std::fmt::Display for TextRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.inner {
+            TextRefInner::Crdt(text) => std::fmt::Display::fmt(text, f),
+            TextRefInner::Cached(text) => text.chunks().try_for_each(|chunk| f.write_str(chunk)),
+        }
+    }
+}
+
+impl std::fmt::Debug for TextRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
 #[derive(Debug, EnumAsInner, Clone, PartialEq)]
 pub enum CachedObjectValue {
     Map(FxHashMap<Selector, Value>),
-    Text(String),
+    Text(CachedText),
 }
 
-impl From<&ObjectValue> for CachedObjectValue {
-    fn from(value: &ObjectValue) -> Self {
+// Text of the view cache, split in chunks that follow the blocks of the text CRDT.
+// Blocks are only split by edits, so most chunks stay the same across saves.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CachedText {
+    text: String,
+    // End of each chunk in `text`
+    chunk_ends: Vec<usize>,
+}
+
+impl CachedText {
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    // Length in bytes
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn chunks(&self) -> impl DoubleEndedIterator<Item = &str> {
+        (0..self.chunk_ends.len()).map(|index| {
+            let start = index
+                .checked_sub(1)
+                .map_or(0, |previous| self.chunk_ends[previous]);
+            &self.text[start..self.chunk_ends[index]]
+        })
+    }
+
+    pub fn chunks_rev(&self) -> impl Iterator<Item = &str> {
+        self.chunks().rev()
+    }
+
+    pub fn is_char_boundary(&self, index: usize) -> bool {
+        self.text.is_char_boundary(index)
+    }
+
+    // Same as `chunks`, but only for the given range of bytes, see `TextRef::chunks_in_range`
+    pub fn chunks_in_range(&self, range: Range<usize>) -> impl Iterator<Item = &str> {
+        let end = range.end;
+        self.chunks()
+            .scan(0, |chunk_start, chunk| {
+                let start = *chunk_start;
+                *chunk_start += chunk.len();
+                Some((start, chunk))
+            })
+            .take_while(move |(chunk_start, _)| *chunk_start < end)
+            .filter_map(move |(chunk_start, chunk)| {
+                let from = range.start.max(chunk_start) - chunk_start;
+                let to = range.end.min(chunk_start + chunk.len()) - chunk_start;
+                (from < to).then(|| &chunk[from..to])
+            })
+    }
+
+    pub(crate) fn push_chunk(&mut self, chunk: &str) {
+        if chunk.is_empty() {
+            return;
+        }
+
+        self.text.push_str(chunk);
+        self.chunk_ends.push(self.text.len());
+    }
+}
+
+impl CachedObjectValue {
+    // Values that expired before `now` are left out
+    pub(crate) fn from_object(value: &ObjectValue, now: Timestamp) -> Self {
         match value {
             ObjectValue::Map(map) => {
                 let mut cached_map = FxHashMap::default();
-                for (key, value) in map.to_map() {
+                for (key, value) in map.to_map(now) {
                     cached_map.insert(key, value.clone());
                 }
                 Self::Map(cached_map)
             }
-            ObjectValue::Text(text) => Self::Text(text.to_string()),
+            ObjectValue::Text(text) => {
+                let mut cached_text = CachedText::default();
+                for (_, block) in text.iter_blocks() {
+                    cached_text.push_chunk(block);
+                }
+                Self::Text(cached_text)
+            }
         }
     }
 }
@@ -178,12 +573,16 @@ pub enum DataMapValue<'a> {
     Int(&'a i32),
     Double(&'a f64),
     Bool(&'a bool),
+    Timestamp(&'a i64),
+    Null,
     Map(DataMap<'a>),
     Text(Cow<'a, str>),
 }
-pub type DataMap<'a> = FxHashMap<&'a Selector, DataMapValue<'a>>;
+// Sorted by selector, so that replicas with the same content list (and export) the keys in
+// the same order
+pub type DataMap<'a> = BTreeMap<&'a Selector, DataMapValue<'a>>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Operation {
     pub id: OperationId,
     pub parent: Option<OperationId>,
@@ -209,14 +608,20 @@ impl ClientRemappable for Operation {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OperationAction {
     CreateMap(CreateMapAction),
     SetMapValue(SetMapValueAction),
+    SetMapValues(SetMapValuesAction),
     DeleteMapValue(DeleteMapValueAction),
+    MoveMapValue(MoveMapValueAction),
     CreateText(CreateTextAction),
     InsertText(InsertTextAction),
     DeleteText(DeleteTextAction),
+    DeleteTextRanges(DeleteTextRangesAction),
+    DeleteTextItems(DeleteTextItemsAction),
+    MoveObject(MoveObjectAction),
+    CreateAnnotation(CreateAnnotationAction),
 }
 
 impl ClientRemappable for OperationAction {
@@ -224,10 +629,75 @@ impl ClientRemappable for OperationAction {
         match self {
             Self::CreateMap(action) => action.remap_client_ids(mappings),
             Self::SetMapValue(action) => action.remap_client_ids(mappings),
+            Self::SetMapValues(action) => action.remap_client_ids(mappings),
             Self::DeleteMapValue(action) => action.remap_client_ids(mappings),
+            Self::MoveMapValue(action) => action.remap_client_ids(mappings),
             Self::CreateText(action) => action.remap_client_ids(mappings),
             Self::InsertText(action) => action.remap_client_ids(mappings),
             Self::DeleteText(action) => action.remap_client_ids(mappings),
+            Self::DeleteTextRanges(action) => action.remap_client_ids(mappings),
+            Self::DeleteTextItems(action) => action.remap_client_ids(mappings),
+            Self::MoveObject(action) => action.remap_client_ids(mappings),
+            Self::CreateAnnotation(action) => action.remap_client_ids(mappings),
+        }
+    }
+}
+
+impl OperationAction {
+    // Name of the variant, e.g. "InsertText"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CreateMap(_) => "CreateMap",
+            Self::SetMapValue(_) => "SetMapValue",
+            Self::SetMapValues(_) => "SetMapValues",
+            Self::DeleteMapValue(_) => "DeleteMapValue",
+            Self::MoveMapValue(_) => "MoveMapValue",
+            Self::CreateText(_) => "CreateText",
+            Self::InsertText(_) => "InsertText",
+            Self::DeleteText(_) => "DeleteText",
+            Self::DeleteTextRanges(_) => "DeleteTextRanges",
+            Self::DeleteTextItems(_) => "DeleteTextItems",
+            Self::MoveObject(_) => "MoveObject",
+            Self::CreateAnnotation(_) => "CreateAnnotation",
+        }
+    }
+
+    // The object modified by the action
+    pub fn object(&self) -> &ObjRef {
+        match self {
+            Self::CreateMap(action) => &action.object,
+            Self::SetMapValue(action) => &action.object,
+            Self::SetMapValues(action) => &action.object,
+            Self::DeleteMapValue(action) => &action.object,
+            Self::MoveMapValue(action) => &action.object,
+            Self::CreateText(action) => &action.object,
+            Self::InsertText(action) => &action.object,
+            Self::DeleteText(action) => &action.object,
+            Self::DeleteTextRanges(action) => &action.object,
+            Self::DeleteTextItems(action) => &action.object,
+            Self::MoveObject(action) => &action.object,
+            Self::CreateAnnotation(action) => &action.object,
+        }
+    }
+
+    // The map key written by the action, if it writes a single one
+    pub fn selector(&self) -> Option<&Selector> {
+        match self {
+            Self::CreateMap(action) => Some(&action.selector),
+            Self::SetMapValue(action) => Some(&action.selector),
+            Self::DeleteMapValue(action) => Some(&action.selector),
+            Self::MoveMapValue(action) => Some(&action.to),
+            Self::CreateText(action) => Some(&action.selector),
+            Self::MoveObject(action) => Some(&action.selector),
+            Self::SetMapValues(action) => match action.entries.as_slice() {
+                [entry] => Some(&entry.selector),
+                _ => None,
+            },
+            Self::InsertText(_)
+            | Self::DeleteText(_)
+            | Self::DeleteTextRanges(_)
+            | Self::DeleteTextItems(_)
+            | Self::CreateAnnotation(_) => None,
         }
     }
 }
@@ -245,7 +715,71 @@ impl ClientRemappable for MapBlockId {
     }
 }
 
-#[derive(Debug, Clone)]
+// A concurrent write to a map key. Conflicts are ordered by the same total order
+// used to pick the winning value, so the last (non deleted) one is the current value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict<'a> {
+    pub id: MapBlockId,
+    pub value: &'a Value,
+    pub timestamp: Timestamp,
+    pub deleted: bool,
+    pub supersedes: &'a [MapBlockId],
+}
+
+// A value written to a map key, see `Doc::key_history`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyHistoryEntry<'a> {
+    pub id: MapBlockId,
+    pub author: GlobalClientId,
+    pub timestamp: Timestamp,
+    pub value: &'a Value,
+    // A later write of the key replaced the value
+    pub overwritten: bool,
+    // The value was deleted, moved to another key or expired
+    pub deleted: bool,
+}
+
+// A range of visible text, along with the operation that inserted it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextHistoryEntry {
+    pub range: Range<u32>,
+    pub value: String,
+    pub operation: OperationId,
+    pub author: GlobalClientId,
+    pub timestamp: Timestamp,
+}
+
+// What an operation did, in the terms of the data model rather than of the operation
+// actions, so that it doesn't change when the actions do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    SetValue,
+    DeleteValue,
+    // A value or object moved to another key
+    MoveValue,
+    CreateMap,
+    CreateText,
+    InsertText,
+    DeleteText,
+    AddAnnotation,
+}
+
+// An operation of the document history, see `Doc::history`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeSummary {
+    pub operation: OperationId,
+    pub author: GlobalClientId,
+    pub timestamp: Timestamp,
+    // Current path of the changed value or text, `None` if it's no longer reachable.
+    // Writes to several keys of a map at once point to the map.
+    pub path: Option<Path>,
+    pub kind: ChangeKind,
+    // Short, human readable description of the new contents, e.g. the written values or
+    // the inserted text. Empty for deletions.
+    pub preview: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateMapAction {
     pub object: ObjRef,
     pub selector: Selector,
@@ -263,13 +797,16 @@ impl ClientRemappable for CreateMapAction {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SetMapValueAction {
     pub object: ObjRef,
     pub selector: Selector,
     pub id: MapBlockId,
     pub parents: Vec<MapBlockId>,
     pub value: Value,
+    // Values written with a TTL disappear once the clock of the reading document
+    // reaches this time, see `Transaction::set_scalar_with_ttl`
+    pub expires_at: Option<Timestamp>,
 }
 
 impl ClientRemappable for SetMapValueAction {
@@ -279,10 +816,49 @@ impl ClientRemappable for SetMapValueAction {
         for parent in &mut self.parents {
             parent.remap_client_ids(mappings);
         }
+        self.value.remap_client_ids(mappings);
+    }
+}
+
+// Sets several keys of the same map at once, as produced by bulk imports.
+// Entries use consecutive block ids, starting from `id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetMapValuesAction {
+    pub object: ObjRef,
+    pub id: MapBlockId,
+    pub entries: Vec<MapValueEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapValueEntry {
+    pub selector: Selector,
+    pub parents: Vec<MapBlockId>,
+    pub value: Value,
+}
+
+impl SetMapValuesAction {
+    pub fn entry_id(&self, index: usize) -> MapBlockId {
+        MapBlockId {
+            client_id: self.id.client_id,
+            sequence: self.id.sequence + index as SequenceIndex,
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+impl ClientRemappable for SetMapValuesAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        self.id.remap_client_ids(mappings);
+        for entry in &mut self.entries {
+            for parent in &mut entry.parents {
+                parent.remap_client_ids(mappings);
+            }
+            entry.value.remap_client_ids(mappings);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DeleteMapValueAction {
     pub object: ObjRef,
     pub selector: Selector,
@@ -298,7 +874,54 @@ impl ClientRemappable for DeleteMapValueAction {
     }
 }
 
-#[derive(Debug, Clone)]
+// Moves the value of a map key to another key of the same map. The moved blocks keep
+// track of their new location, so that concurrent writes to them follow the value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveMapValueAction {
+    pub object: ObjRef,
+    pub from: Selector,
+    pub to: Selector,
+    pub id: MapBlockId,
+    pub parents: Vec<MapBlockId>,
+    pub sources: Vec<MapBlockId>,
+}
+
+impl ClientRemappable for MoveMapValueAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        self.id.remap_client_ids(mappings);
+        for parent in &mut self.parents {
+            parent.remap_client_ids(mappings);
+        }
+        for source in &mut self.sources {
+            source.remap_client_ids(mappings);
+        }
+    }
+}
+
+// Moves an object under another key, possibly of a different map. Concurrent moves are
+// applied in a total order, skipping the ones that would introduce a cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveObjectAction {
+    pub object: ObjRef,
+    pub selector: Selector,
+    pub id: MapBlockId,
+    pub parents: Vec<MapBlockId>,
+    pub moved_object: ObjRef,
+}
+
+impl ClientRemappable for MoveObjectAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        self.id.remap_client_ids(mappings);
+        for parent in &mut self.parents {
+            parent.remap_client_ids(mappings);
+        }
+        self.moved_object.remap_client_ids(mappings);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateTextAction {
     pub object: ObjRef,
     pub selector: Selector,
@@ -338,14 +961,144 @@ impl ClientRemappable for SequenceBlockId {
     }
 }
 
-#[derive(Debug, Clone)]
+// Ids made of a client and a sequence, that can be displayed with `ReadableDoc::format_id`
+pub trait FormattableId {
+    // None for the root object, which isn't created by any client
+    fn client_and_sequence(&self) -> Option<(ClientId, SequenceIndex)>;
+}
+
+impl FormattableId for OperationId {
+    fn client_and_sequence(&self) -> Option<(ClientId, SequenceIndex)> {
+        Some((self.client_id, self.sequence))
+    }
+}
+
+impl FormattableId for MapBlockId {
+    fn client_and_sequence(&self) -> Option<(ClientId, SequenceIndex)> {
+        Some((self.client_id, self.sequence))
+    }
+}
+
+impl FormattableId for SequenceBlockId {
+    fn client_and_sequence(&self) -> Option<(ClientId, SequenceIndex)> {
+        Some((self.client_id, self.sequence))
+    }
+}
+
+impl FormattableId for ObjRef {
+    fn client_and_sequence(&self) -> Option<(ClientId, SequenceIndex)> {
+        match self {
+            ObjRef::Root => None,
+            ObjRef::Object(id) => id.client_and_sequence(),
+        }
+    }
+}
+
+// Displays an id as "<global-client>@<sequence>". Local client ids differ between replicas,
+// so only the global ones can be used to correlate logs across them.
+// Clients that are not part of the document are displayed with their local id, as "#<id>".
+#[derive(Debug, Clone, Copy)]
+pub struct FormattedId<'a> {
+    id: Option<(ClientId, SequenceIndex)>,
+    global_client: Option<&'a GlobalClientId>,
+}
+
+impl<'a> FormattedId<'a> {
+    pub(crate) fn new(
+        id: Option<(ClientId, SequenceIndex)>,
+        global_client: Option<&'a GlobalClientId>,
+    ) -> Self {
+        Self { id, global_client }
+    }
+}
+
+impl std::fmt::Display for FormattedId<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.id, self.global_client) {
+            (None, _) => f.write_str("root"),
+            (Some((_, sequence)), Some(global_client)) => {
+                write!(f, "{}@{}", global_client, sequence)
+            }
+            (Some((client, sequence)), None) => write!(f, "#{}@{}", client, sequence),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct InsertTextAction {
     pub object: ObjRef,
     pub id: SequenceBlockId,
-    pub value: String,
+    pub value: TextValue,
     pub left: Option<SequenceBlockId>,
 }
 
+// Text inserted by an operation. Decoded operations share the buffer they were read from,
+// so loading a document doesn't copy every inserted text, and clones are cheap.
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+pub struct TextValue(Bytes);
+
+impl TextValue {
+    // Fails if the bytes aren't valid UTF-8
+    pub(crate) fn from_bytes(bytes: Bytes) -> Result<Self, std::str::Utf8Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(Self(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("text values are checked when created")
+    }
+}
+
+impl std::ops::Deref for TextValue {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for TextValue {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<String> for TextValue {
+    fn from(value: String) -> Self {
+        Self(Bytes::from(value))
+    }
+}
+
+impl From<&str> for TextValue {
+    fn from(value: &str) -> Self {
+        Self(Bytes::copy_from_slice(value.as_bytes()))
+    }
+}
+
+impl PartialEq<str> for TextValue {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for TextValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl std::fmt::Debug for TextValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl std::fmt::Display for TextValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl ClientRemappable for InsertTextAction {
     fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
         self.object.remap_client_ids(mappings);
@@ -356,7 +1109,8 @@ impl ClientRemappable for InsertTextAction {
     }
 }
 
-#[derive(Debug, Clone)]
+// Deletes the characters from `left` to `right`, see `DeleteTextRangesAction`
+#[derive(Debug, Clone, PartialEq)]
 pub struct DeleteTextAction {
     pub object: ObjRef,
     pub left: SequenceBlockId,
@@ -370,3 +1124,138 @@ impl ClientRemappable for DeleteTextAction {
         self.right.remap_client_ids(mappings);
     }
 }
+
+// Deletes several disjoint ranges of a text with a single operation, e.g. for a replace all.
+// Like `DeleteText`, everything between the ends of a range is deleted, including the
+// characters inserted there concurrently. Both are only produced by older versions, newer
+// ones use `DeleteTextItems` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteTextRangesAction {
+    pub object: ObjRef,
+    pub ranges: Vec<DeletedTextRange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeletedTextRange {
+    pub left: SequenceBlockId,
+    pub right: SequenceBlockId,
+}
+
+impl ClientRemappable for DeleteTextRangesAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        for range in &mut self.ranges {
+            range.left.remap_client_ids(mappings);
+            range.right.remap_client_ids(mappings);
+        }
+    }
+}
+
+// Deletes the characters that were visible when the operation was created, as ranges of
+// consecutive characters of the same client: each range covers the sequences from `left`
+// to `right`, which must belong to the same client. Characters inserted concurrently
+// between them are kept, as they have different ids, so the result doesn't depend on the
+// order the operations are received in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteTextItemsAction {
+    pub object: ObjRef,
+    pub ranges: Vec<DeletedTextRange>,
+}
+
+impl ClientRemappable for DeleteTextItemsAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        for range in &mut self.ranges {
+            range.left.remap_client_ids(mappings);
+            range.right.remap_client_ids(mappings);
+        }
+    }
+}
+
+// How an annotation reacts to text inserted at its edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AnchorBias {
+    // The annotation only covers the text it was added to, e.g. a comment
+    #[default]
+    Contract,
+    // Text inserted at the edges becomes part of the annotation, e.g. a highlight
+    Expand,
+}
+
+// Anchors an annotation to a range of a text. The operation also creates the map that holds
+// the payload of the annotation, which is referred to by the id of the operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateAnnotationAction {
+    pub object: ObjRef,
+    // Characters the edges are anchored to: the first and last ones of the range when
+    // contracting, the ones just outside of it when expanding. `None` stands for the
+    // start (or the end) of the text.
+    pub start: Option<SequenceBlockId>,
+    pub end: Option<SequenceBlockId>,
+    pub bias: AnchorBias,
+}
+
+impl ClientRemappable for CreateAnnotationAction {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.object.remap_client_ids(mappings);
+        if let Some(start) = self.start.as_mut() {
+            start.remap_client_ids(mappings);
+        }
+        if let Some(end) = self.end.as_mut() {
+            end.remap_client_ids(mappings);
+        }
+    }
+}
+
+// An annotation of a text, with the range it currently covers, see `Doc::annotations`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    // Map holding the payload of the annotation
+    pub payload: ObjRef,
+    pub range: Range<u32>,
+    pub bias: AnchorBias,
+}
+
+// A row of a table, along with its current values, see `Doc::rows`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row<'a> {
+    // Key of the row in the table, e.g. "alice@42"
+    pub id: String,
+    // Map holding the values of the row
+    pub object: ObjRef,
+    values: FxHashMap<Selector, &'a Value>,
+}
+
+impl<'a> Row<'a> {
+    pub(crate) fn new(id: String, object: ObjRef, values: FxHashMap<Selector, &'a Value>) -> Self {
+        Self { id, object, values }
+    }
+
+    pub fn get<TSelector: Into<Selector>>(&self, column: TSelector) -> Option<ValueRef<'a>> {
+        self.values
+            .get(&column.into())
+            .map(|value| ValueRef::from(*value))
+    }
+
+    // `None` if the column is missing or holds another type
+    pub fn get_str<TSelector: Into<Selector>>(&self, column: TSelector) -> Option<&'a str> {
+        self.get(column)?.as_str()
+    }
+
+    pub fn get_int<TSelector: Into<Selector>>(&self, column: TSelector) -> Option<i32> {
+        self.get(column)?.as_int()
+    }
+
+    pub fn get_double<TSelector: Into<Selector>>(&self, column: TSelector) -> Option<f64> {
+        self.get(column)?.as_double()
+    }
+
+    pub fn get_bool<TSelector: Into<Selector>>(&self, column: TSelector) -> Option<bool> {
+        self.get(column)?.as_bool()
+    }
+
+    // Columns that have a value, in no particular order
+    pub fn columns(&self) -> impl Iterator<Item = &Selector> {
+        self.values.keys()
+    }
+}
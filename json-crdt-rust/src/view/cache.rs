@@ -1,98 +1,430 @@
-use std::{borrow::Cow, cmp::Ordering, collections::VecDeque};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError},
+};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use bytes_varint::{VarIntSupport, VarIntSupportMut};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
+    crdt::map::map::MapCRDT,
     serde::{
-        deserialize_obj_ref, deserialize_selector, deserialize_value, serialize_obj_ref,
-        serialize_selector, serialize_value, Serializable, SerializationError,
+        deserialize_obj_ref, deserialize_selector, deserialize_value, read_string, read_u8,
+        serialize_obj_ref, serialize_selector, serialize_value, BufferRegions, FormatMigration,
+        Serializable, SerializationError,
     },
-    CachedObjectValue, DataMap, DataMapValue, ObjRef, Selector, Value,
+    CachedObjectValue, CachedText, DataMap, DataMapValue, ObjRef, ObjectValue, Selector, Timestamp,
+    Value, ValueKind,
 };
 
 use super::{view::View, ViewError};
 
 pub struct ViewCache {
-    objects: FxHashMap<ObjRef, CachedObjectValue>,
+    objects: FxHashMap<ObjRef, CachedObject>,
+    // Values written with a TTL, by the time they expire. They are stored after the
+    // objects, and missing in buffers written before version 6 of the format.
+    expirations: FxHashMap<ObjRef, FxHashMap<Selector, Timestamp>>,
+    layout: TextLayout,
+}
+
+// Objects of a loaded cache are only decoded when they are first read, so that lazy
+// documents don't decode the objects that are never read
+struct CachedObject {
+    // Region of the loaded buffer holding the object
+    region: Bytes,
+    decoded: OnceLock<DecodedObject>,
+}
+
+struct DecodedObject {
+    value: Arc<CachedObjectValue>,
+    // The values are flattened to the winner, so the keys that still have conflicting
+    // writes are tracked separately, along with the number of those writes
+    conflicts: FxHashMap<Selector, u32>,
+}
+
+impl CachedObject {
+    fn decoded(value: CachedObjectValue, conflicts: FxHashMap<Selector, u32>) -> Self {
+        Self {
+            region: Bytes::new(),
+            decoded: OnceLock::from(DecodedObject {
+                value: Arc::new(value),
+                conflicts,
+            }),
+        }
+    }
+
+    fn get(&self, layout: TextLayout) -> &DecodedObject {
+        self.decoded.get_or_init(|| self.decode(layout))
+    }
+
+    fn decode(&self, layout: TextLayout) -> DecodedObject {
+        let (value, conflicts) = deserialize_cached_value_object(&mut self.region.clone(), layout)
+            .expect("cached objects are checked when loaded");
+        DecodedObject {
+            value: Arc::new(value),
+            conflicts,
+        }
+    }
+}
+
+// Layout of the texts in the serialized cache
+#[derive(Clone, Copy)]
+enum TextLayout {
+    // Up to version 1 of the format, each text was a single string
+    Contiguous,
+    Chunked,
 }
 
 impl<'a> ViewCache {
     pub fn from_buffer(buffer: Bytes) -> Result<Self, SerializationError> {
+        Self::from_buffer_with_layout(buffer, TextLayout::Chunked)
+    }
+
+    // Every object is decoded to check the buffer, but only their regions are kept
+    fn from_buffer_with_layout(
+        buffer: Bytes,
+        layout: TextLayout,
+    ) -> Result<Self, SerializationError> {
         let mut buffer = Bytes::from(buffer);
         let items_len = buffer
             .get_u32_varint()
             .map_err(|_| SerializationError::Malformed("unable to read items len".to_string()))?;
 
         let mut objects = FxHashMap::default();
+        let mut children = FxHashMap::default();
         for _ in 0..items_len {
             let obj_ref = deserialize_obj_ref(&mut buffer)?;
-            let object_value = deserialize_cached_value_object(&mut buffer)?;
-            objects.insert(obj_ref, object_value);
+            let region = buffer.clone();
+            let (object_value, _) = deserialize_cached_value_object(&mut buffer, layout)?;
+            let object_children = match object_value {
+                CachedObjectValue::Map(map) => Some(
+                    map.into_values()
+                        .filter_map(|value| value.into_object().ok())
+                        .collect(),
+                ),
+                CachedObjectValue::Text(_) => None,
+            };
+            children.insert(obj_ref, object_children);
+            let object = CachedObject {
+                region: region.slice(..region.len() - buffer.remaining()),
+                decoded: OnceLock::new(),
+            };
+            objects.insert(obj_ref, object);
+        }
+        check_structure(&children)?;
+
+        let mut expirations: FxHashMap<ObjRef, FxHashMap<Selector, Timestamp>> =
+            FxHashMap::default();
+        if buffer.has_remaining() {
+            let expirations_len = buffer.get_u32_varint().map_err(|_| {
+                SerializationError::Malformed("unable to read expirations len".to_string())
+            })?;
+            for _ in 0..expirations_len {
+                let obj_ref = deserialize_obj_ref(&mut buffer)?;
+                let selector = deserialize_selector(&mut buffer)?;
+                let expires_at = buffer.get_u64_varint().map_err(|_| {
+                    SerializationError::Malformed("unable to read expiration".to_string())
+                })?;
+                expirations
+                    .entry(obj_ref)
+                    .or_default()
+                    .insert(selector, expires_at);
+            }
         }
 
-        Ok(Self { objects })
+        Ok(Self {
+            objects,
+            expirations,
+            layout,
+        })
     }
 
     pub fn get_object(&self, object: ObjRef) -> Result<Option<&CachedObjectValue>, ViewError> {
-        Ok(self.objects.get(&object))
+        Ok(self
+            .objects
+            .get(&object)
+            .map(|cached| cached.get(self.layout).value.as_ref()))
     }
 
-    pub fn get(&self, object: ObjRef, selector: Selector) -> Result<Option<&Value>, ViewError> {
+    // Unlike `get_object`, the object is not kept decoded by the cache, unless it already
+    // was. Values that expired before `now` are left out.
+    pub fn load_object(&self, object: &ObjRef, now: Timestamp) -> Option<Arc<CachedObjectValue>> {
+        let cached = self.objects.get(object)?;
+        let value = match cached.decoded.get() {
+            Some(decoded) => decoded.value.clone(),
+            None => cached.decode(self.layout).value,
+        };
+
+        match (value.as_ref(), self.expirations.get(object)) {
+            (CachedObjectValue::Map(map), Some(expirations)) => {
+                let map = map
+                    .iter()
+                    .filter(|(selector, _)| {
+                        expirations
+                            .get(*selector)
+                            .is_none_or(|expires_at| *expires_at > now)
+                    })
+                    .map(|(selector, value)| (selector.clone(), value.clone()))
+                    .collect();
+                Some(Arc::new(CachedObjectValue::Map(map)))
+            }
+            _ => Some(value),
+        }
+    }
+
+    pub fn has_expiring_values(&self, object: &ObjRef) -> bool {
+        self.expirations.contains_key(object)
+    }
+
+    pub fn is_expired(&self, object: &ObjRef, selector: &Selector, now: Timestamp) -> bool {
+        self.expirations
+            .get(object)
+            .and_then(|expirations| expirations.get(selector))
+            .is_some_and(|expires_at| *expires_at <= now)
+    }
+
+    pub fn get(
+        &self,
+        object: ObjRef,
+        selector: Selector,
+        now: Timestamp,
+    ) -> Result<Option<&Value>, ViewError> {
+        if self.is_expired(&object, &selector, now) {
+            return Ok(None);
+        }
+
         let map = self.get_object(object)?;
         match map {
             Some(CachedObjectValue::Map(map)) => Ok(map.get(&selector)),
-            Some(val) => Err(ViewError::IncompatibleTypes(format!(
-                "expected map, found: {:?}",
-                val
-            ))),
+            Some(val) => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
             None => Ok(None),
         }
     }
 
-    pub fn as_map(&'a self) -> DataMap<'a> {
-        self.as_map_recursive(&ObjRef::Root)
+    pub fn get_conflict_count(&self, object: ObjRef, selector: Selector) -> Result<u32, ViewError> {
+        let Some(cached) = self.objects.get(&object) else {
+            return Ok(0);
+        };
+
+        let decoded = cached.get(self.layout);
+        match decoded.value.as_ref() {
+            CachedObjectValue::Map(_) => Ok(decoded.conflicts.get(&selector).copied().unwrap_or(0)),
+            val => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
+        }
+    }
+
+    pub fn as_map(&'a self, now: Timestamp) -> DataMap<'a> {
+        self.as_map_recursive(&ObjRef::Root, now)
             .into_map()
             .expect("expected root to be a map")
     }
 
-    fn as_map_recursive(&'a self, obj_ref: &ObjRef) -> DataMapValue {
-        let obj = self.objects.get(&obj_ref).expect("object not found");
-        match obj {
+    fn as_map_recursive(&'a self, obj_ref: &ObjRef, now: Timestamp) -> DataMapValue<'a> {
+        let obj = self.objects.get(obj_ref).expect("object not found");
+        match obj.get(self.layout).value.as_ref() {
             CachedObjectValue::Map(map) => {
                 let mut data_map: DataMap = DataMap::default();
                 for (selector, value) in map.iter() {
+                    if self.is_expired(obj_ref, selector, now) {
+                        continue;
+                    }
+
                     let data_map_value: DataMapValue<'a> = match value {
                         Value::Scalar(scalar) => match scalar {
                             crate::ScalarValue::String(string) => DataMapValue::String(string),
                             crate::ScalarValue::Int(int) => DataMapValue::Int(int),
                             crate::ScalarValue::Double(double) => DataMapValue::Double(double),
                             crate::ScalarValue::Bool(bool) => DataMapValue::Bool(bool),
+                            crate::ScalarValue::Timestamp(timestamp) => {
+                                DataMapValue::Timestamp(timestamp)
+                            }
+                            crate::ScalarValue::Null => DataMapValue::Null,
                         },
-                        Value::Object(obj_ref) => self.as_map_recursive(&obj_ref),
+                        Value::Object(obj_ref) => self.as_map_recursive(obj_ref, now),
                     };
                     data_map.insert(selector, data_map_value);
                 }
                 DataMapValue::Map(data_map)
             }
-            CachedObjectValue::Text(text) => DataMapValue::Text(Cow::Borrowed(text)),
+            CachedObjectValue::Text(text) => DataMapValue::Text(Cow::Borrowed(text.as_str())),
         }
     }
 }
 
+// Reads walk the objects starting from the root, so the root must be a map and every object
+// must be reachable through a single path. Objects are given with the ones they hold if they
+// are maps, `None` for texts.
+fn check_structure(
+    children: &FxHashMap<ObjRef, Option<Vec<ObjRef>>>,
+) -> Result<(), SerializationError> {
+    let Some(Some(_)) = children.get(&ObjRef::Root) else {
+        return Err(SerializationError::Malformed(
+            "the root of the view cache is not a map".to_string(),
+        ));
+    };
+
+    let mut visited = FxHashSet::default();
+    let mut to_visit = vec![&ObjRef::Root];
+    while let Some(obj_ref) = to_visit.pop() {
+        if !visited.insert(obj_ref) {
+            return Err(SerializationError::Malformed(format!(
+                "object {:?} is referenced more than once in the view cache",
+                obj_ref
+            )));
+        }
+
+        match children.get(obj_ref) {
+            Some(Some(map_children)) => to_visit.extend(map_children),
+            Some(None) => {}
+            None => {
+                return Err(SerializationError::Malformed(format!(
+                    "object {:?} is missing from the view cache",
+                    obj_ref
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl From<&View> for ViewCache {
     fn from(view: &View) -> Self {
-        let objects = view
-            .objects
-            .iter()
-            .map(|(obj_ref, object_value)| (obj_ref.clone(), CachedObjectValue::from(object_value)))
-            .collect();
+        let now = view.now();
+        let mut objects = FxHashMap::default();
+        let mut expirations = FxHashMap::default();
+        for (obj_ref, object_value) in view.objects.iter() {
+            let value = CachedObjectValue::from_object(object_value.as_ref(), now);
+            let ObjectValue::Map(map) = object_value.as_ref() else {
+                objects.insert(*obj_ref, CachedObject::decoded(value, FxHashMap::default()));
+                continue;
+            };
 
-        Self { objects }
+            let map_expirations = map_expirations(map, now);
+            if !map_expirations.is_empty() {
+                expirations.insert(*obj_ref, map_expirations);
+            }
+
+            let cached = CachedObject::decoded(value, map_conflicts(map, now));
+            objects.insert(*obj_ref, cached);
+        }
+
+        Self {
+            objects,
+            expirations,
+            layout: TextLayout::Chunked,
+        }
     }
 }
 
+fn map_conflicts(map: &MapCRDT, now: Timestamp) -> FxHashMap<Selector, u32> {
+    map.iter(now)
+        .map(|(selector, _)| (selector, map.conflict_count(selector, now)))
+        .filter(|(_, count)| *count > 0)
+        .map(|(selector, count)| (selector.clone(), count as u32))
+        .collect()
+}
+
+fn map_expirations(map: &MapCRDT, now: Timestamp) -> FxHashMap<Selector, Timestamp> {
+    map.iter(now)
+        .filter_map(|(selector, _)| {
+            map.expires_at(selector, now)
+                .map(|expires_at| (selector.clone(), expires_at))
+        })
+        .collect()
+}
+
+// Regions of the serialized view cache holding each object, reused by the following
+// serializations of the view until the object is marked as dirty (see `View`)
+#[derive(Default)]
+pub(crate) struct EncodedObjects {
+    regions: Mutex<FxHashMap<ObjRef, Bytes>>,
+}
+
+impl EncodedObjects {
+    pub(crate) fn mark_dirty(&mut self, object: &ObjRef) {
+        self.regions_mut().remove(object);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.regions_mut().clear();
+    }
+
+    fn regions_mut(&mut self) -> &mut FxHashMap<ObjRef, Bytes> {
+        self.regions
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn regions(&self) -> MutexGuard<'_, FxHashMap<ObjRef, Bytes>> {
+        self.regions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clone for EncodedObjects {
+    fn clone(&self) -> Self {
+        Self {
+            regions: Mutex::new(self.regions().clone()),
+        }
+    }
+}
+
+// Writes the same bytes as serializing `ViewCache::from(view)`, but only the objects that
+// changed since the previous call are encoded again. Maps holding values with a TTL are
+// never reused, as their contents depend on the time.
+pub(crate) fn serialize_view(view: &View, encoded: &EncodedObjects) -> Vec<u8> {
+    let now = view.now();
+    let mut regions = encoded.regions();
+
+    let mut sorted_keys: Vec<&ObjRef> = view.objects.keys().collect();
+    sorted_keys.sort_by(|a, b| compare_obj_refs(a, b));
+
+    let mut buf = BytesMut::new();
+
+    let items_len: u32 = sorted_keys.len().try_into().expect("too many items");
+    buf.put_u32_varint(items_len);
+
+    let mut expirations = FxHashMap::default();
+    for obj_ref in sorted_keys {
+        serialize_obj_ref(obj_ref, &mut buf);
+        if let Some(region) = regions.get(obj_ref) {
+            buf.put_slice(region);
+            continue;
+        }
+
+        let object_value = view.objects[obj_ref].as_ref();
+        let mut region = BytesMut::new();
+        match object_value {
+            ObjectValue::Map(map) => {
+                let map_conflicts = map_conflicts(map, now);
+                serialize_cached_object_value(
+                    &CachedObjectValue::from_object(object_value, now),
+                    Some(&map_conflicts),
+                    &mut region,
+                );
+                if map.has_expiring_values() {
+                    expirations.insert(*obj_ref, map_expirations(map, now));
+                    buf.put_slice(&region);
+                    continue;
+                }
+            }
+            ObjectValue::Text(_) => serialize_cached_object_value(
+                &CachedObjectValue::from_object(object_value, now),
+                None,
+                &mut region,
+            ),
+        }
+
+        buf.put_slice(&region);
+        regions.insert(*obj_ref, region.freeze());
+    }
+
+    serialize_expirations(&expirations, &mut buf);
+    buf.to_vec()
+}
+
 enum CachedObjectValueType {
     Map,
     Text,
@@ -107,12 +439,17 @@ impl From<CachedObjectValueType> for u8 {
     }
 }
 
-impl From<u8> for CachedObjectValueType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for CachedObjectValueType {
+    type Error = SerializationError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Self::Map,
-            2 => Self::Text,
-            _ => panic!("invalid cached object value type"),
+            1 => Ok(Self::Map),
+            2 => Ok(Self::Text),
+            _ => Err(SerializationError::Malformed(format!(
+                "invalid cached object value type: {}",
+                value
+            ))),
         }
     }
 }
@@ -120,18 +457,7 @@ impl From<u8> for CachedObjectValueType {
 impl Serializable for ViewCache {
     fn serialize(&self) -> Result<Vec<u8>, crate::serde::SerializationError> {
         let mut sorted_keys: Vec<&ObjRef> = self.objects.keys().collect();
-        sorted_keys.sort_by(|a, b| match (a, b) {
-            (ObjRef::Root, ObjRef::Root) => Ordering::Equal,
-            (ObjRef::Root, ObjRef::Object(_)) => Ordering::Less,
-            (ObjRef::Object(_), ObjRef::Root) => Ordering::Greater,
-            (ObjRef::Object(a), ObjRef::Object(b)) => {
-                if a.client_id == b.client_id {
-                    a.sequence.cmp(&b.sequence)
-                } else {
-                    a.client_id.cmp(&b.client_id)
-                }
-            }
-        });
+        sorted_keys.sort_by(|a, b| compare_obj_refs(a, b));
 
         let mut buf = BytesMut::new();
 
@@ -140,39 +466,100 @@ impl Serializable for ViewCache {
 
         for obj_ref in sorted_keys {
             serialize_obj_ref(obj_ref, &mut buf);
-            let object_value = self.objects.get(obj_ref).expect("object not found");
-            serialize_cached_object_value(object_value, &mut buf);
+            let decoded = self.objects[obj_ref].get(self.layout);
+            serialize_cached_object_value(&decoded.value, Some(&decoded.conflicts), &mut buf);
         }
 
+        serialize_expirations(&self.expirations, &mut buf);
+
         Ok(buf.to_vec())
     }
 }
 
-fn serialize_cached_object_value(value: &CachedObjectValue, buf: &mut BytesMut) {
+fn serialize_expirations(
+    expirations: &FxHashMap<ObjRef, FxHashMap<Selector, Timestamp>>,
+    buf: &mut BytesMut,
+) {
+    let mut expirations: Vec<(&ObjRef, &Selector, Timestamp)> = expirations
+        .iter()
+        .flat_map(|(obj_ref, expirations)| {
+            expirations
+                .iter()
+                .map(move |(selector, expires_at)| (obj_ref, selector, *expires_at))
+        })
+        .collect();
+    expirations.sort_by(|(a, a_selector, _), (b, b_selector, _)| {
+        compare_obj_refs(a, b).then_with(|| a_selector.cmp(b_selector))
+    });
+    buf.put_u32_varint(expirations.len() as u32);
+    for (obj_ref, selector, expires_at) in expirations {
+        serialize_obj_ref(obj_ref, buf);
+        serialize_selector(selector, buf);
+        buf.put_u64_varint(expires_at);
+    }
+}
+
+fn compare_obj_refs(a: &ObjRef, b: &ObjRef) -> Ordering {
+    match (a, b) {
+        (ObjRef::Root, ObjRef::Root) => Ordering::Equal,
+        (ObjRef::Root, ObjRef::Object(_)) => Ordering::Less,
+        (ObjRef::Object(_), ObjRef::Root) => Ordering::Greater,
+        (ObjRef::Object(a), ObjRef::Object(b)) => {
+            if a.client_id == b.client_id {
+                a.sequence.cmp(&b.sequence)
+            } else {
+                a.client_id.cmp(&b.client_id)
+            }
+        }
+    }
+}
+
+fn serialize_cached_object_value(
+    value: &CachedObjectValue,
+    conflicts: Option<&FxHashMap<Selector, u32>>,
+    buf: &mut BytesMut,
+) {
     match value {
         CachedObjectValue::Map(map) => {
             buf.put_u8(CachedObjectValueType::Map.into());
             buf.put_u32_varint(map.len() as u32);
-            for (selector, value) in map.iter() {
+            // Sorted, so that replicas with the same content write the same bytes
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(selector, _)| *selector);
+            for (selector, value) in entries {
                 serialize_selector(selector, buf);
                 serialize_value(value, buf);
             }
+
+            // Maps without conflicts only pay for the (empty) length
+            let conflicts_len = conflicts.map(|conflicts| conflicts.len()).unwrap_or(0);
+            buf.put_u32_varint(conflicts_len as u32);
+            let mut conflicts: Vec<_> = conflicts.into_iter().flatten().collect();
+            conflicts.sort_by_key(|(selector, _)| *selector);
+            for (selector, count) in conflicts {
+                serialize_selector(selector, buf);
+                buf.put_u32_varint(*count);
+            }
         }
         CachedObjectValue::Text(text) => {
             buf.put_u8(CachedObjectValueType::Text.into());
 
-            let text_len: u32 = text.len().try_into().expect("text too large");
-            buf.put_u32_varint(text_len);
-            buf.put_slice(text.as_bytes());
+            let chunks: Vec<&str> = text.chunks().collect();
+            buf.put_u32_varint(chunks.len() as u32);
+            for chunk in chunks {
+                let chunk_len: u32 = chunk.len().try_into().expect("chunk too large");
+                buf.put_u32_varint(chunk_len);
+                buf.put_slice(chunk.as_bytes());
+            }
         }
     }
 }
 
 fn deserialize_cached_value_object(
     buf: &mut Bytes,
-) -> Result<CachedObjectValue, SerializationError> {
-    let value_type = buf.get_u8();
-    let value_type: CachedObjectValueType = value_type.into();
+    layout: TextLayout,
+) -> Result<(CachedObjectValue, FxHashMap<Selector, u32>), SerializationError> {
+    let value_type = CachedObjectValueType::try_from(read_u8(buf, "cached object type")?)?;
 
     match value_type {
         CachedObjectValueType::Map => {
@@ -187,19 +574,103 @@ fn deserialize_cached_value_object(
                 map.insert(selector, value);
             }
 
-            Ok(CachedObjectValue::Map(map))
-        }
-        CachedObjectValueType::Text => {
-            let text_len = buf.get_u32_varint().map_err(|_| {
-                SerializationError::Malformed("unable to read text len".to_string())
+            let conflicts_len = buf.get_u32_varint().map_err(|_| {
+                SerializationError::Malformed("unable to read conflicts len".to_string())
             })?;
 
-            let text = buf.copy_to_bytes(text_len as usize);
-            Ok(CachedObjectValue::Text(
-                String::from_utf8(text.to_vec()).map_err(|_| {
-                    SerializationError::Malformed("unable to read text".to_string())
+            let mut conflicts = FxHashMap::default();
+            for _ in 0..conflicts_len {
+                let selector = deserialize_selector(buf)?;
+                let count = buf.get_u32_varint().map_err(|_| {
+                    SerializationError::Malformed("unable to read conflict count".to_string())
+                })?;
+                conflicts.insert(selector, count);
+            }
+
+            Ok((CachedObjectValue::Map(map), conflicts))
+        }
+        CachedObjectValueType::Text => {
+            let chunks_len = match layout {
+                TextLayout::Contiguous => 1,
+                TextLayout::Chunked => buf.get_u32_varint().map_err(|_| {
+                    SerializationError::Malformed("unable to read text chunks len".to_string())
                 })?,
-            ))
+            };
+
+            let mut text = CachedText::default();
+            for _ in 0..chunks_len {
+                let chunk_len = buf.get_u32_varint().map_err(|_| {
+                    SerializationError::Malformed("unable to read text chunk len".to_string())
+                })?;
+                text.push_chunk(&read_string(buf, chunk_len, "text chunk")?);
+            }
+
+            Ok((CachedObjectValue::Text(text), FxHashMap::default()))
         }
     }
 }
+
+// Version 2 of the format splits the texts of the view cache in chunks
+pub(crate) struct ChunkedTextMigration;
+
+impl FormatMigration for ChunkedTextMigration {
+    fn source_version(&self) -> u32 {
+        1
+    }
+
+    fn migrate(&self, mut regions: BufferRegions) -> Result<BufferRegions, SerializationError> {
+        let cache = ViewCache::from_buffer_with_layout(
+            Bytes::from(regions.view_cache),
+            TextLayout::Contiguous,
+        )?;
+        regions.view_cache = cache.serialize()?;
+        Ok(regions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ObjId;
+
+    use super::*;
+
+    #[test]
+    fn test_migration_splits_texts_in_chunks() {
+        let text_ref = ObjRef::Object(ObjId {
+            client_id: 0,
+            sequence: 1,
+        });
+
+        // Root map with a single text, as written by version 1 of the format
+        let mut buf = BytesMut::new();
+        buf.put_u32_varint(2);
+        serialize_obj_ref(&ObjRef::Root, &mut buf);
+        buf.put_u8(CachedObjectValueType::Map.into());
+        buf.put_u32_varint(1);
+        serialize_selector(&Selector::from("text"), &mut buf);
+        serialize_value(&Value::Object(text_ref), &mut buf);
+        buf.put_u32_varint(0);
+        serialize_obj_ref(&text_ref, &mut buf);
+        buf.put_u8(CachedObjectValueType::Text.into());
+        buf.put_u32_varint(5);
+        buf.put_slice(b"Hello");
+
+        let regions = ChunkedTextMigration
+            .migrate(BufferRegions {
+                view_cache: buf.to_vec(),
+                client_registry: Vec::new(),
+                operation_log: Vec::new(),
+            })
+            .unwrap();
+        let cache = ViewCache::from_buffer(Bytes::from(regions.view_cache)).unwrap();
+
+        let text = cache
+            .get_object(text_ref)
+            .unwrap()
+            .unwrap()
+            .as_text()
+            .unwrap();
+        assert_eq!(text.as_str(), "Hello");
+        assert_eq!(text.chunks().collect::<Vec<_>>(), vec!["Hello"]);
+    }
+}
@@ -1,32 +1,165 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, ops::Range, sync::Arc};
 
 use rustc_hash::FxHashMap;
 use thiserror::Error;
 
 use crate::{
-    client_registry::ClientRegistry,
+    client_registry::{ClientRegistry, ClientRemappable, ClientRemappings},
     crdt::{
-        map::map::{DeleteParams, MapCRDT, SetParams},
-        text::TextCRDT,
+        map::map::{DeleteParams, KeyRevision, MapCRDT, MapError, MoveParams, SetParams},
+        shared::tree::SequenceError,
+        text::{TextCRDT, DEFAULT_MAX_BLOCK_LEN},
     },
     operation_log::OperationLog,
     serde::Serializable,
-    ClientId, DataMap, DataMapValue, ObjRef, ObjectValue, Operation, OperationAction, Selector,
-    Value,
+    ClientId, Clock, Conflict, ConflictPolicy, DataMap, DataMapValue, GlobalClientId, ObjId,
+    ObjRef, ObjectValue, Operation, OperationAction, OperationId, Path, Selector, SequenceBlockId,
+    SystemClock, TextFanout, Timestamp, Value, ValueKind,
 };
 
-use super::ViewCache;
+use super::cache::{serialize_view, EncodedObjects};
 
+// Objects are shared with the snapshots of the document, and copied on their first
+// modification after a snapshot, so taking one doesn't copy the whole view
+#[derive(Clone)]
 pub struct View {
-    pub(crate) objects: FxHashMap<ObjRef, ObjectValue>,
+    pub(crate) objects: FxHashMap<ObjRef, Arc<ObjectValue>>,
+    // Every location an object has been placed in, keyed by the operation that placed it
+    placements: FxHashMap<OperationId, Placement>,
+    // Sorted by the order in which moves are applied
+    moves: Vec<ObjectMove>,
+    // Moves prepared since the last call to `resolve_moves`
+    new_moves: Vec<ObjectMove>,
+    current_placements: FxHashMap<ObjId, OperationId>,
+    // Operations of different clients are not ordered with each other, so an operation can be
+    // received before the one creating its object, or before the values and characters it
+    // refers to. It waits here, by the object it's missing, until it can be applied.
+    pending: FxHashMap<ObjRef, Vec<Operation>>,
+    drop_tombstones: bool,
+    max_text_block_len: u32,
+    text_fanout: TextFanout,
+    conflict_policy: ConflictPolicy,
+    key_conflict_policies: FxHashMap<Selector, ConflictPolicy>,
+    // Values written with a TTL are read against it, see `MapCRDT::get`
+    clock: Arc<dyn Clock>,
+    // Objects are marked as dirty whenever they are modified, so that serializing the view
+    // only encodes again the objects that changed since the previous time
+    encoded_objects: EncodedObjects,
+}
+
+#[derive(Clone)]
+struct Placement {
+    object: ObjId,
+    parent: ObjRef,
+}
+
+#[derive(Clone)]
+struct ObjectMove {
+    id: OperationId,
+    timestamp: Timestamp,
+    // Local IDs differ between replicas, so moves are ordered by the global one
+    client: GlobalClientId,
+    object: ObjId,
+    parent: ObjRef,
+    // Placement of the object before the move, `None` if the move was skipped
+    replaced: Option<OperationId>,
+}
+
+impl ObjectMove {
+    fn order_key(&self) -> (Timestamp, &GlobalClientId, u32) {
+        (self.timestamp, &self.client, self.id.sequence)
+    }
 }
 
 impl<'a> View {
     pub fn new(client_id: ClientId) -> Self {
         let mut objects = FxHashMap::default();
-        objects.insert(ObjRef::Root, ObjectValue::Map(MapCRDT::new(client_id)));
+        objects.insert(
+            ObjRef::Root,
+            Arc::new(ObjectValue::Map(MapCRDT::new(client_id))),
+        );
+
+        Self {
+            objects,
+            placements: FxHashMap::default(),
+            moves: Vec::new(),
+            new_moves: Vec::new(),
+            current_placements: FxHashMap::default(),
+            pending: FxHashMap::default(),
+            drop_tombstones: false,
+            max_text_block_len: DEFAULT_MAX_BLOCK_LEN,
+            text_fanout: TextFanout::default(),
+            conflict_policy: ConflictPolicy::default(),
+            key_conflict_policies: FxHashMap::default(),
+            clock: Arc::new(SystemClock),
+            encoded_objects: EncodedObjects::default(),
+        }
+    }
+
+    // Texts created from now on (including the ones rebuilt on repopulation) follow this setting too
+    pub fn set_drop_tombstones(&mut self, enabled: bool) {
+        self.drop_tombstones = enabled;
+        for (obj_ref, object) in self.objects.iter_mut() {
+            if let ObjectValue::Text(text) = Arc::make_mut(object) {
+                text.set_drop_tombstones(enabled);
+                self.encoded_objects.mark_dirty(obj_ref);
+            }
+        }
+    }
+
+    pub fn set_max_text_block_len(&mut self, len: u32) {
+        self.max_text_block_len = len;
+        for (obj_ref, object) in self.objects.iter_mut() {
+            if let ObjectValue::Text(text) = Arc::make_mut(object) {
+                text.set_max_block_len(len);
+                self.encoded_objects.mark_dirty(obj_ref);
+            }
+        }
+    }
+
+    // Existing texts keep their tree, only the ones created from now on are affected
+    pub fn set_text_fanout(&mut self, fanout: TextFanout) {
+        self.text_fanout = fanout;
+    }
+
+    pub fn set_conflict_policies(
+        &mut self,
+        policy: ConflictPolicy,
+        key_policies: FxHashMap<Selector, ConflictPolicy>,
+    ) {
+        self.conflict_policy = policy;
+        self.key_conflict_policies = key_policies;
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    // Drops the contents of the values that expired before `now`, see `MapCRDT::clear_expired_values`
+    pub fn clear_expired_values(&mut self, now: Timestamp) -> usize {
+        let mut cleared = 0;
+        for (obj_ref, object) in self.objects.iter_mut() {
+            // Maps shared with snapshots are only copied when there is something to clear
+            if let ObjectValue::Map(map) = object.as_ref() {
+                if map.has_expired_values(now) {
+                    if let ObjectValue::Map(map) = Arc::make_mut(object) {
+                        cleared += map.clear_expired_values(now);
+                    }
+                    self.encoded_objects.mark_dirty(obj_ref);
+                }
+            }
+        }
+        cleared
+    }
 
-        Self { objects }
+    fn conflict_policy(&self, selector: &Selector) -> &ConflictPolicy {
+        self.key_conflict_policies
+            .get(selector)
+            .unwrap_or(&self.conflict_policy)
     }
 
     pub fn get_object<TRef: Into<ObjRef>>(
@@ -34,7 +167,7 @@ impl<'a> View {
         object: TRef,
     ) -> Result<Option<&ObjectValue>, ViewError> {
         let obj_ref: &ObjRef = &object.into();
-        let object_value = self.objects.get(&obj_ref);
+        let object_value = self.objects.get(obj_ref).map(Arc::as_ref);
         Ok(object_value)
     }
 
@@ -43,22 +176,137 @@ impl<'a> View {
         object: TRef,
     ) -> Result<Option<&mut ObjectValue>, ViewError> {
         let obj_ref: &ObjRef = &object.into();
-        let object_value = self.objects.get_mut(&obj_ref);
+        self.encoded_objects.mark_dirty(obj_ref);
+        let object_value = self.objects.get_mut(obj_ref).map(Arc::make_mut);
         Ok(object_value)
     }
 
     pub fn get(&self, object: ObjRef, selector: Selector) -> Result<Option<&Value>, ViewError> {
         let map = self.get_object(object)?;
         match map {
-            Some(ObjectValue::Map(map)) => Ok(map.get(&selector)),
-            Some(val) => Err(ViewError::IncompatibleTypes(format!(
-                "expected map, found: {:?}",
-                val
-            ))),
+            Some(ObjectValue::Map(map)) => {
+                Ok(map.get_with_policy(&selector, self.conflict_policy(&selector), self.now()))
+            }
+            Some(val) => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
             None => Ok(None),
         }
     }
 
+    // All the concurrent values of the key with the multi-value policy, otherwise the read one
+    pub fn get_all(&self, object: ObjRef, selector: Selector) -> Result<Vec<&Value>, ViewError> {
+        if !matches!(self.conflict_policy(&selector), ConflictPolicy::MultiValue) {
+            return Ok(self.get(object, selector)?.into_iter().collect());
+        }
+
+        // The values of the conflicts that are still visible, the last one wins
+        Ok(self
+            .get_conflicts(object, selector)?
+            .into_iter()
+            .filter(|conflict| !conflict.deleted)
+            .map(|conflict| conflict.value)
+            .collect())
+    }
+
+    pub fn get_conflicts(
+        &self,
+        object: ObjRef,
+        selector: Selector,
+    ) -> Result<Vec<Conflict<'_>>, ViewError> {
+        let map = self.get_object(object)?;
+        match map {
+            Some(ObjectValue::Map(map)) => Ok(map.get_conflicts(&selector, self.now())),
+            Some(val) => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn get_key_history(
+        &self,
+        object: ObjRef,
+        selector: Selector,
+    ) -> Result<Vec<KeyRevision<'_>>, ViewError> {
+        let map = self.get_object(object)?;
+        match map {
+            Some(ObjectValue::Map(map)) => Ok(map.key_history(&selector, self.now())),
+            Some(val) => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn get_conflict_count(
+        &self,
+        object: ObjRef,
+        selector: Selector,
+    ) -> Result<usize, ViewError> {
+        let map = self.get_object(object)?;
+        match map {
+            Some(ObjectValue::Map(map)) => Ok(map.conflict_count(&selector, self.now())),
+            Some(val) => Err(ViewError::incompatible(&object, ValueKind::Map, val)),
+            None => Ok(0),
+        }
+    }
+
+    // Whether the object is the given ancestor, or is nested inside it
+    pub fn is_descendant(&self, object: &ObjRef, ancestor: &ObjRef) -> bool {
+        let ObjRef::Object(ancestor) = ancestor else {
+            return true;
+        };
+
+        let mut current = object;
+        loop {
+            match current {
+                ObjRef::Root => return false,
+                ObjRef::Object(id) if id == ancestor => return true,
+                ObjRef::Object(id) => match self.current_placements.get(id) {
+                    Some(placement) => current = &self.placements[placement].parent,
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    // The root key under which the operation is currently visible, if any
+    pub fn top_level_key(&self, operation: &Operation) -> Option<Selector> {
+        let mut object = match operation.action.object() {
+            ObjRef::Root => return operation.action.selector().cloned(),
+            ObjRef::Object(id) => *id,
+        };
+
+        loop {
+            let placement = self.current_placements.get(&object)?;
+            match &self.placements.get(placement)?.parent {
+                ObjRef::Root => break,
+                ObjRef::Object(parent) => object = *parent,
+            }
+        }
+
+        let object = ObjRef::Object(object);
+        let root = self.get_object(ObjRef::Root).ok()??.as_map()?;
+        root.iter(self.now())
+            .find(|(_, value)| value.as_object() == Some(&object))
+            .map(|(key, _)| key.clone())
+    }
+
+    // The keys leading to the object from the root, if it's currently reachable
+    pub fn path_of(&self, object: &ObjRef) -> Option<Path> {
+        let mut selectors = Vec::new();
+        let mut object = *object;
+        while let ObjRef::Object(id) = object {
+            let placement = self.current_placements.get(&id)?;
+            let parent = &self.placements.get(placement)?.parent;
+            let map = self.get_object(*parent).ok()??.as_map()?;
+            let child = ObjRef::Object(id);
+            let (selector, _) = map
+                .iter(self.now())
+                .find(|(_, value)| value.as_object() == Some(&child))?;
+            selectors.push(selector.clone());
+            object = *parent;
+        }
+
+        selectors.reverse();
+        Some(Path::from(selectors))
+    }
+
     pub fn as_map(&'a self) -> DataMap<'a> {
         self.as_map_recursive(&ObjRef::Root)
             .into_map()
@@ -67,16 +315,24 @@ impl<'a> View {
 
     fn as_map_recursive(&'a self, obj_ref: &ObjRef) -> DataMapValue {
         let obj = self.objects.get(&obj_ref).expect("object not found");
-        match obj {
+        match obj.as_ref() {
             ObjectValue::Map(map) => {
+                let now = self.now();
                 let mut data_map: DataMap = DataMap::default();
-                for (selector, value) in map.iter() {
+                for (selector, value) in map.iter(now) {
+                    let value = map
+                        .get_with_policy(selector, self.conflict_policy(selector), now)
+                        .unwrap_or(value);
                     let data_map_value: DataMapValue<'a> = match value {
                         Value::Scalar(scalar) => match scalar {
                             crate::ScalarValue::String(string) => DataMapValue::String(string),
                             crate::ScalarValue::Int(int) => DataMapValue::Int(int),
                             crate::ScalarValue::Double(double) => DataMapValue::Double(double),
                             crate::ScalarValue::Bool(bool) => DataMapValue::Bool(bool),
+                            crate::ScalarValue::Timestamp(timestamp) => {
+                                DataMapValue::Timestamp(timestamp)
+                            }
+                            crate::ScalarValue::Null => DataMapValue::Null,
                         },
                         Value::Object(obj_ref) => self.as_map_recursive(&obj_ref),
                     };
@@ -88,7 +344,7 @@ impl<'a> View {
         }
     }
 
-    pub fn apply_local_operation(
+    pub fn apply_operation(
         &mut self,
         operation: &Operation,
         client_registry: &ClientRegistry,
@@ -101,16 +357,54 @@ impl<'a> View {
         log: &OperationLog,
         client_registry: &ClientRegistry,
     ) -> Result<(), ViewError> {
-        // TODO: make this actually efficient
-        // TODO: if log sequence is still compatible with view history, just execute the latest operations
-        // TODO: if log sequence is NOT compatible with view history, recompute the whole view
+        // Only needed when the view can't be updated incrementally, see `FullDoc::update_view`
+
+        self.reset(client_registry);
+        self.replay_log(log, 0..log.len(), client_registry)
+    }
+
+    // Replays a range of the log, after the operations before it were replayed
+    pub fn replay_log(
+        &mut self,
+        log: &OperationLog,
+        operations: Range<usize>,
+        client_registry: &ClientRegistry,
+    ) -> Result<(), ViewError> {
+        #[cfg(feature = "parallel")]
+        self.execute_operations_parallel(log, operations, client_registry)?;
+        #[cfg(not(feature = "parallel"))]
+        self.replay(
+            log.iter_from(operations.start).take(operations.len()),
+            client_registry,
+        )?;
+
+        Ok(())
+    }
 
+    // Leaves only an empty root, so that the log can be replayed over it
+    pub fn reset(&mut self, client_registry: &ClientRegistry) {
         self.objects.clear();
+        self.placements.clear();
+        self.moves.clear();
+        self.new_moves.clear();
+        self.current_placements.clear();
+        self.pending.clear();
+        self.encoded_objects.clear();
         self.objects.insert(
             ObjRef::Root,
-            ObjectValue::Map(MapCRDT::new(client_registry.get_current_id())),
+            Arc::new(ObjectValue::Map(MapCRDT::new(
+                client_registry.get_current_id(),
+            ))),
         );
-        for operation in log.iter() {
+    }
+
+    // Operations must be replayed in the order of the log, see `FullDocBuilder`
+    pub fn replay<'o>(
+        &mut self,
+        operations: impl Iterator<Item = &'o Operation>,
+        client_registry: &ClientRegistry,
+    ) -> Result<(), ViewError> {
+        for operation in operations {
             self.execute_operation(operation, client_registry)?;
         }
 
@@ -122,111 +416,655 @@ impl<'a> View {
         operation: &Operation,
         client_registry: &ClientRegistry,
     ) -> Result<(), ViewError> {
+        if let Some(waiting_for) = self.missing_dependency(operation) {
+            self.pending
+                .entry(waiting_for)
+                .or_default()
+                .push(operation.clone());
+            return Ok(());
+        }
+
+        if let Some(target) = self.prepare_operation(operation, client_registry)? {
+            self.encoded_objects.mark_dirty(&target);
+            let object = self
+                .objects
+                .get_mut(&target)
+                .expect("target object should exist");
+            apply_to_object(Arc::make_mut(object), operation)?;
+        }
+
+        if matches!(operation.action, OperationAction::MoveObject(_)) {
+            self.resolve_moves()?;
+        }
+
+        // The operation might have created the object or written the values and characters
+        // that other ones are waiting for
+        for object in [ObjRef::from(operation.id), *operation.action.object()] {
+            if let Some(pending) = self.pending.remove(&object) {
+                for operation in pending {
+                    self.execute_operation(&operation, client_registry)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The object the operation has to wait for: a missing object, or the one it targets, when
+    // it refers to values or characters that are missing from it
+    fn missing_dependency(&self, operation: &Operation) -> Option<ObjRef> {
+        if let Some(missing) = self.missing_object(operation) {
+            return Some(*missing);
+        }
+
+        let target = operation.action.object();
+        if is_applicable(&self.objects[target], operation) {
+            None
+        } else {
+            Some(*target)
+        }
+    }
+
+    // Moves also wait for the moved object, as they are only resolved when a move is applied
+    fn missing_object<'o>(&self, operation: &'o Operation) -> Option<&'o ObjRef> {
+        let moved_object = match &operation.action {
+            OperationAction::MoveObject(action) => Some(&action.moved_object),
+            _ => None,
+        };
+
+        std::iter::once(operation.action.object())
+            .chain(moved_object)
+            .find(|object| !self.objects.contains_key(*object))
+    }
+
+    // Operations only modify the object they target, so once the hierarchy is known,
+    // each object can be rebuilt independently from the others.
+    #[cfg(feature = "parallel")]
+    fn execute_operations_parallel(
+        &mut self,
+        log: &OperationLog,
+        operations: Range<usize>,
+        client_registry: &ClientRegistry,
+    ) -> Result<(), ViewError> {
+        use rayon::prelude::*;
+
+        // Operations received before the ones they depend on are rare, so the log is replayed
+        // sequentially when there are any, see `execute_operation`
+        let mut operations_by_object: FxHashMap<ObjRef, Vec<&Operation>> = FxHashMap::default();
+        let replayed = operations.end;
+        for operation in log.iter_from(operations.start).take(operations.len()) {
+            if self.missing_object(operation).is_some() {
+                return self.replay_from_scratch(log, replayed, client_registry);
+            }
+
+            if let Some(target) = self.prepare_operation(operation, client_registry)? {
+                operations_by_object
+                    .entry(target)
+                    .or_default()
+                    .push(operation);
+            }
+        }
+
+        for target in operations_by_object.keys() {
+            self.encoded_objects.mark_dirty(target);
+        }
+
+        // Only objects with operations are rebuilt, the largest ones first, so that a few big
+        // texts don't end up being replayed last on an otherwise idle pool
+        let mut objects: Vec<(&mut ObjectValue, Vec<&Operation>)> = self
+            .objects
+            .iter_mut()
+            .filter_map(|(obj_ref, object)| {
+                operations_by_object
+                    .remove(obj_ref)
+                    .map(|operations| (Arc::make_mut(object), operations))
+            })
+            .collect();
+        objects.sort_by_key(|(_, operations)| std::cmp::Reverse(operations.len()));
+
+        let applied_in_order = objects
+            .into_par_iter()
+            .with_max_len(1)
+            .map(|(object, operations)| {
+                for operation in operations {
+                    if !is_applicable(object, operation) {
+                        return Ok(false);
+                    }
+                    apply_to_object(object, operation)?;
+                }
+                Ok(true)
+            })
+            .collect::<Result<Vec<bool>, ViewError>>()?;
+        if applied_in_order.contains(&false) {
+            return self.replay_from_scratch(log, replayed, client_registry);
+        }
+
+        // Moves only depend on the final set of placements, so they are resolved once per batch
+        self.resolve_moves()
+    }
+
+    #[cfg(feature = "parallel")]
+    fn replay_from_scratch(
+        &mut self,
+        log: &OperationLog,
+        replayed: usize,
+        client_registry: &ClientRegistry,
+    ) -> Result<(), ViewError> {
+        self.reset(client_registry);
+        self.replay(log.iter().take(replayed), client_registry)
+    }
+
+    // Updates the object hierarchy and returns the object the operation should be applied to
+    fn prepare_operation(
+        &mut self,
+        operation: &Operation,
+        client_registry: &ClientRegistry,
+    ) -> Result<Option<ObjRef>, ViewError> {
         match &operation.action {
             OperationAction::CreateMap(action) => {
-                let obj_ref = ObjRef::from(operation.id);
                 self.objects.insert(
-                    obj_ref.clone(),
-                    ObjectValue::Map(MapCRDT::new(client_registry.get_current_id())),
+                    ObjRef::from(operation.id),
+                    Arc::new(ObjectValue::Map(MapCRDT::new(
+                        client_registry.get_current_id(),
+                    ))),
                 );
+                self.register_creation(operation.id, &action.object);
 
-                let map = self.get_map_mut(&action.object)?;
-                map.set(SetParams {
-                    selector: action.selector.clone(),
-                    id: action.id.clone(),
-                    parents: action.parents.clone(),
-                    timestamp: operation.timestamp,
-                    value: Value::Object(obj_ref),
-                })
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::CreateText(action) => {
+                let mut text =
+                    TextCRDT::new_with_fanout(client_registry.get_current_id(), self.text_fanout);
+                text.set_drop_tombstones(self.drop_tombstones);
+                text.set_max_block_len(self.max_text_block_len);
+                self.objects.insert(
+                    ObjRef::from(operation.id),
+                    Arc::new(ObjectValue::Text(text)),
+                );
+                self.register_creation(operation.id, &action.object);
+
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
             }
             OperationAction::SetMapValue(action) => {
-                let map = self.get_map_mut(&action.object)?;
-                map.set(SetParams {
-                    selector: action.selector.clone(),
-                    id: action.id.clone(),
-                    parents: action.parents.clone(),
-                    timestamp: operation.timestamp,
-                    value: action.value.clone(),
-                });
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::SetMapValues(action) => {
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
             }
             OperationAction::DeleteMapValue(action) => {
-                let map = self.get_map_mut(&action.object)?;
-                map.delete(DeleteParams {
-                    selector: action.selector.clone(),
-                    parents: action.parents.clone(),
-                });
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
             }
-            OperationAction::CreateText(action) => {
-                let obj_ref = ObjRef::from(operation.id);
+            OperationAction::MoveMapValue(action) => {
+                self.get_map_mut(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::InsertText(action) => {
+                self.check_text(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::DeleteText(action) => {
+                self.check_text(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::DeleteTextRanges(action) => {
+                self.check_text(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::DeleteTextItems(action) => {
+                self.check_text(&action.object)?;
+                Ok(Some(action.object))
+            }
+            OperationAction::CreateAnnotation(action) => {
+                self.check_text(&action.object)?;
+                // The payload isn't placed in any map, it's only reachable from the text
                 self.objects.insert(
-                    obj_ref.clone(),
-                    ObjectValue::Text(TextCRDT::new(client_registry.get_current_id())),
+                    ObjRef::from(operation.id),
+                    Arc::new(ObjectValue::Map(MapCRDT::new(
+                        client_registry.get_current_id(),
+                    ))),
                 );
+                Ok(Some(action.object))
+            }
+            OperationAction::MoveObject(action) => {
+                let ObjRef::Object(moved_object) = action.moved_object else {
+                    return Err(ViewError::RootMoved(operation.id));
+                };
 
-                let map = self.get_map_mut(&action.object)?;
-                map.set(SetParams {
-                    selector: action.selector.clone(),
-                    id: action.id.clone(),
-                    parents: action.parents.clone(),
+                self.placements.insert(
+                    operation.id,
+                    Placement {
+                        object: moved_object,
+                        parent: action.object,
+                    },
+                );
+                self.get_map_mut(&action.object)?;
+
+                let client = client_registry
+                    .get_global_id(operation.id.client_id)
+                    .ok_or(ViewError::UnknownClient(operation.id.client_id))?;
+                self.new_moves.push(ObjectMove {
+                    id: operation.id,
                     timestamp: operation.timestamp,
-                    value: Value::Object(obj_ref),
-                })
+                    client: client.clone(),
+                    object: moved_object,
+                    parent: action.object,
+                    replaced: None,
+                });
+
+                Ok(Some(action.object))
             }
-            OperationAction::InsertText(action) => {
-                let obj = self.get_object_mut(&action.object)?;
-                match obj {
-                    Some(ObjectValue::Text(text)) => text.insert(&action),
-                    // TODO: handle better! What should happen in this case?
-                    _ => {}
-                }
+        }
+    }
+
+    fn register_creation(&mut self, object: ObjId, parent: &ObjRef) {
+        self.placements.insert(
+            object,
+            Placement {
+                object,
+                parent: *parent,
+            },
+        );
+        self.current_placements.insert(object, object);
+    }
+
+    // Moves are applied in a total order, so that all replicas agree on the location of
+    // each object, skipping the ones that would move an object inside itself. The moves
+    // ordered after the new ones are undone, and applied again after them.
+    fn resolve_moves(&mut self) -> Result<(), ViewError> {
+        let mut new_moves = std::mem::take(&mut self.new_moves);
+        if new_moves.is_empty() {
+            return Ok(());
+        }
+        new_moves.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+
+        let start = self
+            .moves
+            .partition_point(|other| other.order_key() < new_moves[0].order_key());
+        let undone = self.moves.split_off(start);
+
+        // Placements before the moves, to only update the ones that changed
+        let mut previous: FxHashMap<ObjId, OperationId> = FxHashMap::default();
+        for object_move in undone.iter().rev() {
+            if let Some(replaced) = object_move.replaced {
+                let placement = self
+                    .current_placements
+                    .insert(object_move.object, replaced)
+                    .expect("moved objects should be placed");
+                previous.entry(object_move.object).or_insert(placement);
             }
-            OperationAction::DeleteText(action) => {
-                let obj = self.get_object_mut(&action.object)?;
-                match obj {
-                    Some(ObjectValue::Text(text)) => text.delete(&action),
-                    // TODO: handle better! What should happen in this case?
-                    _ => {}
+        }
+
+        let mut undone = undone.into_iter().peekable();
+        let mut new_moves = new_moves.into_iter().peekable();
+        loop {
+            let next_is_new = match (undone.peek(), new_moves.peek()) {
+                (Some(undone), Some(new)) => new.order_key() < undone.order_key(),
+                (None, Some(_)) => true,
+                (Some(_), None) => false,
+                (None, None) => break,
+            };
+            let mut object_move = if next_is_new {
+                new_moves.next()
+            } else {
+                undone.next()
+            }
+            .expect("the next move was peeked");
+
+            object_move.replaced = match self.current_placements.get(&object_move.object) {
+                Some(placement)
+                    if !self.is_descendant(
+                        &object_move.parent,
+                        &ObjRef::Object(object_move.object),
+                    ) =>
+                {
+                    Some(*placement)
                 }
+                _ => None,
+            };
+            if let Some(replaced) = object_move.replaced {
+                self.current_placements
+                    .insert(object_move.object, object_move.id);
+                previous.entry(object_move.object).or_insert(replaced);
             }
-            _ => {
-                unimplemented!("operation action not implemented");
+            self.moves.push(object_move);
+        }
+
+        for (object, previous) in previous {
+            let placement = self.current_placements[&object];
+            if placement != previous {
+                self.set_placement_detached(previous, true)?;
+                self.set_placement_detached(placement, false)?;
             }
         }
 
         Ok(())
     }
 
+    fn set_placement_detached(
+        &mut self,
+        placement: OperationId,
+        detached: bool,
+    ) -> Result<(), ViewError> {
+        let parent = self.placements[&placement].parent;
+        self.get_map_mut(&parent)?.set_detached(placement, detached);
+        Ok(())
+    }
+
+    fn check_text(&self, object: &ObjRef) -> Result<(), ViewError> {
+        match self.get_object(object)? {
+            Some(ObjectValue::Text(_)) => Ok(()),
+            Some(value) => Err(ViewError::incompatible(object, ValueKind::Text, value)),
+            None => Err(ViewError::ObjectNotFound(*object)),
+        }
+    }
+
     fn get_map_mut(&mut self, object: &ObjRef) -> Result<&mut MapCRDT, ViewError> {
-        let object_value = self.objects.get_mut(object);
+        self.encoded_objects.mark_dirty(object);
+        let object_value = self.objects.get_mut(object).map(Arc::make_mut);
         match object_value {
             Some(ObjectValue::Map(map)) => Ok(map),
-            Some(val) => Err(ViewError::IncompatibleTypes(format!(
-                "expected map, found: {:?}",
-                val
-            ))),
-            None => Err(ViewError::InconsistentHierarchy(format!(
-                "object {:?} not found",
-                object
-            ))),
+            Some(val) => Err(ViewError::incompatible(object, ValueKind::Map, &*val)),
+            None => Err(ViewError::ObjectNotFound(*object)),
+        }
+    }
+}
+
+// Operations refer to the values they overwrite and to the characters they are anchored to,
+// which might not have been received yet when they come from different clients
+fn is_applicable(object: &ObjectValue, operation: &Operation) -> bool {
+    match (object, &operation.action) {
+        (ObjectValue::Map(map), OperationAction::CreateMap(action)) => {
+            map.contains_all(&action.selector, &action.parents)
+        }
+        (ObjectValue::Map(map), OperationAction::CreateText(action)) => {
+            map.contains_all(&action.selector, &action.parents)
+        }
+        (ObjectValue::Map(map), OperationAction::SetMapValue(action)) => {
+            map.contains_all(&action.selector, &action.parents)
+        }
+        (ObjectValue::Map(map), OperationAction::SetMapValues(action)) => action
+            .entries
+            .iter()
+            .all(|entry| map.contains_all(&entry.selector, &entry.parents)),
+        (ObjectValue::Map(map), OperationAction::DeleteMapValue(action)) => {
+            map.contains_all(&action.selector, &action.parents)
+        }
+        (ObjectValue::Map(map), OperationAction::MoveMapValue(action)) => {
+            map.contains_all(&action.to, &action.parents)
+                && map.contains_all(&action.from, &action.sources)
+        }
+        (ObjectValue::Map(map), OperationAction::MoveObject(action)) => {
+            map.contains_all(&action.selector, &action.parents)
+        }
+        (ObjectValue::Text(text), OperationAction::InsertText(action)) => {
+            action.left.iter().all(|left| text.contains(left))
         }
+        (ObjectValue::Text(text), OperationAction::DeleteText(action)) => {
+            text.contains(&action.left) && text.contains(&action.right)
+        }
+        (ObjectValue::Text(text), OperationAction::DeleteTextRanges(action)) => action
+            .ranges
+            .iter()
+            .all(|range| text.contains(&range.left) && text.contains(&range.right)),
+        (ObjectValue::Text(text), OperationAction::DeleteTextItems(action)) => action
+            .ranges
+            .iter()
+            .all(|range| text.contains(&range.left) && text.contains(&range.right)),
+        (ObjectValue::Text(text), OperationAction::CreateAnnotation(action)) => action
+            .start
+            .iter()
+            .chain(action.end.iter())
+            .all(|anchor| text.contains(anchor)),
+        // Left to `prepare_operation`, which rejects them
+        _ => true,
+    }
+}
+
+// Objects are expected to have the type required by the operation, see `prepare_operation`
+fn apply_to_object(object: &mut ObjectValue, operation: &Operation) -> Result<(), ViewError> {
+    let map_error = |error| ViewError::InvalidMapOperation {
+        operation: operation.id,
+        error,
+    };
+
+    match (object, &operation.action) {
+        (ObjectValue::Map(map), OperationAction::CreateMap(action)) => map
+            .set(SetParams {
+                selector: action.selector.clone(),
+                id: action.id.clone(),
+                parents: action.parents.clone(),
+                timestamp: operation.timestamp,
+                value: Value::Object(ObjRef::from(operation.id)),
+                placement: Some(operation.id),
+                expires_at: None,
+            })
+            .map_err(map_error)?,
+        (ObjectValue::Map(map), OperationAction::CreateText(action)) => map
+            .set(SetParams {
+                selector: action.selector.clone(),
+                id: action.id.clone(),
+                parents: action.parents.clone(),
+                timestamp: operation.timestamp,
+                value: Value::Object(ObjRef::from(operation.id)),
+                placement: Some(operation.id),
+                expires_at: None,
+            })
+            .map_err(map_error)?,
+        (ObjectValue::Map(map), OperationAction::SetMapValue(action)) => map
+            .set(SetParams {
+                selector: action.selector.clone(),
+                id: action.id.clone(),
+                parents: action.parents.clone(),
+                timestamp: operation.timestamp,
+                value: action.value.clone(),
+                placement: None,
+                expires_at: action.expires_at,
+            })
+            .map_err(map_error)?,
+        (ObjectValue::Map(map), OperationAction::SetMapValues(action)) => {
+            for (index, entry) in action.entries.iter().enumerate() {
+                map.set(SetParams {
+                    selector: entry.selector.clone(),
+                    id: action.entry_id(index),
+                    parents: entry.parents.clone(),
+                    timestamp: operation.timestamp,
+                    value: entry.value.clone(),
+                    placement: None,
+                    expires_at: None,
+                })
+                .map_err(map_error)?;
+            }
+        }
+        (ObjectValue::Map(map), OperationAction::DeleteMapValue(action)) => map
+            .delete(DeleteParams {
+                selector: action.selector.clone(),
+                parents: action.parents.clone(),
+            })
+            .map_err(map_error)?,
+        (ObjectValue::Map(map), OperationAction::MoveMapValue(action)) => map
+            .move_value(MoveParams {
+                from: action.from.clone(),
+                to: action.to.clone(),
+                id: action.id.clone(),
+                parents: action.parents.clone(),
+                sources: action.sources.clone(),
+                timestamp: operation.timestamp,
+            })
+            .map_err(map_error)?,
+        (ObjectValue::Map(map), OperationAction::MoveObject(action)) => {
+            // The new placement only becomes visible if the move is effective
+            map.set_detached(operation.id, true);
+            map.set(SetParams {
+                selector: action.selector.clone(),
+                id: action.id.clone(),
+                parents: action.parents.clone(),
+                timestamp: operation.timestamp,
+                value: Value::Object(action.moved_object),
+                placement: Some(operation.id),
+                expires_at: None,
+            })
+            .map_err(map_error)?;
+        }
+        (ObjectValue::Text(text), OperationAction::InsertText(action)) => text
+            .insert(action)
+            .map_err(|error| ViewError::InvalidTextOperation {
+                operation: operation.id,
+                error,
+            })?,
+        (ObjectValue::Text(text), OperationAction::DeleteText(action)) => text
+            .delete(action)
+            .map_err(|error| ViewError::InvalidTextOperation {
+                operation: operation.id,
+                error,
+            })?,
+        (ObjectValue::Text(text), OperationAction::DeleteTextRanges(action)) => text
+            .delete_ranges(action)
+            .map_err(|error| ViewError::InvalidTextOperation {
+                operation: operation.id,
+                error,
+            })?,
+        (ObjectValue::Text(text), OperationAction::DeleteTextItems(action)) => text
+            .delete_items(action)
+            .map_err(|error| ViewError::InvalidTextOperation {
+                operation: operation.id,
+                error,
+            })?,
+        (ObjectValue::Text(text), OperationAction::CreateAnnotation(action)) => text
+            .add_annotation(operation.id, action)
+            .map_err(|error| ViewError::InvalidTextOperation {
+                operation: operation.id,
+                error,
+            })?,
+        _ => unreachable!("the type of the object is checked by `prepare_operation`"),
+    }
+
+    Ok(())
+}
+
+// As long as the remapping preserves the relative order of the clients (see `ClientRegistry`),
+// rewriting the ids in place gives the same view as executing the log again
+impl ClientRemappable for View {
+    fn remap_client_ids(&mut self, mappings: &ClientRemappings) {
+        self.encoded_objects.clear();
+        self.objects = std::mem::take(&mut self.objects)
+            .into_iter()
+            .map(|(mut object_ref, mut object)| {
+                object_ref.remap_client_ids(mappings);
+                Arc::make_mut(&mut object).remap_client_ids(mappings);
+                (object_ref, object)
+            })
+            .collect();
+
+        self.placements = std::mem::take(&mut self.placements)
+            .into_iter()
+            .map(|(mut id, mut placement)| {
+                id.remap_client_ids(mappings);
+                placement.object.remap_client_ids(mappings);
+                placement.parent.remap_client_ids(mappings);
+                (id, placement)
+            })
+            .collect();
+
+        for object_move in self.moves.iter_mut().chain(self.new_moves.iter_mut()) {
+            object_move.id.remap_client_ids(mappings);
+            object_move.object.remap_client_ids(mappings);
+            object_move.parent.remap_client_ids(mappings);
+            if let Some(replaced) = object_move.replaced.as_mut() {
+                replaced.remap_client_ids(mappings);
+            }
+        }
+
+        self.current_placements = std::mem::take(&mut self.current_placements)
+            .into_iter()
+            .map(|(mut object, mut placement)| {
+                object.remap_client_ids(mappings);
+                placement.remap_client_ids(mappings);
+                (object, placement)
+            })
+            .collect();
+
+        self.pending = std::mem::take(&mut self.pending)
+            .into_iter()
+            .map(|(mut object, mut operations)| {
+                object.remap_client_ids(mappings);
+                for operation in operations.iter_mut() {
+                    operation.remap_client_ids(mappings);
+                }
+                (object, operations)
+            })
+            .collect();
     }
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ViewError {
-    #[error("inconsistent hierarchy: {0}")]
-    InconsistentHierarchy(String),
+    #[error("object {0:?} not found")]
+    ObjectNotFound(ObjRef),
 
-    #[error("incompatible types: {0}")]
-    IncompatibleTypes(String),
+    #[error("expected {expected}, found {actual} for object {object:?}")]
+    IncompatibleTypes {
+        object: ObjRef,
+        expected: ValueKind,
+        actual: ValueKind,
+    },
+
+    #[error("expected {expected}, found {actual} at {selector:?} of object {object:?}")]
+    IncompatibleValue {
+        object: ObjRef,
+        selector: Selector,
+        expected: ValueKind,
+        actual: ValueKind,
+    },
+
+    #[error("text block {block:?} of object {object:?} not found")]
+    UnknownTextBlock {
+        object: ObjRef,
+        block: SequenceBlockId,
+    },
+
+    #[error("unknown client {0}")]
+    UnknownClient(ClientId),
+
+    #[error("operation {0:?} moves the root object")]
+    RootMoved(OperationId),
 
-    #[error("bad operation: {0}")]
-    BadOperation(String),
+    #[error("operation {operation:?} can't be applied to the text: {error}")]
+    InvalidTextOperation {
+        operation: OperationId,
+        #[source]
+        error: SequenceError,
+    },
+
+    #[error("operation {operation:?} can't be applied to the map: {error}")]
+    InvalidMapOperation {
+        operation: OperationId,
+        #[source]
+        error: MapError,
+    },
+}
+
+impl ViewError {
+    pub(crate) fn incompatible<T: Into<ValueKind>>(
+        object: &ObjRef,
+        expected: ValueKind,
+        actual: T,
+    ) -> Self {
+        Self::IncompatibleTypes {
+            object: *object,
+            expected,
+            actual: actual.into(),
+        }
+    }
 }
 
 impl Serializable for View {
     fn serialize(&self) -> Result<Vec<u8>, crate::serde::SerializationError> {
-        let cache: ViewCache = self.into();
-        cache.serialize()
+        Ok(serialize_view(self, &self.encoded_objects))
     }
 }
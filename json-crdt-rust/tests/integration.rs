@@ -1,4 +1,15 @@
-use json_crdt_rust::{Doc, ObjRef, ReadableDoc, WritableDoc};
+use chrono::TimeZone;
+use std::{sync::Arc, time::Duration};
+
+use json_crdt_rust::{
+    AnchorBias, AutomergeError, AwarenessChange, ChangeKind, ChangeOrigin, ChangeSummary,
+    ConflictPolicy, Doc, DocChange, DocError, DocOptions, DocStatus, DocVersion, FileStorage,
+    LazyOptions, ManualClock, MemoryStorage, MergeOptions, MergeReport, NetworkConditions, ObjRef,
+    OperationAction, OperationId, Path, PathError, PersistentDoc, ReadableDoc, ScalarValue,
+    Selector, SerializationError, SharedDoc, Simulation, SimulationStats, Storage, StorageError,
+    TextFanout, TimestampPolicy, TimestampSource, TransactionError, UpgradeMode, Value, ValueKind,
+    ViewError, WritableDoc, FORMAT_VERSION,
+};
 
 #[test]
 fn create_document() {
@@ -541,3 +552,4987 @@ fn merge_map_concurrent_deletes_are_confirmed() {
     assert!(value1.is_none());
     assert!(value2.is_none());
 }
+
+#[test]
+fn merge_exposes_concurrent_writes_as_ordered_conflicts() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "base").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+    let base_id = doc2.conflicts(ObjRef::Root, "register").unwrap()[0]
+        .id
+        .clone();
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    let conflicts1 = doc1.conflicts(ObjRef::Root, "register").unwrap();
+    let conflicts2 = doc2.conflicts(ObjRef::Root, "register").unwrap();
+    assert_eq!(conflicts1, conflicts2);
+    assert_eq!(conflicts1.len(), 2);
+
+    // The superseded base value is not part of the conflicts
+    for conflict in &conflicts1 {
+        assert_ne!(conflict.id, base_id);
+        assert_eq!(conflict.supersedes, std::slice::from_ref(&base_id));
+    }
+
+    // The last conflict is the winning value
+    let winner = doc1.get(ObjRef::Root, "register").unwrap().unwrap();
+    assert_eq!(conflicts1.last().unwrap().value, winner);
+}
+
+#[test]
+fn resolving_a_conflict_supersedes_the_other_values() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    let text = txn1.create_text(ObjRef::Root, "object").unwrap();
+    txn1.insert_text(text, 0, "kept").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.set_scalar(ObjRef::Root, "object", "scalar").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // Pick the losing value of each key
+    let register_conflicts = doc1.conflicts(ObjRef::Root, "register").unwrap();
+    let register_loser = register_conflicts[0].id.clone();
+    let register_value = register_conflicts[0].value.clone();
+    let object_conflicts = doc1.conflicts(ObjRef::Root, "object").unwrap();
+    let object_loser = object_conflicts
+        .iter()
+        .find(|conflict| matches!(conflict.value, Value::Object(_)))
+        .unwrap()
+        .id
+        .clone();
+
+    let mut txn1 = doc1.transaction();
+    txn1.resolve_conflict(ObjRef::Root, "register", &register_loser)
+        .unwrap();
+    txn1.resolve_conflict(ObjRef::Root, "object", &object_loser)
+        .unwrap();
+    let error = txn1
+        .resolve_conflict(ObjRef::Root, "missing", &register_loser)
+        .unwrap_err();
+    assert!(matches!(error, TransactionError::ConflictNotFound { .. }));
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+    for doc in [&doc1, &doc2] {
+        assert_eq!(doc.conflict_count(ObjRef::Root, "register").unwrap(), 0);
+        assert_eq!(doc.conflict_count(ObjRef::Root, "object").unwrap(), 0);
+        assert_eq!(
+            doc.get(ObjRef::Root, "register").unwrap().unwrap(),
+            &register_value
+        );
+        let object = doc
+            .get(ObjRef::Root, "object")
+            .unwrap()
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(doc.get_text(object).unwrap().unwrap(), "kept");
+    }
+}
+
+#[test]
+fn conflict_policies_decide_which_values_are_read() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    txn1.set_scalar(ObjRef::Root, "picked", "one").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.set_scalar(ObjRef::Root, "picked", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    let one = Value::Scalar(ScalarValue::String("one".to_string()));
+    let two = Value::Scalar(ScalarValue::String("two".to_string()));
+
+    // The last write wins by default
+    assert_eq!(doc1.get(ObjRef::Root, "register").unwrap(), Some(&two));
+    assert_eq!(doc1.get_all(ObjRef::Root, "register").unwrap(), [&two]);
+
+    let mut options = DocOptions {
+        conflict_policy: ConflictPolicy::MultiValue,
+        ..Default::default()
+    };
+    options.key_conflict_policies.insert(
+        Selector::from("picked"),
+        ConflictPolicy::Custom(Arc::new(|_, conflicts| {
+            conflicts.iter().position(|conflict| {
+                conflict.value.as_scalar().unwrap().as_string().unwrap() == "one"
+            })
+        })),
+    );
+    doc1.set_options(options).unwrap();
+
+    assert_eq!(doc1.get(ObjRef::Root, "register").unwrap(), Some(&two));
+    assert_eq!(
+        doc1.get_all(ObjRef::Root, "register").unwrap(),
+        [&one, &two]
+    );
+    // Same values as the conflicts, in the same order
+    let conflicts = doc1.conflicts(ObjRef::Root, "register").unwrap();
+    let conflict_values: Vec<&Value> = conflicts.iter().map(|conflict| conflict.value).collect();
+    assert_eq!(
+        doc1.get_all(ObjRef::Root, "register").unwrap(),
+        conflict_values
+    );
+    assert_eq!(doc1.get(ObjRef::Root, "picked").unwrap(), Some(&one));
+    assert_eq!(doc1.get_all(ObjRef::Root, "picked").unwrap(), [&one]);
+    assert_eq!(
+        doc1.as_map().unwrap()[&Selector::from("picked")]
+            .as_string()
+            .unwrap(),
+        &"one"
+    );
+
+    // Policies only affect reads, so both replicas still converge
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(doc1.version().unwrap(), doc2.version().unwrap());
+    assert_eq!(doc2.get(ObjRef::Root, "picked").unwrap(), Some(&two));
+}
+
+#[test]
+fn logical_clocks_advance_past_merged_timestamps() {
+    let timestamp = |doc: &Doc, key: &str| doc.conflicts(ObjRef::Root, key).unwrap()[0].timestamp;
+
+    let mut doc1 = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            timestamp_source: TimestampSource::Lamport,
+            ..Default::default()
+        },
+    );
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "first", 1).unwrap();
+    txn1.set_scalar(ObjRef::Root, "second", 2).unwrap();
+    txn1.commit().unwrap();
+    assert_eq!(timestamp(&doc1, "first"), 1);
+    assert_eq!(timestamp(&doc1, "second"), 2);
+
+    let mut doc2 = Doc::new("2".to_string());
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "remote", 3).unwrap();
+    txn2.commit().unwrap();
+    let remote = timestamp(&doc2, "remote");
+
+    doc1.merge(&doc2).unwrap();
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "third", 4).unwrap();
+    txn1.commit().unwrap();
+    assert_eq!(timestamp(&doc1, "third"), remote + 1);
+
+    // Hybrid clocks follow the wall clock, but never go backwards
+    let mut doc3 = Doc::new_with_timestamp("3".to_string(), 1);
+    doc3.set_options(DocOptions {
+        timestamp_source: TimestampSource::HybridLogical,
+        ..Default::default()
+    })
+    .unwrap();
+    let mut txn3 = doc3.transaction();
+    txn3.set_scalar(ObjRef::Root, "now", 5).unwrap();
+    txn3.commit().unwrap();
+    assert!(timestamp(&doc3, "now") >= remote);
+
+    doc3.merge(&doc1).unwrap();
+    let mut txn3 = doc3.transaction();
+    txn3.set_scalar(ObjRef::Root, "after", 6).unwrap();
+    txn3.commit().unwrap();
+    assert!(timestamp(&doc3, "after") > remote);
+}
+
+#[test]
+fn injected_clocks_make_timestamps_deterministic() {
+    let clock = Arc::new(ManualClock::new(1000));
+    let mut doc1 = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        },
+    );
+    let mut doc2 = Doc::new_with_options(
+        "2".to_string(),
+        DocOptions {
+            clock: Arc::new(ManualClock::new(500)),
+            ..Default::default()
+        },
+    );
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    txn1.commit().unwrap();
+
+    // The second device is behind, so its concurrent write loses
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    let conflicts = doc1.conflicts(ObjRef::Root, "register").unwrap();
+    assert_eq!(
+        conflicts
+            .iter()
+            .map(|conflict| conflict.timestamp)
+            .collect::<Vec<_>>(),
+        [500, 1000]
+    );
+    assert_eq!(
+        doc1.get(ObjRef::Root, "register").unwrap(),
+        Some(&Value::Scalar(ScalarValue::String("one".to_string())))
+    );
+
+    clock.advance(10);
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "three").unwrap();
+    txn1.commit().unwrap();
+    assert_eq!(
+        doc1.conflicts(ObjRef::Root, "register").unwrap()[0].timestamp,
+        1010
+    );
+}
+
+#[test]
+fn lazy_docs_report_conflicts_from_the_cache() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    txn1.set_scalar(ObjRef::Root, "stable", "value").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    assert_eq!(doc1.conflict_count(ObjRef::Root, "register").unwrap(), 2);
+    assert_eq!(doc1.conflict_count(ObjRef::Root, "stable").unwrap(), 0);
+    assert_eq!(doc1.conflict_count(ObjRef::Root, "missing").unwrap(), 0);
+
+    let lazy_doc = Doc::lazy("3".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    assert_eq!(
+        lazy_doc.conflict_count(ObjRef::Root, "register").unwrap(),
+        2
+    );
+    assert_eq!(lazy_doc.conflict_count(ObjRef::Root, "stable").unwrap(), 0);
+    assert_eq!(lazy_doc.conflict_count(ObjRef::Root, "missing").unwrap(), 0);
+
+    // A later write resolves the conflict
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "resolved")
+        .unwrap();
+    txn1.commit().unwrap();
+
+    let lazy_doc = Doc::lazy("3".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    assert_eq!(
+        lazy_doc.conflict_count(ObjRef::Root, "register").unwrap(),
+        0
+    );
+}
+
+#[test]
+fn lazy_docs_stream_texts_in_the_same_chunks() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+    doc2.merge(&doc1).unwrap();
+    let mut txn = doc2.transaction();
+    txn.insert_text(&text, 5, ",").unwrap();
+    txn.delete_text(&text, 7, 1).unwrap();
+    txn.commit().unwrap();
+
+    let full_chunks: Vec<String> = doc2
+        .text(&text)
+        .unwrap()
+        .unwrap()
+        .chunks()
+        .map(|chunk| chunk.to_string())
+        .collect();
+    assert_eq!(full_chunks, vec!["Hello", ",", " ", "orld"]);
+
+    let lazy_doc = Doc::lazy("3".to_string(), doc2.serialize().unwrap().into()).unwrap();
+    let lazy_text = lazy_doc.text(&text).unwrap().unwrap();
+    assert_eq!(lazy_text.chunks().collect::<Vec<_>>(), full_chunks);
+    assert_eq!(lazy_text.to_string(), "Hello, orld");
+}
+
+#[test]
+fn text_chunks_can_be_read_from_the_end() {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "log").unwrap();
+    txn.append_text(&text, "first\nsecond\n").unwrap();
+    txn.insert_text(&text, 6, "middle\n").unwrap();
+    txn.delete_text(&text, 0, 2).unwrap();
+    txn.append_text(&text, "last\n").unwrap();
+    txn.commit().unwrap();
+
+    let full_text = doc.text(&text).unwrap().unwrap();
+    let mut reversed: Vec<&str> = full_text.chunks_rev().collect();
+    reversed.reverse();
+    assert_eq!(reversed, full_text.chunks().collect::<Vec<_>>());
+    assert_eq!(full_text.chunks_rev().next(), Some("last\n"));
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let lazy_text = lazy_doc.text(&text).unwrap().unwrap();
+    assert_eq!(
+        lazy_text.chunks_rev().collect::<Vec<_>>(),
+        full_text.chunks_rev().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn lazy_docs_read_text_ranges() {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Grüße, world").unwrap();
+    txn.insert_text(&text, 7, "dear ").unwrap();
+    txn.delete_text(&text, 0, 1).unwrap();
+    txn.commit().unwrap();
+    let expected = doc.get_text(&text).unwrap().unwrap();
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let lazy_text = lazy_doc.text(&text).unwrap().unwrap();
+    assert_eq!(lazy_text.to_string(), expected);
+    assert_eq!(lazy_text.len(), expected.len());
+    assert!(lazy_text.chunks().count() > 1);
+
+    for start in 0..=expected.len() {
+        assert_eq!(
+            lazy_text.is_char_boundary(start),
+            expected.is_char_boundary(start)
+        );
+        for end in start..=expected.len() {
+            if expected.is_char_boundary(start) && expected.is_char_boundary(end) {
+                assert_eq!(
+                    lazy_doc.get_text_range(&text, start, end - start).unwrap(),
+                    Some(expected[start..end].to_string())
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn lazy_docs_keep_the_objects_read_one_by_one_within_the_budget() {
+    let mut doc = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            clock: Arc::new(ManualClock::new(1000)),
+            ..Default::default()
+        },
+    );
+    let mut txn = doc.transaction();
+    let texts: Vec<ObjRef> = (0..20)
+        .map(|index| {
+            let text = txn
+                .create_text(ObjRef::Root, format!("text{}", index))
+                .unwrap();
+            txn.append_text(&text, "x".repeat(100)).unwrap();
+            text
+        })
+        .collect();
+    let session = txn.create_map(ObjRef::Root, "session").unwrap();
+    txn.set_scalar(&session, "user", "alice").unwrap();
+    txn.set_scalar_with_ttl(&session, "token", "secret", Duration::from_millis(100))
+        .unwrap();
+    txn.commit().unwrap();
+
+    let options = LazyOptions {
+        memory_budget: 1000,
+    };
+    let buffer = doc.serialize().unwrap().into();
+    let lazy_doc = Doc::lazy_with_options("2".to_string(), 1, buffer, options).unwrap();
+    let first = lazy_doc.get_object(&texts[0]).unwrap().unwrap();
+    let again = lazy_doc.get_object(&texts[0]).unwrap().unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+
+    // Reading the other texts drops the first one from the cache, but the handle keeps it
+    for text in &texts[1..] {
+        let object = lazy_doc.get_object(text).unwrap().unwrap();
+        assert_eq!(object.as_text().unwrap().as_str(), "x".repeat(100));
+    }
+    let reloaded = lazy_doc.get_object(&texts[0]).unwrap().unwrap();
+    assert!(!Arc::ptr_eq(&first, &reloaded));
+    assert_eq!(first, reloaded);
+    assert!(matches!(lazy_doc.status(), DocStatus::Cached));
+
+    // Lazy documents read the values with a TTL against the system clock
+    let session = lazy_doc.get_object(&session).unwrap().unwrap();
+    let keys: Vec<_> = session.as_map().unwrap().keys().collect();
+    assert_eq!(keys, vec![&Selector::from("user")]);
+
+    assert_eq!(doc.get_object(&texts[0]).unwrap().unwrap(), first);
+    assert_eq!(
+        lazy_doc
+            .get_object(ObjRef::Root)
+            .unwrap()
+            .unwrap()
+            .as_map()
+            .unwrap()
+            .len(),
+        21
+    );
+}
+
+#[test]
+fn ids_are_formatted_with_global_clients() {
+    let mut doc1 = Doc::new_with_timestamp("bob".to_string(), 2);
+    let mut doc2 = Doc::new_with_timestamp("alice".to_string(), 1);
+
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // The same object has different local ids on the two replicas
+    let text2 = root_object(&doc2, "text");
+    assert_ne!(text, text2);
+    assert_eq!(doc1.format_id(&text).to_string(), "bob@1");
+    assert_eq!(doc2.format_id(&text2).to_string(), "bob@1");
+    assert_eq!(doc2.format_id(&ObjRef::Root).to_string(), "root");
+    assert_eq!(doc2.format_id(&OperationId::new(7, 3)).to_string(), "#7@3");
+
+    let lazy_doc = Doc::lazy("carol".to_string(), doc2.serialize().unwrap().into()).unwrap();
+    let lazy_text = root_object(&lazy_doc, "text");
+    assert_eq!(lazy_doc.format_id(&lazy_text).to_string(), "bob@1");
+}
+
+#[test]
+fn set_and_get_timestamp() {
+    let mut doc = Doc::new("1".to_string());
+    let date = chrono::Utc
+        .with_ymd_and_hms(2023, 10, 1, 12, 30, 0)
+        .unwrap();
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "date", date).unwrap();
+    txn.commit().unwrap();
+
+    let value = doc
+        .get(ObjRef::Root, "date")
+        .unwrap()
+        .unwrap()
+        .as_scalar()
+        .unwrap();
+    assert_eq!(value.as_datetime().unwrap(), date);
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let map = lazy_doc.as_map().unwrap();
+    let cached = map.get(&Selector::from("date")).unwrap();
+    assert_eq!(**cached.as_timestamp().unwrap(), date.timestamp_millis());
+}
+
+#[test]
+fn null_values_are_distinct_from_deleted_keys() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "nullable", ScalarValue::Null)
+        .unwrap();
+    txn.set_scalar(ObjRef::Root, "deleted", "value").unwrap();
+    txn.delete(ObjRef::Root, "deleted").unwrap();
+    txn.commit().unwrap();
+
+    // A null value is still present, while a deleted key is absent
+    let value = doc.get(ObjRef::Root, "nullable").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::Null));
+    assert!(doc.get(ObjRef::Root, "deleted").unwrap().is_none());
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let value = lazy_doc.get(ObjRef::Root, "nullable").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::Null));
+
+    let map = lazy_doc.as_map().unwrap();
+    assert!(map.get(&Selector::from("nullable")).unwrap().is_null());
+    assert!(!map.contains_key(&Selector::from("deleted")));
+}
+
+#[test]
+fn keys_and_lengths_of_nested_objects() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "dark").unwrap();
+    txn.set_scalar(&settings, "font", "mono").unwrap();
+    txn.set_scalar(&settings, "removed", true).unwrap();
+    txn.delete(&settings, "removed").unwrap();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "héllo").unwrap();
+    txn.commit().unwrap();
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for doc in [&doc, &lazy_doc] {
+        let mut keys: Vec<&str> = doc
+            .keys(&settings)
+            .unwrap()
+            .unwrap()
+            .map(|selector| selector.as_key().unwrap().as_str())
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["font", "theme"]);
+        assert_eq!(doc.len(&settings).unwrap(), Some(2));
+        assert_eq!(doc.len(ObjRef::Root).unwrap(), Some(2));
+        assert_eq!(doc.text_len(&text).unwrap(), Some(6));
+
+        let missing = ObjRef::Object(OperationId::new(0, 1000));
+        assert!(doc.keys(&missing).unwrap().is_none());
+        assert_eq!(doc.len(&missing).unwrap(), None);
+        assert_eq!(doc.text_len(&missing).unwrap(), None);
+
+        assert!(doc.keys(&text).is_err());
+        assert!(doc.text_len(&settings).is_err());
+    }
+}
+
+#[test]
+fn text_ranges_are_read_without_the_whole_text() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.commit().unwrap();
+
+    // Split the text in many blocks, including deleted ones
+    for index in 0..20 {
+        let mut txn = doc.transaction();
+        txn.insert_text(&text, ((index * 7) % (index + 1)) * 3, "é-")
+            .unwrap();
+        txn.commit().unwrap();
+    }
+    let mut txn = doc.transaction();
+    txn.delete_text(&text, 5, 10).unwrap();
+    txn.commit().unwrap();
+
+    let expected = doc.get_text(&text).unwrap().unwrap();
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for doc in [&doc, &lazy_doc] {
+        let chunks: String = doc.text_chunks(&text).unwrap().unwrap().collect();
+        assert_eq!(chunks, expected);
+
+        for start in 0..=expected.len() {
+            for len in 0..=expected.len() - start {
+                let end = start + len;
+                let range = doc.get_text_range(&text, start, len);
+                if expected.is_char_boundary(start) && expected.is_char_boundary(end) {
+                    assert_eq!(range.unwrap().unwrap(), expected[start..end]);
+                } else {
+                    assert!(matches!(
+                        range,
+                        Err(DocError::InvalidRange { range, .. }) if range == (start..end)
+                    ));
+                }
+            }
+        }
+
+        // Ranges past the end are clamped
+        let tail = doc.get_text_range(&text, expected.len() - 3, 100).unwrap();
+        assert_eq!(tail.unwrap(), expected[expected.len() - 3..]);
+        assert_eq!(doc.get_text_range(&text, 1000, 10).unwrap().unwrap(), "");
+        assert!(doc
+            .get_text_range(ObjRef::Object(OperationId::new(0, 1000)), 0, 1)
+            .unwrap()
+            .is_none());
+    }
+}
+
+#[test]
+fn paths_reach_nested_values() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let path: Path = "settings.editor[2].theme".parse().unwrap();
+    txn.set_path(&path, "dark").unwrap();
+    txn.set_path(&Path::parse("settings.version").unwrap(), 3)
+        .unwrap();
+    // Existing maps are reused
+    txn.set_path(&Path::parse("settings.editor[2].font").unwrap(), "mono")
+        .unwrap();
+    assert!(matches!(
+        txn.set_path(&Path::parse("settings.version.major").unwrap(), 1),
+        Err(TransactionError::IncompatibleValue { .. })
+    ));
+    txn.commit().unwrap();
+
+    let settings = root_object(&doc, "settings");
+    assert_eq!(doc.len(&settings).unwrap(), Some(2));
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for doc in [&doc, &lazy_doc] {
+        let theme = doc.get_path(&path).unwrap().unwrap();
+        assert_eq!(theme.as_scalar().unwrap().as_string().unwrap(), "dark");
+        let font = doc
+            .get_path(&Path::from_iter([
+                Selector::from("settings"),
+                Selector::from("editor"),
+                Selector::from(2),
+                Selector::from("font"),
+            ]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(font.as_scalar().unwrap().as_string().unwrap(), "mono");
+
+        assert!(doc
+            .get_path(&Path::parse("settings.missing.theme").unwrap())
+            .unwrap()
+            .is_none());
+        assert!(doc
+            .get_path(&Path::parse("settings.version.major").unwrap())
+            .is_err());
+        assert!(matches!(
+            doc.get_path(&Path::new()),
+            Err(DocError::PathError(PathError::Empty))
+        ));
+    }
+}
+
+#[test]
+fn rename_moves_value_to_new_key() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "old", "value").unwrap();
+    txn.rename(ObjRef::Root, "old", "new").unwrap();
+    txn.commit().unwrap();
+
+    assert!(doc.get(ObjRef::Root, "old").unwrap().is_none());
+    let value = doc.get(ObjRef::Root, "new").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::from("value")));
+
+    // Writing the old key again creates a new value, unrelated to the moved one
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "old", "other").unwrap();
+    txn.commit().unwrap();
+
+    let value = doc.get(ObjRef::Root, "old").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::from("other")));
+    let value = doc.get(ObjRef::Root, "new").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::from("value")));
+
+    let mut txn = doc.transaction();
+    let result = txn.rename(ObjRef::Root, "missing", "new");
+    assert!(result.unwrap_err().to_string().contains("key not found"));
+}
+
+#[test]
+fn merge_concurrent_update_follows_renamed_key() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "old", "one").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn1 = doc1.transaction();
+    txn1.rename(ObjRef::Root, "old", "new").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "old", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    for doc in [&doc1, &doc2] {
+        assert!(doc.get(ObjRef::Root, "old").unwrap().is_none());
+        let value = doc.get(ObjRef::Root, "new").unwrap().unwrap();
+        assert_eq!(value, &Value::Scalar(ScalarValue::from("two")));
+    }
+
+    // Later updates to the new key converge as well
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "new", "three").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+
+    let value = doc1.get(ObjRef::Root, "new").unwrap().unwrap();
+    assert_eq!(value, &Value::Scalar(ScalarValue::from("three")));
+}
+
+#[test]
+fn merge_concurrent_delete_follows_renamed_key() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut doc2 = Doc::new("2".to_string());
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "old", "value").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn1 = doc1.transaction();
+    txn1.rename(ObjRef::Root, "old", "new").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.delete(ObjRef::Root, "old").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    for doc in [&doc1, &doc2] {
+        assert!(doc.get(ObjRef::Root, "old").unwrap().is_none());
+        assert!(doc.get(ObjRef::Root, "new").unwrap().is_none());
+    }
+}
+
+#[test]
+fn renames_converge_when_writes_follow_them_late() {
+    let clock = Arc::new(ManualClock::new(1000));
+    let new_doc = |client: &str| {
+        Doc::new_with_options(
+            client.to_string(),
+            DocOptions {
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        )
+    };
+    let set = |doc: &mut Doc, key: &str, value: i32| {
+        clock.advance(1);
+        let mut txn = doc.transaction();
+        txn.set_scalar(ObjRef::Root, key, value).unwrap();
+        txn.commit().unwrap();
+    };
+    let rename = |doc: &mut Doc, from: &str, to: &str| {
+        clock.advance(1);
+        let mut txn = doc.transaction();
+        txn.rename(ObjRef::Root, from, to).unwrap();
+        txn.commit().unwrap();
+    };
+
+    let mut a = new_doc("a");
+    let mut b = new_doc("b");
+    let mut c = new_doc("c");
+    set(&mut b, "k3", 25);
+    set(&mut a, "k1", 2);
+    c.merge(&b).unwrap();
+    rename(&mut c, "k3", "k0");
+    set(&mut a, "k0", 18);
+    rename(&mut c, "k0", "k3");
+    // Supersedes the value that c moved back and forth, so it follows all the moves
+    set(&mut b, "k3", 29);
+    rename(&mut c, "k3", "k0");
+
+    for _ in 0..2 {
+        a.merge(&b).unwrap();
+        a.merge(&c).unwrap();
+        b.merge(&a).unwrap();
+        b.merge(&c).unwrap();
+        c.merge(&a).unwrap();
+        c.merge(&b).unwrap();
+    }
+
+    for doc in [&a, &b, &c] {
+        let reloaded = Doc::load("d".to_string(), doc.serialize().unwrap().into()).unwrap();
+        for doc in [doc, &reloaded] {
+            assert_eq!(
+                doc.get(ObjRef::Root, "k0").unwrap(),
+                Some(&Value::Scalar(ScalarValue::Int(29)))
+            );
+            assert_eq!(doc.get(ObjRef::Root, "k3").unwrap(), None);
+        }
+    }
+}
+
+#[test]
+fn random_renames_converge_in_any_merge_order() {
+    let keys = ["k0", "k1", "k2", "k3"];
+    for seed in 1..=20 {
+        let mut fuzzer = Fuzzer(seed * 0x9e37_79b9);
+        let mut docs: Vec<Doc> = (0..3)
+            .map(|client| Doc::new_with_timestamp(client.to_string(), 1))
+            .collect();
+        let merge = |docs: &mut [Doc], target: usize, source: usize| {
+            if target == source {
+                return;
+            }
+            let (low, high) = docs.split_at_mut(target.max(source));
+            let (target, source) = if target < source {
+                (&mut low[target], &high[0])
+            } else {
+                (&mut high[0], &low[source])
+            };
+            target.merge(source).unwrap();
+        };
+
+        for step in 0..40 {
+            let index = fuzzer.below(docs.len());
+            let source = fuzzer.below(docs.len());
+            let key = keys[fuzzer.below(keys.len())];
+            let other = keys[fuzzer.below(keys.len())];
+            match fuzzer.below(4) {
+                0 => merge(&mut docs, index, source),
+                action => {
+                    let doc = &mut docs[index];
+                    let exists = doc.get(ObjRef::Root, key).unwrap().is_some();
+                    let mut txn = doc.transaction();
+                    match action {
+                        1 if exists && fuzzer.below(4) == 0 => txn.delete(ObjRef::Root, key),
+                        1 if exists && key != other => txn.rename(ObjRef::Root, key, other),
+                        _ => txn.set_scalar(ObjRef::Root, key, step),
+                    }
+                    .unwrap();
+                    txn.commit().unwrap();
+                }
+            }
+        }
+
+        let report = Doc::verify_convergence(&docs).unwrap();
+        assert!(report.is_converged(), "seed {}: {:?}", seed, report);
+
+        for _ in 0..2 {
+            for target in 0..docs.len() {
+                for source in 0..docs.len() {
+                    merge(&mut docs, target, source);
+                }
+            }
+        }
+        let reloaded = Doc::load("d".to_string(), docs[0].serialize().unwrap().into()).unwrap();
+        for doc in docs.iter().chain([&reloaded]) {
+            for key in keys {
+                assert_eq!(
+                    doc.get(ObjRef::Root, key).unwrap(),
+                    docs[0].get(ObjRef::Root, key).unwrap(),
+                    "seed {}, key {}",
+                    seed,
+                    key
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn renamed_object_survives_serialization() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "draft").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.rename(ObjRef::Root, "draft", "final").unwrap();
+    txn.commit().unwrap();
+
+    let loaded = Doc::load("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    assert!(loaded.get(ObjRef::Root, "draft").unwrap().is_none());
+    let text = loaded
+        .get(ObjRef::Root, "final")
+        .unwrap()
+        .unwrap()
+        .as_object()
+        .unwrap();
+    assert_eq!(loaded.get_text(text).unwrap().unwrap(), "hello");
+}
+
+#[test]
+fn loading_rebuilds_every_object_in_causal_order() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let nested = txn.create_map(ObjRef::Root, "nested").unwrap();
+    let mut texts = Vec::new();
+    for i in 0..50 {
+        let parent = if i % 2 == 0 { &ObjRef::Root } else { &nested };
+        texts.push(
+            txn.create_text(parent.clone(), format!("text_{}", i))
+                .unwrap(),
+        );
+    }
+    txn.commit().unwrap();
+
+    // Edits of different objects are interleaved, and each one depends on the previous ones
+    for round in 0..10 {
+        let mut txn = doc.transaction();
+        for (i, text) in texts.iter().enumerate() {
+            txn.insert_text(text.clone(), 0, format!("{}", (round + i) % 10))
+                .unwrap();
+            if round % 3 == 2 {
+                txn.delete_text(text.clone(), 1, 1).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    let loaded = Doc::load("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for text in &texts {
+        assert_eq!(
+            loaded.get_text(text.clone()).unwrap(),
+            doc.get_text(text.clone()).unwrap()
+        );
+    }
+}
+
+#[test]
+fn insert_text_past_end_is_rejected() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+
+    let result = txn.insert_text(&text, 6, "!");
+    assert!(matches!(
+        result,
+        Err(TransactionError::InvalidIndex {
+            index: 6,
+            len: 5,
+            ..
+        })
+    ));
+
+    // Inserting exactly at the end is still allowed
+    txn.insert_text(&text, 5, "!").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello!");
+}
+
+#[test]
+fn insert_text_past_end_of_empty_text_is_rejected() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    assert!(txn.insert_text(&text, 1, "a").is_err());
+    txn.insert_text(&text, 0, "a").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "a");
+}
+
+#[test]
+fn delete_text_past_end_is_rejected() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.delete_text(&text, 1, 2).unwrap();
+
+    assert!(txn.delete_text(&text, 2, 2).is_err());
+    assert!(txn.delete_text(&text, u32::MAX, 2).is_err());
+    txn.commit().unwrap();
+
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hlo");
+}
+
+#[test]
+fn empty_text_operations_are_rejected() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.commit().unwrap();
+    let operations = Doc::validate_buffer(doc.serialize().unwrap().into())
+        .unwrap()
+        .operations;
+
+    let mut txn = doc.transaction();
+    assert!(matches!(
+        txn.append_text(&text, ""),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    assert!(matches!(
+        txn.insert_text(&text, 2, ""),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    assert!(matches!(
+        txn.insert_text_chars(&text, 2, ""),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    assert!(matches!(
+        txn.delete_text(&text, 2, 0),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    assert!(matches!(
+        txn.delete_text_chars(&text, 2, 0),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    txn.commit().unwrap();
+
+    // No operation was recorded
+    let info = Doc::validate_buffer(doc.serialize().unwrap().into()).unwrap();
+    assert_eq!(info.operations, operations);
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello");
+}
+
+#[test]
+fn move_object_between_maps() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let first = txn.create_map(ObjRef::Root, "first").unwrap();
+    let second = txn.create_map(ObjRef::Root, "second").unwrap();
+    let text = txn.create_text(&first, "section").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.move_object(&first, "section", &second, "moved")
+        .unwrap();
+    txn.commit().unwrap();
+
+    assert!(doc.get(&first, "section").unwrap().is_none());
+    let moved = doc.get(&second, "moved").unwrap().unwrap();
+    assert_eq!(moved, &Value::Object(text.clone()));
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello");
+
+    // Moving an object inside itself is not allowed
+    let mut txn = doc.transaction();
+    let result = txn.move_object(ObjRef::Root, "second", &second, "nested");
+    assert!(matches!(
+        result,
+        Err(TransactionError::InvalidMove { moved, destination })
+            if moved == second && destination == second
+    ));
+    txn.commit().unwrap();
+
+    let loaded = Doc::load("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let map = loaded.as_map().unwrap();
+    let first = map.get(&Selector::from("first")).unwrap().as_map().unwrap();
+    assert!(first.is_empty());
+    let second = map
+        .get(&Selector::from("second"))
+        .unwrap()
+        .as_map()
+        .unwrap();
+    let moved = second.get(&Selector::from("moved")).unwrap();
+    assert_eq!(moved.as_text().unwrap(), "hello");
+}
+
+#[test]
+fn merge_keeps_concurrent_edits_inside_moved_object() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut doc2 = Doc::new("2".to_string());
+
+    let mut txn1 = doc1.transaction();
+    let first = txn1.create_map(ObjRef::Root, "first").unwrap();
+    let second = txn1.create_map(ObjRef::Root, "second").unwrap();
+    let text = txn1.create_text(&first, "section").unwrap();
+    txn1.append_text(&text, "hello").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn1 = doc1.transaction();
+    txn1.move_object(&first, "section", &second, "section")
+        .unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.append_text(&text, " world").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    for doc in [&doc1, &doc2] {
+        assert!(doc.get(&first, "section").unwrap().is_none());
+        let moved = doc.get(&second, "section").unwrap().unwrap();
+        assert_eq!(moved, &Value::Object(text.clone()));
+        assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello world");
+    }
+}
+
+#[test]
+fn merge_concurrent_moves_of_the_same_object_converge() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut doc2 = Doc::new("2".to_string());
+
+    let mut txn1 = doc1.transaction();
+    let first = txn1.create_map(ObjRef::Root, "first").unwrap();
+    let second = txn1.create_map(ObjRef::Root, "second").unwrap();
+    let moved = txn1.create_map(ObjRef::Root, "moved").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn1 = doc1.transaction();
+    txn1.move_object(ObjRef::Root, "moved", &first, "moved")
+        .unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.move_object(ObjRef::Root, "moved", &second, "moved")
+        .unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // The object ends up in exactly one location, the same on both replicas
+    for doc in [&doc1, &doc2] {
+        assert!(doc.get(ObjRef::Root, "moved").unwrap().is_none());
+        let in_first = doc.get(&first, "moved").unwrap().is_some();
+        let in_second = doc.get(&second, "moved").unwrap().is_some();
+        assert!(in_first != in_second);
+    }
+    assert_eq!(
+        doc1.get(&first, "moved").unwrap(),
+        doc2.get(&first, "moved").unwrap()
+    );
+    assert_eq!(
+        doc1.get(&second, "moved").unwrap(),
+        doc2.get(&second, "moved").unwrap()
+    );
+
+    let location = doc1
+        .get(&first, "moved")
+        .unwrap()
+        .or(doc1.get(&second, "moved").unwrap());
+    assert_eq!(location, Some(&Value::Object(moved)));
+}
+
+#[test]
+fn concurrent_moves_at_the_same_time_are_ordered_by_global_client() {
+    // "b" is created first, so it gets the lower local ID
+    let clock = Arc::new(ManualClock::new(500));
+    let options = || DocOptions {
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let mut doc_b = Doc::new_with_options("b".to_string(), options());
+    clock.set(1000);
+    let mut doc_a = Doc::new_with_options("a".to_string(), options());
+
+    let mut txn = doc_b.transaction();
+    let first = txn.create_map(ObjRef::Root, "first").unwrap();
+    let second = txn.create_map(ObjRef::Root, "second").unwrap();
+    txn.create_map(ObjRef::Root, "moved").unwrap();
+    txn.commit().unwrap();
+    doc_a.merge(&doc_b).unwrap();
+
+    let mut txn = doc_a.transaction();
+    txn.move_object(ObjRef::Root, "moved", first, "moved")
+        .unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc_b.transaction();
+    txn.move_object(ObjRef::Root, "moved", second, "moved")
+        .unwrap();
+    txn.commit().unwrap();
+
+    doc_a.merge(&doc_b).unwrap();
+    doc_b.merge(&doc_a).unwrap();
+    let reloaded = Doc::load("c".to_string(), doc_a.serialize().unwrap().into()).unwrap();
+
+    // The move of "b" is applied last and wins on every replica
+    for doc in [&doc_a, &doc_b, &reloaded] {
+        assert!(doc.get(first, "moved").unwrap().is_none());
+        assert!(doc.get(second, "moved").unwrap().is_some());
+    }
+}
+
+#[test]
+fn random_moves_converge_in_any_merge_order() {
+    let keys = ["m0", "m1", "m2", "m3"];
+    for seed in 1..=20 {
+        let mut fuzzer = Fuzzer(seed * 0x9e37_79b9);
+        let mut docs: Vec<Doc> = (0..3)
+            .map(|client| Doc::new_with_timestamp(client.to_string(), 1))
+            .collect();
+        let mut txn = docs[0].transaction();
+        let maps: Vec<ObjRef> = keys
+            .iter()
+            .map(|key| txn.create_map(ObjRef::Root, *key).unwrap())
+            .collect();
+        txn.commit().unwrap();
+
+        let merge = |docs: &mut [Doc], target: usize, source: usize| {
+            if target == source {
+                return;
+            }
+            let (low, high) = docs.split_at_mut(target.max(source));
+            let (target, source) = if target < source {
+                (&mut low[target], &high[0])
+            } else {
+                (&mut high[0], &low[source])
+            };
+            target.merge(source).unwrap();
+        };
+        merge(&mut docs, 1, 0);
+        merge(&mut docs, 2, 0);
+
+        // Each map is always placed under its own key, in the root or in another map
+        let parents: Vec<ObjRef> = std::iter::once(ObjRef::Root).chain(maps).collect();
+        let parent_of = |doc: &Doc, key: &str| -> Option<ObjRef> {
+            parents
+                .iter()
+                .copied()
+                .find(|parent| doc.get(*parent, key).unwrap().is_some())
+        };
+
+        for _ in 0..40 {
+            let index = fuzzer.below(docs.len());
+            let source = fuzzer.below(docs.len());
+            let key = keys[fuzzer.below(keys.len())];
+            let destination = parents[fuzzer.below(parents.len())];
+            if fuzzer.below(3) == 0 {
+                merge(&mut docs, index, source);
+                continue;
+            }
+
+            let doc = &mut docs[index];
+            let parent = parent_of(doc, key).unwrap();
+            let mut txn = doc.transaction();
+            // Moves inside the map itself are rejected
+            if txn.move_object(parent, key, destination, key).is_ok() {
+                txn.commit().unwrap();
+            }
+        }
+
+        let report = Doc::verify_convergence(&docs).unwrap();
+        assert!(report.is_converged(), "seed {}: {:?}", seed, report);
+
+        for _ in 0..2 {
+            for target in 0..docs.len() {
+                for source in 0..docs.len() {
+                    merge(&mut docs, target, source);
+                }
+            }
+        }
+        let reloaded = Doc::load("d".to_string(), docs[0].serialize().unwrap().into()).unwrap();
+        for doc in docs.iter().chain([&reloaded]) {
+            for key in keys {
+                assert_eq!(
+                    parent_of(doc, key),
+                    parent_of(&docs[0], key),
+                    "seed {}, key {}",
+                    seed,
+                    key
+                );
+            }
+            doc.as_map().unwrap();
+        }
+    }
+}
+
+#[test]
+fn merge_concurrent_moves_do_not_introduce_cycles() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut doc2 = Doc::new("2".to_string());
+
+    let mut txn1 = doc1.transaction();
+    let first = txn1.create_map(ObjRef::Root, "first").unwrap();
+    let second = txn1.create_map(ObjRef::Root, "second").unwrap();
+    txn1.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn1 = doc1.transaction();
+    txn1.move_object(ObjRef::Root, "first", &second, "child")
+        .unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.move_object(ObjRef::Root, "second", &first, "child")
+        .unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // Only one of the moves can be applied, the other would create a cycle
+    for doc in [&doc1, &doc2] {
+        let first_at_root = doc.get(ObjRef::Root, "first").unwrap().is_some();
+        let second_at_root = doc.get(ObjRef::Root, "second").unwrap().is_some();
+        assert!(first_at_root != second_at_root);
+
+        let nested = if first_at_root {
+            doc.get(&first, "child").unwrap()
+        } else {
+            doc.get(&second, "child").unwrap()
+        };
+        assert!(nested.is_some());
+
+        // Walks the whole document, which would not terminate with a cycle
+        doc.as_map().unwrap();
+    }
+    assert_eq!(
+        doc1.get(ObjRef::Root, "first").unwrap(),
+        doc2.get(ObjRef::Root, "first").unwrap()
+    );
+}
+
+#[test]
+fn heads_of_linear_history() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    assert!(doc.heads().unwrap().is_empty());
+
+    let mut txn = doc.transaction();
+    txn.create_map(ObjRef::Root, "first").unwrap();
+    txn.create_map(ObjRef::Root, "second").unwrap();
+    txn.commit().unwrap();
+
+    let heads = doc.heads().unwrap();
+    assert_eq!(
+        heads,
+        [OperationId {
+            client_id: 0,
+            sequence: 2
+        }]
+    );
+    assert!(doc.has_operation(&heads[0]).unwrap());
+    assert!(!doc
+        .has_operation(&OperationId {
+            client_id: 0,
+            sequence: 3
+        })
+        .unwrap());
+
+    let operation = doc.get_operation(&heads[0]).unwrap().unwrap();
+    assert_eq!(
+        operation.parent,
+        Some(OperationId {
+            client_id: 0,
+            sequence: 1
+        })
+    );
+    assert!(matches!(operation.action, OperationAction::CreateMap(_)));
+}
+
+#[test]
+fn heads_of_concurrent_history() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.create_map(ObjRef::Root, "first").unwrap();
+    txn1.commit().unwrap();
+
+    let mut txn2 = doc2.transaction();
+    txn2.create_map(ObjRef::Root, "second").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+
+    assert_eq!(
+        doc1.heads().unwrap(),
+        [
+            OperationId {
+                client_id: 0,
+                sequence: 1
+            },
+            OperationId {
+                client_id: 1,
+                sequence: 1
+            }
+        ]
+    );
+
+    let mut txn1 = doc1.transaction();
+    txn1.create_map(ObjRef::Root, "third").unwrap();
+    txn1.commit().unwrap();
+
+    assert_eq!(doc1.heads().unwrap().len(), 2);
+}
+
+#[test]
+fn heads_are_listed_in_the_same_order_by_every_peer() {
+    // "b" is created first, so it gets the lower local ID
+    let mut doc_a = Doc::new_with_timestamp("a".to_string(), 2);
+    let mut doc_b = Doc::new_with_timestamp("b".to_string(), 1);
+
+    let mut txn = doc_a.transaction();
+    txn.set_scalar(ObjRef::Root, "a", 1).unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc_b.transaction();
+    txn.set_scalar(ObjRef::Root, "b", 2).unwrap();
+    let result = txn.commit().unwrap();
+    assert_eq!(result.heads, result.operations);
+
+    doc_a.merge(&doc_b).unwrap();
+    doc_b.merge(&doc_a).unwrap();
+
+    let global_heads = |doc: &Doc| -> Vec<(String, u32)> {
+        doc.heads()
+            .unwrap()
+            .iter()
+            .map(|id| {
+                (
+                    doc.global_client_of(id).unwrap().unwrap().clone(),
+                    id.sequence,
+                )
+            })
+            .collect()
+    };
+    let expected = vec![("a".to_string(), 1), ("b".to_string(), 1)];
+    assert_eq!(global_heads(&doc_a), expected);
+    assert_eq!(global_heads(&doc_b), expected);
+
+    let mut txn = doc_b.transaction();
+    txn.set_scalar(ObjRef::Root, "c", 3).unwrap();
+    let result = txn.commit().unwrap();
+    assert_eq!(result.heads, doc_b.heads().unwrap());
+}
+
+#[test]
+fn heads_are_not_available_on_lazy_docs() {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    assert!(lazy_doc.heads().is_err());
+}
+
+#[test]
+fn read_values_by_reference() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "name", "value").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 3).unwrap();
+    txn.set_scalar(ObjRef::Root, "nullable", ScalarValue::Null)
+        .unwrap();
+    let map = txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.set_scalar(&map, "enabled", true).unwrap();
+    txn.commit().unwrap();
+
+    let name = doc.get_ref(ObjRef::Root, "name").unwrap().unwrap();
+    assert_eq!(name.as_str(), Some("value"));
+    assert_eq!(name.as_int(), None);
+    assert_eq!(name.to_value(), Value::Scalar("value".into()));
+
+    let count = doc.get_ref(ObjRef::Root, "count").unwrap().unwrap();
+    assert_eq!(count.as_int(), Some(3));
+    assert!(doc
+        .get_ref(ObjRef::Root, "nullable")
+        .unwrap()
+        .unwrap()
+        .is_null());
+
+    let map_ref = doc.get_ref(ObjRef::Root, "map").unwrap().unwrap();
+    let map_obj = map_ref.as_object().unwrap();
+    let enabled = doc.get_ref(map_obj, "enabled").unwrap().unwrap();
+    assert_eq!(enabled.as_bool(), Some(true));
+    assert_eq!(map_obj, map);
+
+    assert!(doc.get_ref(ObjRef::Root, "missing").unwrap().is_none());
+}
+
+#[test]
+fn dirty_state_tracks_transactions() {
+    let mut doc = Doc::new("1".to_string());
+    assert!(!doc.is_dirty());
+
+    let mut txn = doc.transaction();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+    assert!(doc.is_dirty());
+
+    doc.clear_dirty();
+    assert!(!doc.is_dirty());
+
+    let loaded = Doc::load("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    assert!(!loaded.is_dirty());
+}
+
+#[test]
+fn dirty_state_tracks_merges() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc2.transaction();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    assert!(doc1.is_dirty());
+
+    // Merging again doesn't bring anything new
+    doc1.clear_dirty();
+    doc1.merge(&doc2).unwrap();
+    assert!(!doc1.is_dirty());
+
+    // New clients are persisted too, even without operations
+    let doc3 = Doc::new_with_timestamp("3".to_string(), 3);
+    doc1.merge(&doc3).unwrap();
+    assert!(doc1.is_dirty());
+}
+
+#[test]
+fn set_text_loads_content_as_a_single_block() {
+    let content = "line\n".repeat(10_000);
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.set_text(ObjRef::Root, "text", &content).unwrap();
+    let empty = txn.set_text(ObjRef::Root, "empty", "").unwrap();
+    txn.insert_text(text.clone(), 0, "first ").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(
+        doc.get_text(&text).unwrap().unwrap(),
+        format!("first {}", content)
+    );
+    assert_eq!(doc.get_text(&empty).unwrap().unwrap(), "");
+    assert_eq!(doc.text_history(&text).unwrap().len(), 2);
+}
+
+#[test]
+fn text_ranges_are_deleted_with_a_single_operation() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn
+        .set_text(ObjRef::Root, "text", "foo bar foo baz foo")
+        .unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+    doc2.merge(&doc1).unwrap();
+    let version = doc1.version().unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.delete_text_ranges(&text, [16..19, 0..4, 8..12])
+        .unwrap();
+    for (ranges, error) in [
+        (vec![], "empty"),
+        (vec![0..2, 1..3], "overlap"),
+        (std::iter::once(2..2).collect(), "empty"),
+        (std::iter::once(0..100).collect(), "range"),
+    ] {
+        assert!(
+            txn.delete_text_ranges(&text, ranges).is_err(),
+            "expected {} error",
+            error
+        );
+    }
+    txn.commit().unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "bar baz ");
+
+    let ops = doc1.encode_new_operations_since(&version).unwrap();
+    let report = doc2.apply_encoded_operations(ops.into()).unwrap();
+    assert_eq!(report.applied_operations, 1);
+    assert_eq!(doc2.get_text(&text).unwrap().unwrap(), "bar baz ");
+
+    let loaded = Doc::load("3".to_string(), doc1.save().unwrap().into()).unwrap();
+    assert_eq!(loaded.get_text(&text).unwrap().unwrap(), "bar baz ");
+}
+
+#[test]
+fn operations_received_before_their_object_wait_for_it() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 2);
+    let mut txn = doc1.transaction();
+    txn.set_text(ObjRef::Root, "notes", "hello").unwrap();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "light").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc3 = Doc::new_with_timestamp("3".to_string(), 1);
+    let mut txn = doc3.transaction();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    txn.commit().unwrap();
+
+    // The operations of doc2 only depend on the last one in its log, which is the one of doc3
+    // as it's the oldest
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 3);
+    doc2.merge(&doc1).unwrap();
+    doc2.merge(&doc3).unwrap();
+    let mut txn = doc2.transaction();
+    let notes = txn.get_text(ObjRef::Root, "notes").unwrap().unwrap();
+    let settings = txn.get_map(ObjRef::Root, "settings").unwrap().unwrap();
+    txn.append_text(&notes, " world").unwrap();
+    txn.set_scalar(&settings, "theme", "dark").unwrap();
+    txn.move_object(ObjRef::Root, "settings", ObjRef::Root, "config")
+        .unwrap();
+    txn.commit().unwrap();
+
+    let mut known = doc1.version().unwrap();
+    known.merge(&doc3.version().unwrap());
+    let updates = [
+        doc1.encode_new_operations_since(&DocVersion::new())
+            .unwrap(),
+        doc3.encode_new_operations_since(&DocVersion::new())
+            .unwrap(),
+        doc2.encode_new_operations_since(&known).unwrap(),
+    ];
+
+    for order in [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ] {
+        let mut doc = Doc::new("4".to_string());
+        for index in order {
+            doc.apply_encoded_operations(updates[index].clone().into())
+                .unwrap();
+        }
+
+        let loaded = Doc::load("5".to_string(), doc.save().unwrap().into()).unwrap();
+        for doc in [&doc, &loaded] {
+            let notes = root_object(doc, "notes");
+            assert_eq!(doc.get_text(&notes).unwrap().unwrap(), "hello world");
+            assert!(doc.get(ObjRef::Root, "settings").unwrap().is_none());
+            let config = root_object(doc, "config");
+            assert_eq!(
+                doc.get(&config, "theme").unwrap(),
+                Some(&Value::Scalar(ScalarValue::from("dark")))
+            );
+        }
+    }
+}
+
+#[test]
+fn operations_waiting_for_missing_parents_are_reported() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut first = DocVersion::new();
+    for (index, key) in ["a", "b", "c"].into_iter().enumerate() {
+        let mut txn = doc1.transaction();
+        txn.set_scalar(ObjRef::Root, key, index as i32).unwrap();
+        txn.commit().unwrap();
+        if index == 0 {
+            first = doc1.version().unwrap();
+        }
+    }
+    let missing_first = doc1.encode_new_operations_since(&first).unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    let report = doc2
+        .apply_encoded_operations(missing_first.clone().into())
+        .unwrap();
+    assert_eq!(report.applied_operations, 0);
+    assert!(doc2.get(ObjRef::Root, "b").unwrap().is_none());
+
+    let missing = doc2.missing_dependencies().unwrap();
+    assert_eq!(missing.len(), 1);
+    assert_eq!(doc2.global_client_of(&missing[0]).unwrap().unwrap(), "1");
+    assert_eq!(missing[0].sequence, first.get(&"1".to_string()));
+
+    let everything = doc1
+        .encode_new_operations_since(&DocVersion::new())
+        .unwrap();
+    let report = doc2.apply_encoded_operations(everything.into()).unwrap();
+    assert_eq!(report.applied_operations, 3);
+    assert!(doc2.missing_dependencies().unwrap().is_empty());
+    assert_eq!(
+        doc2.get(ObjRef::Root, "c").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(2)))
+    );
+
+    // With a limit, orphans past it are rejected instead of being kept indefinitely
+    let mut doc3 = Doc::new_with_options(
+        "3".to_string(),
+        DocOptions {
+            max_orphans: Some(1),
+            ..Default::default()
+        },
+    );
+    let error = doc3
+        .apply_encoded_operations(missing_first.into())
+        .unwrap_err();
+    assert!(matches!(error, DocError::OperationLogError(_)));
+    assert_eq!(doc3.missing_dependencies().unwrap().len(), 1);
+}
+
+#[test]
+fn stats_describe_the_document_and_its_history() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let nested = txn.create_map(ObjRef::Root, "nested").unwrap();
+    txn.set_scalar(nested.clone(), "key", 1).unwrap();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello world").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(nested.clone(), "key", 2).unwrap();
+    txn.delete(nested, "key").unwrap();
+    txn.delete_text(&text, 0, 6).unwrap();
+    txn.commit().unwrap();
+
+    let stats = doc.stats().unwrap();
+    assert_eq!(stats.operations, 6);
+    assert_eq!(stats.operations_by_action["SetMapValue"], 1);
+    assert_eq!(stats.operations_by_action["DeleteMapValue"], 1);
+    assert_eq!(stats.operations_by_action["DeleteTextItems"], 1);
+    assert_eq!(stats.orphans, 0);
+    assert_eq!((stats.maps, stats.texts), (2, 1));
+    assert_eq!(stats.map_values, 4);
+    assert_eq!(stats.deleted_map_values, 1);
+    assert_eq!(stats.text_blocks, 2);
+    assert_eq!((stats.text_tombstones, stats.tombstone_len), (1, 6));
+    assert_eq!(stats.tree_nodes, 1);
+    assert!(stats.serialized_size() <= doc.serialize().unwrap().len());
+    assert!(stats.operation_log_size > 0);
+}
+
+#[test]
+fn text_history_attributes_ranges_to_authors() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+    let mut txn = doc2.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+
+    // Splitting a range by deleting a character doesn't change its author
+    let mut txn = doc1.transaction();
+    txn.delete_text(&text, 2, 1).unwrap();
+    txn.commit().unwrap();
+
+    let history = doc1.text_history(&text).unwrap();
+    assert_eq!(history.len(), 2);
+
+    assert_eq!(history[0].range, 0..4);
+    assert_eq!(history[0].value, "Helo");
+    assert_eq!(history[0].author, "1");
+
+    assert_eq!(history[1].range, 4..10);
+    assert_eq!(history[1].value, " world");
+    assert_eq!(history[1].author, "2");
+    assert_eq!(
+        doc1.get_operation(&history[1].operation)
+            .unwrap()
+            .unwrap()
+            .timestamp,
+        history[1].timestamp
+    );
+}
+
+#[test]
+fn text_history_requires_a_text() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let map = txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    assert!(doc.text_history(&map).is_err());
+    assert!(doc
+        .text_history(ObjRef::Object(OperationId {
+            client_id: 0,
+            sequence: 100
+        }))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn map_position_follows_concurrent_edits() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.commit().unwrap();
+    let before = doc1.version().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+    let mut txn = doc1.transaction();
+    txn.insert_text(&text, 0, "Oh, ").unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc2.transaction();
+    txn.delete_text(&text, 0, 6).unwrap();
+    txn.commit().unwrap();
+
+    // "Hello world" became "Oh, world"
+    doc1.merge(&doc2).unwrap();
+    let after = doc1.version().unwrap();
+
+    assert_eq!(doc1.map_position(&text, 6, &before, &after).unwrap(), 4);
+    assert_eq!(doc1.map_position(&text, 4, &after, &before).unwrap(), 6);
+    // Deleted characters map to where they used to be
+    assert_eq!(doc1.map_position(&text, 2, &before, &after).unwrap(), 4);
+    assert_eq!(doc1.map_position(&text, 11, &before, &after).unwrap(), 9);
+    assert_eq!(doc1.map_position(&text, 0, &after, &before).unwrap(), 0);
+    assert!(matches!(
+        doc1.map_position(&text, 12, &before, &after),
+        Err(DocError::InvalidIndex {
+            index: 12,
+            len: 11,
+            ..
+        })
+    ));
+
+    let mut unknown = after.clone();
+    unknown.set("3".to_string(), 1);
+    assert!(matches!(
+        doc1.map_position(&text, 0, &before, &unknown),
+        Err(DocError::UnknownVersion)
+    ));
+}
+
+#[test]
+fn annotations_follow_concurrent_edits() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello brave world").unwrap();
+    let comment = txn
+        .add_annotation(&text, 6, 11, [("comment", "too bold?")])
+        .unwrap();
+    let highlight = txn
+        .add_annotation_with_bias(&text, 12, 17, AnchorBias::Expand, [("color", "yellow")])
+        .unwrap();
+    txn.commit().unwrap();
+
+    doc2.merge(&doc1).unwrap();
+    let mut txn = doc2.transaction();
+    txn.insert_text(&text, 9, "-").unwrap();
+    txn.insert_text(&text, 12, "!!").unwrap();
+    txn.append_text(&text, "X").unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc1.transaction();
+    txn.delete_text(&text, 0, 6).unwrap();
+    txn.commit().unwrap();
+
+    // Only the expanding annotation grows at its edges
+    doc1.merge(&doc2).unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "bra-ve!! worldX");
+    let annotations = doc1.annotations(&text).unwrap();
+    assert_eq!(annotations.len(), 2);
+    assert_eq!(annotations[0].payload, comment);
+    assert_eq!(annotations[0].range, 0..6);
+    assert_eq!(annotations[1].payload, highlight);
+    assert_eq!(annotations[1].range, 9..15);
+    assert_eq!(annotations[1].bias, AnchorBias::Expand);
+
+    let value = doc1
+        .get(&comment, "comment")
+        .unwrap()
+        .unwrap()
+        .as_scalar()
+        .unwrap();
+    assert_eq!(value.as_string().unwrap(), "too bold?");
+
+    // Annotations are encoded along with the other operations
+    let mut doc3 = Doc::new("3".to_string());
+    doc3.apply_encoded_operations(
+        doc1.encode_new_operations_since(&DocVersion::new())
+            .unwrap()
+            .into(),
+    )
+    .unwrap();
+    assert_eq!(doc3.annotations(&text).unwrap(), annotations);
+
+    // Deleting the annotated text leaves an empty annotation
+    let mut txn = doc1.transaction();
+    txn.delete_text(&text, 0, 8).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc1.annotations(&text).unwrap()[0].range, 0..0);
+}
+
+#[test]
+fn add_annotation_checks_the_range() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    let no_payload: [(&str, &str); 0] = [];
+    assert!(matches!(
+        txn.add_annotation(&text, 2, 2, no_payload),
+        Err(TransactionError::EmptyOperation { .. })
+    ));
+    assert!(matches!(
+        txn.add_annotation(&text, 3, 9, no_payload),
+        Err(TransactionError::InvalidIndex {
+            index: 9,
+            len: 5,
+            ..
+        })
+    ));
+    assert!(matches!(
+        txn.add_annotation(&text, 3, 1, no_payload),
+        Err(TransactionError::InvalidRange { range, .. }) if range.start == 3 && range.end == 1
+    ));
+    let map = txn.create_map(ObjRef::Root, "map").unwrap();
+    assert!(matches!(
+        txn.add_annotation(&map, 0, 1, no_payload),
+        Err(TransactionError::IncompatibleTypes { .. })
+    ));
+    // A position, which follows the text typed at it
+    txn.add_annotation_with_bias(&text, 5, 5, AnchorBias::Expand, no_payload)
+        .unwrap();
+    txn.append_text(&text, " world").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(doc.annotations(&text).unwrap()[0].range, 5..11);
+}
+
+#[test]
+fn table_rows_merge_concurrent_edits() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc1.transaction();
+    let tasks = txn.create_table(ObjRef::Root, "tasks").unwrap();
+    let first = txn
+        .insert_row(
+            &tasks,
+            [
+                ("title", ScalarValue::from("Write docs")),
+                ("done", ScalarValue::from(false)),
+            ],
+        )
+        .unwrap();
+    let second = txn.insert_row(&tasks, [("title", "Review")]).unwrap();
+    txn.commit().unwrap();
+    assert!(first.starts_with("1@"));
+    assert_ne!(first, second);
+
+    doc2.merge(&doc1).unwrap();
+    let mut txn = doc1.transaction();
+    txn.update_row(&tasks, &first, [("title", "Write the docs")])
+        .unwrap();
+    txn.delete_row(&tasks, &second).unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc2.transaction();
+    txn.update_row(&tasks, &first, [("done", true)]).unwrap();
+    txn.update_row(&tasks, &second, [("done", true)]).unwrap();
+    let third = txn.insert_row(&tasks, [("title", "Ship")]).unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // Columns of a row are merged, while deleting a row wins over updating it
+    for doc in [&doc1, &doc2] {
+        let rows = doc.rows(&tasks).unwrap();
+        let ids: Vec<&str> = rows.iter().map(|row| row.id.as_str()).collect();
+        assert_eq!(ids, [first.as_str(), third.as_str()]);
+        assert_eq!(rows[0].get_str("title"), Some("Write the docs"));
+        assert_eq!(rows[0].get_bool("done"), Some(true));
+        assert_eq!(rows[0].get_int("done"), None);
+        assert_eq!(rows[1].get_str("title"), Some("Ship"));
+        assert!(rows[1].get("done").is_none());
+    }
+
+    let mut txn = doc1.transaction();
+    assert!(matches!(
+        txn.update_row(&tasks, &second, [("done", false)]),
+        Err(TransactionError::KeyNotFound { .. })
+    ));
+    assert!(matches!(
+        txn.delete_row(&tasks, "missing"),
+        Err(TransactionError::KeyNotFound { .. })
+    ));
+}
+
+#[test]
+fn serialize_only_encodes_changed_objects() {
+    // Both documents go through the same edits, but only the first one is serialized in
+    // between, so its following serializations reuse the objects that didn't change
+    let clocks = [
+        Arc::new(ManualClock::new(1000)),
+        Arc::new(ManualClock::new(1000)),
+    ];
+    let mut docs = clocks.clone().map(|clock| {
+        Doc::new_with_options(
+            "1".to_string(),
+            DocOptions {
+                clock,
+                ..Default::default()
+            },
+        )
+    });
+
+    let mut buffers = Vec::new();
+    for (doc, clock) in docs.iter_mut().zip(clocks) {
+        let mut txn = doc.transaction();
+        let title = txn.create_text(ObjRef::Root, "title").unwrap();
+        txn.append_text(&title, "Draft").unwrap();
+        let body = txn.create_text(ObjRef::Root, "body").unwrap();
+        txn.append_text(&body, "Hello").unwrap();
+        let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+        txn.set_scalar(&settings, "theme", "dark").unwrap();
+        txn.create_map(ObjRef::Root, "archive").unwrap();
+        let session = txn.create_map(ObjRef::Root, "session").unwrap();
+        txn.set_scalar_with_ttl(&session, "token", "secret", Duration::from_millis(100))
+            .unwrap();
+        txn.commit().unwrap();
+        if buffers.is_empty() {
+            doc.serialize().unwrap();
+        }
+
+        let mut txn = doc.transaction();
+        txn.append_text(&body, " world").unwrap();
+        txn.commit().unwrap();
+        if buffers.is_empty() {
+            doc.serialize().unwrap();
+        }
+
+        let mut txn = doc.transaction();
+        txn.delete_text(&title, 0, 2).unwrap();
+        txn.move_object(ObjRef::Root, "settings", ObjRef::Root, "archive")
+            .unwrap();
+        txn.commit().unwrap();
+        // Maps with a TTL are encoded again, even when they didn't change
+        clock.advance(200);
+        buffers.push(doc.serialize().unwrap());
+    }
+    assert_eq!(buffers[0], buffers[1]);
+
+    let loaded = Doc::load("2".to_string(), buffers[0].clone().into()).unwrap();
+    let map = loaded.as_map().unwrap();
+    assert_eq!(
+        map.get(&Selector::from("title"))
+            .unwrap()
+            .as_text()
+            .unwrap(),
+        "aft"
+    );
+    assert_eq!(
+        map.get(&Selector::from("body")).unwrap().as_text().unwrap(),
+        "Hello world"
+    );
+    assert!(!map.contains_key(&Selector::from("settings")));
+    assert_eq!(
+        map.get(&Selector::from("archive"))
+            .unwrap()
+            .as_map()
+            .unwrap()
+            .get(&Selector::from("theme"))
+            .unwrap()
+            .as_string()
+            .unwrap(),
+        &"dark"
+    );
+    assert!(map
+        .get(&Selector::from("session"))
+        .unwrap()
+        .as_map()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn serialize_snapshot_while_editing() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.commit().unwrap();
+
+    let snapshot = doc.snapshot();
+    let serialization = std::thread::spawn(move || snapshot.serialize().unwrap());
+
+    let mut txn = doc.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    let buffer = serialization.join().unwrap();
+    let loaded = Doc::load("2".to_string(), buffer.clone().into()).unwrap();
+    let map = loaded.as_map().unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(
+        map.get(&Selector::from("text")).unwrap().as_text().unwrap(),
+        "Hello"
+    );
+
+    // The cached view is part of the snapshot as well
+    let lazy = Doc::lazy("3".to_string(), buffer.into()).unwrap();
+    assert_eq!(lazy.as_map().unwrap().len(), 1);
+
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "Hello world");
+}
+
+#[cfg(feature = "threads")]
+#[test]
+fn serialize_async_encodes_the_document_at_call_time() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.commit().unwrap();
+    let expected = doc.serialize().unwrap();
+
+    let handle = doc.serialize_async();
+    let mut txn = doc.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.commit().unwrap();
+
+    let buffer = handle.join().unwrap();
+    assert_eq!(buffer, expected);
+    let loaded = Doc::load("2".to_string(), buffer.into()).unwrap();
+    assert_eq!(loaded.get_text(&text).unwrap().unwrap(), "Hello");
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "Hello world");
+}
+
+#[test]
+fn snapshots_are_readable_while_editing() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    txn.commit().unwrap();
+
+    let snapshot = doc.snapshot();
+    let reader = snapshot.clone();
+    let text_ref = text.clone();
+    let render = std::thread::spawn(move || {
+        (
+            reader.get_text(&text_ref).unwrap().unwrap(),
+            reader.len(ObjRef::Root).unwrap(),
+        )
+    });
+
+    let mut txn = doc.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 2).unwrap();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(render.join().unwrap(), ("Hello".to_string(), Some(2)));
+    assert_eq!(snapshot.get_text(&text).unwrap().unwrap(), "Hello");
+    assert_eq!(
+        snapshot.get(ObjRef::Root, "count").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(1)))
+    );
+    assert!(snapshot.get(ObjRef::Root, "map").unwrap().is_none());
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "Hello world");
+
+    // Snapshots of lazy documents read the cached view
+    let lazy = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let snapshot = lazy.snapshot();
+    assert_eq!(snapshot.get_text(&text).unwrap().unwrap(), "Hello world");
+    assert_eq!(snapshot.len(ObjRef::Root).unwrap(), Some(3));
+}
+
+#[test]
+fn global_clients_are_stable_across_merges() {
+    let doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn = doc2.transaction();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    let before_merge = doc2.heads().unwrap()[0];
+    assert_eq!(before_merge.client_id, 0);
+    assert_eq!(doc2.global_client_of(&before_merge).unwrap().unwrap(), "2");
+
+    // The older client takes the first local ID, so the operations of doc2 are remapped
+    doc2.merge(&doc1).unwrap();
+
+    let clients = doc2.clients().unwrap();
+    assert_eq!(clients.len(), 2);
+    assert_eq!(clients[0].global_id, "1");
+    assert_eq!(clients[0].created_at, 1);
+    assert_eq!(clients[1].global_id, "2");
+
+    let after_merge = doc2.heads().unwrap()[0];
+    assert_eq!(after_merge.client_id, 1);
+    assert_eq!(doc2.global_client_of(&after_merge).unwrap().unwrap(), "2");
+
+    assert!(doc2
+        .global_client_of(&OperationId {
+            client_id: 5,
+            sequence: 1
+        })
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[cfg(any(debug_assertions, feature = "validation"))]
+fn merge_rejects_different_operations_with_the_same_id() {
+    // Two documents sharing the same global client, which is not supported
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("1".to_string(), 1);
+
+    let mut txn = doc1.transaction();
+    txn.create_map(ObjRef::Root, "first").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc2.transaction();
+    txn.create_map(ObjRef::Root, "second").unwrap();
+    txn.commit().unwrap();
+
+    let error = doc1.merge(&doc2).unwrap_err();
+    assert!(error.to_string().contains("duplicate operation"));
+}
+
+#[test]
+fn insert_and_delete_text_by_char_index() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "caffè").unwrap();
+    txn.insert_text_chars(&text, 5, " è buono").unwrap();
+    txn.insert_text_chars(&text, 0, "☕ ").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "☕ caffè è buono");
+
+    let mut txn = doc.transaction();
+    txn.delete_text_chars(&text, 6, 3).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "☕ caff buono");
+
+    let mut txn = doc.transaction();
+    let error = txn.insert_text_chars(&text, 13, "!").unwrap_err();
+    assert!(error.to_string().contains("out of range"));
+}
+
+#[test]
+fn byte_indices_inside_chars_are_rejected() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "è").unwrap();
+
+    let error = txn.insert_text(&text, 1, "a").unwrap_err();
+    assert!(matches!(
+        error,
+        TransactionError::NotCharBoundary { index: 1, .. }
+    ));
+
+    let error = txn.delete_text(&text, 0, 1).unwrap_err();
+    assert!(matches!(
+        error,
+        TransactionError::NotCharBoundary { index: 1, .. }
+    ));
+
+    txn.commit().unwrap();
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "è");
+}
+
+#[test]
+fn merge_with_report_counts_operations_by_key() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let notes = txn.create_text(ObjRef::Root, "notes").unwrap();
+    let tasks = txn.create_map(ObjRef::Root, "tasks").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.append_text(&notes, "a").unwrap();
+    txn.insert_text(&notes, 0, "b").unwrap();
+    let subtasks = txn.create_map(&tasks, "subtasks").unwrap();
+    txn.set_scalar(&subtasks, "first", "done").unwrap();
+    txn.set_scalar(ObjRef::Root, "title", "Todo").unwrap();
+    txn.commit().unwrap();
+
+    let report = doc2.merge_with_report(&doc1).unwrap();
+    assert_eq!(report.applied_operations, 5);
+    assert_eq!(report.operations_for("notes"), 2);
+    assert_eq!(report.operations_for("tasks"), 2);
+    assert_eq!(report.operations_for("title"), 1);
+    assert_eq!(report.by_key.len(), 3);
+
+    // Nothing new to apply
+    let report = doc2.merge_with_report(&doc1).unwrap();
+    assert_eq!(report.applied_operations, 0);
+    assert!(report.by_key.is_empty());
+}
+
+#[test]
+fn dropped_tombstones_preserve_convergence() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello brave new world").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+    doc2.set_drop_tombstones(true).unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.delete_text(&text, 8, 8).unwrap();
+    txn.append_text(&text, "!").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc2.transaction();
+    txn.delete_text(&text, 6, 6).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc2.get_text(&text).unwrap().unwrap(), "Hello new world");
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Hello world!");
+    assert_eq!(doc1.get_text(&text).unwrap(), doc2.get_text(&text).unwrap());
+}
+
+#[test]
+fn splice_text_replaces_ranges() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.splice_text(&text, 6, 5, "there").unwrap();
+    txn.splice_text(&text, 0, 0, "Oh, ").unwrap();
+    txn.splice_text(&text, 2, 1, "").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Oh Hello there");
+
+    let mut txn = doc1.transaction();
+    let error = txn.splice_text(&text, 9, 10, "world").unwrap_err();
+    assert!(error.to_string().contains("out of range"));
+    txn.commit().unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Oh Hello there");
+
+    let mut txn = doc2.transaction();
+    txn.append_text(&text, "!").unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Oh Hello there!");
+    assert_eq!(doc1.get_text(&text).unwrap(), doc2.get_text(&text).unwrap());
+}
+
+#[test]
+fn splice_text_edits_the_text_of_another_client() {
+    // Insertions at the start used to be placed after the blocks of clients that sort first
+    for (author, editor) in [("a", "b"), ("b", "a")] {
+        let mut doc1 = Doc::new(author.to_string());
+        let mut txn = doc1.transaction();
+        let text = txn.create_text(ObjRef::Root, "text").unwrap();
+        txn.append_text(&text, "world").unwrap();
+        txn.commit().unwrap();
+
+        let mut doc2 = Doc::new(editor.to_string());
+        doc2.merge(&doc1).unwrap();
+        let text = root_object(&doc2, "text");
+        let mut txn = doc2.transaction();
+        txn.splice_text(&text, 0, 0, "hello ").unwrap();
+        txn.splice_text(&text, 0, 1, "H").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(root_text(&doc2, "text"), "Hello world");
+
+        doc1.merge(&doc2).unwrap();
+        let text = root_object(&doc1, "text");
+        let mut txn = doc1.transaction();
+        txn.splice_text(&text, 6, 1, "W").unwrap();
+        txn.splice_text(&text, 0, 0, "¡").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(root_text(&doc1, "text"), "¡Hello World");
+
+        doc2.merge(&doc1).unwrap();
+        assert_eq!(root_text(&doc2, "text"), "¡Hello World");
+    }
+}
+
+#[test]
+fn update_text_edits_the_text_of_another_client() {
+    let steps = ["hello world", "Q日Q!", "日Q!Q!", "¿日Q!Q!", "x¿日Q!"];
+    for (author, editor) in [("a", "b"), ("b", "a")] {
+        let mut doc1 = Doc::new(author.to_string());
+        let mut txn = doc1.transaction();
+        let text = txn.create_text(ObjRef::Root, "text").unwrap();
+        txn.append_text(&text, "world").unwrap();
+        txn.commit().unwrap();
+
+        let mut doc2 = Doc::new(editor.to_string());
+        doc2.merge(&doc1).unwrap();
+
+        // The clients take turns, each one editing the text written by the other
+        for (step, value) in steps.into_iter().enumerate() {
+            let (current, other) = if step % 2 == 0 {
+                (&mut doc2, &mut doc1)
+            } else {
+                (&mut doc1, &mut doc2)
+            };
+            let text = root_object(current, "text");
+            let mut txn = current.transaction();
+            txn.update_text(&text, value).unwrap();
+            txn.commit().unwrap();
+            assert_eq!(root_text(current, "text"), value);
+
+            other.merge(current).unwrap();
+            assert_eq!(root_text(other, "text"), value);
+        }
+    }
+}
+
+#[test]
+fn update_text_applies_a_minimal_diff() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "The quick brown fox jumps").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.update_text(&text, "The quick red fox jümps").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(
+        doc1.get_text(&text).unwrap().unwrap(),
+        "The quick red fox jümps"
+    );
+
+    // A single deletion for both removed ranges, and only the new chars are inserted
+    let stats = doc1.stats().unwrap();
+    assert_eq!(stats.operations_by_action["DeleteTextItems"], 1);
+    assert_eq!(stats.operations_by_action["InsertText"], 3);
+
+    let mut txn = doc1.transaction();
+    txn.update_text(&text, "The quick red fox jümps").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc1.stats().unwrap().operations, stats.operations);
+
+    let mut txn = doc2.transaction();
+    txn.append_text(&text, " over the dog").unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(
+        doc1.get_text(&text).unwrap().unwrap(),
+        "The quick red fox jümps over the dog"
+    );
+    assert_eq!(doc1.get_text(&text).unwrap(), doc2.get_text(&text).unwrap());
+
+    let mut txn = doc1.transaction();
+    txn.update_text(&text, "").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "");
+}
+
+#[test]
+fn update_map_from_json_writes_only_the_changes() {
+    let mut doc = Doc::new("1".to_string());
+    let state = serde_json::json!({
+        "title": "draft",
+        "count": 1,
+        "tags": ["x", "y"],
+        "nested": {"done": true, "owner": null},
+    });
+
+    let mut txn = doc.transaction();
+    txn.update_map_from_json(ObjRef::Root, &state).unwrap();
+    txn.set_text(ObjRef::Root, "body", "hello world").unwrap();
+    txn.commit().unwrap();
+    let operations = doc.stats().unwrap().operations;
+
+    let state = serde_json::json!({
+        "title": "draft",
+        "count": 2,
+        "tags": ["x"],
+        "nested": {"done": true},
+        "body": "hello, world!",
+    });
+    let mut txn = doc.transaction();
+    txn.update_map_from_json(ObjRef::Root, &state).unwrap();
+    txn.commit().unwrap();
+
+    // Count, the removed tag and key, and the two insertions in the text
+    assert_eq!(doc.stats().unwrap().operations, operations + 5);
+    assert_eq!(
+        doc.get(ObjRef::Root, "count").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(2)))
+    );
+    let Some(Value::Object(tags)) = doc.get(ObjRef::Root, "tags").unwrap() else {
+        panic!("expected a map");
+    };
+    assert!(doc.get(tags, 0).unwrap().is_some());
+    assert!(doc.get(tags, 1).unwrap().is_none());
+    let Some(Value::Object(body)) = doc.get(ObjRef::Root, "body").unwrap() else {
+        panic!("expected a text");
+    };
+    assert_eq!(doc.get_text(body).unwrap().unwrap(), "hello, world!");
+
+    let mut txn = doc.transaction();
+    txn.update_map_from_json(ObjRef::Root, &state).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc.stats().unwrap().operations, operations + 5);
+
+    let mut txn = doc.transaction();
+    txn.update_map_from_json(ObjRef::Root, &serde_json::json!({"count": {"value": 2}}))
+        .unwrap();
+    let error = txn
+        .update_map_from_json(ObjRef::Root, &serde_json::json!("text"))
+        .unwrap_err();
+    assert!(error.to_string().contains("expected a JSON object"));
+    txn.commit().unwrap();
+    assert!(doc.get(ObjRef::Root, "title").unwrap().is_none());
+    let Some(Value::Object(count)) = doc.get(ObjRef::Root, "count").unwrap() else {
+        panic!("expected a map");
+    };
+    assert_eq!(
+        doc.get(count, "value").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(2)))
+    );
+}
+
+#[test]
+fn compact_merges_typed_text() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    for (index, char) in "Hello".char_indices() {
+        let mut txn = doc1.transaction();
+        txn.insert_text(&text, index as u32, char.to_string())
+            .unwrap();
+        txn.commit().unwrap();
+    }
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+
+    let size_before = doc1.serialize().unwrap().len();
+    doc1.clear_dirty();
+    assert_eq!(doc1.compact().unwrap(), 4);
+    assert!(doc1.is_dirty());
+    assert!(doc1.serialize().unwrap().len() < size_before);
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Hello");
+
+    let loaded = Doc::load("1".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    assert_eq!(loaded.get_text(&text).unwrap().unwrap(), "Hello");
+
+    // Peers that still have the original operations converge
+    let mut txn = doc2.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(doc1.get_text(&text).unwrap().unwrap(), "Hello world");
+    assert_eq!(doc2.get_text(&text).unwrap().unwrap(), "Hello world");
+}
+
+#[test]
+fn operations_received_out_of_order_after_compaction_are_not_dropped() {
+    let mut a = Doc::new_with_timestamp("a".to_string(), 1);
+    let mut txn = a.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+    let mut peer = Doc::new("peer".to_string());
+    peer.merge(&a).unwrap();
+
+    let mut keystrokes = Vec::new();
+    for char in ["a", "b", "c"] {
+        let version = a.version().unwrap();
+        let mut txn = a.transaction();
+        txn.append_text(&text, char).unwrap();
+        txn.commit().unwrap();
+        keystrokes.push(a.encode_new_operations_since(&version).unwrap());
+    }
+    assert_eq!(a.compact().unwrap(), 2);
+
+    // The keystrokes merged by the compaction are recognized in any order
+    let mut b = Doc::new("b".to_string());
+    b.merge(&a).unwrap();
+    for keystroke in keystrokes.iter().rev() {
+        b.apply_encoded_operations(keystroke.clone().into())
+            .unwrap();
+    }
+    assert_eq!(b.get_text(&text).unwrap().unwrap(), "abc");
+
+    peer.apply_encoded_operations(keystrokes[2].clone().into())
+        .unwrap();
+    peer.merge(&a).unwrap();
+    peer.compact().unwrap();
+    peer.apply_encoded_operations(keystrokes[1].clone().into())
+        .unwrap();
+    assert_eq!(peer.get_text(&text).unwrap().unwrap(), "abc");
+
+    // An operation received after a later one of the same client is rejected, not dropped
+    let mut c = Doc::new_with_timestamp("c".to_string(), 2);
+    let mut txn = c.transaction();
+    txn.set_scalar(ObjRef::Root, "first", 1).unwrap();
+    txn.commit().unwrap();
+    c.merge(&a).unwrap();
+    let version = c.version().unwrap();
+    let mut txn = c.transaction();
+    txn.set_scalar(ObjRef::Root, "second", 2).unwrap();
+    txn.commit().unwrap();
+    let second = c.encode_new_operations_since(&version).unwrap();
+
+    b.apply_encoded_operations(second.into()).unwrap();
+    assert!(matches!(b.merge(&c), Err(DocError::OperationLogError(_))));
+}
+
+#[test]
+fn compaction_keeps_the_clock_and_the_timestamp_source() {
+    let type_and_compact = |doc: &mut Doc| {
+        let mut txn = doc.transaction();
+        let text = txn.create_text(ObjRef::Root, "text").unwrap();
+        txn.commit().unwrap();
+        for char in ["a", "b", "c"] {
+            let mut txn = doc.transaction();
+            txn.append_text(&text, char).unwrap();
+            txn.commit().unwrap();
+        }
+        assert_eq!(doc.compact().unwrap(), 2);
+    };
+    let write = |doc: &mut Doc| {
+        let mut txn = doc.transaction();
+        txn.set_scalar(ObjRef::Root, "after", 1).unwrap();
+        txn.commit().unwrap();
+        doc.conflicts(ObjRef::Root, "after").unwrap()[0].timestamp
+    };
+
+    let clock = Arc::new(ManualClock::new(1000));
+    let mut doc = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        },
+    );
+    type_and_compact(&mut doc);
+    clock.set(2000);
+    assert_eq!(write(&mut doc), 2000);
+
+    // The merged operation keeps the timestamp of the first keystroke, but the hybrid
+    // clock still advances past the last one
+    let clock = Arc::new(ManualClock::new(1000));
+    let mut doc = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            clock: clock.clone(),
+            timestamp_source: TimestampSource::HybridLogical,
+            ..Default::default()
+        },
+    );
+    type_and_compact(&mut doc);
+    clock.set(500);
+    assert_eq!(write(&mut doc), 1004);
+}
+
+#[test]
+fn compacted_text_merges_into_peers_with_part_of_it() {
+    let mut b = Doc::new("b".to_string());
+    let mut c = Doc::new("c".to_string());
+    let mut txn = b.transaction();
+    txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    let append = |doc: &mut Doc, value: &str| {
+        let text = root_object(doc, "text");
+        let mut txn = doc.transaction();
+        txn.append_text(&text, value).unwrap();
+        txn.commit().unwrap();
+    };
+    append(&mut b, "a");
+    append(&mut b, "b");
+    c.merge(&b).unwrap();
+    append(&mut b, "c");
+    assert_eq!(b.compact().unwrap(), 2);
+
+    c.merge(&b).unwrap();
+    let text = root_object(&c, "text");
+    assert_eq!(c.get_text(&text).unwrap().unwrap(), "abc");
+
+    append(&mut c, "d");
+    b.merge(&c).unwrap();
+    for doc in [&b, &c] {
+        let text = root_object(doc, "text");
+        assert_eq!(doc.get_text(&text).unwrap().unwrap(), "abcd");
+    }
+}
+
+#[test]
+fn incremental_saves_append_new_operations() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.commit().unwrap();
+    let base = doc.save().unwrap();
+    assert!(!doc.is_dirty());
+
+    let mut increments = Vec::new();
+    for word in [" brave", " new", " world"] {
+        let mut txn = doc.transaction();
+        txn.append_text(&text, word).unwrap();
+        txn.commit().unwrap();
+        increments.push(doc.save_incremental().unwrap());
+        assert!(!doc.is_dirty());
+    }
+    assert!(increments
+        .iter()
+        .all(|increment| increment.len() < base.len()));
+
+    // Remote operations have to be saved as well
+    let mut peer = Doc::new_with_timestamp("2".to_string(), 2);
+    peer.merge(&doc).unwrap();
+    let mut txn = peer.transaction();
+    txn.set_scalar(ObjRef::Root, "author", "peer").unwrap();
+    txn.commit().unwrap();
+    doc.merge(&peer).unwrap();
+    increments.push(doc.save_incremental().unwrap());
+
+    let mut loaded = Doc::load("1".to_string(), base.into()).unwrap();
+    for increment in &increments {
+        loaded.load_incremental(increment.clone().into()).unwrap();
+    }
+    assert_eq!(loaded.version().unwrap(), doc.version().unwrap());
+    assert_eq!(root_text(&loaded, "text"), "Hello brave new world");
+    assert!(loaded.get(ObjRef::Root, "author").unwrap().is_some());
+
+    // The loaded increments are not saved again
+    let mut txn = loaded.transaction();
+    txn.append_text(&text, "!").unwrap();
+    txn.commit().unwrap();
+    let increment = loaded.save_incremental().unwrap();
+    let report = doc.load_incremental(increment.into()).unwrap();
+    assert_eq!(report.applied_operations, 1);
+    assert_eq!(root_text(&doc, "text"), "Hello brave new world!");
+
+    // Compacted operations can't be appended to the saved ones
+    doc.compact().unwrap();
+    assert!(matches!(
+        doc.save_incremental(),
+        Err(DocError::FullSaveRequired)
+    ));
+    let base = doc.save().unwrap();
+    let mut txn = doc.transaction();
+    txn.append_text(&text, "!").unwrap();
+    txn.commit().unwrap();
+    let increment = doc.save_incremental().unwrap();
+
+    let mut loaded = Doc::load("1".to_string(), base.into()).unwrap();
+    loaded.load_incremental(increment.into()).unwrap();
+    assert_eq!(root_text(&loaded, "text"), "Hello brave new world!!");
+}
+
+#[test]
+fn set_many_values_in_one_operation() {
+    let mut doc1 = Doc::new("1".to_string());
+    let mut txn = doc1.transaction();
+    let map = txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.set_scalar(&map, "a", 0).unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new("2".to_string());
+    doc2.merge(&doc1).unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.set_many(&map, [("a", 1), ("b", 2), ("c", 3), ("b", 4)])
+        .unwrap();
+    txn.set_many(&map, Vec::<(&str, i32)>::new()).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(doc1.heads().unwrap(), vec![OperationId::new(0, 3)]);
+
+    assert_eq!(doc1.get_ref(&map, "a").unwrap().unwrap().as_int(), Some(1));
+    assert_eq!(doc1.get_ref(&map, "b").unwrap().unwrap().as_int(), Some(4));
+    assert_eq!(doc1.get_ref(&map, "c").unwrap().unwrap().as_int(), Some(3));
+
+    // The previous value of "a" is overwritten, not in conflict
+    assert_eq!(doc1.conflicts(&map, "a").unwrap().len(), 1);
+
+    doc2.merge(&doc1).unwrap();
+    assert_eq!(doc2.get_ref(&map, "b").unwrap().unwrap().as_int(), Some(4));
+}
+
+#[test]
+fn set_many_serializes_smaller_than_single_sets() {
+    let values: Vec<(String, i32)> = (0..1000).map(|i| (format!("key{}", i), i)).collect();
+
+    let mut single = Doc::new("1".to_string());
+    let mut txn = single.transaction();
+    for (key, value) in &values {
+        txn.set_scalar(ObjRef::Root, key.as_str(), *value).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut batched = Doc::new("1".to_string());
+    let mut txn = batched.transaction();
+    txn.set_many(ObjRef::Root, values).unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(
+        batched
+            .get_ref(ObjRef::Root, "key999")
+            .unwrap()
+            .unwrap()
+            .as_int(),
+        Some(999)
+    );
+    assert!(batched.serialize().unwrap().len() < single.serialize().unwrap().len());
+}
+
+#[test]
+fn display_and_write_text() {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.insert_text(&text, 5, ",").unwrap();
+    txn.commit().unwrap();
+
+    let text_ref = doc.text(&text).unwrap().unwrap();
+    assert_eq!(format!("[{}]", text_ref), "[Hello, world]");
+    assert_eq!(text_ref.len(), 12);
+
+    let mut output = String::from("> ");
+    assert!(doc.write_text_to(&text, &mut output).unwrap());
+    assert_eq!(output, "> Hello, world");
+
+    let missing = ObjRef::from(OperationId::new(0, 100));
+    assert!(!doc.write_text_to(&missing, &mut output).unwrap());
+    assert!(doc.text(&missing).unwrap().is_none());
+
+    let lazy = Doc::lazy("1".to_string(), doc.serialize().unwrap().into()).unwrap();
+    assert_eq!(
+        lazy.text(&text).unwrap().unwrap().to_string(),
+        "Hello, world"
+    );
+}
+
+#[test]
+fn commit_compacts_keystrokes() {
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc.transaction();
+    for (index, char) in "Hello".char_indices() {
+        txn.insert_text(&text, index as u32, char.to_string())
+            .unwrap();
+    }
+    txn.set_scalar(ObjRef::Root, "status", "typing").unwrap();
+    txn.set_scalar(ObjRef::Root, "status", "idle").unwrap();
+    txn.commit().unwrap();
+
+    let heads = doc.heads().unwrap();
+    let status = doc.get_operation(&heads[0]).unwrap().unwrap();
+    let typed = doc.get_operation(&status.parent.unwrap()).unwrap().unwrap();
+    let OperationAction::InsertText(action) = &typed.action else {
+        panic!("expected an insertion");
+    };
+    assert_eq!(action.value, "Hello");
+    assert_eq!(
+        doc.get_operation(&typed.parent.unwrap())
+            .unwrap()
+            .unwrap()
+            .parent,
+        None
+    );
+
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), "Hello");
+    assert_eq!(
+        doc.get_ref(ObjRef::Root, "status")
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        Some("idle")
+    );
+    assert_eq!(doc.conflicts(ObjRef::Root, "status").unwrap().len(), 1);
+
+    let mut other = Doc::new("2".to_string());
+    other.merge(&doc).unwrap();
+    assert_eq!(other.get_text(&text).unwrap().unwrap(), "Hello");
+    assert_eq!(
+        other
+            .get_ref(ObjRef::Root, "status")
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        Some("idle")
+    );
+}
+
+#[test]
+fn relay_encoded_operations_between_docs() {
+    // doc2 is older, so doc1 has to remap its clients when receiving its operations
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 2);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 1);
+
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.create_map(ObjRef::Root, "tasks").unwrap();
+    txn.commit().unwrap();
+
+    let update = doc1
+        .encode_new_operations_since(&doc2.version().unwrap())
+        .unwrap();
+    let report = doc2
+        .apply_encoded_operations(update.clone().into())
+        .unwrap();
+    assert_eq!(report.operations_for("text"), 2);
+    assert_eq!(report.operations_for("tasks"), 1);
+    assert_eq!(root_text(&doc2, "text"), "Hello");
+
+    // Already applied
+    let report = doc2.apply_encoded_operations(update.into()).unwrap();
+    assert_eq!(report.applied_operations, 0);
+
+    let doc1_version = doc1.version().unwrap();
+    let doc2_version = doc2.version().unwrap();
+
+    let mut txn = doc1.transaction();
+    txn.append_text(&text, " world").unwrap();
+    txn.commit().unwrap();
+
+    let doc2_text = root_object(&doc2, "text");
+    let mut txn = doc2.transaction();
+    txn.insert_text(&doc2_text, 0, ">> ").unwrap();
+    txn.create_map(ObjRef::Root, "archive").unwrap();
+    txn.commit().unwrap();
+
+    let update1 = doc1.encode_new_operations_since(&doc2_version).unwrap();
+    let update2 = doc2.encode_new_operations_since(&doc1_version).unwrap();
+    doc2.apply_encoded_operations(update1.into()).unwrap();
+    doc1.apply_encoded_operations(update2.into()).unwrap();
+
+    assert_eq!(doc1.version().unwrap(), doc2.version().unwrap());
+    assert_eq!(root_text(&doc1, "text"), ">> Hello world");
+    assert_eq!(root_text(&doc2, "text"), ">> Hello world");
+    assert!(doc1.get(ObjRef::Root, "archive").unwrap().is_some());
+
+    // Receiving the operations incrementally is the same as merging the whole document
+    let mut doc3 = Doc::new_with_timestamp("3".to_string(), 3);
+    doc3.merge(&doc2).unwrap();
+    assert_eq!(root_text(&doc3, "text"), ">> Hello world");
+    assert!(doc3.get(ObjRef::Root, "archive").unwrap().is_some());
+}
+
+#[test]
+fn scalar_values_survive_the_operation_log() {
+    let date = chrono::Utc
+        .with_ymd_and_hms(2023, 10, 1, 12, 30, 0)
+        .unwrap();
+    let values = [
+        ("string", ScalarValue::from("value")),
+        ("int", ScalarValue::from(-42)),
+        ("double", ScalarValue::from(3.5)),
+        ("bool", ScalarValue::from(true)),
+        ("timestamp", ScalarValue::from(date)),
+        ("null", ScalarValue::Null),
+    ];
+
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    for (key, value) in values.iter() {
+        txn.set_scalar(ObjRef::Root, *key, value.clone()).unwrap();
+    }
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_many(&settings, values.iter().cloned()).unwrap();
+    txn.commit().unwrap();
+
+    // Both a full load and an incremental update have to decode the operations
+    let loaded = Doc::load("2".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    let mut relayed = Doc::new_with_timestamp("3".to_string(), 3);
+    let update = doc1
+        .encode_new_operations_since(&relayed.version().unwrap())
+        .unwrap();
+    relayed.apply_encoded_operations(update.into()).unwrap();
+
+    for doc in [&loaded, &relayed] {
+        for (key, value) in values.iter() {
+            let expected = Value::Scalar(value.clone());
+            assert_eq!(doc.get(ObjRef::Root, *key).unwrap(), Some(&expected));
+            assert_eq!(doc.get(&settings, *key).unwrap(), Some(&expected));
+        }
+    }
+}
+
+#[test]
+fn repeated_merges_match_a_full_rebuild() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    let tasks = txn.create_map(ObjRef::Root, "tasks").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+    doc2.merge(&doc1).unwrap();
+
+    // After the first merge the client IDs are stable, so the views are updated incrementally
+    for round in 0..5 {
+        let mut txn = doc1.transaction();
+        txn.append_text(&text, format!("a{}", round)).unwrap();
+        txn.create_map(&tasks, format!("first-{}", round).as_str())
+            .unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = doc2.transaction();
+        txn.insert_text(&text, 0, format!("b{}", round)).unwrap();
+        txn.delete_text(&text, 0, 1).unwrap();
+        txn.create_map(&tasks, format!("second-{}", round).as_str())
+            .unwrap();
+        txn.commit().unwrap();
+
+        doc1.merge(&doc2).unwrap();
+        doc2.merge(&doc1).unwrap();
+        assert_eq!(doc1.get_text(&text).unwrap(), doc2.get_text(&text).unwrap());
+    }
+
+    let mut rebuilt = Doc::new_with_timestamp("1".to_string(), 1);
+    rebuilt.merge(&doc2).unwrap();
+    assert_eq!(
+        rebuilt.get_text(&text).unwrap(),
+        doc1.get_text(&text).unwrap()
+    );
+    for round in 0..5 {
+        for key in [format!("first-{}", round), format!("second-{}", round)] {
+            assert!(doc1.get(&tasks, key.as_str()).unwrap().is_some());
+            assert!(doc2.get(&tasks, key.as_str()).unwrap().is_some());
+        }
+    }
+}
+
+#[test]
+fn merge_with_timestamp_policy_adjusts_incoming_operations() {
+    let mut server = Doc::new_with_timestamp("server".to_string(), 1);
+    let mut txn = server.transaction();
+    txn.set_scalar(ObjRef::Root, "title", "server").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 2);
+    let mut txn = doc1.transaction();
+    txn.set_scalar(ObjRef::Root, "title", "client").unwrap();
+    txn.commit().unwrap();
+
+    // doc1 wrote after the server, but its timestamp is lowered when received
+    let options = MergeOptions {
+        timestamp_policy: TimestampPolicy::Clamp(1000),
+    };
+    let report = server.merge_with_options(&doc1, &options).unwrap();
+    assert_eq!(report.applied_operations, 1);
+    assert_eq!(report.timestamp_adjustments.len(), 1);
+
+    let adjustment = &report.timestamp_adjustments[0];
+    assert_eq!(adjustment.effective, 1000);
+    assert!(adjustment.original > 1000);
+    assert_eq!(
+        server
+            .get_operation(&adjustment.operation)
+            .unwrap()
+            .unwrap()
+            .timestamp,
+        1000
+    );
+    assert_eq!(
+        server
+            .get_ref(ObjRef::Root, "title")
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        Some("server")
+    );
+
+    // Receiving the original operation again is not a conflict
+    let report = server.merge_with_report(&doc1).unwrap();
+    assert_eq!(report.applied_operations, 0);
+    assert!(report.timestamp_adjustments.is_empty());
+}
+
+#[test]
+fn remapped_clients_keep_the_view_consistent() {
+    let mut doc_a = Doc::new_with_timestamp("a".to_string(), 3);
+    let mut txn = doc_a.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    let first = txn.create_map(ObjRef::Root, "first").unwrap();
+    txn.create_map(&first, "section").unwrap();
+    txn.create_map(ObjRef::Root, "second").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc_b = Doc::new_with_timestamp("b".to_string(), 2);
+    doc_b.merge(&doc_a).unwrap();
+    let b_text = root_object(&doc_b, "text");
+    let b_first = root_object(&doc_b, "first");
+    let b_second = root_object(&doc_b, "second");
+    let mut txn = doc_b.transaction();
+    txn.insert_text(&b_text, 0, "b: ").unwrap();
+    txn.move_object(&b_first, "section", &b_second, "moved")
+        .unwrap();
+    txn.commit().unwrap();
+
+    let mut doc_c = Doc::new_with_timestamp("c".to_string(), 1);
+    doc_c.merge(&doc_b).unwrap();
+    let c_text = root_object(&doc_c, "text");
+    let mut txn = doc_c.transaction();
+    txn.append_text(&c_text, "!").unwrap();
+    txn.create_map(ObjRef::Root, "third").unwrap();
+    txn.commit().unwrap();
+
+    // Both b and c are older than a, so all the ids of doc_a change
+    doc_a.merge(&doc_c).unwrap();
+    assert_eq!(root_text(&doc_a, "text"), "b: Hello!");
+    let a_second = root_object(&doc_a, "second");
+    assert!(doc_a.get(&a_second, "moved").unwrap().is_some());
+
+    // Local edits keep working after the remap
+    let a_text = root_object(&doc_a, "text");
+    let mut txn = doc_a.transaction();
+    txn.append_text(&a_text, "?").unwrap();
+    txn.create_map(ObjRef::Root, "fourth").unwrap();
+    txn.commit().unwrap();
+
+    doc_b.merge(&doc_a).unwrap();
+    doc_c.merge(&doc_a).unwrap();
+    let mut rebuilt = Doc::new_with_timestamp("d".to_string(), 4);
+    rebuilt.merge(&doc_a).unwrap();
+
+    for doc in [&doc_a, &doc_b, &doc_c, &rebuilt] {
+        assert_eq!(root_text(doc, "text"), "b: Hello!?");
+        let second = root_object(doc, "second");
+        assert!(doc.get(&second, "moved").unwrap().is_some());
+        let first = root_object(doc, "first");
+        assert!(doc.get(&first, "section").unwrap().is_none());
+        assert!(doc.get(ObjRef::Root, "third").unwrap().is_some());
+        assert!(doc.get(ObjRef::Root, "fourth").unwrap().is_some());
+    }
+}
+
+// Object IDs depend on the local client IDs, so objects are looked up by key
+fn root_object(doc: &Doc, key: &str) -> ObjRef {
+    doc.get(ObjRef::Root, key)
+        .unwrap()
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .clone()
+}
+
+fn root_text(doc: &Doc, key: &str) -> String {
+    doc.get_text(root_object(doc, key)).unwrap().unwrap()
+}
+
+#[test]
+fn persistent_docs_survive_reopening() {
+    let mut doc =
+        PersistentDoc::open_with_timestamp("1".to_string(), 1, MemoryStorage::new()).unwrap();
+    doc.set_snapshot_interval(3);
+
+    let text = doc
+        .transact(|txn| txn.create_text(ObjRef::Root, "text"))
+        .unwrap();
+    for word in ["All", " work", " and", " no", " play"] {
+        doc.transact(|txn| txn.append_text(&text, word)).unwrap();
+    }
+    // Six changes with a snapshot every three increments
+    assert!(doc.storage().snapshot_len().is_some());
+    assert_eq!(doc.storage().ops_len(), 2);
+
+    // Failed transactions still persist the changes made before the error
+    let result = doc.transact(|txn| {
+        txn.set_scalar(ObjRef::Root, "title", "Notes")?;
+        txn.insert_text(&text, 1000, "out of bounds")
+    });
+    assert!(matches!(result, Err(StorageError::TransactionError(_))));
+
+    let mut peer = Doc::new_with_timestamp("2".to_string(), 2);
+    peer.merge(doc.doc()).unwrap();
+    let mut txn = peer.transaction();
+    txn.append_text(&text, ".").unwrap();
+    txn.commit().unwrap();
+    doc.merge(&peer).unwrap();
+
+    let storage = doc.into_storage();
+    let reopened = PersistentDoc::open_with_timestamp("1".to_string(), 3, storage).unwrap();
+    assert_eq!(root_text(reopened.doc(), "text"), "All work and no play.");
+    assert!(reopened.doc().get(ObjRef::Root, "title").unwrap().is_some());
+}
+
+#[test]
+fn file_storage_persists_docs() {
+    let dir = std::env::temp_dir().join(format!("json-crdt-storage-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let storage = FileStorage::open(&dir).unwrap();
+    let mut doc = PersistentDoc::open_with_timestamp("1".to_string(), 1, storage).unwrap();
+    let text = doc
+        .transact(|txn| txn.create_text(ObjRef::Root, "text"))
+        .unwrap();
+    doc.transact(|txn| txn.append_text(&text, "Hello")).unwrap();
+    doc.save_snapshot().unwrap();
+    doc.transact(|txn| txn.append_text(&text, " world"))
+        .unwrap();
+    drop(doc);
+
+    let storage = FileStorage::open(&dir).unwrap();
+    assert!(storage.load_snapshot().unwrap().is_some());
+    assert_eq!(storage.load_ops().unwrap().len(), 1);
+    let mut doc = PersistentDoc::open_with_timestamp("1".to_string(), 2, storage).unwrap();
+    assert_eq!(root_text(doc.doc(), "text"), "Hello world");
+
+    // Compacting requires a new snapshot, which replaces the appended operations
+    doc.compact().unwrap();
+    assert!(doc.storage().load_ops().unwrap().is_empty());
+
+    // A record cut short by a crash is reported
+    let mut storage = doc.into_storage();
+    storage.append_ops(&[1, 2, 3]).unwrap();
+    let ops_path = dir.join("ops");
+    let ops = std::fs::read(&ops_path).unwrap();
+    std::fs::write(&ops_path, &ops[..ops.len() - 1]).unwrap();
+    assert!(matches!(
+        storage.load_ops(),
+        Err(StorageError::Corrupted(_))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shared_docs_notify_changes_across_threads() {
+    let shared = SharedDoc::new(Doc::new_with_timestamp("1".to_string(), 1));
+    let changes = shared.subscribe();
+
+    let text = shared
+        .write(|txn| txn.create_text(ObjRef::Root, "text"))
+        .unwrap();
+
+    let writers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            let text = text.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    shared.write(|txn| txn.append_text(&text, "a")).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(
+        shared.read(|doc| doc.get_text(&text).unwrap().unwrap().len()),
+        40
+    );
+    let local_changes: Vec<DocChange> = changes.try_iter().collect();
+    assert_eq!(local_changes.len(), 41);
+    assert!(local_changes
+        .iter()
+        .all(|change| change.origin == ChangeOrigin::Local));
+
+    // Remote changes are only notified if something was applied
+    let mut peer = Doc::new_with_timestamp("2".to_string(), 2);
+    shared.read(|doc| peer.merge(doc)).unwrap();
+    let mut txn = peer.transaction();
+    txn.append_text(&text, "b").unwrap();
+    txn.commit().unwrap();
+
+    shared.merge(&peer).unwrap();
+    shared.merge(&peer).unwrap();
+    let remote_changes: Vec<DocChange> = changes.try_iter().collect();
+    assert_eq!(remote_changes.len(), 1);
+    assert_eq!(remote_changes[0].origin, ChangeOrigin::Remote);
+    assert_eq!(remote_changes[0].version, peer.version().unwrap());
+
+    // Writes made before a failure are kept, and notified like the others
+    let result = shared.write(|txn| {
+        txn.append_text(&text, "c")?;
+        txn.delete_text(&text, 100, 1)
+    });
+    assert!(result.is_err());
+    assert!(shared.read(|doc| doc.get_text(&text).unwrap().unwrap().ends_with("bc")));
+    let failed_changes: Vec<DocChange> = changes.try_iter().collect();
+    assert_eq!(failed_changes.len(), 1);
+    assert_eq!(
+        failed_changes[0].version,
+        shared.read(|doc| doc.version().unwrap())
+    );
+
+    // Dropped subscribers are not notified anymore
+    drop(changes);
+    shared.write(|txn| txn.append_text(&text, "d")).unwrap();
+}
+
+#[test]
+fn shared_docs_notify_remote_changes_applied_before_a_failure() {
+    let shared = SharedDoc::new(Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            max_orphans: Some(0),
+            ..Default::default()
+        },
+    ));
+    let changes = shared.subscribe();
+
+    let mut writer = Doc::new_with_timestamp("2".to_string(), 2);
+    let mut txn = writer.transaction();
+    txn.set_scalar(ObjRef::Root, "a", 1).unwrap();
+    txn.commit().unwrap();
+    shared.merge(&writer).unwrap();
+    assert_eq!(changes.try_iter().count(), 1);
+
+    // Created before the writer, so its operations come first in the update
+    let mut other = Doc::new_with_timestamp("3".to_string(), 1);
+    other.merge(&writer).unwrap();
+    let mut txn = other.transaction();
+    txn.set_scalar(ObjRef::Root, "b", 2).unwrap();
+    txn.commit().unwrap();
+    for key in ["c", "d"] {
+        let mut txn = writer.transaction();
+        txn.set_scalar(ObjRef::Root, key, 3).unwrap();
+        txn.commit().unwrap();
+    }
+    other.merge(&writer).unwrap();
+
+    // The update skips "c", so "d" is an orphan and rejected after "b" is applied
+    let mut version = writer.version().unwrap();
+    version.set("2".to_string(), 2);
+    let update = other.encode_new_operations_since(&version).unwrap();
+    assert!(shared.apply_encoded_operations(update.into()).is_err());
+
+    assert!(shared.read(|doc| doc.get(ObjRef::Root, "b").unwrap().is_some()));
+    let remote_changes: Vec<DocChange> = changes.try_iter().collect();
+    assert_eq!(remote_changes.len(), 1);
+    assert_eq!(remote_changes[0].origin, ChangeOrigin::Remote);
+    assert_eq!(
+        remote_changes[0].version,
+        shared.read(|doc| doc.version().unwrap())
+    );
+
+    // Nothing is notified when nothing was applied
+    assert!(shared.merge(&Doc::new("4".to_string())).is_ok());
+    assert_eq!(changes.try_iter().count(), 0);
+}
+
+#[test]
+fn compressed_documents_are_loaded_transparently() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    for _ in 0..200 {
+        txn.append_text(&text, "All work and no play. ").unwrap();
+    }
+    txn.commit().unwrap();
+
+    let buffer = doc.serialize().unwrap();
+    let compressed = doc.serialize_compressed().unwrap();
+    assert!(compressed.len() * 4 < buffer.len());
+
+    let loaded = Doc::load("2".to_string(), compressed.clone().into()).unwrap();
+    assert_eq!(
+        loaded.get_text(&text).unwrap(),
+        doc.get_text(&text).unwrap()
+    );
+
+    let lazy = Doc::lazy("2".to_string(), compressed.into()).unwrap();
+    assert_eq!(lazy.get_text(&text).unwrap(), doc.get_text(&text).unwrap());
+
+    // Lazy documents can be compressed without being loaded
+    let lazy = Doc::lazy("3".to_string(), buffer.into()).unwrap();
+    let recompressed = lazy.serialize_compressed().unwrap();
+    let loaded = Doc::load("4".to_string(), recompressed.into()).unwrap();
+    assert_eq!(
+        loaded.get_text(&text).unwrap(),
+        doc.get_text(&text).unwrap()
+    );
+}
+
+#[test]
+fn loading_buffers_from_newer_format_versions_fails() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    let mut buffer = doc.serialize().unwrap();
+    assert_eq!(&buffer[..4], b"JCRD");
+    assert_eq!(buffer[4] as u32, FORMAT_VERSION);
+    assert!(Doc::load("2".to_string(), buffer.clone().into()).is_ok());
+
+    buffer[4] += 1;
+    let result = Doc::load("2".to_string(), buffer.clone().into());
+    assert!(matches!(
+        result,
+        Err(DocError::SerializationError(
+            SerializationError::UnsupportedVersion { .. }
+        ))
+    ));
+    assert!(Doc::lazy("2".to_string(), buffer.into()).is_err());
+}
+
+#[test]
+fn validate_buffer_checks_structure_without_loading() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello").unwrap();
+    txn.insert_text(&text, 0, ">> ").unwrap();
+    txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+    doc2.merge(&doc).unwrap();
+
+    let buffer = doc2.serialize().unwrap();
+    let info = Doc::validate_buffer(buffer.clone().into()).unwrap();
+    assert_eq!(info.clients, 2);
+    assert_eq!(info.operations, 4);
+    assert!(info.view_cache_len + info.client_registry_len + info.operation_log_len < buffer.len());
+
+    let truncated = buffer[..buffer.len() - 1].to_vec();
+    assert!(Doc::validate_buffer(truncated.into()).is_err());
+
+    let mut trailing = buffer.clone();
+    trailing.push(0);
+    assert!(Doc::validate_buffer(trailing.into()).is_err());
+
+    assert!(Doc::validate_buffer(Vec::new().into()).is_err());
+}
+
+// Minimal xorshift generator, so that the fuzzing tests are reproducible
+struct Fuzzer(u64);
+
+impl Fuzzer {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+// Decoding must fail with an error, so everything is allowed except panics
+fn decode_untrusted(buffer: &[u8]) {
+    let _ = Doc::validate_buffer(buffer.to_vec().into());
+
+    if let Ok(doc) = Doc::load("fuzz".to_string(), buffer.to_vec().into()) {
+        let _ = doc.as_map();
+    }
+
+    if let Ok(doc) = Doc::lazy("fuzz".to_string(), buffer.to_vec().into()) {
+        let _ = doc.as_map();
+        let _ = doc.get(ObjRef::Root, "text");
+    }
+
+    let mut doc = Doc::new("receiver".to_string());
+    if doc.apply_encoded_operations(buffer.to_vec().into()).is_ok() {
+        let _ = doc.as_map();
+    }
+}
+
+#[test]
+fn decoding_random_bytes_never_panics() {
+    let mut fuzzer = Fuzzer(0x2545f4914f6cdd1d);
+    for _ in 0..2000 {
+        let len = fuzzer.below(96);
+        decode_untrusted(&fuzzer.bytes(len));
+    }
+
+    // Huge lengths and run counts must be rejected before allocating anything
+    decode_untrusted(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+    decode_untrusted(&[0, 0, 5, 1, 1, 1, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+}
+
+#[test]
+fn decoding_corrupted_documents_never_panics() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "Hello world").unwrap();
+    txn.delete_text(&text, 2, 3).unwrap();
+    let map = txn.create_map(ObjRef::Root, "map").unwrap();
+    txn.create_text(&map, "nested").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+    doc2.merge(&doc).unwrap();
+    let text = root_object(&doc2, "text");
+    let mut txn = doc2.transaction();
+    txn.insert_text(&text, 0, ">> ").unwrap();
+    txn.commit().unwrap();
+
+    let buffer = doc2.serialize().unwrap();
+    let compressed = doc2.serialize_compressed().unwrap();
+    let update = doc2
+        .encode_new_operations_since(&DocVersion::new())
+        .unwrap();
+
+    for original in [buffer, compressed, update] {
+        for len in 0..original.len() {
+            decode_untrusted(&original[..len]);
+        }
+
+        let mut fuzzer = Fuzzer(original.len() as u64 | 1);
+        for _ in 0..2000 {
+            let mut corrupted = original.clone();
+            for _ in 0..=fuzzer.below(3) {
+                let position = fuzzer.below(corrupted.len());
+                corrupted[position] = fuzzer.next() as u8;
+            }
+            decode_untrusted(&corrupted);
+        }
+    }
+}
+
+#[test]
+fn concurrent_edits_converge_regardless_of_merge_order() {
+    fn edit(doc: &mut Doc, prefix: &str) {
+        let text = root_object(doc, "text");
+        let mut txn = doc.transaction();
+        txn.insert_text(&text, 0, prefix).unwrap();
+        txn.append_text(&text, prefix).unwrap();
+        txn.create_text(ObjRef::Root, "winner").unwrap();
+        txn.commit().unwrap();
+    }
+
+    fn summary(doc: &Doc) -> (String, String) {
+        let winner = doc
+            .get(ObjRef::Root, "winner")
+            .unwrap()
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        let ObjRef::Object(winner) = winner else {
+            panic!("expected object");
+        };
+        let author = doc.global_client_of(&winner).unwrap().unwrap().clone();
+        (root_text(doc, "text"), author)
+    }
+
+    let mut base = Doc::new_with_timestamp("base".to_string(), 10);
+    let mut txn = base.transaction();
+    txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    // Each client only knows about the base document, and they are all created at the same
+    // time, so their order only depends on the global IDs
+    let mut docs: Vec<Doc> = ["c", "a", "b"]
+        .iter()
+        .map(|client| {
+            let mut doc = Doc::new_with_timestamp(client.to_string(), 20);
+            doc.merge(&base).unwrap();
+            edit(&mut doc, client);
+            doc
+        })
+        .collect();
+
+    // An older client that joins later, remapping every other client
+    let mut oldest = Doc::new_with_timestamp("z".to_string(), 1);
+    oldest.merge(&base).unwrap();
+    edit(&mut oldest, "z");
+    docs.push(oldest);
+
+    let orders: [[usize; 4]; 3] = [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]];
+    let mut summaries = Vec::new();
+    for order in orders {
+        let mut merged = Doc::new_with_timestamp("observer".to_string(), 30);
+        for index in order {
+            merged.merge(&docs[index]).unwrap();
+        }
+        summaries.push(summary(&merged));
+
+        let loaded = Doc::load("reader".to_string(), merged.serialize().unwrap().into()).unwrap();
+        summaries.push(summary(&loaded));
+    }
+
+    // Pairwise merges between the clients themselves
+    for (first, second) in [(0, 1), (2, 3), (3, 0), (1, 3), (0, 2), (2, 1)] {
+        let (low, high) = docs.split_at_mut(first.max(second));
+        let (target, source) = if first < second {
+            (&mut low[first], &high[0])
+        } else {
+            (&mut high[0], &low[second])
+        };
+        target.merge(source).unwrap();
+    }
+    for doc in &docs {
+        let mut complete = Doc::new_with_timestamp("complete".to_string(), 40);
+        complete.merge(doc).unwrap();
+        for other in &docs {
+            complete.merge(other).unwrap();
+        }
+        summaries.push(summary(&complete));
+    }
+
+    let (text, winner) = &summaries[0];
+    assert_eq!(text.len(), 8);
+    assert!(["a", "b", "c", "z"].contains(&winner.as_str()));
+    for other in &summaries {
+        assert_eq!(other, &summaries[0]);
+    }
+}
+
+fn simulate_edits(seed: u64, conditions: NetworkConditions) -> Simulation {
+    let mut simulation = Simulation::new(4, conditions, seed);
+
+    let mut txn = simulation.doc_mut(0).transaction();
+    txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+    assert!(simulation.sync_until_converged(20).unwrap().is_some());
+
+    // Every peer appends to the text that the others just changed
+    for _ in 0..10 {
+        for peer in 0..simulation.peers() {
+            let doc = simulation.doc_mut(peer);
+            let text = root_object(doc, "text");
+            let mut txn = doc.transaction();
+            txn.append_text(&text, peer.to_string()).unwrap();
+            txn.commit().unwrap();
+
+            simulation.broadcast(peer).unwrap();
+            simulation.advance(20).unwrap();
+        }
+    }
+
+    simulation
+}
+
+#[test]
+fn simulated_peers_converge_despite_drops_and_duplicates() {
+    let conditions = NetworkConditions {
+        drop_rate: 0.3,
+        duplicate_rate: 0.2,
+        ..NetworkConditions::default()
+    };
+    let mut simulation = simulate_edits(7, conditions.clone());
+    assert!(simulation.sync_until_converged(50).unwrap().is_some());
+
+    let stats = simulation.stats().clone();
+    assert!(stats.messages_dropped > 0);
+    assert!(stats.messages_duplicated > 0);
+    assert!(stats.redundant_updates > 0);
+
+    let text = root_text(simulation.doc(0), "text");
+    assert_eq!(text.len(), 40);
+    for peer in 0..simulation.peers() {
+        assert_eq!(text.matches(&peer.to_string()).count(), 10);
+        assert_eq!(root_text(simulation.doc(peer), "text"), text);
+    }
+
+    // The network only depends on the seed, while sizes depend on the operation timestamps
+    let mut replay = simulate_edits(7, conditions);
+    replay.sync_until_converged(50).unwrap();
+    let replay_stats = SimulationStats {
+        update_bytes: stats.update_bytes,
+        ..replay.stats().clone()
+    };
+    assert_eq!(replay_stats, stats);
+}
+
+#[test]
+fn partitioned_peers_converge_after_healing() {
+    let mut simulation = Simulation::new(3, NetworkConditions::default(), 1);
+    simulation.partition(&[&[0, 1], &[2]]);
+
+    for peer in 0..3 {
+        let mut txn = simulation.doc_mut(peer).transaction();
+        txn.create_text(ObjRef::Root, peer.to_string()).unwrap();
+        txn.commit().unwrap();
+    }
+    simulation.broadcast_all().unwrap();
+    simulation.run_until_idle().unwrap();
+
+    assert_eq!(simulation.stats().messages_dropped, 4);
+    assert!(simulation.doc(1).get(ObjRef::Root, "0").unwrap().is_some());
+    assert!(simulation.doc(2).get(ObjRef::Root, "0").unwrap().is_none());
+    assert!(!simulation.is_converged().unwrap());
+
+    simulation.heal();
+    assert_eq!(simulation.sync_until_converged(10).unwrap(), Some(1));
+    for peer in 0..3 {
+        let doc = simulation.doc(peer);
+        for key in ["0", "1", "2"] {
+            assert!(doc.get(ObjRef::Root, key).unwrap().is_some());
+        }
+    }
+}
+
+#[test]
+fn integrity_holds_after_concurrent_edits_and_loading() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::load("2".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    for (index, doc) in [&mut doc1, &mut doc2].into_iter().enumerate() {
+        for word in 0..200 {
+            let mut txn = doc.transaction();
+            txn.insert_text(&text, word, format!("{}{} ", index, word))
+                .unwrap();
+            if word % 3 == 0 {
+                txn.delete_text(&text, 0, 2).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+    }
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    for doc in [&doc1, &doc2] {
+        let report = doc.check_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
+
+    let loaded = Doc::load("3".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    assert!(loaded.check_integrity().unwrap().is_ok());
+}
+
+#[test]
+fn concurrent_overlapping_deletions_converge() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, "hello brave new world").unwrap();
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::load("2".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    let mut doc3 = Doc::load("3".to_string(), doc1.serialize().unwrap().into()).unwrap();
+    doc3.set_drop_tombstones(true).unwrap();
+
+    // "brave new " and "new world" overlap on "new ", the ranges of doc3 overlap both
+    let mut txn = doc1.transaction();
+    txn.delete_text(&text, 6, 10).unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc2.transaction();
+    txn.delete_text(&text, 12, 9).unwrap();
+    txn.commit().unwrap();
+    let mut txn = doc3.transaction();
+    txn.delete_text_ranges(&text, [6..10, 14..21]).unwrap();
+    txn.commit().unwrap();
+
+    let copy = |doc: &Doc| Doc::load("copy".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let (copy1, copy2, copy3) = (copy(&doc1), copy(&doc2), copy(&doc3));
+    doc1.merge(&copy2).unwrap();
+    doc1.merge(&copy3).unwrap();
+    doc2.merge(&copy3).unwrap();
+    doc2.merge(&copy1).unwrap();
+    doc3.merge(&copy1).unwrap();
+    doc3.merge(&copy2).unwrap();
+
+    for doc in [&mut doc1, &mut doc2, &mut doc3] {
+        // Merging the same deletions again doesn't change anything
+        doc.merge(&copy1).unwrap();
+        assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello ");
+        let report = doc.check_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+
+        // Positions rely on the sizes of the tree, which must not count deletions twice
+        let mut txn = doc.transaction();
+        txn.append_text(&text, "there").unwrap();
+        txn.insert_text(&text, 6, "out ").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(doc.get_text(&text).unwrap().unwrap(), "hello out there");
+    }
+}
+
+#[test]
+fn inserts_after_concurrently_deleted_characters_keep_their_place() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc1.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, "abcdefghij").unwrap();
+    txn.commit().unwrap();
+
+    for drop_tombstones in [false, true] {
+        let load = |doc: &Doc, client: &str| {
+            let mut doc = Doc::load(client.to_string(), doc.serialize().unwrap().into()).unwrap();
+            doc.set_drop_tombstones(drop_tombstones).unwrap();
+            doc
+        };
+        let mut deleting = load(&doc1, "deleting");
+        let mut inserting = load(&doc1, "inserting");
+
+        let mut txn = deleting.transaction();
+        txn.delete_text(&text, 3, 4).unwrap();
+        txn.commit().unwrap();
+
+        // Anchored to characters of the deleted "defg": inside the range, at its end, and
+        // to the one right before it
+        let mut txn = inserting.transaction();
+        txn.insert_text(&text, 5, "X").unwrap();
+        txn.insert_text(&text, 8, "Y").unwrap();
+        txn.insert_text(&text, 3, "Z").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(inserting.get_text(&text).unwrap().unwrap(), "abcZdeXfgYhij");
+
+        // Deleted characters stay as anchors, and only the characters that the deleting
+        // replica saw are deleted, whichever operation is received first
+        let deleted = load(&deleting, "copy");
+        let inserted = load(&inserting, "copy");
+        deleting.merge(&inserted).unwrap();
+        inserting.merge(&deleted).unwrap();
+        for doc in [&deleting, &inserting] {
+            assert_eq!(doc.get_text(&text).unwrap().unwrap(), "abcZXYhij");
+            assert_eq!(
+                load(doc, "reloaded").get_text(&text).unwrap().unwrap(),
+                "abcZXYhij"
+            );
+        }
+    }
+}
+
+#[test]
+fn concurrent_text_edits_converge_in_any_order() {
+    fn edit(doc: &mut Doc, text: &ObjRef, fuzzer: &mut Fuzzer) {
+        let len = doc.get_text(text).unwrap().unwrap().len();
+        let index = fuzzer.below(len + 1) as u32;
+        let count = fuzzer.below((len - index as usize).min(4) + 1) as u32;
+        let value = ["x", "yy", "zzz", ""][fuzzer.below(4)];
+
+        let mut txn = doc.transaction();
+        match fuzzer.below(3) {
+            0 if !value.is_empty() => txn.insert_text(text, index, value).unwrap(),
+            1 if count > 0 => txn.delete_text(text, index, count).unwrap(),
+            _ => txn.splice_text(text, index, count, value).unwrap(),
+        }
+        txn.commit().unwrap();
+    }
+
+    fn copy(doc: &Doc) -> Doc {
+        Doc::load("copy".to_string(), doc.serialize().unwrap().into()).unwrap()
+    }
+
+    let mut base = Doc::new_with_timestamp("base".to_string(), 1);
+    let mut txn = base.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, "the quick brown fox").unwrap();
+    txn.commit().unwrap();
+
+    for seed in 1..20 {
+        let mut fuzzer = Fuzzer(seed);
+        let mut docs: Vec<Doc> = (0..3)
+            .map(|index| {
+                let mut doc =
+                    Doc::load(index.to_string(), base.serialize().unwrap().into()).unwrap();
+                doc.set_drop_tombstones(index == 0).unwrap();
+                doc
+            })
+            .collect();
+
+        // Replicas edit the text and sometimes receive the changes of another one
+        for _ in 0..40 {
+            let index = fuzzer.below(docs.len());
+            if fuzzer.below(4) == 0 {
+                let other = copy(&docs[fuzzer.below(docs.len())]);
+                docs[index].merge(&other).unwrap();
+            } else {
+                edit(&mut docs[index], &text, &mut fuzzer);
+            }
+        }
+
+        let copies: Vec<Doc> = docs.iter().map(copy).collect();
+        for doc in docs.iter_mut() {
+            for other in &copies {
+                doc.merge(other).unwrap();
+            }
+        }
+
+        let expected = docs[0].get_text(&text).unwrap().unwrap();
+        for doc in &docs {
+            assert_eq!(
+                doc.get_text(&text).unwrap().unwrap(),
+                expected,
+                "seed {}",
+                seed
+            );
+            assert_eq!(copy(doc).get_text(&text).unwrap().unwrap(), expected);
+            let report = doc.check_integrity().unwrap();
+            assert!(report.is_ok(), "{:?}", report.issues);
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn documents_round_trip_through_serde() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 0);
+    let json = r#"{"name":"doc","count":3,"big":10000000000,"ratio":0.5,"done":false,"none":null,"nested":{"list":["a",{"b":true}]}}"#;
+
+    let mut txn = doc.transaction();
+    txn.import(ObjRef::Root, &mut serde_json::Deserializer::from_str(json))
+        .unwrap();
+    txn.commit().unwrap();
+
+    let list = Path::parse("nested.list[1].b").unwrap();
+    assert_eq!(
+        doc.get_path(&list).unwrap().unwrap().as_scalar().unwrap(),
+        &ScalarValue::Bool(true)
+    );
+    assert_eq!(
+        doc.get(ObjRef::Root, "big")
+            .unwrap()
+            .unwrap()
+            .as_scalar()
+            .unwrap(),
+        &ScalarValue::Double(10000000000.0)
+    );
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, "hello").unwrap();
+    txn.commit().unwrap();
+
+    let exported = serde_json::to_value(doc.as_map().unwrap()).unwrap();
+    let mut expected: serde_json::Value = serde_json::from_str(json).unwrap();
+    expected["big"] = serde_json::json!(10000000000.0);
+    expected["text"] = serde_json::json!("hello");
+    // Sequences are imported as maps keyed by index
+    expected["nested"]["list"] = serde_json::json!({"0": "a", "1": {"b": true}});
+    assert_eq!(exported, expected);
+
+    let mut txn = doc.transaction();
+    let error = txn
+        .import(ObjRef::Root, &mut serde_json::Deserializer::from_str("[1]"))
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        TransactionError::ImportError {
+            object: ObjRef::Root,
+            ..
+        }
+    ));
+    // Errors of the transaction are kept as they are
+    let error = txn
+        .import(text, &mut serde_json::Deserializer::from_str(r#"{"a": 1}"#))
+        .unwrap_err();
+    assert!(matches!(error, TransactionError::IncompatibleTypes { .. }));
+}
+
+#[cfg(feature = "debug-tools")]
+#[test]
+fn debug_dump_renders_operations_maps_and_texts() {
+    use json_crdt_rust::GraphFormat;
+
+    let mut doc = Doc::new_with_timestamp("alice".to_string(), 0);
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "title", "draft").unwrap();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello \"world\"").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = doc.transaction();
+    txn.delete_text(&text, 0, 6).unwrap();
+    txn.commit().unwrap();
+
+    let mermaid = doc.debug_dump(GraphFormat::Mermaid).unwrap();
+    assert!(mermaid.starts_with("flowchart TB\n"));
+    assert!(mermaid.contains("subgraph cluster_0[\"operations\"]"));
+    assert!(mermaid.contains("alice@1 SetMapValue"));
+    assert!(mermaid.contains("alice@2 CreateText"));
+    assert!(mermaid.contains("op_0_1 --> op_0_2"));
+    assert!(mermaid.contains("map root"));
+    assert!(mermaid.contains("text alice@2"));
+    assert!(mermaid.contains("#quot;world#quot;"));
+
+    let dot = doc.debug_dump(GraphFormat::Dot).unwrap();
+    assert!(dot.starts_with("digraph {\n"));
+    assert!(dot.contains("label=\"text alice@2\";"));
+    assert!(dot.contains("op_0_1 -> op_0_2;"));
+    assert!(dot.ends_with("}\n"));
+}
+
+#[test]
+fn lazy_docs_initialize_over_several_steps() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    for i in 0..10_000 {
+        txn.set_scalar(ObjRef::Root, format!("key{}", i).as_str(), i)
+            .unwrap();
+    }
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.commit().unwrap();
+
+    let mut lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    let mut steps = 1;
+    while !lazy_doc.initialize_step(1).unwrap() {
+        steps += 1;
+    }
+    // Reading the clients, decoding the columns one at a time, then loading and replaying
+    // the operations in batches
+    assert!(steps > 32);
+
+    for i in [0, 4096, 9999] {
+        let key = format!("key{}", i);
+        assert_eq!(
+            lazy_doc.get(ObjRef::Root, key.as_str()).unwrap(),
+            doc.get(ObjRef::Root, key.as_str()).unwrap()
+        );
+    }
+    assert_eq!(lazy_doc.get_text(&text).unwrap().unwrap(), "hello");
+    assert_eq!(lazy_doc.version().unwrap(), doc.version().unwrap());
+}
+
+#[test]
+fn lazy_docs_keep_reporting_the_error_of_a_failed_step() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hello").unwrap();
+    txn.set_scalar(ObjRef::Root, "key", 1).unwrap();
+    txn.commit().unwrap();
+
+    // A buffer whose view can be read, but not its operations
+    let buffer = doc.serialize().unwrap();
+    let corrupted = (0..buffer.len())
+        .rev()
+        .map(|i| {
+            let mut corrupted = buffer.clone();
+            corrupted[i] ^= 0xff;
+            corrupted
+        })
+        .find(|corrupted| {
+            Doc::lazy("2".to_string(), corrupted.clone().into()).is_ok()
+                && Doc::load("2".to_string(), corrupted.clone().into()).is_err()
+        })
+        .unwrap();
+
+    let mut lazy_doc = Doc::lazy("2".to_string(), corrupted.into()).unwrap();
+    let error = loop {
+        match lazy_doc.initialize_step(1) {
+            Ok(done) => assert!(!done),
+            Err(error) => break error,
+        }
+    };
+    let DocError::BuildFailed(cause) = error else {
+        panic!("unexpected error {:?}", error);
+    };
+    assert!(matches!(
+        *cause,
+        DocError::SerializationError(_) | DocError::OperationLogError(_) | DocError::ViewError(_)
+    ));
+
+    for _ in 0..2 {
+        match lazy_doc.initialize_step(1) {
+            Err(DocError::BuildFailed(error)) => assert!(Arc::ptr_eq(&error, &cause)),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn validators_reject_writes_that_break_the_schema() {
+    let mut doc = Doc::new("1".to_string());
+    doc.set_validator(|path, value| match path.to_string().as_str() {
+        "settings.theme" => matches!(
+            value,
+            Value::Scalar(ScalarValue::String(theme)) if theme.len() <= 8
+        ),
+        "count" => matches!(value, Value::Scalar(ScalarValue::Int(_))),
+        _ => true,
+    })
+    .unwrap();
+
+    let mut txn = doc.transaction();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "dark").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    assert!(matches!(
+        txn.set_scalar(&settings, "theme", "solarized-dark"),
+        Err(TransactionError::SchemaViolation { path }) if path == Path::parse("settings.theme").unwrap()
+    ));
+    assert!(matches!(
+        txn.create_text(ObjRef::Root, "count"),
+        Err(TransactionError::SchemaViolation { .. })
+    ));
+    assert!(matches!(
+        txn.set_many(&settings, [("theme", 2)]),
+        Err(TransactionError::SchemaViolation { .. })
+    ));
+    txn.commit().unwrap();
+
+    assert_eq!(
+        doc.get(&settings, "theme").unwrap(),
+        Some(&Value::Scalar(ScalarValue::from("dark")))
+    );
+    assert_eq!(
+        doc.get(ObjRef::Root, "count").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(1)))
+    );
+
+    doc.remove_validator().unwrap();
+    let mut txn = doc.transaction();
+    txn.set_scalar(&settings, "theme", "solarized-dark")
+        .unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn maps_iterate_in_sorted_order() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "b", 1).unwrap();
+    txn.set_scalar(ObjRef::Root, 10, 2).unwrap();
+    txn.set_scalar(ObjRef::Root, "a", 3).unwrap();
+    txn.set_scalar(ObjRef::Root, 2, 4).unwrap();
+    txn.set_scalar(ObjRef::Root, "deleted", 5).unwrap();
+    txn.delete(ObjRef::Root, "deleted").unwrap();
+    txn.commit().unwrap();
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for doc in [&doc, &lazy_doc] {
+        let entries: Vec<(&Selector, &Value)> =
+            doc.iter_sorted(ObjRef::Root).unwrap().unwrap().collect();
+        let expected = [
+            (Selector::Index(2), 4),
+            (Selector::Index(10), 2),
+            (Selector::from("a"), 3),
+            (Selector::from("b"), 1),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for ((selector, value), (expected_selector, expected_value)) in entries.iter().zip(expected)
+        {
+            assert_eq!(**selector, expected_selector);
+            assert_eq!(**value, Value::Scalar(ScalarValue::Int(expected_value)));
+        }
+    }
+
+    let missing = ObjRef::Object(OperationId {
+        client_id: 0,
+        sequence: 100,
+    });
+    assert!(doc.iter_sorted(missing).unwrap().is_none());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json_exports_list_keys_in_sorted_order() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 0);
+    let mut txn = doc1.transaction();
+    for key in ["zeta", "alpha", "mid"] {
+        txn.set_scalar(ObjRef::Root, key, 1).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 0);
+    let mut txn = doc2.transaction();
+    txn.set_scalar(ObjRef::Root, 1, 2).unwrap();
+    txn.set_scalar(ObjRef::Root, 0, 2).unwrap();
+    txn.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    let json1 = serde_json::to_string(&doc1.as_map().unwrap()).unwrap();
+    let json2 = serde_json::to_string(&doc2.as_map().unwrap()).unwrap();
+    assert_eq!(json1, r#"{"0":2,"1":2,"alpha":1,"mid":1,"zeta":1}"#);
+    assert_eq!(json1, json2);
+}
+
+#[test]
+fn nested_objects_are_read_with_type_checks() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    let notes = txn.create_text(ObjRef::Root, "notes").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    txn.commit().unwrap();
+
+    let lazy_doc = Doc::lazy("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    for doc in [&doc, &lazy_doc] {
+        assert_eq!(
+            doc.get_map(ObjRef::Root, "settings").unwrap(),
+            Some(settings.clone())
+        );
+        assert_eq!(
+            doc.get_text_ref(ObjRef::Root, "notes").unwrap(),
+            Some(notes.clone())
+        );
+        assert_eq!(doc.get_map(ObjRef::Root, "missing").unwrap(), None);
+        assert_eq!(doc.get_text_ref(ObjRef::Root, "missing").unwrap(), None);
+
+        assert!(doc.get_map(ObjRef::Root, "notes").is_err());
+        assert!(doc.get_text_ref(ObjRef::Root, "settings").is_err());
+        assert!(doc.get_map(ObjRef::Root, "count").is_err());
+        assert!(doc.get_text_ref(ObjRef::Root, "count").is_err());
+    }
+}
+
+#[test]
+fn get_or_create_map_reuses_existing_maps() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let settings = txn.get_or_create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "dark").unwrap();
+    assert_eq!(
+        txn.get_or_create_map(ObjRef::Root, "settings").unwrap(),
+        settings
+    );
+    txn.create_text(ObjRef::Root, "notes").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    assert!(matches!(
+        txn.get_or_create_map(ObjRef::Root, "notes"),
+        Err(TransactionError::IncompatibleTypes { .. })
+    ));
+    assert!(matches!(
+        txn.get_or_create_map(ObjRef::Root, "count"),
+        Err(TransactionError::IncompatibleValue { .. })
+    ));
+    txn.commit().unwrap();
+
+    assert_eq!(doc.stats().unwrap().maps, 2);
+    assert_eq!(
+        doc.get(&settings, "theme").unwrap(),
+        Some(&Value::Scalar(ScalarValue::from("dark")))
+    );
+}
+
+#[test]
+fn errors_describe_the_mismatched_values() {
+    let mut doc = Doc::new("1".to_string());
+
+    let mut txn = doc.transaction();
+    let notes = txn.create_text(ObjRef::Root, "notes").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    match txn.set_scalar(&notes, "key", 1) {
+        Err(TransactionError::IncompatibleTypes {
+            object,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(object, notes);
+            assert_eq!(expected, ValueKind::Map);
+            assert_eq!(actual, ValueKind::Text);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match txn.get_or_create_text(ObjRef::Root, "count") {
+        Err(TransactionError::IncompatibleValue {
+            object,
+            selector,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(object, ObjRef::Root);
+            assert_eq!(selector, Selector::from("count"));
+            assert_eq!(expected, ValueKind::Text);
+            assert_eq!(actual, ValueKind::Scalar);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match txn.rename(ObjRef::Root, "missing", "other") {
+        Err(TransactionError::KeyNotFound { object, selector }) => {
+            assert_eq!(object, ObjRef::Root);
+            assert_eq!(selector, Selector::from("missing"));
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    txn.commit().unwrap();
+
+    match doc.get_path(&Path::parse("count.value").unwrap()) {
+        Err(DocError::ViewError(ViewError::IncompatibleValue {
+            selector, actual, ..
+        })) => {
+            assert_eq!(selector, Selector::from("count"));
+            assert_eq!(actual, ValueKind::Scalar);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(matches!(
+        doc.text(&ObjRef::Root),
+        Err(DocError::ViewError(ViewError::IncompatibleTypes {
+            expected: ValueKind::Text,
+            actual: ValueKind::Map,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn commits_report_the_committed_operations() {
+    let mut doc = Doc::new_with_timestamp("1".to_string(), 0);
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.append_text(&text, "hel").unwrap();
+    txn.append_text(&text, "lo").unwrap();
+    txn.set_scalar(ObjRef::Root, "count", 1).unwrap();
+    let result = txn.commit().unwrap();
+
+    // The two insertions are compacted into one
+    assert_eq!(result.operations.len(), 3);
+    assert_eq!(ObjRef::Object(result.operations[0]), text);
+    assert_eq!(result.heads, vec![*result.operations.last().unwrap()]);
+    assert_eq!(result.heads, doc.heads().unwrap());
+    assert!(result.timestamp.is_some());
+
+    let txn = doc.transaction();
+    let result = txn.commit().unwrap();
+    assert!(result.operations.is_empty());
+    assert_eq!(result.heads, doc.heads().unwrap());
+    assert_eq!(result.timestamp, None);
+}
+
+#[test]
+fn merge_bytes_accepts_documents_and_changesets() {
+    let mut remote = Doc::new("remote".to_string());
+    let mut txn = remote.transaction();
+    txn.set_scalar(ObjRef::Root, "first", 1).unwrap();
+    txn.commit().unwrap();
+
+    let mut local = Doc::new("local".to_string());
+    let mut txn = local.transaction();
+    txn.set_scalar(ObjRef::Root, "local", true).unwrap();
+    txn.commit().unwrap();
+
+    let report = local.merge_bytes(&remote.serialize().unwrap()).unwrap();
+    assert_eq!(report.applied_operations, 1);
+    assert_eq!(
+        local.get(ObjRef::Root, "first").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(1)))
+    );
+
+    let version = remote.version().unwrap();
+    let mut txn = remote.transaction();
+    txn.set_scalar(ObjRef::Root, "second", 2).unwrap();
+    txn.commit().unwrap();
+    let changes = remote.encode_new_operations_since(&version).unwrap();
+    local.merge_bytes(&changes).unwrap();
+    assert_eq!(
+        local.get(ObjRef::Root, "second").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(2)))
+    );
+
+    // Merging the same document again doesn't change anything
+    let version = local.version().unwrap();
+    local.merge_bytes(&remote.serialize().unwrap()).unwrap();
+    assert_eq!(local.version().unwrap(), version);
+
+    assert!(local.merge_bytes(b"JCRD-not-a-document").is_err());
+}
+
+#[test]
+fn non_blocking_docs_are_upgraded_explicitly() {
+    use std::future::Future;
+
+    let mut doc = Doc::new("1".to_string());
+    let mut txn = doc.transaction();
+    for i in 0..5_000 {
+        txn.set_scalar(ObjRef::Root, format!("key{}", i).as_str(), i)
+            .unwrap();
+    }
+    txn.commit().unwrap();
+    let buffer: bytes::Bytes = doc.serialize().unwrap().into();
+
+    let mut other = Doc::new("2".to_string());
+    let mut lazy_doc = Doc::lazy("3".to_string(), buffer.clone()).unwrap();
+    lazy_doc.set_upgrade_mode(UpgradeMode::NonBlocking);
+    assert!(matches!(
+        lazy_doc.merge(&other),
+        Err(DocError::DocumentNotReady)
+    ));
+    assert!(matches!(lazy_doc.status(), DocStatus::Cached));
+
+    let mut upgrade = lazy_doc.begin_upgrade();
+    let mut steps = 1;
+    while !upgrade.step().unwrap() {
+        steps += 1;
+    }
+    assert!(steps > 1);
+    lazy_doc.merge(&other).unwrap();
+    other.merge(&lazy_doc).unwrap();
+
+    // Awaiting the upgrade performs one step per poll
+    let mut lazy_doc = Doc::lazy("4".to_string(), buffer).unwrap();
+    let mut upgrade = std::pin::pin!(lazy_doc.begin_upgrade());
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut polls = 1;
+    while upgrade.as_mut().poll(&mut context).is_pending() {
+        polls += 1;
+    }
+    assert_eq!(polls, steps);
+    assert!(matches!(lazy_doc.status(), DocStatus::Ready));
+}
+
+#[test]
+fn large_pastes_are_stored_in_several_blocks() {
+    let mut doc = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            max_text_block_len: 1024,
+            ..Default::default()
+        },
+    );
+    let pasted = "lorem ipsum ".repeat(1000);
+
+    let mut txn = doc.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, pasted.as_str()).unwrap();
+    txn.insert_text(&text, 6000, "!").unwrap();
+    txn.commit().unwrap();
+
+    // The paste is still a single operation, with contiguous ids
+    let stats = doc.stats().unwrap();
+    assert_eq!(stats.operations_by_action["InsertText"], 2);
+    assert_eq!(stats.text_blocks, 14);
+
+    let mut expected = pasted.clone();
+    expected.insert(6000, '!');
+    assert_eq!(doc.get_text(&text).unwrap().unwrap(), expected);
+
+    let loaded = Doc::load("2".to_string(), doc.serialize().unwrap().into()).unwrap();
+    assert_eq!(loaded.get_text(&text).unwrap().unwrap(), expected);
+    // Options are not saved, so the loaded document uses the default length of 4KB
+    assert_eq!(loaded.stats().unwrap().text_blocks, 5);
+}
+
+#[test]
+fn text_fanout_only_changes_the_shape_of_the_tree() {
+    let edit = |fanout| {
+        let mut doc = Doc::new_with_options(
+            "1".to_string(),
+            DocOptions {
+                text_fanout: fanout,
+                ..Default::default()
+            },
+        );
+        let mut txn = doc.transaction();
+        let text = txn.create_text(ObjRef::Root, "text").unwrap();
+        txn.insert_text(&text, 0, "start").unwrap();
+        for i in 0..2000u32 {
+            let position = (i * 7919) % (5 + i);
+            txn.insert_text(&text, position, "ab").unwrap();
+            if i % 3 == 0 {
+                txn.delete_text(&text, position / 2, 1).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+        (doc, text)
+    };
+
+    let (narrow, text) = edit(TextFanout::Narrow);
+    let (medium, _) = edit(TextFanout::Medium);
+    let (wide, _) = edit(TextFanout::Wide);
+
+    let expected = narrow.get_text(&text).unwrap().unwrap();
+    assert_eq!(medium.get_text(&text).unwrap().unwrap(), expected);
+    assert_eq!(wide.get_text(&text).unwrap().unwrap(), expected);
+
+    let nodes = |doc: &Doc| doc.stats().unwrap().tree_nodes;
+    assert!(nodes(&narrow) > nodes(&medium));
+    assert!(nodes(&medium) > nodes(&wide));
+
+    // Replicas with different fanouts still exchange operations
+    let mut replica = Doc::new_with_options(
+        "2".to_string(),
+        DocOptions {
+            text_fanout: TextFanout::Wide,
+            ..Default::default()
+        },
+    );
+    replica.merge(&narrow).unwrap();
+    assert_eq!(replica.get_text(&text).unwrap().unwrap(), expected);
+}
+
+#[test]
+fn history_summarizes_each_operation() {
+    let mut doc = Doc::new("alice".to_string());
+    let mut txn = doc.transaction();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "dark").unwrap();
+    let notes = txn.create_text(&settings, "notes").unwrap();
+    txn.append_text(&notes, "x".repeat(100)).unwrap();
+    txn.commit().unwrap();
+
+    let mut remote = Doc::new("bob".to_string());
+    remote.merge(&doc).unwrap();
+    let mut txn = remote.transaction();
+    txn.delete(ObjRef::Root, "settings").unwrap();
+    txn.commit().unwrap();
+    doc.merge(&remote).unwrap();
+
+    let history: Vec<ChangeSummary> = doc.history().unwrap().collect();
+    let kinds: Vec<ChangeKind> = history.iter().map(|change| change.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ChangeKind::CreateMap,
+            ChangeKind::SetValue,
+            ChangeKind::CreateText,
+            ChangeKind::InsertText,
+            ChangeKind::DeleteValue,
+        ]
+    );
+
+    assert_eq!(history[1].author, "alice");
+    let operation = doc.get_operation(&history[1].operation).unwrap().unwrap();
+    assert_eq!(history[1].timestamp, operation.timestamp);
+    assert_eq!(history[1].preview, "\"dark\"");
+    assert_eq!(history[4].author, "bob");
+    assert_eq!(history[4].path, Some(Path::parse("settings").unwrap()));
+
+    // The map was deleted, so the changes inside it are no longer reachable
+    assert_eq!(history[1].path, None);
+    assert_eq!(history[3].preview.chars().count(), 81);
+    assert!(history[3].preview.ends_with('…'));
+}
+
+#[test]
+fn automerge_changes_are_imported_and_exported() {
+    // Two actors editing concurrently from the same initial change
+    let changes = r#"[
+        {"actor": "aaaa", "seq": 1, "startOp": 1, "time": 0, "deps": [], "ops": [
+            {"action": "makeText", "obj": "_root", "key": "notes", "insert": false, "pred": []},
+            {"action": "set", "obj": "1@aaaa", "elemId": "_head", "insert": true,
+                "values": ["h", "i"], "pred": []},
+            {"action": "set", "obj": "_root", "key": "title", "value": "draft", "pred": []}
+        ]},
+        {"actor": "aaaa", "seq": 2, "startOp": 5, "time": 0, "deps": [], "ops": [
+            {"action": "set", "obj": "1@aaaa", "elemId": "_head", "insert": true,
+                "value": "A", "pred": []},
+            {"action": "set", "obj": "_root", "key": "title", "value": "first",
+                "pred": ["4@aaaa"]},
+            {"action": "makeMap", "obj": "_root", "key": "meta", "pred": []},
+            {"action": "set", "obj": "7@aaaa", "key": "pages", "value": 3,
+                "datatype": "int", "pred": []}
+        ]},
+        {"actor": "bbbb", "seq": 1, "startOp": 5, "time": 0, "deps": [], "ops": [
+            {"action": "set", "obj": "1@aaaa", "elemId": "_head", "insert": true,
+                "value": "B", "pred": []},
+            {"action": "set", "obj": "_root", "key": "title", "value": "second",
+                "pred": ["4@aaaa"]},
+            {"action": "del", "obj": "1@aaaa", "elemId": "3@aaaa", "pred": ["3@aaaa"]}
+        ]}
+    ]"#;
+
+    let doc = Doc::import_automerge_changes("1".to_string(), changes.as_bytes()).unwrap();
+
+    // Concurrent writes are resolved by the highest operation id, like Automerge does
+    assert_eq!(
+        doc.get(ObjRef::Root, "title").unwrap(),
+        Some(&Value::Scalar("second".into()))
+    );
+    let notes = doc.get_text_ref(ObjRef::Root, "notes").unwrap().unwrap();
+    assert_eq!(doc.get_text(&notes).unwrap().unwrap(), "BAh");
+    let meta = doc.get_map(ObjRef::Root, "meta").unwrap().unwrap();
+    assert_eq!(
+        doc.get(&meta, "pages").unwrap(),
+        Some(&Value::Scalar(3.into()))
+    );
+
+    // The export describes the same state, which is imported back as it was
+    let exported = doc.export_automerge_changes().unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&exported).unwrap();
+    assert_eq!(json[0]["actor"], "31");
+    let imported = Doc::import_automerge_changes("2".to_string(), &exported).unwrap();
+    assert_eq!(
+        format!("{:?}", imported.as_map().unwrap()),
+        format!("{:?}", doc.as_map().unwrap())
+    );
+
+    let list = r#"[{"actor": "aaaa", "seq": 1, "startOp": 1, "time": 0, "deps": [], "ops": [
+        {"action": "makeList", "obj": "_root", "key": "items", "pred": []}
+    ]}]"#;
+    assert!(matches!(
+        Doc::import_automerge_changes("3".to_string(), list.as_bytes()),
+        Err(DocError::AutomergeError(
+            AutomergeError::UnsupportedOperation(_)
+        ))
+    ));
+}
+
+#[cfg(feature = "net")]
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_clients_exchange_operations_through_the_server() {
+    use json_crdt_rust::{serve, SyncClient};
+
+    async fn wait_for(doc: &SharedDoc, key: &str) {
+        for _ in 0..500 {
+            if doc.read(|doc| doc.get(ObjRef::Root, key).unwrap().is_some()) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} was not synced", key);
+    }
+
+    let server = SharedDoc::new(Doc::new("server".to_string()));
+    server
+        .write(|txn| txn.set_scalar(ObjRef::Root, "server", 1))
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve(listener, server.clone()));
+
+    let alice = SharedDoc::new(Doc::new("alice".to_string()));
+    let bob = SharedDoc::new(Doc::new("bob".to_string()));
+    let _alice_client = SyncClient::connect(&url, alice.clone()).await.unwrap();
+    let bob_client = SyncClient::connect(&url, bob.clone()).await.unwrap();
+    assert!(bob_client.is_connected());
+
+    // The operations of the server are backfilled on connection
+    wait_for(&alice, "server").await;
+
+    alice
+        .write(|txn| txn.set_scalar(ObjRef::Root, "alice", 2))
+        .unwrap();
+    wait_for(&bob, "alice").await;
+    bob.write(|txn| txn.set_scalar(ObjRef::Root, "bob", 3))
+        .unwrap();
+    wait_for(&alice, "bob").await;
+
+    // Clients connecting later receive everything from the server
+    drop(bob_client);
+    alice
+        .write(|txn| txn.set_scalar(ObjRef::Root, "later", 4))
+        .unwrap();
+    let carol = SharedDoc::new(Doc::new("carol".to_string()));
+    let _carol_client = SyncClient::connect(&url, carol.clone()).await.unwrap();
+    for key in ["server", "alice", "bob", "later"] {
+        wait_for(&carol, key).await;
+    }
+    assert_eq!(
+        carol.read(|doc| doc.version().unwrap()),
+        alice.read(|doc| doc.version().unwrap())
+    );
+}
+
+#[cfg(feature = "net")]
+#[tokio::test(flavor = "multi_thread")]
+async fn awareness_states_are_relayed_by_the_server() {
+    use json_crdt_rust::{serve_with_awareness, Awareness, SyncClient};
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("condition was not met");
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve_with_awareness(
+        listener,
+        SharedDoc::new(Doc::new("server".to_string())),
+        Awareness::new("server".to_string()),
+    ));
+
+    let alice = Awareness::new("alice".to_string());
+    let bob = Awareness::new("bob".to_string());
+    let bob_changes = bob.subscribe();
+    let _alice_client = SyncClient::connect_with_awareness(
+        &url,
+        SharedDoc::new(Doc::new("alice".to_string())),
+        alice.clone(),
+    )
+    .await
+    .unwrap();
+    let _bob_client = SyncClient::connect_with_awareness(
+        &url,
+        SharedDoc::new(Doc::new("bob".to_string())),
+        bob.clone(),
+    )
+    .await
+    .unwrap();
+
+    alice.set_local_field("name", "Alice");
+    alice.set_local_field("cursor", 12);
+    let alice_id = "alice".to_string();
+    wait_until(|| {
+        bob.state_of(&alice_id)
+            .is_some_and(|state| state.get("cursor") == Some(&ScalarValue::Int(12)))
+    })
+    .await;
+
+    // Leaving removes the state on the other peers
+    alice.set_local_state(None);
+    wait_until(|| bob.state_of(&alice_id).is_none()).await;
+    let changes: Vec<AwarenessChange> = bob_changes.try_iter().collect();
+    assert!(changes
+        .iter()
+        .any(|change| change.added == vec![alice_id.clone()]));
+    assert!(changes
+        .iter()
+        .any(|change| change.removed == vec![alice_id.clone()]));
+    assert!(changes
+        .iter()
+        .all(|change| change.origin == Some(ChangeOrigin::Remote)));
+}
+
+#[test]
+fn map_entries_with_ttl_expire_and_are_cleared_on_compaction() {
+    let clock = Arc::new(ManualClock::new(1000));
+    let mut doc1 = Doc::new_with_options(
+        "1".to_string(),
+        DocOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        },
+    );
+    let mut doc2 = Doc::new_with_options(
+        "2".to_string(),
+        DocOptions {
+            clock: Arc::new(ManualClock::new(1000)),
+            ..Default::default()
+        },
+    );
+
+    let cursor = "x".repeat(1000);
+    let mut txn = doc1.transaction();
+    txn.set_scalar(ObjRef::Root, "name", "Alice").unwrap();
+    txn.set_scalar_with_ttl(
+        ObjRef::Root,
+        "cursor",
+        cursor.as_str(),
+        Duration::from_secs(10),
+    )
+    .unwrap();
+    txn.commit().unwrap();
+
+    // Expiring values survive merges like any other value
+    doc2.merge(&doc1).unwrap();
+    for doc in [&doc1, &doc2] {
+        assert_eq!(
+            doc.get(ObjRef::Root, "cursor").unwrap(),
+            Some(&Value::Scalar(ScalarValue::String(cursor.clone())))
+        );
+    }
+
+    // Each document evaluates the expiration against its own clock
+    clock.advance(10_000);
+    assert_eq!(doc1.get(ObjRef::Root, "cursor").unwrap(), None);
+    assert_eq!(doc1.len(ObjRef::Root).unwrap(), Some(1));
+    assert!(!doc1
+        .as_map()
+        .unwrap()
+        .contains_key(&Selector::from("cursor")));
+    assert!(doc2.get(ObjRef::Root, "cursor").unwrap().is_some());
+
+    // Lazy documents read against the system clock, which is way past the expiration
+    let lazy_doc = Doc::lazy("3".to_string(), doc2.serialize().unwrap().into()).unwrap();
+    assert_eq!(lazy_doc.get(ObjRef::Root, "cursor").unwrap(), None);
+    assert_eq!(lazy_doc.len(ObjRef::Root).unwrap(), Some(1));
+
+    // A later write replaces the expired value
+    let mut txn = doc1.transaction();
+    txn.set_scalar(ObjRef::Root, "cursor", 12).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(
+        doc1.get(ObjRef::Root, "cursor").unwrap(),
+        Some(&Value::Scalar(ScalarValue::Int(12)))
+    );
+
+    // Compaction drops the contents of the expired value, but keeps its operation
+    let size = doc1.serialize().unwrap().len();
+    doc1.compact().unwrap();
+    assert!(doc1.serialize().unwrap().len() < size - cursor.len());
+    assert_eq!(doc1.history().unwrap().count(), 3);
+
+    // Replicas that still have the value accept the compacted operation
+    doc2.merge(&doc1).unwrap();
+    let mut doc4 = Doc::new("4".to_string());
+    doc4.merge(&doc1).unwrap();
+    for doc in [&doc2, &doc4] {
+        assert_eq!(
+            doc.get(ObjRef::Root, "cursor").unwrap(),
+            Some(&Value::Scalar(ScalarValue::Int(12)))
+        );
+    }
+}
+
+#[test]
+fn verify_convergence_merges_documents_in_every_order() {
+    let mut base = Doc::new_with_timestamp("base".to_string(), 1);
+    let mut txn = base.transaction();
+    let text = txn.create_text(ObjRef::Root, "text").unwrap();
+    txn.insert_text(&text, 0, "hello world").unwrap();
+    let settings = txn.create_map(ObjRef::Root, "settings").unwrap();
+    txn.set_scalar(&settings, "theme", "light").unwrap();
+    txn.commit().unwrap();
+
+    let mut docs: Vec<Doc> = (0..7)
+        .map(|i| Doc::load(i.to_string(), base.serialize().unwrap().into()).unwrap())
+        .collect();
+    for (i, doc) in docs.iter_mut().enumerate() {
+        let mut txn = doc.transaction();
+        txn.insert_text(&text, 5, i.to_string()).unwrap();
+        txn.delete_text(&text, 7 + i as u32 % 3, 1).unwrap();
+        txn.set_scalar(&settings, "theme", format!("theme {}", i))
+            .unwrap();
+        txn.set_scalar(ObjRef::Root, i.to_string(), i as i32)
+            .unwrap();
+        txn.commit().unwrap();
+    }
+
+    let report = Doc::verify_convergence(&docs[..4]).unwrap();
+    assert!(report.is_converged(), "{:?}", report.divergence);
+    assert_eq!(report.orders_checked, 24);
+
+    let report = Doc::verify_convergence(&docs).unwrap();
+    assert!(report.is_converged(), "{:?}", report.divergence);
+    assert_eq!(report.orders_checked, 120);
+
+    // The documents themselves are left untouched
+    assert_eq!(docs[0].get_text(&text).unwrap().unwrap(), "hello0 orld");
+}
+
+#[test]
+fn key_history_lists_every_value_in_causal_order() {
+    let mut doc1 = Doc::new_with_timestamp("1".to_string(), 1);
+    let mut doc2 = Doc::new_with_timestamp("2".to_string(), 2);
+
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "base").unwrap();
+    let text = txn1.create_text(ObjRef::Root, "text").unwrap();
+    txn1.commit().unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    // Concurrent writes are listed after the value they both replaced
+    let mut txn1 = doc1.transaction();
+    txn1.set_scalar(ObjRef::Root, "register", "one").unwrap();
+    txn1.commit().unwrap();
+    let mut txn2 = doc2.transaction();
+    txn2.set_scalar(ObjRef::Root, "register", "two").unwrap();
+    txn2.commit().unwrap();
+
+    doc1.merge(&doc2).unwrap();
+    doc2.merge(&doc1).unwrap();
+
+    let history = doc1.key_history(ObjRef::Root, "register").unwrap();
+    assert_eq!(history, doc2.key_history(ObjRef::Root, "register").unwrap());
+    let summary: Vec<(&str, &str, bool, bool)> = history
+        .iter()
+        .map(|entry| {
+            let value = entry.value.as_scalar().unwrap().as_string().unwrap();
+            (
+                entry.author.as_str(),
+                value.as_str(),
+                entry.overwritten,
+                entry.deleted,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("1", "base", true, false),
+            ("1", "one", false, false),
+            ("2", "two", false, false),
+        ]
+    );
+    assert!(history[0].timestamp <= history[1].timestamp);
+
+    let mut txn1 = doc1.transaction();
+    txn1.delete(ObjRef::Root, "register").unwrap();
+    txn1.commit().unwrap();
+
+    // Deleting the key hides the values without removing them from the history
+    let history = doc1.key_history(ObjRef::Root, "register").unwrap();
+    assert_eq!(history.len(), 3);
+    assert!(history[1..].iter().all(|entry| entry.deleted));
+    assert_eq!(doc1.get(ObjRef::Root, "register").unwrap(), None);
+
+    assert!(doc1
+        .key_history(ObjRef::Root, "missing")
+        .unwrap()
+        .is_empty());
+    assert!(matches!(
+        doc1.key_history(&text, "register"),
+        Err(DocError::ViewError(ViewError::IncompatibleTypes { .. }))
+    ));
+}
+
+#[test]
+// Duplicate operation IDs are only detected by the validation of the merged operations
+#[cfg(any(debug_assertions, feature = "validation"))]
+fn operations_ingested_before_a_failed_merge_reach_the_view() {
+    // Two documents reusing the same client, so that their first operations share an ID
+    let mut doc = Doc::new_with_timestamp("a".to_string(), 1);
+    let mut txn = doc.transaction();
+    txn.set_scalar(ObjRef::Root, "x", 1).unwrap();
+    txn.commit().unwrap();
+
+    // The conflicting operation comes after five new ones
+    let mut remote = Doc::new_with_timestamp("b".to_string(), 2);
+    let mut txn = remote.transaction();
+    for i in 0..5 {
+        txn.set_scalar(ObjRef::Root, format!("y{}", i), i).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut impostor = Doc::new_with_timestamp("a".to_string(), 1);
+    impostor.merge(&remote).unwrap();
+    let mut txn = impostor.transaction();
+    txn.set_scalar(ObjRef::Root, "x", 2).unwrap();
+    txn.commit().unwrap();
+    remote.merge(&impostor).unwrap();
+    let remote_bytes = remote.serialize().unwrap();
+
+    type Merge = fn(&mut Doc, &Doc, &[u8]) -> Result<MergeReport, DocError>;
+    let merges: [Merge; 2] = [
+        |doc, remote, _| doc.merge_with_report(remote),
+        |doc, _, bytes| doc.merge_bytes(bytes),
+    ];
+    for merge in merges {
+        let mut doc = Doc::load("a".to_string(), doc.serialize().unwrap().into()).unwrap();
+        assert!(merge(&mut doc, &remote, &remote_bytes).is_err());
+
+        let reloaded = Doc::load("c".to_string(), doc.serialize().unwrap().into()).unwrap();
+        for key in ["x", "y0", "y4"] {
+            assert_eq!(
+                doc.get(ObjRef::Root, key).unwrap(),
+                reloaded.get(ObjRef::Root, key).unwrap()
+            );
+        }
+        assert!(doc.get(ObjRef::Root, "y0").unwrap().is_some());
+    }
+}